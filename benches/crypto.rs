@@ -0,0 +1,66 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+use store::crypto::{KeyPair, request_aad};
+
+const SIZES: [usize; 3] = [64, 1024, 16384];
+
+fn bench_encrypt(c: &mut Criterion) {
+    let key_pair = KeyPair::generate();
+    let mut group = c.benchmark_group("encrypt_into");
+    for size in SIZES {
+        let data = vec![0x42; size];
+        let mut result = Vec::new();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| key_pair.encrypt_into(data, &mut result, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_decrypt(c: &mut Criterion) {
+    let key_pair = KeyPair::generate();
+    let mut group = c.benchmark_group("decrypt_into");
+    for size in SIZES {
+        let (ciphertext, _) = key_pair.encrypt(&vec![0x42; size], 0);
+        let mut result = Vec::new();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &ciphertext, |b, ciphertext| {
+            b.iter(|| key_pair.decrypt_into(ciphertext, &mut result, 0).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_seal(c: &mut Criterion) {
+    let key_pair = KeyPair::generate();
+    let aad = request_aad("pool", 0x03);
+    let mut group = c.benchmark_group("seal_into");
+    for size in SIZES {
+        let data = vec![0x42; size];
+        let mut result = Vec::new();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| key_pair.seal_into(&aad, data, &mut result, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_open(c: &mut Criterion) {
+    let key_pair = KeyPair::generate();
+    let aad = request_aad("pool", 0x03);
+    let mut group = c.benchmark_group("open_into");
+    for size in SIZES {
+        let (sealed, _) = key_pair.seal(&aad, &vec![0x42; size], 0);
+        let mut result = Vec::new();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &sealed, |b, sealed| {
+            b.iter(|| key_pair.open_into(&aad, sealed, &mut result, 0).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encrypt, bench_decrypt, bench_seal, bench_open);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+use store::{DeviceId, GroupId, ObjectId};
+use store::storage_map::{
+    Algorithm, Bucket, GroupMapping, HashVersion, Node, NodeEntry, PickMode, PlacementRule, StorageMap, build_alias_bucket,
+    build_straw_bucket, build_tree_bucket,
+};
+
+/// Children of each bucket.
+const FANOUT: usize = 8;
+
+const GROUPS: u32 = 1024;
+
+fn bench_object_to_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("object_to_group");
+    for hash in [HashVersion::Fx, HashVersion::Murmur3] {
+        let map = StorageMap { hash, ..StorageMap::single_device(DeviceId([0; 16])) };
+        for len in [16, 128] {
+            let object_id = ObjectId(vec![b'a'; len]);
+            group.bench_with_input(BenchmarkId::new(format!("{:?}", hash), len), &object_id, |b, object_id| {
+                b.iter(|| map.object_to_group(object_id))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Build a bucket of equally weighted children with the named algorithm.
+fn bucket(algorithm: &str, children: Vec<NodeEntry>, id: u32) -> Bucket {
+    let pick_mode = PickMode::NeverRepeat;
+    let algorithm = match algorithm {
+        "straw" => return build_straw_bucket(children, id, pick_mode),
+        "tree" => return build_tree_bucket(children, id, pick_mode),
+        "alias" => return build_alias_bucket(children, id, pick_mode),
+        "uniform" => Algorithm::Uniform,
+        "straw2" => Algorithm::Straw2,
+        "list" => Algorithm::List,
+        _ => unreachable!(),
+    };
+    Bucket { id, algorithm, pick_mode, children }
+}
+
+/// Build a tree of buckets `depth` levels deep, with devices at the bottom.
+fn tree(algorithm: &str, depth: u32, next_id: &mut u32) -> Node {
+    if depth == 0 {
+        let mut id = [0; 16];
+        id[..4].copy_from_slice(&next_id.to_be_bytes());
+        *next_id += 1;
+        return Node::Device(DeviceId(id));
+    }
+    let id = *next_id;
+    *next_id += 1;
+    let children = (0..FANOUT)
+        .map(|_| NodeEntry { weight: 10, node: tree(algorithm, depth - 1, next_id) })
+        .collect();
+    Node::Bucket(bucket(algorithm, children, id))
+}
+
+fn bench_group_to_devices(c: &mut Criterion) {
+    let mut group = c.benchmark_group("group_to_devices");
+    for algorithm in ["uniform", "straw", "straw2", "tree", "alias", "list"] {
+        for depth in 1..=3 {
+            let map = StorageMap {
+                generation: 1,
+                groups: GROUPS as usize,
+                replicas: 3,
+                group_mapping: GroupMapping::Modulo,
+                placement_groups: None,
+                hash: HashVersion::Murmur3,
+                rule: PlacementRule::Any,
+                map_root: tree(algorithm, depth, &mut 0),
+            };
+            group.bench_with_input(BenchmarkId::new(algorithm, depth), &map, |b, map| {
+                let mut group_id = 0;
+                b.iter(|| {
+                    group_id = (group_id + 1) % GROUPS;
+                    map.group_to_devices(&GroupId(group_id), 3)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_object_to_group, bench_group_to_devices);
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tempdir::TempDir;
+
+use store::{ObjectId, PoolName};
+use store::storage::StorageBackend;
+use store::storage::file_store::create_file_store;
+use store::storage::mem_store::MemStore;
+
+const SIZES: [usize; 2] = [1024, 65536];
+
+/// Objects written and read in turn, so reads don't all hit the same one.
+const OBJECTS: usize = 64;
+
+fn bench_backend<S: StorageBackend>(c: &mut Criterion, name: &str, storage: &S) {
+    let pool = PoolName("bench".to_owned());
+    let objects: Vec<ObjectId> = (0..OBJECTS).map(|i| ObjectId(format!("object{}", i).into_bytes())).collect();
+
+    let mut group = c.benchmark_group(format!("{}_write_object", name));
+    for size in SIZES {
+        let data = vec![0x42; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % OBJECTS;
+                storage.write_object(&pool, &objects[i], data).unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}_read_object", name));
+    for size in SIZES {
+        for object_id in &objects {
+            storage.write_object(&pool, object_id, &vec![0x42; size]).unwrap();
+        }
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % OBJECTS;
+                storage.read_object(&pool, &objects[i]).unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}_write_part", name));
+    let data = vec![0x42; 512];
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("512", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % OBJECTS;
+            storage.write_part(&pool, &objects[i], 4096, &data).unwrap()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}_append_object", name));
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("512", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % OBJECTS;
+            // Keep the objects from growing without bound
+            if i == 0 {
+                for object_id in &objects {
+                    storage.truncate_object(&pool, object_id, 0).unwrap();
+                }
+            }
+            storage.append_object(&pool, &objects[i], &data).unwrap()
+        })
+    });
+    group.finish();
+
+    for object_id in &objects {
+        storage.delete_object(&pool, object_id).unwrap();
+    }
+}
+
+fn bench_mem_store(c: &mut Criterion) {
+    bench_backend(c, "mem_store", &MemStore::default());
+}
+
+fn bench_file_store(c: &mut Criterion) {
+    let dir = TempDir::new("store-bench").unwrap();
+    let (storage, _) = create_file_store(&dir.path().join("store")).unwrap();
+    bench_backend(c, "file_store", &storage);
+}
+
+criterion_group!(benches, bench_mem_store, bench_file_store);
+criterion_main!(benches);
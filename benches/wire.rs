@@ -0,0 +1,76 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+use store::{ObjectId, PoolName};
+use store::proto::wire::{Request, RequestMessage, Response, ResponseMessage};
+
+const SIZES: [usize; 3] = [64, 1024, 16384];
+
+fn write_request(size: usize) -> RequestMessage {
+    RequestMessage {
+        counter: 1,
+        epoch: 1,
+        trace_id: None,
+        capability: vec![0; 96],
+        pool: PoolName("default".to_owned()),
+        request: Request::WriteObject { object_id: ObjectId(b"bench/object".to_vec()), data: vec![0x42; size] },
+    }
+}
+
+fn object_response(size: usize) -> ResponseMessage {
+    ResponseMessage {
+        counter: 1,
+        trace_id: None,
+        response: Response::Object { version: 1, data: vec![0x42; size] },
+    }
+}
+
+fn bench_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_encode");
+    for size in SIZES {
+        let message = write_request(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| message.encode())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("request_decode");
+    for size in SIZES {
+        let encoded = write_request(size).encode();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &encoded, |b, encoded| {
+            b.iter(|| RequestMessage::decode(encoded).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response_encode_into");
+    for size in SIZES {
+        let message = object_response(size);
+        let mut result = Vec::new();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| {
+                result.clear();
+                message.encode_into(&mut result)
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("response_decode");
+    for size in SIZES {
+        let encoded = object_response(size).encode();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &encoded, |b, encoded| {
+            b.iter(|| ResponseMessage::decode(encoded).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_request, bench_response);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+fn main() {
+    // The gRPC service is generated from its published definition
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/store.proto").unwrap();
+}
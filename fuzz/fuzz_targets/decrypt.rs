@@ -0,0 +1,25 @@
+//! Decrypting messages, with a MAC computed over the input so that what
+//! comes after checking it is reached too.
+
+#![no_main]
+
+use hmac::{Hmac, Mac};
+use libfuzzer_sys::fuzz_target;
+use sha2::Sha256;
+use store::crypto::{KEY_PAIR_SIZE, KeyPair};
+
+fuzz_target!(|data: &[u8]| {
+    let keys = KeyPair::from_bytes(&[7; KEY_PAIR_SIZE]);
+    let mut result = Vec::new();
+    let _ = keys.decrypt_into(data, &mut result, 0);
+    let _ = keys.open_into(b"aad", data, &mut result, 0);
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(keys.mac_key.as_bytes()).unwrap();
+    mac.update(data);
+    let mut message = data.to_owned();
+    message.extend_from_slice(&mac.finalize().into_bytes());
+    if let Some(counter) = keys.decrypt_into(&message, &mut result, 0) {
+        assert!(result.len() < data.len());
+        assert!(counter >= 1);
+    }
+});
@@ -0,0 +1,24 @@
+//! The line protocol, as read from a connection: data arrives in pieces,
+//! with limits small enough to be hit.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use store::proto::Parser;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let mut parser = Parser::with_limits(data[0] as usize, data[1] as usize * 4);
+    for piece in data[3..].chunks(data[2] as usize + 1) {
+        let _ = parser.feed(piece);
+        while let Some(message) = parser.next() {
+            for i in 0..message.len() {
+                let _ = message.get_bytes(i);
+                let _ = message.get_str(i);
+            }
+        }
+        let _ = parser.is_empty();
+    }
+});
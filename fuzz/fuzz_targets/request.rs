@@ -0,0 +1,20 @@
+//! The binary messages a storage daemon reads from the network: requests
+//! from clients, and the responses of other daemons.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use store::proto::wire::{RequestMessage, ResponseFrame, request_counter, request_trace_id, response_counter};
+
+fuzz_target!(|data: &[u8]| {
+    // What the daemon looks at to answer requests it can't decode
+    let _ = request_counter(data);
+    let _ = request_trace_id(data);
+    if let Ok(message) = RequestMessage::decode(data) {
+        let _ = message.check();
+        let _ = message.encode();
+    }
+
+    let _ = response_counter(data);
+    let _ = ResponseFrame::decode(data);
+});
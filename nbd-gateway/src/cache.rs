@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+/// Holds the writes to blocks until they are written back.
+///
+/// Writes to the same block are merged, so a burst of small writes turns
+/// into few requests. Writes being written back stay visible until that
+/// finishes, see `start_write_back()`.
+pub struct WriteCache {
+    max_blocks: usize,
+    block_size: usize,
+    dirty: BTreeMap<usize, CachedBlock>,
+    in_flight: BTreeMap<usize, CachedBlock>,
+}
+
+/// The cached writes to a block.
+#[derive(Clone)]
+pub struct CachedBlock {
+    data: Vec<u8>,
+    /// The parts of `data` that were written, sorted and not touching.
+    ranges: Vec<(usize, usize)>,
+}
+
+impl CachedBlock {
+    fn new(block_size: usize) -> CachedBlock {
+        CachedBlock {
+            data: vec![0; block_size],
+            ranges: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        let (mut start, mut end) = (offset, offset + data.len());
+        self.data[start..end].clone_from_slice(data);
+
+        // Merge with the ranges it overlaps or touches
+        self.ranges.retain(|&(s, e)| {
+            if e < start || s > end {
+                true
+            } else {
+                start = start.min(s);
+                end = end.max(e);
+                false
+            }
+        });
+        let pos = self.ranges.iter().position(|&(s, _)| s > start).unwrap_or(self.ranges.len());
+        self.ranges.insert(pos, (start, end));
+    }
+
+    /// Apply the writes of `other` over ours.
+    fn merge(&mut self, other: &CachedBlock) {
+        for &(s, e) in &other.ranges {
+            self.write(s, &other.data[s..e]);
+        }
+    }
+
+    /// Copy the cached data over what was read from the storage.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        let end = offset + buf.len();
+        for &(s, e) in &self.ranges {
+            let (s, e) = (s.max(offset), e.min(end));
+            if s < e {
+                buf[s - offset..e - offset].clone_from_slice(&self.data[s..e]);
+            }
+        }
+    }
+}
+
+/// A write to do to the storage.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockWrite {
+    pub block_num: usize,
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+impl WriteCache {
+    /// Create a cache, which is full with `max_blocks` dirty blocks.
+    pub fn new(max_blocks: usize, block_size: usize) -> WriteCache {
+        WriteCache {
+            max_blocks,
+            block_size,
+            dirty: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirty.is_empty()
+    }
+
+    /// Whether the cache should be written back before taking more writes.
+    pub fn is_full(&self) -> bool {
+        self.dirty.len() >= self.max_blocks
+    }
+
+    /// Record a write within a block.
+    pub fn write(&mut self, block_num: usize, offset: usize, data: &[u8]) {
+        let block_size = self.block_size;
+        self.dirty.entry(block_num).or_insert_with(|| CachedBlock::new(block_size)).write(offset, data);
+    }
+
+    /// Get a copy of the cached writes to a block.
+    ///
+    /// Take it before reading the block from the storage, so writes being
+    /// written back in the meantime are not missed.
+    pub fn get(&self, block_num: usize) -> Option<CachedBlock> {
+        match (self.in_flight.get(&block_num), self.dirty.get(&block_num)) {
+            (None, None) => None,
+            (Some(block), None) | (None, Some(block)) => Some(block.clone()),
+            (Some(in_flight), Some(dirty)) => {
+                let mut block = in_flight.clone();
+                block.merge(dirty);
+                Some(block)
+            }
+        }
+    }
+
+    /// Whether there are writes to a block that are not in the storage yet.
+    pub fn contains(&self, block_num: usize) -> bool {
+        self.dirty.contains_key(&block_num) || self.in_flight.contains_key(&block_num)
+    }
+
+    /// Zero part of a block in the cache, after it was zeroed in the storage.
+    pub fn zero(&mut self, block_num: usize, offset: usize, len: usize) {
+        for blocks in [&mut self.dirty, &mut self.in_flight] {
+            if let Some(block) = blocks.get_mut(&block_num) {
+                block.data[offset..offset + len].fill(0);
+            }
+        }
+    }
+
+    /// Forget the writes to a block, which is being overwritten.
+    pub fn discard(&mut self, block_num: usize) {
+        self.dirty.remove(&block_num);
+        self.in_flight.remove(&block_num);
+    }
+
+    /// Start writing back, returning the writes to do, in block order.
+    ///
+    /// Only one write back can happen at a time, and it has to be ended with
+    /// `finish_write_back()`.
+    pub fn start_write_back(&mut self) -> Vec<BlockWrite> {
+        assert!(self.in_flight.is_empty());
+        self.in_flight = std::mem::take(&mut self.dirty);
+        let mut writes = Vec::new();
+        for (&block_num, block) in &self.in_flight {
+            for &(start, end) in &block.ranges {
+                writes.push(BlockWrite {
+                    block_num,
+                    offset: start,
+                    data: block.data[start..end].to_owned(),
+                });
+            }
+        }
+        writes
+    }
+
+    /// End the write back.
+    ///
+    /// If it failed, the writes are kept, under the ones made since it
+    /// started.
+    pub fn finish_write_back(&mut self, success: bool) {
+        let in_flight = std::mem::take(&mut self.in_flight);
+        if success {
+            return;
+        }
+        for (block_num, mut block) in in_flight {
+            if let Some(newer) = self.dirty.get(&block_num) {
+                block.merge(newer);
+            }
+            self.dirty.insert(block_num, block);
+        }
+    }
+}
+
+#[test]
+fn test_cache() {
+    let mut cache = WriteCache::new(2, 512);
+    assert!(cache.is_empty());
+
+    // Sequential writes are merged
+    cache.write(3, 0, b"abc");
+    cache.write(3, 3, b"def");
+    cache.write(3, 10, b"xy");
+    cache.write(1, 500, &[1; 12]);
+    assert!(cache.is_full());
+
+    // Reads see the cached data, and only that
+    let mut buf = [b'.'; 14];
+    cache.get(3).unwrap().read(0, &mut buf);
+    assert_eq!(&buf, b"abcdef....xy..");
+    let mut buf = [b'.'; 4];
+    cache.get(3).unwrap().read(4, &mut buf);
+    assert_eq!(&buf, b"ef..");
+    assert!(cache.get(2).is_none());
+    assert!(cache.contains(3) && !cache.contains(2));
+
+    // Overlapping writes replace the data and merge the ranges
+    cache.write(3, 5, b"FGHIJ");
+    cache.zero(3, 1, 1);
+    cache.write(5, 0, &[2; 512]);
+    cache.discard(1);
+
+    let writes = cache.start_write_back();
+    assert!(cache.is_empty());
+    assert_eq!(
+        writes,
+        vec![
+            BlockWrite { block_num: 3, offset: 0, data: b"a\0cdeFGHIJxy".to_vec() },
+            BlockWrite { block_num: 5, offset: 0, data: vec![2; 512] },
+        ],
+    );
+
+    // Writes being written back are still visible, under newer ones
+    assert!(cache.contains(5));
+    cache.write(3, 2, b"zz");
+    let mut buf = [b'.'; 6];
+    cache.get(3).unwrap().read(0, &mut buf);
+    assert_eq!(&buf, b"a\0zzeF");
+
+    // They are kept if the write back fails
+    cache.finish_write_back(false);
+    let writes = cache.start_write_back();
+    assert_eq!(writes[0], BlockWrite { block_num: 3, offset: 0, data: b"a\0zzeFGHIJxy".to_vec() });
+    cache.finish_write_back(true);
+    assert!(cache.is_empty());
+    assert!(cache.get(3).is_none());
+}
@@ -1,37 +1,521 @@
-mod iter;
+mod cache;
+mod readahead;
 
-use byteorder::{BigEndian, ReadBytesExt};
 use lazy_static::lazy_static;
-use log::info;
-use std::io::{Cursor, Write};
+use log::{debug, error, info, warn};
+use std::io::{Error as IoError, ErrorKind};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
 
-use iter::list_blocks;
+use cache::{CachedBlock, WriteCache};
+use readahead::{ReadCache, fill_from};
 use nbdkit::*;
 use store::{ObjectId, PoolName};
 use store::client::{Client, create_client};
-use store::metrics::start_http_server;
+use store::image::iter::{ListBlocks, list_blocks};
+use store::image::{
+    Geometry, LOCK_LEASE, block_object_id, lock_image, read_image, renew_image_lock, snapshot_name, unlock_image,
+};
+use store::metrics::{register_counter, register_counter_vec, register_latency, start_http_server};
+use store::proto::wire::{ErrorCode, TraceId};
 
-const BLOCK_SIZE: usize = 512;
+struct Metrics {
+    requests: prometheus::IntCounterVec,
+    request_bytes: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+    latency: prometheus::HistogramVec,
+    block_reads: prometheus::IntCounter,
+    block_writes: prometheus::IntCounter,
+    block_deletes: prometheus::IntCounter,
+    readahead_hits: prometheus::IntCounter,
+    readahead_blocks: prometheus::IntCounter,
+    cached_writes: prometheus::IntCounter,
+    write_backs: prometheus::IntCounter,
+}
 
-struct BlockDeviceClient {
-    runtime: tokio::runtime::Runtime,
-    client: Client,
-    size: u64,
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        requests: register_counter_vec("nbd_gateway", "requests", "Total NBD requests", &["op"]),
+        request_bytes: register_counter_vec("nbd_gateway", "request_bytes", "Total bytes covered by NBD requests", &["op"]),
+        errors: register_counter_vec("nbd_gateway", "errors", "Total NBD requests that failed", &["op"]),
+        latency: register_latency("nbd_gateway", "request_duration_seconds", "Time to answer NBD requests", &["op"]),
+        block_reads: register_counter("nbd_gateway", "block_reads", "Total block reads sent to the storage, not counting readahead"),
+        block_writes: register_counter("nbd_gateway", "block_writes", "Total block writes sent to the storage"),
+        block_deletes: register_counter("nbd_gateway", "block_deletes", "Total blocks deleted or zeroed in the storage"),
+        readahead_hits: register_counter("nbd_gateway", "readahead_hits", "Total blocks read from the readahead cache"),
+        readahead_blocks: register_counter("nbd_gateway", "readahead_blocks", "Total blocks read ahead"),
+        cached_writes: register_counter("nbd_gateway", "cached_writes", "Total block writes held in the write-back cache"),
+        write_backs: register_counter("nbd_gateway", "write_backs", "Total write backs of the cache"),
+    };
+}
+
+/// Count an NBD request, its duration, and whether it failed.
+fn measure<T>(op: &str, size: usize, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let _timer = METRICS.latency.with_label_values(&[op]).start_timer();
+    METRICS.requests.with_label_values(&[op]).inc();
+    METRICS.request_bytes.with_label_values(&[op]).inc_by(size as u64);
+    let res = f();
+    if res.is_err() {
+        METRICS.errors.with_label_values(&[op]).inc();
+    }
+    res
+}
+
+/// How many block requests are sent at the same time for one NBD request.
+const MAX_PARALLEL_REQUESTS: usize = 32;
+
+/// How often the write-back cache is written back, if not configured.
+const DEFAULT_CACHE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many blocks to look up for one extents request, clients ask again
+/// for the rest.
+const MAX_EXTENT_BLOCKS: usize = 1024;
+
+/// How long to wait for a storage daemon, if not configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times the readahead the read cache holds, so blocks are not
+/// dropped before the reads reach them.
+const READ_CACHE_FACTOR: usize = 2;
+
+/// How often the leases on the images are renewed.
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(LOCK_LEASE.as_secs() / 4);
+
+/// The block device of an export, shared by all its connections.
+struct BlockDevice {
+    geometry: Geometry,
+    pool: PoolName,
     base_name: Vec<u8>,
+    /// Snapshots can't be written to.
+    read_only: bool,
+    /// Writes not sent yet, if the cache is enabled.
+    cache: Option<Mutex<WriteCache>>,
+    /// How many blocks to read ahead of sequential reads.
+    readahead: usize,
+    /// The blocks read ahead, if readahead is enabled.
+    read_cache: Option<Mutex<ReadCache>>,
+    /// Held while writing back the cache, or while zeroing, so that old
+    /// cached data doesn't get written over the zeros.
+    write_back_lock: Mutex<()>,
+    /// The lock on the image, unless it is read-only.
+    lease: Option<Arc<Lease>>,
+}
+
+/// Run the requests concurrently, at most `MAX_PARALLEL_REQUESTS` at a time.
+///
+/// Returns the results in order, or the first error.
+async fn run_parallel<T, F>(requests: impl IntoIterator<Item = F>) -> std::result::Result<Vec<T>, IoError>
+where
+    T: Send + 'static,
+    F: Future<Output = std::result::Result<T, IoError>> + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    let mut results = Vec::new();
+    for (i, request) in requests.into_iter().enumerate() {
+        if tasks.len() >= MAX_PARALLEL_REQUESTS {
+            let (j, result) = tasks.join_next().await.unwrap().map_err(IoError::other)?;
+            results.push((j, result?));
+        }
+        tasks.spawn(async move { (i, request.await) });
+    }
+    while let Some(res) = tasks.join_next().await {
+        let (j, result) = res.map_err(IoError::other)?;
+        results.push((j, result?));
+    }
+    results.sort_by_key(|&(i, _)| i);
+    Ok(results.into_iter().map(|(_, r)| r).collect())
+}
+
+impl BlockDevice {
+    /// Refuse to write unless we still hold the lock on the image.
+    fn check_lease(&self) -> Result<()> {
+        match &self.lease {
+            Some(lease) => lease.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// The object holding a block.
+    fn block_object_id(&self, block_num: usize) -> ObjectId {
+        block_object_id(&self.base_name, block_num as u64)
+    }
+
+    /// The parts of blocks to read or write for a range.
+    fn parts(&self, offset: usize, size: usize) -> ListBlocks {
+        list_blocks(self.geometry.block_size, self.geometry.stripe_unit, offset, size)
+    }
+
+    fn read(&self, runtime: &Runtime, client: &Client, offset: usize, buf: &mut [u8]) -> Result<()> {
+        // Reads are not limited by the stripe unit, read each block at once
+        let block_size = self.geometry.block_size;
+        let parts: Vec<_> = list_blocks(block_size, block_size, offset, buf.len()).collect();
+
+        // Get the cached writes first, so those written back while we read
+        // are not missed
+        let cached: Vec<Option<CachedBlock>> = match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                parts.iter().map(|p| cache.get(p.block_num())).collect()
+            }
+            None => vec![None; parts.len()],
+        };
+
+        // Use the blocks read ahead, and pick the next ones to read ahead
+        let mut missing = Vec::new();
+        let mut prefetch = Vec::new();
+        let mut generation = 0;
+        match &self.read_cache {
+            Some(read_cache) => {
+                let mut read_cache = read_cache.lock().unwrap();
+                for part in &parts {
+                    let part_buf = &mut buf[part.buf_start()..part.buf_end()];
+                    if !read_cache.read(part.block_num(), part.block_offset(), part_buf) {
+                        missing.push(part);
+                    }
+                }
+                if read_cache.record_read(offset, buf.len()) {
+                    let next = parts.last().map_or(0, |p| p.block_num() + 1);
+                    let end = (next + self.readahead).min(self.geometry.num_blocks() as usize);
+                    prefetch.extend((next..end).filter(|&b| !read_cache.contains(b)));
+                }
+                generation = read_cache.generation();
+            }
+            None => missing.extend(&parts),
+        }
+        METRICS.readahead_hits.inc_by((parts.len() - missing.len()) as u64);
+        METRICS.block_reads.inc_by(missing.len() as u64);
+        METRICS.readahead_blocks.inc_by(prefetch.len() as u64);
+
+        let requests = missing.iter()
+            .map(|part| (part.block_num(), part.block_offset(), part.size()))
+            .chain(prefetch.iter().map(|&block_num| (block_num, 0, block_size)))
+            .map(|(block_num, offset, size)| {
+                let client = client.clone();
+                let object_id = self.block_object_id(block_num);
+                async move { client.read_part(&object_id, offset as u32, size as u32).await }
+            });
+        let mut results = runtime
+            .block_on(run_parallel(requests))
+            .map_err(|e| storage_error("Error reading block", e))?;
+
+        if let Some(read_cache) = &self.read_cache {
+            let mut read_cache = read_cache.lock().unwrap();
+            for (block_num, data) in prefetch.into_iter().zip(results.drain(missing.len()..)) {
+                read_cache.insert(block_num, data, generation);
+            }
+        }
+        for (part, data) in missing.iter().zip(results) {
+            fill_from(&mut buf[part.buf_start()..part.buf_end()], data.as_deref());
+        }
+        for (part, cached) in parts.iter().zip(cached) {
+            if let Some(cached) = cached {
+                cached.read(part.block_offset(), &mut buf[part.buf_start()..part.buf_end()]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find which blocks of a range exist, looking at `MAX_EXTENT_BLOCKS` at
+    /// most.
+    ///
+    /// Returns the first block, and whether each block exists.
+    fn block_status(&self, runtime: &Runtime, client: &Client, offset: usize, size: usize) -> Result<(usize, Vec<bool>)> {
+        let block_size = self.geometry.block_size;
+        let first = offset / block_size;
+        let end = (offset + size).div_ceil(block_size).min(first + MAX_EXTENT_BLOCKS);
+
+        // Cached writes might not be in the storage yet, look at them first
+        let cached: Vec<bool> = match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                (first..end).map(|b| cache.contains(b)).collect()
+            }
+            None => vec![false; end - first],
+        };
+
+        let requests = (first..end).map(|block_num| {
+            let client = client.clone();
+            let object_id = self.block_object_id(block_num);
+            async move { Ok(client.stat_object(&object_id).await?.is_some()) }
+        });
+        let exists = runtime
+            .block_on(run_parallel(requests))
+            .map_err(|e| storage_error("Error getting block status", e))?;
+        Ok((first, exists.into_iter().zip(cached).map(|(e, c)| e || c).collect()))
+    }
+
+    /// Forget the blocks read ahead, after they have been written to.
+    fn invalidate(&self, blocks: impl IntoIterator<Item = usize>) {
+        if let Some(read_cache) = &self.read_cache {
+            read_cache.lock().unwrap().invalidate(blocks);
+        }
+    }
+
+    /// Write, through the cache if it is enabled.
+    ///
+    /// Returns whether the cache is full.
+    fn write(&self, runtime: &Runtime, client: &Client, offset: usize, buf: &[u8]) -> Result<bool> {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            for part in self.parts(offset, buf.len()) {
+                cache.write(part.block_num(), part.block_offset(), &buf[part.buf_start()..part.buf_end()]);
+                METRICS.cached_writes.inc();
+            }
+            return Ok(cache.is_full());
+        }
+
+        let requests = self.parts(offset, buf.len()).map(|part| {
+            METRICS.block_writes.inc();
+            let client = client.clone();
+            let object_id = self.block_object_id(part.block_num());
+            let offset = part.block_offset() as u32;
+            let data = buf[part.buf_start()..part.buf_end()].to_owned();
+            async move { client.write_part(&object_id, offset, &data).await }
+        });
+        let res = runtime.block_on(run_parallel(requests));
+        self.invalidate(self.parts(offset, buf.len()).map(|p| p.block_num()));
+        res.map_err(|e| storage_error("Error writing block", e))?;
+        Ok(false)
+    }
+
+    /// Zero a range, deleting the blocks it covers entirely.
+    ///
+    /// Missing blocks read as zeros, so they don't use any space. Blocks only
+    /// partly covered get zeros written over the range, rather than being
+    /// read and deleted if all zeros, which would lose concurrent writes to
+    /// the rest of the block.
+    fn zero_range(&self, runtime: &Runtime, client: &Client, offset: usize, size: usize) -> Result<()> {
+        let _guard = self.write_back_lock.lock().unwrap();
+        let block_size = self.geometry.block_size;
+        let parts: Vec<_> = list_blocks(block_size, block_size, offset, size).collect();
+
+        // Cached writes are sent later, zero them too
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            for part in &parts {
+                if part.is_whole_block() {
+                    cache.discard(part.block_num());
+                } else {
+                    cache.zero(part.block_num(), part.block_offset(), part.size());
+                }
+            }
+        }
+
+        METRICS.block_deletes.inc_by(parts.len() as u64);
+        let requests = parts.iter().map(|part| {
+            let client = client.clone();
+            let object_id = self.block_object_id(part.block_num());
+            let whole_block = part.is_whole_block();
+            let (offset, size) = (part.block_offset(), part.size());
+            let stripe_unit = self.geometry.stripe_unit;
+            async move {
+                if whole_block {
+                    return client.delete_object(&object_id).await;
+                }
+
+                // Don't create missing blocks, and don't make short ones longer
+                let len = match client.stat_object(&object_id).await? {
+                    Some(stat) => stat.size as usize,
+                    None => return Ok(()),
+                };
+                let end = (offset + size).min(len);
+                if offset >= end {
+                    return Ok(());
+                }
+
+                // Only write the zeros, in requests of at most a stripe unit
+                let zeros = vec![0; stripe_unit.min(end - offset)];
+                for start in (offset..end).step_by(stripe_unit) {
+                    let chunk = stripe_unit.min(end - start);
+                    client.write_part(&object_id, start as u32, &zeros[..chunk]).await?;
+                }
+                Ok(())
+            }
+        });
+        let res = runtime.block_on(run_parallel(requests));
+        self.invalidate(parts.iter().map(|p| p.block_num()));
+        res.map_err(|e| storage_error("Error zeroing block", e))?;
+        Ok(())
+    }
+
+    /// Send the writes held in the cache.
+    ///
+    /// The writes that could not be sent stay in the cache.
+    fn write_back(&self, runtime: &Runtime, client: &Client) -> Result<()> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let _guard = self.write_back_lock.lock().unwrap();
+        let writes = {
+            let mut cache = cache.lock().unwrap();
+            if cache.is_empty() {
+                return Ok(());
+            }
+            self.check_lease()?;
+            cache.start_write_back()
+        };
+        debug!("Writing back {} cached writes", writes.len());
+        METRICS.write_backs.inc();
+
+        // Split the writes in stripe units
+        let block_size = self.geometry.block_size;
+        let requests = writes.iter().flat_map(|write| {
+            let start = write.block_num * block_size + write.offset;
+            self.parts(start, write.data.len()).map(move |part| (write, part))
+        }).map(|(write, part)| {
+            METRICS.block_writes.inc();
+            let client = client.clone();
+            let object_id = self.block_object_id(write.block_num);
+            let offset = part.block_offset() as u32;
+            let data = write.data[part.buf_start()..part.buf_end()].to_owned();
+            async move {
+                if part.is_whole_block() {
+                    client.write_object(&object_id, &data).await
+                } else {
+                    client.write_part(&object_id, offset, &data).await
+                }
+            }
+        });
+        let res = runtime.block_on(run_parallel(requests));
+
+        // Drop the blocks read ahead before the new data leaves the cache,
+        // so reads never see the older data
+        self.invalidate(writes.iter().map(|w| w.block_num));
+        cache.lock().unwrap().finish_write_back(res.is_ok());
+        res.map_err(|e| storage_error("Error writing block", e))?;
+        Ok(())
+    }
 }
 
 lazy_static! {
-    static ref DEVICE: Mutex<Option<BlockDeviceClient>> = Mutex::new(None);
+    /// The devices of the exports opened so far, by export name.
+    static ref DEVICES: Mutex<HashMap<String, Arc<BlockDevice>>> = Mutex::new(HashMap::new());
 }
 
-#[derive(Default)]
+lazy_static! {
+    /// The runtime shared by the connections, started on first use.
+    static ref RUNTIME: Arc<Runtime> = {
+        let mut runtime = tokio::runtime::Builder::new_multi_thread();
+        runtime.enable_all();
+        Arc::new(runtime.build().expect("Error starting runtime"))
+    };
+}
+
+/// A connection to the storage daemons.
+struct Connection {
+    runtime: Arc<Runtime>,
+    client: Client,
+}
+
+impl Connection {
+    /// Connect using the shared runtime.
+    ///
+    /// Threads started before nbdkit goes into the background don't
+    /// survive, so this is only used from connections.
+    fn new(pool: PoolName) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
+        Connection::with_runtime(RUNTIME.clone(), pool)
+    }
+
+    /// Connect with a runtime of our own, which works at any time.
+    fn standalone(pool: PoolName) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
+        let mut runtime = tokio::runtime::Builder::new_current_thread();
+        runtime.enable_all();
+        Connection::with_runtime(Arc::new(runtime.build()?), pool)
+    }
+
+    fn with_runtime(runtime: Arc<Runtime>, pool: PoolName) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
+        let (address, timeout) = {
+            let config = CONFIG.lock().unwrap();
+            (config.storage_daemon_address.unwrap(), config.timeout.unwrap_or(DEFAULT_TIMEOUT))
+        };
+        let mut client = runtime.block_on(create_client(address, pool))?;
+        if !timeout.is_zero() {
+            client = client.with_timeout(timeout);
+        }
+        Ok(Connection { runtime, client })
+    }
+}
+
+/// The export a client opened, with its own connection to the storage
+/// daemons.
+struct Export {
+    device: Arc<BlockDevice>,
+    conn: Connection,
+}
+
+/// A client of the gateway.
 struct NbdGateway {
-    // Box::new doesn't allocate anything unless we put some dummy
-    // fields here.  In a real implementation you would put per-handle
-    // data here as required.
-    _not_used: i32,
+    export: std::result::Result<Export, String>,
+}
+
+impl NbdGateway {
+    fn export(&self) -> Result<&Export> {
+        self.export
+            .as_ref()
+            .map_err(|e| Error::new(libc::EIO, format!("Error opening export: {}", e)))
+    }
+
+    fn writable_export(&self) -> Result<&Export> {
+        let export = self.export()?;
+        if export.device.read_only {
+            return Err(Error::new(libc::EROFS, "Snapshots are read-only"));
+        }
+        export.device.check_lease()?;
+        Ok(export)
+    }
+}
+
+impl Drop for NbdGateway {
+    fn drop(&mut self) {
+        // Don't keep cached writes around when the client disconnects
+        if let Ok(export) = &self.export {
+            if let Err(e) = export.device.write_back(&export.conn.runtime, &export.conn.client) {
+                warn!("Error writing back cache on close: {}", e);
+            }
+        }
+    }
+}
+
+/// Where the image of an export is.
+#[derive(Clone)]
+struct ExportConfig {
+    pool: PoolName,
+    /// The image, or the snapshot image if `read_only`.
+    image: Vec<u8>,
+    read_only: bool,
+}
+
+impl ExportConfig {
+    fn new(pool: PoolName, image: &[u8], snapshot: Option<&str>) -> ExportConfig {
+        match snapshot {
+            Some(snapshot) => ExportConfig { pool, image: snapshot_name(image, snapshot), read_only: true },
+            None => ExportConfig { pool, image: image.to_owned(), read_only: false },
+        }
+    }
+
+    /// Parse the value of the `export` option, `NAME:POOL/IMAGE[@SNAPSHOT]`.
+    fn parse(value: &str) -> Option<(String, ExportConfig)> {
+        let (name, location) = value.split_once(':')?;
+        let (pool, image) = location.split_once('/')?;
+        let (image, snapshot) = match image.rsplit_once('@') {
+            Some((image, snapshot)) => (image, Some(snapshot)),
+            None => (image, None),
+        };
+        if pool.is_empty() || image.is_empty() || snapshot == Some("") {
+            return None;
+        }
+        Some((
+            name.to_owned(),
+            ExportConfig::new(PoolName(pool.to_owned()), image.as_bytes(), snapshot),
+        ))
+    }
 }
 
 #[derive(Default)]
@@ -39,35 +523,321 @@ struct NbdGatewayConfig {
     storage_daemon_address: Option<SocketAddr>,
     pool: Option<PoolName>,
     image: Option<Vec<u8>>,
+    snapshot: Option<String>,
+    exports: HashMap<String, ExportConfig>,
     metrics: Option<SocketAddr>,
+    timeout: Option<Duration>,
+    cache_blocks: usize,
+    cache_interval: Option<Duration>,
+    readahead: usize,
+    force: bool,
 }
 
 lazy_static! {
     static ref CONFIG: Mutex<NbdGatewayConfig> = Mutex::new(NbdGatewayConfig::default());
 }
 
-async fn read_image_metadata(client: &Client, base_name: &[u8]) -> Result<u64> {
-    // Get metadata object
-    let metadata = client.read_object(&ObjectId(base_name.to_owned())).await?;
-    let metadata = metadata.ok_or(Error::new(
+/// Turn an error from the storage daemon into the closest errno.
+///
+/// Only a few errors exist in NBD, nbdkit sends the others as EINVAL, which
+/// would blame the client.
+fn storage_error(msg: &str, e: IoError) -> Error {
+    if e.kind() == std::io::ErrorKind::TimedOut {
+        return Error::new(libc::EIO, format!("{}: storage daemon timed out", msg));
+    }
+    let errno = match ErrorCode::get(&e) {
+        Some(ErrorCode::QuotaExceeded) => libc::ENOSPC,
+        Some(ErrorCode::Unauthorized) => libc::EPERM,
+        _ => libc::EIO,
+    };
+    Error::new(errno, format!("{}: {}", msg, e))
+}
+
+/// Turn the status of consecutive blocks into extents within a range.
+///
+/// Returns the offset, length, and whether it is allocated for each extent,
+/// merging the blocks with the same status.
+fn block_extents(block_size: usize, offset: usize, size: usize, first: usize, exists: &[bool]) -> Vec<(u64, u64, bool)> {
+    let end = offset + size;
+    let mut extents: Vec<(u64, u64, bool)> = Vec::new();
+    for (i, &allocated) in exists.iter().enumerate() {
+        let start = ((first + i) * block_size).max(offset);
+        let stop = ((first + i + 1) * block_size).min(end);
+        match extents.last_mut() {
+            Some(last) if last.2 == allocated => last.1 += (stop - start) as u64,
+            _ => extents.push((start as u64, (stop - start) as u64, allocated)),
+        }
+    }
+    extents
+}
+
+/// Make the writes durable on the storage daemons.
+fn flush_device(runtime: &Runtime, client: &Client) -> Result<()> {
+    runtime
+        .block_on(client.flush())
+        .map_err(|e| storage_error("Error flushing", e))
+}
+
+/// Write back the cache periodically, so writes don't stay there forever.
+fn write_back_task(device: Arc<BlockDevice>, interval: Duration) {
+    let conn = match Connection::new(device.pool.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error connecting client, cache won't be written back periodically: {}", e);
+            return;
+        }
+    };
+    loop {
+        std::thread::sleep(interval);
+        if let Err(e) = device.write_back(&conn.runtime, &conn.client) {
+            warn!("Error writing back cache: {}", e);
+        }
+    }
+}
+
+async fn read_image_metadata(client: &Client, base_name: &[u8]) -> Result<Geometry> {
+    let geometry = read_image(client, base_name).await?.ok_or(Error::new(
         libc::ENOENT,
         "No such object in storage",
     ))?;
 
-    // Read it
-    let mut metadata = Cursor::new(&metadata);
-    let size = metadata.read_u64::<BigEndian>()?;
+    info!(
+        "Found block device, size={} block_size={} stripe_unit={}",
+        geometry.size, geometry.block_size, geometry.stripe_unit,
+    );
+    Ok(geometry)
+}
+
+/// The lock this gateway holds on an image.
+struct Lease {
+    pool: PoolName,
+    image: Vec<u8>,
+    /// Who we are, as written in the lock.
+    holder: String,
+    /// Until when writes are sent, `None` once another gateway took the lock
+    /// over. This is well before the lease ends for other gateways, so their
+    /// clocks don't have to agree with ours.
+    valid_until: Mutex<Option<Instant>>,
+}
+
+impl Lease {
+    /// Record that the lease was taken or renewed, by a request sent at
+    /// `start`.
+    fn renewed(&self, start: Instant) {
+        *self.valid_until.lock().unwrap() = Some(start + LOCK_LEASE / 2);
+    }
+
+    /// Fail writes once the lease is lost.
+    fn check(&self) -> Result<()> {
+        let image = String::from_utf8_lossy(&self.image);
+        match *self.valid_until.lock().unwrap() {
+            None => Err(Error::new(libc::EROFS, format!("Lock on image {:?} was taken over", image))),
+            Some(until) if Instant::now() >= until => {
+                Err(Error::new(libc::EIO, format!("Lease on image {:?} could not be renewed", image)))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
 
-    info!("Found block device, size={}", size);
-    Ok(size)
+lazy_static! {
+    /// The images locked by this gateway.
+    static ref LOCKS: Mutex<Vec<Arc<Lease>>> = Mutex::new(Vec::new());
+}
+
+/// Describe this gateway, for the locks it takes.
+fn lock_holder() -> String {
+    let mut hostname = [0u8; 256];
+    let res = unsafe { libc::gethostname(hostname.as_mut_ptr() as *mut libc::c_char, hostname.len()) };
+    let hostname = match res {
+        0 => {
+            let len = hostname.iter().position(|&b| b == 0).unwrap_or(hostname.len());
+            String::from_utf8_lossy(&hostname[..len]).into_owned()
+        }
+        _ => "unknown host".to_owned(),
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("nbd-gateway on {} (pid {}), started at {}", hostname, std::process::id(), now)
+}
+
+/// Lock the images of all the exports, so no other gateway writes to them.
+fn lock_exports(exports: &[ExportConfig], force: bool) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let holder = lock_holder();
+    for export in exports {
+        let mut locks = LOCKS.lock().unwrap();
+        if locks.iter().any(|l| l.pool == export.pool && l.image == export.image) {
+            continue;
+        }
+        let conn = Connection::standalone(export.pool.clone())?;
+        let start = Instant::now();
+        conn.runtime.block_on(lock_image(&conn.client, &export.image, &holder, force))?;
+        let lease = Lease {
+            pool: export.pool.clone(),
+            image: export.image.clone(),
+            holder: holder.clone(),
+            valid_until: Mutex::new(None),
+        };
+        lease.renewed(start);
+        locks.push(Arc::new(lease));
+    }
+    Ok(())
+}
+
+/// Release the locks taken by `lock_exports()`.
+fn unlock_exports() {
+    for lease in LOCKS.lock().unwrap().drain(..) {
+        let res = Connection::standalone(lease.pool.clone()).and_then(|conn| {
+            Ok(conn.runtime.block_on(unlock_image(&conn.client, &lease.image, &lease.holder))?)
+        });
+        if let Err(e) = res {
+            warn!("Error releasing lock on image {:?}: {}", String::from_utf8_lossy(&lease.image), e);
+        }
+    }
+}
+
+/// Renew the leases on the images, with a connection to each pool.
+///
+/// The leases that another gateway took over are marked lost, so that writes
+/// fail from then on.
+fn renew_leases(conns: &mut HashMap<PoolName, Connection>) {
+    let leases = LOCKS.lock().unwrap().clone();
+    for lease in leases {
+        if lease.valid_until.lock().unwrap().is_none() {
+            continue;
+        }
+        let conn = match conns.entry(lease.pool.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match Connection::new(lease.pool.clone()) {
+                Ok(c) => e.insert(c),
+                Err(err) => {
+                    warn!("Error connecting client to renew leases: {}", err);
+                    continue;
+                }
+            },
+        };
+        let start = Instant::now();
+        match conn.runtime.block_on(renew_image_lock(&conn.client, &lease.image, &lease.holder)) {
+            Ok(()) => lease.renewed(start),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                error!("{}, refusing writes", e);
+                *lease.valid_until.lock().unwrap() = None;
+            }
+            Err(e) => warn!("Error renewing lease on image {:?}: {}", String::from_utf8_lossy(&lease.image), e),
+        }
+    }
+}
+
+/// Start the metrics server and the renewal of the leases.
+///
+/// Threads started before nbdkit goes into the background don't survive, so
+/// this is done by the first connection, which also renews the leases right
+/// away since they might have ended while no client was connected.
+fn start_background_tasks() {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        let metrics = CONFIG.lock().unwrap().metrics;
+        if let Some(addr) = metrics {
+            if let Err(e) = start_http_server(addr) {
+                error!("Error starting metrics server: {}", e);
+            }
+        }
+
+        let mut conns = HashMap::new();
+        renew_leases(&mut conns);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(LEASE_RENEWAL_INTERVAL);
+            renew_leases(&mut conns);
+        });
+    });
+}
+
+/// Open the export the client asked for.
+///
+/// Its device is set up by the first client, since images can be created
+/// after the gateway starts.
+fn open_export() -> std::result::Result<Export, Box<dyn std::error::Error>> {
+    start_background_tasks();
+    let name = export_name()?;
+    let config = CONFIG.lock().unwrap().exports.get(&name).cloned();
+    let config = config.ok_or_else(|| format!("No export named {:?}", name))?;
+    let conn = Connection::new(config.pool.clone())?;
+
+    let mut devices = DEVICES.lock().unwrap();
+    if let Some(device) = devices.get(&name) {
+        return Ok(Export { device: device.clone(), conn });
+    }
+
+    // Read the geometry from the metadata object
+    let geometry = conn.runtime
+        .block_on(read_image_metadata(&conn.client, &config.image))
+        .map_err(|e| format!("Error getting metadata object: {}", e))?;
+
+    let (cache_blocks, cache_interval, readahead) = {
+        let config = CONFIG.lock().unwrap();
+        (config.cache_blocks, config.cache_interval.unwrap_or(DEFAULT_CACHE_INTERVAL), config.readahead)
+    };
+    let cache = match (cache_blocks, config.read_only) {
+        (0, _) | (_, true) => None,
+        (blocks, false) => Some(Mutex::new(WriteCache::new(blocks, geometry.block_size))),
+    };
+    let read_cache = match readahead {
+        0 => None,
+        blocks => Some(Mutex::new(ReadCache::new(blocks * READ_CACHE_FACTOR))),
+    };
+    let lease = LOCKS.lock().unwrap().iter()
+        .find(|l| l.pool == config.pool && l.image == config.image)
+        .cloned();
+    let device = Arc::new(BlockDevice {
+        geometry,
+        pool: config.pool,
+        base_name: config.image,
+        read_only: config.read_only,
+        cache,
+        readahead,
+        read_cache,
+        write_back_lock: Mutex::new(()),
+        lease,
+    });
+
+    // Threads started before nbdkit goes into the background don't survive,
+    // but this runs in a connection
+    if device.cache.is_some() {
+        info!(
+            "Write-back cache of {} blocks for export {:?}, written back every {:?}",
+            cache_blocks, name, cache_interval,
+        );
+        let device = device.clone();
+        std::thread::spawn(move || write_back_task(device, cache_interval));
+    }
+
+    devices.insert(name, device.clone());
+    Ok(Export { device, conn })
 }
 
 const CONFIG_HELP: &'static str = "\
 Configuration options (pass KEY=VALUE on command line):
     storage_daemon_address: address and UDP port of the storage daemon
-    pool: name of the pool
-    image: base name of the block device objects in the pool
-    metrics: address on which to serve metrics in Prometheus format
+    export: NAME:POOL/IMAGE[@SNAPSHOT], serve the image with this base name
+        from that pool under this export name, or a snapshot of it read-only
+        (can be repeated)
+    pool: name of the pool of the default export (empty name)
+    image: base name of the image of the default export
+    snapshot: serve this snapshot of the image read-only as the default
+        export
+    metrics: address on which to serve metrics in Prometheus format, from
+        the first connection
+    cache_blocks: number of blocks to hold in the write-back cache (default 0,
+        disabled)
+    cache_interval: seconds after which cached writes are written back
+        (default 5)
+    timeout: seconds after which a request to a storage daemon fails with EIO
+        (default 30, 0 to wait forever)
+    readahead: number of blocks to read ahead of sequential reads (default 0,
+        disabled)
+    force: take the locks on the images even if another gateway holds them,
+        for example after a crash (default false)
 ";
 
 impl Server for NbdGateway {
@@ -93,9 +863,34 @@ impl Server for NbdGateway {
             CONFIG.lock().unwrap().pool = Some(PoolName(value.to_owned()));
         } else if key == "image" {
             CONFIG.lock().unwrap().image = Some(value.as_bytes().to_owned());
+        } else if key == "snapshot" {
+            CONFIG.lock().unwrap().snapshot = Some(value.to_owned());
+        } else if key == "export" {
+            let (name, export) = ExportConfig::parse(value)
+                .ok_or(Error::new(libc::EINVAL, "Invalid export, should be NAME:POOL/IMAGE"))?;
+            let mut config = CONFIG.lock().unwrap();
+            if config.exports.contains_key(&name) {
+                return Err(Error::new(libc::EINVAL, format!("Export {:?} given twice", name)));
+            }
+            config.exports.insert(name, export);
         } else if key == "metrics" {
             let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid address for the metrics"))?;
             CONFIG.lock().unwrap().metrics = Some(value);
+        } else if key == "cache_blocks" {
+            let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid number of cache blocks"))?;
+            CONFIG.lock().unwrap().cache_blocks = value;
+        } else if key == "timeout" {
+            let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid timeout"))?;
+            CONFIG.lock().unwrap().timeout = Some(Duration::from_secs(value));
+        } else if key == "cache_interval" {
+            let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid cache interval"))?;
+            CONFIG.lock().unwrap().cache_interval = Some(Duration::from_secs(value));
+        } else if key == "force" {
+            let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid value for force, should be true or false"))?;
+            CONFIG.lock().unwrap().force = value;
+        } else if key == "readahead" {
+            let value = value.parse().map_err(|_| Error::new(libc::EINVAL, "Invalid number of readahead blocks"))?;
+            CONFIG.lock().unwrap().readahead = value;
         } else {
             return Err(Error::new(
                 libc::EINVAL,
@@ -117,119 +912,285 @@ impl Server for NbdGateway {
             logger_builder.init();
         }
 
-        let config = CONFIG.lock().unwrap();
+        let mut config = CONFIG.lock().unwrap();
         if config.storage_daemon_address.is_none() {
-            Err(Error::new(
+            return Err(Error::new(
                 libc::EINVAL,
                 "Missing option storage_daemon_address",
-            ))
-        } else if config.pool.is_none() {
-            Err(Error::new(libc::EINVAL, "Missing option pool"))
-        } else if config.image.is_none() {
-            Err(Error::new(libc::EINVAL, "Missing option image"))
-        } else {
-            Ok(())
-        }?;
-
-        if let Some(addr) = config.metrics {
-            start_http_server(addr);
+            ));
         }
 
-        let mut device = DEVICE.lock().unwrap();
-        if device.is_none() {
-            let base_name = config.image.as_ref().unwrap().clone();
-
-            // Initialize tokio
-            let mut runtime = tokio::runtime::Builder::new_current_thread();
-            runtime.enable_all();
-            let runtime = runtime.build().unwrap();
+        // The pool, image, and snapshot options are the default export
+        match (config.pool.clone(), config.image.clone()) {
+            (Some(pool), Some(image)) => {
+                if config.exports.contains_key("") {
+                    return Err(Error::new(libc::EINVAL, "Default export given twice"));
+                }
+                let export = ExportConfig::new(pool, &image, config.snapshot.as_deref());
+                config.exports.insert(String::new(), export);
+            }
+            (None, None) if config.snapshot.is_none() => {}
+            _ => return Err(Error::new(libc::EINVAL, "Options pool and image go together")),
+        }
+        if config.exports.is_empty() {
+            return Err(Error::new(libc::EINVAL, "Missing option export (or pool and image)"));
+        }
 
-            // Create client
-            let client = runtime.block_on(create_client(
-                config.storage_daemon_address.unwrap(),
-                config.pool.as_ref().unwrap().clone(),
-            ));
-            let client = client
-                .map_err(|e| Error::new(libc::EIO, format!("Error connecting client: {}", e)))?;
-
-            // Read size from the metadata object
-            let size = runtime
-                .block_on(read_image_metadata(&client, &base_name))
-                .map_err(|e| {
-                    Error::new(libc::EIO, format!("Error getting metadata object: {}", e))
-                })?;
-
-            // Set the global
-            *device = Some(BlockDeviceClient {
-                runtime,
-                client,
-                size,
-                base_name,
-            });
+        // Refuse to start if another gateway uses the images, snapshots
+        // can be shared
+        let exports: Vec<ExportConfig> = config.exports.values().filter(|e| !e.read_only).cloned().collect();
+        let force = config.force;
+        drop(config);
+        if let Err(e) = lock_exports(&exports, force) {
+            unlock_exports();
+            return Err(Error::new(libc::EBUSY, format!("Error locking image: {}", e)));
         }
+
         Ok(())
     }
 
+    fn unload() {
+        unlock_exports();
+    }
+
     fn open(_readonly: bool) -> Box<dyn Server> {
-        Box::new(NbdGateway::default())
+        let export = open_export().map_err(|e| {
+            error!("Error opening export: {}", e);
+            e.to_string()
+        });
+        Box::new(NbdGateway { export })
     }
 
     fn get_size(&self) -> Result<i64> {
-        Ok(DEVICE.lock().unwrap().as_ref().unwrap().size as i64)
+        Ok(self.export()?.device.geometry.size as i64)
     }
 
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
-        let device = DEVICE.lock().unwrap();
-        let device = device.as_ref().unwrap();
-        let offset = offset as usize;
-
-        for part in list_blocks(offset, buf.len()) {
-            let mut object_id = device.base_name.clone();
-            write!(object_id, "_{}", part.block_num()).unwrap();
-            let object_id = ObjectId(object_id);
-            let data = device.runtime.block_on(device.client.read_part(
-                &object_id,
-                part.block_offset() as u32,
-                part.size() as u32,
-            ));
-            let data = match data {
-                Err(e) => return Err(Error::new(libc::EIO, format!("Error reading block: {}", e))),
-                Ok(None) => vec![0; part.size()],
-                Ok(Some(d)) => d,
-            };
-            buf[part.buf_start()..part.buf_end()].clone_from_slice(&data);
-        }
+        measure("read", buf.len(), || {
+            let export = self.export()?;
+            let conn = &export.conn;
 
-        Ok(())
+            // Use the same trace ID for all the blocks
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Reading {} bytes at {}, trace {}", buf.len(), offset, trace_id);
+
+            export.device.read(&conn.runtime, &client, offset as usize, buf)
+        })
     }
 
     fn thread_model() -> Result<ThreadModel> where Self: Sized {
         Ok(ThreadModel::Parallel)
     }
 
-    fn write_at(&self, buf: &[u8], offset: u64, _flags: Flags) -> Result<()> {
-        let device = DEVICE.lock().unwrap();
-        let device = device.as_ref().unwrap();
-        let offset = offset as usize;
-
-        for part in list_blocks(offset, buf.len()) {
-            let mut object_id = device.base_name.clone();
-            write!(object_id, "_{}", part.block_num()).unwrap();
-            let object_id = ObjectId(object_id);
-            let data = &buf[part.buf_start()..part.buf_end()];
-            let res = device.runtime.block_on(device.client.write_part(
-                &object_id,
-                part.block_offset() as u32,
-                data,
-            ));
-            match res {
-                Err(e) => return Err(Error::new(libc::EIO, format!("Error reading block: {}", e))),
-                Ok(()) => {}
+    fn write_at(&self, buf: &[u8], offset: u64, flags: Flags) -> Result<()> {
+        measure("write", buf.len(), || {
+            let export = self.writable_export()?;
+            let conn = &export.conn;
+
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Writing {} bytes at {}, trace {}", buf.len(), offset, trace_id);
+
+            let cache_full = export.device.write(&conn.runtime, &client, offset as usize, buf)?;
+
+            // Forced unit access, the write must be durable before we reply
+            if flags.contains(Flags::FUA) {
+                export.device.write_back(&conn.runtime, &client)?;
+                flush_device(&conn.runtime, &client)?;
+            } else if cache_full {
+                export.device.write_back(&conn.runtime, &client)?;
             }
-        }
 
-        Ok(())
+            Ok(())
+        })
     }
+
+    fn trim(&self, count: u32, offset: u64, flags: Flags) -> Result<()> {
+        measure("trim", count as usize, || {
+            let export = self.writable_export()?;
+            let conn = &export.conn;
+
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Trimming {} bytes at {}, trace {}", count, offset, trace_id);
+
+            // Trimmed ranges read as zeros, so this is the same as zeroing
+            export.device.zero_range(&conn.runtime, &client, offset as usize, count as usize)?;
+            if flags.contains(Flags::FUA) {
+                export.device.write_back(&conn.runtime, &client)?;
+                flush_device(&conn.runtime, &client)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn zero(&self, count: u32, offset: u64, flags: Flags) -> Result<()> {
+        measure("zero", count as usize, || {
+            let export = self.writable_export()?;
+            let conn = &export.conn;
+
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Zeroing {} bytes at {}, trace {}", count, offset, trace_id);
+
+            // Deleting blocks is allowed even without MAY_TRIM, since the guest
+            // can't tell the difference
+            export.device.zero_range(&conn.runtime, &client, offset as usize, count as usize)?;
+            if flags.contains(Flags::FUA) {
+                export.device.write_back(&conn.runtime, &client)?;
+                flush_device(&conn.runtime, &client)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn can_write(&self) -> Result<bool> {
+        Ok(!self.export()?.device.read_only)
+    }
+
+    fn can_trim(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn can_zero(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn flush(&self) -> Result<()> {
+        measure("flush", 0, || {
+            let export = self.export()?;
+            let conn = &export.conn;
+
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Flushing, trace {}", trace_id);
+            export.device.write_back(&conn.runtime, &client)?;
+            flush_device(&conn.runtime, &client)
+        })
+    }
+
+    fn can_extents(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn extents(&self, count: u32, offset: u64, flags: Flags, extent_handle: &mut ExtentHandle) -> Result<()> {
+        measure("extents", count as usize, || {
+            let export = self.export()?;
+            let conn = &export.conn;
+
+            let trace_id = TraceId::generate();
+            let client = conn.client.traced(trace_id);
+            debug!("Getting extents of {} bytes at {}, trace {}", count, offset, trace_id);
+
+            // Missing blocks are holes that read as zeros
+            let (offset, count) = (offset as usize, count as usize);
+            let (first, exists) = export.device.block_status(&conn.runtime, &client, offset, count)?;
+            let block_size = export.device.geometry.block_size;
+            for (start, len, allocated) in block_extents(block_size, offset, count, first, &exists) {
+                let extent_type = if allocated { ExtentType::Allocated } else { ExtentType::HoleZero };
+                extent_handle.add(start, len, extent_type)?;
+                if flags.contains(Flags::REQ_ONE) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn can_flush(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn can_fua(&self) -> Result<FuaFlags> {
+        Ok(FuaFlags::Native)
+    }
+
+    fn can_cache(&self) -> Result<CacheFlags> {
+        // The cache only holds writes, there is nothing to prefetch into
+        Ok(CacheFlags::None)
+    }
+
+    fn can_multi_conn(&self) -> Result<bool> {
+        // All the connections share the cache, and a flush syncs all the
+        // daemons, so it covers the writes from every connection
+        Ok(true)
+    }
+}
+
+plugin!(NbdGateway {
+    thread_model,
+    write_at,
+    can_write,
+    trim,
+    zero,
+    can_trim,
+    can_zero,
+    can_extents,
+    extents,
+    flush,
+    can_flush,
+    can_fua,
+    can_cache,
+    can_multi_conn,
+    config,
+    config_complete,
+    unload
+});
+
+#[test]
+fn test_block_extents() {
+    // Partial blocks at both ends, merged blocks in the middle
+    let exists = [true, false, false, true];
+    assert_eq!(
+        block_extents(512, 1000, 1100, 1, &exists),
+        vec![(1000, 24, true), (1024, 1024, false), (2048, 52, true)],
+    );
+
+    // Only the blocks that were looked at are covered
+    assert_eq!(block_extents(512, 0, 4096, 0, &[false, false]), vec![(0, 1024, false)]);
 }
 
-plugin!(NbdGateway {thread_model, write_at, config, config_complete});
+#[test]
+fn test_export_config() {
+    let (name, export) = ExportConfig::parse("disk1:pool/images/disk1").unwrap();
+    assert_eq!(name, "disk1");
+    assert_eq!(export.pool, PoolName("pool".to_owned()));
+    assert_eq!(export.image, b"images/disk1");
+
+    assert!(!export.read_only);
+
+    // Empty name is the default export
+    let (name, _) = ExportConfig::parse(":pool/disk").unwrap();
+    assert_eq!(name, "");
+
+    // Snapshots are read-only
+    let (_, export) = ExportConfig::parse("backup:pool/disk@monday").unwrap();
+    assert_eq!(export.image, b"disk@monday");
+    assert!(export.read_only);
+    assert!(ExportConfig::parse("backup:pool/disk@").is_none());
+
+    assert!(ExportConfig::parse("disk1").is_none());
+    assert!(ExportConfig::parse("disk1:pool").is_none());
+    assert!(ExportConfig::parse("disk1:/disk").is_none());
+    assert!(ExportConfig::parse("disk1:pool/").is_none());
+}
+
+#[test]
+fn test_lease() {
+    let lease = Lease {
+        pool: PoolName("pool".to_owned()),
+        image: b"disk".to_vec(),
+        holder: lock_holder(),
+        valid_until: Mutex::new(None),
+    };
+    lease.renewed(Instant::now());
+    assert!(lease.check().is_ok());
+
+    // Writes stop before the lease ends, if it can't be renewed
+    lease.renewed(Instant::now() - LOCK_LEASE / 2);
+    assert!(lease.check().unwrap_err().to_string().contains("could not be renewed"));
+
+    *lease.valid_until.lock().unwrap() = None;
+    assert!(lease.check().unwrap_err().to_string().contains("taken over"));
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Holds the blocks read ahead of sequential reads.
+///
+/// Blocks are dropped from it when they are written to. A block that was
+/// being read while that happened is not kept, see `generation()`.
+pub struct ReadCache {
+    max_blocks: usize,
+    /// The content of the blocks, `None` if they don't exist (all zeros).
+    blocks: HashMap<usize, Option<Vec<u8>>>,
+    /// The blocks in the order they were added, oldest first.
+    order: VecDeque<usize>,
+    generation: u64,
+    /// Where the last read ended, to tell whether reads are sequential.
+    last_read_end: usize,
+}
+
+impl ReadCache {
+    pub fn new(max_blocks: usize) -> ReadCache {
+        ReadCache {
+            max_blocks,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+            last_read_end: 0,
+        }
+    }
+
+    /// Record a read, and return whether it continues the previous one.
+    pub fn record_read(&mut self, offset: usize, len: usize) -> bool {
+        let sequential = offset == self.last_read_end;
+        self.last_read_end = offset + len;
+        sequential
+    }
+
+    pub fn contains(&self, block_num: usize) -> bool {
+        self.blocks.contains_key(&block_num)
+    }
+
+    /// Copy part of a block, if it is in the cache.
+    pub fn read(&self, block_num: usize, offset: usize, buf: &mut [u8]) -> bool {
+        match self.blocks.get(&block_num) {
+            None => false,
+            Some(data) => {
+                fill_from(buf, data.as_deref().map(|d| d.get(offset..).unwrap_or(&[])));
+                true
+            }
+        }
+    }
+
+    /// Changes when blocks are written. Get it before reading blocks to
+    /// add, and pass it to `insert()`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Add a block, unless blocks were written since it was read.
+    pub fn insert(&mut self, block_num: usize, data: Option<Vec<u8>>, generation: u64) {
+        if generation != self.generation || self.max_blocks == 0 {
+            return;
+        }
+        if self.blocks.insert(block_num, data).is_none() {
+            self.order.push_back(block_num);
+        }
+        while self.order.len() > self.max_blocks {
+            let oldest = self.order.pop_front().unwrap();
+            self.blocks.remove(&oldest);
+        }
+    }
+
+    /// Drop blocks after they have been written to in the storage.
+    pub fn invalidate(&mut self, blocks: impl IntoIterator<Item = usize>) {
+        self.generation += 1;
+        for block_num in blocks {
+            if self.blocks.remove(&block_num).is_some() {
+                self.order.retain(|&b| b != block_num);
+            }
+        }
+    }
+}
+
+/// Copy data read from the storage, which can be missing or shorter than
+/// the buffer, in which case the rest reads as zeros.
+pub fn fill_from(buf: &mut [u8], data: Option<&[u8]>) {
+    let data = data.unwrap_or(&[]);
+    let len = data.len().min(buf.len());
+    buf[..len].clone_from_slice(&data[..len]);
+    buf[len..].fill(0);
+}
+
+#[test]
+fn test_readahead() {
+    let mut cache = ReadCache::new(2);
+
+    assert!(cache.record_read(0, 512));
+    assert!(cache.record_read(512, 1024));
+    assert!(!cache.record_read(4096, 512));
+    assert!(cache.record_read(4608, 512));
+
+    // Short and missing blocks read as zeros
+    let generation = cache.generation();
+    cache.insert(1, Some(b"abcdef".to_vec()), generation);
+    cache.insert(2, None, generation);
+    let mut buf = [b'.'; 6];
+    assert!(cache.read(1, 2, &mut buf));
+    assert_eq!(&buf, b"cdef\0\0");
+    assert!(cache.read(1, 10, &mut buf));
+    assert_eq!(&buf, &[0; 6]);
+    buf.fill(b'.');
+    assert!(cache.read(2, 0, &mut buf));
+    assert_eq!(&buf, &[0; 6]);
+    assert!(!cache.read(3, 0, &mut buf));
+
+    // The oldest block is dropped
+    cache.insert(3, None, generation);
+    assert!(!cache.contains(1));
+    assert!(cache.contains(2) && cache.contains(3));
+
+    // Blocks read before a write are not added
+    cache.invalidate([3]);
+    assert!(!cache.contains(3));
+    cache.insert(4, None, generation);
+    assert!(!cache.contains(4));
+    cache.insert(4, None, cache.generation());
+    assert!(cache.contains(4));
+}
@@ -0,0 +1,193 @@
+//! The admin protocol, spoken by `store admin` to the master.
+//!
+//! Admins connect over TLS and authenticate with a client certificate
+//! signed by the admin CA the master was given. Each message is a
+//! big-endian u32 length followed by JSON; the client sends a request and
+//! reads its response, as many times as it wants on a connection.
+//!
+//! Clients use the same messages on the master's client listener, without a
+//! certificate, to get the storage map of a pool and a ticket to open
+//! sessions with the daemons with `ClientRequest`.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, ServerName};
+
+use crate::{DeviceId, PoolName};
+use crate::master::{load_certs, load_key};
+use crate::storage_map::StorageMap;
+use crate::storage_map::builder::{DeviceSpec, FailureDomain};
+use crate::storage_map::overlay::DeviceStatus;
+
+/// Largest message accepted, which has to fit a storage map.
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminRequest {
+    Status,
+    ListDevices,
+    /// Add a device, or change its location, weight, or address.
+    AddDevice { spec: DeviceSpec, address: SocketAddr },
+    SetDeviceStatus { device_id: DeviceId, status: DeviceStatus },
+    /// Create a pool, with a map over the devices that are not out.
+    CreatePool { name: String, groups: usize, replicas: u32, failure_domain: FailureDomain },
+    RemovePool { name: String },
+    GetMap { pool: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub spec: DeviceSpec,
+    pub address: SocketAddr,
+    pub status: DeviceStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolInfo {
+    pub name: String,
+    pub generation: u32,
+    pub groups: usize,
+    pub replicas: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    pub devices_up: usize,
+    pub devices_down: usize,
+    pub devices_out: usize,
+    pub pools: Vec<PoolInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Status(ClusterStatus),
+    Devices(Vec<DeviceInfo>),
+    Map(StorageMap),
+    Done,
+    Error(String),
+}
+
+/// A request from a client on the master's client listener.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientRequest {
+    /// Get the storage map of a pool, and where its devices are.
+    GetPool { pool: PoolName },
+    /// Get a ticket to open sessions with the storage daemons.
+    GetTicket,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientResponse {
+    Pool { map: StorageMap, devices: Vec<DeviceInfo> },
+    /// A ticket with its key, see `Ticket::to_bytes()`.
+    Ticket(Vec<u8>),
+    Error(String),
+}
+
+/// Read a message, `None` if the connection was closed.
+pub async fn read_message<T: DeserializeOwned, S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<T>, IoError> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_MESSAGE_SIZE {
+        return Err(IoError::new(ErrorKind::InvalidData, "Message too large"));
+    }
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+    serde_json::from_slice(&data).map(Some).map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+}
+
+pub async fn write_message<T: Serialize, S: AsyncWrite + Unpin>(stream: &mut S, message: &T) -> Result<(), IoError> {
+    let data = serde_json::to_vec(message)?;
+    stream.write_u32(data.len() as u32).await?;
+    stream.write_all(&data).await?;
+    stream.flush().await
+}
+
+/// A connection to the admin interface of the master.
+pub struct AdminClient {
+    stream: TlsStream<TcpStream>,
+}
+
+/// Connect to the master at `address` (`host:port`), checking its
+/// certificate against `ca_cert` for `server_name` (default: the host), and
+/// authenticating with a client certificate and key if given.
+pub async fn connect_master(address: &str, server_name: Option<&str>, ca_cert: &Path, client_cert: Option<(&Path, &Path)>) -> Result<TlsStream<TcpStream>, Box<dyn std::error::Error>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for ca in load_certs(ca_cert)? {
+        roots.add(&ca)?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match client_cert {
+        Some((cert, key)) => config.with_single_cert(load_certs(cert)?, load_key(key)?)?,
+        None => config.with_no_client_auth(),
+    };
+    let server_name = match server_name {
+        Some(name) => name,
+        None => address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address),
+    };
+    // rustls can't check certificates issued for IP addresses
+    let server_name = match ServerName::try_from(server_name) {
+        Ok(name @ ServerName::DnsName(_)) => name,
+        _ => return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid server name {:?}, set the name the master's certificate is issued for", server_name),
+        ).into()),
+    };
+    let stream = TcpStream::connect(address).await?;
+    Ok(TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?)
+}
+
+impl AdminClient {
+    /// Connect to the master at `address` (`host:port`), checking its
+    /// certificate against `ca_cert` for `server_name` (default: the host).
+    pub async fn connect(address: &str, server_name: Option<&str>, ca_cert: &Path, cert: &Path, key: &Path) -> Result<AdminClient, Box<dyn std::error::Error>> {
+        let stream = connect_master(address, server_name, ca_cert, Some((cert, key))).await?;
+        Ok(AdminClient { stream })
+    }
+
+    /// Send a request and get the response, turning errors from the master
+    /// into `Err`.
+    pub async fn request(&mut self, request: &AdminRequest) -> Result<AdminResponse, IoError> {
+        write_message(&mut self.stream, request).await?;
+        match read_message(&mut self.stream).await? {
+            Some(AdminResponse::Error(message)) => Err(IoError::other(message)),
+            Some(response) => Ok(response),
+            None => Err(IoError::new(ErrorKind::UnexpectedEof, "Master closed the connection")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceId;
+    use crate::storage_map::overlay::DeviceStatus;
+    use super::{AdminRequest, read_message, write_message};
+
+    #[tokio::test]
+    async fn test_messages() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let request = AdminRequest::SetDeviceStatus {
+            device_id: DeviceId([7; 16]),
+            status: DeviceStatus::Out,
+        };
+        write_message(&mut client, &request).await.unwrap();
+        write_message(&mut client, &AdminRequest::Status).await.unwrap();
+        drop(client);
+        assert_eq!(read_message::<AdminRequest, _>(&mut server).await.unwrap(), Some(request));
+        assert_eq!(read_message::<AdminRequest, _>(&mut server).await.unwrap(), Some(AdminRequest::Status));
+        assert_eq!(read_message::<AdminRequest, _>(&mut server).await.unwrap(), None);
+    }
+}
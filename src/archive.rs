@@ -0,0 +1,257 @@
+//! Export of a pool to a tar archive, and import of such an archive.
+//!
+//! The archive starts with a `store-export` entry naming the format version
+//! and the pool, followed by an `objects/<name>` entry for each object. Names
+//! are percent-encoded, so any object ID is a single valid file name. The
+//! expiry of an object, if it has one, is recorded in a `STORE.expires` PAX
+//! record (milliseconds since the Unix epoch).
+//!
+//! Objects are listed and read one after the other, so the export is not a
+//! consistent snapshot if the pool is written to at the same time.
+//!
+//! Archives can be compressed with zstd, which import detects.
+
+use log::{info, warn};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::ObjectId;
+use crate::client::Client;
+use crate::mirror::{read_whole, write_whole};
+
+/// Format of the archives, the first line of the `store-export` entry.
+const FORMAT: &str = "store-export 1";
+
+/// Number of objects to request at once when listing the pool.
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// How many times to read an object that keeps changing before giving up.
+const READ_ATTEMPTS: u32 = 5;
+
+/// PAX record holding the expiry of an object.
+const EXPIRES_KEY: &str = "STORE.expires";
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+/// Encode an object ID as a file name, escaping all bytes but unreserved
+/// characters, and a leading dot.
+fn encode_name(name: &[u8]) -> String {
+    let mut result = String::with_capacity(name.len());
+    for (i, &b) in name.iter().enumerate() {
+        if b.is_ascii_alphanumeric() || b"-_~".contains(&b) || (b == b'.' && i > 0) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+fn decode_name(name: &[u8]) -> Result<Vec<u8>, IoError> {
+    let mut result = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] == b'%' {
+            let hex = name.get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .ok_or_else(|| invalid("Invalid escape in object name"))?;
+            let byte = u8::from_str_radix(hex, 16).map_err(|_| invalid("Invalid escape in object name"))?;
+            result.push(byte);
+            i += 3;
+        } else {
+            result.push(name[i]);
+            i += 1;
+        }
+    }
+    Ok(result)
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Write all the objects of the pool to an archive, compressed with zstd if
+/// `compress` is set. Returns the number of objects.
+pub async fn export_pool<W: Write>(client: &Client, pool: &str, output: W, compress: bool) -> Result<u64, IoError> {
+    if compress {
+        let (count, encoder) = write_archive(client, pool, zstd::Encoder::new(output, 0)?).await?;
+        encoder.finish()?.flush()?;
+        Ok(count)
+    } else {
+        let (count, mut output) = write_archive(client, pool, output).await?;
+        output.flush()?;
+        Ok(count)
+    }
+}
+
+async fn write_archive<W: Write>(client: &Client, pool: &str, output: W) -> Result<(u64, W), IoError> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut builder = Builder::new(output);
+
+    let manifest = format!("{}\npool {}\n", FORMAT, pool);
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(now);
+    builder.append_data(&mut header, "store-export", manifest.as_bytes())?;
+
+    let mut count = 0;
+    let mut position: Option<ObjectId> = None;
+    loop {
+        let page = client.list_objects(b"", position.as_ref(), LIST_PAGE_SIZE).await?;
+        let last = match page.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        for object_id in page {
+            let mut attempt = 1;
+            let object = loop {
+                match read_whole(client, &object_id).await {
+                    Err(e) if e.kind() == ErrorKind::Interrupted && attempt < READ_ATTEMPTS => attempt += 1,
+                    r => break r?,
+                }
+            };
+            let (stat, data) = match object {
+                Some(object) => object,
+                // Deleted since listed
+                None => continue,
+            };
+
+            if let Some(expires) = stat.expires {
+                let expires = unix_ms(expires).to_string();
+                builder.append_pax_extensions([(EXPIRES_KEY, expires.as_bytes())])?;
+            }
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(now);
+            let path = format!("objects/{}", encode_name(&object_id.0));
+            builder.append_data(&mut header, path, &data[..])?;
+            count += 1;
+            if count % 10000 == 0 {
+                info!("Exported {} objects", count);
+            }
+        }
+        position = Some(last);
+    }
+
+    let output = builder.into_inner()?;
+    Ok((count, output))
+}
+
+/// Write the objects of an archive to the pool, replacing existing objects
+/// with the same names. Returns the number of objects.
+///
+/// The expiry of objects can't be set through the client, so objects that
+/// have one are imported without it, unless they expired already in which
+/// case they are skipped.
+pub async fn import_pool<R: Read>(client: &Client, input: R) -> Result<u64, IoError> {
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        read_archive(client, zstd::Decoder::with_buffer(input)?).await
+    } else {
+        read_archive(client, input).await
+    }
+}
+
+async fn read_archive<R: Read>(client: &Client, input: R) -> Result<u64, IoError> {
+    let now = unix_ms(SystemTime::now());
+    let mut archive = Archive::new(input);
+    let mut entries = archive.entries()?;
+
+    let mut manifest = String::new();
+    match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if &entry.path_bytes()[..] != b"store-export" {
+                return Err(invalid("Not a store export"));
+            }
+            entry.read_to_string(&mut manifest)?;
+        }
+        None => return Err(invalid("Not a store export")),
+    }
+    let mut lines = manifest.lines();
+    if lines.next() != Some(FORMAT) {
+        return Err(invalid("Unsupported export format"));
+    }
+    if let Some(pool) = lines.find_map(|l| l.strip_prefix("pool ")) {
+        info!("Importing objects exported from pool {}", pool);
+    }
+
+    let mut count = 0;
+    let mut expiring = 0;
+    for entry in entries {
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            return Err(invalid("Unexpected entry in archive"));
+        }
+        let object_id = match entry.path_bytes().strip_prefix(b"objects/") {
+            Some(name) => ObjectId(decode_name(name)?),
+            None => return Err(invalid("Unexpected entry in archive")),
+        };
+        let mut expires = None;
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if extension.key_bytes() == EXPIRES_KEY.as_bytes() {
+                    let value = extension.value().map_err(|_| invalid("Invalid expiry"))?;
+                    expires = Some(u64::from_str(value).map_err(|_| invalid("Invalid expiry"))?);
+                }
+            }
+        }
+        let size = u32::try_from(entry.size()).map_err(|_| invalid("Object too large"))?;
+        let mut data = Vec::with_capacity(size as usize);
+        entry.read_to_end(&mut data)?;
+
+        match expires {
+            Some(expires) if expires <= now => continue,
+            Some(_) => expiring += 1,
+            None => {}
+        }
+        write_whole(client, &object_id, &data).await?;
+        count += 1;
+        if count % 10000 == 0 {
+            info!("Imported {} objects", count);
+        }
+    }
+    client.flush().await?;
+
+    if expiring > 0 {
+        warn!("{} objects were imported without their expiry", expiring);
+    }
+    Ok(count)
+}
+
+/// Whether an output file name asks for compression.
+pub fn is_compressed_name(name: &str) -> bool {
+    name.ends_with(".zst") || name.ends_with(".tzst")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_name, encode_name};
+
+    #[test]
+    fn test_names() {
+        for (name, encoded) in [
+            (&b"hello.txt"[..], "hello.txt"),
+            (b"a/b c", "a%2Fb%20c"),
+            (b".", "%2E"),
+            (b"..", "%2E."),
+            (b"\xff%", "%FF%25"),
+        ] {
+            assert_eq!(encode_name(name), encoded);
+            assert_eq!(decode_name(encoded.as_bytes()).unwrap(), name);
+        }
+        assert!(decode_name(b"a%2").is_err());
+        assert!(decode_name(b"a%zz").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! A benchmark driving the client, to evaluate cluster changes.
+//!
+//! Workers run concurrently until the duration is over, each doing one
+//! operation on a whole object at a time. Objects are written in parts like
+//! other clients do, and an operation's latency covers all its parts.
+//!
+//! `write` creates objects `<prefix><worker>_<n>`, which are deleted at the
+//! end unless asked otherwise; `read` and `randread` read the objects found
+//! under the prefix, left by a previous `write`.
+
+use log::warn;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ObjectId;
+use crate::client::{Client, PART_SIZE};
+
+/// How long to wait for the storage daemons before counting an operation as
+/// failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchMode {
+    Write,
+    /// Read the objects in order, each worker going through its share.
+    Read,
+    /// Read objects picked at random.
+    RandRead,
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub mode: BenchMode,
+    pub prefix: Vec<u8>,
+    pub object_size: usize,
+    pub threads: usize,
+    pub duration: Duration,
+    /// Delete the objects created by `write`.
+    pub cleanup: bool,
+}
+
+/// The results of a run.
+#[derive(Debug)]
+pub struct BenchReport {
+    pub ops: u64,
+    /// Operations that timed out.
+    pub errors: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Latency of each successful operation, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// The latency under which are `p` percent of operations.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{} ops in {:.1}s: {:.1} ops/s, {:.2} MiB/s",
+            self.ops, secs, self.ops as f64 / secs, self.bytes as f64 / secs / (1 << 20) as f64,
+        )?;
+        if self.errors > 0 {
+            writeln!(f, "{} ops timed out", self.errors)?;
+        }
+        write!(
+            f,
+            "latency (ms): min {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}, p99.9 {:.2}, max {:.2}",
+            millis(self.latencies.first().copied().unwrap_or_default()),
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            millis(self.percentile(99.9)),
+            millis(self.latencies.last().copied().unwrap_or_default()),
+        )
+    }
+}
+
+/// What a worker did.
+#[derive(Default)]
+struct WorkerStats {
+    ops: u64,
+    errors: u64,
+    bytes: u64,
+    latencies: Vec<Duration>,
+    /// When the last operation ended.
+    finished: Option<Instant>,
+}
+
+impl WorkerStats {
+    /// Record the result of an operation, failing on errors other than
+    /// timeouts.
+    fn record(&mut self, start: Instant, result: Result<usize, IoError>) -> Result<(), IoError> {
+        match result {
+            Ok(bytes) => {
+                self.ops += 1;
+                self.bytes += bytes as u64;
+                self.latencies.push(start.elapsed());
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                if self.errors == 0 {
+                    warn!("Operation timed out: {}", e);
+                }
+                self.errors += 1;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn object_name(prefix: &[u8], worker: usize, n: u64) -> ObjectId {
+    let mut name = prefix.to_owned();
+    name.extend_from_slice(format!("{}_{}", worker, n).as_bytes());
+    ObjectId(name)
+}
+
+async fn write_object(client: &Client, object_id: &ObjectId, data: &[u8]) -> Result<usize, IoError> {
+    let mut parts = data.chunks(PART_SIZE);
+    client.write_object(object_id, parts.next().unwrap_or(&[])).await?;
+    let mut offset = PART_SIZE as u32;
+    for part in parts {
+        client.write_part(object_id, offset, part).await?;
+        offset += part.len() as u32;
+    }
+    Ok(data.len())
+}
+
+/// Read up to `size` bytes of an object, stopping early if it is shorter.
+async fn read_object(client: &Client, object_id: &ObjectId, size: usize) -> Result<usize, IoError> {
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(PART_SIZE);
+        let part = client.read_part(object_id, offset as u32, len as u32).await?
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, format!("{:?} disappeared", object_id)))?;
+        offset += part.len();
+        if part.len() < len {
+            break;
+        }
+    }
+    Ok(offset)
+}
+
+async fn run_worker(client: Client, config: Arc<BenchConfig>, worker: usize, objects: Arc<Vec<ObjectId>>, deadline: Instant) -> Result<WorkerStats, IoError> {
+    let mut stats = WorkerStats::default();
+    let mut rng = StdRng::from_entropy();
+    let mut data = vec![0; if config.mode == BenchMode::Write { config.object_size } else { 0 }];
+    rng.fill_bytes(&mut data);
+    let mut n = 0;
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        let result = match config.mode {
+            BenchMode::Write => {
+                write_object(&client, &object_name(&config.prefix, worker, n), &data).await
+            }
+            BenchMode::Read => {
+                let index = (worker + n as usize * config.threads) % objects.len();
+                read_object(&client, &objects[index], config.object_size).await
+            }
+            BenchMode::RandRead => {
+                let index = rng.gen_range(0..objects.len());
+                read_object(&client, &objects[index], config.object_size).await
+            }
+        };
+        stats.record(start, result)?;
+        n += 1;
+    }
+    stats.finished = Some(Instant::now());
+
+    if config.mode == BenchMode::Write && config.cleanup {
+        for i in 0..n {
+            client.delete_object(&object_name(&config.prefix, worker, i)).await?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Run the benchmark.
+pub async fn run_bench(client: &Client, config: BenchConfig) -> Result<BenchReport, IoError> {
+    if config.threads == 0 || config.object_size == 0 || config.object_size > u32::MAX as usize {
+        return Err(IoError::new(ErrorKind::InvalidInput, "Invalid number of threads or object size"));
+    }
+    let client = client.with_timeout(REQUEST_TIMEOUT);
+    let objects = match config.mode {
+        BenchMode::Write => Vec::new(),
+        BenchMode::Read | BenchMode::RandRead => {
+            let objects = client.list_all_objects(&config.prefix).await?;
+            if objects.is_empty() {
+                return Err(IoError::new(
+                    ErrorKind::NotFound,
+                    "No objects to read, run a write benchmark with --no-cleanup first",
+                ));
+            }
+            objects
+        }
+    };
+
+    let config = Arc::new(config);
+    let objects = Arc::new(objects);
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let workers: Vec<_> = (0..config.threads).map(|worker| {
+        tokio::spawn(run_worker(client.clone(), config.clone(), worker, objects.clone(), deadline))
+    }).collect();
+
+    let mut report = BenchReport { ops: 0, errors: 0, bytes: 0, elapsed: Duration::ZERO, latencies: Vec::new() };
+    let mut first_error = None;
+    for worker in workers {
+        match worker.await.map_err(IoError::other)? {
+            Ok(stats) => {
+                report.ops += stats.ops;
+                report.errors += stats.errors;
+                report.bytes += stats.bytes;
+                report.latencies.extend(stats.latencies);
+                if let Some(finished) = stats.finished {
+                    report.elapsed = report.elapsed.max(finished - start);
+                }
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+    report.latencies.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::BenchReport;
+
+    #[test]
+    fn test_percentile() {
+        let report = BenchReport {
+            ops: 100,
+            errors: 0,
+            bytes: 0,
+            elapsed: Duration::from_secs(1),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(99.9), Duration::from_millis(100));
+        assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+
+        let empty = BenchReport { latencies: Vec::new(), ..report };
+        assert_eq!(empty.percentile(50.0), Duration::ZERO);
+    }
+}
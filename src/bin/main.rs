@@ -2,19 +2,38 @@ extern crate clap;
 extern crate env_logger;
 extern crate log;
 
-use clap::{Arg, Command};
-use std::borrow::Cow;
+use clap::{Arg, ArgMatches, Command};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsString;
 use std::io::Write;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use store::{ObjectId, PoolName};
+use store::client::{Client, DaemonStats, create_client, create_client_from_master};
+use store::config::{Config, ConfigValue};
+use store::daemon::DaemonSettings;
+use store::gc::GcOptions;
+use store::image::{Geometry, MAX_STRIPE_UNIT, parse_size};
 use store::metrics::start_http_server;
+use store::progress::{Progress, ProgressIo};
 
 fn main() {
-    // Parse command line
-    let mut cli = Command::new("store")
+    // Before any thread is started, as this changes the environment
+    store::systemd::init_listen_fds();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// The command line, without the defaults from the configuration file.
+fn cli() -> Command<'static> {
+    Command::new("store")
         .bin_name("store")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -25,12 +44,38 @@ fn main() {
                 .help("Augment verbosity (print more details)")
                 .multiple_occurrences(true)
         )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .help("What to log, like $STORE_LOG (e.g. 'info' or 'warn,store::daemon=debug'), on top of -v")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help("Configuration file providing defaults for options (default: $STORE_CONFIG)")
+                .takes_value(true)
+                .global(true)
+                .allow_invalid_utf8(true)
+        )
         .arg(
             Arg::new("serve-metrics")
                 .long("serve-metrics")
                 .help("Serve metrics in Prometheus format on this port")
                 .takes_value(true)
         )
+        .arg(
+            Arg::new("report-interval")
+                .long("report-interval")
+                .help("Log activity every this many seconds, 0 to disable (default 10)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Send metrics to this OpenTelemetry collector, using OTLP over HTTP (e.g. http://localhost:4318)")
+                .takes_value(true)
+        )
         .subcommand(Command::new("master")
             .about("Start master server, used for coordination and authentication")
             .arg(
@@ -87,9 +132,297 @@ fn main() {
                     .takes_value(true)
                     .allow_invalid_utf8(true)
             )
+            .arg(
+                Arg::new("keyring")
+                    .long("keyring")
+                    .help("Keyring file with the keys to issue to clients (see 'store keyring')")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("admin-address")
+                    .long("admin-address")
+                    .help("Address to listen on for admins (see 'store admin'), presenting listen-cert")
+                    .takes_value(true)
+                    .requires("admin-ca-cert")
+            )
+            .arg(
+                Arg::new("admin-ca-cert")
+                    .long("admin-ca-cert")
+                    .help("Path to certificate to use to validate admin connections")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+                    .requires("admin-address")
+            )
+        )
+        .subcommand(Command::new("admin")
+            .about("Operate the cluster through the master")
+            .arg(
+                Arg::new("master")
+                    .long("master")
+                    .help("Admin address of the master, as HOST:PORT")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("server-name")
+                    .long("server-name")
+                    .help("Name the master's certificate is issued for (default: HOST)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("ca-cert")
+                    .long("ca-cert")
+                    .help("Path to certificate to use to validate the master")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("cert")
+                    .long("cert")
+                    .help("Path to certificate to present, signed by the master's admin CA")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("key")
+                    .long("key")
+                    .help("Path to key for cert")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .subcommand(Command::new("status")
+                .about("Show the number of devices and the pools")
+            )
+            .subcommand(Command::new("pool")
+                .about("Manage pools")
+                .subcommand(Command::new("create")
+                    .about("Create a pool, placing data on the devices that are not out")
+                    .arg(
+                        Arg::new("name")
+                            .help("Name of the pool")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("groups")
+                            .long("groups")
+                            .help("Number of groups")
+                            .takes_value(true)
+                            .default_value("256")
+                    )
+                    .arg(
+                        Arg::new("replicas")
+                            .long("replicas")
+                            .help("Number of copies of each object")
+                            .takes_value(true)
+                            .default_value("3")
+                    )
+                    .arg(
+                        Arg::new("failure-domain")
+                            .long("failure-domain")
+                            .help("Level across which the copies are spread")
+                            .takes_value(true)
+                            .possible_values(["device", "host", "rack"])
+                            .default_value("host")
+                    )
+                )
+                .subcommand(Command::new("rm")
+                    .about("Remove a pool")
+                    .arg(
+                        Arg::new("name")
+                            .help("Name of the pool")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                )
+            )
+            .subcommand(Command::new("device")
+                .about("Manage devices")
+                .subcommand(Command::new("ls")
+                    .about("List the devices")
+                )
+                .subcommand(Command::new("add")
+                    .about("Add a device, or change its location, weight, or address")
+                    .arg(
+                        Arg::new("id")
+                            .help("ID of the device, as 32 hexadecimal digits")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("address")
+                            .long("address")
+                            .help("Address of the storage daemon")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("host")
+                            .long("host")
+                            .help("Host the device is on")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("rack")
+                            .long("rack")
+                            .help("Rack the host is in")
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("weight")
+                            .long("weight")
+                            .help("Relative share of the data the device gets")
+                            .takes_value(true)
+                            .default_value("100")
+                    )
+                )
+                .subcommand(Command::new("out")
+                    .about("Mark a device out, moving its data to other devices")
+                    .arg(
+                        Arg::new("id")
+                            .help("ID of the device")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                )
+                .subcommand(Command::new("in")
+                    .about("Mark a device back up")
+                    .arg(
+                        Arg::new("id")
+                            .help("ID of the device")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                )
+            )
+            .subcommand(Command::new("map")
+                .about("Inspect storage maps")
+                .subcommand(Command::new("show")
+                    .about("Print the storage map of a pool as JSON")
+                    .arg(
+                        Arg::new("pool")
+                            .help("Name of the pool")
+                            .required(true)
+                            .takes_value(true)
+                    )
+                    .arg(
+                        Arg::new("output")
+                            .long("output")
+                            .help("Write the map to this file in binary format instead, for 'store map analyze'")
+                            .takes_value(true)
+                            .allow_invalid_utf8(true)
+                    )
+                )
+            )
+            .subcommand(Command::new("gc")
+                .about("Delete the objects left behind by failed writes to a pool")
+                .arg(
+                    Arg::new("pool")
+                        .help("Name of the pool")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new("capability")
+                        .long("capability")
+                        .help("File with the credentials to attach to requests, allowing reads and deletes")
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("grace")
+                        .long("grace")
+                        .help("Keep objects written less than this many seconds ago")
+                        .takes_value(true)
+                        .default_value("3600")
+                )
+                .arg(
+                    Arg::new("striped")
+                        .long("striped")
+                        .help("Look for stripes of striped objects that are no longer used")
+                )
+                .arg(
+                    Arg::new("s3")
+                        .long("s3")
+                        .help("Look for chunks of S3 objects that are no longer used")
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only report what would be deleted")
+                )
+            )
         )
         .subcommand(Command::new("mem-store")
             .about("Start storage daemon, storing object data memory (not persistent)")
+            .args(gc_args())
+            .arg(
+                Arg::new("peer-address")
+                    .long("peer-address")
+                    .help("Address to listen on for storage daemons")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("peer-cert")
+                    .long("peer-cert")
+                    .help("Path to certificate to present for peer connections")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("peer-key")
+                    .long("peer-key")
+                    .help("Path to key for peer-cert")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("peer-ca-cert")
+                    .long("peer-ca-cert")
+                    .help("Path to certificate to use to validate peer connections")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("keyring")
+                    .long("keyring")
+                    .help("Keyring file shared with the master, requests without a valid capability are refused")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-address")
+                    .long("dtls-address")
+                    .help("Address to also listen on for clients using DTLS, with peer-cert and peer-key")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("receive-threads")
+                    .long("receive-threads")
+                    .help("Receive client requests on this many sockets bound with SO_REUSEPORT, each with its own thread (Linux only)")
+                    .takes_value(true)
+                    .default_value("1")
+            )
+        )
+        .subcommand(Command::new("file-store")
+            .about("Start storage daemon, storing each object in a file")
+            .args(gc_args())
             .arg(
                 Arg::new("peer-address")
                     .long("peer-address")
@@ -128,9 +461,43 @@ fn main() {
                     .required(true)
                     .takes_value(true)
             )
+            .arg(
+                Arg::new("keyring")
+                    .long("keyring")
+                    .help("Keyring file shared with the master, requests without a valid capability are refused")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-address")
+                    .long("dtls-address")
+                    .help("Address to also listen on for clients using DTLS, with peer-cert and peer-key")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("receive-threads")
+                    .long("receive-threads")
+                    .help("Receive client requests on this many sockets bound with SO_REUSEPORT, each with its own thread (Linux only)")
+                    .takes_value(true)
+                    .default_value("1")
+            )
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .help("Directory where to store object data")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("read-only")
+                    .long("read-only")
+                    .help("Open an existing store read-only, refusing writes")
+            )
         )
         .subcommand(Command::new("rocksdb-store")
             .about("Start storage daemon, storing object data in rocksdb")
+            .args(gc_args())
             .arg(
                 Arg::new("peer-address")
                     .long("peer-address")
@@ -169,6 +536,26 @@ fn main() {
                     .required(true)
                     .takes_value(true)
             )
+            .arg(
+                Arg::new("keyring")
+                    .long("keyring")
+                    .help("Keyring file shared with the master, requests without a valid capability are refused")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-address")
+                    .long("dtls-address")
+                    .help("Address to also listen on for clients using DTLS, with peer-cert and peer-key")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("receive-threads")
+                    .long("receive-threads")
+                    .help("Receive client requests on this many sockets bound with SO_REUSEPORT, each with its own thread (Linux only)")
+                    .takes_value(true)
+                    .default_value("1")
+            )
             .arg(
                 Arg::new("dir")
                     .long("dir")
@@ -177,6 +564,27 @@ fn main() {
                     .takes_value(true)
                     .allow_invalid_utf8(true)
             )
+            .arg(
+                Arg::new("read-only")
+                    .long("read-only")
+                    .help("Open an existing store read-only, refusing writes")
+            )
+        )
+        .subcommand(Command::new("fsck")
+            .about("Check the consistency of a file or RocksDB store, with its daemon stopped")
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .help("Directory of the store")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("repair")
+                    .long("repair")
+                    .help("Fix the problems found, deleting what can't be read")
+            )
         )
         .subcommand(Command::new("read")
             .about("Download data as a client")
@@ -184,8 +592,23 @@ fn main() {
                 Arg::new("storage-daemon")
                     .long("storage-daemon")
                     .help("Address of the storage daemon")
-                    .required(true)
+                    .required_unless_present("master")
+                    .takes_value(true)
+            )
+            .args(master_args())
+            .arg(
+                Arg::new("capability")
+                    .long("capability")
+                    .help("File with the credentials to attach to requests (see 'store keyring issue')")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
                     .takes_value(true)
+                    .allow_invalid_utf8(true)
             )
             .arg(
                 Arg::new("pool")
@@ -219,8 +642,23 @@ fn main() {
                 Arg::new("storage-daemon")
                     .long("storage-daemon")
                     .help("Address of the storage daemon")
-                    .required(true)
+                    .required_unless_present("master")
+                    .takes_value(true)
+            )
+            .args(master_args())
+            .arg(
+                Arg::new("capability")
+                    .long("capability")
+                    .help("File with the credentials to attach to requests (see 'store keyring issue')")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
                     .takes_value(true)
+                    .allow_invalid_utf8(true)
             )
             .arg(
                 Arg::new("pool")
@@ -244,7 +682,7 @@ fn main() {
             .arg(
                 Arg::new("data-file")
                     .long("data-file")
-                    .help("Read data to set from file; use either this or --data-literal")
+                    .help("Read data to set from file, - for standard input; use either this or --data-literal")
                     .takes_value(true)
                     .allow_invalid_utf8(true)
             )
@@ -256,13 +694,29 @@ fn main() {
             )
         )
         .subcommand(Command::new("delete")
-            .about("Delete an object")
+            .about("Delete an object, or all objects with a prefix")
+            .visible_alias("rm")
             .arg(
                 Arg::new("storage-daemon")
                     .long("storage-daemon")
                     .help("Address of the storage daemon")
-                    .required(true)
+                    .required_unless_present("master")
+                    .takes_value(true)
+            )
+            .args(master_args())
+            .arg(
+                Arg::new("capability")
+                    .long("capability")
+                    .help("File with the credentials to attach to requests (see 'store keyring issue')")
                     .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
             )
             .arg(
                 Arg::new("pool")
@@ -273,160 +727,1079 @@ fn main() {
             )
             .arg(
                 Arg::new("object-id")
-                    .help("Object ID to set")
+                    .help("Object ID to delete, or prefix with --prefix")
                     .required(true)
                     .takes_value(true)
             )
-        );
-
-    let matches = match cli.try_get_matches_from_mut(env::args_os()) {
-        Ok(m) => m,
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(2);
-        }
-    };
-
-    macro_rules! check {
-        ($res:expr $(,)?) => {
-            match $res {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("{}", e);
-                    std::process::exit(1);
-                }
-            }
-        };
-        ($res:expr, $msg:expr $(,)?) => {
-            match $res {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("{}: {}", $msg, e);
-                    std::process::exit(1);
-                }
-            }
-        };
-    }
-
-    // Set up logging
-    {
-        let level = match matches.occurrences_of("verbose") {
-            0 => log::LevelFilter::Warn,
-            1 => log::LevelFilter::Info,
-            2 => log::LevelFilter::Debug,
-            _ => log::LevelFilter::Trace,
-        };
-        let mut logger_builder = env_logger::builder();
-        logger_builder.filter(None, level);
-        if let Ok(val) = env::var("STORE_LOG") {
-            logger_builder.parse_filters(&val);
-        }
-        if let Ok(val) = env::var("STORE_LOG_STYLE") {
-            logger_builder.parse_write_style(&val);
-        }
-        logger_builder.init();
-    }
-
-    // Set up metrics
-    if let Some(metrics_addr) = matches.value_of("serve-metrics") {
-        let metrics_addr: SocketAddr = check!(
-            metrics_addr.parse(),
-            "Invalid metrics address",
-        );
-        start_http_server(metrics_addr);
-    }
-
-    let mut runtime = tokio::runtime::Builder::new_current_thread();
-    runtime.enable_all();
-
-    match matches.subcommand_name() {
-        Some("master") => {
-            use store::master::run_master;
-
-            let s_matches = matches.subcommand_matches("master").unwrap();
-            let peer_address = s_matches.value_of("peer-address").unwrap();
-            let peer_address: SocketAddr = check!(
-                peer_address.parse(),
-                "Invalid peer-address",
-            );
-            let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
-            let peer_cert = Path::new(peer_cert);
-            let peer_key = s_matches.value_of_os("peer-key").unwrap();
-            let peer_key = Path::new(peer_key);
-            let peer_ca_cert = s_matches.value_of_os("peer-ca-cert").unwrap();
-            let peer_ca_cert = Path::new(peer_ca_cert);
-            let listen_address = s_matches.value_of("listen-address").unwrap();
-            let listen_address: SocketAddr = check!(
-                listen_address.parse(),
-                "Invalid listen-address",
-            );
-            let listen_cert = s_matches.value_of_os("listen-cert").unwrap();
-            let listen_cert = Path::new(listen_cert);
-            let listen_key = s_matches.value_of_os("listen-key").unwrap();
-            let listen_key = Path::new(listen_key);
-
-            runtime
-                .build()
-                .unwrap()
-                .block_on(run_master(
-                    peer_address,
-                    peer_cert,
-                    peer_key,
-                    peer_ca_cert,
-                    listen_address,
-                    listen_cert,
-                    listen_key,
-                ))
-                .unwrap();
-        }
-        Some("mem-store") => {
-            use store::daemon::run_storage_daemon;
-            use store::storage::mem_store::create_mem_store;
-
-            let s_matches = matches.subcommand_matches("mem-store").unwrap();
-            let peer_address = s_matches.value_of("peer-address").unwrap();
-            let peer_address: SocketAddr = check!(
-                peer_address.parse(),
-                "Invalid peer-address",
-            );
-            let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
-            let peer_cert = Path::new(peer_cert);
-            let peer_key = s_matches.value_of_os("peer-key").unwrap();
-            let peer_key = Path::new(peer_key);
-            let peer_ca_cert = s_matches.value_of_os("peer-ca-cert").unwrap();
-            let peer_ca_cert = Path::new(peer_ca_cert);
-            let listen_address = s_matches.value_of("listen-address").unwrap();
-            let listen_address: SocketAddr = check!(
-                listen_address.parse(),
-                "Invalid listen-address",
-            );
-            let (storage_backend, device_id) = create_mem_store();
-
-            runtime
-                .build()
-                .unwrap()
-                .block_on(run_storage_daemon(
-                    peer_address,
-                    peer_cert,
-                    peer_key,
-                    peer_ca_cert,
+            .arg(
+                Arg::new("prefix")
+                    .long("prefix")
+                    .help("Delete all the objects whose name starts with object-id")
+            )
+            .arg(
+                Arg::new("tag")
+                    .long("tag")
+                    .help("Only delete the objects with this tag, with --prefix")
+                    .takes_value(true)
+                    .requires("prefix")
+            )
+            .arg(
+                Arg::new("yes")
+                    .long("yes")
+                    .help("Don't ask for confirmation before deleting objects by prefix")
+            )
+        )
+        .subcommand(Command::new("cp")
+            .about("Copy an object, or all objects with a prefix, to another pool or cluster")
+            .arg(
+                Arg::new("source-storage-daemon")
+                    .long("source-storage-daemon")
+                    .help("Address of a storage daemon of the source cluster")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("source-dtls-ca-cert")
+                    .long("source-dtls-ca-cert")
+                    .help("Connect to the source using DTLS, validating its certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("source-pool")
+                    .long("source-pool")
+                    .help("Pool to copy from, as POOL or POOL:CREDENTIALS_FILE")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("destination-storage-daemon")
+                    .long("destination-storage-daemon")
+                    .help("Address of a storage daemon of the destination cluster (default: the source's)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("destination-dtls-ca-cert")
+                    .long("destination-dtls-ca-cert")
+                    .help("Connect to the destination using DTLS, validating its certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("destination-pool")
+                    .long("destination-pool")
+                    .help("Pool to copy to, as POOL or POOL:CREDENTIALS_FILE (default: the source pool)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("prefix")
+                    .long("prefix")
+                    .help("Copy all the objects whose name starts with SOURCE, replacing it with DESTINATION")
+            )
+            .arg(
+                Arg::new("source")
+                    .help("Object to copy")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("destination")
+                    .help("Name of the copy (default: the same)")
+                    .takes_value(true)
+            )
+        )
+        .subcommand(client_args(Command::new("du"))
+            .about("Show the objects and bytes stored in pools, per pool and per device")
+            .mut_arg("storage-daemon", |arg| {
+                arg.help("Address of a storage daemon, can be repeated")
+                    .multiple_occurrences(true)
+            })
+            .mut_arg("pool", |arg| {
+                arg.required(false)
+                    .required_unless_present("all-pools")
+            })
+            .arg(
+                Arg::new("all-pools")
+                    .long("all-pools")
+                    .help("Show all the pools the capability allows reading, instead of --pool")
+            )
+        )
+        .subcommand(client_args(Command::new("tag"))
+            .about("Set the tags of an object, or print them")
+            .arg(
+                Arg::new("object-id")
+                    .help("Object ID")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("tags")
+                    .help("Tags to replace the object's tags with")
+                    .takes_value(true)
+                    .multiple_values(true)
+            )
+            .arg(
+                Arg::new("clear")
+                    .long("clear")
+                    .help("Remove all the tags of the object")
+                    .conflicts_with("tags")
+            )
+        )
+        .subcommand(client_args(Command::new("watch"))
+            .about("Print the objects created, updated, and deleted, as it happens")
+            .arg(
+                Arg::new("prefix")
+                    .long("prefix")
+                    .help("Only watch the objects whose name starts with this")
+                    .takes_value(true)
+                    .default_value("")
+            )
+        )
+        .subcommand(client_args(Command::new("bench"))
+            .about("Measure the throughput and latency of the cluster")
+            .arg(
+                Arg::new("mode")
+                    .help("Write new objects, or read those left by a write benchmark in order or at random")
+                    .required(true)
+                    .takes_value(true)
+                    .possible_values(["write", "read", "randread"])
+            )
+            .arg(
+                Arg::new("object-size")
+                    .long("object-size")
+                    .help("Size of the objects to write or read, with an optional K, M, or G suffix")
+                    .takes_value(true)
+                    .default_value("4K")
+            )
+            .arg(
+                Arg::new("threads")
+                    .long("threads")
+                    .help("Number of operations in flight")
+                    .takes_value(true)
+                    .default_value("16")
+            )
+            .arg(
+                Arg::new("duration")
+                    .long("duration")
+                    .help("Seconds to run for")
+                    .takes_value(true)
+                    .default_value("10")
+            )
+            .arg(
+                Arg::new("prefix")
+                    .long("prefix")
+                    .help("Prefix of the names of the objects")
+                    .takes_value(true)
+                    .default_value("bench/")
+            )
+            .arg(
+                Arg::new("no-cleanup")
+                    .long("no-cleanup")
+                    .help("Keep the objects created by a write benchmark, to read them")
+            )
+        )
+        .subcommand(Command::new("keyring")
+            .about("Manage keyring files")
+            .subcommand(Command::new("create")
+                .about("Create a new keyring file with a new key")
+                .arg(
+                    Arg::new("file")
+                        .help("Path of the keyring file, which must not exist")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+            )
+            .subcommand(Command::new("rotate")
+                .about("Generate a new current key in a keyring file")
+                .arg(
+                    Arg::new("file")
+                        .help("Path of the keyring file")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("keep")
+                        .long("keep")
+                        .help("Number of previous keys to keep accepting")
+                        .takes_value(true)
+                        .default_value("1")
+                )
+            )
+            .subcommand(Command::new("add")
+                .about("Import the keys of another keyring file, such as the master's")
+                .arg(
+                    Arg::new("file")
+                        .help("Path of the keyring file, created if it doesn't exist")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("source")
+                        .help("Path of the keyring file to import, whose current key becomes the current key")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("keep")
+                        .long("keep")
+                        .help("Number of previous keys to keep accepting (default: all)")
+                        .takes_value(true)
+                )
+            )
+            .subcommand(Command::new("issue")
+                .about("Issue credentials allowing a client to access pools: a capability, and a ticket to open sessions")
+                .arg(
+                    Arg::new("file")
+                        .help("Path of the keyring file")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .help("File to write the credentials to")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("client-id")
+                        .long("client-id")
+                        .help("Name of the client, for logging")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new("pool")
+                        .long("pool")
+                        .help("Pool the client can access, can be repeated (default: all pools)")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                )
+                .arg(
+                    Arg::new("ops")
+                        .long("ops")
+                        .help("Allowed operations, comma-separated among read, write, delete")
+                        .takes_value(true)
+                        .default_value("read")
+                )
+                .arg(
+                    Arg::new("lifetime")
+                        .long("lifetime")
+                        .help("How long the credentials are valid, in seconds")
+                        .takes_value(true)
+                        .default_value("86400")
+                )
+            )
+            .subcommand(Command::new("list")
+                .about("Show the keys in a keyring file, oldest first")
+                .arg(
+                    Arg::new("file")
+                        .help("Path of the keyring file")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+            )
+        )
+        .subcommand(Command::new("map")
+            .about("Inspect storage maps")
+            .subcommand(Command::new("analyze")
+                .about("Validate a map, simulate placement and show how evenly data is spread")
+                .arg(
+                    Arg::new("map")
+                        .long("map")
+                        .help("Storage map file, in binary or JSON format")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Number of groups to simulate")
+                        .takes_value(true)
+                        .default_value("100000")
+                )
+                .arg(
+                    Arg::new("objects")
+                        .long("objects")
+                        .help("Number of objects to simulate, placed in the map's groups")
+                        .takes_value(true)
+                        .conflicts_with("groups")
+                )
+                .arg(
+                    Arg::new("current")
+                        .long("current")
+                        .help("Current storage map, to show the data movement to the new one")
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+            )
+        )
+        .subcommand(Command::new("s3-gateway")
+            .about("Serve a subset of the S3 API, with pools as buckets")
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for HTTP clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("storage-daemon")
+                    .long("storage-daemon")
+                    .help("Address of the storage daemon")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("bucket")
+                    .long("bucket")
+                    .help("Pool to serve as a bucket, as POOL or POOL:CREDENTIALS_FILE (can be repeated)")
+                    .required(true)
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("credentials-file")
+                    .long("credentials-file")
+                    .help("File listing the access key IDs and secret keys clients sign requests with, one pair per line")
+                    .required_unless_present("no-auth")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("no-auth")
+                    .long("no-auth")
+                    .help("Accept requests without checking their signature, from anyone who can reach the gateway")
+                    .conflicts_with("credentials-file")
+            )
+        )
+        .subcommand(Command::new("http-gateway")
+            .about("Serve objects over plain HTTP, at /pool/object")
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for HTTP clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("storage-daemon")
+                    .long("storage-daemon")
+                    .help("Address of the storage daemon")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("pool")
+                    .long("pool")
+                    .help("Pool to serve, as POOL or POOL:CREDENTIALS_FILE (can be repeated)")
+                    .required(true)
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("token-file")
+                    .long("token-file")
+                    .help("File listing the tokens clients can authenticate with, one per line")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+        )
+        .subcommand(Command::new("grpc-gateway")
+            .about("Serve the gRPC API defined in proto/store.proto (needs the grpc feature)")
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for gRPC clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("storage-daemon")
+                    .long("storage-daemon")
+                    .help("Address of the storage daemon")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("pool")
+                    .long("pool")
+                    .help("Pool to serve, as POOL or POOL:CREDENTIALS_FILE (can be repeated)")
+                    .required(true)
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("token-file")
+                    .long("token-file")
+                    .help("File listing the tokens clients can authenticate with, one per line")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+        )
+        .subcommand(Command::new("redis-gateway")
+            .about("Serve a subset of the Redis protocol, storing keys as objects of a pool")
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for Redis clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("storage-daemon")
+                    .long("storage-daemon")
+                    .help("Address of the storage daemon")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("pool")
+                    .long("pool")
+                    .help("Pool to store keys in, as POOL or POOL:CREDENTIALS_FILE")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("token-file")
+                    .long("token-file")
+                    .help("File listing the passwords clients must send with AUTH, one per line")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+        )
+        .subcommand(Command::new("mirror")
+            .about("Replicate a pool to another cluster, continuously")
+            .arg(
+                Arg::new("source-storage-daemon")
+                    .long("source-storage-daemon")
+                    .help("Address of a storage daemon of the source cluster")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("source-dtls-ca-cert")
+                    .long("source-dtls-ca-cert")
+                    .help("Connect to the source using DTLS, validating its certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("source-pool")
+                    .long("source-pool")
+                    .help("Pool to replicate, as POOL or POOL:CREDENTIALS_FILE")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("destination-storage-daemon")
+                    .long("destination-storage-daemon")
+                    .help("Address of a storage daemon of the destination cluster")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("destination-dtls-ca-cert")
+                    .long("destination-dtls-ca-cert")
+                    .help("Connect to the destination using DTLS, validating its certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("destination-pool")
+                    .long("destination-pool")
+                    .help("Pool to replicate to, as POOL or POOL:CREDENTIALS_FILE")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("checkpoint")
+                    .long("checkpoint")
+                    .help("File recording what was replicated, to resume from")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("interval")
+                    .long("interval")
+                    .help("Seconds between the start of passes over the source pool")
+                    .takes_value(true)
+                    .default_value("60")
+            )
+            .arg(
+                Arg::new("once")
+                    .long("once")
+                    .help("Stop after one complete pass")
+            )
+        )
+        .subcommand(client_args(Command::new("export"))
+            .about("Write all the objects of a pool to a tar archive")
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .help("Archive to write, compressed with zstd if it ends in .zst or .tzst, - for standard output")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("zstd")
+                    .long("zstd")
+                    .help("Compress with zstd whatever the name of the output")
+            )
+        )
+        .subcommand(client_args(Command::new("import"))
+            .about("Write the objects of an archive from 'store export' to a pool")
+            .arg(
+                Arg::new("input")
+                    .long("input")
+                    .help("Archive to read, - for standard input")
+                    .required(true)
+                    .takes_value(true)
+            )
+        )
+        .subcommand(Command::new("image")
+            .about("Manage block device images, as used by the NBD gateway")
+            .subcommand(image_geometry_args(image_client_args(Command::new("create")))
+                .about("Create an image")
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("Size of the image in bytes, with an optional K, M, G, or T suffix")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new("preallocate")
+                        .long("preallocate")
+                        .help("Write all the blocks with zeros, instead of creating them on first write")
+                )
+            )
+            .subcommand(image_client_args(Command::new("resize"))
+                .about("Change the size of an image, deleting the blocks past the end when shrinking")
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("New size of the image in bytes, with an optional K, M, G, or T suffix")
+                        .required(true)
+                        .takes_value(true)
+                )
+            )
+            .subcommand(image_client_args(Command::new("delete"))
+                .about("Delete an image and its blocks")
+            )
+            .subcommand(image_client_args(Command::new("snapshot"))
+                .about("Copy an image that is not in use to a snapshot, which gateways can export read-only")
+                .arg(
+                    Arg::new("snapshot")
+                        .help("Name of the snapshot, the copy is the image <image>@<snapshot>")
+                        .required(true)
+                        .takes_value(true)
+                )
+            )
+            .subcommand(image_client_args(Command::new("info"))
+                .about("Show the geometry of an image and how many blocks are allocated")
+            )
+            .subcommand(image_geometry_args(image_client_args(Command::new("import")))
+                .about("Create an image from a raw or qcow2 disk image file")
+                .arg(
+                    Arg::new("file")
+                        .help("Disk image file to read")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Format of the file (default: qcow2 if it has the qcow2 header, raw otherwise)")
+                        .takes_value(true)
+                        .possible_values(["raw", "qcow2"])
+                )
+            )
+            .subcommand(image_client_args(Command::new("export"))
+                .about("Write an image that is not in use to a raw disk image file")
+                .arg(
+                    Arg::new("file")
+                        .help("Disk image file to write, sparse")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                )
+            )
+        )
+        .subcommand(image_geometry_args(Command::new("provisioner"))
+            .about("Serve an HTTP API creating, expanding, and deleting images as volumes, for a CSI driver")
+            .arg(
+                Arg::new("listen-address")
+                    .long("listen-address")
+                    .help("Address to listen on for HTTP clients")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("storage-daemon")
+                    .long("storage-daemon")
+                    .help("Address of the storage daemon")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("attach-storage-daemon")
+                    .long("attach-storage-daemon")
+                    .help("Address of the storage daemon for the nodes' NBD gateways (default: --storage-daemon)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("dtls-ca-cert")
+                    .long("dtls-ca-cert")
+                    .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+            .arg(
+                Arg::new("pool")
+                    .long("pool")
+                    .help("Pool holding the volumes, as POOL or POOL:CREDENTIALS_FILE")
+                    .required(true)
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::new("token-file")
+                    .long("token-file")
+                    .help("File listing the tokens clients can authenticate with, one per line")
+                    .required(true)
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+            )
+        )
+        .subcommand(Command::new("completions")
+            .about("Print the shell completion script for the command line")
+            .arg(
+                Arg::new("shell")
+                    .help("Shell to generate the script for")
+                    .required(true)
+                    .takes_value(true)
+                    .possible_values(["bash", "elvish", "fish", "powershell", "zsh"])
+            )
+        )
+        .subcommand(Command::new("man")
+            .about("Print the manual page for the command line, in roff format")
+        )
+}
+
+fn run() -> Result<(), CliError> {
+    // Parse command line, with defaults from the configuration file
+    let mut cli = cli();
+    load_config(&mut cli)?;
+
+    // Exits with code 2 on invalid arguments
+    let matches = cli.try_get_matches_from_mut(env::args_os()).unwrap_or_else(|e| e.exit());
+
+
+    // Set up logging
+    set_logger(&matches);
+    log::set_logger(&LOGGER).expect("Logger already set");
+
+    // Set up metrics
+    if let Some(interval) = matches.value_of("report-interval") {
+        use store::metrics::reporter::{ReporterConfig, configure};
+
+        let interval: u64 = interval.parse().usage("Invalid report-interval")?;
+        configure(match interval {
+            0 => ReporterConfig { level: None, ..Default::default() },
+            i => ReporterConfig { interval: std::time::Duration::from_secs(i), ..Default::default() },
+        });
+    }
+    if let Some(metrics_addr) = matches.value_of("serve-metrics") {
+        let metrics_addr: SocketAddr = metrics_addr.parse().usage("Invalid metrics address")?;
+        start_http_server(metrics_addr).context("Error starting metrics server")?;
+    }
+    if let Some(endpoint) = matches.value_of("otlp-endpoint") {
+        #[cfg(feature = "otlp")]
+        {
+            use store::otlp::start_otlp_exporter;
+
+            let service_name = format!("store-{}", matches.subcommand_name().unwrap_or("cli"));
+            start_otlp_exporter(endpoint, &service_name, std::time::Duration::from_secs(30)).context("Error starting OTLP exporter")?;
+        }
+        #[cfg(not(feature = "otlp"))]
+        {
+            return Err(CliError::Other(format!("Can't send metrics to {}, OTLP support was not compiled in", endpoint)));
+        }
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_current_thread();
+    runtime.enable_all();
+
+    match matches.subcommand_name() {
+        Some("master") => {
+            use store::master::run_master;
+
+            let s_matches = matches.subcommand_matches("master").unwrap();
+            let peer_address = s_matches.value_of("peer-address").unwrap();
+            let peer_address: SocketAddr = peer_address.parse().usage("Invalid peer-address")?;
+            let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
+            let peer_cert = Path::new(peer_cert);
+            let peer_key = s_matches.value_of_os("peer-key").unwrap();
+            let peer_key = Path::new(peer_key);
+            let peer_ca_cert = s_matches.value_of_os("peer-ca-cert").unwrap();
+            let peer_ca_cert = Path::new(peer_ca_cert);
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let listen_cert = s_matches.value_of_os("listen-cert").unwrap();
+            let listen_cert = Path::new(listen_cert);
+            let listen_key = s_matches.value_of_os("listen-key").unwrap();
+            let listen_key = Path::new(listen_key);
+            let keyring = s_matches.value_of_os("keyring").map(Path::new);
+            let admin_address: Option<SocketAddr> = s_matches.value_of("admin-address").map(|address| {
+                address.parse().usage("Invalid admin-address")
+            }).transpose()?;
+            let admin_ca_cert = s_matches.value_of_os("admin-ca-cert").map(Path::new);
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(run_master(
+                    peer_address,
+                    peer_cert,
+                    peer_key,
+                    peer_ca_cert,
+                    listen_address,
+                    listen_cert,
+                    listen_key,
+                    keyring,
+                    admin_address,
+                    admin_ca_cert,
+                ))?;
+        }
+        Some("admin") => {
+            use store::DeviceId;
+            use store::admin::{AdminClient, AdminRequest, AdminResponse};
+            use store::storage_map::builder::{DeviceSpec, FailureDomain};
+            use store::storage_map::overlay::DeviceStatus;
+
+            let s_matches = matches.subcommand_matches("admin").unwrap();
+            let master = s_matches.value_of("master").unwrap();
+            let server_name = s_matches.value_of("server-name");
+            let ca_cert = Path::new(s_matches.value_of_os("ca-cert").unwrap());
+            let cert = Path::new(s_matches.value_of_os("cert").unwrap());
+            let key = Path::new(s_matches.value_of_os("key").unwrap());
+            let device_id = |matches: &ArgMatches| -> Result<DeviceId, CliError> {
+                matches.value_of("id").unwrap().parse().usage("Invalid device ID")
+            };
+            if let Some(g_matches) = s_matches.subcommand_matches("gc") {
+                use store::client::create_client_with_map;
+                use store::gc::collect_garbage;
+
+                let pool = PoolName::new(g_matches.value_of("pool").unwrap())?;
+                let capability = g_matches.value_of_os("capability").map(|path| {
+                    std::fs::read(path).context("Error reading capability")
+                }).transpose()?;
+                let options = GcOptions {
+                    grace: Duration::from_secs(g_matches.value_of("grace").unwrap().parse().usage("Invalid grace")?),
+                    striped: g_matches.is_present("striped"),
+                    s3: g_matches.is_present("s3"),
+                    dry_run: g_matches.is_present("dry-run"),
+                };
+                if !options.striped && !options.s3 {
+                    return Err(CliError::Usage("Please provide --striped, --s3, or both".to_owned()));
+                }
+                runtime
+                    .build()
+                    .unwrap()
+                    .block_on(async move {
+                        let mut admin = AdminClient::connect(master, server_name, ca_cert, cert, key).await?;
+                        let map = match admin.request(&AdminRequest::GetMap { pool: pool.0.clone() }).await? {
+                            AdminResponse::Map(map) => map,
+                            _ => return Err("Unexpected response from master".into()),
+                        };
+                        let devices = match admin.request(&AdminRequest::ListDevices).await? {
+                            AdminResponse::Devices(devices) => devices,
+                            _ => return Err("Unexpected response from master".into()),
+                        };
+                        let client = create_client_with_map(pool, map, devices).await?;
+                        if let Some(capability) = capability {
+                            client.set_credentials(&capability)?;
+                        }
+                        let report = collect_garbage(&client, &options).await?;
+                        println!("scanned: {}", report.scanned);
+                        println!("orphans: {} ({} bytes)", report.orphans, report.bytes);
+                        println!("kept as too recent: {}", report.recent);
+                        println!("deleted: {}", report.deleted);
+                        Ok(()) as Result<(), Box<dyn std::error::Error>>
+                    })?;
+                return Ok(());
+            }
+            let request = match s_matches.subcommand() {
+                Some(("status", _)) => AdminRequest::Status,
+                Some(("pool", p_matches)) => match p_matches.subcommand() {
+                    Some(("create", c_matches)) => AdminRequest::CreatePool {
+                        name: c_matches.value_of("name").unwrap().to_owned(),
+                        groups: c_matches.value_of("groups").unwrap().parse().usage("Invalid number of groups")?,
+                        replicas: c_matches.value_of("replicas").unwrap().parse().usage("Invalid number of replicas")?,
+                        failure_domain: match c_matches.value_of("failure-domain").unwrap() {
+                            "device" => FailureDomain::Device,
+                            "host" => FailureDomain::Host,
+                            "rack" => FailureDomain::Rack,
+                            _ => unreachable!(),
+                        },
+                    },
+                    Some(("rm", r_matches)) => AdminRequest::RemovePool {
+                        name: r_matches.value_of("name").unwrap().to_owned(),
+                    },
+                    _ => {
+                        cli.find_subcommand_mut("admin").unwrap()
+                            .find_subcommand_mut("pool").unwrap()
+                            .print_help()
+                            .expect("Can't print help");
+                        return Err(CliError::Usage("Missing subcommand".to_owned()));
+                    }
+                },
+                Some(("device", d_matches)) => match d_matches.subcommand() {
+                    Some(("ls", _)) => AdminRequest::ListDevices,
+                    Some(("add", a_matches)) => AdminRequest::AddDevice {
+                        spec: DeviceSpec {
+                            id: device_id(a_matches)?,
+                            host: a_matches.value_of("host").unwrap().to_owned(),
+                            rack: a_matches.value_of("rack").map(|r| r.to_owned()),
+                            weight: a_matches.value_of("weight").unwrap().parse().usage("Invalid weight")?,
+                        },
+                        address: a_matches.value_of("address").unwrap().parse().usage("Invalid address")?,
+                    },
+                    Some(("out", o_matches)) => AdminRequest::SetDeviceStatus {
+                        device_id: device_id(o_matches)?,
+                        status: DeviceStatus::Out,
+                    },
+                    Some(("in", i_matches)) => AdminRequest::SetDeviceStatus {
+                        device_id: device_id(i_matches)?,
+                        status: DeviceStatus::Up,
+                    },
+                    _ => {
+                        cli.find_subcommand_mut("admin").unwrap()
+                            .find_subcommand_mut("device").unwrap()
+                            .print_help()
+                            .expect("Can't print help");
+                        return Err(CliError::Usage("Missing subcommand".to_owned()));
+                    }
+                },
+                Some(("map", m_matches)) => match m_matches.subcommand() {
+                    Some(("show", s_matches)) => AdminRequest::GetMap {
+                        pool: s_matches.value_of("pool").unwrap().to_owned(),
+                    },
+                    _ => {
+                        cli.find_subcommand_mut("admin").unwrap()
+                            .find_subcommand_mut("map").unwrap()
+                            .print_help()
+                            .expect("Can't print help");
+                        return Err(CliError::Usage("Missing subcommand".to_owned()));
+                    }
+                },
+                _ => {
+                    cli.find_subcommand_mut("admin")
+                        .unwrap()
+                        .print_help()
+                        .expect("Can't print help");
+                    return Err(CliError::Usage("Missing subcommand".to_owned()));
+                }
+            };
+            let map_output = s_matches.subcommand_matches("map")
+                .and_then(|m| m.subcommand_matches("show"))
+                .and_then(|m| m.value_of_os("output"))
+                .map(Path::new);
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let mut client = AdminClient::connect(master, server_name, ca_cert, cert, key).await?;
+                    match client.request(&request).await? {
+                        AdminResponse::Status(status) => {
+                            println!(
+                                "devices: {} up, {} down, {} out",
+                                status.devices_up, status.devices_down, status.devices_out,
+                            );
+                            for pool in status.pools {
+                                println!(
+                                    "pool {}: generation {}, {} groups, {} replicas",
+                                    pool.name, pool.generation, pool.groups, pool.replicas,
+                                );
+                            }
+                        }
+                        AdminResponse::Devices(devices) => {
+                            println!("{:<58} {:<21} {:<12} {:<12} {:>7} status", "device", "address", "host", "rack", "weight");
+                            for device in devices {
+                                println!(
+                                    "{:<58} {:<21} {:<12} {:<12} {:>7} {:?}",
+                                    format!("{:?}", device.spec.id),
+                                    device.address.to_string(),
+                                    device.spec.host,
+                                    device.spec.rack.as_deref().unwrap_or("-"),
+                                    device.spec.weight,
+                                    device.status,
+                                );
+                            }
+                        }
+                        AdminResponse::Map(map) => match map_output {
+                            Some(path) => std::fs::write(path, map.encode())?,
+                            None => println!("{}", serde_json::to_string_pretty(&map)?),
+                        },
+                        AdminResponse::Done => {}
+                        AdminResponse::Error(_) => unreachable!(),
+                    }
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("mem-store") => {
+            use store::crypto::keyring::Keyring;
+            use store::daemon::run_storage_daemon;
+            use store::storage::mem_store::create_mem_store;
+
+            let s_matches = matches.subcommand_matches("mem-store").unwrap();
+            let peer_address = s_matches.value_of("peer-address").unwrap();
+            let peer_address: SocketAddr = peer_address.parse().usage("Invalid peer-address")?;
+            let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
+            let peer_cert = Path::new(peer_cert);
+            let peer_key = s_matches.value_of_os("peer-key").unwrap();
+            let peer_key = Path::new(peer_key);
+            let peer_ca_cert = s_matches.value_of_os("peer-ca-cert").unwrap();
+            let peer_ca_cert = Path::new(peer_ca_cert);
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let (storage_backend, device_id) = create_mem_store();
+            let capability_keys = s_matches.value_of_os("keyring").map(|path| {
+                Keyring::load(Path::new(path)).context("Error reading keyring")
+            }).transpose()?;
+            let dtls_address: Option<SocketAddr> = s_matches.value_of("dtls-address").map(|address| {
+                address.parse().usage("Invalid dtls-address")
+            }).transpose()?;
+            let receive_threads = receive_threads(s_matches)?;
+            let settings = daemon_settings(s_matches)?;
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(run_storage_daemon(
+                    peer_address,
+                    peer_cert,
+                    peer_key,
+                    peer_ca_cert,
+                    listen_address,
+                    Box::new(storage_backend),
+                    device_id,
+                    None,
+                    capability_keys,
+                    dtls_address,
+                    receive_threads,
+                    settings,
+                    || reload_daemon_settings("mem-store"),
+                ))?;
+        }
+        Some("file-store") => {
+            use store::crypto::keyring::Keyring;
+            use store::daemon::run_storage_daemon;
+            use store::storage::file_store::{create_file_store, open_file_store_read_only};
+
+            let s_matches = matches.subcommand_matches("file-store").unwrap();
+            let peer_address = s_matches.value_of("peer-address").unwrap();
+            let peer_address: SocketAddr = peer_address.parse().usage("Invalid peer-address")?;
+            let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
+            let peer_cert = Path::new(peer_cert);
+            let peer_key = s_matches.value_of_os("peer-key").unwrap();
+            let peer_key = Path::new(peer_key);
+            let peer_ca_cert = s_matches.value_of_os("peer-ca-cert").unwrap();
+            let peer_ca_cert = Path::new(peer_ca_cert);
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr =
+                listen_address.parse().usage("Invalid listen-address")?;
+            let storage_dir = s_matches.value_of_os("dir").unwrap();
+            let storage_dir = Path::new(storage_dir);
+            let (storage_backend, device_id) = if s_matches.is_present("read-only") {
+                open_file_store_read_only(storage_dir)?
+            } else {
+                create_file_store(storage_dir)?
+            };
+            // Can't write the epoch to a read-only store
+            let epoch_file = if s_matches.is_present("read-only") {
+                None
+            } else {
+                Some(storage_dir.join("epoch"))
+            };
+            let capability_keys = s_matches.value_of_os("keyring").map(|path| {
+                Keyring::load(Path::new(path)).context("Error reading keyring")
+            }).transpose()?;
+            let dtls_address: Option<SocketAddr> = s_matches.value_of("dtls-address").map(|address| {
+                address.parse().usage("Invalid dtls-address")
+            }).transpose()?;
+            let receive_threads = receive_threads(s_matches)?;
+            let settings = daemon_settings(s_matches)?;
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(run_storage_daemon(
+                    peer_address,
+                    peer_cert,
+                    peer_key,
+                    peer_ca_cert,
                     listen_address,
                     Box::new(storage_backend),
                     device_id,
-                ))
-                .unwrap();
+                    epoch_file.as_deref(),
+                    capability_keys,
+                    dtls_address,
+                    receive_threads,
+                    settings,
+                    || reload_daemon_settings("file-store"),
+                ))?;
         }
         #[cfg(feature = "rocksdb")]
         Some("rocksdb-store") => {
+            use store::crypto::keyring::Keyring;
             use store::daemon::run_storage_daemon;
-            use store::storage::rocksdb_store::create_rocksdb_store;
+            use store::storage::rocksdb_store::{create_rocksdb_store, open_rocksdb_store_read_only};
 
             let s_matches = matches.subcommand_matches("rocksdb-store").unwrap();
             let peer_address = s_matches.value_of("peer-address").unwrap();
-            let peer_address: SocketAddr = check!(
-                peer_address.parse(),
-                "Invalid peer-address",
-            );
+            let peer_address: SocketAddr = peer_address.parse().usage("Invalid peer-address")?;
             let peer_cert = s_matches.value_of_os("peer-cert").unwrap();
             let peer_cert = Path::new(peer_cert);
             let peer_key = s_matches.value_of_os("peer-key").unwrap();
@@ -435,10 +1808,28 @@ fn main() {
             let peer_ca_cert = Path::new(peer_ca_cert);
             let listen_address = s_matches.value_of("listen-address").unwrap();
             let listen_address: SocketAddr =
-                check!(listen_address.parse(), "Invalid listen-address",);
+                listen_address.parse().usage("Invalid listen-address")?;
             let storage_dir = s_matches.value_of_os("dir").unwrap();
             let storage_dir = Path::new(storage_dir);
-            let (storage_backend, device_id) = check!(create_rocksdb_store(storage_dir));
+            let (storage_backend, device_id) = if s_matches.is_present("read-only") {
+                open_rocksdb_store_read_only(storage_dir)?
+            } else {
+                create_rocksdb_store(storage_dir)?
+            };
+            // Can't write the epoch to a read-only store
+            let epoch_file = if s_matches.is_present("read-only") {
+                None
+            } else {
+                Some(storage_dir.join("epoch"))
+            };
+            let capability_keys = s_matches.value_of_os("keyring").map(|path| {
+                Keyring::load(Path::new(path)).context("Error reading keyring")
+            }).transpose()?;
+            let dtls_address: Option<SocketAddr> = s_matches.value_of("dtls-address").map(|address| {
+                address.parse().usage("Invalid dtls-address")
+            }).transpose()?;
+            let receive_threads = receive_threads(s_matches)?;
+            let settings = daemon_settings(s_matches)?;
 
             runtime
                 .build()
@@ -451,178 +1842,1623 @@ fn main() {
                     listen_address,
                     Box::new(storage_backend),
                     device_id,
-                ))
-                .unwrap();
+                    epoch_file.as_deref(),
+                    capability_keys,
+                    dtls_address,
+                    receive_threads,
+                    settings,
+                    || reload_daemon_settings("rocksdb-store"),
+                ))?;
         }
         #[cfg(not(feature = "rocksdb"))]
         Some("rocksdb-store") => {
-            eprintln!("RocksDB support was not compiled in");
-            std::process::exit(1);
+            return Err(CliError::Other("RocksDB support was not compiled in".to_owned()));
+        }
+        Some("fsck") => {
+            use store::storage::{ProblemKind, StorageBackend};
+            use store::storage::file_store::{open_file_store, open_file_store_read_only};
+
+            let s_matches = matches.subcommand_matches("fsck").unwrap();
+            let storage_dir = Path::new(s_matches.value_of_os("dir").unwrap());
+            let repair = s_matches.is_present("repair");
+
+            // Stores are opened read-only unless repairing
+            let (storage_backend, device_id): (Box<dyn StorageBackend>, _) = if storage_dir.join("pools").is_dir() {
+                let (storage_backend, device_id) = if repair {
+                    open_file_store(storage_dir)?
+                } else {
+                    open_file_store_read_only(storage_dir)?
+                };
+                (Box::new(storage_backend), device_id)
+            } else if !storage_dir.join("store.id").is_file() {
+                return Err(CliError::NotFound(format!("{} is not a store", storage_dir.display())));
+            } else {
+                #[cfg(feature = "rocksdb")]
+                {
+                    use store::storage::rocksdb_store::{create_rocksdb_store, open_rocksdb_store_read_only};
+
+                    let (storage_backend, device_id) = if repair {
+                        create_rocksdb_store(storage_dir)?
+                    } else {
+                        open_rocksdb_store_read_only(storage_dir)?
+                    };
+                    (Box::new(storage_backend), device_id)
+                }
+                #[cfg(not(feature = "rocksdb"))]
+                {
+                    return Err(CliError::Other("Not a file store, and RocksDB support was not compiled in".to_owned()));
+                }
+            };
+
+            let report = storage_backend.verify(repair).context("Error checking store")?;
+            if repair {
+                storage_backend.flush()?;
+            }
+            println!("{:?}: {} objects checked", device_id, report.objects);
+            for problem in &report.problems {
+                println!(
+                    "{}: {}{}",
+                    match problem.kind {
+                        ProblemKind::Orphaned => "orphaned",
+                        ProblemKind::Corrupt => "corrupt",
+                    },
+                    problem.description,
+                    if problem.repaired { " (repaired)" } else { "" },
+                );
+            }
+            let left = report.problems.iter().filter(|p| !p.repaired).count();
+            println!(
+                "{} problems found, {} repaired",
+                report.problems.len(), report.problems.len() - left,
+            );
+            if left > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some("read") => {
+            let s_matches = matches.subcommand_matches("read").unwrap();
+            let target = client_target(s_matches)?;
+            let pool = PoolName::new(s_matches.value_of("pool").unwrap())?;
+            let object_id = ObjectId::new(s_matches.value_of("object-id").unwrap())?;
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let offset: Option<u32> = match s_matches.value_of("offset") {
+                None => None,
+                Some(s) => match s.parse() {
+                    Ok(i) => Some(i),
+                    Err(_) => {
+                        return Err(CliError::Usage("Invalid offset".to_owned()));
+                    }
+                },
+            };
+            let length: Option<u32> = match s_matches.value_of("length") {
+                None => None,
+                Some(s) => match s.parse() {
+                    Ok(i) => Some(i),
+                    Err(_) => {
+                        return Err(CliError::Usage("Invalid length".to_owned()));
+                    }
+                },
+            };
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let client = target.connect(pool).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    let offset = offset.unwrap_or(0);
+                    let mut progress = Progress::new(None);
+                    if progress.is_enabled() {
+                        // Only known if the daemon supports stat
+                        if let Ok(Some(stat)) = client.stat_object(&object_id).await {
+                            let size = stat.size.saturating_sub(offset as u64);
+                            progress.set_total(Some(length.map_or(size, |l| size.min(l as u64))));
+                        }
+                    }
+                    let mut output = ProgressIo::new(std::io::stdout().lock(), progress);
+                    let found = client.read_to(&object_id, offset, length, &mut output).await?;
+                    output.progress.finish();
+                    if !found {
+                        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such key").into());
+                    }
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("write") => {
+            let s_matches = matches.subcommand_matches("write").unwrap();
+            let target = client_target(s_matches)?;
+            let pool = PoolName::new(s_matches.value_of("pool").unwrap())?;
+            let object_id = ObjectId::new(s_matches.value_of("object-id").unwrap())?;
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let offset: Option<u32> = match s_matches.value_of("offset") {
+                None => None,
+                Some(s) => match s.parse() {
+                    Ok(i) => Some(i),
+                    Err(_) => {
+                        return Err(CliError::Usage("Invalid offset".to_owned()));
+                    }
+                },
+            };
+            let (data, size): (Box<dyn std::io::Read>, Option<u64>) = {
+                let data_literal = s_matches.value_of("data-literal");
+                let data_file = s_matches.value_of_os("data-file");
+                if data_literal.is_some() && data_file.is_some() {
+                    cli.find_subcommand_mut("write")
+                        .unwrap()
+                        .print_help()
+                        .expect("Can't print help");
+                    return Err(CliError::Usage("Please provide EITHER --data-literal or --data-file".to_owned()));
+                } else if let Some(d) = data_literal {
+                    (Box::new(d.as_bytes()), Some(d.len() as u64))
+                } else if data_file == Some("-".as_ref()) {
+                    (Box::new(std::io::stdin().lock()), None)
+                } else if let Some(path) = data_file {
+                    let f = std::fs::File::open(path).context("Error reading data file")?;
+                    let size = f.metadata().ok().filter(|m| m.is_file()).map(|m| m.len());
+                    (Box::new(f), size)
+                } else {
+                    cli.find_subcommand_mut("write")
+                        .unwrap()
+                        .print_help()
+                        .expect("Can't print help");
+                    return Err(CliError::Usage("Data missing, please provide --data-literal or --data-file".to_owned()));
+                }
+            };
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let client = target.connect(pool).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    let mut input = ProgressIo::new(data, Progress::new(size));
+                    client.write_from(&object_id, offset, &mut input).await?;
+                    input.progress.finish();
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("delete") => {
+            let s_matches = matches.subcommand_matches("delete").unwrap();
+            let target = client_target(s_matches)?;
+            let pool = PoolName::new(s_matches.value_of("pool").unwrap())?;
+            let object_id = s_matches.value_of("object-id").unwrap();
+            let object_id = ObjectId(object_id.as_bytes().to_owned());
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let prefix = s_matches.is_present("prefix");
+            let tag = s_matches.value_of("tag");
+            let yes = s_matches.is_present("yes");
+
+            let runtime = runtime.build().unwrap();
+            let client = runtime.block_on(async {
+                let client = target.connect(pool.clone()).await?;
+                if let Some(capability) = capability {
+                    client.set_credentials(&capability)?;
+                }
+                Ok(client) as Result<Client, Box<dyn std::error::Error>>
+            })?;
+
+            if !prefix {
+                runtime.block_on(client.delete_object(&object_id))?;
+                return Ok(());
+            }
+
+            let objects = match tag {
+                Some(tag) => runtime.block_on(client.list_all_tagged_objects(&object_id.0, tag)),
+                None => runtime.block_on(client.list_all_objects(&object_id.0)),
+            }.context("Error listing objects")?;
+            if objects.is_empty() {
+                eprintln!("No objects to delete");
+                return Ok(());
+            }
+            if !yes {
+                use std::io::{BufRead, IsTerminal, Write};
+
+                if !std::io::stdin().is_terminal() {
+                    return Err(CliError::Usage(format!("Refusing to delete {} objects without --yes", objects.len())));
+                }
+                eprint!("Delete {} objects from pool {}? [y/N] ", objects.len(), pool.0);
+                std::io::stderr().flush().unwrap();
+                let mut answer = String::new();
+                std::io::stdin().lock().read_line(&mut answer)?;
+                if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                    return Err(CliError::Other("Not deleting anything".to_owned()));
+                }
+            }
+
+            runtime
+                .block_on(async {
+                    use std::io::IsTerminal;
+                    use std::time::{Duration, Instant};
+
+                    // Show progress on terminals, at most a few times per second
+                    let show_progress = std::io::stderr().is_terminal();
+                    let mut last_progress = Instant::now();
+                    for (i, object_id) in objects.iter().enumerate() {
+                        client.delete_object(object_id).await?;
+                        if show_progress && last_progress.elapsed() >= Duration::from_millis(200) {
+                            eprint!("\r{}/{} objects deleted", i + 1, objects.len());
+                            last_progress = Instant::now();
+                        }
+                    }
+                    if show_progress {
+                        eprint!("\r");
+                    }
+                    eprintln!("{} objects deleted", objects.len());
+                    Ok(()) as Result<(), std::io::Error>
+                })?;
+        }
+        Some("s3-gateway") => {
+            use store::s3_gateway::auth::Credentials;
+            use store::s3_gateway::run_s3_gateway;
+
+            let s_matches = matches.subcommand_matches("s3-gateway").unwrap();
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+            let buckets = read_pool_args(s_matches.values_of("bucket").unwrap()).context("Error reading capability")?;
+            let credentials = s_matches.value_of_os("credentials-file")
+                .map(|path| std::fs::read_to_string(path).and_then(|text| Credentials::parse(&text)))
+                .transpose()
+                .context("Error reading credentials file")?;
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let clients = connect_pools(storage_daemon_address, buckets, dtls_ca_cert).await?;
+                    run_s3_gateway(listen_address, clients, credentials).await
+                })?;
+        }
+        Some("http-gateway") => {
+            use store::http_gateway::{Tokens, run_http_gateway};
+
+            let s_matches = matches.subcommand_matches("http-gateway").unwrap();
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+            let pools = read_pool_args(s_matches.values_of("pool").unwrap()).context("Error reading capability")?;
+            let token_file = Path::new(s_matches.value_of_os("token-file").unwrap());
+            let tokens = std::fs::read_to_string(token_file).and_then(|text| Tokens::parse(&text)).context("Error reading token file")?;
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let clients = connect_pools(storage_daemon_address, pools, dtls_ca_cert).await?;
+                    run_http_gateway(listen_address, clients, tokens).await
+                })?;
+        }
+        Some("redis-gateway") => {
+            use store::http_gateway::Tokens;
+            use store::redis_gateway::run_redis_gateway;
+
+            let s_matches = matches.subcommand_matches("redis-gateway").unwrap();
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+            let pools = read_pool_args(s_matches.values_of("pool").unwrap()).context("Error reading capability")?;
+            let tokens = s_matches.value_of_os("token-file")
+                .map(|path| std::fs::read_to_string(path).and_then(|text| Tokens::parse(&text)))
+                .transpose()
+                .context("Error reading token file")?;
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let clients = connect_pools(storage_daemon_address, pools, dtls_ca_cert).await?;
+                    let client = clients.into_values().next().unwrap();
+                    run_redis_gateway(listen_address, client, tokens).await
+                })?;
+        }
+        Some("mirror") => {
+            use std::time::Duration;
+            use store::mirror::run_mirror;
+
+            let s_matches = matches.subcommand_matches("mirror").unwrap();
+            let source_address: SocketAddr = s_matches.value_of("source-storage-daemon").unwrap().parse().usage("Invalid source-storage-daemon address")?;
+            let source_dtls_ca_cert = s_matches.value_of_os("source-dtls-ca-cert").map(Path::new);
+            let source_pool = read_pool_args(s_matches.values_of("source-pool").unwrap()).context("Error reading capability")?;
+            let destination_address: SocketAddr = s_matches.value_of("destination-storage-daemon").unwrap().parse().usage("Invalid destination-storage-daemon address")?;
+            let destination_dtls_ca_cert = s_matches.value_of_os("destination-dtls-ca-cert").map(Path::new);
+            let destination_pool = read_pool_args(s_matches.values_of("destination-pool").unwrap()).context("Error reading capability")?;
+            let checkpoint = Path::new(s_matches.value_of_os("checkpoint").unwrap());
+            let interval: u64 = s_matches.value_of("interval").unwrap().parse().usage("Invalid interval")?;
+            let once = s_matches.is_present("once");
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let source = connect_pools(source_address, source_pool, source_dtls_ca_cert).await?;
+                    let destination = connect_pools(destination_address, destination_pool, destination_dtls_ca_cert).await?;
+                    run_mirror(
+                        source.into_values().next().unwrap(),
+                        destination.into_values().next().unwrap(),
+                        checkpoint,
+                        Duration::from_secs(interval),
+                        once,
+                    ).await
+                })?;
+        }
+        Some(command @ ("export" | "import")) => {
+            use store::archive::{export_pool, import_pool, is_compressed_name};
+
+            let s_matches = matches.subcommand_matches(command).unwrap();
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let pool = s_matches.value_of("pool").unwrap();
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let client = connect_client(
+                        storage_daemon_address,
+                        PoolName::new(pool)?,
+                        dtls_ca_cert,
+                    ).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    if command == "export" {
+                        let output = s_matches.value_of("output").unwrap();
+                        let compress = s_matches.is_present("zstd") || is_compressed_name(output);
+                        let count = if output == "-" {
+                            let mut output = ProgressIo::new(std::io::stdout().lock(), Progress::new(None));
+                            export_pool(&client, pool, &mut output, compress).await?
+                        } else {
+                            let file = std::io::BufWriter::new(std::fs::File::create(output)?);
+                            let mut output = ProgressIo::new(file, Progress::new(None));
+                            export_pool(&client, pool, &mut output, compress).await?
+                        };
+                        eprintln!("Exported {} objects", count);
+                    } else {
+                        let input = s_matches.value_of("input").unwrap();
+                        let count = if input == "-" {
+                            let mut input = ProgressIo::new(std::io::stdin().lock(), Progress::new(None));
+                            import_pool(&client, &mut input).await?
+                        } else {
+                            // The size read from the file, compressed or not
+                            let file = std::fs::File::open(input)?;
+                            let size = file.metadata()?.len();
+                            let mut input = ProgressIo::new(file, Progress::new(Some(size)));
+                            import_pool(&client, &mut input).await?
+                        };
+                        eprintln!("Imported {} objects", count);
+                    }
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("grpc-gateway") => {
+            #[cfg(feature = "grpc")]
+            {
+                use store::grpc::run_grpc_gateway;
+                use store::http_gateway::Tokens;
+
+                let s_matches = matches.subcommand_matches("grpc-gateway").unwrap();
+                let listen_address = s_matches.value_of("listen-address").unwrap();
+                let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+                let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+                let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+                let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+                let pools = read_pool_args(s_matches.values_of("pool").unwrap()).context("Error reading capability")?;
+                let token_file = Path::new(s_matches.value_of_os("token-file").unwrap());
+                let tokens = std::fs::read_to_string(token_file).and_then(|text| Tokens::parse(&text)).context("Error reading token file")?;
+
+                runtime
+                    .build()
+                    .unwrap()
+                    .block_on(async move {
+                        let clients = connect_pools(storage_daemon_address, pools, dtls_ca_cert).await?;
+                        run_grpc_gateway(listen_address, clients, tokens).await
+                    })?;
+            }
+            #[cfg(not(feature = "grpc"))]
+            {
+                return Err(CliError::Other("gRPC support was not compiled in".to_owned()));
+            }
+        }
+        Some("cp") => {
+            use store::copy::{CopyStats, copy_object, copy_prefix};
+
+            let s_matches = matches.subcommand_matches("cp").unwrap();
+            let source_address: SocketAddr = s_matches.value_of("source-storage-daemon").unwrap().parse().usage("Invalid source-storage-daemon address")?;
+            let source_dtls_ca_cert = s_matches.value_of_os("source-dtls-ca-cert").map(Path::new);
+            let source_pool = s_matches.value_of("source-pool").unwrap();
+            let destination_address: SocketAddr = match s_matches.value_of("destination-storage-daemon") {
+                Some(address) => address.parse().usage("Invalid destination-storage-daemon address")?,
+                None => source_address,
+            };
+            let destination_dtls_ca_cert = match s_matches.value_of_os("destination-dtls-ca-cert") {
+                Some(path) => Some(Path::new(path)),
+                None if destination_address == source_address => source_dtls_ca_cert,
+                None => None,
+            };
+            let destination_pool = s_matches.value_of("destination-pool").unwrap_or(source_pool);
+            let source = s_matches.value_of("source").unwrap();
+            let destination = s_matches.value_of("destination").unwrap_or(source);
+            let prefix = s_matches.is_present("prefix");
+            if destination_address == source_address && destination_pool == source_pool && destination == source {
+                return Err(CliError::Usage("Source and destination are the same".to_owned()));
+            }
+            let source_pool = read_pool_args(std::iter::once(source_pool)).context("Error reading capability")?;
+            let destination_pool = read_pool_args(std::iter::once(destination_pool)).context("Error reading capability")?;
+
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let source_client = connect_pools(source_address, source_pool, source_dtls_ca_cert).await?
+                        .into_values().next().unwrap();
+                    let destination_client = connect_pools(destination_address, destination_pool, destination_dtls_ca_cert).await?
+                        .into_values().next().unwrap();
+                    let mut progress = Progress::new(None);
+                    let stats = if prefix {
+                        copy_prefix(
+                            &source_client, source.as_bytes(),
+                            &destination_client, destination.as_bytes(),
+                            &mut |stats: &CopyStats| progress.set(stats.bytes, Some(stats.objects)),
+                        ).await?
+                    } else {
+                        let source_id = ObjectId::new(source)?;
+                        if progress.is_enabled() {
+                            // Only known if the daemon supports stat
+                            if let Ok(Some(stat)) = source_client.stat_object(&source_id).await {
+                                progress.set_total(Some(stat.size));
+                            }
+                        }
+                        let mut stats = CopyStats::default();
+                        let found = copy_object(
+                            &source_client, &source_id,
+                            &destination_client, &ObjectId::new(destination)?,
+                            &mut stats, &mut |stats: &CopyStats| progress.set(stats.bytes, None),
+                        ).await?;
+                        if !found {
+                            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such key").into());
+                        }
+                        stats
+                    };
+                    progress.finish();
+                    eprintln!("{} objects, {} bytes copied", stats.objects, stats.bytes);
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("du") => {
+            use std::collections::BTreeMap;
+            use std::time::Duration;
+
+            let s_matches = matches.subcommand_matches("du").unwrap();
+            let storage_daemon_addresses: Vec<SocketAddr> = s_matches.values_of("storage-daemon").unwrap()
+                .map(|address| address.parse().usage("Invalid storage-daemon address"))
+                .collect::<Result<_, _>>()?;
+            let pool = if s_matches.is_present("all-pools") {
+                None
+            } else {
+                Some(s_matches.value_of("pool").unwrap())
+            };
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+
+            let mut daemons = runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let mut daemons = Vec::new();
+                    for address in storage_daemon_addresses {
+                        // Daemons report on all the pools, this one is only
+                        // used to check the capability
+                        let client = connect_client(
+                            address,
+                            PoolName::new(pool.unwrap_or("default"))?,
+                            dtls_ca_cert,
+                        ).await?;
+                        if let Some(ref capability) = capability {
+                            client.set_credentials(capability)?;
+                        }
+                        let stats = client.with_timeout(Duration::from_secs(10)).daemon_stats().await
+                            .map_err(|e| format!("{}: {}", address, e))?;
+                        daemons.extend(stats);
+                    }
+                    Ok(daemons) as Result<Vec<DaemonStats>, Box<dyn std::error::Error>>
+                })?;
+            daemons.sort_by_key(|d| d.device_id.0);
+            for daemon in &mut daemons {
+                daemon.pools.retain(|usage| pool.is_none_or(|p| usage.pool.0 == p));
+            }
+
+            // Daemons count every replica they hold, divide by the number of
+            // replicas to get the pool's data
+            let mut pools: BTreeMap<&str, (u32, u64, u64)> = BTreeMap::new();
+            for usage in daemons.iter().flat_map(|d| &d.pools) {
+                let totals = pools.entry(&usage.pool.0).or_default();
+                totals.0 = totals.0.max(usage.replicas);
+                totals.1 += usage.objects;
+                totals.2 += usage.bytes;
+            }
+            if let Some(pool) = pool {
+                if !pools.contains_key(pool) {
+                    return Err(CliError::NotFound(format!("No pool {} on the storage daemons", pool)));
+                }
+            }
+
+            println!(
+                "{:<20} {:>8} {:>12} {:>14} {:>12} {:>14}",
+                "pool", "replicas", "objects", "bytes", "raw objects", "raw bytes",
+            );
+            for (name, (replicas, objects, bytes)) in &pools {
+                let replicas = *replicas.max(&1);
+                println!(
+                    "{:<20} {:>8} {:>12} {:>14} {:>12} {:>14}",
+                    name, replicas, objects / replicas as u64, bytes / replicas as u64, objects, bytes,
+                );
+            }
+            println!();
+            println!("{:<58} {:<20} {:>12} {:>14} {:>14}", "device", "pool", "raw objects", "raw bytes", "free");
+            for daemon in &daemons {
+                let free = match daemon.free_space {
+                    Some(free) => free.to_string(),
+                    None => "-".to_owned(),
+                };
+                for usage in &daemon.pools {
+                    println!(
+                        "{:<58} {:<20} {:>12} {:>14} {:>14}",
+                        format!("{:?}", daemon.device_id), usage.pool.0, usage.objects, usage.bytes, free,
+                    );
+                }
+            }
+        }
+        Some("tag") => {
+            let s_matches = matches.subcommand_matches("tag").unwrap();
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let pool = PoolName::new(s_matches.value_of("pool").unwrap())?;
+            let object_id = ObjectId::new(s_matches.value_of("object-id").unwrap())?;
+            let tags: Option<Vec<String>> = match s_matches.values_of("tags") {
+                Some(tags) => Some(tags.map(str::to_owned).collect()),
+                None if s_matches.is_present("clear") => Some(Vec::new()),
+                None => None,
+            };
+            if let Some(tags) = &tags {
+                store::check_tags(tags).usage("Invalid tags")?;
+            }
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let client = connect_client(storage_daemon_address, pool, dtls_ca_cert).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    match tags {
+                        Some(tags) => client.set_tags(&object_id, &tags).await?,
+                        None => match client.stat_object(&object_id).await? {
+                            Some(stat) => {
+                                for tag in stat.tags {
+                                    println!("{}", tag);
+                                }
+                            }
+                            None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such key").into()),
+                        },
+                    }
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
+        }
+        Some("watch") => {
+            use std::io::Write;
+            use std::time::Duration;
+            use store::client::WatchCursor;
+
+            let s_matches = matches.subcommand_matches("watch").unwrap();
+            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let pool = s_matches.value_of("pool").unwrap();
+            let prefix = s_matches.value_of("prefix").unwrap().as_bytes().to_owned();
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+
+            runtime
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let client = connect_client(
+                        storage_daemon_address,
+                        PoolName::new(pool)?,
+                        dtls_ca_cert,
+                    ).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    let client = client.with_timeout(Duration::from_secs(10));
+
+                    let mut cursor = WatchCursor::default();
+                    let mut interval = tokio::time::interval(Duration::from_millis(500));
+                    loop {
+                        interval.tick().await;
+                        let (events, lost) = client.watch(&prefix, &mut cursor).await?;
+                        if lost {
+                            eprintln!("Some changes were missed");
+                        }
+                        let mut stdout = std::io::stdout().lock();
+                        let written = events.iter()
+                            .try_for_each(|event| {
+                                writeln!(stdout, "{} {}", event.kind.name(), event.object_id.0.escape_ascii())
+                            })
+                            // Show changes right away, even through a pipe
+                            .and_then(|()| stdout.flush());
+                        match written {
+                            // The reader went away, as with `store watch | head`
+                            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+                            r => r?,
+                        }
+                    }
+                    Ok(()) as Result<(), Box<dyn std::error::Error>>
+                })?;
         }
-        Some("read") => {
-            use store::client::create_client;
+        Some("bench") => {
+            use std::time::Duration;
+            use store::bench::{BenchConfig, BenchMode, run_bench};
 
-            let s_matches = matches.subcommand_matches("read").unwrap();
+            let s_matches = matches.subcommand_matches("bench").unwrap();
             let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
-            let storage_daemon_address: SocketAddr = check!(
-                storage_daemon_address.parse(),
-                "Invalid storage-daemon address",
-            );
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
             let pool = s_matches.value_of("pool").unwrap();
-            let object_id = s_matches.value_of("object-id").unwrap();
-            let object_id = ObjectId(object_id.as_bytes().to_owned());
-            let offset: Option<u32> = match s_matches.value_of("offset") {
-                None => None,
-                Some(s) => match s.parse() {
-                    Ok(i) => Some(i),
-                    Err(_) => {
-                        eprintln!("Invalid offset");
-                        std::process::exit(2);
-                    }
-                },
-            };
-            let length: Option<u32> = match s_matches.value_of("length") {
-                None => None,
-                Some(s) => match s.parse() {
-                    Ok(i) => Some(i),
-                    Err(_) => {
-                        eprintln!("Invalid length");
-                        std::process::exit(2);
-                    }
+            let capability = s_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+            let config = BenchConfig {
+                mode: match s_matches.value_of("mode").unwrap() {
+                    "write" => BenchMode::Write,
+                    "read" => BenchMode::Read,
+                    "randread" => BenchMode::RandRead,
+                    _ => unreachable!(),
                 },
+                prefix: s_matches.value_of("prefix").unwrap().as_bytes().to_owned(),
+                object_size: parse_size(s_matches.value_of("object-size").unwrap()).usage("Invalid object-size")? as usize,
+                threads: s_matches.value_of("threads").unwrap().parse().usage("Invalid threads")?,
+                duration: Duration::from_secs(s_matches.value_of("duration").unwrap().parse().usage("Invalid duration")?),
+                cleanup: !s_matches.is_present("no-cleanup"),
             };
 
             runtime
                 .build()
                 .unwrap()
                 .block_on(async move {
-                    let client =
-                        create_client(storage_daemon_address, PoolName(pool.to_owned())).await?;
-                    let data = match (offset, length) {
-                        (None, None) => client.read_object(&object_id).await?,
-                        (offset, length) => {
-                            client
-                                .read_part(
-                                    &object_id,
-                                    offset.unwrap_or(0),
-                                    length.unwrap_or(u32::MAX),
-                                )
-                                .await?
-                        }
-                    };
-                    match data {
-                        None => eprintln!("No such key"),
-                        Some(bytes) => std::io::stdout().write_all(&bytes).unwrap(),
+                    let client = connect_client(
+                        storage_daemon_address,
+                        PoolName::new(pool)?,
+                        dtls_ca_cert,
+                    ).await?;
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
                     }
+                    let report = run_bench(&client, config).await?;
+                    println!("{}", report);
                     Ok(()) as Result<(), Box<dyn std::error::Error>>
-                })
-                .unwrap();
+                })?;
         }
-        Some("write") => {
-            use store::client::create_client;
+        Some("keyring") => {
+            use store::crypto::KeyPair;
+            use store::crypto::keyring::Keyring;
 
-            let s_matches = matches.subcommand_matches("write").unwrap();
-            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
-            let storage_daemon_address: SocketAddr = check!(
-                storage_daemon_address.parse(),
-                "Invalid storage-daemon address",
-            );
-            let pool = s_matches.value_of("pool").unwrap();
-            let object_id = s_matches.value_of("object-id").unwrap();
-            let object_id = ObjectId(object_id.as_bytes().to_owned());
-            let offset: Option<u32> = match s_matches.value_of("offset") {
-                None => None,
-                Some(s) => match s.parse() {
-                    Ok(i) => Some(i),
-                    Err(_) => {
-                        eprintln!("Invalid offset");
-                        std::process::exit(2);
+            let s_matches = matches.subcommand_matches("keyring").unwrap();
+            match s_matches.subcommand() {
+                Some(("create", k_matches)) => {
+                    let path = Path::new(k_matches.value_of_os("file").unwrap());
+                    if path.exists() {
+                        return Err(CliError::Other("Keyring file already exists".to_owned()));
                     }
-                },
-            };
-            let data: Cow<[u8]> = {
-                let data_literal = s_matches.value_of("data-literal");
-                let data_file = s_matches.value_of_os("data-file");
-                if data_literal.is_some() && data_file.is_some() {
-                    eprintln!("Please provide EITHER --data-literal or --data-file");
-                    cli.find_subcommand_mut("write")
+                    let keyring = Keyring::new(KeyPair::generate());
+                    keyring.save(path).context("Error writing keyring")?;
+                    println!("{}", keyring.current().fingerprint());
+                }
+                Some(("rotate", k_matches)) => {
+                    let path = Path::new(k_matches.value_of_os("file").unwrap());
+                    let keep: usize = k_matches.value_of("keep").unwrap().parse().usage("Invalid number of keys to keep")?;
+                    let mut keyring = Keyring::load(path).context("Error reading keyring")?;
+                    let fingerprint = keyring.rotate(keep).fingerprint();
+                    keyring.save(path).context("Error writing keyring")?;
+                    println!("{}", fingerprint);
+                }
+                Some(("add", k_matches)) => {
+                    let path = Path::new(k_matches.value_of_os("file").unwrap());
+                    let source = Path::new(k_matches.value_of_os("source").unwrap());
+                    let keep: Option<usize> = k_matches.value_of("keep").map(|v| {
+                        v.parse().usage("Invalid number of keys to keep")
+                    }).transpose()?;
+                    let source = Keyring::load(source).context("Error reading source keyring")?;
+                    let (mut keyring, added) = if path.exists() {
+                        let mut keyring = Keyring::load(path).context("Error reading keyring")?;
+                        let added = keyring.add(source.keys());
+                        (keyring, added)
+                    } else {
+                        (source.clone(), source.keys().len())
+                    };
+                    if let Some(keep) = keep {
+                        keyring.truncate(keep);
+                    }
+                    keyring.save(path).context("Error writing keyring")?;
+                    eprintln!("{} keys added", added);
+                    println!("{}", keyring.current().fingerprint());
+                }
+                Some(("issue", k_matches)) => {
+                    use store::crypto::capability::{Capability, encode_credentials, parse_ops};
+                    use store::crypto::session::issue_ticket;
+
+                    let path = Path::new(k_matches.value_of_os("file").unwrap());
+                    let output = Path::new(k_matches.value_of_os("output").unwrap());
+                    let client_id = k_matches.value_of("client-id").unwrap();
+                    let pools = k_matches.values_of("pool")
+                        .map(|v| v.map(PoolName::new).collect::<Result<_, _>>())
+                        .transpose()?
+                        .unwrap_or_default();
+                    let ops = parse_ops(k_matches.value_of("ops").unwrap()).usage("Invalid ops")?;
+                    let lifetime: u64 = k_matches.value_of("lifetime").unwrap().parse().usage("Invalid lifetime")?;
+                    let keyring = Keyring::load(path).context("Error reading keyring")?;
+                    let lifetime = std::time::Duration::from_secs(lifetime);
+                    let capability = Capability::new(client_id.to_owned(), pools, ops, lifetime);
+                    let credentials = encode_credentials(&capability.seal(&keyring), &issue_ticket(&keyring, lifetime));
+                    std::fs::write(output, &*credentials).context("Error writing credentials")?;
+                }
+                Some(("list", k_matches)) => {
+                    let path = Path::new(k_matches.value_of_os("file").unwrap());
+                    let keyring = Keyring::load(path).context("Error reading keyring")?;
+                    let current = keyring.current();
+                    for key in keyring.keys() {
+                        println!(
+                            "{}{}",
+                            key.fingerprint(),
+                            if key == current { " (current)" } else { "" },
+                        );
+                    }
+                }
+                _ => {
+                    cli.find_subcommand_mut("keyring")
                         .unwrap()
                         .print_help()
                         .expect("Can't print help");
-                    std::process::exit(2);
-                } else if let Some(d) = data_literal {
-                    Cow::Borrowed(d.as_bytes())
-                } else if let Some(path) = data_file {
-                    fn read_file(path: &Path) -> Result<Vec<u8>, std::io::Error> {
-                        use std::io::Read;
-                        let mut file = std::fs::File::open(path)?;
-                        let mut data = Vec::new();
-                        file.read_to_end(&mut data)?;
-                        Ok(data)
+                    return Err(CliError::Usage("Missing subcommand".to_owned()));
+                }
+            }
+        }
+        Some("map") => {
+            use store::storage_map::StorageMap;
+
+            let s_matches = matches.subcommand_matches("map").unwrap();
+            match s_matches.subcommand() {
+                Some(("analyze", a_matches)) => {
+                    let read_map = |path: &Path| -> Result<StorageMap, CliError> {
+                        let data = std::fs::read(path).context("Error reading map")?;
+                        let map = if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+                            serde_json::from_slice(&data).map_err(|e| CliError::Data(format!("Invalid map: {}", e)))?
+                        } else {
+                            StorageMap::decode(&data).map_err(|e| CliError::Data(format!("Invalid map: {}", e)))?
+                        };
+                        let problems = map.validate();
+                        if !problems.is_empty() {
+                            let mut message = format!("Invalid map {}:", path.display());
+                            for problem in problems {
+                                message.push_str(&format!("\n  {}", problem));
+                            }
+                            return Err(CliError::Data(message));
+                        }
+                        Ok(map)
+                    };
+                    let map = read_map(Path::new(a_matches.value_of_os("map").unwrap()))?;
+                    let current = a_matches.value_of_os("current").map(|p| read_map(Path::new(p))).transpose()?;
+                    let objects: Option<u32> = a_matches.value_of("objects")
+                        .map(|o| o.parse().usage("Invalid number of objects"))
+                        .transpose()?;
+
+                    let distribution = match objects {
+                        Some(objects) => map.analyze_objects(objects),
+                        None => {
+                            let groups = a_matches.value_of("groups").unwrap().parse().usage("Invalid number of groups")?;
+                            map.analyze(groups)
+                        }
+                    };
+                    println!("{:<58} {:>12} {:>10} {:>7}", "device", "expected", "actual", "ratio");
+                    for device in &distribution.devices {
+                        println!(
+                            "{:<58} {:>12.1} {:>10} {:>7.3}",
+                            format!("{:?}", device.device_id),
+                            device.expected,
+                            device.actual,
+                            device.ratio(),
+                        );
+                    }
+                    println!();
+                    match objects {
+                        Some(_) => println!("objects: {} in {} groups", distribution.placed, map.groups),
+                        None => println!("groups: {}", distribution.placed),
                     }
+                    println!("missing replicas: {}", distribution.missing_replicas);
+                    println!("standard deviation: {:.4}", distribution.std_dev);
+                    println!("worst imbalance: {:+.2}%", distribution.max_imbalance * 100.0);
+
+                    if let Some(current) = current {
+                        use std::collections::HashMap;
+                        use store::DeviceId;
+
+                        // Movement is counted in objects if simulating them,
+                        // else in groups
+                        let diff = current.diff(&map);
+                        let counts = objects.map(|objects| map.objects_per_group(objects));
+                        let size = |group_id: &store::GroupId| match &counts {
+                            Some(counts) => counts[group_id.0 as usize],
+                            None => 1,
+                        };
+                        let unit = if objects.is_some() { "objects" } else { "groups" };
 
-                    match read_file(Path::new(path)) {
-                        Ok(d) => Cow::Owned(d),
-                        Err(e) => {
-                            eprintln!("Error reading data file: {}", e);
-                            std::process::exit(1);
+                        let mut devices: Vec<DeviceId> = Vec::new();
+                        let mut moves: HashMap<DeviceId, (u64, u64)> = HashMap::new();
+                        for change in &diff.changes {
+                            for device_id in change.added_devices() {
+                                moves.entry(device_id.clone()).or_insert_with(|| {
+                                    devices.push(device_id.clone());
+                                    (0, 0)
+                                }).0 += size(&change.group_id);
+                            }
+                            for device_id in change.removed_devices() {
+                                moves.entry(device_id.clone()).or_insert_with(|| {
+                                    devices.push(device_id.clone());
+                                    (0, 0)
+                                }).1 += size(&change.group_id);
+                            }
                         }
+                        devices.sort_by_key(|d| d.0);
+
+                        println!();
+                        println!("{:<58} {:>10} {:>10}", "device", "gains", "drops");
+                        for device_id in &devices {
+                            let (gained, dropped) = moves[device_id];
+                            println!("{:<58} {:>10} {:>10}", format!("{:?}", device_id), gained, dropped);
+                        }
+                        println!();
+                        let total: u64 = match &counts {
+                            Some(counts) => counts.iter().sum(),
+                            None => diff.groups as u64,
+                        };
+                        let moved: u64 = diff.changes.iter().map(|c| size(&c.group_id)).sum();
+                        println!(
+                            "changed {}: {} of {} ({:.2}%)",
+                            unit, moved, total, moved as f64 * 100.0 / total.max(1) as f64,
+                        );
+                        println!(
+                            "primary changes: {} of {} groups",
+                            diff.primary_changes(), diff.groups,
+                        );
+                        println!("replicas to copy ({}): {}", unit, diff.bytes_to_move(size));
                     }
-                } else {
-                    eprintln!("Data missing, please provide --data-literal or --data-file");
-                    cli.find_subcommand_mut("write")
+                }
+                _ => {
+                    cli.find_subcommand_mut("map")
+                        .unwrap()
+                        .print_help()
+                        .expect("Can't print help");
+                    return Err(CliError::Usage("Missing subcommand".to_owned()));
+                }
+            }
+        }
+        Some("image") => {
+            use std::io::{Read, Seek, SeekFrom};
+            use store::image::qcow2;
+
+            let s_matches = matches.subcommand_matches("image").unwrap();
+            let (command, i_matches) = match s_matches.subcommand() {
+                Some(c) => c,
+                None => {
+                    cli.find_subcommand_mut("image")
                         .unwrap()
                         .print_help()
                         .expect("Can't print help");
-                    std::process::exit(2);
+                    return Err(CliError::Usage("Missing subcommand".to_owned()));
                 }
             };
+            let storage_daemon_address = i_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon_address.parse().usage("Invalid storage-daemon address")?;
+            let pool = i_matches.value_of("pool").unwrap();
+            let image = i_matches.value_of("image").unwrap().as_bytes();
+            let capability = i_matches.value_of_os("capability").map(|path| {
+                std::fs::read(path).context("Error reading capability")
+            }).transpose()?;
+            let dtls_ca_cert = i_matches.value_of_os("dtls-ca-cert").map(Path::new);
 
             runtime
                 .build()
                 .unwrap()
                 .block_on(async move {
-                    let client = create_client(
+                    let client = connect_client(
                         storage_daemon_address,
-                        PoolName(pool.to_owned()),
+                        PoolName::new(pool)?,
+                        dtls_ca_cert,
                     ).await?;
-                    match offset {
-                        None => client.write_object(&object_id, &data).await?,
-                        Some(offset) => client.write_part(&object_id, offset, &data).await?,
+                    if let Some(capability) = capability {
+                        client.set_credentials(&capability)?;
+                    }
+                    match command {
+                        "create" => {
+                            let size = parse_size(i_matches.value_of("size").unwrap())?;
+                            let geometry = image_geometry(i_matches, size)?;
+                            let preallocate = i_matches.is_present("preallocate");
+                            store::image::create_image(&client, image, &geometry, preallocate).await?;
+                        }
+                        "resize" => {
+                            let size = parse_size(i_matches.value_of("size").unwrap())?;
+                            store::image::resize_image(&client, image, size).await?;
+                        }
+                        "delete" => {
+                            store::image::delete_image(&client, image).await?;
+                        }
+                        "snapshot" => {
+                            let snapshot = i_matches.value_of("snapshot").unwrap();
+                            store::image::snapshot_image(&client, image, snapshot).await?;
+                        }
+                        "info" => {
+                            let geometry = store::image::read_image(&client, image).await?
+                                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No such image"))?;
+                            let blocks = store::image::list_image_blocks(&client, image).await?;
+                            let allocated = blocks.iter().filter(|&&b| b < geometry.num_blocks()).count();
+                            println!("size: {}", geometry.size);
+                            println!("block size: {}", geometry.block_size);
+                            println!("stripe unit: {}", geometry.stripe_unit);
+                            println!("blocks: {}", geometry.num_blocks());
+                            println!("allocated blocks: {}", allocated);
+                            if let Some(holder) = store::image::image_lock_holder(&client, image).await? {
+                                println!("locked by: {}", holder);
+                            }
+                        }
+                        "import" => {
+                            let path = Path::new(i_matches.value_of_os("file").unwrap());
+                            let mut file = std::fs::File::open(path)?;
+                            let qcow2 = match i_matches.value_of("format") {
+                                Some(format) => format == "qcow2",
+                                None => {
+                                    let mut header = [0; 4];
+                                    let len = file.read(&mut header)?;
+                                    file.seek(SeekFrom::Start(0))?;
+                                    qcow2::is_qcow2(&header[..len])
+                                }
+                            };
+                            if qcow2 {
+                                let reader = qcow2::Qcow2Reader::new(std::io::BufReader::new(file))?;
+                                let geometry = image_geometry(i_matches, reader.size())?;
+                                store::image::import_image(&client, image, &geometry, reader).await?;
+                            } else {
+                                let geometry = image_geometry(i_matches, file.metadata()?.len())?;
+                                store::image::import_image(&client, image, &geometry, std::io::BufReader::new(file)).await?;
+                            }
+                        }
+                        "export" => {
+                            let path = Path::new(i_matches.value_of_os("file").unwrap());
+                            let mut file = std::fs::File::create(path)?;
+                            let geometry = store::image::export_image(&client, image, &mut file).await?;
+                            file.set_len(geometry.size)?;
+                        }
+                        _ => unreachable!(),
                     }
                     Ok(()) as Result<(), Box<dyn std::error::Error>>
-                })
-                .unwrap();
+                })?;
         }
-        Some("delete") => {
-            use store::client::create_client;
+        Some("provisioner") => {
+            use store::http_gateway::Tokens;
+            use store::provisioner::{ProvisionerConfig, run_provisioner};
 
-            let s_matches = matches.subcommand_matches("delete").unwrap();
-            let storage_daemon_address = s_matches.value_of("storage-daemon").unwrap();
-            let storage_daemon_address: SocketAddr = check!(
-                storage_daemon_address.parse(),
-                "Invalid storage-daemon address",
-            );
-            let pool = s_matches.value_of("pool").unwrap();
-            let object_id = s_matches.value_of("object-id").unwrap();
-            let object_id = ObjectId(object_id.as_bytes().to_owned());
+            let s_matches = matches.subcommand_matches("provisioner").unwrap();
+            let listen_address = s_matches.value_of("listen-address").unwrap();
+            let listen_address: SocketAddr = listen_address.parse().usage("Invalid listen-address")?;
+            let storage_daemon = s_matches.value_of("storage-daemon").unwrap();
+            let storage_daemon_address: SocketAddr = storage_daemon.parse().usage("Invalid storage-daemon address")?;
+            let dtls_ca_cert = s_matches.value_of_os("dtls-ca-cert").map(Path::new);
+            let pools = read_pool_args(s_matches.values_of("pool").unwrap()).context("Error reading capability")?;
+            let token_file = Path::new(s_matches.value_of_os("token-file").unwrap());
+            let tokens = std::fs::read_to_string(token_file).and_then(|text| Tokens::parse(&text)).context("Error reading token file")?;
+            let geometry = image_geometry(s_matches, 0).usage("Invalid geometry")?;
+            let config = ProvisionerConfig {
+                pool: pools[0].0.clone(),
+                storage_daemon: s_matches.value_of("attach-storage-daemon").unwrap_or(storage_daemon).to_owned(),
+                block_size: geometry.block_size,
+                stripe_unit: geometry.stripe_unit,
+            };
 
             runtime
                 .build()
                 .unwrap()
                 .block_on(async move {
-                    let client = create_client(
-                        storage_daemon_address,
-                        PoolName(pool.to_owned()),
-                    ).await?;
-                    client.delete_object(&object_id).await?;
-                    Ok(()) as Result<(), Box<dyn std::error::Error>>
-                })
-                .unwrap();
+                    let clients = connect_pools(storage_daemon_address, pools, dtls_ca_cert).await?;
+                    let client = clients.into_values().next().unwrap();
+                    run_provisioner(listen_address, client, config, tokens).await
+                })?;
+        }
+        Some("completions") => {
+            let s_matches = matches.subcommand_matches("completions").unwrap();
+            let shell: clap_complete::Shell = s_matches.value_of("shell").unwrap().parse().unwrap();
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut cli, "store", &mut script);
+            std::io::stdout().write_all(&script).context("Error writing completion script")?;
+        }
+        Some("man") => {
+            write_man_page(&mut cli, &mut std::io::stdout().lock()).context("Error writing manual page")?;
         }
         _ => {
             cli.print_help().expect("Can't print help");
-            std::process::exit(2);
+            return Err(CliError::Usage("Missing subcommand".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Why a command failed, which decides the exit code, so scripts can tell
+/// failures apart.
+#[derive(Debug)]
+enum CliError {
+    /// Invalid arguments or configuration.
+    Usage(String),
+    /// A missing object, pool, or file.
+    NotFound(String),
+    /// The master or storage daemons could not be reached.
+    Connection(String),
+    /// Corrupt or invalid data, read from a file or the cluster.
+    Data(String),
+    Other(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Other(_) => 1,
+            CliError::Usage(_) => 2,
+            CliError::NotFound(_) => 3,
+            CliError::Connection(_) => 4,
+            CliError::Data(_) => 5,
+        }
+    }
+
+    /// Classify an error from its `ErrorKind`, if it is an I/O error.
+    fn classify(message: String, error: &(dyn std::error::Error + 'static)) -> CliError {
+        use std::io::ErrorKind;
+
+        match error.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(ErrorKind::InvalidInput) => CliError::Usage(message),
+            Some(ErrorKind::NotFound) => CliError::NotFound(message),
+            Some(
+                ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected | ErrorKind::AddrNotAvailable | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable | ErrorKind::TimedOut
+            ) => CliError::Connection(message),
+            Some(ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => CliError::Data(message),
+            _ => CliError::Other(message),
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CliError::Usage(m) | CliError::NotFound(m) | CliError::Connection(m) | CliError::Data(m) | CliError::Other(m) => {
+                f.write_str(m)
+            }
+        }
+    }
+}
+
+impl<E: Into<Box<dyn std::error::Error>>> From<E> for CliError {
+    fn from(error: E) -> CliError {
+        let error = error.into();
+        CliError::classify(error.to_string(), &*error)
+    }
+}
+
+/// Add a message to errors, turning them into a `CliError`.
+trait Context<T> {
+    /// Fail as an invalid argument.
+    fn usage(self, message: &str) -> Result<T, CliError>;
+
+    /// Fail with the exit code for this kind of error.
+    fn context(self, message: &str) -> Result<T, CliError>;
+}
+
+impl<T, E: Into<Box<dyn std::error::Error>>> Context<T> for Result<T, E> {
+    fn usage(self, message: &str) -> Result<T, CliError> {
+        self.map_err(|e| CliError::Usage(format!("{}: {}", message, e.into())))
+    }
+
+    fn context(self, message: &str) -> Result<T, CliError> {
+        self.map_err(|e| {
+            let error = e.into();
+            CliError::classify(format!("{}: {}", message, error), &*error)
+        })
+    }
+}
+
+/// Escape text for roff.
+fn roff_escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+/// Write a manual page describing every subcommand, in roff format.
+fn write_man_page(cli: &mut Command, out: &mut dyn Write) -> std::io::Result<()> {
+    cli.build();
+    writeln!(out, ".TH STORE 1 \"\" \"store {}\"", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, ".SH NAME\nstore \\- {}", roff_escape(cli.get_about().unwrap_or("")))?;
+    writeln!(out, ".SH SYNOPSIS\n\\fBstore\\fR [OPTIONS] <SUBCOMMAND>")?;
+    writeln!(out, ".SH OPTIONS")?;
+    write_man_arguments(cli, true, out)?;
+    writeln!(out, ".SH COMMANDS")?;
+    for command in cli.get_subcommands_mut() {
+        write_man_command(command, out)?;
+    }
+    if let Some(author) = cli.get_author() {
+        writeln!(out, ".SH AUTHORS\n{}", roff_escape(author))?;
+    }
+    Ok(())
+}
+
+fn write_man_command(command: &mut Command, out: &mut dyn Write) -> std::io::Result<()> {
+    if command.is_hide_set() {
+        return Ok(());
+    }
+    let name = command.get_bin_name().unwrap_or(command.get_name()).to_owned();
+    writeln!(out, ".SS \"{}\"", roff_escape(&name))?;
+    if let Some(about) = command.get_long_about().or(command.get_about()) {
+        writeln!(out, "{}", roff_escape(about))?;
+    }
+    let usage = command.render_usage();
+    writeln!(out, ".PP\n\\fB{}\\fR", roff_escape(usage.trim_start_matches("USAGE:").trim()))?;
+    write_man_arguments(command, false, out)?;
+    for subcommand in command.get_subcommands_mut() {
+        write_man_command(subcommand, out)?;
+    }
+    Ok(())
+}
+
+/// Describe the arguments of a command, skipping the global ones and
+/// `--help` unless it is the top-level command.
+fn write_man_arguments(command: &Command, top: bool, out: &mut dyn Write) -> std::io::Result<()> {
+    for arg in command.get_arguments() {
+        if arg.is_hide_set() || (!top && (arg.is_global_set() || ["help", "version"].contains(&arg.get_id()))) {
+            continue;
+        }
+        let value = match arg.get_value_names() {
+            Some(names) => names.join(" "),
+            None => arg.get_id().to_owned(),
+        };
+        let mut flags = Vec::new();
+        if let Some(short) = arg.get_short() {
+            flags.push(format!("\\fB\\-{}\\fR", short));
+        }
+        if let Some(long) = arg.get_long() {
+            flags.push(format!("\\fB\\-\\-{}\\fR", long.replace('-', "\\-")));
+        }
+        let mut line = flags.join(", ");
+        if arg.is_positional() {
+            line = format!("\\fI{}\\fR", roff_escape(&value));
+        } else if arg.is_takes_value_set() {
+            line.push_str(&format!(" \\fI{}\\fR", roff_escape(&value)));
+        }
+        writeln!(out, ".TP\n{}", line)?;
+        let mut help = arg.get_long_help().or(arg.get_help()).unwrap_or("").to_owned();
+        if let Some(values) = arg.get_possible_values() {
+            let names: Vec<&str> = values.iter().map(|v| v.get_name()).collect();
+            help.push_str(&format!(" [possible values: {}]", names.join(", ")));
+        }
+        if !arg.get_default_values().is_empty() {
+            let defaults: Vec<_> = arg.get_default_values().iter().map(|v| v.to_string_lossy()).collect();
+            help.push_str(&format!(" [default: {}]", defaults.join(", ")));
+        }
+        writeln!(out, "{}", roff_escape(help.trim_start()))?;
+    }
+    Ok(())
+}
+
+/// Add the arguments to reach an image to an image subcommand.
+fn image_client_args(command: Command) -> Command {
+    client_args(command)
+        .arg(
+            Arg::new("image")
+                .help("Name of the image, which is also the name of its metadata object")
+                .required(true)
+                .takes_value(true)
+        )
+}
+
+/// Get the configuration file from the command line or the environment.
+///
+/// This is needed before the command line is parsed, so it is looked for
+/// directly.
+fn config_path() -> Option<OsString> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next();
+        } else if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    env::var_os("STORE_CONFIG")
+}
+
+/// Apply the configuration file, if any, to the command line.
+fn load_config(cli: &mut Command<'static>) -> Result<(), CliError> {
+    // Read the configuration file first, since it sets the defaults
+    if let Some(path) = config_path() {
+        let config = Config::load(Path::new(&path)).usage("Error reading configuration")?;
+        let mut used = HashSet::new();
+        apply_config(cli, &config, "", &mut used).usage("Invalid configuration")?;
+        for (table, key) in config.keys() {
+            if !used.contains(&(table.to_owned(), key.to_owned())) {
+                match table {
+                    "" => eprintln!("Warning: unknown option {} in configuration", key),
+                    _ => eprintln!("Warning: unknown option {} in [{}] in configuration", key, table),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The logger, which is replaced when a daemon reloads its configuration.
+struct ReloadableLogger(RwLock<Option<env_logger::Logger>>);
+
+static LOGGER: ReloadableLogger = ReloadableLogger(RwLock::new(None));
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.read().unwrap().as_ref().is_some_and(|logger| log::Log::enabled(logger, metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(logger) = &*self.0.read().unwrap() {
+            log::Log::log(logger, record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set up the logger from `-v`, `--log-level`, and the environment.
+fn set_logger(matches: &ArgMatches) {
+    let level = match matches.occurrences_of("verbose") {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    let mut logger_builder = env_logger::Builder::new();
+    logger_builder.filter(None, level);
+    if let Some(val) = matches.value_of("log-level") {
+        logger_builder.parse_filters(val);
+    }
+    if let Ok(val) = env::var("STORE_LOG") {
+        logger_builder.parse_filters(&val);
+    }
+    if let Ok(val) = env::var("STORE_LOG_STYLE") {
+        logger_builder.parse_write_style(&val);
+    }
+    let logger = logger_builder.build();
+    log::set_max_level(logger.filter());
+    *LOGGER.0.write().unwrap() = Some(logger);
+}
+
+/// Read the configuration file and the command line again for a storage
+/// daemon, setting up the logger, and get its settings that can change.
+fn reload_daemon_settings(command: &str) -> Result<DaemonSettings, String> {
+    let mut cli = cli();
+    load_config(&mut cli).map_err(|e| e.to_string())?;
+    let matches = cli.try_get_matches_from_mut(env::args_os()).map_err(|e| e.to_string())?;
+    let settings = daemon_settings(matches.subcommand_matches(command).unwrap()).map_err(|e| e.to_string())?;
+    set_logger(&matches);
+    Ok(settings)
+}
+
+/// Set the defaults of the options of a command and its subcommands from the
+/// configuration file.
+///
+/// `table` is the table of the command, e.g. `admin.device`. Its keys take
+/// precedence over those of its parents. The keys that were used are added
+/// to `used`.
+fn apply_config(command: &mut Command<'static>, config: &Config, table: &str, used: &mut HashSet<(String, String)>) -> Result<(), String> {
+    let mut tables = vec![table];
+    let mut parent = table;
+    while let Some((p, _)) = parent.rsplit_once('.') {
+        tables.push(p);
+        parent = p;
+    }
+    if !table.is_empty() {
+        tables.push("");
+    }
+
+    let mut defaults = Vec::new();
+    for arg in command.get_arguments() {
+        // Flags and positional arguments can't be set
+        if arg.get_long().is_none() || !arg.is_takes_value_set() {
+            continue;
+        }
+        let mut value = None;
+        for &t in &tables {
+            if let Some(v) = config.get(t, arg.get_id()) {
+                used.insert((t.to_owned(), arg.get_id().to_owned()));
+                value.get_or_insert(v);
+            }
+        }
+        match value {
+            Some(ConfigValue::List(_)) if !arg.is_multiple_occurrences_set() => {
+                return Err(format!("option {} takes a single value", arg.get_id()));
+            }
+            Some(value) => defaults.push((arg.get_id(), value.clone())),
+            None => {}
+        }
+    }
+    // Clap needs the defaults for the lifetime of the program
+    fn leak(value: String) -> &'static str {
+        Box::leak(value.into_boxed_str())
+    }
+    for (id, value) in defaults {
+        let c = std::mem::replace(command, Command::new(""));
+        *command = c.mut_arg(id, |arg| {
+            let arg = arg.required(false);
+            match value {
+                ConfigValue::Single(value) => arg.default_value(leak(value)),
+                ConfigValue::List(values) => {
+                    let values: Vec<&'static str> = values.into_iter().map(leak).collect();
+                    arg.default_values(Box::leak(values.into_boxed_slice()))
+                }
+            }
+        });
+    }
+
+    for subcommand in command.get_subcommands_mut() {
+        let sub_table = match table {
+            "" => subcommand.get_name().to_owned(),
+            _ => format!("{}.{}", table, subcommand.get_name()),
+        };
+        apply_config(subcommand, config, &sub_table, used)?;
+    }
+    Ok(())
+}
+
+/// Add the arguments to connect to a pool to a subcommand.
+fn client_args(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("storage-daemon")
+                .long("storage-daemon")
+                .help("Address of the storage daemon")
+                .required(true)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("capability")
+                .long("capability")
+                .help("File with the credentials to attach to requests (see 'store keyring issue')")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+        )
+        .arg(
+            Arg::new("dtls-ca-cert")
+                .long("dtls-ca-cert")
+                .help("Connect using DTLS, validating the storage daemon's certificate with this CA")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+        )
+        .arg(
+            Arg::new("pool")
+                .long("pool")
+                .help("Name of the pool")
+                .required(true)
+                .takes_value(true)
+        )
+}
+
+/// The arguments to get the storage map from the master, instead of using a
+/// single storage daemon.
+fn master_args() -> [Arg<'static>; 3] {
+    [
+        Arg::new("master")
+            .long("master")
+            .help("Client address of the master, as HOST:PORT, to find the storage daemons of the pool")
+            .takes_value(true)
+            .requires("master-ca-cert")
+            .conflicts_with_all(&["storage-daemon", "dtls-ca-cert"]),
+        Arg::new("master-server-name")
+            .long("master-server-name")
+            .help("Name the master's certificate is issued for (default: HOST)")
+            .takes_value(true)
+            .requires("master"),
+        Arg::new("master-ca-cert")
+            .long("master-ca-cert")
+            .help("Path to certificate to use to validate the master")
+            .takes_value(true)
+            .allow_invalid_utf8(true)
+            .requires("master"),
+    ]
+}
+
+/// The arguments to have a storage daemon collect garbage periodically.
+fn gc_args() -> [Arg<'static>; 4] {
+    [
+        Arg::new("gc-interval")
+            .long("gc-interval")
+            .help("Delete the objects left behind by failed writes every this many seconds (see 'store admin gc')")
+            .takes_value(true),
+        Arg::new("gc-grace")
+            .long("gc-grace")
+            .help("Keep objects written less than this many seconds ago")
+            .takes_value(true)
+            .default_value("3600"),
+        Arg::new("gc-striped")
+            .long("gc-striped")
+            .help("Look for stripes of striped objects that are no longer used")
+            .requires("gc-interval"),
+        Arg::new("gc-s3")
+            .long("gc-s3")
+            .help("Look for chunks of S3 objects that are no longer used")
+            .requires("gc-interval"),
+    ]
+}
+
+/// Get the number of sockets a storage daemon receives client requests on.
+fn receive_threads(matches: &ArgMatches) -> Result<usize, CliError> {
+    match matches.value_of("receive-threads").unwrap().parse() {
+        Ok(0) | Err(_) => Err(CliError::Usage("Invalid receive-threads".to_owned())),
+        Ok(threads) => Ok(threads),
+    }
+}
+
+/// Get the settings of a storage daemon that can be reloaded.
+fn daemon_settings(matches: &ArgMatches) -> Result<DaemonSettings, CliError> {
+    Ok(DaemonSettings { gc: gc_options(matches)? })
+}
+
+/// Get the garbage collection interval and options from the arguments.
+fn gc_options(matches: &ArgMatches) -> Result<Option<(Duration, GcOptions)>, CliError> {
+    let interval: u64 = match matches.value_of("gc-interval") {
+        Some(interval) => interval.parse().usage("Invalid gc-interval")?,
+        None => return Ok(None),
+    };
+    let options = GcOptions {
+        grace: Duration::from_secs(matches.value_of("gc-grace").unwrap().parse().usage("Invalid gc-grace")?),
+        striped: matches.is_present("gc-striped"),
+        s3: matches.is_present("gc-s3"),
+        dry_run: false,
+    };
+    if interval == 0 || (!options.striped && !options.s3) {
+        return Err(CliError::Usage("gc-interval needs a positive number of seconds, and --gc-striped or --gc-s3".to_owned()));
+    }
+    Ok(Some((Duration::from_secs(interval), options)))
+}
+
+/// Where a client finds the storage daemons.
+enum ClientTarget {
+    StorageDaemon { address: SocketAddr, dtls_ca_cert: Option<PathBuf> },
+    Master { address: String, server_name: Option<String>, ca_cert: PathBuf },
+}
+
+impl ClientTarget {
+    async fn connect(&self, pool: PoolName) -> Result<Client, Box<dyn std::error::Error>> {
+        match self {
+            ClientTarget::StorageDaemon { address, dtls_ca_cert } => {
+                connect_client(*address, pool, dtls_ca_cert.as_deref()).await
+            }
+            ClientTarget::Master { address, server_name, ca_cert } => {
+                create_client_from_master(address, server_name.as_deref(), ca_cert, pool).await
+            }
+        }
+    }
+}
+
+/// Get the storage daemon, or the master, from the arguments.
+fn client_target(matches: &ArgMatches) -> Result<ClientTarget, CliError> {
+    if let Some(address) = matches.value_of("master") {
+        return Ok(ClientTarget::Master {
+            address: address.to_owned(),
+            server_name: matches.value_of("master-server-name").map(str::to_owned),
+            ca_cert: matches.value_of_os("master-ca-cert").unwrap().into(),
+        });
+    }
+    let address = matches.value_of("storage-daemon").unwrap();
+    let address: SocketAddr = address.parse().usage("Invalid storage-daemon address")?;
+    let dtls_ca_cert = matches.value_of_os("dtls-ca-cert").map(PathBuf::from);
+    Ok(ClientTarget::StorageDaemon { address, dtls_ca_cert })
+}
+
+/// Add the arguments setting the geometry of a new image.
+fn image_geometry_args(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("block-size")
+                .long("block-size")
+                .help("Size of the objects holding the image")
+                .takes_value(true)
+                .default_value("4K")
+        )
+        .arg(
+            Arg::new("stripe-unit")
+                .long("stripe-unit")
+                .help("Largest part of a block sent in one request, at most 32K (default: block size)")
+                .takes_value(true)
+        )
+}
+
+/// Get the geometry of a new image from the arguments.
+fn image_geometry(matches: &ArgMatches, size: u64) -> Result<Geometry, std::io::Error> {
+    let block_size = parse_size(matches.value_of("block-size").unwrap())? as usize;
+    let stripe_unit = match matches.value_of("stripe-unit") {
+        Some(s) => parse_size(s)? as usize,
+        None => block_size.min(MAX_STRIPE_UNIT),
+    };
+    Ok(Geometry { size, block_size, stripe_unit, flags: 0 })
+}
+
+/// Pools to connect to, with the credentials to use if any.
+type PoolArgs = Vec<(String, Option<Vec<u8>>)>;
+
+/// Read the pools given as POOL or POOL:CREDENTIALS_FILE.
+fn read_pool_args<'a>(values: impl Iterator<Item = &'a str>) -> Result<PoolArgs, std::io::Error> {
+    values.map(|value| match value.split_once(':') {
+        Some((pool, path)) => Ok((pool.to_owned(), Some(std::fs::read(path)?))),
+        None => Ok((value.to_owned(), None)),
+    }).collect()
+}
+
+/// Connect a client to each pool, using its credentials if it has some.
+async fn connect_pools(address: SocketAddr, pools: PoolArgs, dtls_ca_cert: Option<&Path>) -> Result<HashMap<String, Client>, Box<dyn std::error::Error>> {
+    let mut clients = HashMap::new();
+    for (pool, capability) in pools {
+        let client = connect_client(address, PoolName::new(pool.as_str())?, dtls_ca_cert).await?;
+        if let Some(capability) = capability {
+            client.set_credentials(&capability)?;
         }
+        clients.insert(pool, client);
+    }
+    Ok(clients)
+}
+
+/// Connect to the storage daemon, over DTLS if a CA certificate is given.
+async fn connect_client(address: SocketAddr, pool: PoolName, dtls_ca_cert: Option<&Path>) -> Result<Client, Box<dyn std::error::Error>> {
+    match dtls_ca_cert {
+        None => create_client(address, pool).await,
+        #[cfg(feature = "dtls")]
+        Some(ca_cert) => store::client::create_client_dtls(address, pool, ca_cert).await,
+        #[cfg(not(feature = "dtls"))]
+        Some(_) => Err("DTLS support was not compiled in".into()),
     }
 }
@@ -0,0 +1,111 @@
+//! Reusable buffers, so datagrams don't cost allocations on busy daemons.
+//!
+//! Buffers come back to their pool when dropped, with their content. Those
+//! that grew too large are freed instead, so a few big responses don't keep
+//! memory around, and so are buffers returned to a pool that is full.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Most buffers kept for reuse.
+    max_free: usize,
+    /// Largest buffer kept for reuse, in bytes.
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    pub const fn new(max_free: usize, max_capacity: usize) -> BufferPool {
+        BufferPool { free: Mutex::new(Vec::new()), max_free, max_capacity }
+    }
+
+    /// Get a buffer, with whatever content it had when it was returned.
+    pub fn get(&self) -> Buffer<'_> {
+        let data = self.free.lock().unwrap().pop().unwrap_or_default();
+        Buffer { pool: self, data }
+    }
+
+    /// Number of buffers waiting to be reused.
+    pub fn free_count(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+/// A buffer from a pool, returned to it when dropped.
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    data: Vec<u8>,
+}
+
+impl Deref for Buffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl AsRef<[u8]> for Buffer<'_> {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl AsMut<[u8]> for Buffer<'_> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        if self.data.capacity() == 0 || self.data.capacity() > self.pool.max_capacity {
+            return;
+        }
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max_free {
+            free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn test_pool() {
+        let pool = BufferPool::new(2, 1024);
+        let mut a = pool.get();
+        a.extend_from_slice(b"hello");
+        let address = a.as_ptr();
+        drop(a);
+        assert_eq!(pool.free_count(), 1);
+
+        // The same buffer is reused, with its content
+        let b = pool.get();
+        assert_eq!(b.as_ptr(), address);
+        assert_eq!(&b[..], b"hello");
+        assert_eq!(pool.free_count(), 0);
+
+        // Big buffers, and buffers beyond the limit, are freed
+        let mut big = pool.get();
+        big.resize(2048, 0);
+        drop(big);
+        assert_eq!(pool.free_count(), 0);
+        let mut buffers: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        for buffer in &mut buffers {
+            buffer.push(1);
+        }
+        drop(buffers);
+        drop(b);
+        assert_eq!(pool.free_count(), 2);
+    }
+}
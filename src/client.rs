@@ -1,60 +1,77 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use lazy_static::lazy_static;
-use log::{debug, info};
+use log::{debug, warn};
 use std::collections::HashMap;
 use std::net::{TcpStream, SocketAddr};
-use std::io::{Cursor, Error as IoError, ErrorKind, Write};
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "dtls")]
+use tokio::io::ReadHalf;
 use tokio::net::UdpSocket;
 use tokio::sync::oneshot::{Sender, channel};
 
 use crate::{DeviceId, ObjectId, PoolName};
-use crate::storage_map::{self, StorageMap};
+use crate::admin::{ClientRequest, ClientResponse, DeviceInfo, connect_master, read_message, write_message};
+use crate::crypto::KeyPair;
+use crate::crypto::capability::decode_credentials;
+use crate::crypto::epoch::clock_epoch;
+use crate::crypto::envelope::{RequestHeader, VERSION_SEALED_RESPONSE, open_response, read_response_header, seal_request};
+use crate::crypto::session::{Session, Ticket};
+use crate::error::{Error, NetworkError, PlacementError};
+#[cfg(feature = "dtls")]
+use crate::dtls::{self, DtlsStream, SessionSender};
+use crate::metrics::{register_counter, register_gauge, register_latency};
+use crate::metrics::reporter::Reporter;
+use crate::netsim::Socket;
+use crate::proto::wire::{
+    ChunkAssembler, ErrorCode, Event, PoolUsage, Request, RequestMessage, Response, ResponseChunk,
+    ResponseFrame, ResponseMessage, TraceId, add_checksum, check_checksum,
+};
+use crate::storage_map::StorageMap;
+use crate::storage_map::overlay::MapOverlay;
 
 #[derive(Clone)]
 struct Metrics {
     reads: prometheus::IntCounter,
     writes: prometheus::IntCounter,
     resends: prometheus::IntCounter,
+    timeouts: prometheus::IntCounter,
     in_flight: prometheus::IntGauge,
+    corrupt_responses: prometheus::IntCounter,
+    latency: prometheus::HistogramVec,
 }
 
 lazy_static! {
     static ref METRICS: Metrics = {
         let m = Metrics {
-            reads: prometheus::register_int_counter!("reads", "Total reads").unwrap(),
-            writes: prometheus::register_int_counter!("writes", "Total writes").unwrap(),
-            resends: prometheus::register_int_counter!("resends", "Total resent packets").unwrap(),
-            in_flight: prometheus::register_int_gauge!("in_flight", "Requests currently in flight").unwrap(),
+            reads: register_counter("client", "reads", "Total reads"),
+            writes: register_counter("client", "writes", "Total writes"),
+            resends: register_counter("client", "resends", "Total resent packets"),
+            timeouts: register_counter("client", "timeouts", "Total requests given up on, see Client::with_timeout()"),
+            in_flight: register_gauge("client", "in_flight", "Requests currently in flight"),
+            corrupt_responses: register_counter("client", "corrupt_responses", "Total responses dropped for a bad checksum"),
+            latency: register_latency("client", "request_duration_seconds", "Time until a request is answered, including resends", &["op"]),
         };
-        let metrics = m.clone();
-        std::thread::spawn(move || {
-            let mut last_reads = 0;
-            let mut last_writes = 0;
-            let mut last_resends = 0;
-            loop {
-                let reads = metrics.reads.get();
-                let writes = metrics.writes.get();
-                let resends = metrics.resends.get();
-                if reads != last_reads || writes != last_writes || resends != last_resends {
-                    info!(
-                        "last 10s: {} reads, {} writes, {} resent packets",
-                        reads - last_reads,
-                        writes - last_writes,
-                        resends - last_resends
-                    );
-                    last_reads = reads;
-                    last_writes = writes;
-                    last_resends = resends;
-                }
-                std::thread::sleep(std::time::Duration::from_millis(10000));
-            }
-        });
+        let (reads, writes, resends) = (m.reads.clone(), m.writes.clone(), m.resends.clone());
+        Reporter::new("client")
+            .value("reads", "reads", move || reads.get())
+            .value("writes", "writes", move || writes.get())
+            .value("resends", "resent packets", move || resends.get())
+            .start();
         m
     };
 }
 
+/// A request waiting for its response.
+struct PendingRequest {
+    sent: Instant,
+    /// The keys and header of a request sent in a session, to open its
+    /// response.
+    session: Option<(KeyPair, RequestHeader)>,
+    channel: Sender<Response>,
+}
+
 pub struct ClientInner {
     /// Addresses of master server(s).
     masters: Vec<SocketAddr>,
@@ -68,25 +85,145 @@ pub struct ClientInner {
     /// The storage map for the pool we care about.
     storage_map: StorageMap,
 
+    /// Status of the devices, as known to the master.
+    overlay: MapOverlay,
+
     /// The storage daemons.
     storage_daemons: HashMap<DeviceId, StorageDaemon>,
 
+    /// Our epoch, sent with the counter so we can restart counting from 0.
+    epoch: u32,
+
+    /// Sealed capability issued by the master, attached to requests.
+    capability: Vec<u8>,
+
+    /// Ticket to open sessions with the daemons, if we have one.
+    ticket: Option<Ticket>,
+
+    /// The master to get new tickets from, before ours expires.
+    ticket_master: Option<MasterInfo>,
+
     /// Map of channels to get responses from the reading task.
-    response_channels: HashMap<(SocketAddr, u32), (Instant, Sender<Vec<u8>>)>,
+    response_channels: HashMap<(SocketAddr, u32), PendingRequest>,
+
+    /// Responses we got some of the chunks of.
+    partial_responses: HashMap<(SocketAddr, u32), ChunkAssembler>,
 }
 
 struct StorageDaemon {
     address: SocketAddr,
     client_counter: u32,
+    /// Session opened with our ticket, if any.
+    session: Option<Session>,
+}
+
+/// How to connect to the master, see `create_client_from_master()`.
+#[derive(Clone)]
+struct MasterInfo {
+    address: String,
+    server_name: Option<String>,
+    ca_cert: PathBuf,
 }
 
 const TIMEOUT: Duration = Duration::from_millis(200);
 
+/// Timeout for services answering their own clients, such as the gateways, so
+/// requests fail instead of hanging when storage daemons are unreachable. See
+/// `Client::with_timeout()`.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long before they expire tickets and sessions are renewed.
+const RENEW_BEFORE: Duration = Duration::from_secs(300);
+
+/// Largest response to put back together from chunks.
+const MAX_RESPONSE_SIZE: usize = 64 << 20;
+
+/// Most datagrams to receive at once, each in a buffer of 64 KiB.
+const RECEIVE_BATCH: usize = 8;
+
+/// Size of the parts objects are read and written in, by
+/// `Client::read_to()` and `Client::write_from()` for example, so requests
+/// fit in a datagram.
+pub const PART_SIZE: usize = 32 << 10;
+
+/// Number of objects to request at once in `Client::list_all_objects()`.
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// Information about an object, from `Client::stat_object()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectStat {
+    pub size: u64,
+    pub expires: Option<SystemTime>,
+    /// Version of the object, 0 if it was written before versions existed.
+    pub version: u64,
+    pub tags: Vec<String>,
+}
+
+/// The result of `Client::read_versioned()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionedRead {
+    NotFound,
+    /// The object still has the version the client already has.
+    Unchanged,
+    Object { version: u64, data: Vec<u8> },
+}
+
+/// The space used on a storage daemon, from `Client::daemon_stats()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DaemonStats {
+    pub device_id: DeviceId,
+    pub free_space: Option<u64>,
+    pub pools: Vec<PoolUsage>,
+}
+
+/// Where a watch is in the changes of each daemon, for `Client::watch()`.
+#[derive(Clone, Debug, Default)]
+pub struct WatchCursor {
+    positions: HashMap<DeviceId, u64>,
+}
+
 #[derive(Clone)]
 pub struct Client {
     client: Arc<Mutex<ClientInner>>,
-    udp_socket: Arc<UdpSocket>,
+    transport: Transport,
     _receive_task_handle: Arc<CancelTask>,
+    /// Trace ID to send with all requests, instead of a new one each time.
+    trace_id: Option<TraceId>,
+    /// How long to resend requests before giving up, forever if `None`.
+    timeout: Option<Duration>,
+}
+
+/// How requests are sent to the storage daemons.
+#[derive(Clone)]
+enum Transport {
+    /// Plain datagrams on a socket.
+    Udp(Arc<Socket>),
+    /// A DTLS session with each daemon.
+    #[cfg(feature = "dtls")]
+    Dtls(Arc<HashMap<SocketAddr, SessionSender>>),
+}
+
+impl Transport {
+    /// Send a request. Sealed requests are authenticated already and go
+    /// without a checksum.
+    async fn send(&self, request: &[u8], sealed: bool, address: SocketAddr) -> Result<(), IoError> {
+        match self {
+            Transport::Udp(socket) if sealed => {
+                socket.send_to(request, address).await?;
+            }
+            Transport::Udp(socket) => {
+                let mut datagram = request.to_owned();
+                add_checksum(&mut datagram);
+                socket.send_to(&datagram, address).await?;
+            }
+            #[cfg(feature = "dtls")]
+            Transport::Dtls(sessions) => match sessions.get(&address) {
+                Some(session) => session.send(request.to_owned())?,
+                None => return Err(NetworkError::NotConnected("No DTLS session with storage daemon".to_owned()).into()),
+            },
+        }
+        Ok(())
+    }
 }
 
 struct CancelTask(tokio::task::JoinHandle<Result<(), IoError>>);
@@ -98,154 +235,528 @@ impl Drop for CancelTask {
 }
 
 impl Client {
+    /// Get a client that sends the given trace ID with its requests.
+    ///
+    /// The ID is logged by every daemon the requests go through, so an
+    /// operation spanning multiple requests can be followed.
+    pub fn traced(&self, trace_id: TraceId) -> Client {
+        Client {
+            trace_id: Some(trace_id),
+            ..self.clone()
+        }
+    }
+
+    /// Get a client that gives up on requests after some time.
+    ///
+    /// Requests are resent until the deadline, then fail with
+    /// `ErrorKind::TimedOut`. By default, they are resent forever.
+    pub fn with_timeout(&self, timeout: Duration) -> Client {
+        Client {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
     pub async fn read_object(&self, object_id: &ObjectId) -> Result<Option<Vec<u8>>, IoError> {
-        // Do the request
         METRICS.reads.inc();
-        let response = self.do_request(object_id, |req| {
-            req.write_u8(0x01).unwrap(); // read_object
-            req.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
-            req.write_all(&object_id.0).unwrap();
+        let response = self.do_request(Request::ReadObject {
+            object_id: object_id.clone(),
+            if_changed: None,
         }).await?;
+        read_response(response)
+    }
 
-        // Read the response
-        if response.len() < 5 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            ));
-        }
-        match response[4] {
-            1 => Ok(Some(response[5..].to_owned())),
-            0 => Ok(None),
-            _ => Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            )),
+    /// Read an object with its version, unless it still has the version
+    /// `if_changed`.
+    pub async fn read_versioned(&self, object_id: &ObjectId, if_changed: Option<u64>) -> Result<VersionedRead, IoError> {
+        METRICS.reads.inc();
+        let response = self.do_request(Request::ReadObject {
+            object_id: object_id.clone(),
+            if_changed,
+        }).await?;
+        match response {
+            Response::Object { version, data } => Ok(VersionedRead::Object { version, data }),
+            Response::Unchanged => Ok(VersionedRead::Unchanged),
+            Response::Error(ErrorCode::NotFound) => Ok(VersionedRead::NotFound),
+            Response::Error(code) => Err(code.into()),
+            _ => Err(unexpected_response()),
         }
     }
 
     pub async fn read_part(&self, object_id: &ObjectId, offset: u32, len: u32) -> Result<Option<Vec<u8>>, IoError> {
-        // Do the request
         METRICS.reads.inc();
-        let response = self.do_request(object_id, |req| {
-            req.write_u8(0x02).unwrap(); // read_part
-            req.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
-            req.write_all(&object_id.0).unwrap();
-            req.write_u32::<BigEndian>(offset).unwrap();
-            req.write_u32::<BigEndian>(len).unwrap();
+        let response = self.do_request(Request::ReadPart {
+            object_id: object_id.clone(),
+            offset,
+            len,
         }).await?;
+        read_response(response)
+    }
 
-        // Read the response
-        if response.len() < 5 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            ));
-        }
-        match response[4] {
-            1 => Ok(Some(response[5..].to_owned())),
-            0 => Ok(None),
-            _ => Err(IoError::new(ErrorKind::InvalidData, "Invalid reply from storage daemon")),
+    /// Replace an object, returning its new version.
+    pub async fn write_object(&self, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        METRICS.writes.inc();
+        let response = self.do_request(Request::WriteObject {
+            object_id: object_id.clone(),
+            data: data.to_owned(),
+        }).await?;
+        written_response(response)
+    }
+
+    pub async fn write_part(&self, object_id: &ObjectId, offset: u32, data: &[u8]) -> Result<u64, IoError> {
+        METRICS.writes.inc();
+        let response = self.do_request(Request::WritePart {
+            object_id: object_id.clone(),
+            offset,
+            data: data.to_owned(),
+        }).await?;
+        written_response(response)
+    }
+
+    pub async fn delete_object(&self, object_id: &ObjectId) -> Result<(), IoError> {
+        METRICS.writes.inc();
+        let response = self.do_request(Request::DeleteObject {
+            object_id: object_id.clone(),
+        }).await?;
+        done_response(response)
+    }
+
+    pub async fn stat_object(&self, object_id: &ObjectId) -> Result<Option<ObjectStat>, IoError> {
+        METRICS.reads.inc();
+        let response = self.do_request(Request::StatObject {
+            object_id: object_id.clone(),
+        }).await?;
+        match response {
+            Response::Stat { size, expires, version, tags } => Ok(Some(ObjectStat { size, expires, version, tags })),
+            Response::Error(ErrorCode::NotFound) => Ok(None),
+            Response::Error(code) => Err(code.into()),
+            _ => Err(unexpected_response()),
         }
     }
 
-    pub async fn write_object(&self, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError> {
-        // Do the request
+    /// Replace the tags of an object, see `check_tags()` for what they can be.
+    ///
+    /// Writing the whole object clears them.
+    pub async fn set_tags(&self, object_id: &ObjectId, tags: &[String]) -> Result<(), IoError> {
         METRICS.writes.inc();
-        let response = self.do_request(object_id, |req| {
-            req.write_u8(0x03).unwrap(); // write_object
-            req.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
-            req.write_all(&object_id.0).unwrap();
-            req.write_all(data).unwrap();
+        let response = self.do_request(Request::SetTags {
+            object_id: object_id.clone(),
+            tags: tags.to_owned(),
         }).await?;
+        done_response(response)
+    }
+
+    /// List a page of the objects whose name starts with `prefix`, in order.
+    ///
+    /// Pass the last object of a page as `start_after` to get the next one.
+    /// Pages can be shorter than `limit`, the end is reached when a page is
+    /// empty.
+    pub async fn list_objects(&self, prefix: &[u8], start_after: Option<&ObjectId>, limit: u32) -> Result<Vec<ObjectId>, IoError> {
+        self.list(prefix, None, start_after, limit).await
+    }
+
+    /// List a page of the objects whose name starts with `prefix` and that
+    /// have `tag`, like `list_objects()`.
+    ///
+    /// Daemons go through all their objects to find them, so this is slower
+    /// than listing.
+    pub async fn list_tagged_objects(&self, prefix: &[u8], tag: &str, start_after: Option<&ObjectId>, limit: u32) -> Result<Vec<ObjectId>, IoError> {
+        self.list(prefix, Some(tag), start_after, limit).await
+    }
+
+    async fn list(&self, prefix: &[u8], tag: Option<&str>, start_after: Option<&ObjectId>, limit: u32) -> Result<Vec<ObjectId>, IoError> {
+        METRICS.reads.inc();
+        let device_ids: Vec<DeviceId> = self.client.lock().unwrap().storage_daemons.keys().cloned().collect();
+
+        // Objects are spread over the daemons, merge their lists
+        let mut list = Vec::new();
+        let mut end: Option<ObjectId> = None;
+        for device_id in device_ids {
+            let response = self.do_request_to(&device_id, Request::ListObjects {
+                prefix: prefix.to_owned(),
+                start_after: start_after.cloned(),
+                limit,
+                tag: tag.map(str::to_owned),
+            }).await?;
+            let page = match response {
+                Response::List(page) => page,
+                Response::Error(code) => return Err(code.into()),
+                _ => return Err(unexpected_response()),
+            };
 
-        // Read the response
-        if response.len() != 4 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            ));
+            // That daemon might have more objects after its last one, we
+            // can't return anything past it
+            if let Some(last) = page.last() {
+                if end.as_ref().is_none_or(|e| last.0 < e.0) {
+                    end = Some(last.clone());
+                }
+            }
+            list.extend(page);
         }
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list.dedup();
+        if let Some(end) = end {
+            list.retain(|o| o.0 <= end.0);
+        }
+        list.truncate(limit as usize);
+        Ok(list)
+    }
 
-        Ok(())
+    /// List all the objects whose name starts with `prefix`, in order.
+    pub async fn list_all_objects(&self, prefix: &[u8]) -> Result<Vec<ObjectId>, IoError> {
+        self.list_all(prefix, None).await
+    }
+
+    /// List all the objects whose name starts with `prefix` and that have
+    /// `tag`, in order.
+    pub async fn list_all_tagged_objects(&self, prefix: &[u8], tag: &str) -> Result<Vec<ObjectId>, IoError> {
+        self.list_all(prefix, Some(tag)).await
     }
 
-    pub async fn write_part(&self, object_id: &ObjectId, offset: u32, data: &[u8]) -> Result<(), IoError> {
-        // Do the request
+    async fn list_all(&self, prefix: &[u8], tag: Option<&str>) -> Result<Vec<ObjectId>, IoError> {
+        let mut objects: Vec<ObjectId> = Vec::new();
+        loop {
+            let page = self.list(prefix, tag, objects.last(), LIST_PAGE_SIZE).await?;
+            if page.is_empty() {
+                return Ok(objects);
+            }
+            objects.extend(page);
+        }
+    }
+
+    pub async fn append_object(&self, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
         METRICS.writes.inc();
-        let response = self.do_request(object_id, |req| {
-            req.write_u8(0x04).unwrap(); // write_part
-            req.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
-            req.write_all(&object_id.0).unwrap();
-            req.write_u32::<BigEndian>(offset).unwrap();
-            req.write_all(data).unwrap();
+        let response = self.do_request(Request::AppendObject {
+            object_id: object_id.clone(),
+            data: data.to_owned(),
         }).await?;
+        written_response(response)
+    }
 
-        // Read the response
-        if response.len() != 4 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            ));
-        }
+    pub async fn truncate_object(&self, object_id: &ObjectId, len: u32) -> Result<u64, IoError> {
+        METRICS.writes.inc();
+        let response = self.do_request(Request::TruncateObject {
+            object_id: object_id.clone(),
+            len,
+        }).await?;
+        written_response(response)
+    }
 
-        Ok(())
+    /// Replace an object if it has the expected content.
+    ///
+    /// `expected` is `None` to create an object that must not exist yet.
+    /// Returns the new version if the object was written.
+    pub async fn compare_and_swap(&self, object_id: &ObjectId, expected: Option<&[u8]>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        METRICS.writes.inc();
+        let response = self.do_request(Request::CompareAndSwap {
+            object_id: object_id.clone(),
+            expected: expected.map(|e| e.to_owned()),
+            data: data.to_owned(),
+        }).await?;
+        conditional_response(response)
     }
 
-    pub async fn delete_object(&self, object_id: &ObjectId) -> Result<(), IoError> {
-        // Do the request
+    /// Replace an object if it has the expected version.
+    ///
+    /// `version` is `None` to create an object that must not exist yet.
+    /// Returns the new version if the object was written.
+    pub async fn write_if_version(&self, object_id: &ObjectId, version: Option<u64>, data: &[u8]) -> Result<Option<u64>, IoError> {
         METRICS.writes.inc();
-        let response = self.do_request(object_id, |req| {
-            req.write_u8(0x05).unwrap(); // delete_object
-            req.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
-            req.write_all(&object_id.0).unwrap();
+        let response = self.do_request(Request::WriteIfVersion {
+            object_id: object_id.clone(),
+            version,
+            data: data.to_owned(),
         }).await?;
+        conditional_response(response)
+    }
 
-        // Read the response
-        if response.len() != 4 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "Invalid reply from storage daemon",
-            ));
+    /// Read an object from `offset`, to the end or for `len` bytes, writing
+    /// it to `output` one part at a time.
+    ///
+    /// Returns `false` if the object doesn't exist.
+    pub async fn read_to(&self, object_id: &ObjectId, offset: u32, len: Option<u32>, mut output: impl Write) -> Result<bool, IoError> {
+        let end = len.map_or(u32::MAX, |len| offset.saturating_add(len));
+        let mut position = offset;
+        while position < end {
+            let part_len = (end - position).min(PART_SIZE as u32);
+            let part = match self.read_part(object_id, position, part_len).await? {
+                Some(part) => part,
+                None if position == offset => return Ok(false),
+                None => return Err(IoError::new(ErrorKind::NotFound, "Object was deleted while reading it")),
+            };
+            output.write_all(&part)?;
+            position += part.len() as u32;
+            if part.len() < part_len as usize {
+                break;
+            }
         }
+        output.flush()?;
+        Ok(true)
+    }
 
+    /// Write an object from `input`, one part at a time, replacing it, or
+    /// overwriting it from `offset` if given.
+    ///
+    /// Returns the number of bytes written.
+    pub async fn write_from(&self, object_id: &ObjectId, offset: Option<u32>, mut input: impl Read) -> Result<u64, IoError> {
+        let mut buffer = vec![0; PART_SIZE];
+        let mut written = 0;
+        loop {
+            let len = read_full(&mut input, &mut buffer)?;
+            let position = u32::try_from(offset.unwrap_or(0) as u64 + written)
+                .map_err(|_| IoError::new(ErrorKind::InvalidInput, "Object too large"))?;
+            if written == 0 && offset.is_none() {
+                // Replace the object, even if the input is empty
+                self.write_object(object_id, &buffer[..len]).await?;
+            } else if len > 0 {
+                self.write_part(object_id, position, &buffer[..len]).await?;
+            }
+            written += len as u64;
+            if len < buffer.len() {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Make all the writes that completed so far durable.
+    ///
+    /// This asks every daemon to sync its storage, since writes could have
+    /// gone to any of them.
+    pub async fn flush(&self) -> Result<(), IoError> {
+        let device_ids: Vec<DeviceId> = self.client.lock().unwrap().storage_daemons.keys().cloned().collect();
+        for device_id in device_ids {
+            let response = self.do_request_to(&device_id, Request::Flush).await?;
+            done_response(response)?;
+        }
         Ok(())
     }
 
-    async fn do_request<F: FnOnce(&mut Vec<u8>)>(&self, object_id: &ObjectId, write_request: F) -> Result<Vec<u8>, IoError> {
+    /// Get the space used on every daemon, for each pool the capability
+    /// allows reading.
+    ///
+    /// Daemons count their objects at most every 30 seconds, so this can be
+    /// a bit behind.
+    pub async fn daemon_stats(&self) -> Result<Vec<DaemonStats>, IoError> {
+        let device_ids: Vec<DeviceId> = self.client.lock().unwrap().storage_daemons.keys().cloned().collect();
+        let mut stats = Vec::with_capacity(device_ids.len());
+        for device_id in device_ids {
+            match self.do_request_to(&device_id, Request::Stats).await? {
+                Response::Stats { device_id, free_space, pools } => {
+                    stats.push(DaemonStats { device_id, free_space, pools });
+                }
+                Response::Error(code) => return Err(code.into()),
+                _ => return Err(unexpected_response()),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Get the changes to the objects whose name starts with `prefix` since
+    /// the previous call with `cursor`.
+    ///
+    /// The first call starts watching and returns no changes. Also returns
+    /// whether some changes were missed, because too many happened between
+    /// calls or a daemon restarted. Daemons stop recording changes when
+    /// nobody asked for them in a minute, and don't report objects that
+    /// expire.
+    pub async fn watch(&self, prefix: &[u8], cursor: &mut WatchCursor) -> Result<(Vec<Event>, bool), IoError> {
+        let device_ids: Vec<DeviceId> = self.client.lock().unwrap().storage_daemons.keys().cloned().collect();
+        let mut events = Vec::new();
+        let mut lost = false;
+        for device_id in device_ids {
+            loop {
+                let response = self.do_request_to(&device_id, Request::Watch {
+                    prefix: prefix.to_owned(),
+                    after: cursor.positions.get(&device_id).copied(),
+                }).await?;
+                let page = match response {
+                    Response::Events { next, lost: page_lost, events } => {
+                        cursor.positions.insert(device_id.clone(), next);
+                        lost |= page_lost;
+                        events
+                    }
+                    Response::Error(code) => return Err(code.into()),
+                    _ => return Err(unexpected_response()),
+                };
+
+                // Responses are limited in size, ask again until caught up
+                if page.is_empty() {
+                    break;
+                }
+                events.extend(page);
+            }
+        }
+        Ok((events, lost))
+    }
+
+    /// Set the capability to attach to requests, as issued by the master.
+    pub fn set_capability(&self, capability: Vec<u8>) {
+        self.client.lock().unwrap().capability = capability;
+    }
+
+    /// Set the ticket to open sessions with the daemons, as issued by the
+    /// master.
+    pub fn set_ticket(&self, ticket: Ticket) {
         let mut client = self.client.lock().unwrap();
-        let group_id = client.storage_map.object_to_group(object_id);
-        let device_id = match client.storage_map.group_to_first_device(&group_id) {
+        client.ticket = Some(ticket);
+        for daemon in client.storage_daemons.values_mut() {
+            daemon.session = None;
+        }
+    }
+
+    /// Set the capability and ticket from credentials, as written by
+    /// `store keyring issue`.
+    ///
+    /// A capability without a ticket is only accepted by the daemons over
+    /// DTLS.
+    pub fn set_credentials(&self, credentials: &[u8]) -> Result<(), IoError> {
+        let (capability, ticket) = decode_credentials(credentials)?;
+        self.set_capability(capability);
+        if let Some(ticket) = ticket {
+            self.set_ticket(ticket);
+        }
+        Ok(())
+    }
+
+    /// Send a request to the daemon holding its object.
+    async fn do_request(&self, request: Request) -> Result<Response, IoError> {
+        let device_id = {
+            let client = self.client.lock().unwrap();
+            request.object_id().and_then(|o| {
+                let group_id = client.storage_map.object_to_group(o);
+                client.storage_map.group_to_first_device_with_overlay(&group_id, &client.overlay)
+            })
+        };
+        let device_id = match device_id {
             Some(device_id) => device_id,
-            None => return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "No device for object",
-            )),
+            None => return Err(PlacementError::NoDevice.into()),
+        };
+        self.do_request_to(&device_id, request).await
+    }
+
+    async fn do_request_to(&self, device_id: &DeviceId, request: Request) -> Result<Response, IoError> {
+        // The daemon would reject it anyway, fail without waiting for it
+        request.check()?;
+        self.open_session(device_id).await?;
+        let retry = self.client.lock().unwrap().ticket.as_ref().map(|_| request.clone());
+        let response = self.send_request(device_id, request).await?;
+
+        // The daemon doesn't know our session anymore, it might have
+        // restarted. Open another one and send the request again
+        match (response, retry) {
+            (Response::Error(ErrorCode::NoSession), Some(request)) => {
+                debug!("Session with {:?} is gone, opening another", device_id);
+                self.client.lock().unwrap().storage_daemons.get_mut(device_id).unwrap().session = None;
+                self.open_session(device_id).await?;
+                self.send_request(device_id, request).await
+            }
+            (response, _) => Ok(response),
+        }
+    }
+
+    /// Open a session with a daemon if we have a ticket, unless one is open
+    /// that doesn't expire soon.
+    async fn open_session(&self, device_id: &DeviceId) -> Result<(), IoError> {
+        let renew_at = SystemTime::now() + RENEW_BEFORE;
+        let master = {
+            let client = self.client.lock().unwrap();
+            match client.ticket {
+                None => return Ok(()),
+                Some(ref ticket) if ticket.expires < renew_at => client.ticket_master.clone(),
+                Some(_) => None,
+            }
         };
-        let daemon = client.storage_daemons.get_mut(&device_id).unwrap();
-        let counter = daemon.client_counter;
-        daemon.client_counter += 1;
-        let address = daemon.address.clone();
-
-        // Assemble the request
-        let mut request = Vec::new();
-        request.write_u32::<BigEndian>(counter).unwrap();
-        request.write_u32::<BigEndian>(client.pool.0.len() as u32).unwrap();
-        request.write_all(client.pool.0.as_bytes()).unwrap();
-        write_request(&mut request);
-
-        // Register our counter to get response
-        let (send, mut recv) = channel();
-        client.response_channels.insert((address, counter), (Instant::now(), send));
-
-        // Unlock the mutex during network operations
-        drop(client);
-
-        debug!("Sending request {}, size {}", counter, request.len());
+
+        // Get a new ticket before ours expires
+        if let Some(master) = master {
+            match get_ticket(&master).await {
+                Ok(ticket) => self.client.lock().unwrap().ticket = Some(ticket),
+                Err(e) => warn!("Error getting a new ticket from the master: {}", e),
+            }
+        }
+
+        let ticket = {
+            let client = self.client.lock().unwrap();
+            let ticket = client.ticket.as_ref().unwrap();
+            let session = client.storage_daemons.get(device_id).unwrap().session.as_ref();
+            // Renew sessions once there is a ticket lasting longer
+            if let Some(session) = session {
+                if session.expires > renew_at || (session.expires >= ticket.expires && session.expires > SystemTime::now()) {
+                    return Ok(());
+                }
+            }
+            ticket.clone()
+        };
+
+        let (handshake, hello) = ticket.start_session();
+        let session = match self.send_request(device_id, Request::Handshake { hello }).await? {
+            Response::Session(answer) => handshake.finish(&answer)?,
+            Response::Error(code) => return Err(code.into()),
+            _ => return Err(unexpected_response()),
+        };
+        debug!("Opened session {} with {:?}", session.id, device_id);
+        self.client.lock().unwrap().storage_daemons.get_mut(device_id).unwrap().session = Some(session);
+        Ok(())
+    }
+
+    async fn send_request(&self, device_id: &DeviceId, request: Request) -> Result<Response, IoError> {
+        let _timer = METRICS.latency.with_label_values(&[request.name()]).start_timer();
+        let trace_id = self.trace_id.unwrap_or_else(TraceId::generate);
+
+        // Unlock the mutex before network operations, the block makes sure
+        // the future doesn't hold it
+        let (address, counter, message, session, mut recv) = {
+            let mut client = self.client.lock().unwrap();
+            let daemon = client.storage_daemons.get_mut(device_id).unwrap();
+            let counter = daemon.client_counter;
+            daemon.client_counter += 1;
+            let address = daemon.address.clone();
+
+            // Assemble the request, handshakes don't need the capability and
+            // open the session the others are sent in
+            let (capability, session) = match request {
+                Request::Handshake { .. } => (Vec::new(), None),
+                _ => {
+                    let session = daemon.session.clone();
+                    (client.capability.clone(), session)
+                }
+            };
+            let message = RequestMessage {
+                counter,
+                epoch: client.epoch,
+                trace_id: Some(trace_id),
+                capability,
+                pool: client.pool.clone(),
+                request,
+            };
+            let session = session.map(|session| {
+                let header = RequestHeader {
+                    session: session.id,
+                    epoch: message.epoch,
+                    counter,
+                    pool: message.pool.clone(),
+                    opcode: message.request.opcode(),
+                };
+                (session.keys, header)
+            });
+
+            // Register our counter to get response
+            let (send, recv) = channel();
+            let pending = PendingRequest { sent: Instant::now(), session: session.clone(), channel: send };
+            client.response_channels.insert((address, counter), pending);
+            (address, counter, message, session, recv)
+        };
+        let encoded = match session {
+            Some((ref keys, ref header)) => seal_request(keys, header, &message.encode()),
+            None => message.encode(),
+        };
+
+        debug!("Sending request {}, size {}, trace {}", counter, encoded.len(), trace_id);
         METRICS.in_flight.inc();
+        let start = Instant::now();
         loop {
             // Send the request
-            self.udp_socket.send_to(&request, address).await?;
+            self.transport.send(&encoded, session.is_some(), address).await?;
 
             // Wait for the response or timeout
             tokio::select! {
@@ -255,40 +766,193 @@ impl Client {
                 }
                 _ = tokio::time::sleep(TIMEOUT) => {}
             }
-            debug!("Timeout, resending request {}", counter);
+
+            if self.timeout.is_some_and(|t| start.elapsed() >= t) {
+                let mut client = self.client.lock().unwrap();
+                client.partial_responses.remove(&(address, counter));
+                client.response_channels.remove(&(address, counter));
+                METRICS.in_flight.dec();
+                METRICS.timeouts.inc();
+                debug!("Giving up on request {}, trace {}", counter, trace_id);
+                return Err(NetworkError::TimedOut("No response from storage daemon".to_owned()).into());
+            }
             METRICS.resends.inc();
+
+            // Resend with the same counter, the daemon answers it again
+            // rather than doing it twice
+            debug!("Timeout, resending request {}, trace {}", counter, trace_id);
         }
     }
 }
 
-pub async fn create_client(storage_daemon_address: SocketAddr, pool: PoolName) -> Result<Client, Box<dyn std::error::Error>> {
+/// Fill `buffer` from `input`, unless the end is reached first.
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize, IoError> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match input.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn unexpected_response() -> IoError {
+    Error::Protocol("Invalid reply from storage daemon".to_owned()).into()
+}
+
+/// Get the result of a read from the response.
+fn read_response(response: Response) -> Result<Option<Vec<u8>>, IoError> {
+    match response {
+        Response::Data(data) | Response::Object { data, .. } => Ok(Some(data)),
+        Response::Error(ErrorCode::NotFound) => Ok(None),
+        Response::Error(code) => Err(code.into()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Get the new version from the response to a write.
+fn written_response(response: Response) -> Result<u64, IoError> {
+    match response {
+        Response::Written { version } => Ok(version),
+        Response::Error(code) => Err(code.into()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Get the new version from the response to a conditional write, `None` if
+/// the condition didn't hold.
+fn conditional_response(response: Response) -> Result<Option<u64>, IoError> {
+    match response {
+        Response::Written { version } => Ok(Some(version)),
+        Response::Error(ErrorCode::Conflict) => Ok(None),
+        Response::Error(code) => Err(code.into()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Check the response to a delete or flush.
+fn done_response(response: Response) -> Result<(), IoError> {
+    match response {
+        Response::Done => Ok(()),
+        Response::Error(code) => Err(code.into()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn new_client_inner(storage_daemon_address: SocketAddr, pool: PoolName) -> Arc<Mutex<ClientInner>> {
     let device_id = DeviceId([0; 16]);
-    let storage_map = StorageMap {
-        generation: 1,
-        groups: 128,
-        replicas: 1,
-        map_root: storage_map::Node::Device(device_id.clone()),
-    };
-    let mut storage_daemons = HashMap::new();
-    storage_daemons.insert(
-        device_id,
-        StorageDaemon {
-            address: storage_daemon_address,
-            client_counter: 0,
-        },
-    );
+    let storage_map = StorageMap::single_device(device_id.clone());
+    new_client_inner_with_map(pool, storage_map, MapOverlay::new(), vec![(device_id, storage_daemon_address)])
+}
+
+fn new_client_inner_with_map(pool: PoolName, storage_map: StorageMap, overlay: MapOverlay, devices: Vec<(DeviceId, SocketAddr)>) -> Arc<Mutex<ClientInner>> {
+    let storage_daemons = devices.into_iter().map(|(device_id, address)| {
+        (device_id, StorageDaemon { address, client_counter: 0, session: None })
+    }).collect();
 
     let client_inner = ClientInner {
         masters: vec![],
         master_connection: None,
         pool,
         storage_map,
+        overlay,
         storage_daemons,
+        epoch: clock_epoch(),
+        capability: Vec::new(),
+        ticket: None,
+        ticket_master: None,
         response_channels: HashMap::new(),
+        partial_responses: HashMap::new(),
+    };
+    Arc::new(Mutex::new(client_inner))
+}
+
+pub async fn create_client(storage_daemon_address: SocketAddr, pool: PoolName) -> Result<Client, Box<dyn std::error::Error>> {
+    pool.check()?;
+    create_client_udp(new_client_inner(storage_daemon_address, pool)).await
+}
+
+/// Create a client using the storage map of the pool it gets from the master
+/// at `master_address`, checking the master's certificate against the CA.
+///
+/// The client also gets a ticket from the master if it issues them, and
+/// opens sessions with the daemons with it.
+pub async fn create_client_from_master(master_address: &str, server_name: Option<&str>, ca_cert: &Path, pool: PoolName) -> Result<Client, Box<dyn std::error::Error>> {
+    pool.check()?;
+    let mut stream = connect_master(master_address, server_name, ca_cert, None).await?;
+    write_message(&mut stream, &ClientRequest::GetPool { pool: pool.clone() }).await?;
+    let (storage_map, devices) = match read_message(&mut stream).await? {
+        Some(ClientResponse::Pool { map, devices }) => (map, devices),
+        Some(ClientResponse::Error(e)) => return Err(IoError::new(ErrorKind::NotFound, e).into()),
+        Some(_) => return Err(IoError::new(ErrorKind::InvalidData, "Unexpected response from master").into()),
+        None => return Err(IoError::from(NetworkError::Closed("Master closed the connection".to_owned())).into()),
     };
-    let client_inner = Arc::new(Mutex::new(client_inner));
+    let ticket = match read_ticket(&mut stream).await {
+        Ok(ticket) => Some(ticket),
+        Err(e) => {
+            debug!("No ticket from master: {}", e);
+            None
+        }
+    };
+    let client = create_client_with_map(pool, storage_map, devices).await?;
+    if let Some(ticket) = ticket {
+        let mut inner = client.client.lock().unwrap();
+        inner.ticket = Some(ticket);
+        inner.ticket_master = Some(MasterInfo {
+            address: master_address.to_owned(),
+            server_name: server_name.map(str::to_owned),
+            ca_cert: ca_cert.to_owned(),
+        });
+    }
+    Ok(client)
+}
+
+/// Get a new ticket from the master.
+async fn get_ticket(master: &MasterInfo) -> Result<Ticket, IoError> {
+    let mut stream = connect_master(&master.address, master.server_name.as_deref(), &master.ca_cert, None).await
+        .map_err(|e| IoError::new(ErrorKind::NotConnected, e.to_string()))?;
+    read_ticket(&mut stream).await
+}
 
+/// Ask for a ticket on a connection to the master.
+async fn read_ticket<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: &mut S) -> Result<Ticket, IoError> {
+    write_message(stream, &ClientRequest::GetTicket).await?;
+    match read_message(stream).await? {
+        Some(ClientResponse::Ticket(ticket)) => Ticket::from_bytes(&ticket),
+        Some(ClientResponse::Error(e)) => Err(IoError::new(ErrorKind::PermissionDenied, e)),
+        Some(_) => Err(IoError::new(ErrorKind::InvalidData, "Unexpected response from master")),
+        None => Err(NetworkError::Closed("Master closed the connection".to_owned()).into()),
+    }
+}
+
+/// Create a client using the storage map of the pool and the devices, as
+/// the master lists them.
+pub async fn create_client_with_map(pool: PoolName, storage_map: StorageMap, devices: Vec<DeviceInfo>) -> Result<Client, Box<dyn std::error::Error>> {
+    pool.check()?;
+    let mut overlay = MapOverlay::new();
+    let devices = devices.into_iter().map(|device| {
+        overlay.set_status(device.spec.id.clone(), device.status);
+        (device.spec.id, device.address)
+    }).collect();
+    create_client_udp(new_client_inner_with_map(pool, storage_map, overlay, devices)).await
+}
+
+/// Create a client sending its requests on `socket`, which can be on a
+/// simulated network.
+pub async fn create_client_with_socket(socket: Socket, storage_daemon_address: SocketAddr, pool: PoolName) -> Result<Client, Box<dyn std::error::Error>> {
+    pool.check()?;
+    create_client_on(new_client_inner(storage_daemon_address, pool), socket)
+}
+
+async fn create_client_udp(client_inner: Arc<Mutex<ClientInner>>) -> Result<Client, Box<dyn std::error::Error>> {
     let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
+    create_client_on(client_inner, Socket::Udp(udp_socket))
+}
+
+fn create_client_on(client_inner: Arc<Mutex<ClientInner>>, udp_socket: Socket) -> Result<Client, Box<dyn std::error::Error>> {
     let udp_socket = Arc::new(udp_socket);
 
     // Start the receiving task
@@ -300,30 +964,205 @@ pub async fn create_client(storage_daemon_address: SocketAddr, pool: PoolName) -
 
     let client = Client {
         client: client_inner,
-        udp_socket,
+        transport: Transport::Udp(udp_socket),
         _receive_task_handle: receive_task_handle,
+        trace_id: None,
+        timeout: None,
     };
 
     Ok(client)
 }
 
-async fn receive_task(client: Arc<Mutex<ClientInner>>, udp_socket: Arc<UdpSocket>) -> Result<(), IoError> {
-    let udp_socket: &UdpSocket = &udp_socket;
+/// Create a client talking to the storage daemon over DTLS, checking its
+/// certificate against the CA.
+#[cfg(feature = "dtls")]
+pub async fn create_client_dtls(storage_daemon_address: SocketAddr, pool: PoolName, ca_cert: &Path) -> Result<Client, Box<dyn std::error::Error>> {
+    pool.check()?;
+    let client_inner = new_client_inner(storage_daemon_address, pool);
+
+    let stream = dtls::connect(storage_daemon_address, ca_cert).await?;
+    let (read, session) = dtls::split(stream);
+    let mut sessions = HashMap::new();
+    sessions.insert(storage_daemon_address, session);
+
+    let receive_task_handle = tokio::spawn(receive_task_dtls(client_inner.clone(), read, storage_daemon_address));
+    let receive_task_handle = Arc::new(CancelTask(receive_task_handle));
+
+    let client = Client {
+        client: client_inner,
+        transport: Transport::Dtls(Arc::new(sessions)),
+        _receive_task_handle: receive_task_handle,
+        trace_id: None,
+        timeout: None,
+    };
+
+    Ok(client)
+}
+
+async fn receive_task(client: Arc<Mutex<ClientInner>>, udp_socket: Arc<Socket>) -> Result<(), IoError> {
+    let mut bufs = vec![vec![0; 65536]; RECEIVE_BATCH];
+    let mut received = Vec::with_capacity(RECEIVE_BATCH);
+    loop {
+        let count = udp_socket.recv_batch(&mut bufs, &mut received).await?;
+        for (buf, &(len, addr)) in bufs[0..count].iter().zip(&received) {
+            debug!("Got packet from {}, size {}", addr, len);
+            if buf[..len].first() == Some(&VERSION_SEALED_RESPONSE) {
+                match open_sealed_response(&client, addr, &buf[0..len]) {
+                    Some(msg) => deliver_response(&client, addr, &msg, true),
+                    None => debug!("Invalid sealed reply from {}", addr),
+                }
+                continue;
+            }
+            match check_checksum(&buf[0..len]) {
+                Some(msg) => deliver_response(&client, addr, msg, false),
+                None => {
+                    debug!("Corrupted reply from {}", addr);
+                    METRICS.corrupt_responses.inc();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "dtls")]
+async fn receive_task_dtls(client: Arc<Mutex<ClientInner>>, mut read: ReadHalf<DtlsStream>, addr: SocketAddr) -> Result<(), IoError> {
     let mut buf = [0; 65536];
     loop {
-        let (len, addr) = udp_socket.recv_from(&mut buf).await?;
-        debug!("Got packet from {}, size {}", addr, len);
-        let msg = &buf[0..len];
-        if msg.len() < 4 {
-            continue;
+        let len = tokio::io::AsyncReadExt::read(&mut read, &mut buf).await?;
+        if len == 0 {
+            return Err(NetworkError::Closed("DTLS session closed".to_owned()).into());
         }
-        let counter = Cursor::new(msg).read_u32::<BigEndian>().unwrap();
+        debug!("Got DTLS message from {}, size {}", addr, len);
+        deliver_response(&client, addr, &buf[0..len], true);
+    }
+}
+
+/// Add a chunk of a response, returning it once complete.
+fn add_chunk(client: &mut ClientInner, addr: SocketAddr, chunk: ResponseChunk) -> Option<ResponseMessage> {
+    let key = (addr, chunk.counter);
+
+    // Only collect chunks for requests we're waiting on
+    if !client.response_channels.contains_key(&key) {
+        return None;
+    }
+    let assembler = client.partial_responses
+        .entry(key)
+        .or_insert_with(|| ChunkAssembler::new(MAX_RESPONSE_SIZE));
+    match assembler.add(chunk) {
+        Ok(None) => None,
+        Ok(Some(message)) => {
+            client.partial_responses.remove(&key);
+            Some(message)
+        }
+        Err(e) => {
+            debug!("Invalid chunked reply from {}: {}", addr, e);
+            client.partial_responses.remove(&key);
+            None
+        }
+    }
+}
+
+/// Open a frame of the response to a request sent in a session.
+fn open_sealed_response(client: &Mutex<ClientInner>, addr: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    let (_, counter) = read_response_header(data)?;
+    let (keys, header) = {
+        let client = client.lock().unwrap();
+        client.response_channels.get(&(addr, counter))?.session.clone()?
+    };
+    open_response(&keys, &header, data)
+}
+
+/// Pass a response to the task waiting for it.
+///
+/// `authenticated` is false for plain datagrams, which only get through for
+/// requests sent in a session if they tell us the daemon doesn't know it.
+fn deliver_response(client: &Mutex<ClientInner>, addr: SocketAddr, msg: &[u8], authenticated: bool) {
+    let frame = match ResponseFrame::decode(msg) {
+        Ok(f) => f,
+        Err(e) => {
+            debug!("Invalid reply from {}: {}", addr, e);
+            return;
+        }
+    };
+
+    let mut client = client.lock().unwrap();
+
+    // Requests sent in a session get sealed responses, unless the daemon
+    // doesn't know the session
+    if !authenticated {
+        let (counter, no_session) = match frame {
+            ResponseFrame::Whole(ref m) => (m.counter, m.response == Response::Error(ErrorCode::NoSession)),
+            ResponseFrame::Chunk(ref c) => (c.counter, false),
+        };
+        let in_session = client.response_channels.get(&(addr, counter)).is_some_and(|p| p.session.is_some());
+        if in_session && !no_session {
+            debug!("Plain reply to sealed request from {}", addr);
+            return;
+        }
+    }
+
+    let message = match frame {
+        ResponseFrame::Whole(m) => m,
+        ResponseFrame::Chunk(chunk) => match add_chunk(&mut client, addr, chunk) {
+            Some(m) => m,
+            None => return,
+        },
+    };
+
+    // Get the channel
+    if let Some(pending) = client.response_channels.remove(&(addr, message.counter)) {
+        debug!("Handling reply, counter={}, after {:?}", message.counter, pending.sent.elapsed());
+        pending.channel.send(message.response).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+
+    use crate::{ObjectId, PoolName};
+    use super::{create_client, read_full};
+
+    #[test]
+    fn test_read_full() {
+        // A reader returning a few bytes at a time
+        struct Trickle<'a>(&'a [u8]);
+        impl std::io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut input = Trickle(b"hello world");
+        let mut buffer = [0; 8];
+        assert_eq!(read_full(&mut input, &mut buffer).unwrap(), 8);
+        assert_eq!(&buffer, b"hello wo");
+        assert_eq!(read_full(&mut input, &mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"rld");
+        assert_eq!(read_full(&mut input, &mut buffer).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // A daemon that never answers
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = create_client(socket.local_addr().unwrap(), PoolName("default".to_owned())).await.unwrap();
+
+        let start = Instant::now();
+        let client = client.with_timeout(Duration::from_millis(500));
+        let err = client.read_object(&ObjectId(b"obj".to_vec())).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(500));
 
-        // Get the channel
-        let mut client = client.lock().unwrap();
-        if let Some((_, channel)) = client.response_channels.remove(&(addr, counter)) {
-            debug!("Handling reply, counter={}", counter);
-            channel.send(msg.to_owned()).unwrap();
+        // The request was resent until then
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            socket.recv_from(&mut buf).await.unwrap();
         }
     }
 }
@@ -0,0 +1,164 @@
+//! Configuration file, providing defaults for command-line options.
+//!
+//! The file is in TOML. Values are strings, numbers, booleans, or arrays of
+//! those, grouped in `[table]` sections. Keys are the names of long options,
+//! the top-level ones applying to any command and `[command]` or
+//! `[command.subcommand]` ones applying to that command only:
+//!
+//! ```toml
+//! storage-daemon = "10.0.0.5:4001"
+//! pool = "default"
+//! serve-metrics = "0.0.0.0:9100"
+//!
+//! [admin]
+//! master = "master.example.org:4020"
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+
+/// A value from the file, with scalars turned into their text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigValue {
+    Single(String),
+    List(Vec<String>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// The keys in each table, the top-level table being `""`.
+    tables: HashMap<String, HashMap<String, ConfigValue>>,
+}
+
+/// A scalar from the file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Datetime(toml::value::Datetime),
+}
+
+impl Scalar {
+    fn into_text(self) -> String {
+        match self {
+            Scalar::String(s) => s,
+            Scalar::Integer(i) => i.to_string(),
+            Scalar::Float(f) => f.to_string(),
+            Scalar::Boolean(b) => b.to_string(),
+            Scalar::Datetime(d) => d.to_string(),
+        }
+    }
+}
+
+/// An entry in a table, which can be a table itself.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Single(Scalar),
+    List(Vec<Scalar>),
+    Table(HashMap<String, Entry>),
+}
+
+/// Put the values of a table in `tables`, and its sub-tables under their
+/// dotted names.
+fn add_table(tables: &mut HashMap<String, HashMap<String, ConfigValue>>, name: String, entries: HashMap<String, Entry>) {
+    let mut values = HashMap::new();
+    for (key, entry) in entries {
+        let value = match entry {
+            Entry::Single(scalar) => ConfigValue::Single(scalar.into_text()),
+            Entry::List(items) => ConfigValue::List(items.into_iter().map(Scalar::into_text).collect()),
+            Entry::Table(table) => {
+                let sub_name = if name.is_empty() { key } else { format!("{}.{}", name, key) };
+                add_table(tables, sub_name, table);
+                continue;
+            }
+        };
+        values.insert(key, value);
+    }
+    if !values.is_empty() {
+        tables.insert(name, values);
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, IoError> {
+        std::fs::read_to_string(path)
+            .and_then(|text| Config::parse(&text))
+            .map_err(|e| IoError::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, IoError> {
+        let entries: HashMap<String, Entry> = toml::from_str(text)
+            .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))?;
+        let mut tables = HashMap::new();
+        add_table(&mut tables, String::new(), entries);
+        Ok(Config { tables })
+    }
+
+    /// Get a key from a table, `""` being the top-level table.
+    pub fn get(&self, table: &str, key: &str) -> Option<&ConfigValue> {
+        self.tables.get(table).and_then(|t| t.get(key))
+    }
+
+    /// Iterate on all the keys, as `(table, key)`.
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tables
+            .iter()
+            .flat_map(|(table, keys)| keys.keys().map(move |key| (table.as_str(), key.as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigValue};
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "# Cluster
+storage-daemon = \"127.0.0.1:4000\" # local
+report-interval = 30
+pool = ['default', \"a\\\"b\"]
+
+[ admin . device ]
+weight = 1_000
+zstd = true
+",
+        ).unwrap();
+        let single = |s: &str| Some(ConfigValue::Single(s.to_owned()));
+        assert_eq!(config.get("", "storage-daemon").cloned(), single("127.0.0.1:4000"));
+        assert_eq!(config.get("", "report-interval").cloned(), single("30"));
+        assert_eq!(
+            config.get("", "pool").cloned(),
+            Some(ConfigValue::List(vec!["default".to_owned(), "a\"b".to_owned()])),
+        );
+        assert_eq!(config.get("admin.device", "weight").cloned(), single("1000"));
+        assert_eq!(config.get("admin.device", "zstd").cloned(), single("true"));
+        assert_eq!(config.get("admin", "weight"), None);
+        let mut keys: Vec<_> = config.keys().collect();
+        keys.sort();
+        assert_eq!(keys.len(), 5);
+        assert_eq!(keys[0], ("", "pool"));
+        let config = Config::parse("since = 1979-05-27").unwrap();
+        assert_eq!(config.get("", "since").cloned(), single("1979-05-27"));
+
+        for bad in [
+            "pool",
+            "pool = default",
+            "pool = \"default",
+            "pool = \"a\" \"b\"",
+            "pool = [\"a\" \"b\"]",
+            "[admin",
+            "[]",
+            "a = 1\na = 2",
+            "a = [[1]]",
+        ] {
+            assert!(Config::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Copy of objects between pools, possibly of different clusters.
+//!
+//! The protocol has no copy request, so the data always goes through the
+//! client, one part at a time.
+
+use std::io::{Error as IoError, ErrorKind};
+
+use crate::ObjectId;
+use crate::client::{Client, PART_SIZE};
+
+/// What was copied so far.
+#[derive(Clone, Debug, Default)]
+pub struct CopyStats {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// Copy an object, calling `progress` after each part.
+///
+/// Returns `false` if the source object doesn't exist.
+pub async fn copy_object(source: &Client, source_id: &ObjectId, destination: &Client, destination_id: &ObjectId, stats: &mut CopyStats, progress: &mut dyn FnMut(&CopyStats)) -> Result<bool, IoError> {
+    let size = match source.stat_object(source_id).await? {
+        Some(stat) => u32::try_from(stat.size).map_err(|_| IoError::new(ErrorKind::InvalidData, "Object too large"))?,
+        None => return Ok(false),
+    };
+    let mut offset = 0;
+    loop {
+        let len = (size - offset).min(PART_SIZE as u32);
+        let part = match source.read_part(source_id, offset, len).await? {
+            Some(part) if part.len() == len as usize => part,
+            Some(_) => return Err(IoError::new(ErrorKind::Interrupted, format!("{:?} changed while copying it", source_id))),
+            None if offset == 0 => return Ok(false),
+            None => return Err(IoError::new(ErrorKind::Interrupted, format!("{:?} was deleted while copying it", source_id))),
+        };
+        if offset == 0 {
+            destination.write_object(destination_id, &part).await?;
+        } else {
+            destination.write_part(destination_id, offset, &part).await?;
+        }
+        offset += len;
+        stats.bytes += len as u64;
+        progress(stats);
+        if offset >= size {
+            break;
+        }
+    }
+    stats.objects += 1;
+    progress(stats);
+    Ok(true)
+}
+
+/// Copy all the objects whose name starts with `prefix`, replacing the
+/// prefix with `destination_prefix` in their new name.
+///
+/// The objects are listed before copying, so objects created during the
+/// copy (possibly by it) are not copied.
+pub async fn copy_prefix(source: &Client, prefix: &[u8], destination: &Client, destination_prefix: &[u8], progress: &mut dyn FnMut(&CopyStats)) -> Result<CopyStats, IoError> {
+    let objects = source.list_all_objects(prefix).await?;
+
+    let mut stats = CopyStats::default();
+    for source_id in objects {
+        let mut name = destination_prefix.to_owned();
+        name.extend_from_slice(&source_id.0[prefix.len()..]);
+        // Objects deleted since listed are skipped
+        copy_object(source, &source_id, destination, &ObjectId(name), &mut stats, progress).await?;
+    }
+    Ok(stats)
+}
@@ -0,0 +1,252 @@
+//! Capabilities, authorizing clients to do specific operations.
+//!
+//! A capability is issued by the master and sealed with the keys it shares
+//! with the storage daemons, so clients can't forge or modify them. Clients
+//! attach it to their requests, and daemons check that it covers the pool
+//! and operation before executing them.
+//!
+//! A capability is a bearer token, so daemons with keys only accept it when
+//! it can't be read in transit: in a session, over DTLS, or from another
+//! daemon. Clients are handed credentials, holding a capability and the
+//! ticket to open sessions with (see `super::session`).
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Error as IoError, ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use crate::PoolName;
+use super::keyring::Keyring;
+use super::session::Ticket;
+
+/// Reading objects.
+pub const OP_READ: u8 = 0x01;
+/// Writing objects, fully or partially.
+pub const OP_WRITE: u8 = 0x02;
+/// Deleting objects.
+pub const OP_DELETE: u8 = 0x04;
+
+/// Associated data for sealed capabilities.
+const CAPABILITY_AAD: &[u8] = b"store capability";
+
+/// Version byte for credentials, a capability with a ticket.
+const VERSION_CREDENTIALS: u8 = 0x10;
+
+fn invalid(msg: &'static str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// What a client is allowed to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capability {
+    /// Identifies the client, for logging.
+    pub client_id: String,
+    /// The pools the client can access, all of them if empty.
+    pub pools: Vec<PoolName>,
+    /// The allowed operations, a combination of the `OP_*` flags.
+    pub ops: u8,
+    /// Expiration time, in seconds since 1970.
+    pub expires: u64,
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.write_u16::<BigEndian>(s.len() as u16).unwrap();
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(reader: &mut Cursor<&[u8]>) -> Result<String, IoError> {
+    let len = reader.read_u16::<BigEndian>()? as usize;
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid("Invalid string in capability"))
+}
+
+impl Capability {
+    /// Create a capability valid for the given duration.
+    pub fn new(client_id: String, pools: Vec<PoolName>, ops: u8, lifetime: Duration) -> Capability {
+        Capability {
+            client_id,
+            pools,
+            ops,
+            expires: now() + lifetime.as_secs(),
+        }
+    }
+
+    /// Whether this allows the operation on the pool.
+    pub fn allows(&self, pool: &PoolName, op: u8) -> bool {
+        self.ops & op == op
+            && (self.pools.is_empty() || self.pools.contains(pool))
+            && self.expires >= now()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_string(&mut buf, &self.client_id);
+        buf.write_u64::<BigEndian>(self.expires).unwrap();
+        buf.write_u8(self.ops).unwrap();
+        buf.write_u16::<BigEndian>(self.pools.len() as u16).unwrap();
+        for pool in &self.pools {
+            write_string(&mut buf, &pool.0);
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Capability, IoError> {
+        let mut reader = Cursor::new(data);
+        let client_id = read_string(&mut reader)?;
+        let expires = reader.read_u64::<BigEndian>()?;
+        let ops = reader.read_u8()?;
+        let num_pools = reader.read_u16::<BigEndian>()? as usize;
+        let mut pools = Vec::with_capacity(num_pools);
+        for _ in 0..num_pools {
+            pools.push(PoolName(read_string(&mut reader)?));
+        }
+        if reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after capability"));
+        }
+        Ok(Capability { client_id, pools, ops, expires })
+    }
+
+    /// Seal the capability so it can be handed to a client.
+    pub fn seal(&self, keyring: &Keyring) -> Vec<u8> {
+        keyring.seal(CAPABILITY_AAD, &self.encode(), 0).0
+    }
+
+    /// Open a capability presented by a client.
+    ///
+    /// This checks that it was issued with one of our keys, but not what it
+    /// allows; use `allows()` for that.
+    pub fn open(keyring: &Keyring, sealed: &[u8]) -> Result<Capability, IoError> {
+        let (data, _) = keyring.open(CAPABILITY_AAD, sealed, 0)
+            .ok_or_else(|| invalid("Invalid capability"))?;
+        Capability::decode(&data)
+    }
+}
+
+/// Serialize a capability with a ticket, to hand them to a client.
+///
+/// Format: version byte, sealed capability length (u16), sealed capability,
+/// then the ticket (see `Ticket::to_bytes()`).
+pub fn encode_credentials(capability: &[u8], ticket: &Ticket) -> Zeroizing<Vec<u8>> {
+    let ticket = ticket.to_bytes();
+    let mut buf = Zeroizing::new(Vec::with_capacity(3 + capability.len() + ticket.len()));
+    buf.write_u8(VERSION_CREDENTIALS).unwrap();
+    buf.write_u16::<BigEndian>(capability.len() as u16).unwrap();
+    buf.extend_from_slice(capability);
+    buf.extend_from_slice(&ticket);
+    buf
+}
+
+/// Read credentials, returning the sealed capability and the ticket.
+///
+/// A sealed capability alone is accepted too, it has no ticket.
+pub fn decode_credentials(data: &[u8]) -> Result<(Vec<u8>, Option<Ticket>), IoError> {
+    if data.first() != Some(&VERSION_CREDENTIALS) {
+        return Ok((data.to_vec(), None));
+    }
+    let mut reader = Cursor::new(data);
+    reader.set_position(1);
+    let len = reader.read_u16::<BigEndian>()? as usize;
+    let start = reader.position() as usize;
+    if data.len() < start + len {
+        return Err(invalid("Invalid credentials"));
+    }
+    let ticket = Ticket::from_bytes(&data[start + len..])?;
+    Ok((data[start..start + len].to_vec(), Some(ticket)))
+}
+
+/// Parse operations from a list such as "read,write".
+pub fn parse_ops(s: &str) -> Result<u8, IoError> {
+    let mut ops = 0;
+    for op in s.split(',') {
+        ops |= match op.trim() {
+            "read" => OP_READ,
+            "write" => OP_WRITE,
+            "delete" => OP_DELETE,
+            _ => return Err(IoError::new(ErrorKind::InvalidInput, "Unknown operation")),
+        };
+    }
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::PoolName;
+    use super::super::KeyPair;
+    use super::super::keyring::Keyring;
+    use super::super::session::issue_ticket;
+    use super::{Capability, OP_DELETE, OP_READ, OP_WRITE, decode_credentials, encode_credentials, parse_ops};
+
+    #[test]
+    fn test_capability() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let capability = Capability::new(
+            "client1".to_owned(),
+            vec![PoolName("pool1".to_owned()), PoolName("pool2".to_owned())],
+            OP_READ | OP_WRITE,
+            Duration::from_secs(60),
+        );
+        assert_eq!(Capability::decode(&capability.encode()).unwrap(), capability);
+
+        let sealed = capability.seal(&keyring);
+        let opened = Capability::open(&keyring, &sealed).unwrap();
+        assert_eq!(opened, capability);
+        assert!(opened.allows(&PoolName("pool1".to_owned()), OP_READ));
+        assert!(opened.allows(&PoolName("pool2".to_owned()), OP_WRITE));
+        assert!(!opened.allows(&PoolName("pool1".to_owned()), OP_DELETE));
+        assert!(!opened.allows(&PoolName("pool3".to_owned()), OP_READ));
+
+        // Forged or modified
+        assert!(Capability::open(&Keyring::new(KeyPair::generate()), &sealed).is_err());
+        let mut modified = sealed.clone();
+        let last = modified.len() - 1;
+        modified[last] ^= 1;
+        assert!(Capability::open(&keyring, &modified).is_err());
+
+        // All pools
+        let mut capability = capability;
+        capability.pools.clear();
+        assert!(capability.allows(&PoolName("pool3".to_owned()), OP_READ));
+
+        // Expired
+        capability.expires = 1000;
+        assert!(!capability.allows(&PoolName("pool1".to_owned()), OP_READ));
+    }
+
+    #[test]
+    fn test_credentials() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let sealed = Capability::new("client1".to_owned(), vec![], OP_READ, Duration::from_secs(60)).seal(&keyring);
+        let ticket = issue_ticket(&keyring, Duration::from_secs(60));
+
+        let (capability, read) = decode_credentials(&encode_credentials(&sealed, &ticket)).unwrap();
+        assert_eq!(capability, sealed);
+        let read = read.unwrap();
+        assert_eq!(read.sealed, ticket.sealed);
+        assert!(read.ticket_key == ticket.ticket_key);
+
+        // A capability alone
+        let (capability, read) = decode_credentials(&sealed).unwrap();
+        assert_eq!(capability, sealed);
+        assert!(read.is_none());
+
+        let encoded = encode_credentials(&sealed, &ticket);
+        assert!(decode_credentials(&encoded[0..sealed.len()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ops() {
+        assert_eq!(parse_ops("read").unwrap(), OP_READ);
+        assert_eq!(parse_ops("read,delete").unwrap(), OP_READ | OP_DELETE);
+        assert!(parse_ops("read,admin").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Sealed requests with a header readable without decrypting them.
+//!
+//! Clients with a session (see `super::session`) send their requests in an
+//! envelope. The header holds what a daemon needs to find the session and
+//! route the request: session ID, epoch, counter, pool and opcode. It is sent
+//! in the clear but used as the associated data of the sealed body, so it
+//! can't be changed without the message being rejected. This lets a daemon
+//! drop replays and requests for pools it doesn't hold before decrypting
+//! them.
+//!
+//! Format: version byte, session ID (u32), epoch (u32), counter (u32), pool
+//! name length (u16), pool name, opcode, then the body sealed with the
+//! session keys using `KeyPair::seal()`.
+//!
+//! The daemon seals each frame of the response the same way. Its header is
+//! the version byte, session ID (u32) and counter (u32) of the request; the
+//! pool and opcode of the request are bound as associated data too (see
+//! `super::request_aad()`), so a response can't be passed off as the answer
+//! to another request.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+
+use crate::PoolName;
+use super::{KeyPair, SEAL_OVERHEAD, request_aad};
+
+/// Version byte for requests with a cleartext header.
+pub const VERSION_ENVELOPE: u8 = 3;
+
+/// Version byte for responses to requests sent in a session.
+pub const VERSION_SEALED_RESPONSE: u8 = 5;
+
+/// Size of the cleartext header of a sealed response.
+pub const RESPONSE_HEADER_SIZE: usize = 9;
+
+/// The cleartext part of a sealed request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub session: u32,
+    pub epoch: u32,
+    pub counter: u32,
+    pub pool: PoolName,
+    pub opcode: u8,
+}
+
+impl RequestHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(16 + self.pool.0.len());
+        result.write_u8(VERSION_ENVELOPE).unwrap();
+        result.write_u32::<BigEndian>(self.session).unwrap();
+        result.write_u32::<BigEndian>(self.epoch).unwrap();
+        result.write_u32::<BigEndian>(self.counter).unwrap();
+        result.write_u16::<BigEndian>(self.pool.0.len() as u16).unwrap();
+        result.extend_from_slice(self.pool.0.as_bytes());
+        result.write_u8(self.opcode).unwrap();
+        result
+    }
+
+    /// Read the header of a sealed request, without checking it.
+    ///
+    /// Returns the header and its size. It is only authenticated once the
+    /// request is opened with `open_request()`.
+    pub fn read(data: &[u8]) -> Option<(RequestHeader, usize)> {
+        let mut reader = Cursor::new(data);
+        if reader.read_u8().ok()? != VERSION_ENVELOPE {
+            return None;
+        }
+        let session = reader.read_u32::<BigEndian>().ok()?;
+        let epoch = reader.read_u32::<BigEndian>().ok()?;
+        let counter = reader.read_u32::<BigEndian>().ok()?;
+        let pool_len = reader.read_u16::<BigEndian>().ok()? as usize;
+        let mut pool = vec![0; pool_len];
+        reader.read_exact(&mut pool).ok()?;
+        let pool = PoolName(String::from_utf8(pool).ok()?);
+        let opcode = reader.read_u8().ok()?;
+        let header = RequestHeader { session, epoch, counter, pool, opcode };
+        Some((header, reader.position() as usize))
+    }
+}
+
+/// Seal a request body, with the header in the clear.
+pub fn seal_request(keys: &KeyPair, header: &RequestHeader, body: &[u8]) -> Vec<u8> {
+    let mut result = header.encode();
+    let (sealed, _) = keys.seal(&result, body, header.counter);
+    result.extend_from_slice(&sealed);
+    result
+}
+
+/// Open a sealed request, returning its body.
+///
+/// The header was read with `RequestHeader::read()` to find the keys,
+/// `header_len` is its size.
+pub fn open_request(keys: &KeyPair, header: &RequestHeader, header_len: usize, data: &[u8]) -> Option<Vec<u8>> {
+    let (aad, sealed) = data.split_at(header_len);
+    let (body, next_counter) = keys.open(aad, sealed, header.counter)?;
+
+    // The counter is also in the nonce, they have to agree
+    if next_counter != header.counter.wrapping_add(1) {
+        return None;
+    }
+    Some(body)
+}
+
+/// Seal a frame of the response to a request sent in a session.
+///
+/// `header` is the header the request came with.
+pub fn seal_response(keys: &KeyPair, header: &RequestHeader, frame: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(RESPONSE_HEADER_SIZE + SEAL_OVERHEAD + frame.len());
+    result.write_u8(VERSION_SEALED_RESPONSE).unwrap();
+    result.write_u32::<BigEndian>(header.session).unwrap();
+    result.write_u32::<BigEndian>(header.counter).unwrap();
+    let (sealed, _) = keys.seal(&response_aad(&result, header), frame, header.counter);
+    result.extend_from_slice(&sealed);
+    result
+}
+
+/// Read the session ID and request counter from a sealed response, without
+/// checking them.
+pub fn read_response_header(data: &[u8]) -> Option<(u32, u32)> {
+    let mut reader = Cursor::new(data);
+    if reader.read_u8().ok()? != VERSION_SEALED_RESPONSE {
+        return None;
+    }
+    let session = reader.read_u32::<BigEndian>().ok()?;
+    let counter = reader.read_u32::<BigEndian>().ok()?;
+    Some((session, counter))
+}
+
+/// Open a frame of the response to a request sent in a session.
+///
+/// `header` is the header the request was sent with.
+pub fn open_response(keys: &KeyPair, header: &RequestHeader, data: &[u8]) -> Option<Vec<u8>> {
+    if read_response_header(data)? != (header.session, header.counter) {
+        return None;
+    }
+    let (cleartext, sealed) = data.split_at(RESPONSE_HEADER_SIZE);
+    let (frame, next_counter) = keys.open(&response_aad(cleartext, header), sealed, header.counter)?;
+    if next_counter != header.counter.wrapping_add(1) {
+        return None;
+    }
+    Some(frame)
+}
+
+fn response_aad(cleartext: &[u8], header: &RequestHeader) -> Vec<u8> {
+    let mut aad = cleartext.to_owned();
+    aad.extend_from_slice(&request_aad(&header.pool.0, header.opcode));
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PoolName;
+    use super::super::KeyPair;
+    use super::{RESPONSE_HEADER_SIZE, RequestHeader, open_request, open_response, seal_request, seal_response};
+
+    #[test]
+    fn test_envelope() {
+        let keys = KeyPair::generate();
+        let header = RequestHeader {
+            session: 7,
+            epoch: 1000,
+            counter: 42,
+            pool: PoolName("pool".to_owned()),
+            opcode: 0x03,
+        };
+        let body = b"object name and data";
+        let sealed = seal_request(&keys, &header, body);
+
+        // The header can be read without the keys
+        let (read, header_len) = RequestHeader::read(&sealed).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(&sealed[0..header_len], &header.encode()[..]);
+        assert!(!sealed.windows(8).any(|w| body.windows(8).any(|b| b == w)));
+
+        assert_eq!(open_request(&keys, &read, header_len, &sealed), Some(body.to_vec()));
+
+        // Changing the header is detected
+        for i in 0..header_len {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            if let Some((read, header_len)) = RequestHeader::read(&tampered) {
+                assert_eq!(open_request(&keys, &read, header_len, &tampered), None);
+            }
+        }
+
+        assert_eq!(open_request(&KeyPair::generate(), &read, header_len, &sealed), None);
+        assert_eq!(RequestHeader::read(&sealed[0..8]), None);
+    }
+
+    #[test]
+    fn test_sealed_response() {
+        let keys = KeyPair::generate();
+        let header = RequestHeader {
+            session: 7,
+            epoch: 1000,
+            counter: 42,
+            pool: PoolName("pool".to_owned()),
+            opcode: 0x01,
+        };
+        let frame = b"the object data that was read";
+        let sealed = seal_response(&keys, &header, frame);
+        assert!(!sealed.windows(8).any(|w| frame.windows(8).any(|b| b == w)));
+        assert_eq!(open_response(&keys, &header, &sealed), Some(frame.to_vec()));
+
+        // It only opens as the response to the same request
+        let other_pool = RequestHeader { pool: PoolName("other".to_owned()), ..header.clone() };
+        assert_eq!(open_response(&keys, &other_pool, &sealed), None);
+        let other_opcode = RequestHeader { opcode: 0x03, ..header.clone() };
+        assert_eq!(open_response(&keys, &other_opcode, &sealed), None);
+        let other_counter = RequestHeader { counter: 43, ..header.clone() };
+        assert_eq!(open_response(&keys, &other_counter, &sealed), None);
+        for i in 0..RESPONSE_HEADER_SIZE {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(open_response(&keys, &header, &tampered), None);
+        }
+        assert_eq!(open_response(&KeyPair::generate(), &header, &sealed), None);
+        assert_eq!(open_response(&keys, &header, &sealed[0..5]), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Epochs, so that counters can restart from zero safely.
+//!
+//! Each process picks an epoch when it starts, and sends it along with its
+//! counters. The epoch must never go down: a receiver seeing a higher epoch
+//! knows the sender restarted and starts tracking its counters anew, while
+//! messages from a lower epoch are rejected as replays.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Get an epoch from the clock, in seconds since 1970.
+///
+/// This is enough if the clock never goes backwards, and the process doesn't
+/// restart multiple times within a second; use `next_boot_epoch()` otherwise.
+pub fn clock_epoch() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Get a new epoch, persisting it in a file so that it always goes up.
+///
+/// The epoch follows the clock when it can, so that it stays comparable with
+/// the ones from `clock_epoch()`.
+pub fn next_boot_epoch(path: &Path) -> Result<u32, IoError> {
+    let previous = match File::open(path) {
+        Ok(mut file) => Some(file.read_u32::<BigEndian>()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let epoch = match previous {
+        Some(previous) => {
+            let next = previous.checked_add(1).ok_or_else(|| {
+                IoError::new(ErrorKind::InvalidData, "Epoch overflow")
+            })?;
+            next.max(clock_epoch())
+        }
+        None => clock_epoch(),
+    };
+
+    // Write it to a new file then rename, so it's never lost
+    let mut temp_name = path.file_name()
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Invalid epoch path"))?
+        .to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+    file.write_u32::<BigEndian>(epoch)?;
+    file.flush()?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::{clock_epoch, next_boot_epoch};
+
+    #[test]
+    fn test_boot_epoch() {
+        let dir = TempDir::new("store_epoch_test").unwrap();
+        let path = dir.path().join("epoch");
+        let first = next_boot_epoch(&path).unwrap();
+        assert!(first >= clock_epoch() - 1);
+        let second = next_boot_epoch(&path).unwrap();
+        assert!(second > first);
+
+        // Clock going backwards
+        std::fs::write(&path, (u32::MAX - 1).to_be_bytes()).unwrap();
+        assert_eq!(next_boot_epoch(&path).unwrap(), u32::MAX);
+        assert!(next_boot_epoch(&path).is_err());
+    }
+}
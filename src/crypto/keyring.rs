@@ -0,0 +1,316 @@
+//! Sets of keys, and storing them in a file.
+//!
+//! A keyring holds the current keys and the previous ones, so that messages
+//! sealed by clients that haven't picked up new keys yet are still accepted
+//! after a rotation.
+//!
+//! The file starts with a magic string and a version byte, followed by the
+//! number of keys and the keys themselves. Since it holds secrets, it is
+//! created readable only by its owner, and files readable by other users are
+//! refused.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use super::{KEY_PAIR_SIZE, KeyPair, sealed_key_id};
+
+const MAGIC: &[u8; 4] = b"STKR";
+const VERSION: u8 = 1;
+
+/// Maximum number of keys in a file, to protect against corrupted files.
+const MAX_KEYS: usize = 1024;
+
+fn invalid(msg: &'static str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+/// Check that the file can't be read by other users.
+#[cfg(unix)]
+fn check_permissions(file: &File) -> Result<(), IoError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(IoError::new(
+            ErrorKind::PermissionDenied,
+            format!("Keyring file is accessible by other users (mode {:o})", mode & 0o777),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &File) -> Result<(), IoError> {
+    Ok(())
+}
+
+/// Create a file only readable by its owner.
+fn create_private(path: &Path) -> Result<File, IoError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Encode keys in the keyring format.
+pub fn encode_keyring(keys: &[KeyPair]) -> Zeroizing<Vec<u8>> {
+    let mut result = Zeroizing::new(Vec::with_capacity(9 + keys.len() * KEY_PAIR_SIZE));
+    result.extend_from_slice(MAGIC);
+    result.write_u8(VERSION).unwrap();
+    result.write_u32::<BigEndian>(keys.len() as u32).unwrap();
+    for key in keys {
+        result.extend_from_slice(&key.to_bytes()[..]);
+    }
+    result
+}
+
+/// Decode keys from the keyring format.
+pub fn decode_keyring(data: &[u8]) -> Result<Vec<KeyPair>, IoError> {
+    let mut reader = Cursor::new(data);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("Not a keyring file"));
+    }
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("Unknown keyring version {}", version),
+        ));
+    }
+    let count = reader.read_u32::<BigEndian>()? as usize;
+    if count > MAX_KEYS {
+        return Err(invalid("Too many keys in keyring"));
+    }
+    let mut keys = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut bytes = Zeroizing::new([0; KEY_PAIR_SIZE]);
+        reader.read_exact(&mut bytes[..])?;
+        keys.push(KeyPair::from_bytes(&bytes));
+    }
+    if reader.position() as usize != data.len() {
+        return Err(invalid("Extra data after keyring"));
+    }
+    Ok(keys)
+}
+
+/// Read keys from a keyring file.
+pub fn read_keyring(path: &Path) -> Result<Vec<KeyPair>, IoError> {
+    let mut file = File::open(path)?;
+    check_permissions(&file)?;
+    let size = file.metadata()?.len() as usize;
+    let mut data = Zeroizing::new(Vec::with_capacity(size));
+    file.read_to_end(&mut data)?;
+    decode_keyring(&data)
+}
+
+/// Write keys to a keyring file, replacing it if it exists.
+///
+/// The new file is written next to it then renamed, so the keyring is never
+/// left partially written.
+pub fn write_keyring(path: &Path, keys: &[KeyPair]) -> Result<(), IoError> {
+    let mut temp_name = path.file_name()
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Invalid keyring path"))?
+        .to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let result = (|| {
+        let mut file = create_private(&temp_path)?;
+        file.write_all(&encode_keyring(keys))?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        std::fs::remove_file(&temp_path).ok();
+    }
+    result
+}
+
+/// The active keys, looked up by key ID.
+///
+/// The keys are ordered from oldest to newest, the last one being the current
+/// keys, used for sealing new messages.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    keys: Vec<KeyPair>,
+}
+
+impl Keyring {
+    /// Create a keyring with only the given keys.
+    pub fn new(current: KeyPair) -> Keyring {
+        Keyring { keys: vec![current] }
+    }
+
+    /// Create a keyring from keys ordered from oldest to newest.
+    pub fn from_keys(keys: Vec<KeyPair>) -> Result<Keyring, IoError> {
+        if keys.is_empty() {
+            return Err(invalid("Keyring is empty"));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Read a keyring file.
+    pub fn load(path: &Path) -> Result<Keyring, IoError> {
+        Keyring::from_keys(read_keyring(path)?)
+    }
+
+    /// Write the keyring to a file, replacing it if it exists.
+    pub fn save(&self, path: &Path) -> Result<(), IoError> {
+        write_keyring(path, &self.keys)
+    }
+
+    /// All the keys, from oldest to newest.
+    pub fn keys(&self) -> &[KeyPair] {
+        &self.keys
+    }
+
+    /// The keys used for sealing new messages.
+    pub fn current(&self) -> &KeyPair {
+        self.keys.last().unwrap()
+    }
+
+    /// Find keys by ID.
+    pub fn get(&self, key_id: u32) -> Option<&KeyPair> {
+        self.keys.iter().rev().find(|k| k.key_id() == key_id)
+    }
+
+    /// Generate new current keys, keeping at most `keep_previous` of the
+    /// older ones.
+    pub fn rotate(&mut self, keep_previous: usize) -> &KeyPair {
+        self.keys.push(KeyPair::generate());
+        self.truncate(keep_previous);
+        self.current()
+    }
+
+    /// Add keys from another keyring, such as the master's, ordered from
+    /// oldest to newest.
+    ///
+    /// Keys that are already present are moved to their new place, so the
+    /// last of the given keys becomes the current keys. Returns the number of
+    /// keys that were not present.
+    pub fn add(&mut self, keys: &[KeyPair]) -> usize {
+        let mut added = 0;
+        for key in keys {
+            let len = self.keys.len();
+            self.keys.retain(|k| k != key);
+            if self.keys.len() == len {
+                added += 1;
+            }
+            self.keys.push(key.clone());
+        }
+        added
+    }
+
+    /// Drop the oldest keys, keeping the current ones and at most
+    /// `keep_previous` others.
+    pub fn truncate(&mut self, keep_previous: usize) {
+        let extra = self.keys.len().saturating_sub(keep_previous + 1);
+        self.keys.drain(0..extra);
+    }
+
+    /// Seal a message with the current keys (see `KeyPair::seal()`).
+    pub fn seal(&self, aad: &[u8], data: &[u8], counter: u32) -> (Vec<u8>, u32) {
+        self.current().seal(aad, data, counter)
+    }
+
+    /// Open a message sealed with any of the keys (see `KeyPair::open()`).
+    pub fn open(&self, aad: &[u8], data: &[u8], min_counter: u32) -> Option<(Vec<u8>, u32)> {
+        let key_id = sealed_key_id(data)?;
+        self.get(key_id)?.open(aad, data, min_counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::super::{KeyPair, request_aad};
+    use super::{Keyring, decode_keyring, encode_keyring, read_keyring, write_keyring};
+
+    #[test]
+    fn test_keyring_roundtrip() {
+        let keys = vec![KeyPair::generate(), KeyPair::generate()];
+        let encoded = encode_keyring(&keys);
+        assert_eq!(encoded.len(), 9 + 2 * 32);
+        assert_eq!(decode_keyring(&encoded).unwrap(), keys);
+        assert!(decode_keyring(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode_keyring(b"nope").is_err());
+    }
+
+    #[test]
+    fn test_keyring_file() {
+        let dir = TempDir::new("store_keyring_test").unwrap();
+        let path = dir.path().join("keyring");
+        let keys = vec![KeyPair::generate()];
+        write_keyring(&path, &keys).unwrap();
+        assert_eq!(read_keyring(&path).unwrap(), keys);
+
+        // Replace
+        let keys = vec![KeyPair::generate(), KeyPair::generate()];
+        write_keyring(&path, &keys).unwrap();
+        assert_eq!(read_keyring(&path).unwrap(), keys);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            // Refuse files readable by others
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(read_keyring(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut keyring = Keyring::new(KeyPair::generate());
+        let aad = request_aad("pool", 0x01);
+        let (old_message, _) = keyring.seal(&aad, b"old", 0);
+        let old_key = keyring.current().clone();
+
+        // Messages with the previous keys are still accepted
+        let new_key = keyring.rotate(1).clone();
+        assert!(new_key != old_key);
+        assert_eq!(keyring.keys(), &[old_key.clone(), new_key.clone()]);
+        assert_eq!(keyring.get(old_key.key_id()), Some(&old_key));
+        let (new_message, _) = keyring.seal(&aad, b"new", 0);
+        assert_eq!(new_key.open(&aad, &new_message, 0), Some((b"new".to_vec(), 1)));
+        assert_eq!(keyring.open(&aad, &old_message, 0), Some((b"old".to_vec(), 1)));
+        assert_eq!(keyring.open(&aad, &new_message, 0), Some((b"new".to_vec(), 1)));
+
+        // Until they are rotated out
+        keyring.rotate(1);
+        assert_eq!(keyring.keys().len(), 2);
+        assert_eq!(keyring.get(old_key.key_id()), None);
+        assert_eq!(keyring.open(&aad, &old_message, 0), None);
+        assert_eq!(keyring.open(&aad, &new_message, 0), Some((b"new".to_vec(), 1)));
+
+        assert!(Keyring::from_keys(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_add() {
+        let (key1, key2, key3) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let mut keyring = Keyring::from_keys(vec![key1.clone(), key2.clone()]).unwrap();
+
+        // Keys already present are moved, the last one becomes current
+        assert_eq!(keyring.add(&[key2.clone(), key3.clone()]), 1);
+        assert_eq!(keyring.keys(), &[key1.clone(), key2.clone(), key3.clone()]);
+        assert_eq!(keyring.add(&[key3.clone(), key1.clone()]), 0);
+        assert_eq!(keyring.keys(), &[key2.clone(), key3.clone(), key1.clone()]);
+        assert_eq!(keyring.current(), &key1);
+
+        keyring.truncate(1);
+        assert_eq!(keyring.keys(), &[key3, key1]);
+    }
+}
@@ -0,0 +1,624 @@
+//! Custom crypto for client -> storage messaging.
+//!
+//! This is custom crypto code and I am sorry. It is unfortunate that this is
+//! currently here. I would rather use a third-party solution here, however I
+//! don't want to do multiple roundtrips to send a request.
+//!
+//! This implementation does not establish a channel for each request. The
+//! master shares key material with the storage daemons, and seals the tickets
+//! it gives to clients with it. A client opens a session with each daemon
+//! once using its ticket (see `session`).
+//!
+//! Two formats exist. The original one is AES-CTR with HMAC-SHA256, without
+//! associated data (`KeyPair::encrypt()`). The newer one is AES-128-GCM,
+//! binding the pool and opcode of the request as associated data
+//! (`KeyPair::seal()`). Messages in the newer format start with a version
+//! byte so that the scheme can be changed again later.
+//!
+//! Requests in a session go in an envelope sealed with its keys (see
+//! `envelope`), which keeps the fields needed for routing them readable but
+//! authenticated. Their responses are sealed with the same keys, bound to the
+//! pool and opcode of the request.
+
+pub mod capability;
+pub mod envelope;
+pub mod epoch;
+pub mod keyring;
+pub mod peer;
+pub mod replay;
+pub mod session;
+
+use aes::{Aes128Enc, Block};
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use aes_gcm::Aes128Gcm;
+use aes_gcm::aead::AeadInPlace;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::encode_hex;
+
+/// A secret key, erased from memory when dropped.
+///
+/// Comparisons are done in constant time, and `Debug` doesn't show it.
+#[derive(Clone)]
+pub struct SecretKey([u8; 16]);
+
+impl SecretKey {
+    pub fn new(bytes: [u8; 16]) -> SecretKey {
+        SecretKey(bytes)
+    }
+
+    /// Generate a new key, using the operating system's random generator.
+    pub fn generate() -> SecretKey {
+        let mut key = SecretKey([0; 16]);
+        OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &SecretKey) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+/// A pair of keys: MAC and symmetric encryption.
+///
+/// Currently using HMAC-SHA256 and AES128.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeyPair {
+    pub mac_key: SecretKey,
+    pub encrypt_key: SecretKey,
+}
+
+const SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+
+/// Version byte for messages using AES-128-GCM.
+pub const VERSION_AES_GCM: u8 = 2;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Bytes added to a message by `KeyPair::seal()`: version, key ID, nonce
+/// and tag.
+pub const SEAL_OVERHEAD: usize = 5 + NONCE_SIZE + TAG_SIZE;
+
+/// Get the ID of the key a message was sealed with, without decrypting it.
+pub fn sealed_key_id(data: &[u8]) -> Option<u32> {
+    if data.len() < SEAL_OVERHEAD || data[0] != VERSION_AES_GCM {
+        return None;
+    }
+    Some(Cursor::new(&data[1..5]).read_u32::<BigEndian>().unwrap())
+}
+
+/// Build the associated data for a request, binding it to a pool and opcode.
+pub fn request_aad(pool: &str, opcode: u8) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(5 + pool.len());
+    aad.write_u32::<BigEndian>(pool.len() as u32).unwrap();
+    aad.extend_from_slice(pool.as_bytes());
+    aad.write_u8(opcode).unwrap();
+    aad
+}
+
+/// Number of blocks of keystream computed at once, so that the AES
+/// implementation can process them in parallel.
+const PARALLEL_BLOCKS: usize = 8;
+
+/// XOR data with the keystream, starting at the given counter.
+///
+/// Returns the counter following the last block used.
+fn apply_keystream(cipher: &Aes128Enc, mut counter: u32, data: &mut [u8]) -> u32 {
+    let mut keystream = [Block::default(); PARALLEL_BLOCKS];
+    for chunk in data.chunks_mut(SIZE * PARALLEL_BLOCKS) {
+        let blocks = chunk.len().div_ceil(SIZE);
+        for block in &mut keystream[..blocks] {
+            *block = Block::default();
+            block[0..4].copy_from_slice(&counter.to_le_bytes());
+            counter += 1;
+        }
+        cipher.encrypt_blocks(&mut keystream[..blocks]);
+        for (data, key) in chunk.chunks_mut(SIZE).zip(&keystream) {
+            xor_block(data, key);
+        }
+    }
+    counter
+}
+
+/// Name of the AES implementation in use.
+///
+/// The `aes` crate picks AES-NI at runtime when the CPU supports it, and
+/// falls back to a constant-time software implementation otherwise.
+pub fn aes_implementation() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if std::is_x86_feature_detected!("aes") && std::is_x86_feature_detected!("sse2") {
+            return "AES-NI";
+        }
+    }
+    "software"
+}
+
+fn xor_block(a: &mut [u8], b: &[u8]) {
+    for (a1, b1) in a.into_iter().zip(b) {
+        *a1 ^= b1;
+    }
+}
+
+/// Size of a serialized `KeyPair`.
+pub const KEY_PAIR_SIZE: usize = 32;
+
+impl KeyPair {
+    /// Generate new keys, using the operating system's random generator.
+    pub fn generate() -> KeyPair {
+        KeyPair {
+            mac_key: SecretKey::generate(),
+            encrypt_key: SecretKey::generate(),
+        }
+    }
+
+    /// Serialize the keys, in a buffer that is erased when dropped.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; KEY_PAIR_SIZE]> {
+        let mut bytes = Zeroizing::new([0; KEY_PAIR_SIZE]);
+        bytes[0..16].copy_from_slice(self.mac_key.as_bytes());
+        bytes[16..32].copy_from_slice(self.encrypt_key.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; KEY_PAIR_SIZE]) -> KeyPair {
+        let mut key_pair = KeyPair {
+            mac_key: SecretKey([0; 16]),
+            encrypt_key: SecretKey([0; 16]),
+        };
+        key_pair.mac_key.0.copy_from_slice(&bytes[0..16]);
+        key_pair.encrypt_key.0.copy_from_slice(&bytes[16..32]);
+        key_pair
+    }
+
+    /// A short identifier for the keys, that doesn't reveal them.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(&self.to_bytes()[..]);
+        encode_hex(&digest[0..8])
+    }
+
+    /// The identifier sent with sealed messages, so the receiver can find
+    /// the right keys.
+    ///
+    /// This is the start of the fingerprint.
+    pub fn key_id(&self) -> u32 {
+        let digest = Sha256::digest(&self.to_bytes()[..]);
+        Cursor::new(&digest[0..4]).read_u32::<BigEndian>().unwrap()
+    }
+
+    /// Encrypt and authenticate some data.
+    ///
+    /// The function takes the current counter value, and returns the new
+    /// value. That counter is used to prevent replay attacks; messages will be
+    /// rejected if it ever goes down.
+    pub fn encrypt(&self, data: &[u8], counter: u32) -> (Vec<u8>, u32) {
+        let mut result = Vec::new();
+        let counter = self.encrypt_into(data, &mut result, counter);
+        (result, counter)
+    }
+
+    /// Encrypt and authenticate some data.
+    ///
+    /// The function takes the current counter value, and returns the new
+    /// value. That counter is used to prevent replay attacks; messages will be
+    /// rejected if it ever goes down.
+    pub fn encrypt_into(&self, data: &[u8], result: &mut Vec<u8>, counter: u32) -> u32 {
+        result.clear();
+
+        // Initialize cipher
+        let cipher = Aes128Enc::new(GenericArray::from_slice(self.encrypt_key.as_bytes()));
+
+        // Write initial counter
+        result.write_u32::<BigEndian>(counter).unwrap();
+
+        // Write length and data, padded to whole blocks
+        let blocks = (4 + data.len()).div_ceil(SIZE);
+        result.reserve(blocks * SIZE + MAC_SIZE);
+        result.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        result.extend_from_slice(data);
+        result.resize(4 + blocks * SIZE, 0);
+
+        // Encrypt in place
+        let counter = apply_keystream(&cipher, counter, &mut result[4..]);
+
+        // Now add message digest
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.mac_key.as_bytes()).unwrap();
+        mac.update(&result);
+        let mac: [u8; MAC_SIZE] = mac.finalize().into_bytes().into();
+        result.extend_from_slice(&mac);
+
+        counter
+    }
+
+    /// Authenticate and decrypt some data.
+    ///
+    /// The function takes the current counter value, and returns the new
+    /// value. That counter is used to prevent replay attacks; if the message
+    /// countains a counter too low, it will be rejected.
+    pub fn decrypt(&self, data: &[u8], min_counter: u32) -> Option<(Vec<u8>, u32)> {
+        let mut result = Vec::new();
+        let counter = self.decrypt_into(data, &mut result, min_counter);
+        counter.map(|c| (result, c))
+    }
+
+    /// Authenticate and decrypt some data.
+    ///
+    /// The function takes the current counter value, and returns the new
+    /// value. That counter is used to prevent replay attacks; if the message
+    /// countains a counter too low, it will be rejected.
+    pub fn decrypt_into(&self, data: &[u8], result: &mut Vec<u8>, min_counter: u32) -> Option<u32> {
+        result.clear();
+
+        if data.len() < 4 + SIZE + MAC_SIZE {
+            warn!("decrypt: missing MAC (size={})", data.len());
+            return None;
+        }
+        if data.len() % SIZE != (4 + MAC_SIZE) % SIZE {
+            warn!("decrypt: wrong size ({})", data.len());
+            return None;
+        }
+
+        // Check MAC
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.mac_key.as_bytes()).unwrap();
+        mac.update(&data[0..data.len() - MAC_SIZE]);
+        match mac.verify_slice(&data[data.len() - MAC_SIZE..]) {
+            Ok(()) => {}
+            Err(_) => {
+                warn!("Invalid MAC");
+                return None;
+            }
+        }
+
+        // Read counter, which has to last for all the blocks
+        let counter = Cursor::new(&data).read_u32::<BigEndian>().unwrap();
+        let blocks = (data.len() - 4 - MAC_SIZE) / SIZE;
+        if counter < min_counter || counter.checked_add(blocks as u32).is_none() {
+            warn!("Invalid counter");
+            return None;
+        }
+
+        // Initialize cipher
+        let cipher = Aes128Enc::new(GenericArray::from_slice(self.encrypt_key.as_bytes()));
+
+        // Decrypt in place
+        result.extend_from_slice(&data[4..data.len() - MAC_SIZE]);
+        let counter = apply_keystream(&cipher, counter, result);
+
+        // Read total length, which has to match the number of blocks
+        let length = Cursor::new(&result[..]).read_u32::<BigEndian>().unwrap() as usize;
+        if length > result.len() - 4 || (4 + length).div_ceil(SIZE) != result.len() / SIZE {
+            warn!("Invalid size left over");
+            result.clear();
+            return None;
+        }
+        result.copy_within(4..4 + length, 0);
+        result.truncate(length);
+
+        Some(counter)
+    }
+
+    /// Encrypt and authenticate some data with AES-128-GCM, also
+    /// authenticating the associated data (see `request_aad()`).
+    ///
+    /// The nonce is made of the counter followed by random bytes, since the
+    /// same keys are shared by many clients. The function takes the current
+    /// counter value and returns the new value, which is used by the receiver
+    /// to reject replayed messages.
+    pub fn seal(&self, aad: &[u8], data: &[u8], counter: u32) -> (Vec<u8>, u32) {
+        let mut result = Vec::new();
+        let counter = self.seal_into(aad, data, &mut result, counter);
+        (result, counter)
+    }
+
+    /// Encrypt and authenticate some data with AES-128-GCM, into the given
+    /// buffer (see `seal()`).
+    pub fn seal_into(&self, aad: &[u8], data: &[u8], result: &mut Vec<u8>, counter: u32) -> u32 {
+        result.clear();
+        result.reserve(SEAL_OVERHEAD + data.len());
+        result.write_u8(VERSION_AES_GCM).unwrap();
+        result.write_u32::<BigEndian>(self.key_id()).unwrap();
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        Cursor::new(&mut nonce[..]).write_u32::<BigEndian>(counter).unwrap();
+        OsRng.fill_bytes(&mut nonce[4..]);
+        result.extend_from_slice(&nonce);
+
+        // Encrypt in place
+        result.extend_from_slice(data);
+        let cipher = Aes128Gcm::new(GenericArray::from_slice(self.encrypt_key.as_bytes()));
+        let tag = cipher.encrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            aad,
+            &mut result[5 + NONCE_SIZE..],
+        ).expect("Message too long");
+        result.extend_from_slice(&tag);
+        counter + 1
+    }
+
+    /// Authenticate and decrypt data sealed with `seal()`.
+    ///
+    /// The associated data has to match what the sender used. If the message
+    /// contains a counter lower than `min_counter`, it is rejected; otherwise
+    /// the new counter value is returned along with the plaintext.
+    pub fn open(&self, aad: &[u8], data: &[u8], min_counter: u32) -> Option<(Vec<u8>, u32)> {
+        let mut result = Vec::new();
+        let counter = self.open_into(aad, data, &mut result, min_counter);
+        counter.map(|c| (result, c))
+    }
+
+    /// Authenticate and decrypt data sealed with `seal()`, into the given
+    /// buffer (see `open()`).
+    pub fn open_into(&self, aad: &[u8], data: &[u8], result: &mut Vec<u8>, min_counter: u32) -> Option<u32> {
+        result.clear();
+
+        if data.len() < SEAL_OVERHEAD {
+            warn!("open: message too short (size={})", data.len());
+            return None;
+        }
+        if data[0] != VERSION_AES_GCM {
+            warn!("open: unknown version {}", data[0]);
+            return None;
+        }
+        if sealed_key_id(data) != Some(self.key_id()) {
+            warn!("open: wrong key ID");
+            return None;
+        }
+        let nonce = &data[5..5 + NONCE_SIZE];
+
+        // Read counter
+        let counter = Cursor::new(nonce).read_u32::<BigEndian>().unwrap();
+        if counter < min_counter || counter == u32::MAX {
+            warn!("Invalid counter");
+            return None;
+        }
+
+        // Decrypt in place
+        let tag = &data[data.len() - TAG_SIZE..];
+        result.extend_from_slice(&data[5 + NONCE_SIZE..data.len() - TAG_SIZE]);
+        let cipher = Aes128Gcm::new(GenericArray::from_slice(self.encrypt_key.as_bytes()));
+        match cipher.decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            aad,
+            result,
+            GenericArray::from_slice(tag),
+        ) {
+            Ok(()) => Some(counter + 1),
+            Err(_) => {
+                warn!("Invalid tag");
+                result.clear();
+                None
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "KeyPair({})", self.fingerprint())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{KeyPair, MAC_SIZE, SEAL_OVERHEAD, SIZE, SecretKey, request_aad, sealed_key_id};
+
+    #[test]
+    fn test_generate() {
+        let key1 = KeyPair::generate();
+        let key2 = KeyPair::generate();
+        assert!(key1 != key2);
+        assert!(key1.mac_key != key1.encrypt_key);
+        assert_eq!(KeyPair::from_bytes(&key1.to_bytes()), key1);
+        assert_eq!(key1.fingerprint().len(), 16);
+        assert_eq!(format!("{:?}", key1.mac_key), "SecretKey(..)");
+        assert_eq!(SecretKey::new([3; 16]), SecretKey::new([3; 16]));
+
+        // Keys are usable
+        let (ciphertext, counter) = key1.encrypt(b"hello", 0);
+        assert_eq!(key1.decrypt(&ciphertext, 0), Some((b"hello".to_vec(), counter)));
+        assert_eq!(key2.decrypt(&ciphertext, 0), None);
+    }
+
+    #[test]
+    fn test_seal() {
+        let key_pair = KeyPair::generate();
+        let aad = request_aad("pool", 0x03);
+        let (sealed, counter) = key_pair.seal(&aad, b"some data", 7);
+        assert_eq!(counter, 8);
+        assert_eq!(sealed.len(), SEAL_OVERHEAD + 9);
+        assert_eq!(sealed[0], 2);
+        assert_eq!(sealed_key_id(&sealed), Some(key_pair.key_id()));
+        assert_eq!(&sealed[5..9], &[0, 0, 0, 7]);
+
+        // Same counter and data still gives a different message
+        let (sealed2, _) = key_pair.seal(&aad, b"some data", 7);
+        assert!(sealed != sealed2);
+
+        assert_eq!(key_pair.open(&aad, &sealed, 7), Some((b"some data".to_vec(), 8)));
+
+        // Replay
+        assert_eq!(key_pair.open(&aad, &sealed, 8), None);
+
+        // Different pool or opcode
+        assert_eq!(key_pair.open(&request_aad("other", 0x03), &sealed, 0), None);
+        assert_eq!(key_pair.open(&request_aad("pool", 0x05), &sealed, 0), None);
+
+        // Tampering
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x10;
+            assert_eq!(key_pair.open(&aad, &tampered, 0), None);
+        }
+        assert_eq!(key_pair.open(&aad, &sealed[..sealed.len() - 1], 0), None);
+
+        // Wrong key
+        assert_eq!(KeyPair::generate().open(&aad, &sealed, 0), None);
+    }
+
+    #[test]
+    fn test_into() {
+        let key_pair = KeyPair::generate();
+        let aad = request_aad("pool", 0x03);
+        let mut buffer = vec![0xff; 1000];
+        let mut plaintext = vec![0xff; 1000];
+
+        // Sizes around the blocks processed at once
+        for size in [0, 1, 12, 13, 127, 128, 129, 300] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+            let counter = key_pair.encrypt_into(&data, &mut buffer, 5);
+            assert_eq!((buffer.clone(), counter), key_pair.encrypt(&data, 5));
+            assert_eq!(key_pair.decrypt_into(&buffer, &mut plaintext, 5), Some(counter));
+            assert_eq!(plaintext, data);
+
+            assert_eq!(key_pair.seal_into(&aad, &data, &mut buffer, 5), 6);
+            assert_eq!(buffer.len(), SEAL_OVERHEAD + size);
+            assert_eq!(key_pair.open_into(&aad, &buffer, &mut plaintext, 5), Some(6));
+            assert_eq!(plaintext, data);
+            assert_eq!(key_pair.open_into(&aad, &buffer, &mut plaintext, 6), None);
+        }
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let key_pair = KeyPair::generate();
+        let (ciphertext, counter) = key_pair.encrypt(b"", u32::MAX - 1);
+        assert_eq!(key_pair.decrypt(&ciphertext, 0), Some((vec![], counter)));
+
+        // A message whose blocks would take the counter past the end, even
+        // with a valid MAC, is rejected
+        let (mut ciphertext, _) = key_pair.encrypt(b"two blocks of data", 5);
+        ciphertext.truncate(ciphertext.len() - MAC_SIZE);
+        ciphertext[0..4].copy_from_slice(&(u32::MAX - 1).to_be_bytes());
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key_pair.mac_key.as_bytes()).unwrap();
+        mac.update(&ciphertext);
+        ciphertext.extend_from_slice(&mac.finalize().into_bytes());
+        assert_eq!(key_pair.decrypt(&ciphertext, 0), None);
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let message = b"\
+            Lorem ipsum dolor sit amet, consectetur adipiscing elit. Maecenas \
+            est purus, sagittis eu cursus sed, ullamcorper sed nibh. Mauris \
+            quis aliquam leo. Integer porttitor sapien orci, sed semper ex \
+            elementum maximus.";
+        assert_eq!(message.len(), 211);
+        let key_pair = KeyPair {
+            mac_key: SecretKey::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]),
+            encrypt_key: SecretKey::new([2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32]),
+        };
+        let (result, counter) = key_pair.encrypt(message, 4);
+
+        // Counter should increase by 14
+        assert_eq!(counter, 18);
+
+        // Result should be 14 blocks + counter + digest
+        assert_eq!(result.len(), 4 + 14 * SIZE + MAC_SIZE);
+
+        let mut expected = Vec::new();
+        // Initial counter (4)
+        expected.extend_from_slice(&[0, 0, 0, 4]);
+        // Encrypted data
+        expected.extend_from_slice(b"\
+            \x6c\x25\xf2\x89\x66\xb2\x4b\x30\x72\x96\xf5\xb6\x76\xdc\x76\x41\
+            \x16\xda\x5a\x77\x54\xee\xc3\x2c\x59\x09\xe4\x2f\x7c\x95\x4e\xf0\
+            \xe5\xa7\xbc\xed\x59\x42\xdb\x7c\xcf\x63\x6a\x01\x98\x18\x73\xce\
+            \x69\x36\x8c\x4a\xb5\x7c\xe3\xfb\x8d\xc6\x78\x68\x3b\x4a\x18\xde\
+            \x82\x16\x2d\x5a\x38\xb9\xa4\x13\x17\x68\xf7\x16\xe0\x12\x7b\x60\
+            \xde\x82\x8a\x0c\x31\x58\x19\x8e\x62\xa8\xa8\xc6\x4b\x72\xb1\xbb\
+            \xf8\x77\xff\xcf\xa2\xf7\xa1\x21\xb7\xa5\x8e\x64\x8b\x5f\xe5\x6b\
+            \x49\xf9\x14\xc8\xb5\x4d\x6e\x1a\x87\xb6\x27\x65\xf6\x8c\xfe\x33\
+            \xc9\x4a\x25\xeb\x9b\x15\xc5\xb8\x6b\xd0\x1f\x60\xc2\x84\x33\x4b\
+            \xd3\x43\xbb\x76\xda\x05\x53\xb2\x3c\x0f\x6f\x4c\x34\x7c\x4c\xbd\
+            \x57\x90\x60\xf7\xbe\x1f\x0f\xa4\x7d\xc4\xb2\x5d\x88\x59\x37\x60\
+            \x4e\x11\x9f\x0e\x77\xbf\x1f\xb1\x5a\xc9\xed\x3f\xde\xdc\xf4\x07\
+            \x6c\xec\xbd\xa9\xe8\x7d\x8f\xfe\x81\x78\xa4\xdf\x4a\xc9\x6d\x49\
+            \xdc\x15\x11\x95\x68\x40\xde\x9b\x6e\xe9\x1b\xc2\xda\xe4\x74\x2b",
+        );
+        // MAC
+        expected.extend_from_slice(
+            b"\
+          \xf5\x4d\x3c\xa0\x76\x5d\xef\xab\x12\x5b\xe1\x6f\x62\x6b\x85\x20\
+          \x82\x50\xc5\x55\x89\xe4\x13\xc0\x86\x1a\x8c\xf4\x2d\xa7\x3f\xd4");
+        assert_eq!(
+            result,
+            expected,
+        );
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let key_pair = KeyPair {
+            mac_key: SecretKey::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]),
+            encrypt_key: SecretKey::new([2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32]),
+        };
+
+        let mut ciphertext = Vec::new();
+        // Initial counter (4)
+        ciphertext.extend_from_slice(&[0, 0, 0, 4]);
+        // Encrypted data
+        ciphertext.extend_from_slice(b"\
+            \x6c\x25\xf2\x89\x66\xb2\x4b\x30\x72\x96\xf5\xb6\x76\xdc\x76\x41\
+            \x16\xda\x5a\x77\x54\xee\xc3\x2c\x59\x09\xe4\x2f\x7c\x95\x4e\xf0\
+            \xe5\xa7\xbc\xed\x59\x42\xdb\x7c\xcf\x63\x6a\x01\x98\x18\x73\xce\
+            \x69\x36\x8c\x4a\xb5\x7c\xe3\xfb\x8d\xc6\x78\x68\x3b\x4a\x18\xde\
+            \x82\x16\x2d\x5a\x38\xb9\xa4\x13\x17\x68\xf7\x16\xe0\x12\x7b\x60\
+            \xde\x82\x8a\x0c\x31\x58\x19\x8e\x62\xa8\xa8\xc6\x4b\x72\xb1\xbb\
+            \xf8\x77\xff\xcf\xa2\xf7\xa1\x21\xb7\xa5\x8e\x64\x8b\x5f\xe5\x6b\
+            \x49\xf9\x14\xc8\xb5\x4d\x6e\x1a\x87\xb6\x27\x65\xf6\x8c\xfe\x33\
+            \xc9\x4a\x25\xeb\x9b\x15\xc5\xb8\x6b\xd0\x1f\x60\xc2\x84\x33\x4b\
+            \xd3\x43\xbb\x76\xda\x05\x53\xb2\x3c\x0f\x6f\x4c\x34\x7c\x4c\xbd\
+            \x57\x90\x60\xf7\xbe\x1f\x0f\xa4\x7d\xc4\xb2\x5d\x88\x59\x37\x60\
+            \x4e\x11\x9f\x0e\x77\xbf\x1f\xb1\x5a\xc9\xed\x3f\xde\xdc\xf4\x07\
+            \x6c\xec\xbd\xa9\xe8\x7d\x8f\xfe\x81\x78\xa4\xdf\x4a\xc9\x6d\x49\
+            \xdc\x15\x11\x95\x68\x40\xde\x9b\x6e\xe9\x1b\xc2\xda\xe4\x74\x2b");
+        // MAC
+        ciphertext.extend_from_slice(b"\
+          \xf5\x4d\x3c\xa0\x76\x5d\xef\xab\x12\x5b\xe1\x6f\x62\x6b\x85\x20\
+          \x82\x50\xc5\x55\x89\xe4\x13\xc0\x86\x1a\x8c\xf4\x2d\xa7\x3f\xd4");
+
+        let (result, counter) = key_pair.decrypt(&ciphertext, 3).unwrap();
+        assert_eq!(counter, 18);
+
+        let message = b"\
+            Lorem ipsum dolor sit amet, consectetur adipiscing elit. Maecenas \
+            est purus, sagittis eu cursus sed, ullamcorper sed nibh. Mauris \
+            quis aliquam leo. Integer porttitor sapien orci, sed semper ex \
+            elementum maximus.";
+        assert_eq!(result, message);
+    }
+}
@@ -0,0 +1,64 @@
+//! Sealing the messages exchanged between storage daemons.
+//!
+//! Daemons forward requests to each other and replicate the objects they
+//! hold, including the data being written, so this traffic is sealed with the
+//! keys they share with the master.
+
+use super::keyring::Keyring;
+
+/// A request forwarded to another daemon.
+pub const PEER_REQUEST: u8 = 0x01;
+/// The response to a forwarded or replicated request.
+pub const PEER_RESPONSE: u8 = 0x02;
+/// A change to an object, sent by its primary to the daemons holding a
+/// replica.
+pub const PEER_REPLICATE: u8 = 0x03;
+
+/// Associated data for messages between daemons.
+const PEER_AAD: &[u8] = b"store peer";
+
+/// Seal a message to another daemon.
+///
+/// `counter` goes in the nonce, the sender keeps one for each daemon it
+/// sends to and uses each value once. Replays are detected using the counter
+/// and epoch of the message itself, like for client requests.
+pub fn seal_peer_message(keyring: &Keyring, kind: u8, msg: &[u8], counter: u32) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(1 + msg.len());
+    plaintext.push(kind);
+    plaintext.extend_from_slice(msg);
+    keyring.seal(PEER_AAD, &plaintext, counter).0
+}
+
+/// Open a message from another daemon, returning its kind and content.
+pub fn open_peer_message(keyring: &Keyring, data: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let (mut plaintext, _) = keyring.open(PEER_AAD, data, 0)?;
+    if plaintext.is_empty() {
+        return None;
+    }
+    let kind = plaintext.remove(0);
+    Some((kind, plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::KeyPair;
+    use super::super::keyring::Keyring;
+    use super::{PEER_AAD, PEER_REQUEST, open_peer_message, seal_peer_message};
+
+    #[test]
+    fn test_peer_message() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let payload = b"replicated object data, which must not be readable";
+        let sealed = seal_peer_message(&keyring, PEER_REQUEST, payload, 5);
+        assert!(!sealed.windows(8).any(|w| payload.windows(8).any(|p| p == w)));
+        assert_eq!(
+            open_peer_message(&keyring, &sealed),
+            Some((PEER_REQUEST, payload.to_vec())),
+        );
+        assert_eq!(open_peer_message(&Keyring::new(KeyPair::generate()), &sealed), None);
+
+        // The counter is in the nonce
+        assert_eq!(keyring.open(PEER_AAD, &sealed, 5).map(|(_, next)| next), Some(6));
+        assert_eq!(keyring.open(PEER_AAD, &sealed, 6), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Protection against replayed messages.
+//!
+//! This is the sliding window from RFC 4303 (section 3.4.3): the highest
+//! counter seen is remembered along with a bitmap of the counters just below
+//! it, so that messages arriving late or out of order are still accepted, but
+//! only once.
+//!
+//! Counters are scoped by an epoch (see `super::epoch`), so that a sender
+//! can restart from zero after a restart.
+
+/// Number of counters below the highest one that are tracked.
+pub const WINDOW_SIZE: u32 = 64;
+
+/// Sliding window of the counters that were already received.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    /// Epoch of the sender, counters from older epochs are rejected.
+    epoch: u32,
+    /// Highest counter received, `None` if nothing was received yet.
+    highest: Option<u32>,
+    /// Bit `n` is set if `highest - n` was received.
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        Default::default()
+    }
+
+    /// Check whether a counter would be accepted, without recording it.
+    ///
+    /// This should be used before authenticating a message, and `update()`
+    /// after, so that forged messages can't move the window.
+    pub fn check(&self, epoch: u32, counter: u32) -> bool {
+        if epoch != self.epoch {
+            return epoch > self.epoch || self.highest.is_none();
+        }
+        let highest = match self.highest {
+            None => return true,
+            Some(h) => h,
+        };
+        if counter > highest {
+            return true;
+        }
+        let offset = highest - counter;
+        offset < WINDOW_SIZE && self.bitmap & (1 << offset) == 0
+    }
+
+    /// Record a counter as received.
+    ///
+    /// If the epoch is higher than before, the window is reset.
+    pub fn update(&mut self, epoch: u32, counter: u32) {
+        if epoch != self.epoch {
+            if epoch < self.epoch && self.highest.is_some() {
+                return;
+            }
+            self.epoch = epoch;
+            self.highest = None;
+        }
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                let offset = highest - counter;
+                if offset < WINDOW_SIZE {
+                    self.bitmap |= 1 << offset;
+                }
+            }
+            Some(highest) => {
+                let shift = counter - highest;
+                self.bitmap = if shift < WINDOW_SIZE { self.bitmap << shift } else { 0 };
+                self.bitmap |= 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.bitmap = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+
+    /// Check a counter and record it if it is accepted.
+    pub fn accept(&mut self, epoch: u32, counter: u32) -> bool {
+        if self.check(epoch, counter) {
+            self.update(epoch, counter);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayWindow, WINDOW_SIZE};
+
+    #[test]
+    fn test_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(1, 10));
+        assert!(!window.accept(1, 10));
+
+        // Out of order
+        assert!(window.accept(1, 12));
+        assert!(window.accept(1, 11));
+        assert!(!window.accept(1, 11));
+        assert!(!window.accept(1, 12));
+
+        // Below the first counter, but in the window
+        assert!(window.accept(1, 5));
+        assert!(!window.accept(1, 5));
+
+        // Check doesn't record
+        assert!(window.check(1, 13));
+        assert!(window.check(1, 13));
+        assert!(window.accept(1, 13));
+        assert!(!window.check(1, 13));
+
+        // Too old
+        assert!(window.accept(1, 13 + WINDOW_SIZE - 1));
+        assert!(!window.check(1, 13));
+        assert!(window.accept(1, 14));
+        assert!(window.accept(1, 100));
+        assert!(!window.accept(1, 100 - WINDOW_SIZE));
+        assert!(window.accept(1, 100 - WINDOW_SIZE + 1));
+
+        // Large jump
+        assert!(window.accept(1, 1000));
+        assert!(window.accept(1, 999));
+        assert!(!window.accept(1, 100));
+    }
+
+    #[test]
+    fn test_epochs() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(5, 100));
+        assert!(window.accept(5, 101));
+
+        // Sender restarted
+        assert!(window.accept(6, 0));
+        assert!(window.accept(6, 1));
+        assert!(!window.accept(6, 0));
+
+        // Old epoch
+        assert!(!window.check(5, 102));
+        window.update(5, 102);
+        assert!(window.accept(6, 2));
+    }
+}
@@ -0,0 +1,273 @@
+//! Establishing sessions between clients and storage daemons.
+//!
+//! The master issues tickets to clients: a ticket is a random ticket key and
+//! an expiration time, sealed with the keys shared by the master and the
+//! storage daemons. The client keeps the ticket key and presents the sealed
+//! ticket to a daemon along with a random value; the daemon answers with its
+//! own random value, and both sides derive the session keys from the ticket
+//! key and the two random values using HKDF.
+//!
+//! This way the shared keys only ever seal tickets, and every session uses
+//! different keys.
+//!
+//! The handshake goes in a request with `OPCODE_HANDSHAKE`, and sessions end
+//! when their ticket expires.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hkdf::Hkdf;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::io::{Cursor, Error as IoError, ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use super::{KEY_PAIR_SIZE, KeyPair, SecretKey};
+use super::keyring::Keyring;
+
+const TICKET_KEY_SIZE: usize = 16;
+const RANDOM_SIZE: usize = 16;
+
+/// Associated data for sealed tickets, so they can't be confused with
+/// requests.
+const TICKET_AAD: &[u8] = b"store ticket";
+
+/// Context for the key derivation.
+const SESSION_INFO: &[u8] = b"store session keys";
+
+fn invalid(msg: &'static str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn derive_keys(ticket_key: &SecretKey, client_random: &[u8; RANDOM_SIZE], server_random: &[u8; RANDOM_SIZE]) -> KeyPair {
+    let mut salt = [0; 2 * RANDOM_SIZE];
+    salt[..RANDOM_SIZE].copy_from_slice(client_random);
+    salt[RANDOM_SIZE..].copy_from_slice(server_random);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), ticket_key.as_bytes());
+    let mut okm = Zeroizing::new([0; KEY_PAIR_SIZE]);
+    hkdf.expand(SESSION_INFO, &mut okm[..]).unwrap();
+    KeyPair::from_bytes(&okm)
+}
+
+fn to_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs)).unwrap_or(UNIX_EPOCH)
+}
+
+/// An established session.
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// Identifier picked by the daemon, sent with requests.
+    pub id: u32,
+    /// Keys for this session only.
+    pub keys: KeyPair,
+    /// When the ticket the session was opened with expires, and the session
+    /// with it.
+    pub expires: SystemTime,
+}
+
+/// A ticket issued by the master to a client.
+#[derive(Clone)]
+pub struct Ticket {
+    /// The sealed ticket, opaque to the client.
+    pub sealed: Vec<u8>,
+    /// The ticket key, which the client must keep secret.
+    pub ticket_key: SecretKey,
+    /// When the daemons stop accepting the ticket.
+    pub expires: SystemTime,
+}
+
+impl std::fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Ticket({} bytes)", self.sealed.len())
+    }
+}
+
+/// Issue a new ticket, valid for the given duration.
+pub fn issue_ticket(keyring: &Keyring, lifetime: Duration) -> Ticket {
+    let ticket_key = SecretKey::generate();
+    let expires = now() + lifetime.as_secs();
+
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(8 + TICKET_KEY_SIZE));
+    plaintext.write_u64::<BigEndian>(expires).unwrap();
+    plaintext.extend_from_slice(ticket_key.as_bytes());
+    let (sealed, _) = keyring.seal(TICKET_AAD, &plaintext, 0);
+
+    Ticket { sealed, ticket_key, expires: to_time(expires) }
+}
+
+/// The client side of a handshake in progress.
+pub struct ClientHandshake {
+    ticket_key: SecretKey,
+    client_random: [u8; RANDOM_SIZE],
+    expires: SystemTime,
+}
+
+impl Ticket {
+    /// Serialize the ticket with its key, to hand it to a client.
+    ///
+    /// Format: expiration time (u64, seconds since the epoch), ticket key,
+    /// then the sealed ticket.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(8 + TICKET_KEY_SIZE + self.sealed.len()));
+        let expires = self.expires.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        bytes.write_u64::<BigEndian>(expires).unwrap();
+        bytes.extend_from_slice(self.ticket_key.as_bytes());
+        bytes.extend_from_slice(&self.sealed);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Ticket, IoError> {
+        if bytes.len() <= 8 + TICKET_KEY_SIZE {
+            return Err(invalid("Invalid ticket"));
+        }
+        let expires = Cursor::new(bytes).read_u64::<BigEndian>()?;
+        let mut ticket_key = SecretKey::new([0; TICKET_KEY_SIZE]);
+        ticket_key.0.copy_from_slice(&bytes[8..8 + TICKET_KEY_SIZE]);
+        Ok(Ticket {
+            sealed: bytes[8 + TICKET_KEY_SIZE..].to_vec(),
+            ticket_key,
+            expires: to_time(expires),
+        })
+    }
+
+    /// Start a session, returning the message to send to the daemon.
+    pub fn start_session(&self) -> (ClientHandshake, Vec<u8>) {
+        let mut client_random = [0; RANDOM_SIZE];
+        OsRng.fill_bytes(&mut client_random);
+
+        let mut hello = Vec::with_capacity(2 + self.sealed.len() + RANDOM_SIZE);
+        hello.write_u16::<BigEndian>(self.sealed.len() as u16).unwrap();
+        hello.extend_from_slice(&self.sealed);
+        hello.extend_from_slice(&client_random);
+
+        let handshake = ClientHandshake {
+            ticket_key: self.ticket_key.clone(),
+            client_random,
+            expires: self.expires,
+        };
+        (handshake, hello)
+    }
+}
+
+impl ClientHandshake {
+    /// Finish the handshake with the daemon's answer.
+    pub fn finish(self, answer: &[u8]) -> Result<Session, IoError> {
+        if answer.len() != 4 + RANDOM_SIZE {
+            return Err(invalid("Invalid session answer"));
+        }
+        let mut reader = Cursor::new(answer);
+        let id = reader.read_u32::<BigEndian>()?;
+        let mut server_random = [0; RANDOM_SIZE];
+        reader.read_exact(&mut server_random)?;
+        Ok(Session {
+            id,
+            keys: derive_keys(&self.ticket_key, &self.client_random, &server_random),
+            expires: self.expires,
+        })
+    }
+}
+
+/// Accept a session on the daemon side, returning the answer to send back.
+pub fn accept_session(keyring: &Keyring, hello: &[u8]) -> Result<(Vec<u8>, Session), IoError> {
+    let mut reader = Cursor::new(hello);
+    let ticket_len = reader.read_u16::<BigEndian>()? as usize;
+    if hello.len() != 2 + ticket_len + RANDOM_SIZE {
+        return Err(invalid("Invalid session request"));
+    }
+    let sealed = &hello[2..2 + ticket_len];
+    let mut client_random = [0; RANDOM_SIZE];
+    client_random.copy_from_slice(&hello[2 + ticket_len..]);
+
+    // Open the ticket
+    let ticket = keyring.open(TICKET_AAD, sealed, 0)
+        .map(|(plaintext, _)| Zeroizing::new(plaintext))
+        .ok_or_else(|| invalid("Invalid ticket"))?;
+    if ticket.len() != 8 + TICKET_KEY_SIZE {
+        return Err(invalid("Invalid ticket"));
+    }
+    let expires = Cursor::new(&ticket[..]).read_u64::<BigEndian>()?;
+    if expires < now() {
+        return Err(invalid("Expired ticket"));
+    }
+    let mut ticket_key = SecretKey::new([0; TICKET_KEY_SIZE]);
+    ticket_key.0.copy_from_slice(&ticket[8..]);
+
+    // Derive the keys
+    let mut server_random = [0; RANDOM_SIZE];
+    OsRng.fill_bytes(&mut server_random);
+    let session = Session {
+        id: OsRng.next_u32(),
+        keys: derive_keys(&ticket_key, &client_random, &server_random),
+        expires: to_time(expires),
+    };
+
+    let mut answer = Vec::with_capacity(4 + RANDOM_SIZE);
+    answer.write_u32::<BigEndian>(session.id).unwrap();
+    answer.extend_from_slice(&server_random);
+    Ok((answer, session))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::KeyPair;
+    use super::super::keyring::Keyring;
+    use super::{Ticket, accept_session, issue_ticket};
+
+    #[test]
+    fn test_handshake() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let ticket = issue_ticket(&keyring, Duration::from_secs(60));
+
+        let (handshake, hello) = ticket.start_session();
+        let (answer, daemon_session) = accept_session(&keyring, &hello).unwrap();
+        let client_session = handshake.finish(&answer).unwrap();
+        assert_eq!(client_session.id, daemon_session.id);
+        assert_eq!(client_session.keys, daemon_session.keys);
+        assert_eq!(client_session.expires, ticket.expires);
+        assert_eq!(daemon_session.expires, ticket.expires);
+        assert!(client_session.keys != *keyring.current());
+
+        // Another session with the same ticket gets other keys
+        let (handshake, hello) = ticket.start_session();
+        let (answer, _) = accept_session(&keyring, &hello).unwrap();
+        let other_session = handshake.finish(&answer).unwrap();
+        assert!(other_session.keys != client_session.keys);
+
+        // Tickets from other masters are refused
+        let other_keyring = Keyring::new(KeyPair::generate());
+        let (_, hello) = ticket.start_session();
+        assert!(accept_session(&other_keyring, &hello).is_err());
+
+        // Tampered hello
+        let mut tampered = hello.clone();
+        tampered[10] ^= 1;
+        assert!(accept_session(&keyring, &tampered).is_err());
+        assert!(accept_session(&keyring, &hello[..hello.len() - 1]).is_err());
+
+        // Tickets handed to clients work the same
+        let copy = Ticket::from_bytes(&ticket.to_bytes()).unwrap();
+        assert_eq!((&copy.sealed, &copy.ticket_key, copy.expires), (&ticket.sealed, &ticket.ticket_key, ticket.expires));
+        assert!(Ticket::from_bytes(&ticket.to_bytes()[..24]).is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let mut ticket = issue_ticket(&keyring, Duration::from_secs(0));
+        // Reseal the ticket with an expiration in the past
+        let mut plaintext = 1000u64.to_be_bytes().to_vec();
+        plaintext.extend_from_slice(ticket.ticket_key.as_bytes());
+        ticket.sealed = keyring.seal(super::TICKET_AAD, &plaintext, 0).0;
+        let (_, hello) = ticket.start_session();
+        assert!(accept_session(&keyring, &hello).is_err());
+    }
+}
@@ -1,65 +1,154 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::io::{Cursor, Error as IoError, ErrorKind, Read};
-use std::net::SocketAddr;
+use log::{debug, error, info, warn};
+use prometheus::core::Collector;
+use std::collections::{HashMap, VecDeque};
+use std::io::Error as IoError;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot::{Sender, channel};
 
 use crate::{DeviceId, GroupId, ObjectId, PoolName};
-use super::storage::StorageBackend;
-use super::storage_map::{Node, StorageMap};
+use crate::buffer_pool::{Buffer, BufferPool};
+use crate::client::create_client;
+use crate::crypto::aes_implementation;
+use crate::crypto::{KeyPair, SEAL_OVERHEAD};
+use crate::crypto::capability::{Capability, OP_DELETE, OP_READ, OP_WRITE};
+use crate::crypto::envelope::{RESPONSE_HEADER_SIZE, RequestHeader, VERSION_ENVELOPE, open_request, seal_response};
+use crate::crypto::epoch::{clock_epoch, next_boot_epoch};
+use crate::crypto::keyring::Keyring;
+use crate::crypto::peer::{PEER_REPLICATE, PEER_REQUEST, PEER_RESPONSE, open_peer_message, seal_peer_message};
+use crate::crypto::replay::{ReplayWindow, WINDOW_SIZE};
+use crate::crypto::session::{Session, accept_session, issue_ticket};
+use crate::error::{Error, NetworkError, PlacementError};
+use crate::gc::{GcOptions, collect_garbage};
+use crate::metrics::{register_counter, register_counter_vec, register_gauge, register_gauge_vec, register_latency};
+use crate::metrics::reporter::Reporter;
+use crate::netsim::Socket;
+use crate::proto::wire::{
+    OPCODE_APPEND_OBJECT, OPCODE_COMPARE_AND_SWAP, OPCODE_DELETE_OBJECT, OPCODE_FLUSH, OPCODE_LIST_OBJECTS,
+    OPCODE_READ_OBJECT, OPCODE_READ_PART, OPCODE_SET_TAGS, OPCODE_STAT_OBJECT, OPCODE_STATS, OPCODE_TRUNCATE_OBJECT,
+    OPCODE_WATCH, OPCODE_WRITE_IF_VERSION, OPCODE_WRITE_OBJECT, OPCODE_WRITE_PART, CHECKSUM_SIZE,
+    ChunkAssembler, ErrorCode, EventKind, MAX_FRAME_SIZE, Request, RequestMessage, Response, ResponseChunk, ResponseFrame,
+    ResponseMessage, TraceLabel,
+    add_checksum, check_checksum, frame_count, request_counter, request_trace_id, write_frame,
+};
+#[cfg(feature = "extended-ops")]
+use crate::proto::wire::PoolUsage;
+#[cfg(feature = "dtls")]
+use crate::dtls::{self, DtlsListener, SessionSender};
+use super::storage::{BackendStats, StorageBackend};
+use super::storage_map::StorageMap;
+use super::systemd;
+use super::udp_batch::{MAX_BATCH, bind_reuse_port};
+use super::watch::EventLog;
 
 #[derive(Clone)]
 struct Metrics {
-    reads: prometheus::IntCounter,
-    writes: prometheus::IntCounter,
+    requests: prometheus::IntCounterVec,
     invalid_requests: prometheus::IntCounter,
+    corrupt_requests: prometheus::IntCounter,
+    resent_responses: prometheus::IntCounter,
+    latency: prometheus::HistogramVec,
+    stored_objects: prometheus::IntGaugeVec,
+    stored_bytes: prometheus::IntGaugeVec,
+    free_bytes: prometheus::IntGauge,
 }
 
 lazy_static! {
     static ref METRICS: Metrics = {
         let m = Metrics {
-            reads: prometheus::register_int_counter!("reads", "Total reads").unwrap(),
-            writes: prometheus::register_int_counter!("writes", "Total writes").unwrap(),
-            invalid_requests: prometheus::register_int_counter!("invalid_requests", "Total invalid requests").unwrap(),
+            requests: register_counter_vec("daemon", "requests", "Total requests handled", &["pool", "op", "result"]),
+            invalid_requests: register_counter("daemon", "invalid_requests", "Total invalid requests"),
+            corrupt_requests: register_counter("daemon", "corrupt_requests", "Total requests dropped for a bad checksum"),
+            resent_responses: register_counter("daemon", "resent_responses", "Total responses sent again to clients resending a request"),
+            latency: register_latency("daemon", "request_duration_seconds", "Time spent handling requests, including the backend and forwarding", &["op"]),
+            stored_objects: register_gauge_vec("daemon", "stored_objects", "Objects stored in the backend", &["pool"]),
+            stored_bytes: register_gauge_vec("daemon", "stored_bytes", "Bytes of data stored in the backend", &["pool"]),
+            free_bytes: register_gauge("daemon", "free_bytes", "Space left for the backend, if known"),
         };
-        let metrics = m.clone();
-        std::thread::spawn(move || {
-            let mut last_reads = 0;
-            let mut last_writes = 0;
-            let mut last_invalid_requests = 0;
-            loop {
-                let reads = metrics.reads.get();
-                let writes = metrics.writes.get();
-                let invalid_requests = metrics.invalid_requests.get();
-                if reads != last_reads
-                    || writes != last_writes
-                    || invalid_requests != last_invalid_requests
-                {
-                    info!(
-                        "last 10s: {} reads, {} writes, {} invalid requests",
-                        reads - last_reads,
-                        writes - last_writes,
-                        invalid_requests - last_invalid_requests
-                    );
-                    last_reads = reads;
-                    last_writes = writes;
-                    last_invalid_requests = invalid_requests;
-                }
-                std::thread::sleep(std::time::Duration::from_millis(10000));
-            }
-        });
+        let (requests, errors) = (m.requests.clone(), m.requests.clone());
+        let invalid_requests = m.invalid_requests.clone();
+        Reporter::new("daemon")
+            .value("requests", "requests", move || count_requests(&requests).0)
+            .value("errors", "errors", move || count_requests(&errors).1)
+            .value("invalid_requests", "invalid requests", move || invalid_requests.get())
+            .start();
         m
     };
 }
 
+/// Sum the request counters, returning the total and the number of errors.
+fn count_requests(requests: &prometheus::IntCounterVec) -> (u64, u64) {
+    let mut total = 0;
+    let mut errors = 0;
+    for family in requests.collect() {
+        for metric in family.get_metric() {
+            let value = metric.get_counter().get_value() as u64;
+            total += value;
+            if metric.get_label().iter().any(|l| l.get_name() == "result" && l.get_value() != "ok") {
+                errors += value;
+            }
+        }
+    }
+    (total, errors)
+}
+
 const TIMEOUT: Duration = Duration::from_millis(5000);
 
+/// How often to delete expired objects from the backend.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to update the metrics about the data stored in the backend.
+const STATS_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How old the counts can be to answer stats requests, before counting again.
+#[cfg(feature = "extended-ops")]
+const STATS_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// How long the capability used to collect garbage is valid, longer than a
+/// collection takes.
+const GC_CAPABILITY_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+
+/// How long to remember the counters of a client we stopped hearing from.
+const CLIENT_EXPIRY: Duration = Duration::from_secs(600);
+
+/// How many responses to keep for each client, older requests are outside
+/// of the replay window anyway.
+const CACHED_RESPONSES: usize = WINDOW_SIZE as usize;
+
+/// Most sessions to keep, the least recently used ones are dropped to open
+/// new ones past that.
+const MAX_SESSIONS: usize = 16384;
+
+/// How far ahead of our clock the epoch of a request can be.
+const MAX_EPOCH_AHEAD: u32 = 24 * 3600;
+
+/// Largest forwarded response to put back together from chunks.
+const MAX_RESPONSE_SIZE: usize = 64 << 20;
+
+/// Size of the buffers datagrams are received in, the largest UDP payload.
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+/// Buffers datagrams are received in, kept `RECEIVE_BUFFER_SIZE` long so
+/// they don't have to be zeroed again.
+static RECEIVE_BUFFERS: BufferPool = BufferPool::new(256, RECEIVE_BUFFER_SIZE);
+
+/// Buffers responses are encoded and split into frames in. Those that grew
+/// for large responses are not kept.
+static RESPONSE_BUFFERS: BufferPool = BufferPool::new(256, 2 * RECEIVE_BUFFER_SIZE);
+
+/// Most objects returned by one list request.
+#[cfg(feature = "extended-ops")]
+const MAX_LIST_OBJECTS: usize = 1000;
+
+/// Most bytes of object names returned by one list request.
+#[cfg(feature = "extended-ops")]
+const MAX_LIST_SIZE: usize = 32768;
+
 pub struct StorageDaemon {
     /// The random ID for this storage daemon.
     device_id: DeviceId,
@@ -78,12 +167,86 @@ pub struct StorageDaemon {
 
     /// Addresses of all storage daemons.
     storage_daemons: HashMap<DeviceId, Arc<Mutex<PeerDaemon>>>,
+
+    /// Keys to check the capabilities attached to requests, and to seal the
+    /// traffic between daemons. If unset, all requests are allowed, and
+    /// requests can't be forwarded to other daemons.
+    capability_keys: Option<Arc<Keyring>>,
+
+    /// Socket to exchange sealed messages with other daemons.
+    peer_socket: Option<Arc<Socket>>,
+
+    /// Our epoch, sent with the requests we forward.
+    epoch: u32,
+
+    /// Requests received from each client, by address and the client ID in
+    /// its capability.
+    clients: HashMap<(SocketAddr, String), ClientState>,
+
+    /// Sessions opened by clients with a ticket, by ID.
+    sessions: HashMap<u32, SessionState>,
+
+    /// The last usage counted, and when.
+    backend_stats: Option<(Instant, BackendStats)>,
+
+    /// Recent changes to our objects, for watchers.
+    events: EventLog,
+}
+
+/// Identifies a client, to find what we remember of its requests.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ClientKey {
+    /// Plain requests, by address and the client ID in their capability.
+    Address(SocketAddr, String),
+    /// Requests in a session, by its ID.
+    Session(u32),
+}
+
+/// What we remember of the requests from a client.
+struct ClientState {
+    last_seen: Instant,
+    /// Counters received, to reject replayed requests.
+    window: ReplayWindow,
+    /// The latest responses to requests changing objects, by epoch and
+    /// counter, to send again if the client resends the request rather than
+    /// doing it twice.
+    responses: VecDeque<((u32, u32), Response)>,
+}
+
+impl ClientState {
+    fn new() -> ClientState {
+        ClientState {
+            last_seen: Instant::now(),
+            window: ReplayWindow::new(),
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+/// A session opened by a client, see `crypto::session`.
+struct SessionState {
+    session: Session,
+    /// The requests received in the session.
+    client: ClientState,
 }
 
 pub struct PeerDaemon {
     address: SocketAddr,
     counter: u32,
-    response_channels: HashMap<u32, (Instant, Sender<Vec<u8>>)>,
+    /// Counter for the nonces of the messages we seal to this daemon.
+    seal_counter: u32,
+    response_channels: HashMap<u32, (Instant, Sender<Response>)>,
+    /// Responses we got some of the chunks of.
+    partial_responses: HashMap<u32, ChunkAssembler>,
+}
+
+impl PeerDaemon {
+    /// Seal a message to this daemon, with the next counter of the link.
+    fn seal(&mut self, keyring: &Keyring, kind: u8, msg: &[u8]) -> Vec<u8> {
+        let counter = self.seal_counter;
+        self.seal_counter = self.seal_counter.wrapping_add(1);
+        seal_peer_message(keyring, kind, msg, counter)
+    }
 }
 
 pub enum Pool {
@@ -97,6 +260,53 @@ pub enum Pool {
     Transition { previous: StorageMap, current: StorageMap },
 }
 
+impl StorageDaemon {
+    /// A daemon storing the "default" pool alone, until the master tells
+    /// it about others.
+    fn new(device_id: DeviceId, peer_address: SocketAddr, listen_address: SocketAddr, capability_keys: Option<Keyring>, epoch: u32) -> StorageDaemon {
+        let storage_map = StorageMap::single_device(device_id.clone());
+        let mut pools = HashMap::new();
+        pools.insert(PoolName("default".to_owned()), Pool::Normal(storage_map));
+        StorageDaemon {
+            device_id,
+            peer_address,
+            listen_address,
+            masters: vec![],
+            pools,
+            storage_daemons: HashMap::new(),
+            capability_keys: capability_keys.map(Arc::new),
+            peer_socket: None,
+            epoch,
+            clients: HashMap::new(),
+            sessions: HashMap::new(),
+            backend_stats: None,
+            events: EventLog::new(epoch),
+        }
+    }
+
+    /// What we remember of a client, starting to remember clients sending
+    /// plain requests. Sessions have to be open already.
+    fn client_state(&mut self, key: &ClientKey) -> Option<&mut ClientState> {
+        match key {
+            ClientKey::Address(addr, client_id) => {
+                Some(self.clients.entry((*addr, client_id.clone())).or_insert_with(ClientState::new))
+            }
+            ClientKey::Session(id) => self.sessions.get_mut(id).map(|s| &mut s.client),
+        }
+    }
+}
+
+/// Settings of a storage daemon that can change while it runs.
+///
+/// `run_storage_daemon()` gets new ones on SIGHUP. The others, such as the
+/// addresses, the storage and the keyring, are only read on startup.
+#[derive(Clone, Debug, Default)]
+pub struct DaemonSettings {
+    /// How often to collect garbage, and how.
+    pub gc: Option<(Duration, GcOptions)>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_storage_daemon(
     peer_address: SocketAddr,
     peer_cert: &Path,
@@ -105,65 +315,912 @@ pub async fn run_storage_daemon(
     listen_address: SocketAddr,
     storage_backend: Box<dyn StorageBackend>,
     device_id: DeviceId,
+    epoch_file: Option<&Path>,
+    capability_keys: Option<Keyring>,
+    dtls_address: Option<SocketAddr>,
+    receive_threads: usize,
+    settings: DaemonSettings,
+    reload: impl Fn() -> Result<DaemonSettings, String> + Send + 'static,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let storage_backend: Arc<dyn StorageBackend> = storage_backend.into();
+    info!("Using {} AES implementation", aes_implementation());
+
+    // Without a file to keep it in, trust the clock not to go backwards
+    let epoch = match epoch_file {
+        Some(path) => next_boot_epoch(path)?,
+        None => clock_epoch(),
+    };
+    info!("Starting with epoch {}", epoch);
+
+    let storage_daemon = StorageDaemon::new(device_id, peer_address, listen_address, capability_keys, epoch);
+    let storage_daemon = Arc::new(Mutex::new(storage_daemon));
+
+    tokio::spawn(sweep_expired(storage_backend.clone()));
+    tokio::spawn(report_backend_stats(storage_daemon.clone(), storage_backend.clone()));
+    tokio::spawn(expire_clients(storage_daemon.clone()));
+    tokio::spawn(apply_settings(storage_daemon.clone(), settings, reload));
+
+    let keyring = storage_daemon.lock().unwrap().capability_keys.clone();
+    let peers_fut = match keyring {
+        Some(keyring) => {
+            info!("Listening for peer messages on {}", peer_address);
+            let socket = bind_udp(peer_address, false).await?;
+            let socket = Arc::new(Socket::Udp(socket));
+            storage_daemon.lock().unwrap().peer_socket = Some(socket.clone());
+            Some(serve_peers(socket, keyring, storage_daemon.clone(), storage_backend.clone()))
+        }
+        None => {
+            warn!("No keyring, requests can't be forwarded to other daemons");
+            None
+        }
+    };
+
+    if let Some(dtls_address) = dtls_address {
+        #[cfg(feature = "dtls")]
+        {
+            info!("Listening for DTLS client sessions on {}", dtls_address);
+            let listener = DtlsListener::bind(dtls_address, peer_cert, peer_key).await?;
+            let storage_daemon = storage_daemon.clone();
+            let storage_backend = storage_backend.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_dtls_clients(listener, storage_daemon, storage_backend).await {
+                    error!("Error accepting DTLS sessions: {}", e);
+                }
+            });
+        }
+        #[cfg(not(feature = "dtls"))]
+        return Err(format!("Can't listen on {}, DTLS support was not compiled in", dtls_address).into());
+    }
+
+    let clients_fut = {
+        info!("Listening for client connections on {}", listen_address);
+        let socket = bind_udp(listen_address, receive_threads > 1).await?;
+        let socket = Arc::new(Socket::Udp(socket));
+
+        // More sockets on the same address, each read by its own thread
+        let (errors_tx, mut errors_rx) = tokio::sync::mpsc::unbounded_channel();
+        for num in 1..receive_threads {
+            let socket = bind_reuse_port(listen_address).map_err(|e| {
+                format!("Can't bind another socket to {} (for socket activation, set ReusePort=yes): {}", listen_address, e)
+            })?;
+            spawn_receive_thread(num, socket, storage_daemon.clone(), storage_backend.clone(), errors_tx.clone())?;
+        }
+        drop(errors_tx);
+
+        let serve_fut = serve_clients(socket, storage_daemon.clone(), storage_backend.clone());
+        async move {
+            tokio::select! {
+                r = serve_fut => r,
+                Some(e) = errors_rx.recv() => Err(e),
+            }
+        }
+    };
+    let peers_fut = async {
+        match peers_fut {
+            Some(peers_fut) => peers_fut.await,
+            None => std::future::pending().await,
+        }
+    };
+
+    systemd::notify("READY=1");
+    {
+        // Pings stop if the daemon is stuck holding its lock
+        let storage_daemon = storage_daemon.clone();
+        systemd::spawn_watchdog(move || drop(storage_daemon.lock().unwrap()));
+    }
+
+    tokio::select! {
+        r = clients_fut => r?,
+        r = peers_fut => r?,
+        signal = systemd::termination_signal() => {
+            info!("Got signal {}, stopping", signal?);
+            systemd::notify("STOPPING=1");
+            storage_backend.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Bind a UDP socket, or use the one systemd passed for that address.
+///
+/// With `reuse_port`, more sockets can be bound to the address with
+/// `bind_reuse_port()`.
+async fn bind_udp(address: SocketAddr, reuse_port: bool) -> Result<UdpSocket, IoError> {
+    match systemd::take_udp_socket(address)? {
+        Some(socket) => UdpSocket::from_std(socket),
+        None if reuse_port => UdpSocket::from_std(bind_reuse_port(address)?),
+        None => UdpSocket::bind(address).await,
+    }
+}
+
+/// Serve clients on another socket, from a thread with its own runtime.
+///
+/// The requests received on it are handled on that thread too, so they are
+/// spread over as many cores as there are sockets. Errors are sent to
+/// `errors`.
+fn spawn_receive_thread(
+    num: usize,
+    socket: std::net::UdpSocket,
+    storage_daemon: Arc<Mutex<StorageDaemon>>,
+    storage_backend: Arc<dyn StorageBackend>,
+    errors: UnboundedSender<IoError>,
+) -> Result<(), IoError> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    std::thread::Builder::new().name(format!("receive-{}", num)).spawn(move || {
+        let result = runtime.block_on(async move {
+            let socket = Arc::new(Socket::Udp(UdpSocket::from_std(socket)?));
+            serve_clients(socket, storage_daemon, storage_backend).await
+        });
+        if let Err(e) = result {
+            let _ = errors.send(e);
+        }
+    })?;
+    Ok(())
+}
+
+async fn sweep_expired(storage_backend: Arc<dyn StorageBackend>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match storage_backend.sweep_expired(SystemTime::now()) {
+            Ok(0) => {}
+            Ok(count) => info!("Deleted {} expired objects", count),
+            Err(e) => error!("Error deleting expired objects: {}", e),
+        }
+    }
+}
+
+/// Update the metrics about the data stored in the backend, and keep the
+/// counts to answer stats requests.
+async fn report_backend_stats(storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>) {
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        // This goes over every object, don't block the runtime
+        let backend = storage_backend.clone();
+        let stats = match tokio::task::spawn_blocking(move || backend.stats()).await {
+            Ok(Ok(stats)) => stats,
+            Ok(Err(e)) => {
+                error!("Error getting backend stats: {}", e);
+                continue;
+            }
+            Err(e) => {
+                error!("Error getting backend stats: {}", e);
+                continue;
+            }
+        };
+
+        // Reset, so pools that are gone don't stay around
+        METRICS.stored_objects.reset();
+        METRICS.stored_bytes.reset();
+        for (pool, pool_stats) in &stats.pools {
+            METRICS.stored_objects.with_label_values(&[&pool.0]).set(pool_stats.objects as i64);
+            METRICS.stored_bytes.with_label_values(&[&pool.0]).set(pool_stats.bytes as i64);
+        }
+        if let Some(free_space) = stats.free_space {
+            METRICS.free_bytes.set(free_space as i64);
+        }
+        storage_daemon.lock().unwrap().backend_stats = Some((Instant::now(), stats));
+    }
+}
+
+/// Forget about clients that haven't sent requests in a while, and about
+/// sessions that expired.
+async fn expire_clients(storage_daemon: Arc<Mutex<StorageDaemon>>) {
+    loop {
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+        let mut storage_daemon = storage_daemon.lock().unwrap();
+        let before = storage_daemon.clients.len();
+        storage_daemon.clients.retain(|_, client| client.last_seen.elapsed() < CLIENT_EXPIRY);
+        let expired = before - storage_daemon.clients.len();
+        if expired > 0 {
+            debug!("Forgot {} inactive clients", expired);
+        }
+
+        let now = SystemTime::now();
+        let before = storage_daemon.sessions.len();
+        storage_daemon.sessions.retain(|_, s| s.session.expires > now && s.client.last_seen.elapsed() < CLIENT_EXPIRY);
+        let expired = before - storage_daemon.sessions.len();
+        if expired > 0 {
+            debug!("Closed {} sessions", expired);
+        }
+    }
+}
+
+/// Run with `settings`, and with those from `reload()` after each SIGHUP.
+async fn apply_settings<F: Fn() -> Result<DaemonSettings, String>>(storage_daemon: Arc<Mutex<StorageDaemon>>, settings: DaemonSettings, reload: F) {
+    let spawn_gc = |settings: DaemonSettings| {
+        settings.gc.map(|(interval, options)| {
+            tokio::spawn(collect_garbage_periodically(storage_daemon.clone(), interval, options))
+        })
+    };
+    let mut gc = spawn_gc(settings);
+
+    let mut hangups = match systemd::Hangups::new() {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Can't reload on SIGHUP: {}", e);
+            return;
+        }
+    };
+    loop {
+        if let Err(e) = hangups.recv().await {
+            error!("Can't reload on SIGHUP: {}", e);
+            return;
+        }
+        systemd::notify_reloading();
+        match reload() {
+            Ok(settings) => {
+                info!("Reloaded configuration");
+                if let Some(gc) = &gc {
+                    gc.abort();
+                }
+                gc = spawn_gc(settings);
+            }
+            Err(e) => error!("Error reloading configuration, keeping the current one: {}", e),
+        }
+        systemd::notify("READY=1");
+    }
+}
+
+/// Delete the orphaned objects we store, see `gc`.
+///
+/// This goes through a client of this daemon, which lists only our objects,
+/// and forwards the reads of the objects referring to them.
+async fn collect_garbage_periodically(storage_daemon: Arc<Mutex<StorageDaemon>>, interval: Duration, options: GcOptions) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let (mut address, pools, keyring) = {
+            let storage_daemon = storage_daemon.lock().unwrap();
+            let pools: Vec<PoolName> = storage_daemon.pools.keys().cloned().collect();
+            (storage_daemon.listen_address, pools, storage_daemon.capability_keys.clone())
+        };
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        for pool in pools {
+            let client = match create_client(address, pool.clone()).await {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Error creating client to collect garbage: {}", e);
+                    continue;
+                }
+            };
+            if let Some(keyring) = &keyring {
+                let capability = Capability::new("gc".to_owned(), vec![pool.clone()], OP_READ | OP_DELETE, GC_CAPABILITY_LIFETIME);
+                client.set_capability(capability.seal(keyring));
+                client.set_ticket(issue_ticket(keyring, GC_CAPABILITY_LIFETIME));
+            }
+            match collect_garbage(&client, &options).await {
+                Ok(report) if report.orphans > 0 => info!(
+                    "Deleted {} orphaned objects ({} bytes) in pool {}",
+                    report.deleted, report.bytes, pool,
+                ),
+                Ok(_) => {}
+                Err(e) => error!("Error collecting garbage in pool {}: {}", pool, e),
+            }
+        }
+    }
+}
+
+/// A message received in a pooled buffer, which goes back to the pool once
+/// the request is handled.
+struct Received {
+    buf: Buffer<'static>,
+    len: usize,
+}
+
+impl Received {
+    fn buffer() -> Buffer<'static> {
+        let mut buf = RECEIVE_BUFFERS.get();
+        buf.resize(RECEIVE_BUFFER_SIZE, 0);
+        buf
+    }
+}
+
+impl AsRef<[u8]> for Received {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[0..self.len]
+    }
+}
+
+async fn serve_clients(socket: Arc<Socket>, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>) -> Result<(), IoError> {
+    let mut bufs = Vec::with_capacity(MAX_BATCH);
+    let mut received = Vec::with_capacity(MAX_BATCH);
+    loop {
+        // Replace the buffers handed to requests with new ones
+        bufs.resize_with(MAX_BATCH, Received::buffer);
+        let count = socket.recv_batch(&mut bufs, &mut received).await?;
+        for (buf, &(len, addr)) in bufs.drain(0..count).zip(&received) {
+            debug!("Got packet from {}, size {}", addr, len);
+            // Sealed requests are authenticated already and carry no checksum
+            let msg = if buf.first() == Some(&VERSION_ENVELOPE) {
+                Received { len, buf }
+            } else {
+                match check_checksum(&buf[0..len]) {
+                    Some(m) => Received { len: m.len(), buf },
+                    None => {
+                        warn!("Corrupted packet from {}", addr);
+                        METRICS.corrupt_requests.inc();
+                        continue;
+                    }
+                }
+            };
+
+            let reply = Reply {
+                addr,
+                path: ReplyPath::Datagram { socket: socket.clone(), seal: None },
+            };
+            tokio::spawn(handle_client_request(
+                reply,
+                storage_daemon.clone(),
+                storage_backend.clone(),
+                msg,
+                false,
+            ));
+        }
+    }
+}
+
+/// Serve clients on a simulated socket from memory, for tests of the client
+/// side.
+#[cfg(test)]
+pub(crate) fn spawn_test_daemon(socket: crate::netsim::SimSocket) -> tokio::task::JoinHandle<Result<(), IoError>> {
+    spawn_test_daemon_with_keys(socket, None).1
+}
+
+/// Like `spawn_test_daemon()`, checking capabilities and tickets with
+/// `keyring`.
+#[cfg(test)]
+fn spawn_test_daemon_with_keys(socket: crate::netsim::SimSocket, keyring: Option<Keyring>) -> (Arc<Mutex<StorageDaemon>>, tokio::task::JoinHandle<Result<(), IoError>>) {
+    let address = socket.local_addr();
+    let storage_daemon = StorageDaemon::new(DeviceId([1; 16]), address, address, keyring, clock_epoch());
+    let storage_daemon = Arc::new(Mutex::new(storage_daemon));
+    let task = tokio::spawn(serve_clients(
+        Arc::new(Socket::Sim(socket)),
+        storage_daemon.clone(),
+        Arc::new(super::storage::mem_store::MemStore::default()),
+    ));
+    (storage_daemon, task)
+}
+
+/// Handle requests from clients over DTLS sessions.
+#[cfg(feature = "dtls")]
+async fn serve_dtls_clients(mut listener: DtlsListener, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>) -> Result<(), IoError> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("DTLS session with {}", addr);
+        let (mut read, session) = dtls::split(stream);
+        let storage_daemon = storage_daemon.clone();
+        let storage_backend = storage_backend.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut buf = Received::buffer();
+                let len = match tokio::time::timeout(CLIENT_EXPIRY, tokio::io::AsyncReadExt::read(&mut read, &mut buf)).await {
+                    Ok(Ok(0)) | Err(_) => break,
+                    Ok(Ok(len)) => len,
+                    Ok(Err(e)) => {
+                        warn!("Error reading from DTLS session with {}: {}", addr, e);
+                        break;
+                    }
+                };
+                debug!("Got DTLS message from {}, size {}", addr, len);
+                let reply = Reply { addr, path: ReplyPath::Session(session.clone()) };
+                tokio::spawn(handle_client_request(
+                    reply,
+                    storage_daemon.clone(),
+                    storage_backend.clone(),
+                    Received { buf, len },
+                    false,
+                ));
+            }
+            debug!("DTLS session with {} ended", addr);
+        });
+    }
+}
+
+/// Receive sealed messages from other daemons: forwarded and replicated
+/// requests, and responses to the requests we sent.
+async fn serve_peers(socket: Arc<Socket>, keyring: Arc<Keyring>, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>) -> Result<(), IoError> {
+    loop {
+        let mut buf = Received::buffer();
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        debug!("Got peer packet from {}, size {}", addr, len);
+        let msg = match open_peer_message(&keyring, &buf[0..len]) {
+            Some(m) => m,
+            None => {
+                warn!("Invalid peer message from {}", addr);
+                METRICS.invalid_requests.inc();
+                continue;
+            }
+        };
+
+        match msg {
+            (kind @ (PEER_REQUEST | PEER_REPLICATE), msg) => {
+                // Responses are sealed with the counter of the link
+                let peer = storage_daemon.lock().unwrap().storage_daemons.values()
+                    .find(|p| p.lock().unwrap().address == addr)
+                    .cloned();
+                let peer = match peer {
+                    Some(p) => p,
+                    None => {
+                        warn!("Peer message from unknown daemon {}", addr);
+                        continue;
+                    }
+                };
+                let reply = Reply {
+                    addr,
+                    path: ReplyPath::Datagram { socket: socket.clone(), seal: Some(Seal::Peer(keyring.clone(), peer)) },
+                };
+                tokio::spawn(handle_client_request(
+                    reply,
+                    storage_daemon.clone(),
+                    storage_backend.clone(),
+                    msg,
+                    kind == PEER_REPLICATE,
+                ));
+            }
+            (PEER_RESPONSE, msg) => {
+                let frame = match ResponseFrame::decode(&msg) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        warn!("Invalid response from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+
+                // Get the channel
+                let storage_daemon = storage_daemon.lock().unwrap();
+                for peer in storage_daemon.storage_daemons.values() {
+                    let mut peer = peer.lock().unwrap();
+                    if peer.address == addr {
+                        let message = match frame {
+                            ResponseFrame::Whole(m) => m,
+                            ResponseFrame::Chunk(chunk) => match add_chunk(&mut peer, chunk) {
+                                Some(m) => m,
+                                None => break,
+                            },
+                        };
+                        let counter = message.counter;
+                        if let Some((_, channel)) = peer.response_channels.remove(&counter) {
+                            debug!("Handling forwarded reply, counter={}", counter);
+                            channel.send(message.response).ok();
+                        }
+                        break;
+                    }
+                }
+            }
+            (kind, _) => warn!("Unknown peer message kind {} from {}", kind, addr),
+        }
+    }
+}
+
+/// Add a chunk of a forwarded response, returning it once complete.
+fn add_chunk(peer: &mut PeerDaemon, chunk: ResponseChunk) -> Option<ResponseMessage> {
+    let counter = chunk.counter;
+
+    // Only collect chunks for requests we're waiting on
+    if !peer.response_channels.contains_key(&counter) {
+        return None;
+    }
+    let assembler = peer.partial_responses
+        .entry(counter)
+        .or_insert_with(|| ChunkAssembler::new(MAX_RESPONSE_SIZE));
+    match assembler.add(chunk) {
+        Ok(None) => None,
+        Ok(Some(message)) => {
+            peer.partial_responses.remove(&counter);
+            Some(message)
+        }
+        Err(e) => {
+            warn!("Invalid chunked response from {}: {}", peer.address, e);
+            peer.partial_responses.remove(&counter);
+            None
+        }
+    }
+}
+
+/// Where to send the response to a request.
+struct Reply {
+    addr: SocketAddr,
+    path: ReplyPath,
+}
+
+enum ReplyPath {
+    /// A datagram on the socket the request came from.
+    Datagram {
+        socket: Arc<Socket>,
+        /// How to seal the response, plain datagrams get a checksum.
+        seal: Option<Seal>,
+    },
+    /// The DTLS session the request came from.
+    #[cfg(feature = "dtls")]
+    Session(SessionSender),
+}
+
+/// How responses sent as datagrams are sealed.
+enum Seal {
+    /// With the keys shared by the daemons, for requests from other daemons.
+    Peer(Arc<Keyring>, Arc<Mutex<PeerDaemon>>),
+    /// With the keys of the session the request was sent in, for requests
+    /// from clients.
+    Session(KeyPair, RequestHeader),
+}
+
+impl Reply {
+    /// Seal the response with the keys of the session the request came in.
+    fn seal_for_session(&mut self, keys: KeyPair, header: RequestHeader) {
+        match self.path {
+            ReplyPath::Datagram { ref mut seal, .. } => *seal = Some(Seal::Session(keys, header)),
+            #[cfg(feature = "dtls")]
+            ReplyPath::Session(_) => {}
+        }
+    }
+
+    async fn send(&self, response: &ResponseMessage) -> Result<(), IoError> {
+        match self.path {
+            ReplyPath::Datagram { ref socket, ref seal } => {
+                let max_size = match seal {
+                    None => MAX_FRAME_SIZE - CHECKSUM_SIZE,
+                    Some(Seal::Peer(..)) => MAX_FRAME_SIZE,
+                    Some(Seal::Session(..)) => MAX_FRAME_SIZE - RESPONSE_HEADER_SIZE - SEAL_OVERHEAD,
+                };
+                let mut encoded = RESPONSE_BUFFERS.get();
+                encoded.clear();
+                response.encode_into(&mut encoded);
+                let make_frame = |seq| {
+                    let mut frame = RESPONSE_BUFFERS.get();
+                    frame.clear();
+                    write_frame(&encoded, max_size, seq, &mut frame);
+                    let sealed = match seal {
+                        Some(Seal::Peer(keyring, peer)) => peer.lock().unwrap().seal(keyring, PEER_RESPONSE, &frame),
+                        Some(Seal::Session(keys, header)) => seal_response(keys, header, &frame),
+                        None => {
+                            add_checksum(&mut frame);
+                            return frame;
+                        }
+                    };
+                    frame.clear();
+                    frame.extend_from_slice(&sealed);
+                    frame
+                };
+
+                // Responses in many frames are sent in batches
+                let count = frame_count(&encoded, max_size);
+                if count == 1 {
+                    socket.send_to(&make_frame(0), self.addr).await?;
+                } else {
+                    let mut frames = Vec::with_capacity(MAX_BATCH);
+                    for first in (0..count).step_by(MAX_BATCH) {
+                        frames.clear();
+                        frames.extend((first..count.min(first + MAX_BATCH)).map(make_frame));
+                        socket.send_batch(&frames, self.addr).await?;
+                    }
+                }
+            }
+            #[cfg(feature = "dtls")]
+            ReplyPath::Session(ref session) => {
+                for frame in response.encode_frames(dtls::MAX_MESSAGE_SIZE) {
+                    session.send(frame)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handle a request from a client, or from another daemon.
+///
+/// `replica` is set for changes replicated by the primary of an object,
+/// which are applied here without looking where the object goes.
+async fn handle_client_request(mut reply: Reply, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>, msg: impl AsRef<[u8]>, replica: bool) -> Result<(), IoError> {
+    let addr = reply.addr;
+    let msg = msg.as_ref();
+
+    // Requests in a session are checked against it before being opened
+    let opened;
+    let (header, msg) = if msg.first() == Some(&VERSION_ENVELOPE) {
+        match open_session_request(&storage_daemon, addr, msg) {
+            Opened::Request(header, keys, body) => {
+                reply.seal_for_session(keys, header.clone());
+                opened = body;
+                (Some(header), &opened[..])
+            }
+            Opened::NoSession(counter) => {
+                // The client can't tell this was us, but all it does is
+                // open another session
+                debug!("Request from {} in unknown session", addr);
+                let response = Response::Error(ErrorCode::NoSession);
+                return reply.send(&ResponseMessage { counter, trace_id: None, response }).await;
+            }
+            Opened::Dropped => {
+                METRICS.invalid_requests.inc();
+                return Ok(());
+            }
+        }
+    } else {
+        (None, msg)
+    };
+
+    let message = match RequestMessage::decode(msg) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Invalid request from {}: {}", addr, e);
+            METRICS.invalid_requests.inc();
+
+            // Requests for operations we don't know are otherwise well-formed,
+            // answer them so the client doesn't wait
+            if ErrorCode::get(&e) == Some(ErrorCode::Unsupported) {
+                if let Some(counter) = request_counter(msg) {
+                    let trace_id = request_trace_id(msg);
+                    let response = Response::Error(ErrorCode::Unsupported);
+                    reply.send(&ResponseMessage { counter, trace_id, response }).await?;
+                }
+            }
+            return Ok(());
+        }
+    };
+    let path = match header {
+        Some(header) => {
+            let fields = (header.epoch, header.counter, &header.pool, header.opcode);
+            if fields != (message.epoch, message.counter, &message.pool, message.request.opcode()) {
+                warn!("Sealed request from {} doesn't match its header", addr);
+                METRICS.invalid_requests.inc();
+                return Ok(());
+            }
+            RequestPath::Session(header.session)
+        }
+        None => match reply.path {
+            ReplyPath::Datagram { seal: None, .. } => RequestPath::Plain,
+            _ => RequestPath::Authenticated,
+        },
+    };
+    let counter = message.counter;
+    let trace_id = message.trace_id;
+    let _timer = METRICS.latency.with_label_values(&[message.request.name()]).start_timer();
+    debug!("Request {} from {}, trace {}", counter, addr, TraceLabel(trace_id));
+
+    let op = message.request.name();
+
+    // Handshakes need no capability, the daemon checks the ticket instead
+    if let Request::Handshake { ref hello } = message.request {
+        let response = match open_session(&storage_daemon, hello) {
+            Ok(answer) => Response::Session(answer),
+            Err(e) => {
+                warn!("Invalid handshake from {}: {}", addr, e);
+                METRICS.invalid_requests.inc();
+                Response::Error(ErrorCode::for_error(&e))
+            }
+        };
+        return reply.send(&ResponseMessage { counter, trace_id, response }).await;
+    }
+
+    // Only label with the pool once the capability is checked, so clients
+    // can't create any number of labels
+    let accepted = message.check().and_then(|()| accept_request(&storage_daemon, addr, path, &message));
+    let mut remember = None;
+    let (pool, result) = match accepted {
+        Ok(Accepted::New(client)) => {
+            if !is_read_only(message.request.opcode()) {
+                remember = Some((client, (message.epoch, counter)));
+            }
+            let pool = message.pool.0.clone();
+            let result = if replica {
+                apply_replica(&storage_daemon, &*storage_backend, &message)
+            } else {
+                handle_client_request_inner(storage_daemon.clone(), storage_backend, message).await
+            };
+            (pool, result)
+        }
+        Ok(Accepted::Answered(response)) => {
+            // Our response was lost, send it again
+            debug!("Resending response to request {} from {}", counter, addr);
+            METRICS.resent_responses.inc();
+            return reply.send(&ResponseMessage { counter, trace_id, response }).await;
+        }
+        Ok(Accepted::Replayed) => {
+            // The original request is still being handled, or is too old
+            warn!("Replayed request from {}", addr);
+            METRICS.invalid_requests.inc();
+            return Ok(());
+        }
+        Err(e) => (String::new(), Err(e)),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Error handling request from {}, trace {}: {}", addr, TraceLabel(trace_id), e);
+            METRICS.invalid_requests.inc();
+            Response::Error(ErrorCode::for_error(&e))
+        }
+    };
+    let result = match response {
+        Response::Error(code) => code.name(),
+        _ => "ok",
+    };
+    METRICS.requests.with_label_values(&[&pool, op, result]).inc();
+    if let Some((client, key)) = remember {
+        remember_response(&storage_daemon, &client, key, &response);
+    }
+    reply.send(&ResponseMessage { counter, trace_id, response }).await
+}
+
+/// Open a session with the ticket in a handshake, returning the answer.
+fn open_session(storage_daemon: &Mutex<StorageDaemon>, hello: &[u8]) -> Result<Vec<u8>, IoError> {
+    let mut storage_daemon = storage_daemon.lock().unwrap();
+    let keyring = match storage_daemon.capability_keys {
+        Some(ref keyring) => keyring.clone(),
+        None => return Err(ErrorCode::Unsupported.into()),
+    };
+    let (answer, session) = accept_session(&keyring, hello)?;
+
+    // Make room by dropping the session that was used the longest ago
+    let sessions = &mut storage_daemon.sessions;
+    if sessions.len() >= MAX_SESSIONS {
+        let oldest = sessions.iter().min_by_key(|(_, s)| s.client.last_seen).map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            sessions.remove(&id);
+        }
+    }
+    if sessions.contains_key(&session.id) {
+        return Err(Error::Crypto("Session ID already in use".to_owned()).into());
+    }
+    debug!("Opened session {}", session.id);
+    sessions.insert(session.id, SessionState { session, client: ClientState::new() });
+    Ok(answer)
+}
+
+/// What came of opening a request sent in a session, see
+/// `open_session_request()`.
+enum Opened {
+    /// The request with its header, which still has to match it, and the
+    /// keys of the session to seal the response.
+    Request(RequestHeader, KeyPair, Vec<u8>),
+    /// The session is unknown, this is the counter of the request.
+    NoSession(u32),
+    /// The request is invalid or replayed, or for a pool we don't have.
+    Dropped,
+}
 
-    let storage_map = StorageMap {
-        generation: 1,
-        groups: 128,
-        replicas: 1,
-        map_root: Node::Device(device_id.clone()),
+/// Check the header of a request sent in a session, and open it.
+fn open_session_request(storage_daemon: &Mutex<StorageDaemon>, addr: SocketAddr, msg: &[u8]) -> Opened {
+    let (header, header_len) = match RequestHeader::read(msg) {
+        Some(h) => h,
+        None => {
+            warn!("Invalid sealed request from {}", addr);
+            return Opened::Dropped;
+        }
     };
-    let mut pools = HashMap::new();
-    pools.insert(PoolName("default".to_owned()), Pool::Normal(storage_map));
-    let storage_daemon = StorageDaemon {
-        device_id,
-        peer_address,
-        listen_address,
-        masters: vec![],
-        pools,
-        storage_daemons: HashMap::new(),
+    let keys = match check_session_header(storage_daemon, addr, &header) {
+        Ok(keys) => keys,
+        Err(opened) => return opened,
     };
-    let storage_daemon = Arc::new(Mutex::new(storage_daemon));
+    match open_request(&keys, &header, header_len, msg) {
+        Some(body) => Opened::Request(header, keys, body),
+        None => {
+            warn!("Invalid sealed request from {}", addr);
+            Opened::Dropped
+        }
+    }
+}
 
-    let clients_fut = {
-        info!("Listening for client connections on {}", listen_address);
-        let socket = UdpSocket::bind(listen_address).await?;
-        let socket = Arc::new(socket);
-        serve_clients(socket, storage_daemon.clone(), storage_backend)
+/// Check the cleartext header of a request, returning the keys to open it.
+///
+/// Requests that would be rejected are dropped before decrypting them:
+/// replays, and requests for pools we don't have, which the client sends
+/// again until we know about them.
+fn check_session_header(storage_daemon: &Mutex<StorageDaemon>, addr: SocketAddr, header: &RequestHeader) -> Result<KeyPair, Opened> {
+    let storage_daemon = storage_daemon.lock().unwrap();
+    let state = match storage_daemon.sessions.get(&header.session) {
+        Some(s) => s,
+        None => return Err(Opened::NoSession(header.counter)),
     };
+    if !storage_daemon.pools.contains_key(&header.pool) {
+        debug!("Request from {} for unknown pool {}", addr, header.pool);
+        return Err(Opened::Dropped);
+    }
 
-    clients_fut.await?;
+    // Like accept_request(), without recording anything until the request
+    // is authenticated
+    let client = &state.client;
+    let key = (header.epoch, header.counter);
+    if !client.window.check(header.epoch, header.counter)
+        && !is_read_only(header.opcode)
+        && !client.responses.iter().any(|(k, _)| *k == key)
+    {
+        warn!("Replayed request from {}", addr);
+        return Err(Opened::Dropped);
+    }
+    Ok(state.session.keys.clone())
+}
 
-    Ok(())
+/// How a request got to us, which decides whether its capability can be
+/// trusted not to have been read in transit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RequestPath {
+    /// A plain datagram.
+    Plain,
+    /// Sealed in a session, by its ID.
+    Session(u32),
+    /// Over DTLS, or sealed by another daemon.
+    Authenticated,
 }
 
-async fn serve_clients(socket: Arc<UdpSocket>, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>) -> Result<(), IoError> {
-    loop {
-        let mut buf = [0; 65536];
-        let (len, addr) = socket.recv_from(&mut buf).await?;
-        debug!("Got packet from {}, size {}", addr, len);
-        let msg = buf[0..len].to_owned();
+/// What to do with a request, see `accept_request()`.
+enum Accepted {
+    /// The request is new, handle it. This identifies the client, to
+    /// remember the response.
+    New(ClientKey),
+    /// The request was already handled, this is the response.
+    Answered(Response),
+    /// The request was already received, drop it.
+    Replayed,
+}
+
+/// Check the capability of a request, and that it is not a replay.
+///
+/// Requests that only read are handled again if resent, the others are only
+/// handled once and the client gets the same response again.
+fn accept_request(storage_daemon: &Mutex<StorageDaemon>, addr: SocketAddr, path: RequestPath, message: &RequestMessage) -> Result<Accepted, IoError> {
+    let mut storage_daemon = storage_daemon.lock().unwrap();
+
+    // Check that the client is allowed to do this. Capabilities are bearer
+    // tokens, they are refused where anyone on the way could have read them
+    let client_id = match storage_daemon.capability_keys {
+        Some(_) if path == RequestPath::Plain => {
+            return Err(Error::Crypto("Capabilities are only accepted in a session or over DTLS".to_owned()).into());
+        }
+        Some(ref keyring) => check_capability(keyring, &message.capability, &message.pool, message.request.opcode())?.client_id,
+        None => String::new(),
+    };
+
+    // Epochs follow the clock, a client getting one far ahead would have its
+    // next requests rejected
+    if message.epoch > clock_epoch().saturating_add(MAX_EPOCH_AHEAD) {
+        return Err(Error::Protocol("Epoch is in the future".to_owned()).into());
+    }
 
-        tokio::spawn(handle_client_request(
-            socket.clone(),
-            storage_daemon.clone(),
-            storage_backend.clone(),
-            addr,
-            msg,
-        ));
+    // Reject replayed requests, while tolerating reordering. Only requests
+    // with a valid capability get here, and each client ID has its own
+    // window, so datagrams spoofing the address of a client can't move it
+    // without its capability. Sessions have their own window
+    let client_key = match path {
+        RequestPath::Session(id) => ClientKey::Session(id),
+        RequestPath::Plain | RequestPath::Authenticated => ClientKey::Address(addr, client_id),
+    };
+    let client = match storage_daemon.client_state(&client_key) {
+        Some(client) => client,
+        None => return Err(ErrorCode::NoSession.into()),
+    };
+    client.last_seen = Instant::now();
+    if client.window.accept(message.epoch, message.counter) {
+        return Ok(Accepted::New(client_key));
+    }
+    let key = (message.epoch, message.counter);
+    if let Some((_, response)) = client.responses.iter().find(|(k, _)| *k == key) {
+        Ok(Accepted::Answered(response.clone()))
+    } else if is_read_only(message.request.opcode()) {
+        Ok(Accepted::New(client_key))
+    } else {
+        Ok(Accepted::Replayed)
     }
 }
 
-async fn handle_client_request(socket: Arc<UdpSocket>, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>, addr: SocketAddr, msg: Vec<u8>) -> Result<(), IoError> {
-    match handle_client_request_inner(socket, storage_daemon, storage_backend, addr, msg).await {
-        Ok(()) => {}
-        Err(e) => {
-            warn!("Error handling request from {}: {}", addr, e);
-            METRICS.invalid_requests.inc();
+/// Keep the response to a request, in case the client resends it.
+fn remember_response(storage_daemon: &Mutex<StorageDaemon>, client: &ClientKey, key: (u32, u32), response: &Response) {
+    let mut storage_daemon = storage_daemon.lock().unwrap();
+    if let Some(client) = storage_daemon.client_state(client) {
+        if client.responses.len() >= CACHED_RESPONSES {
+            client.responses.pop_front();
         }
+        client.responses.push_back((key, response.clone()));
     }
-    Ok(())
 }
 
 enum Location {
@@ -174,12 +1231,12 @@ enum Location {
 }
 
 fn get_secondaries(map: &StorageMap, storage_daemons: &HashMap<DeviceId, Arc<Mutex<PeerDaemon>>>, group_id: &GroupId) -> Result<Vec<(DeviceId, Arc<Mutex<PeerDaemon>>)>, IoError> {
-    let mut secondaries = Vec::with_capacity(map.replicas as usize - 1);
-    let replicas = map.group_to_devices(group_id, map.replicas as usize);
+    let mut secondaries = Vec::with_capacity((map.replicas as usize).saturating_sub(1));
+    let replicas = map.group_to_replicas(group_id);
     for device_id in replicas.into_iter().skip(1) {
         let peer = storage_daemons
             .get(&device_id)
-            .ok_or(IoError::new(ErrorKind::NotFound, "No address for device"))?
+            .ok_or_else(|| PlacementError::NoAddress(device_id.clone()))?
             .clone();
         secondaries.push((device_id, peer));
     }
@@ -191,7 +1248,7 @@ fn get_location(storage_daemon: Arc<Mutex<StorageDaemon>>, pool_name: &PoolName,
     let device_id = &daemon.device_id;
     let pool = match daemon.pools.get(pool_name) {
         Some(p) => p,
-        None => return Err(IoError::new(ErrorKind::InvalidData, "Unknown pool")),
+        None => return Err(PlacementError::UnknownPool(pool_name.clone()).into()),
     };
 
     // Check that we are responsible for this object
@@ -203,7 +1260,7 @@ fn get_location(storage_daemon: Arc<Mutex<StorageDaemon>>, pool_name: &PoolName,
                 let secondaries = get_secondaries(map, &daemon.storage_daemons, &group_id)?;
                 Ok(Location::HereOrFallback(None, secondaries))
             } else {
-                Err(IoError::new(ErrorKind::Other, "Request was sent to wrong daemon"))
+                Err(ErrorCode::WrongDaemon.into())
             }
         }
         Pool::TransitionPrepare { current, next } => {
@@ -213,24 +1270,23 @@ fn get_location(storage_daemon: Arc<Mutex<StorageDaemon>>, pool_name: &PoolName,
             let current_group_id = current.object_to_group(object_id);
             let current_device = match current.group_to_first_device(&current_group_id) {
                 Some(device_id) => device_id,
-                None => return Err(IoError::new(ErrorKind::InvalidData, "No device for object")),
+                None => return Err(PlacementError::NoDevice.into()),
             };
             if &current_device == device_id {
                 let secondaries = get_secondaries(current, &daemon.storage_daemons, &current_group_id)?;
                 return Ok(Location::HereOrFallback(None, secondaries));
             }
 
-            let next_group_id = next.object_to_group(object_id);
-            let next_device = next.group_to_first_device(&next_group_id);
+            let next_device = next.object_to_first_device(object_id);
             if next_device.as_ref() == Some(device_id) {
                 let current_addr = daemon.storage_daemons
                     .get(&current_device)
-                    .ok_or(IoError::new(ErrorKind::NotFound, "No address for device"))?
+                    .ok_or_else(|| PlacementError::NoAddress(current_device.clone()))?
                     .clone();
                 return Ok(Location::Forward(current_addr));
             }
 
-            Err(IoError::new(ErrorKind::Other, "Request was sent to wrong daemon"))
+            Err(ErrorCode::WrongDaemon.into())
         }
         Pool::Transition { previous, current } => {
             // We are in transition
@@ -239,177 +1295,552 @@ fn get_location(storage_daemon: Arc<Mutex<StorageDaemon>>, pool_name: &PoolName,
             let current_group_id = current.object_to_group(object_id);
             let current_device = current.group_to_first_device(&current_group_id);
             if current_device.as_ref() == Some(device_id) {
-                let previous_group_id = previous.object_to_group(object_id);
-                let previous_device = match previous.group_to_first_device(&previous_group_id) {
+                let previous_device = match previous.object_to_first_device(object_id) {
                     Some(device_id) => device_id,
-                    None => return Err(IoError::new(ErrorKind::InvalidData, "No device for object")),
+                    None => return Err(PlacementError::NoDevice.into()),
                 };
                 let previous_peer = daemon.storage_daemons
                     .get(&previous_device)
-                    .ok_or(IoError::new(ErrorKind::NotFound, "No address for device"))?
+                    .ok_or_else(|| PlacementError::NoAddress(previous_device.clone()))?
                     .clone();
                 let secondaries = get_secondaries(current, &daemon.storage_daemons, &current_group_id)?;
                 Ok(Location::HereOrFallback(Some((previous_device, previous_peer)), secondaries))
             } else {
-                Err(IoError::new(ErrorKind::Other, "Request was sent to wrong daemon"))
+                Err(ErrorCode::WrongDaemon.into())
             }
         }
     }
 }
 
-async fn handle_client_request_inner(socket: Arc<UdpSocket>, storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>, client_addr: SocketAddr, msg: Vec<u8>) -> Result<(), IoError> {
-    let mut reader = Cursor::new(&msg);
-    let msg_ctr = reader.read_u32::<BigEndian>()?;
+/// The operation a capability has to allow for a command.
+fn required_op(command: u8) -> Result<u8, IoError> {
+    match command {
+        OPCODE_READ_OBJECT | OPCODE_READ_PART | OPCODE_STAT_OBJECT | OPCODE_LIST_OBJECTS
+        | OPCODE_STATS | OPCODE_WATCH => Ok(OP_READ),
+        OPCODE_WRITE_OBJECT | OPCODE_WRITE_PART | OPCODE_APPEND_OBJECT
+        | OPCODE_TRUNCATE_OBJECT | OPCODE_COMPARE_AND_SWAP | OPCODE_WRITE_IF_VERSION
+        | OPCODE_SET_TAGS | OPCODE_FLUSH => Ok(OP_WRITE),
+        OPCODE_DELETE_OBJECT => Ok(OP_DELETE),
+        _ => Err(Error::Protocol(format!("Unknown command 0x{:02x} from client", command)).into()),
+    }
+}
+
+/// Whether a command doesn't change anything, so it can be handled again
+/// rather than keeping its response, which can be large.
+fn is_read_only(command: u8) -> bool {
+    matches!(required_op(command), Ok(OP_READ))
+}
+
+/// Check that a capability allows a command on a pool, and return it.
+fn check_capability(keyring: &Keyring, capability: &[u8], pool_name: &PoolName, command: u8) -> Result<Capability, IoError> {
+    let op = required_op(command)?;
+    if capability.is_empty() {
+        return Err(Error::Crypto("Missing capability".to_owned()).into());
+    }
+    let capability = Capability::open(keyring, capability)?;
+    // Stats cover all the pools, the ones the capability allows reading are
+    // picked when answering
+    if command != OPCODE_STATS && !capability.allows(pool_name, op) {
+        return Err(Error::Crypto(format!("Capability of {:?} doesn't allow this request", capability.client_id)).into());
+    }
+    Ok(capability)
+}
+
+/// Whether an object exists before changing it, to tell watchers whether
+/// it is created; `None` if nobody is watching.
+fn exists_if_watched(storage_daemon: &Mutex<StorageDaemon>, storage_backend: &dyn StorageBackend, pool_name: &PoolName, object_id: &ObjectId) -> Result<Option<bool>, IoError> {
+    if !storage_daemon.lock().unwrap().events.is_watched() {
+        return Ok(None);
+    }
+    Ok(Some(storage_backend.object_size(pool_name, object_id)?.is_some()))
+}
+
+/// Tell watchers about a change to an object.
+fn record_event(storage_daemon: &Mutex<StorageDaemon>, pool_name: &PoolName, kind: EventKind, object_id: &ObjectId) {
+    storage_daemon.lock().unwrap().events.record(pool_name, kind, object_id);
+}
+
+/// Tell watchers about a write, using whether the object existed before.
+fn record_write(storage_daemon: &Mutex<StorageDaemon>, pool_name: &PoolName, object_id: &ObjectId, existed: Option<bool>) {
+    if let Some(existed) = existed {
+        let kind = if existed { EventKind::Updated } else { EventKind::Created };
+        record_event(storage_daemon, pool_name, kind, object_id);
+    }
+}
+
+async fn handle_client_request_inner(storage_daemon: Arc<Mutex<StorageDaemon>>, storage_backend: Arc<dyn StorageBackend>, message: RequestMessage) -> Result<Response, IoError> {
+    let pool_name = &message.pool;
 
-    let pool_name = {
-        let name_len = reader.read_u32::<BigEndian>()? as usize;
-        let mut pool_name = vec![0; name_len];
-        reader.read_exact(&mut pool_name)?;
-        let pool_name = String::from_utf8(pool_name)
-            .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid pool name"))?;
-        PoolName(pool_name)
+    let peer_link = {
+        let storage_daemon = storage_daemon.lock().unwrap();
+        match (&storage_daemon.peer_socket, &storage_daemon.capability_keys) {
+            (Some(socket), Some(keyring)) => Some(PeerLink {
+                socket: socket.clone(),
+                keyring: keyring.clone(),
+                epoch: storage_daemon.epoch,
+            }),
+            _ => None,
+        }
     };
 
-    let command = reader.read_u8()?;
-    match command {
-        0x01 => { // read_object
-            let object_id = {
-                let object_id_len = reader.read_u32::<BigEndian>()? as usize;
-                let mut object_id = vec![0; object_id_len];
-                reader.read_exact(&mut object_id)?;
-                ObjectId(object_id)
-            };
-            debug!("read_object {:?}", object_id);
+    let response = match message.request {
+        Request::ReadObject { ref object_id, if_changed } => {
+            debug!("read_object {:?} {:?}", object_id, if_changed);
 
-            match get_location(storage_daemon, &pool_name, &object_id)? {
+            match get_location(storage_daemon, pool_name, object_id)? {
                 Location::HereOrFallback(fallback, _secondaries) => {
-                    let object = storage_backend.read_object(&pool_name, &object_id)?;
-                    METRICS.reads.inc();
-                    let mut response = Vec::new();
-                    response.write_u32::<BigEndian>(msg_ctr).unwrap();
-                    match object {
-                        Some(data) => {
-                            response.write_u8(1).unwrap();
-                            response.extend_from_slice(&data);
+                    // Read the version first, if the object changes in
+                    // between the client gets an older version and only
+                    // reads it again
+                    let version = storage_backend.get_version(pool_name, object_id)?;
+                    if version.is_some() && version == if_changed {
+                        Response::Unchanged
+                    } else {
+                        let object = storage_backend.read_object(pool_name, object_id)?;
+                        match object {
+                            Some(data) => Response::Object { version: version.unwrap_or(0), data },
+                            // TODO: fallback
+                            None => Response::Error(ErrorCode::NotFound),
                         }
-                        // TODO: fallback
-                        None => response.write_u8(0).unwrap(),
                     }
-                    socket.send_to(&response, client_addr).await?;
                 }
                 Location::Forward(peer) => {
-                    forward_request(&socket, msg_ctr, peer, &msg[4..], client_addr).await?;
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
                 }
             }
         }
-        0x02 => { // read_part
-            let object_id = {
-                let object_id_len = reader.read_u32::<BigEndian>()? as usize;
-                let mut object_id = vec![0; object_id_len];
-                reader.read_exact(&mut object_id)?;
-                ObjectId(object_id)
-            };
-            let offset = reader.read_u32::<BigEndian>()?;
-            let len = reader.read_u32::<BigEndian>()?;
+        Request::ReadPart { ref object_id, offset, len } => {
             debug!("read_part {:?} {} {}", object_id, offset, len);
 
-            match get_location(storage_daemon, &pool_name, &object_id)? {
+            match get_location(storage_daemon, pool_name, object_id)? {
                 Location::HereOrFallback(fallback, _secondaries) => {
-                    let object = storage_backend.read_part(&pool_name, &object_id, offset as usize, len as usize)?;
-                    METRICS.reads.inc();
-                    let mut response = Vec::new();
-                    response.write_u32::<BigEndian>(msg_ctr).unwrap();
+                    let object = storage_backend.read_part(pool_name, object_id, offset as usize, len as usize)?;
                     match object {
-                        Some(data) => {
-                            response.write_u8(1).unwrap();
-                            response.extend_from_slice(&data);
-                        }
+                        Some(data) => Response::Data(data),
                         // TODO: fallback
-                        None => response.write_u8(0).unwrap(),
+                        None => Response::Error(ErrorCode::NotFound),
                     }
-                    socket.send_to(&response, client_addr).await?;
                 }
                 Location::Forward(peer) => {
-                    forward_request(&socket, msg_ctr, peer, &msg[4..], client_addr).await?;
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
                 }
             }
         }
-        0x03 => { // write_object
-            let object_id = {
-                let object_id_len = reader.read_u32::<BigEndian>()? as usize;
-                let mut object_id = vec![0; object_id_len];
-                reader.read_exact(&mut object_id)?;
-                ObjectId(object_id)
-            };
-            let data = &msg[reader.position() as usize..];
+        Request::WriteObject { ref object_id, ref data } => {
             debug!("write_object {:?} {}", object_id, data.len());
 
-            match get_location(storage_daemon, &pool_name, &object_id)? {
-                Location::HereOrFallback(_fallback, _secondaries) => {
-                    storage_backend.write_object(&pool_name, &object_id, data)?;
-                    METRICS.writes.inc();
-                    // TODO: replicate to secondaries
-                    let mut response = Vec::new();
-                    response.write_u32::<BigEndian>(msg_ctr).unwrap();
-                    socket.send_to(&response, client_addr).await?;
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    let existed = exists_if_watched(&storage_daemon, &*storage_backend, pool_name, object_id)?;
+                    let version = storage_backend.write_object(pool_name, object_id, data)?;
+                    record_write(&storage_daemon, pool_name, object_id, existed);
+                    replicate(peer_link.as_ref(), secondaries, &message, message.request.clone()).await;
+                    Response::Written { version }
                 }
                 Location::Forward(peer) => {
-                    forward_request(&socket, msg_ctr, peer, &msg[4..], client_addr).await?;
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
                 }
             }
         }
-        0x04 => { // write_part
-            let object_id = {
-                let object_id_len = reader.read_u32::<BigEndian>()? as usize;
-                let mut object_id = vec![0; object_id_len];
-                reader.read_exact(&mut object_id)?;
-                ObjectId(object_id)
-            };
-
-            let offset = reader.read_u32::<BigEndian>()? as usize;
-            let data = &msg[reader.position() as usize..];
+        Request::WritePart { ref object_id, offset, ref data } => {
             debug!("write_part {:?} {} {}", object_id, offset, data.len());
 
-            match get_location(storage_daemon, &pool_name, &object_id)? {
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
                 Location::HereOrFallback(fallback, secondaries) => {
                     // TODO: fallback
-                    storage_backend.write_part(&pool_name, &object_id, offset, data)?;
-                    METRICS.writes.inc();
-                    // TODO: replicate to secondaries
-                    let mut response = Vec::new();
-                    response.write_u32::<BigEndian>(msg_ctr).unwrap();
-                    socket.send_to(&response, client_addr).await?;
+                    let existed = exists_if_watched(&storage_daemon, &*storage_backend, pool_name, object_id)?;
+                    let version = storage_backend.write_part(pool_name, object_id, offset as usize, data)?;
+                    record_write(&storage_daemon, pool_name, object_id, existed);
+                    replicate_object(peer_link.as_ref(), secondaries, &message, &*storage_backend).await?;
+                    Response::Written { version }
                 }
                 Location::Forward(peer) => {
-                    forward_request(&socket, msg_ctr, peer, &msg[4..], client_addr).await?;
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
                 }
             }
         }
-        0x05 => { // delete_object
-            let object_id = {
-                let object_id_len = reader.read_u32::<BigEndian>()? as usize;
-                let mut object_id = vec![0; object_id_len];
-                reader.read_exact(&mut object_id)?;
-                ObjectId(object_id)
-            };
+        Request::DeleteObject { ref object_id } => {
             debug!("delete_object {:?}", object_id);
 
-            storage_backend.delete_object(&pool_name, &object_id)?;
-            METRICS.writes.inc();
-            let mut response = Vec::new();
-            response.write_u32::<BigEndian>(msg_ctr).unwrap();
-            socket.send_to(&response, client_addr).await?;
+            let existed = exists_if_watched(&storage_daemon, &*storage_backend, pool_name, object_id)?;
+            storage_backend.delete_object(pool_name, object_id)?;
+            if existed == Some(true) {
+                record_event(&storage_daemon, pool_name, EventKind::Deleted, object_id);
+            }
+
+            // Deletes are done wherever they are sent, the secondaries are
+            // only known on the primary
+            if let Ok(Location::HereOrFallback(_, secondaries)) = get_location(storage_daemon.clone(), pool_name, object_id) {
+                replicate(peer_link.as_ref(), secondaries, &message, message.request.clone()).await;
+            }
+            Response::Done
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::StatObject { ref object_id } => {
+            debug!("stat_object {:?}", object_id);
+
+            match get_location(storage_daemon, pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, _secondaries) => {
+                    let size = storage_backend.object_size(pool_name, object_id)?;
+                    match size {
+                        Some(size) => {
+                            let expires = storage_backend.get_expiry(pool_name, object_id)?;
+                            let version = storage_backend.get_version(pool_name, object_id)?.unwrap_or(0);
+                            let tags = storage_backend.get_tags(pool_name, object_id)?;
+                            Response::Stat { size, expires, version, tags }
+                        }
+                        // TODO: fallback
+                        None => Response::Error(ErrorCode::NotFound),
+                    }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::ListObjects { ref prefix, ref start_after, limit, ref tag } => {
+            debug!("list_objects {:?} {} {:?}", String::from_utf8_lossy(prefix), limit, tag);
+
+            // Only list pools we are part of
+            if !storage_daemon.lock().unwrap().pools.contains_key(pool_name) {
+                return Err(PlacementError::UnknownPool(pool_name.clone()).into());
+            }
+
+            // Objects are spread over daemons, so this only lists ours
+            let limit = (limit as usize).min(MAX_LIST_OBJECTS);
+            let mut object_ids = match tag {
+                None => storage_backend.list_objects(pool_name, prefix, start_after.as_ref(), limit)?,
+                Some(tag) => list_tagged_objects(&*storage_backend, pool_name, prefix, start_after.as_ref(), limit, tag)?,
+            };
+
+            // Keep the response in a datagram
+            let mut size = 0;
+            if let Some(pos) = object_ids.iter().position(|o| {
+                size += 4 + o.0.len();
+                size > MAX_LIST_SIZE
+            }) {
+                object_ids.truncate(pos);
+            }
+            Response::List(object_ids)
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::AppendObject { ref object_id, ref data } => {
+            debug!("append_object {:?} {}", object_id, data.len());
+
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    let existed = exists_if_watched(&storage_daemon, &*storage_backend, pool_name, object_id)?;
+                    let version = storage_backend.append_object(pool_name, object_id, data)?;
+                    record_write(&storage_daemon, pool_name, object_id, existed);
+                    replicate_object(peer_link.as_ref(), secondaries, &message, &*storage_backend).await?;
+                    Response::Written { version }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::TruncateObject { ref object_id, len } => {
+            debug!("truncate_object {:?} {}", object_id, len);
+
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    let version = storage_backend.truncate_object(pool_name, object_id, len as usize)?;
+                    match version {
+                        Some(version) => {
+                            record_event(&storage_daemon, pool_name, EventKind::Updated, object_id);
+                            replicate_object(peer_link.as_ref(), secondaries, &message, &*storage_backend).await?;
+                            Response::Written { version }
+                        }
+                        None => Response::Error(ErrorCode::NotFound),
+                    }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::CompareAndSwap { ref object_id, ref expected, ref data } => {
+            debug!("compare_and_swap {:?} {}", object_id, data.len());
+
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    let version = storage_backend.compare_and_swap(pool_name, object_id, expected.as_deref(), data)?;
+                    match version {
+                        Some(version) => {
+                            let kind = if expected.is_some() { EventKind::Updated } else { EventKind::Created };
+                            record_event(&storage_daemon, pool_name, kind, object_id);
+                            replicate_object(peer_link.as_ref(), secondaries, &message, &*storage_backend).await?;
+                            Response::Written { version }
+                        }
+                        None => Response::Error(ErrorCode::Conflict),
+                    }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::WriteIfVersion { ref object_id, version, ref data } => {
+            debug!("write_if_version {:?} {:?} {}", object_id, version, data.len());
+
+            match get_location(storage_daemon.clone(), pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    let expected = version;
+                    let version = storage_backend.write_if_version(pool_name, object_id, expected, data)?;
+                    match version {
+                        Some(version) => {
+                            let kind = if expected.is_some() { EventKind::Updated } else { EventKind::Created };
+                            record_event(&storage_daemon, pool_name, kind, object_id);
+                            replicate_object(peer_link.as_ref(), secondaries, &message, &*storage_backend).await?;
+                            Response::Written { version }
+                        }
+                        None => Response::Error(ErrorCode::Conflict),
+                    }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        Request::Flush => {
+            debug!("flush");
+
+            if !storage_daemon.lock().unwrap().pools.contains_key(pool_name) {
+                return Err(PlacementError::UnknownPool(pool_name.clone()).into());
+            }
+
+            // This syncs the whole backend, not only this pool
+            storage_backend.flush()?;
+            Response::Done
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::Stats => {
+            debug!("stats");
+
+            let (device_id, backend_stats, mut pools, keyring) = {
+                let storage_daemon = storage_daemon.lock().unwrap();
+                let pools: Vec<(PoolName, u32)> = storage_daemon.pools.iter().map(|(name, pool)| {
+                    let map = match pool {
+                        Pool::Normal(map)
+                        | Pool::TransitionPrepare { current: map, .. }
+                        | Pool::Transition { current: map, .. } => map,
+                    };
+                    (name.clone(), map.replicas)
+                }).collect();
+                (
+                    storage_daemon.device_id.clone(),
+                    storage_daemon.backend_stats.clone(),
+                    pools,
+                    storage_daemon.capability_keys.clone(),
+                )
+            };
+            if let Some(keyring) = keyring {
+                let capability = Capability::open(&keyring, &message.capability)?;
+                pools.retain(|(name, _)| capability.allows(name, OP_READ));
+            }
+            pools.sort_by(|a, b| a.0.0.cmp(&b.0.0));
+
+            // Counting goes over every object, don't do it for every request
+            let stats = match backend_stats {
+                Some((counted, stats)) if counted.elapsed() < STATS_MAX_AGE => stats,
+                _ => {
+                    let backend = storage_backend.clone();
+                    let stats = tokio::task::spawn_blocking(move || backend.stats()).await.map_err(IoError::other)??;
+                    storage_daemon.lock().unwrap().backend_stats = Some((Instant::now(), stats.clone()));
+                    stats
+                }
+            };
+            let pools = pools.into_iter().map(|(pool, replicas)| {
+                let usage = stats.pools.get(&pool).copied().unwrap_or_default();
+                PoolUsage { pool, replicas, objects: usage.objects, bytes: usage.bytes }
+            }).collect();
+            Response::Stats { device_id, free_space: stats.free_space, pools }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::Watch { ref prefix, after } => {
+            debug!("watch {:?} {:?}", String::from_utf8_lossy(prefix), after);
+
+            let mut storage_daemon = storage_daemon.lock().unwrap();
+            if !storage_daemon.pools.contains_key(pool_name) {
+                return Err(PlacementError::UnknownPool(pool_name.clone()).into());
+            }
+
+            // Only our objects, the client asks every daemon
+            let (next, lost, events) = storage_daemon.events.watch(pool_name, prefix, after);
+            Response::Events { next, lost, events }
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::SetTags { ref object_id, ref tags } => {
+            debug!("set_tags {:?} {:?}", object_id, tags);
+
+            match get_location(storage_daemon, pool_name, object_id)? {
+                Location::HereOrFallback(_fallback, secondaries) => {
+                    if storage_backend.set_tags(pool_name, object_id, tags)? {
+                        replicate(peer_link.as_ref(), secondaries, &message, message.request.clone()).await;
+                        Response::Done
+                    } else {
+                        Response::Error(ErrorCode::NotFound)
+                    }
+                }
+                Location::Forward(peer) => {
+                    return forward_request(peer_link.as_ref(), peer, &message).await;
+                }
+            }
+        }
+        // Answered before getting here
+        Request::Handshake { .. } => return Err(ErrorCode::InvalidRequest.into()),
+        #[cfg(not(feature = "extended-ops"))]
+        Request::StatObject { .. }
+        | Request::ListObjects { .. }
+        | Request::AppendObject { .. }
+        | Request::TruncateObject { .. }
+        | Request::CompareAndSwap { .. }
+        | Request::WriteIfVersion { .. }
+        | Request::SetTags { .. }
+        | Request::Stats
+        | Request::Watch { .. } => return Err(ErrorCode::Unsupported.into()),
+    };
+
+    Ok(response)
+}
+
+/// List the objects that have a tag, going through the backend's list until
+/// there are `limit` of them or there are no more objects.
+///
+/// Without an index of tags, this reads the tags of every object after
+/// `start_after`.
+#[cfg(feature = "extended-ops")]
+fn list_tagged_objects(
+    storage_backend: &dyn StorageBackend,
+    pool_name: &PoolName,
+    prefix: &[u8],
+    start_after: Option<&ObjectId>,
+    limit: usize,
+    tag: &str,
+) -> Result<Vec<ObjectId>, IoError> {
+    let mut object_ids = Vec::new();
+    let mut after = start_after.cloned();
+    while object_ids.len() < limit {
+        let page = storage_backend.list_objects(pool_name, prefix, after.as_ref(), MAX_LIST_OBJECTS)?;
+        let last = match page.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        for object_id in page {
+            if object_ids.len() < limit && storage_backend.get_tags(pool_name, &object_id)?.iter().any(|t| t == tag) {
+                object_ids.push(object_id);
+            }
+        }
+        after = Some(last);
+    }
+    Ok(object_ids)
+}
+
+/// What is needed to forward requests to other daemons.
+#[derive(Clone)]
+struct PeerLink {
+    socket: Arc<Socket>,
+    keyring: Arc<Keyring>,
+    /// Our epoch, sent with the requests we forward.
+    epoch: u32,
+}
+
+/// Forward a request to the daemon that should handle it, and get its response.
+async fn forward_request(peer_link: Option<&PeerLink>, peer: Arc<Mutex<PeerDaemon>>, message: &RequestMessage) -> Result<Response, IoError> {
+    // Never send the request in the clear
+    let peer_link = match peer_link {
+        Some(l) => l,
+        None => return Err(NetworkError::NotConnected("Can't forward request without a keyring".to_owned()).into()),
+    };
+    send_to_peer(peer_link, peer, PEER_REQUEST, message).await
+}
+
+/// Apply a change to the secondaries of an object, after applying it here.
+///
+/// This waits for them, but failures are only logged: the change is done
+/// here already. Without a keyring nothing is sent, rather than sending the
+/// data in the clear.
+async fn replicate(peer_link: Option<&PeerLink>, secondaries: Vec<(DeviceId, Arc<Mutex<PeerDaemon>>)>, message: &RequestMessage, request: Request) {
+    if secondaries.is_empty() {
+        return;
+    }
+    let peer_link = match peer_link {
+        Some(l) => l,
+        None => {
+            debug!("Can't replicate without a keyring");
+            return;
+        }
+    };
+
+    let replica = RequestMessage { request, ..message.clone() };
+    let tasks: Vec<_> = secondaries.into_iter().map(|(device_id, peer)| {
+        let (peer_link, replica) = (peer_link.clone(), replica.clone());
+        tokio::spawn(async move {
+            (device_id, send_to_peer(&peer_link, peer, PEER_REPLICATE, &replica).await)
+        })
+    }).collect();
+    for task in tasks {
+        match task.await {
+            Ok((device_id, Ok(Response::Error(code)))) => warn!("Error replicating to {:?}: {}", device_id, code),
+            Ok((device_id, Err(e))) => warn!("Error replicating to {:?}: {}", device_id, e),
+            Ok((_, Ok(_))) => {}
+            Err(e) => warn!("Error replicating: {}", e),
         }
-        _ => return Err(IoError::new(
-            ErrorKind::InvalidData,
-            format!("Unknown command 0x{:02x} from client", command),
-        )),
     }
+}
 
+/// Bring the copies of an object on its secondaries to what we have, after
+/// changing it here.
+async fn replicate_object(peer_link: Option<&PeerLink>, secondaries: Vec<(DeviceId, Arc<Mutex<PeerDaemon>>)>, message: &RequestMessage, storage_backend: &dyn StorageBackend) -> Result<(), IoError> {
+    let object_id = match message.request.object_id() {
+        Some(o) if !secondaries.is_empty() => o,
+        _ => return Ok(()),
+    };
+    let request = match storage_backend.read_object(&message.pool, object_id)? {
+        Some(data) => Request::WriteObject { object_id: object_id.clone(), data },
+        None => Request::DeleteObject { object_id: object_id.clone() },
+    };
+    replicate(peer_link, secondaries, message, request).await;
     Ok(())
 }
 
-async fn forward_request(socket: &UdpSocket, client_ctr: u32, peer: Arc<Mutex<PeerDaemon>>, request: &[u8], client_addr: SocketAddr) -> Result<(), IoError> {
+/// Apply a change replicated by the primary of an object, see `replicate()`.
+///
+/// The primary already recorded the event, watchers don't get it twice.
+fn apply_replica(storage_daemon: &Mutex<StorageDaemon>, storage_backend: &dyn StorageBackend, message: &RequestMessage) -> Result<Response, IoError> {
+    let pool_name = &message.pool;
+    if !storage_daemon.lock().unwrap().pools.contains_key(pool_name) {
+        return Err(PlacementError::UnknownPool(pool_name.clone()).into());
+    }
+    debug!("replica of {} {:?}", message.request.name(), message.request.object_id());
+    match message.request {
+        Request::WriteObject { ref object_id, ref data } => {
+            let version = storage_backend.write_object(pool_name, object_id, data)?;
+            Ok(Response::Written { version })
+        }
+        Request::DeleteObject { ref object_id } => {
+            storage_backend.delete_object(pool_name, object_id)?;
+            Ok(Response::Done)
+        }
+        #[cfg(feature = "extended-ops")]
+        Request::SetTags { ref object_id, ref tags } => {
+            if storage_backend.set_tags(pool_name, object_id, tags)? {
+                Ok(Response::Done)
+            } else {
+                Ok(Response::Error(ErrorCode::NotFound))
+            }
+        }
+        _ => Err(ErrorCode::InvalidRequest.into()),
+    }
+}
+
+/// Send a sealed request to another daemon, and get its response.
+async fn send_to_peer(peer_link: &PeerLink, peer: Arc<Mutex<PeerDaemon>>, kind: u8, message: &RequestMessage) -> Result<Response, IoError> {
     let (address, counter, new_request, mut recv) = {
         let mut peer_locked = peer.lock().unwrap();
         let address = peer_locked.address.clone();
@@ -418,10 +1849,13 @@ async fn forward_request(socket: &UdpSocket, client_ctr: u32, peer: Arc<Mutex<Pe
         let counter = peer_locked.counter;
         peer_locked.counter += 1;
 
-        // Assemble the request
-        let mut new_request = Vec::with_capacity(4 + request.len());
-        new_request.write_u32::<BigEndian>(counter).unwrap();
-        new_request.extend_from_slice(request);
+        // Assemble the request, with our counter and epoch
+        let new_request = RequestMessage {
+            counter,
+            epoch: peer_link.epoch,
+            ..message.clone()
+        };
+        let new_request = peer_locked.seal(&peer_link.keyring, kind, &new_request.encode());
 
         // Register our counter to get the response
         let (send, recv) = channel();
@@ -429,26 +1863,500 @@ async fn forward_request(socket: &UdpSocket, client_ctr: u32, peer: Arc<Mutex<Pe
 
         // Unlock the mutex during network operations
 
-        debug!("Sending forwarded request {}, size {}", counter, new_request.len());
+        debug!(
+            "Sending forwarded request {}, size {}, trace {}",
+            counter, new_request.len(), TraceLabel(message.trace_id),
+        );
         (address, counter, new_request, recv)
     };
 
     // Send the request
-    socket.send_to(&new_request, address).await?;
+    peer_link.socket.send_to(&new_request, address).await?;
 
     // Wait for the response
-    let mut response = tokio::select! {
+    let response = tokio::select! {
         response = &mut recv => response.unwrap(),
         _ = tokio::time::sleep(TIMEOUT) => {
             debug!("Timeout forwarding request {}", counter);
-            return Err(IoError::new(ErrorKind::TimedOut, "Timeout waiting for response to forwarded request"));
+            let mut peer = peer.lock().unwrap();
+            peer.response_channels.remove(&counter);
+            peer.partial_responses.remove(&counter);
+            return Err(NetworkError::TimedOut("Timeout waiting for response to forwarded request".to_owned()).into());
         }
     };
 
-    // Send response to client
-    Cursor::new(&mut response[0..4]).write_u32::<BigEndian>(client_ctr).unwrap();
-    debug!("Sending forwarded response to client, size {}", response.len());
-    socket.send_to(&response, client_addr).await?;
+    debug!("Got forwarded response {}, trace {}", counter, TraceLabel(message.trace_id));
+    Ok(response)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    use crate::{DeviceId, ObjectId, PoolName};
+    use crate::crypto::KeyPair;
+    use crate::crypto::capability::{Capability, OP_READ, OP_WRITE, encode_credentials};
+    use crate::crypto::envelope::{RequestHeader, open_response, seal_request};
+    use crate::crypto::epoch::clock_epoch;
+    use crate::crypto::keyring::Keyring;
+    use crate::crypto::peer::{PEER_REPLICATE, PEER_REQUEST, open_peer_message};
+    use crate::crypto::session::issue_ticket;
+    use crate::client::{VersionedRead, create_client_with_socket};
+    use crate::netsim::{SimConfig, SimNetwork, SimStats, Socket};
+    use crate::storage::StorageBackend;
+    use crate::storage::mem_store::MemStore;
+    use crate::proto::wire::{
+        ChunkAssembler, Request, RequestMessage, Response, ResponseFrame, ResponseMessage, TraceId, check_checksum,
+    };
+    use super::{
+        MAX_RESPONSE_SIZE, Accepted, Opened, PeerDaemon, PeerLink, Reply, ReplyPath, RequestPath, StorageDaemon, accept_request,
+        apply_replica, check_session_header, count_requests, forward_request, open_session_request, replicate,
+        spawn_test_daemon, spawn_test_daemon_with_keys,
+    };
+
+    #[test]
+    fn test_count_requests() {
+        let opts = prometheus::Opts::new("requests", "Requests");
+        let requests = prometheus::IntCounterVec::new(opts, &["pool", "op", "result"]).unwrap();
+        assert_eq!(count_requests(&requests), (0, 0));
+        requests.with_label_values(&["a", "read_object", "ok"]).inc_by(3);
+        requests.with_label_values(&["b", "read_object", "ok"]).inc();
+        requests.with_label_values(&["a", "write_object", "not_found"]).inc_by(2);
+        requests.with_label_values(&["", "read_object", "unauthorized"]).inc();
+        assert_eq!(count_requests(&requests), (7, 3));
+    }
+
+    #[test]
+    fn test_accept_request() {
+        let keyring = Keyring::new(KeyPair::generate());
+        let address = "127.0.0.1:4000".parse().unwrap();
+        let daemon = StorageDaemon::new(DeviceId([1; 16]), address, address, Some(keyring.clone()), clock_epoch());
+        let daemon = Mutex::new(daemon);
+        let capability = |client_id: &str| {
+            Capability::new(client_id.to_owned(), vec![], OP_WRITE, Duration::from_secs(60)).seal(&keyring)
+        };
+        let request = |capability: &[u8], epoch, counter| RequestMessage {
+            counter,
+            epoch,
+            trace_id: None,
+            capability: capability.to_vec(),
+            pool: PoolName("default".to_owned()),
+            request: Request::WriteObject { object_id: ObjectId(b"obj".to_vec()), data: vec![] },
+        };
+        let client = "10.0.0.2:5000".parse().unwrap();
+        let epoch = clock_epoch();
+        let (alice, mallory) = (capability("alice"), capability("mallory"));
+
+        // Capabilities in plain datagrams are refused, they could have been
+        // read on the way
+        assert!(accept_request(&daemon, client, RequestPath::Plain, &request(&alice, epoch, 1)).is_err());
+
+        assert!(matches!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&alice, epoch, 1)), Ok(Accepted::New(_))));
+        assert!(matches!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&alice, epoch, 1)), Ok(Accepted::Replayed)));
+
+        // Requests without a valid capability don't move the window
+        assert!(accept_request(&daemon, client, RequestPath::Authenticated, &request(b"forged", epoch + 1, 1000)).is_err());
+        assert!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&[], epoch + 1, 1000)).is_err());
+
+        // Nor do requests with the capability of another client
+        assert!(matches!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&mallory, epoch + 1, 1000)), Ok(Accepted::New(_))));
+        assert!(matches!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&alice, epoch, 2)), Ok(Accepted::New(_))));
+
+        // Epochs far ahead of the clock are rejected
+        assert!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&alice, epoch + 7 * 24 * 3600, 3)).is_err());
+        assert!(matches!(accept_request(&daemon, client, RequestPath::Authenticated, &request(&alice, epoch, 3)), Ok(Accepted::New(_))));
+    }
+
+    #[tokio::test]
+    async fn test_forward_sealed() {
+        let keyring = Arc::new(Keyring::new(KeyPair::generate()));
+        let our_socket = Arc::new(Socket::Udp(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let peer_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = Arc::new(Mutex::new(PeerDaemon {
+            address: peer_socket.local_addr().unwrap(),
+            counter: 0,
+            seal_counter: 0,
+            response_channels: HashMap::new(),
+            partial_responses: HashMap::new(),
+        }));
+        let reply = Reply {
+            addr: client_socket.local_addr().unwrap(),
+            path: ReplyPath::Datagram { socket: our_socket.clone(), seal: None },
+        };
+        let data = b"some object data to replicate";
+        let request = RequestMessage {
+            counter: 42,
+            epoch: 1000,
+            trace_id: Some(TraceId(0xabc)),
+            capability: vec![],
+            pool: PoolName("pool".to_owned()),
+            request: Request::WriteObject { object_id: ObjectId(b"obj".to_vec()), data: data.to_vec() },
+        };
+
+        // Without keys, the request is not forwarded
+        assert!(forward_request(None, peer.clone(), &request).await.is_err());
+
+        let link = PeerLink { socket: our_socket.clone(), keyring: keyring.clone(), epoch: 7 };
+        let forward = forward_request(Some(&link), peer.clone(), &request);
+        let peer_side = async {
+            let mut buf = [0; 65536];
+            let (len, _) = peer_socket.recv_from(&mut buf).await.unwrap();
+            let sealed = &buf[0..len];
+
+            // The payload is not sent in the clear
+            assert!(!sealed.windows(16).any(|w| data.windows(16).any(|r| r == w)));
+
+            let (kind, msg) = open_peer_message(&keyring, sealed).unwrap();
+            assert_eq!(kind, PEER_REQUEST);
+            let forwarded = RequestMessage::decode(&msg).unwrap();
+            assert_eq!((forwarded.counter, forwarded.epoch), (0, 7));
+            assert_eq!(forwarded.trace_id, Some(TraceId(0xabc)));
+            assert_eq!(forwarded.request, request.request);
+
+            // Deliver the response, like serve_peers()
+            let (_, channel) = peer.lock().unwrap().response_channels.remove(&0).unwrap();
+            channel.send(Response::Data(b"x".to_vec())).unwrap();
+        };
+        let (result, ()) = tokio::join!(forward, peer_side);
+        let response = result.unwrap();
+        assert_eq!(response, Response::Data(b"x".to_vec()));
+
+        // The client gets the response with its counter
+        let message = ResponseMessage { counter: request.counter, trace_id: request.trace_id, response };
+        reply.send(&message).await.unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = client_socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            ResponseMessage::decode(check_checksum(&buf[0..len]).unwrap()).unwrap(),
+            ResponseMessage { counter: 42, trace_id: Some(TraceId(0xabc)), response: Response::Data(b"x".to_vec()) },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replicate_sealed() {
+        let keyring = Arc::new(Keyring::new(KeyPair::generate()));
+        let our_socket = Arc::new(Socket::Udp(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let secondary_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let secondary = Arc::new(Mutex::new(PeerDaemon {
+            address: secondary_socket.local_addr().unwrap(),
+            counter: 0,
+            seal_counter: 0,
+            response_channels: HashMap::new(),
+            partial_responses: HashMap::new(),
+        }));
+        let secondaries = || vec![(DeviceId([2; 16]), secondary.clone())];
+        let data = b"some object data to replicate";
+        let request = RequestMessage {
+            counter: 42,
+            epoch: 1000,
+            trace_id: None,
+            capability: vec![],
+            pool: PoolName("default".to_owned()),
+            request: Request::WriteObject { object_id: ObjectId(b"obj".to_vec()), data: data.to_vec() },
+        };
+
+        // Without keys, nothing is sent
+        replicate(None, secondaries(), &request, request.request.clone()).await;
+        assert_eq!(secondary.lock().unwrap().seal_counter, 0);
+
+        let link = PeerLink { socket: our_socket.clone(), keyring: keyring.clone(), epoch: 7 };
+        let storage_daemon = Mutex::new(StorageDaemon::new(DeviceId([2; 16]), our_socket.local_addr().unwrap(), our_socket.local_addr().unwrap(), None, clock_epoch()));
+        let storage_backend = MemStore::default();
+        for expected_counter in 0..2 {
+            let replication = replicate(Some(&link), secondaries(), &request, request.request.clone());
+            let secondary_side = async {
+                let mut buf = [0; 65536];
+                let (len, _) = secondary_socket.recv_from(&mut buf).await.unwrap();
+                let sealed = &buf[0..len];
+
+                // The payload is never sent in the clear
+                assert!(!sealed.windows(16).any(|w| data.windows(16).any(|r| r == w)));
+
+                let (kind, msg) = open_peer_message(&keyring, sealed).unwrap();
+                assert_eq!(kind, PEER_REPLICATE);
+                let replica = RequestMessage::decode(&msg).unwrap();
+                assert_eq!((replica.counter, replica.epoch), (expected_counter, 7));
+                assert_eq!(replica.request, request.request);
+
+                // Apply it, like serve_peers()
+                let response = apply_replica(&storage_daemon, &storage_backend, &replica).unwrap();
+                let (_, channel) = secondary.lock().unwrap().response_channels.remove(&expected_counter).unwrap();
+                channel.send(response).unwrap();
+            };
+            tokio::join!(replication, secondary_side);
+        }
+
+        // Each message was sealed with a different counter
+        assert_eq!(secondary.lock().unwrap().seal_counter, 2);
+        assert_eq!(
+            storage_backend.read_object(&PoolName("default".to_owned()), &ObjectId(b"obj".to_vec())).unwrap(),
+            Some(data.to_vec()),
+        );
+
+        // Only changes are replicated
+        let read = RequestMessage { request: Request::ReadObject { object_id: ObjectId(b"obj".to_vec()), if_changed: None }, ..request };
+        assert!(apply_replica(&storage_daemon, &storage_backend, &read).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reply_sealed_for_session() {
+        let network = SimNetwork::new(5, SimConfig {
+            loss: 0.0,
+            duplication: 0.0,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+        });
+        let keys = KeyPair::generate();
+        let our_socket = Arc::new(Socket::Sim(network.bind("10.0.0.1:4000".parse().unwrap()).unwrap()));
+        let client_address = "10.0.0.2:5000".parse().unwrap();
+        let client_socket = network.bind(client_address).unwrap();
+        let mut reply = Reply {
+            addr: client_address,
+            path: ReplyPath::Datagram { socket: our_socket, seal: None },
+        };
+        let header = RequestHeader {
+            session: 7,
+            epoch: 1000,
+            counter: 42,
+            pool: PoolName("pool".to_owned()),
+            opcode: 0x01,
+        };
+        reply.seal_for_session(keys.clone(), header.clone());
+
+        // A response big enough to be split, every chunk is sealed
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let response = Response::Data(data.clone());
+        reply.send(&ResponseMessage { counter: 42, trace_id: None, response: response.clone() }).await.unwrap();
+        let windows: HashSet<&[u8]> = data.windows(16).collect();
+        let mut assembler = ChunkAssembler::new(MAX_RESPONSE_SIZE);
+        let mut buf = vec![0; 65536];
+        let message = loop {
+            let (len, _) = client_socket.recv_from(&mut buf).await.unwrap();
+            let sealed = &buf[0..len];
+            assert!(!sealed.windows(16).any(|w| windows.contains(w)));
+            let frame = open_response(&keys, &header, sealed).unwrap();
+            match ResponseFrame::decode(&frame).unwrap() {
+                ResponseFrame::Chunk(chunk) => {
+                    if let Some(message) = assembler.add(chunk).unwrap() {
+                        break message;
+                    }
+                }
+                ResponseFrame::Whole(_) => panic!("response not split"),
+            }
+        };
+        assert_eq!(message, ResponseMessage { counter: 42, trace_id: None, response });
+    }
+
+    /// Run requests over a network losing, duplicating and reordering
+    /// datagrams, returning what it did.
+    async fn simulated_requests(seed: u64) -> SimStats {
+        let network = SimNetwork::new(seed, SimConfig {
+            loss: 0.3,
+            duplication: 0.3,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(50),
+        });
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+
+        let client_socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(client_socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+
+        // Requests are resent until answered, and duplicates are answered
+        // again rather than handled twice
+        let big: Vec<u8> = (0..200000u32).map(|i| i as u8).collect();
+        for i in 0..20 {
+            let object_id = ObjectId(format!("obj{}", i).into_bytes());
+            client.write_object(&object_id, &big[0..i * 3000]).await.unwrap();
+        }
+        for i in 0..20 {
+            let object_id = ObjectId(format!("obj{}", i).into_bytes());
+            assert_eq!(client.read_object(&object_id).await.unwrap().as_deref(), Some(&big[0..i * 3000]));
+        }
+
+        // Versions go up with writes, and reads can skip unchanged objects
+        let object_id = ObjectId(b"obj1".to_vec());
+        let version = match client.read_versioned(&object_id, None).await.unwrap() {
+            VersionedRead::Object { version, data } => {
+                assert_eq!(data, &big[0..3000]);
+                version
+            }
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(client.read_versioned(&object_id, Some(version)).await.unwrap(), VersionedRead::Unchanged);
+        let new_version = client.write_object(&object_id, b"new").await.unwrap();
+        assert!(new_version > version);
+        assert_eq!(
+            client.read_versioned(&object_id, Some(version)).await.unwrap(),
+            VersionedRead::Object { version: new_version, data: b"new".to_vec() },
+        );
+
+        // Tags, which listing can filter on
+        #[cfg(feature = "extended-ops")]
+        {
+            let tmp = vec!["tmp".to_owned()];
+            let (obj2, obj5) = (ObjectId(b"obj2".to_vec()), ObjectId(b"obj5".to_vec()));
+            client.set_tags(&obj2, &tmp).await.unwrap();
+            client.set_tags(&obj5, &tmp).await.unwrap();
+            assert_eq!(client.stat_object(&obj2).await.unwrap().unwrap().tags, tmp);
+            assert_eq!(client.list_all_tagged_objects(b"obj", "tmp").await.unwrap(), vec![obj2.clone(), obj5.clone()]);
+            assert_eq!(client.list_tagged_objects(b"", "tmp", Some(&obj2), 10).await.unwrap(), vec![obj5]);
+            assert!(client.list_all_tagged_objects(b"", "other").await.unwrap().is_empty());
+            let err = client.set_tags(&ObjectId(b"missing".to_vec()), &tmp).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        }
+
+        // Large responses arrive in chunks, out of order
+        let object_id = ObjectId(b"big".to_vec());
+        for (i, part) in big.chunks(40000).enumerate() {
+            client.write_part(&object_id, (i * 40000) as u32, part).await.unwrap();
+        }
+        assert_eq!(client.read_object(&object_id).await.unwrap(), Some(big));
+
+        daemon.abort();
+        network.stats()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_network() {
+        let stats = simulated_requests(42).await;
+        assert!(stats.lost > 0 && stats.duplicated > 0);
+
+        // Runs with the same seed go the same way
+        assert_eq!(simulated_requests(42).await, stats);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session() {
+        let network = SimNetwork::new(3, SimConfig {
+            loss: 0.0,
+            duplication: 0.0,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+        });
+        let keyring = Keyring::new(KeyPair::generate());
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let (storage_daemon, daemon) = spawn_test_daemon_with_keys(network.bind(daemon_address).unwrap(), Some(keyring.clone()));
+
+        let client_socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(client_socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+        let capability = Capability::new("alice".to_owned(), vec![], OP_READ | OP_WRITE, Duration::from_secs(60));
+        client.set_capability(capability.seal(&keyring));
+        let object_id = ObjectId(b"obj".to_vec());
+
+        // The capability alone is refused, it would be sent in the clear
+        assert!(client.write_object(&object_id, b"data").await.is_err());
+
+        // Tickets from another master are refused
+        client.set_ticket(issue_ticket(&Keyring::new(KeyPair::generate()), Duration::from_secs(3600)));
+        assert!(client.write_object(&object_id, b"data").await.is_err());
+        assert!(storage_daemon.lock().unwrap().sessions.is_empty());
+
+        // With a ticket from ours, the client opens a session and keeps it
+        let ticket = issue_ticket(&keyring, Duration::from_secs(3600));
+        client.set_credentials(&encode_credentials(&capability.seal(&keyring), &ticket)).unwrap();
+        client.write_object(&object_id, b"data").await.unwrap();
+        assert_eq!(client.read_object(&object_id).await.unwrap(), Some(b"data".to_vec()));
+        {
+            let storage_daemon = storage_daemon.lock().unwrap();
+            assert_eq!(storage_daemon.sessions.len(), 1);
+            let session = &storage_daemon.sessions.values().next().unwrap().session;
+            assert_eq!(session.expires, ticket.expires);
+
+            // Requests all went in the session
+            assert!(storage_daemon.clients.is_empty());
+        }
+
+        // Sealed requests don't show the data
+        let (id, keys) = {
+            let storage_daemon = storage_daemon.lock().unwrap();
+            let (id, state) = storage_daemon.sessions.iter().next().unwrap();
+            (*id, state.session.keys.clone())
+        };
+        let data = b"some data that stays sealed";
+        let message = RequestMessage {
+            counter: 1000,
+            epoch: clock_epoch(),
+            trace_id: None,
+            capability: capability.seal(&keyring),
+            pool: PoolName("default".to_owned()),
+            request: Request::WriteObject { object_id: object_id.clone(), data: data.to_vec() },
+        };
+        let header = RequestHeader {
+            session: id,
+            epoch: message.epoch,
+            counter: message.counter,
+            pool: message.pool.clone(),
+            opcode: message.request.opcode(),
+        };
+        let sealed = seal_request(&keys, &header, &message.encode());
+        assert!(!sealed.windows(data.len()).any(|w| w == data));
+        let client_address = "10.0.0.2:5000".parse().unwrap();
+        match open_session_request(&storage_daemon, client_address, &sealed) {
+            Opened::Request(opened, _, body) => {
+                assert_eq!(opened, header);
+                assert_eq!(RequestMessage::decode(&body).unwrap(), message);
+            }
+            _ => panic!("sealed request not opened"),
+        }
+        assert!(matches!(accept_request(&storage_daemon, client_address, RequestPath::Session(id), &message), Ok(Accepted::New(_))));
+
+        // Replays, unknown pools and unknown sessions are dropped from the
+        // header, without the keys
+        assert!(matches!(check_session_header(&storage_daemon, client_address, &header), Err(Opened::Dropped)));
+        let other_pool = RequestHeader { counter: 1001, pool: PoolName("other".to_owned()), ..header.clone() };
+        assert!(matches!(check_session_header(&storage_daemon, client_address, &other_pool), Err(Opened::Dropped)));
+        let other_session = RequestHeader { session: id.wrapping_add(1), counter: 1001, ..header.clone() };
+        assert!(matches!(check_session_header(&storage_daemon, client_address, &other_session), Err(Opened::NoSession(1001))));
+        let next = RequestHeader { counter: 1001, ..header.clone() };
+        assert!(check_session_header(&storage_daemon, client_address, &next).is_ok());
+
+        // Once the daemon forgets the session, the client opens another
+        storage_daemon.lock().unwrap().sessions.clear();
+        assert_eq!(client.read_object(&object_id).await.unwrap(), Some(b"data".to_vec()));
+        assert_eq!(storage_daemon.lock().unwrap().sessions.len(), 1);
+
+        daemon.abort();
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_append() {
+        let network = SimNetwork::new(7, SimConfig {
+            loss: 0.5,
+            duplication: 0.3,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(50),
+        });
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+
+        let client_socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(client_socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+
+        // Appends whose response is lost are resent, but only done once
+        let object_id = ObjectId(b"log".to_vec());
+        let mut expected = Vec::new();
+        for i in 0..30u8 {
+            client.append_object(&object_id, &[i]).await.unwrap();
+            expected.push(i);
+        }
+        assert_eq!(client.read_object(&object_id).await.unwrap(), Some(expected));
+
+        // A swap done twice would conflict with itself
+        let object_id = ObjectId(b"count".to_vec());
+        assert!(client.compare_and_swap(&object_id, None, b"0").await.unwrap().is_some());
+        for i in 0..10 {
+            let (old, new) = (i.to_string(), (i + 1).to_string());
+            assert!(client.compare_and_swap(&object_id, Some(old.as_bytes()), new.as_bytes()).await.unwrap().is_some());
+        }
+
+        assert!(network.stats().lost > 0);
+        assert!(super::METRICS.resent_responses.get() > 0);
+        daemon.abort();
+    }
 }
@@ -0,0 +1,437 @@
+//! DTLS transport for the datagrams between clients and storage daemons.
+//!
+//! This is an alternative to sealing requests with the keyring, for
+//! deployments that would rather rely on a standard protocol. The daemon uses
+//! the same PEM certificate and key as for its peer connections, signed by the
+//! CA that clients are given.
+//!
+//! DTLS 1.2 is the minimum version, DTLS 1.3 is negotiated when the OpenSSL
+//! library supports it. Each message is carried in a single record, so it
+//! can't be larger than `MAX_MESSAGE_SIZE`.
+//!
+//! The listener does the cookie exchange: it only answers a new address with
+//! a small HelloVerifyRequest, until the client proves it can receive at that
+//! address, so it can't be used to amplify traffic to spoofed addresses.
+
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::ssl::{
+    Ssl, SslConnector, SslContext, SslFiletype, SslMethod, SslOptions, SslVersion,
+};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_openssl::SslStream;
+
+use crate::udp_batch;
+
+/// Largest message that fits in a record.
+pub const MAX_MESSAGE_SIZE: usize = 16384;
+
+/// MTU given to OpenSSL, large enough for a full record.
+///
+/// Datagrams can be fragmented by IP, like the unencrypted protocol does.
+const MTU: u32 = 17408;
+
+/// How long a handshake can take, including retransmissions.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a handshake in progress is driven while waiting for the peer.
+///
+/// OpenSSL retransmits the last flight of handshake messages when it is
+/// called after its timer expired (1 second, doubling every time), which
+/// doesn't happen by itself while waiting for datagrams.
+const RETRANSMIT_CHECK: Duration = Duration::from_millis(100);
+
+/// Maximum number of sessions a listener keeps track of.
+const MAX_SESSIONS: usize = 4096;
+
+/// Number of established sessions waiting to be accepted.
+const ACCEPT_QUEUE: usize = 16;
+
+/// Number of datagrams buffered for a session before dropping them.
+const SESSION_QUEUE: usize = 64;
+
+fn ssl_error(err: openssl::ssl::Error) -> IoError {
+    err.into_io_error()
+        .unwrap_or_else(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+/// A UDP socket seen as a stream by OpenSSL, each write being a datagram.
+pub struct DatagramStream {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    /// Datagrams from the peer, when the socket is shared by a listener.
+    /// Otherwise, the socket is connected to the peer.
+    incoming: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl AsyncRead for DatagramStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        match this.incoming {
+            Some(ref mut incoming) => match incoming.poll_recv(cx) {
+                Poll::Ready(Some(datagram)) => {
+                    let len = datagram.len().min(buf.remaining());
+                    buf.put_slice(&datagram[..len]);
+                    Poll::Ready(Ok(()))
+                }
+                // The listener is gone, signal the end of the stream
+                Poll::Ready(None) => Poll::Ready(Ok(())),
+                Poll::Pending => Poll::Pending,
+            },
+            None => this.socket.poll_recv(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for DatagramStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+        self.socket.poll_send_to(cx, buf, self.peer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub type DtlsStream = SslStream<DatagramStream>;
+
+fn new_stream(mut ssl: Ssl, stream: DatagramStream) -> Result<DtlsStream, IoError> {
+    ssl.set_mtu(MTU)?;
+    Ok(SslStream::new(ssl, stream)?)
+}
+
+/// Run the handshake, as the client or the server, retransmitting lost
+/// messages.
+async fn handshake(stream: &mut DtlsStream, server: bool) -> Result<(), IoError> {
+    let handshake = async {
+        loop {
+            let step = poll_fn(|cx| {
+                let stream = Pin::new(&mut *stream);
+                if server {
+                    stream.poll_accept(cx)
+                } else {
+                    stream.poll_connect(cx)
+                }
+            });
+            if let Ok(result) = tokio::time::timeout(RETRANSMIT_CHECK, step).await {
+                return result.map_err(ssl_error);
+            }
+        }
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(result) => result,
+        Err(_) => Err(IoError::new(ErrorKind::TimedOut, "DTLS handshake timed out")),
+    }
+}
+
+/// Compute the cookie for a client address.
+fn cookie(secret: &[u8], address: &SocketAddr) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).unwrap();
+    mac.update(address.to_string().as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Open a session with a storage daemon, checking its certificate against
+/// the CA.
+pub async fn connect(address: SocketAddr, ca_cert: &Path) -> Result<DtlsStream, IoError> {
+    let mut builder = SslConnector::builder(SslMethod::dtls())?;
+    builder.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    builder.set_ca_file(ca_cert)?;
+    let connector = builder.build();
+
+    let local: SocketAddr = if address.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    let stream = DatagramStream { socket: Arc::new(socket), peer: address, incoming: None };
+
+    // The daemon's certificate has to be issued for its IP address
+    let ssl = connector.configure()?.into_ssl(&address.ip().to_string())?;
+    let mut stream = new_stream(ssl, stream)?;
+    handshake(&mut stream, false).await?;
+    Ok(stream)
+}
+
+/// Accepts sessions on a UDP socket.
+pub struct DtlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(DtlsStream, SocketAddr)>,
+    task: tokio::task::JoinHandle<Result<(), IoError>>,
+}
+
+impl DtlsListener {
+    /// Listen with the given PEM certificate chain and private key.
+    pub async fn bind(address: SocketAddr, cert: &Path, key: &Path) -> Result<DtlsListener, IoError> {
+        let mut builder = SslContext::builder(SslMethod::dtls())?;
+        builder.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_certificate_chain_file(cert)?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+
+        // Cookies are a MAC of the client's address, which is attached to
+        // each session
+        builder.set_options(SslOptions::COOKIE_EXCHANGE);
+        let address_index = Ssl::new_ex_index::<SocketAddr>()?;
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        builder.set_cookie_generate_cb(move |ssl, buf| {
+            let address = ssl.ex_data(address_index).ok_or_else(ErrorStack::get)?;
+            let cookie = cookie(&secret, address);
+            buf[..cookie.len()].copy_from_slice(&cookie);
+            Ok(cookie.len())
+        });
+        builder.set_cookie_verify_cb(move |ssl, received| {
+            match ssl.ex_data(address_index) {
+                Some(address) => bool::from(cookie(&secret, address).ct_eq(received)),
+                None => false,
+            }
+        });
+        let context = builder.build();
+
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let local_addr = socket.local_addr()?;
+        let (send, accepted) = mpsc::channel(ACCEPT_QUEUE);
+        let task = tokio::spawn(dispatch(socket, context, address_index, send));
+        Ok(DtlsListener { local_addr, accepted, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the next session, once its handshake is complete.
+    pub async fn accept(&mut self) -> Result<(DtlsStream, SocketAddr), IoError> {
+        match self.accepted.recv().await {
+            Some(r) => Ok(r),
+            None => Err(IoError::new(ErrorKind::BrokenPipe, "DTLS listener stopped")),
+        }
+    }
+}
+
+impl Drop for DtlsListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Receive datagrams and route them to the session for their address,
+/// starting a handshake for new addresses.
+async fn dispatch(socket: Arc<UdpSocket>, context: SslContext, address_index: Index<Ssl, SocketAddr>, accepted: mpsc::Sender<(DtlsStream, SocketAddr)>) -> Result<(), IoError> {
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = [0; 65536];
+    loop {
+        let (len, addr) = udp_batch::recv_from(&socket, &mut buf).await?;
+        let datagram = buf[0..len].to_owned();
+
+        // Route to the existing session, unless it ended
+        let datagram = match sessions.get(&addr) {
+            Some(session) => match session.try_send(datagram) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping datagram from {}, session is busy", addr);
+                    continue;
+                }
+                // Could be a new handshake, handled below
+                Err(TrySendError::Closed(datagram)) => {
+                    sessions.remove(&addr);
+                    datagram
+                }
+            },
+            None => datagram,
+        };
+
+        if sessions.len() >= MAX_SESSIONS {
+            sessions.retain(|_, s| !s.is_closed());
+            if sessions.len() >= MAX_SESSIONS {
+                warn!("Too many DTLS sessions, dropping datagram from {}", addr);
+                continue;
+            }
+        }
+
+        // Start a new session
+        let (send, recv) = mpsc::channel(SESSION_QUEUE);
+        send.try_send(datagram).unwrap();
+        sessions.insert(addr, send);
+        let stream = DatagramStream { socket: socket.clone(), peer: addr, incoming: Some(recv) };
+        let mut ssl = Ssl::new(&context)?;
+        ssl.set_ex_data(address_index, addr);
+        let mut stream = new_stream(ssl, stream)?;
+        let accepted = accepted.clone();
+        tokio::spawn(async move {
+            match handshake(&mut stream, true).await {
+                Ok(()) => {
+                    debug!("DTLS session established with {}", addr);
+                    accepted.send((stream, addr)).await.ok();
+                }
+                Err(e) => warn!("DTLS handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// Sends messages on a session, from any task.
+#[derive(Clone)]
+pub struct SessionSender(mpsc::UnboundedSender<Vec<u8>>);
+
+impl SessionSender {
+    pub fn send(&self, message: Vec<u8>) -> Result<(), IoError> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Message too large for DTLS record"));
+        }
+        self.0.send(message)
+            .map_err(|_| IoError::new(ErrorKind::NotConnected, "DTLS session closed"))
+    }
+}
+
+/// Split a session into its reading half and a sender for messages, written
+/// by a separate task.
+pub fn split(stream: DtlsStream) -> (ReadHalf<DtlsStream>, SessionSender) {
+    let (read, mut write) = tokio::io::split(stream);
+    let (send, mut recv) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(message) = recv.recv().await {
+            if let Err(e) = write.write_all(&message).await {
+                warn!("Error writing to DTLS session: {}", e);
+                break;
+            }
+        }
+    });
+    (read, SessionSender(send))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UdpSocket;
+
+    use super::{DtlsListener, MAX_MESSAGE_SIZE, connect, split};
+
+    /// Write a self-signed certificate for 127.0.0.1 and its key.
+    fn write_certificate(dir: &Path) -> (PathBuf, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().ip("127.0.0.1").build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let dir = TempDir::new("store_dtls_test").unwrap();
+        let (cert_path, key_path) = write_certificate(dir.path());
+
+        let mut listener = DtlsListener::bind("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path).await.unwrap();
+        let address = listener.local_addr();
+        let (client, server) = tokio::join!(connect(address, &cert_path), listener.accept());
+        let (mut client_read, client_send) = split(client.unwrap());
+        let (mut server_read, server_send) = split(server.unwrap().0);
+
+        // Each message is read whole
+        let mut buf = vec![0; 65536];
+        client_send.send(b"request".to_vec()).unwrap();
+        let len = server_read.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..len], b"request");
+        let large = vec![42; MAX_MESSAGE_SIZE];
+        server_send.send(large.clone()).unwrap();
+        let len = client_read.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..len], &large[..]);
+
+        assert!(client_send.send(vec![0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lossy_handshake() {
+        let dir = TempDir::new("store_dtls_test").unwrap();
+        let (cert_path, key_path) = write_certificate(dir.path());
+        let mut listener = DtlsListener::bind("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path).await.unwrap();
+        let server_address = listener.local_addr();
+
+        // Relay between client and server, dropping the client's first
+        // datagram and recording the sizes of the others
+        let proxy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let relay = tokio::spawn({
+            let sizes = sizes.clone();
+            async move {
+                let mut client_address: Option<SocketAddr> = None;
+                let mut dropped = false;
+                let mut buf = vec![0; 65536];
+                loop {
+                    let (len, from) = proxy.recv_from(&mut buf).await.unwrap();
+                    if from == server_address {
+                        sizes.lock().unwrap().push((false, len));
+                        proxy.send_to(&buf[..len], client_address.unwrap()).await.unwrap();
+                    } else {
+                        client_address = Some(from);
+                        if !dropped {
+                            dropped = true;
+                            continue;
+                        }
+                        sizes.lock().unwrap().push((true, len));
+                        proxy.send_to(&buf[..len], server_address).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let (client, server) = tokio::join!(connect(proxy_address, &cert_path), listener.accept());
+        client.unwrap();
+        server.unwrap();
+        relay.abort();
+
+        // The client hello was sent again, and first answered with a cookie,
+        // smaller than the hello
+        let sizes = sizes.lock().unwrap();
+        assert!(sizes[0].0);
+        assert!(!sizes[1].0);
+        assert!(sizes[1].1 < sizes[0].1, "{:?}", sizes);
+    }
+}
@@ -0,0 +1,129 @@
+//! Typed causes for the failures of the library.
+//!
+//! Functions return `std::io::Error`, whose kind tells what sort of failure
+//! happened. When the cause is one of ours, the I/O error wraps an `Error`,
+//! which `Error::get()` returns so callers can match on it, the same way
+//! `ErrorCode::get()` returns the code a storage daemon answered with.
+
+use std::io::{Error as IoError, ErrorKind};
+
+use crate::{DeviceId, PoolName};
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A message that can't be decoded, or a response that doesn't answer
+    /// the request.
+    #[error("{0}")]
+    Protocol(String),
+    /// A capability that is missing or doesn't allow the request.
+    #[error("{0}")]
+    Crypto(String),
+    /// The storage map doesn't tell where to send a request.
+    #[error(transparent)]
+    Placement(#[from] PlacementError),
+    /// The storage backend failed.
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+    /// A daemon or the master can't be reached, or didn't answer.
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PlacementError {
+    #[error("Unknown pool {0}")]
+    UnknownPool(PoolName),
+    #[error("No device for object")]
+    NoDevice,
+    #[error("No address for device {0:?}")]
+    NoAddress(DeviceId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum NetworkError {
+    /// No answer before the timeout, after resending.
+    #[error("{0}")]
+    TimedOut(String),
+    /// There is no connection to send on.
+    #[error("{0}")]
+    NotConnected(String),
+    /// The other side closed the connection.
+    #[error("{0}")]
+    Closed(String),
+}
+
+impl Error {
+    /// The closest kind of I/O error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Protocol(_) => ErrorKind::InvalidData,
+            Error::Crypto(_) => ErrorKind::PermissionDenied,
+            Error::Placement(PlacementError::UnknownPool(_) | PlacementError::NoDevice) => ErrorKind::InvalidData,
+            Error::Placement(PlacementError::NoAddress(_)) => ErrorKind::NotFound,
+            Error::Backend(_) => ErrorKind::Other,
+            Error::Network(NetworkError::TimedOut(_)) => ErrorKind::TimedOut,
+            Error::Network(NetworkError::NotConnected(_)) => ErrorKind::NotConnected,
+            Error::Network(NetworkError::Closed(_)) => ErrorKind::ConnectionAborted,
+        }
+    }
+
+    /// Get the error an I/O error was created from, if any.
+    pub fn get(err: &IoError) -> Option<&Error> {
+        err.get_ref()?.downcast_ref::<Error>()
+    }
+}
+
+impl From<Error> for IoError {
+    fn from(err: Error) -> IoError {
+        IoError::new(err.kind(), err)
+    }
+}
+
+impl From<PlacementError> for IoError {
+    fn from(err: PlacementError) -> IoError {
+        Error::Placement(err).into()
+    }
+}
+
+impl From<NetworkError> for IoError {
+    fn from(err: NetworkError) -> IoError {
+        Error::Network(err).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error as IoError, ErrorKind};
+
+    use crate::{DeviceId, PoolName};
+    use crate::proto::wire::ErrorCode;
+    use super::{Error, NetworkError, PlacementError};
+
+    #[test]
+    fn test_io_error() {
+        let err: IoError = PlacementError::UnknownPool(PoolName("pool".to_owned())).into();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Unknown pool pool");
+        assert_eq!(
+            Error::get(&err),
+            Some(&Error::Placement(PlacementError::UnknownPool(PoolName("pool".to_owned())))),
+        );
+
+        let err: IoError = PlacementError::NoAddress(DeviceId([0; 16])).into();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let err: IoError = NetworkError::TimedOut("No response".to_owned()).into();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(matches!(Error::get(&err), Some(Error::Network(NetworkError::TimedOut(_)))));
+
+        // Daemons answer with the closest code
+        let err: IoError = Error::Crypto("Missing capability".to_owned()).into();
+        assert_eq!(ErrorCode::for_error(&err), ErrorCode::Unauthorized);
+        let err: IoError = Error::Backend("disk on fire".to_owned()).into();
+        assert_eq!(err.to_string(), "Storage backend error: disk on fire");
+        assert_eq!(ErrorCode::for_error(&err), ErrorCode::Internal);
+
+        assert_eq!(Error::get(&IoError::other("other")), None);
+    }
+}
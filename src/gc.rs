@@ -0,0 +1,187 @@
+//! Deleting the objects that failed writes leave behind.
+//!
+//! Striped objects (see `striping`) and S3 objects (see
+//! `s3_gateway::objects`) are made of many objects, written before the one
+//! referring to them, so a write that fails midway leaves objects nothing
+//! refers to. `collect_garbage()` lists a pool, finds those objects, and
+//! deletes them.
+//!
+//! Objects written less than a grace period ago are kept, as the write they
+//! are part of may still be going on. Their age comes from their version,
+//! which is the time of their last write; objects without a version are old.
+//!
+//! The layouts to look for have to be picked, as nothing tells a stripe from
+//! an object that only has a similar name.
+
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::io::Error as IoError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ObjectId;
+use crate::client::Client;
+use crate::s3_gateway::objects::{chunk_segment, referenced_segments};
+use crate::striping::{current_stripes, parse_stripe_object_id};
+
+#[derive(Clone, Debug)]
+pub struct GcOptions {
+    /// How old an object has to be to be deleted.
+    pub grace: Duration,
+    /// Look for stripes beyond the end of their object, or of no object.
+    pub striped: bool,
+    /// Look for chunks of S3 segments that no object or part refers to.
+    pub s3: bool,
+    /// Only report the orphaned objects, don't delete them.
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            grace: Duration::from_secs(3600),
+            striped: false,
+            s3: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// What a collection found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub scanned: u64,
+    /// Orphaned objects older than the grace period.
+    pub orphans: u64,
+    /// Size of those objects.
+    pub bytes: u64,
+    /// Orphaned objects kept because they are too recent.
+    pub recent: u64,
+    pub deleted: u64,
+}
+
+/// Whether an object with this version was written within `grace`.
+fn is_recent(version: u64, grace: Duration) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+    version != 0 && now.saturating_sub(version) < grace.as_micros() as u64
+}
+
+/// Find the orphaned objects of the pool, and delete them.
+///
+/// A striped object growing again while its old stripes are deleted can
+/// lose a stripe, reading it then fails instead of returning wrong data.
+pub async fn collect_garbage(client: &Client, options: &GcOptions) -> Result<GcReport, IoError> {
+    // Get the segments in use before and after listing, so that those of an
+    // upload completing meanwhile are seen in one or the other
+    let mut segments = HashSet::new();
+    if options.s3 {
+        segments = referenced_segments(client).await?;
+    }
+    let objects = client.list_all_objects(b"").await?;
+    if options.s3 {
+        segments.extend(referenced_segments(client).await?);
+    }
+
+    let mut report = GcReport { scanned: objects.len() as u64, ..GcReport::default() };
+    let mut stripe_counts: HashMap<ObjectId, u64> = HashMap::new();
+    for object_id in objects {
+        let orphan = match (chunk_segment(&object_id), parse_stripe_object_id(&object_id)) {
+            (Some(segment), _) if options.s3 => !segments.contains(segment),
+            (_, Some((base, num))) if options.striped => {
+                let count = match stripe_counts.get(&base) {
+                    Some(&count) => count,
+                    None => {
+                        let count = current_stripes(client, &base).await?;
+                        stripe_counts.insert(base, count);
+                        count
+                    }
+                };
+                num >= count
+            }
+            _ => false,
+        };
+        if !orphan {
+            continue;
+        }
+
+        let stat = match client.stat_object(&object_id).await? {
+            Some(stat) => stat,
+            None => continue,
+        };
+        if is_recent(stat.version, options.grace) {
+            report.recent += 1;
+            continue;
+        }
+        report.orphans += 1;
+        report.bytes += stat.size;
+        if !options.dry_run {
+            debug!("Deleting orphaned object {:?}", object_id);
+            client.delete_object(&object_id).await?;
+            report.deleted += 1;
+        }
+    }
+    Ok(report)
+}
+
+// Listing and the S3 layout need the extended operations
+#[cfg(all(test, feature = "extended-ops"))]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{ObjectId, PoolName};
+    use crate::client::create_client_with_socket;
+    use crate::daemon::spawn_test_daemon;
+    use crate::netsim::{SimConfig, SimNetwork, Socket};
+    use crate::s3_gateway::objects::{SegmentWriter, put_object};
+    use crate::striping::StripedClient;
+    use super::{GcOptions, GcReport, collect_garbage};
+
+    #[tokio::test]
+    async fn test_collect_garbage() {
+        let network = SimNetwork::new(1, SimConfig::default());
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+        let socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+        let exists = |id: &'static [u8]| {
+            let client = client.clone();
+            async move { client.read_object(&ObjectId(id.to_vec())).await.unwrap().is_some() }
+        };
+
+        // A striped object, and stripes left by failed writes
+        let striped = StripedClient::new(client.clone()).with_threshold(10).with_stripe_size(10).unwrap();
+        striped.write_object(&ObjectId(b"big".to_vec()), &[1; 25]).await.unwrap();
+        client.write_object(&ObjectId(b"big.3".to_vec()), b"orphan").await.unwrap();
+        client.write_object(&ObjectId(b"gone.0".to_vec()), b"orphan").await.unwrap();
+
+        // An S3 object, and a chunk left by a failed upload
+        let mut writer = SegmentWriter::new(&client, b"seg1");
+        writer.write(b"content").await.unwrap();
+        put_object(&client, b"key", writer).await.unwrap();
+        client.write_object(&ObjectId(b"d:seg2:0".to_vec()), b"orphan").await.unwrap();
+
+        // Everything is too recent
+        let options = GcOptions { striped: true, s3: true, ..GcOptions::default() };
+        let report = collect_garbage(&client, &options).await.unwrap();
+        assert_eq!(report, GcReport { scanned: 9, orphans: 0, bytes: 0, recent: 3, deleted: 0 });
+
+        let options = GcOptions { grace: Duration::ZERO, dry_run: true, ..options };
+        let report = collect_garbage(&client, &options).await.unwrap();
+        assert_eq!(report, GcReport { scanned: 9, orphans: 3, bytes: 18, recent: 0, deleted: 0 });
+        assert!(exists(b"big.3").await);
+
+        // Only the layouts asked for
+        let s3_only = GcOptions { striped: false, dry_run: false, ..options.clone() };
+        assert_eq!(collect_garbage(&client, &s3_only).await.unwrap().deleted, 1);
+        assert!(!exists(b"d:seg2:0").await);
+        assert!(exists(b"big.3").await);
+
+        let options = GcOptions { dry_run: false, ..options };
+        let report = collect_garbage(&client, &options).await.unwrap();
+        assert_eq!(report, GcReport { scanned: 8, orphans: 2, bytes: 12, recent: 0, deleted: 2 });
+        assert!(!exists(b"big.3").await && !exists(b"gone.0").await);
+        assert!(exists(b"big.2").await && exists(b"d:seg1:0").await);
+        assert_eq!(striped.read_object(&ObjectId(b"big".to_vec())).await.unwrap(), Some(vec![1; 25]));
+
+        daemon.abort();
+    }
+}
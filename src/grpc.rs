@@ -0,0 +1,317 @@
+//! A gRPC service wrapping the client operations, plus admin calls.
+//!
+//! The service is defined in `proto/store.proto`, so clients can be
+//! generated for other languages. Clients authenticate with a bearer token,
+//! like with the HTTP gateway.
+
+// tonic's Status is large, but it is what the API uses for errors
+#![allow(clippy::result_large_err)]
+
+use lazy_static::lazy_static;
+use log::info;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tonic::{Request, Response, Status};
+
+use crate::ObjectId;
+use crate::client::{Client, REQUEST_TIMEOUT};
+use crate::http_gateway::Tokens;
+use crate::image::{self, Geometry, MAX_STRIPE_UNIT};
+use crate::metrics::register_counter_vec;
+
+pub mod proto {
+    tonic::include_proto!("store.v1");
+}
+
+use proto::store_admin_server::{StoreAdmin, StoreAdminServer};
+use proto::store_server::{Store, StoreServer};
+use proto::*;
+
+/// Block size of images created without one.
+const DEFAULT_BLOCK_SIZE: usize = 4 << 10;
+
+/// Largest page of a listing.
+const MAX_LIST_LIMIT: u32 = 1000;
+
+struct Metrics {
+    requests: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        requests: register_counter_vec("grpc", "requests", "Total requests", &["method"]),
+        errors: register_counter_vec("grpc", "errors", "Total requests that failed", &["method"]),
+    };
+}
+
+fn status(err: IoError) -> Status {
+    let message = err.to_string();
+    match err.kind() {
+        ErrorKind::NotFound => Status::not_found(message),
+        ErrorKind::PermissionDenied => Status::permission_denied(message),
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => Status::invalid_argument(message),
+        ErrorKind::AlreadyExists => Status::already_exists(message),
+        ErrorKind::TimedOut => Status::deadline_exceeded(message),
+        ErrorKind::Unsupported => Status::unimplemented(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Count a call in the metrics.
+async fn observe<T>(method: &'static str, call: impl Future<Output = Result<T, Status>>) -> Result<Response<T>, Status> {
+    METRICS.requests.with_label_values(&[method]).inc();
+    let result = call.await;
+    if result.is_err() {
+        METRICS.errors.with_label_values(&[method]).inc();
+    }
+    result.map(Response::new)
+}
+
+#[derive(Clone)]
+struct GrpcGateway {
+    pools: Arc<HashMap<String, Client>>,
+}
+
+impl GrpcGateway {
+    fn pool(&self, pool: &str) -> Result<&Client, Status> {
+        self.pools.get(pool).ok_or_else(|| Status::not_found(format!("No pool {:?}", pool)))
+    }
+}
+
+/// Serve the gRPC API for `pools` on `listen_address`.
+pub async fn run_grpc_gateway(listen_address: SocketAddr, pools: HashMap<String, Client>, tokens: Tokens) -> Result<(), Box<dyn std::error::Error>> {
+    let pools = pools.into_iter()
+        .map(|(name, client)| (name, client.with_timeout(REQUEST_TIMEOUT)))
+        .collect();
+    let gateway = GrpcGateway { pools: Arc::new(pools) };
+
+    let tokens = Arc::new(tokens);
+    let check_token = move |req: Request<()>| {
+        let header = req.metadata().get("authorization").and_then(|h| h.to_str().ok());
+        if tokens.check(header) {
+            Ok(req)
+        } else {
+            Err(Status::unauthenticated("Missing or invalid token"))
+        }
+    };
+
+    info!("gRPC gateway listening on {}", listen_address);
+    tonic::transport::Server::builder()
+        .add_service(StoreServer::with_interceptor(gateway.clone(), check_token.clone()))
+        .add_service(StoreAdminServer::with_interceptor(gateway, check_token))
+        .serve(listen_address)
+        .await?;
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Store for GrpcGateway {
+    async fn read_object(&self, request: Request<ObjectRequest>) -> Result<Response<ReadObjectResponse>, Status> {
+        observe("read_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            match client.read_object(&ObjectId(req.object)).await.map_err(status)? {
+                Some(data) => Ok(ReadObjectResponse { data }),
+                None => Err(Status::not_found("No such object")),
+            }
+        }).await
+    }
+
+    async fn read_part(&self, request: Request<ReadPartRequest>) -> Result<Response<ReadObjectResponse>, Status> {
+        observe("read_part", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            match client.read_part(&ObjectId(req.object), req.offset, req.length).await.map_err(status)? {
+                Some(data) => Ok(ReadObjectResponse { data }),
+                None => Err(Status::not_found("No such object")),
+            }
+        }).await
+    }
+
+    async fn write_object(&self, request: Request<WriteObjectRequest>) -> Result<Response<Empty>, Status> {
+        observe("write_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            client.write_object(&ObjectId(req.object), &req.data).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn write_part(&self, request: Request<WritePartRequest>) -> Result<Response<Empty>, Status> {
+        observe("write_part", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            client.write_part(&ObjectId(req.object), req.offset, &req.data).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn delete_object(&self, request: Request<ObjectRequest>) -> Result<Response<Empty>, Status> {
+        observe("delete_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            client.delete_object(&ObjectId(req.object)).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn stat_object(&self, request: Request<ObjectRequest>) -> Result<Response<StatObjectResponse>, Status> {
+        observe("stat_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let stat = client.stat_object(&ObjectId(req.object)).await.map_err(status)?
+                .ok_or_else(|| Status::not_found("No such object"))?;
+            let expires_unix_ms = stat.expires.map(|t| {
+                t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+            });
+            Ok(StatObjectResponse { size: stat.size, expires_unix_ms })
+        }).await
+    }
+
+    async fn list_objects(&self, request: Request<ListObjectsRequest>) -> Result<Response<ListObjectsResponse>, Status> {
+        observe("list_objects", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let start_after = req.start_after.map(ObjectId);
+            let limit = match req.limit {
+                0 => MAX_LIST_LIMIT,
+                limit => limit.min(MAX_LIST_LIMIT),
+            };
+            let objects = client.list_objects(&req.prefix, start_after.as_ref(), limit).await.map_err(status)?;
+            Ok(ListObjectsResponse { objects: objects.into_iter().map(|o| o.0).collect() })
+        }).await
+    }
+
+    async fn append_object(&self, request: Request<WriteObjectRequest>) -> Result<Response<Empty>, Status> {
+        observe("append_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            client.append_object(&ObjectId(req.object), &req.data).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn truncate_object(&self, request: Request<TruncateObjectRequest>) -> Result<Response<Empty>, Status> {
+        observe("truncate_object", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            client.truncate_object(&ObjectId(req.object), req.length).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn compare_and_swap(&self, request: Request<CompareAndSwapRequest>) -> Result<Response<CompareAndSwapResponse>, Status> {
+        observe("compare_and_swap", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let swapped = client.compare_and_swap(
+                &ObjectId(req.object),
+                req.expected.as_deref(),
+                &req.data,
+            ).await.map_err(status)?.is_some();
+            Ok(CompareAndSwapResponse { swapped })
+        }).await
+    }
+
+    async fn flush(&self, request: Request<PoolRequest>) -> Result<Response<Empty>, Status> {
+        observe("flush", async {
+            let req = request.into_inner();
+            self.pool(&req.pool)?.flush().await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+}
+
+fn image_info(geometry: &Geometry, lock_holder: Option<String>) -> ImageInfo {
+    ImageInfo {
+        size: geometry.size,
+        block_size: geometry.block_size as u32,
+        stripe_unit: geometry.stripe_unit as u32,
+        lock_holder,
+    }
+}
+
+#[tonic::async_trait]
+impl StoreAdmin for GrpcGateway {
+    async fn list_pools(&self, _request: Request<Empty>) -> Result<Response<ListPoolsResponse>, Status> {
+        observe("list_pools", async {
+            let mut pools: Vec<String> = self.pools.keys().cloned().collect();
+            pools.sort();
+            Ok(ListPoolsResponse { pools })
+        }).await
+    }
+
+    async fn create_image(&self, request: Request<CreateImageRequest>) -> Result<Response<Empty>, Status> {
+        observe("create_image", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let block_size = req.block_size.map_or(DEFAULT_BLOCK_SIZE, |s| s as usize);
+            let stripe_unit = req.stripe_unit.map_or(block_size.min(MAX_STRIPE_UNIT), |s| s as usize);
+            let geometry = Geometry { size: req.size, block_size, stripe_unit, flags: 0 };
+            geometry.check().map_err(status)?;
+            image::create_image(client, req.image.as_bytes(), &geometry, req.preallocate).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn get_image(&self, request: Request<ImageRequest>) -> Result<Response<ImageInfo>, Status> {
+        observe("get_image", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let geometry = image::read_image(client, req.image.as_bytes()).await.map_err(status)?
+                .ok_or_else(|| Status::not_found("No such image"))?;
+            let lock_holder = image::image_lock_holder(client, req.image.as_bytes()).await.map_err(status)?;
+            Ok(image_info(&geometry, lock_holder))
+        }).await
+    }
+
+    async fn resize_image(&self, request: Request<ResizeImageRequest>) -> Result<Response<ImageInfo>, Status> {
+        observe("resize_image", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            let geometry = image::resize_image(client, req.image.as_bytes(), req.size).await.map_err(status)?;
+            Ok(image_info(&geometry, None))
+        }).await
+    }
+
+    async fn delete_image(&self, request: Request<ImageRequest>) -> Result<Response<Empty>, Status> {
+        observe("delete_image", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            image::delete_image(client, req.image.as_bytes()).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+
+    async fn snapshot_image(&self, request: Request<SnapshotImageRequest>) -> Result<Response<Empty>, Status> {
+        observe("snapshot_image", async {
+            let req = request.into_inner();
+            let client = self.pool(&req.pool)?;
+            image::snapshot_image(client, req.image.as_bytes(), &req.snapshot).await.map_err(status)?;
+            Ok(Empty {})
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error as IoError, ErrorKind};
+    use tonic::Code;
+
+    use super::status;
+
+    #[test]
+    fn test_status() {
+        let code = |kind| status(IoError::new(kind, "test")).code();
+        assert_eq!(code(ErrorKind::NotFound), Code::NotFound);
+        assert_eq!(code(ErrorKind::PermissionDenied), Code::PermissionDenied);
+        assert_eq!(code(ErrorKind::InvalidData), Code::InvalidArgument);
+        assert_eq!(code(ErrorKind::TimedOut), Code::DeadlineExceeded);
+        assert_eq!(code(ErrorKind::Other), Code::Internal);
+    }
+}
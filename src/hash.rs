@@ -1,21 +1,124 @@
 use fxhash::FxHasher;
+use serde::{Deserialize, Serialize};
 use std::hash::Hasher;
 
-use crate::{GroupId, ObjectId};
+use crate::{DeviceId, GroupId, ObjectId};
+
+/// The hash function used for placement.
+///
+/// Changing the hash function moves all the data, so it is recorded in the
+/// map, and existing maps keep using the function they were created with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashVersion {
+    /// FxHash, used by the first maps.
+    ///
+    /// Its output is not guaranteed to stay the same across versions of the
+    /// fxhash crate, and it mixes small inputs poorly.
+    #[default]
+    Fx,
+    /// MurmurHash3 (32-bit, seed 0) over the little-endian encoding of the
+    /// inputs, as specified by its reference implementation.
+    Murmur3,
+}
+
+/// MurmurHash3, 32-bit variant.
+pub fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes(block.try_into().unwrap());
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, &b) in tail.iter().enumerate() {
+            k |= (b as u32) << (8 * i);
+        }
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+    }
+    h ^= data.len() as u32;
+    mix32(h)
+}
+
+pub fn compute_hash(hash: HashVersion, level: u32, group_id: &GroupId, replica_num: u32, attempt: u32, idx: usize) -> u32 {
+    if hash == HashVersion::Murmur3 {
+        let mut data = [0; 20];
+        data[0..4].copy_from_slice(&level.to_le_bytes());
+        data[4..8].copy_from_slice(&group_id.0.to_le_bytes());
+        data[8..12].copy_from_slice(&replica_num.to_le_bytes());
+        data[12..16].copy_from_slice(&attempt.to_le_bytes());
+        data[16..20].copy_from_slice(&(idx as u32).to_le_bytes());
+        return murmur3_32(&data, 0);
+    }
 
-pub fn compute_hash(level: u32, group_id: &GroupId, replica_num: u32, attempt: u32, idx: usize) -> u32 {
     let mut h = FxHasher::default();
     h.write_u32(level);
     h.write_u32(group_id.0);
     h.write_u32(replica_num);
+    // Only hash the attempt number on retries, so that first picks stay where
+    // they were placed before the attempt was taken into account
+    if attempt > 0 {
+        h.write_u32(attempt);
+    }
     h.write_u32(idx as u32);
     let r: u64 = h.finish();
     r as u32
 }
 
-pub fn compute_object_hash(object_id: &ObjectId) -> u32 {
+pub fn compute_object_hash(hash: HashVersion, object_id: &ObjectId) -> u32 {
+    if hash == HashVersion::Murmur3 {
+        return murmur3_32(&object_id.0, 0);
+    }
+
     let mut h = FxHasher::default();
     h.write(&object_id.0);
     let r: u64 = h.finish();
     r as u32
 }
+
+/// Hash a group with a device, for decisions that concern that pair.
+pub fn compute_device_hash(hash: HashVersion, group_id: &GroupId, device_id: &DeviceId) -> u32 {
+    if hash == HashVersion::Murmur3 {
+        let mut data = [0; 20];
+        data[0..4].copy_from_slice(&group_id.0.to_le_bytes());
+        data[4..20].copy_from_slice(&device_id.0);
+        return murmur3_32(&data, 0);
+    }
+
+    let mut h = FxHasher::default();
+    h.write_u32(group_id.0);
+    h.write(&device_id.0);
+    let r: u64 = h.finish();
+    mix32(r as u32)
+}
+
+/// Finalization mix from MurmurHash3, improves the avalanche of a hash.
+pub fn mix32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::murmur3_32;
+
+    #[test]
+    fn test_murmur3() {
+        // Reference values
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"", 1), 0x514e28b7);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248bfa47);
+        assert_eq!(murmur3_32(b"The quick brown fox jumps over the lazy dog", 0), 0x2e4ff723);
+    }
+}
@@ -0,0 +1,461 @@
+//! A plain HTTP gateway, reading and writing objects at `/pool/object`.
+//!
+//! This is lighter than the S3 gateway: objects are stored as-is, so other
+//! clients see them, but writes are not atomic. A failed upload deletes the
+//! object, and readers can see an object while it is being replaced.
+//!
+//! Clients authenticate with a bearer token (`Authorization: Bearer ...`),
+//! one of those listed in the token file.
+
+use hyper::body::HttpBody;
+use hyper::header::{
+    ACCEPT_RANGES, ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+    WWW_AUTHENTICATE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+
+use crate::ObjectId;
+use crate::client::{Client, PART_SIZE, REQUEST_TIMEOUT};
+use crate::metrics::{register_counter, register_counter_vec, register_latency};
+use crate::s3_gateway::http::{parse_range, percent_decode};
+
+/// Largest object accepted by PUT.
+pub const MAX_OBJECT_SIZE: u64 = 64 << 20;
+
+/// How much of a body to buffer before writing its parts in parallel.
+const BATCH_SIZE: usize = 1 << 20;
+
+struct Metrics {
+    requests: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+    latency: prometheus::HistogramVec,
+    bytes_read: prometheus::IntCounter,
+    bytes_written: prometheus::IntCounter,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        requests: register_counter_vec("http_gateway", "requests", "Total requests", &["op"]),
+        errors: register_counter_vec("http_gateway", "errors", "Total requests that failed", &["op"]),
+        latency: register_latency("http_gateway", "duration_seconds", "Time to handle requests, up to the start of the body", &["op"]),
+        bytes_read: register_counter("http_gateway", "bytes_read", "Total bytes sent to clients"),
+        bytes_written: register_counter("http_gateway", "bytes_written", "Total bytes received from clients"),
+    };
+}
+
+/// An error to send back, with its status.
+#[derive(Debug)]
+struct HttpError {
+    status: StatusCode,
+    message: String,
+}
+
+impl HttpError {
+    fn new<S: Into<String>>(status: StatusCode, message: S) -> HttpError {
+        HttpError { status, message: message.into() }
+    }
+}
+
+impl From<IoError> for HttpError {
+    fn from(err: IoError) -> HttpError {
+        let status = match err.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorKind::TimedOut => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        HttpError::new(status, err.to_string())
+    }
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> HttpError {
+        HttpError::new(StatusCode::BAD_REQUEST, err.to_string())
+    }
+}
+
+/// The tokens clients can authenticate with, stored hashed.
+pub struct Tokens(Vec<[u8; 32]>);
+
+impl Tokens {
+    /// Read tokens from a file, one per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Tokens, IoError> {
+        let tokens: Vec<[u8; 32]> = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|token| Sha256::digest(token.as_bytes()).into())
+            .collect();
+        if tokens.is_empty() {
+            return Err(IoError::new(ErrorKind::InvalidData, "No tokens in file"));
+        }
+        Ok(Tokens(tokens))
+    }
+
+    /// Check the `Authorization` header of a request.
+    pub(crate) fn check(&self, header: Option<&str>) -> bool {
+        let token = match header.and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(token) => token.trim(),
+            None => return false,
+        };
+        let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        // Compare with all of them, in constant time
+        self.0.iter().fold(false, |found, t| found | bool::from(t.ct_eq(&hash)))
+    }
+}
+
+struct HttpGateway {
+    pools: HashMap<String, Client>,
+    tokens: Tokens,
+}
+
+/// Serve objects of `pools` over HTTP on `listen_address`.
+pub async fn run_http_gateway(listen_address: SocketAddr, pools: HashMap<String, Client>, tokens: Tokens) -> Result<(), Box<dyn std::error::Error>> {
+    let pools = pools.into_iter()
+        .map(|(name, client)| (name, client.with_timeout(REQUEST_TIMEOUT)))
+        .collect();
+    let gateway = Arc::new(HttpGateway { pools, tokens });
+    let server = Server::try_bind(&listen_address)?
+        .serve(make_service_fn(move |_| {
+            let gateway = gateway.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(gateway.clone(), req)))
+            }
+        }));
+    info!("HTTP gateway listening on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+async fn handle(gateway: Arc<HttpGateway>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let op = match *req.method() {
+        Method::GET => "get",
+        Method::HEAD => "head",
+        Method::PUT => "put",
+        Method::DELETE => "delete",
+        _ => "unknown",
+    };
+    let path = req.uri().path().to_owned();
+    let response = match route(&gateway, req).await {
+        Ok(response) => response,
+        Err(err) => {
+            METRICS.errors.with_label_values(&[op]).inc();
+            if err.status.is_server_error() {
+                warn!("Error handling {} on {}: {}", op, path, err.message);
+            }
+            let mut response = Response::builder()
+                .status(err.status)
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+            match err.status {
+                StatusCode::UNAUTHORIZED => response = response.header(WWW_AUTHENTICATE, "Bearer"),
+                StatusCode::METHOD_NOT_ALLOWED => response = response.header(ALLOW, "GET, HEAD, PUT, DELETE"),
+                _ => {}
+            }
+            response.body(format!("{}\n", err.message).into()).unwrap()
+        }
+    };
+    METRICS.requests.with_label_values(&[op]).inc();
+    METRICS.latency.with_label_values(&[op]).observe(start.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+async fn route(gateway: &HttpGateway, req: Request<Body>) -> Result<Response<Body>, HttpError> {
+    let authorization = req.headers().get(AUTHORIZATION).and_then(|h| h.to_str().ok());
+    if !gateway.tokens.check(authorization) {
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, "Missing or invalid token"));
+    }
+
+    let path = percent_decode(req.uri().path())?;
+    let path = path.strip_prefix('/').unwrap_or(&path);
+    let (pool, name) = match path.split_once('/') {
+        Some((pool, name)) if !name.is_empty() => (pool, name),
+        _ => return Err(HttpError::new(StatusCode::NOT_FOUND, "Requests are for /pool/object")),
+    };
+    let client = gateway.pools.get(pool).ok_or_else(|| {
+        HttpError::new(StatusCode::NOT_FOUND, format!("No pool {:?}", pool))
+    })?;
+    let object_id = ObjectId(name.as_bytes().to_owned());
+
+    match *req.method() {
+        Method::GET => get_object(client, object_id, &req, false).await,
+        Method::HEAD => get_object(client, object_id, &req, true).await,
+        Method::PUT => put_object(client, object_id, req).await,
+        Method::DELETE => {
+            client.delete_object(&object_id).await?;
+            Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+        }
+        _ => Err(HttpError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+    }
+}
+
+async fn get_object(client: &Client, object_id: ObjectId, req: &Request<Body>, head: bool) -> Result<Response<Body>, HttpError> {
+    let size = match client.stat_object(&object_id).await? {
+        Some(stat) => stat.size,
+        None => return Err(HttpError::new(StatusCode::NOT_FOUND, "No such object")),
+    };
+    let range = match req.headers().get(RANGE).and_then(|r| r.to_str().ok()) {
+        Some(range) => parse_range(range, size).map_err(|e| {
+            HttpError::new(StatusCode::RANGE_NOT_SATISFIABLE, e.to_string())
+        })?,
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, size));
+
+    let mut response = Response::builder()
+        .header(CONTENT_LENGTH, end - start)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(ACCEPT_RANGES, "bytes");
+    if range.is_some() {
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, size));
+    }
+    if head {
+        return Ok(response.body(Body::empty()).unwrap());
+    }
+
+    // Send the parts as they are read, aborting the response on errors
+    let (mut sender, body) = Body::channel();
+    let client = client.clone();
+    tokio::spawn(async move {
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(PART_SIZE as u64) as u32;
+            match client.read_part(&object_id, offset as u32, len).await {
+                Ok(Some(data)) if data.len() == len as usize => {
+                    METRICS.bytes_read.inc_by(data.len() as u64);
+                    if sender.send_data(data.into()).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {
+                    warn!("Object {:?} changed while it was read", object_id);
+                    sender.abort();
+                    return;
+                }
+                Err(e) => {
+                    warn!("Error reading {:?}: {}", object_id, e);
+                    sender.abort();
+                    return;
+                }
+            }
+            offset += len as u64;
+        }
+    });
+    Ok(response.body(body).unwrap())
+}
+
+async fn put_object(client: &Client, object_id: ObjectId, req: Request<Body>) -> Result<Response<Body>, HttpError> {
+    let too_large = || HttpError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Objects are limited to {} bytes", MAX_OBJECT_SIZE),
+    );
+    let length = req.headers().get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok());
+    if length.is_some_and(|l| l > MAX_OBJECT_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut body = req.into_body();
+    let mut buffer = Vec::with_capacity(BATCH_SIZE);
+    let mut written = 0;
+    let mut started = false;
+    let result: Result<(), HttpError> = async {
+        loop {
+            let data = body.data().await.transpose()?;
+            if let Some(data) = &data {
+                buffer.extend_from_slice(data);
+                if written + buffer.len() as u64 > MAX_OBJECT_SIZE {
+                    return Err(too_large());
+                }
+            }
+            if buffer.len() >= BATCH_SIZE || data.is_none() {
+                started = true;
+                write_batch(client, &object_id, written, &buffer).await?;
+                written += buffer.len() as u64;
+                buffer.clear();
+            }
+            if data.is_none() {
+                return Ok(());
+            }
+        }
+    }.await;
+    if let Err(e) = result {
+        // Don't leave part of the body behind
+        if started {
+            client.delete_object(&object_id).await.ok();
+        }
+        return Err(e);
+    }
+    METRICS.bytes_written.inc_by(written);
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+}
+
+/// Write data at `offset`, in parts sent in parallel.
+///
+/// The first part of the object replaces it, so it is written first.
+async fn write_batch(client: &Client, object_id: &ObjectId, offset: u64, mut data: &[u8]) -> Result<(), IoError> {
+    let mut offset = offset as u32;
+    if offset == 0 {
+        let first = &data[..data.len().min(PART_SIZE)];
+        client.write_object(object_id, first).await?;
+        offset += first.len() as u32;
+        data = &data[first.len()..];
+    }
+    let handles: Vec<_> = data.chunks(PART_SIZE).enumerate().map(|(i, part)| {
+        let client = client.clone();
+        let object_id = object_id.clone();
+        let part = part.to_owned();
+        let part_offset = offset + (i * PART_SIZE) as u32;
+        tokio::spawn(async move {
+            client.write_part(&object_id, part_offset, &part).await
+        })
+    }).collect();
+    for handle in handles {
+        handle.await.map_err(IoError::other)??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{
+        ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, HeaderMap, RANGE, WWW_AUTHENTICATE,
+    };
+    use hyper::{Body, Method, Request, StatusCode};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::PoolName;
+    use crate::client::{PART_SIZE, create_client_with_socket};
+    use crate::daemon::spawn_test_daemon;
+    use crate::netsim::{SimConfig, SimNetwork, Socket};
+    use super::{HttpGateway, MAX_OBJECT_SIZE, Tokens, handle};
+
+    /// Send a request to the gateway, returning the status, headers and body.
+    async fn request(
+        gateway: &Arc<HttpGateway>,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let mut req = Request::builder().method(method).uri(path);
+        for &(name, value) in headers {
+            req = req.header(name, value);
+        }
+        let response = handle(gateway.clone(), req.body(Body::from(body)).unwrap()).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn test_http_gateway() {
+        let network = SimNetwork::new(1, SimConfig::default());
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+        let socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+        let gateway = Arc::new(HttpGateway {
+            pools: HashMap::from([("default".to_owned(), client)]),
+            tokens: Tokens::parse("secret\n").unwrap(),
+        });
+        let auth = (AUTHORIZATION.as_str(), "Bearer secret");
+
+        // Missing or wrong token
+        let (status, headers, _) = request(&gateway, Method::GET, "/default/greeting", &[], vec![]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[WWW_AUTHENTICATE], "Bearer");
+        let wrong = (AUTHORIZATION.as_str(), "Bearer guess");
+        let (status, _, _) = request(&gateway, Method::PUT, "/default/greeting", &[wrong], b"hello".to_vec()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Write and read back
+        let (status, _, _) = request(&gateway, Method::GET, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = request(&gateway, Method::PUT, "/default/greeting", &[auth], b"hello world".to_vec()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, headers, body) = request(&gateway, Method::GET, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_LENGTH], "11");
+        assert_eq!(body, b"hello world");
+        let (status, headers, body) = request(&gateway, Method::HEAD, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_LENGTH], "11");
+        assert!(body.is_empty());
+
+        // Ranges
+        let (status, headers, body) = request(&gateway, Method::GET, "/default/greeting", &[auth, (RANGE.as_str(), "bytes=6-")], vec![]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[CONTENT_RANGE], "bytes 6-10/11");
+        assert_eq!(body, b"world");
+        let (status, _, body) = request(&gateway, Method::GET, "/default/greeting", &[auth, (RANGE.as_str(), "bytes=-5")], vec![]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"world");
+        let (status, _, _) = request(&gateway, Method::GET, "/default/greeting", &[auth, (RANGE.as_str(), "bytes=11-")], vec![]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // Objects of several parts, read across them
+        let big: Vec<u8> = (0..PART_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let (status, _, _) = request(&gateway, Method::PUT, "/default/dir%2Fbig", &[auth], big.clone()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, _, body) = request(&gateway, Method::GET, "/default/dir/big", &[auth], vec![]).await;
+        assert_eq!(body, big);
+        let range = format!("bytes={}-{}", PART_SIZE - 10, PART_SIZE * 2 + 9);
+        let (status, _, body) = request(&gateway, Method::GET, "/default/dir/big", &[auth, (RANGE.as_str(), &range)], vec![]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, &big[PART_SIZE - 10..PART_SIZE * 2 + 10]);
+
+        // Too large, announced
+        let length = (MAX_OBJECT_SIZE + 1).to_string();
+        let (status, _, _) = request(&gateway, Method::PUT, "/default/huge", &[auth, (CONTENT_LENGTH.as_str(), &length)], vec![]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Delete
+        let (status, _, _) = request(&gateway, Method::DELETE, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = request(&gateway, Method::HEAD, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Invalid paths and methods
+        let (status, _, _) = request(&gateway, Method::GET, "/other/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = request(&gateway, Method::GET, "/default/", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, headers, _) = request(&gateway, Method::POST, "/default/greeting", &[auth], vec![]).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[ALLOW], "GET, HEAD, PUT, DELETE");
+
+        daemon.abort();
+    }
+
+    #[test]
+    fn test_tokens() {
+        let tokens = Tokens::parse("# Backups\nsecret1\n\n  secret2  \n").unwrap();
+        assert!(tokens.check(Some("Bearer secret1")));
+        assert!(tokens.check(Some("Bearer secret2")));
+        assert!(!tokens.check(Some("Bearer secret3")));
+        assert!(!tokens.check(Some("Bearer # Backups")));
+        assert!(!tokens.check(Some("secret1")));
+        assert!(!tokens.check(Some("Basic c2VjcmV0MQ==")));
+        assert!(!tokens.check(None));
+
+        assert!(Tokens::parse("# Nothing\n\n").is_err());
+    }
+}
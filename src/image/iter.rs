@@ -0,0 +1,150 @@
+/// Iterates on block-aligned parts.
+///
+/// Parts are also split every `unit` bytes, which divides `block_size`.
+pub fn list_blocks(block_size: usize, unit: usize, start: usize, size: usize) -> ListBlocks {
+    ListBlocks {
+        block_size,
+        unit,
+        buf_pos: 0,
+        device_pos: start,
+        remaining_size: size,
+    }
+}
+
+pub struct ListBlocks {
+    block_size: usize,
+    unit: usize,
+    buf_pos: usize,
+    device_pos: usize,
+    remaining_size: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListBlockItem {
+    block_size: usize,
+    buf_start: usize,
+    device_start: usize,
+    size: usize,
+}
+
+impl ListBlockItem {
+    pub fn buf_start(&self) -> usize {
+        self.buf_start
+    }
+
+    pub fn buf_end(&self) -> usize {
+        self.buf_start + self.size
+    }
+
+    pub fn device_start(&self) -> usize {
+        self.device_start
+    }
+
+    pub fn block_num(&self) -> usize {
+        self.device_start / self.block_size
+    }
+
+    pub fn block_offset(&self) -> usize {
+        self.device_start % self.block_size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether this part covers its entire block.
+    pub fn is_whole_block(&self) -> bool {
+        self.size == self.block_size
+    }
+}
+
+impl Iterator for ListBlocks {
+    type Item = ListBlockItem;
+
+    fn next(&mut self) -> Option<ListBlockItem> {
+        if self.remaining_size > 0 {
+            let unit = self.device_pos / self.unit;
+            let end_unit = (unit + 1) * self.unit;
+            let size = self.remaining_size.min(end_unit - self.device_pos);
+            let item = ListBlockItem {
+                block_size: self.block_size,
+                buf_start: self.buf_pos,
+                device_start: self.device_pos,
+                size,
+            };
+            self.buf_pos += size;
+            self.device_pos += size;
+            self.remaining_size -= size;
+            Some(item)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ListBlockItem, list_blocks};
+
+    #[test]
+    fn test_iter() {
+        assert_eq!(
+            list_blocks(512, 512, 512, 1024).collect::<Vec<_>>(),
+            vec![
+                ListBlockItem {
+                    block_size: 512,
+                    buf_start: 0,
+                    device_start: 512,
+                    size: 512,
+                },
+                ListBlockItem {
+                    block_size: 512,
+                    buf_start: 512,
+                    device_start: 1024,
+                    size: 512,
+                },
+            ],
+        );
+
+        assert_eq!(
+            list_blocks(512, 512, 536, 200).collect::<Vec<_>>(),
+            vec![
+                ListBlockItem {
+                    block_size: 512,
+                    buf_start: 0,
+                    device_start: 536,
+                    size: 200,
+                },
+            ],
+        );
+
+        assert_eq!(
+            list_blocks(512, 512, 536, 700).collect::<Vec<_>>(),
+            vec![
+                ListBlockItem {
+                    block_size: 512,
+                    buf_start: 0,
+                    device_start: 536,
+                    size: 488,
+                },
+                ListBlockItem {
+                    block_size: 512,
+                    buf_start: 488,
+                    device_start: 1024,
+                    size: 212,
+                },
+            ],
+        );
+
+        let whole: Vec<bool> = list_blocks(512, 512, 536, 1536).map(|p| p.is_whole_block()).collect();
+        assert_eq!(whole, vec![false, true, true, false]);
+
+        // Blocks split in smaller units
+        let parts: Vec<_> = list_blocks(4096, 1024, 3000, 3000)
+            .map(|p| (p.block_num(), p.block_offset(), p.size(), p.buf_start()))
+            .collect();
+        assert_eq!(parts, vec![(0, 3000, 72, 0), (0, 3072, 1024, 72), (1, 0, 1024, 1096), (1, 1024, 880, 2120)]);
+        let whole: Vec<bool> = list_blocks(1024, 1024, 0, 3000).map(|p| p.is_whole_block()).collect();
+        assert_eq!(whole, vec![true, true, false]);
+    }
+}
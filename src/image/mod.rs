@@ -0,0 +1,651 @@
+//! Block device images, as exposed by the NBD gateway.
+//!
+//! An image is a metadata object holding its geometry, named after the image,
+//! and block objects named `<image>_<block number>`. Blocks that don't exist
+//! read as zeros, so images are thinly provisioned.
+//!
+//! While a gateway uses an image, it holds a lock, which is an object named
+//! `<image>.lock` describing the holder. The lock is a lease, which expires
+//! unless the holder renews it, so a gateway that crashed or lost its
+//! connection to the cluster doesn't keep the image locked.
+//!
+//! Pools can't take snapshots yet, so a snapshot of an image is a copy, which
+//! is an image named `<image>@<snapshot>`.
+
+pub mod iter;
+pub mod qcow2;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ObjectId;
+use crate::client::Client;
+use iter::list_blocks;
+
+/// Block size of images with the first metadata format, which only has the
+/// size.
+const V1_BLOCK_SIZE: usize = 512;
+
+const METADATA_V2: u8 = 2;
+
+pub const MIN_BLOCK_SIZE: usize = 512;
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Largest stripe unit, so that a request fits in a datagram.
+pub const MAX_STRIPE_UNIT: usize = 32 << 10;
+
+/// How long the lock on an image lasts, unless its holder renews it.
+pub const LOCK_LEASE: Duration = Duration::from_secs(60);
+
+/// How many objects to get per request when listing the blocks.
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// How an image is laid out in objects.
+///
+/// The image is stored in objects of `block_size` bytes, which are read and
+/// written in parts of at most `stripe_unit` bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub size: u64,
+    pub block_size: usize,
+    pub stripe_unit: usize,
+    /// No flag is defined yet, images with any set are refused.
+    pub flags: u32,
+}
+
+fn invalid(msg: String) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+impl Geometry {
+    /// Read the metadata object.
+    ///
+    /// The first format is only the size, as a big-endian u64. The second
+    /// format follows it with the version byte 2, then the block size, the
+    /// stripe unit, and the flags, as big-endian u32.
+    pub fn decode(data: &[u8]) -> Result<Geometry, IoError> {
+        let mut reader = Cursor::new(data);
+        let size = reader.read_u64::<BigEndian>()?;
+        if data.len() == 8 {
+            return Ok(Geometry {
+                size,
+                block_size: V1_BLOCK_SIZE,
+                stripe_unit: V1_BLOCK_SIZE,
+                flags: 0,
+            });
+        }
+
+        let version = reader.read_u8()?;
+        if version != METADATA_V2 {
+            return Err(invalid(format!("Unknown image metadata version {}", version)));
+        }
+        let geometry = Geometry {
+            size,
+            block_size: reader.read_u32::<BigEndian>()? as usize,
+            stripe_unit: reader.read_u32::<BigEndian>()? as usize,
+            flags: reader.read_u32::<BigEndian>()?,
+        };
+        if reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after image metadata".to_owned()));
+        }
+        geometry.check()?;
+        Ok(geometry)
+    }
+
+    /// Write the metadata object, always in the second format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.write_u64::<BigEndian>(self.size).unwrap();
+        result.write_u8(METADATA_V2).unwrap();
+        result.write_u32::<BigEndian>(self.block_size as u32).unwrap();
+        result.write_u32::<BigEndian>(self.stripe_unit as u32).unwrap();
+        result.write_u32::<BigEndian>(self.flags).unwrap();
+        result
+    }
+
+    /// Check that the block size and stripe unit can be used.
+    pub fn check(&self) -> Result<(), IoError> {
+        if !self.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return Err(invalid(format!(
+                "Invalid block size {}, should be a power of 2 between {} and {}",
+                self.block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE,
+            )));
+        }
+        if !self.stripe_unit.is_power_of_two()
+            || self.stripe_unit < MIN_BLOCK_SIZE
+            || self.stripe_unit > self.block_size.min(MAX_STRIPE_UNIT)
+        {
+            return Err(invalid(format!(
+                "Invalid stripe unit {}, should be a power of 2 between {} and {}",
+                self.stripe_unit, MIN_BLOCK_SIZE, self.block_size.min(MAX_STRIPE_UNIT),
+            )));
+        }
+        if self.flags != 0 {
+            return Err(invalid(format!("Unknown image flags 0x{:x}", self.flags)));
+        }
+        Ok(())
+    }
+
+    /// The number of blocks covering the image.
+    pub fn num_blocks(&self) -> u64 {
+        self.size.div_ceil(self.block_size as u64)
+    }
+}
+
+/// Parse a size in bytes, with an optional K, M, G, or T suffix (powers of
+/// 1024).
+pub fn parse_size(s: &str) -> Result<u64, IoError> {
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'K')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        Some((i, 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let bad_size = || IoError::new(ErrorKind::InvalidInput, format!("Invalid size {:?}", s));
+    let number: u64 = number.parse().map_err(|_| bad_size())?;
+    number.checked_mul(1 << shift).ok_or_else(bad_size)
+}
+
+/// The object holding a block of an image.
+pub fn block_object_id(image: &[u8], block_num: u64) -> ObjectId {
+    let mut object_id = image.to_owned();
+    write!(object_id, "_{}", block_num).unwrap();
+    ObjectId(object_id)
+}
+
+/// The name of the image holding a snapshot.
+pub fn snapshot_name(image: &[u8], snapshot: &str) -> Vec<u8> {
+    let mut name = image.to_owned();
+    name.push(b'@');
+    name.extend_from_slice(snapshot.as_bytes());
+    name
+}
+
+/// The object locking an image.
+pub fn lock_object_id(image: &[u8]) -> ObjectId {
+    let mut object_id = image.to_owned();
+    object_id.extend_from_slice(b".lock");
+    ObjectId(object_id)
+}
+
+/// Get the block number from the name of a block object.
+fn parse_block_object_id(image: &[u8], object_id: &ObjectId) -> Option<u64> {
+    let suffix = object_id.0.strip_prefix(image)?.strip_prefix(b"_")?;
+    let suffix = std::str::from_utf8(suffix).ok()?;
+    let block_num: u64 = suffix.parse().ok()?;
+    // Only take the canonical form, not "+1" or "01"
+    if block_num.to_string() != suffix {
+        return None;
+    }
+    Some(block_num)
+}
+
+/// Read the geometry of an image, if it exists.
+pub async fn read_image(client: &Client, image: &[u8]) -> Result<Option<Geometry>, IoError> {
+    match client.read_object(&ObjectId(image.to_owned())).await? {
+        Some(metadata) => Ok(Some(Geometry::decode(&metadata)?)),
+        None => Ok(None),
+    }
+}
+
+fn not_found(image: &[u8]) -> IoError {
+    IoError::new(ErrorKind::NotFound, format!("No image {:?}", String::from_utf8_lossy(image)))
+}
+
+/// Create an image, failing if it already exists.
+///
+/// If `preallocate` is set, all the blocks are written with zeros.
+pub async fn create_image(client: &Client, image: &[u8], geometry: &Geometry, preallocate: bool) -> Result<(), IoError> {
+    geometry.check()?;
+    let metadata_id = ObjectId(image.to_owned());
+    if client.compare_and_swap(&metadata_id, None, &geometry.encode()).await?.is_none() {
+        return Err(IoError::new(
+            ErrorKind::AlreadyExists,
+            format!("Image {:?} already exists", String::from_utf8_lossy(image)),
+        ));
+    }
+
+    if preallocate {
+        for block_num in 0..geometry.num_blocks() {
+            zero_block(client, image, geometry, block_num, 0).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Change the size of an image.
+///
+/// When shrinking, the blocks past the new end are deleted, and the end of
+/// the last block is zeroed, so that growing again reads zeros. Fails if the
+/// image is locked.
+pub async fn resize_image(client: &Client, image: &[u8], size: u64) -> Result<Geometry, IoError> {
+    let old = read_image(client, image).await?.ok_or_else(|| not_found(image))?;
+    check_unlocked(client, image).await?;
+    let geometry = Geometry { size, ..old.clone() };
+    client.write_object(&ObjectId(image.to_owned()), &geometry.encode()).await?;
+
+    if size < old.size {
+        let block_size = geometry.block_size as u64;
+        let end_offset = (size % block_size) as usize;
+        for block_num in list_image_blocks(client, image).await? {
+            if block_num >= geometry.num_blocks() {
+                client.delete_object(&block_object_id(image, block_num)).await?;
+            } else if end_offset != 0 && block_num == size / block_size {
+                zero_block(client, image, &geometry, block_num, end_offset).await?;
+            }
+        }
+    }
+    Ok(geometry)
+}
+
+/// Delete an image and its blocks.
+///
+/// The blocks are deleted first, so this can be tried again if interrupted.
+/// Fails if the image is locked.
+pub async fn delete_image(client: &Client, image: &[u8]) -> Result<(), IoError> {
+    read_image(client, image).await?.ok_or_else(|| not_found(image))?;
+    check_unlocked(client, image).await?;
+    for block_num in list_image_blocks(client, image).await? {
+        client.delete_object(&block_object_id(image, block_num)).await?;
+    }
+    client.delete_object(&ObjectId(image.to_owned())).await
+}
+
+/// Copy an image to a snapshot, which gateways can export read-only.
+///
+/// Fails if the image is locked, since writes during the copy would make the
+/// snapshot inconsistent. The snapshot only appears once it is complete.
+pub async fn snapshot_image(client: &Client, image: &[u8], snapshot: &str) -> Result<(), IoError> {
+    let geometry = read_image(client, image).await?.ok_or_else(|| not_found(image))?;
+    check_unlocked(client, image).await?;
+    let snapshot = snapshot_name(image, snapshot);
+    if read_image(client, &snapshot).await?.is_some() {
+        return Err(IoError::new(
+            ErrorKind::AlreadyExists,
+            format!("Snapshot {:?} already exists", String::from_utf8_lossy(&snapshot)),
+        ));
+    }
+
+    for block_num in list_image_blocks(client, image).await? {
+        if block_num >= geometry.num_blocks() {
+            continue;
+        }
+        let data = match client.read_object(&block_object_id(image, block_num)).await? {
+            Some(d) => d,
+            None => continue,
+        };
+        let object_id = block_object_id(&snapshot, block_num);
+        for (i, part) in data.chunks(geometry.stripe_unit).enumerate() {
+            client.write_part(&object_id, (i * geometry.stripe_unit) as u32, part).await?;
+        }
+    }
+    create_image(client, &snapshot, &geometry, false).await
+}
+
+/// Create an image from the content of a disk, such as a raw file or a
+/// `qcow2::Qcow2Reader`.
+///
+/// Blocks that are all zeros are not written. Like snapshots, the image only
+/// appears once all its blocks are written.
+pub async fn import_image(client: &Client, image: &[u8], geometry: &Geometry, mut reader: impl Read) -> Result<(), IoError> {
+    geometry.check()?;
+    if read_image(client, image).await?.is_some() {
+        return Err(IoError::new(
+            ErrorKind::AlreadyExists,
+            format!("Image {:?} already exists", String::from_utf8_lossy(image)),
+        ));
+    }
+
+    let mut block = vec![0; geometry.block_size];
+    for block_num in 0..geometry.num_blocks() {
+        let start = block_num as usize * geometry.block_size;
+        let len = (geometry.size - start as u64).min(geometry.block_size as u64) as usize;
+        read_full(&mut reader, &mut block[..len])?;
+        if block[..len].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let object_id = block_object_id(image, block_num);
+        for part in list_blocks(geometry.block_size, geometry.stripe_unit, start, len) {
+            client.write_part(&object_id, part.block_offset() as u32, &block[part.buf_start()..part.buf_end()]).await?;
+        }
+    }
+    create_image(client, image, geometry, false).await
+}
+
+/// Fill a buffer, with zeros after the end of the data.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), IoError> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match reader.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buf[pos..].fill(0);
+    Ok(())
+}
+
+/// Write the content of an image as a raw disk.
+///
+/// Blocks that don't exist or are all zeros are skipped rather than written,
+/// so the caller should set the length of the output to the size of the
+/// image, making a sparse file. Fails if the image is locked, export a
+/// snapshot instead.
+pub async fn export_image(client: &Client, image: &[u8], mut writer: impl Write + Seek) -> Result<Geometry, IoError> {
+    let geometry = read_image(client, image).await?.ok_or_else(|| not_found(image))?;
+    check_unlocked(client, image).await?;
+    for block_num in 0..geometry.num_blocks() {
+        let start = block_num * geometry.block_size as u64;
+        let len = (geometry.size - start).min(geometry.block_size as u64) as usize;
+        let data = match client.read_object(&block_object_id(image, block_num)).await? {
+            Some(d) => d,
+            None => continue,
+        };
+        let data = &data[..len.min(data.len())];
+        if data.iter().all(|&b| b == 0) {
+            continue;
+        }
+        writer.seek(SeekFrom::Start(start))?;
+        writer.write_all(data)?;
+    }
+    writer.flush()?;
+    Ok(geometry)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The content of the lock object of an image.
+#[derive(Debug, PartialEq, Eq)]
+struct ImageLock {
+    holder: String,
+    /// When the lease ends, in seconds since 1970.
+    expires: u64,
+}
+
+impl ImageLock {
+    /// A lease for `holder`, starting now.
+    fn new(holder: &str) -> ImageLock {
+        ImageLock { holder: holder.to_owned(), expires: now() + LOCK_LEASE.as_secs() }
+    }
+
+    /// Encode the lock as the end of the lease, a space, and the holder.
+    fn encode(&self) -> Vec<u8> {
+        format!("{} {}", self.expires, self.holder).into_bytes()
+    }
+
+    /// Decode a lock, those from older gateways are only the holder and don't
+    /// expire.
+    fn decode(data: &[u8]) -> ImageLock {
+        let data = String::from_utf8_lossy(data);
+        let lease = data.split_once(' ').and_then(|(expires, holder)| Some((expires.parse().ok()?, holder)));
+        match lease {
+            Some((expires, holder)) => ImageLock { holder: holder.to_owned(), expires },
+            None => ImageLock { holder: data.into_owned(), expires: u64::MAX },
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires < now()
+    }
+}
+
+/// Take the lock on an image, so that a single gateway writes to it.
+///
+/// `holder` describes who takes the lock, for those who then can't, and has
+/// to be unique. The lock is a lease of `LOCK_LEASE`, which the holder has to
+/// renew with `renew_image_lock()`; a lock whose lease ended is taken over.
+/// With `force`, the lock is taken even if it is held, for example by a
+/// gateway that crashed with a lock from an older version, which doesn't
+/// expire.
+pub async fn lock_image(client: &Client, image: &[u8], holder: &str, force: bool) -> Result<(), IoError> {
+    let lock_id = lock_object_id(image);
+    let lock = ImageLock::new(holder).encode();
+    let current = match client.read_object(&lock_id).await? {
+        None if client.compare_and_swap(&lock_id, None, &lock).await?.is_some() => return Ok(()),
+        None => client.read_object(&lock_id).await?.unwrap_or_default(),
+        Some(current) => current,
+    };
+    let current_lock = ImageLock::decode(&current);
+    if current_lock.is_expired() || force {
+        warn!(
+            "Taking over the lock on image {:?} from {}",
+            String::from_utf8_lossy(image), current_lock.holder,
+        );
+        // Fail if someone else is taking it over too
+        if client.compare_and_swap(&lock_id, Some(&current), &lock).await?.is_some() {
+            return Ok(());
+        }
+    }
+    Err(IoError::new(
+        ErrorKind::AlreadyExists,
+        format!("Image {:?} is locked by {}", String::from_utf8_lossy(image), current_lock.holder),
+    ))
+}
+
+/// Extend the lease on an image taken by `lock_image()`.
+///
+/// Fails with `PermissionDenied` if someone else took the lock over, in which
+/// case the image must not be written to anymore.
+pub async fn renew_image_lock(client: &Client, image: &[u8], holder: &str) -> Result<(), IoError> {
+    let lock_id = lock_object_id(image);
+    let taken_over = || IoError::new(
+        ErrorKind::PermissionDenied,
+        format!("Lock on image {:?} was taken over", String::from_utf8_lossy(image)),
+    );
+    let current = client.read_object(&lock_id).await?.ok_or_else(taken_over)?;
+    if ImageLock::decode(&current).holder != holder {
+        return Err(taken_over());
+    }
+    match client.compare_and_swap(&lock_id, Some(&current), &ImageLock::new(holder).encode()).await? {
+        Some(_) => Ok(()),
+        None => Err(taken_over()),
+    }
+}
+
+/// Release the lock on an image, unless someone else took it over.
+pub async fn unlock_image(client: &Client, image: &[u8], holder: &str) -> Result<(), IoError> {
+    let lock_id = lock_object_id(image);
+    match client.read_object(&lock_id).await? {
+        Some(current) if ImageLock::decode(&current).holder == holder => client.delete_object(&lock_id).await,
+        _ => {
+            warn!("Lock on image {:?} was taken over", String::from_utf8_lossy(image));
+            Ok(())
+        }
+    }
+}
+
+/// Refuse to change an image that a gateway is using.
+async fn check_unlocked(client: &Client, image: &[u8]) -> Result<(), IoError> {
+    match image_lock_holder(client, image).await? {
+        None => Ok(()),
+        Some(holder) => Err(IoError::new(
+            ErrorKind::AlreadyExists,
+            format!("Image {:?} is in use, locked by {}", String::from_utf8_lossy(image), holder),
+        )),
+    }
+}
+
+/// Get who holds the lock on an image, if anyone and its lease didn't end.
+pub async fn image_lock_holder(client: &Client, image: &[u8]) -> Result<Option<String>, IoError> {
+    let lock = client.read_object(&lock_object_id(image)).await?.map(|l| ImageLock::decode(&l));
+    Ok(lock.filter(|l| !l.is_expired()).map(|l| l.holder))
+}
+
+/// List the blocks of an image that exist in the storage, in no particular
+/// order.
+///
+/// This can include block numbers past the end of the image, left over from
+/// an interrupted resize.
+pub async fn list_image_blocks(client: &Client, image: &[u8]) -> Result<Vec<u64>, IoError> {
+    let mut prefix = image.to_owned();
+    prefix.push(b'_');
+    let mut blocks = Vec::new();
+    let mut start_after = None;
+    loop {
+        let page = client.list_objects(&prefix, start_after.as_ref(), LIST_PAGE_SIZE).await?;
+        let last = match page.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        blocks.extend(page.iter().filter_map(|o| parse_block_object_id(image, o)));
+        start_after = Some(last);
+    }
+    Ok(blocks)
+}
+
+/// Write zeros from `offset` to the end of a block, one stripe unit at a time.
+async fn zero_block(client: &Client, image: &[u8], geometry: &Geometry, block_num: u64, offset: usize) -> Result<(), IoError> {
+    let object_id = block_object_id(image, block_num);
+    let zeros = vec![0; geometry.stripe_unit];
+    let mut pos = offset;
+    while pos < geometry.block_size {
+        // Align the following parts to the stripe unit
+        let len = geometry.stripe_unit - pos % geometry.stripe_unit;
+        client.write_part(&object_id, pos as u32, &zeros[..len]).await?;
+        pos += len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::{ObjectId, PoolName};
+    use crate::client::create_client_with_socket;
+    use crate::daemon::spawn_test_daemon;
+    use crate::netsim::{SimConfig, SimNetwork, Socket};
+    use super::{
+        Geometry, ImageLock, block_object_id, image_lock_holder, lock_image, lock_object_id, parse_block_object_id,
+        parse_size, renew_image_lock, snapshot_name, unlock_image,
+    };
+
+    #[test]
+    fn test_metadata() {
+        // First format
+        let geometry = Geometry::decode(b"\x00\x00\x00\x00\x06\x40\x00\x00").unwrap();
+        assert_eq!(
+            geometry,
+            Geometry { size: 100 << 20, block_size: 512, stripe_unit: 512, flags: 0 },
+        );
+        assert_eq!(geometry.num_blocks(), 204800);
+
+        let geometry = Geometry { size: 1 << 30, block_size: 65536, stripe_unit: 16384, flags: 0 };
+        let encoded = geometry.encode();
+        assert_eq!(encoded.len(), 21);
+        assert_eq!(Geometry::decode(&encoded).unwrap(), geometry);
+        assert_eq!(Geometry { size: 65537, ..geometry }.num_blocks(), 2);
+
+        let check = |block_size, stripe_unit, flags| {
+            Geometry { size: 1 << 30, block_size, stripe_unit, flags }.encode()
+        };
+        assert!(Geometry::decode(&check(4096, 4096, 0)).is_ok());
+        assert!(Geometry::decode(&check(1 << 20, 32768, 0)).is_ok());
+        assert!(Geometry::decode(&check(3000, 512, 0)).is_err());
+        assert!(Geometry::decode(&check(256, 256, 0)).is_err());
+        assert!(Geometry::decode(&check(2 << 20, 4096, 0)).is_err());
+        assert!(Geometry::decode(&check(4096, 8192, 0)).is_err());
+        assert!(Geometry::decode(&check(65536, 65536, 0)).is_err());
+        assert!(Geometry::decode(&check(4096, 4096, 1)).is_err());
+
+        // Bad version, truncated, or too long
+        let mut bad = check(4096, 4096, 0);
+        bad[8] = 3;
+        assert!(Geometry::decode(&bad).is_err());
+        assert!(Geometry::decode(&check(4096, 4096, 0)[..15]).is_err());
+        let mut long = check(4096, 4096, 0);
+        long.push(0);
+        assert!(Geometry::decode(&long).is_err());
+        assert!(Geometry::decode(b"\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("64K").unwrap(), 65536);
+        assert_eq!(parse_size("100M").unwrap(), 100 << 20);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("10k").is_err());
+        assert!(parse_size("100000000T").is_err());
+    }
+
+    #[test]
+    fn test_block_names() {
+        assert_eq!(block_object_id(b"disk", 0), ObjectId(b"disk_0".to_vec()));
+        assert_eq!(block_object_id(b"disk", 1234), ObjectId(b"disk_1234".to_vec()));
+
+        let parse = |name: &[u8]| parse_block_object_id(b"disk", &ObjectId(name.to_vec()));
+        assert_eq!(parse(b"disk_1234"), Some(1234));
+        assert_eq!(parse(b"disk_0"), Some(0));
+        assert_eq!(parse(b"disk"), None);
+        assert_eq!(parse(b"disk_"), None);
+        assert_eq!(parse(b"disk_01"), None);
+        assert_eq!(parse(b"disk_+1"), None);
+        assert_eq!(parse(b"disk_backup"), None);
+        assert_eq!(parse(b"other_1"), None);
+        assert_eq!(parse(&lock_object_id(b"disk").0), None);
+        assert_eq!(parse(&block_object_id(&snapshot_name(b"disk", "monday"), 3).0), None);
+        assert_eq!(block_object_id(&snapshot_name(b"disk", "monday"), 3), ObjectId(b"disk@monday_3".to_vec()));
+    }
+
+    #[test]
+    fn test_lock_encoding() {
+        let lock = ImageLock { holder: "gateway on host1".to_owned(), expires: 1700000000 };
+        assert_eq!(lock.encode(), b"1700000000 gateway on host1");
+        assert_eq!(ImageLock::decode(&lock.encode()), lock);
+        assert!(lock.is_expired());
+        assert!(!ImageLock::new("gateway").is_expired());
+
+        // Older locks don't expire
+        let old = ImageLock::decode(b"nbd-gateway on host1, started at 1700000000");
+        assert_eq!(old.holder, "nbd-gateway on host1, started at 1700000000");
+        assert!(!old.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_lock_lease() {
+        let network = SimNetwork::new(1, SimConfig::default());
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+        let socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+
+        lock_image(&client, b"disk", "gateway 1", false).await.unwrap();
+        assert_eq!(image_lock_holder(&client, b"disk").await.unwrap().as_deref(), Some("gateway 1"));
+        let err = lock_image(&client, b"disk", "gateway 2", false).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        renew_image_lock(&client, b"disk", "gateway 1").await.unwrap();
+
+        // Once taken over, the lease can't be renewed or released
+        lock_image(&client, b"disk", "gateway 2", true).await.unwrap();
+        let err = renew_image_lock(&client, b"disk", "gateway 1").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        unlock_image(&client, b"disk", "gateway 1").await.unwrap();
+        assert_eq!(image_lock_holder(&client, b"disk").await.unwrap().as_deref(), Some("gateway 2"));
+
+        // A lease that ended is taken over without force
+        let expired = ImageLock { holder: "gateway 2".to_owned(), expires: 1 };
+        client.write_object(&lock_object_id(b"disk"), &expired.encode()).await.unwrap();
+        assert_eq!(image_lock_holder(&client, b"disk").await.unwrap(), None);
+        lock_image(&client, b"disk", "gateway 3", false).await.unwrap();
+        assert!(renew_image_lock(&client, b"disk", "gateway 2").await.is_err());
+
+        unlock_image(&client, b"disk", "gateway 3").await.unwrap();
+        assert_eq!(client.read_object(&lock_object_id(b"disk")).await.unwrap(), None);
+
+        daemon.abort();
+    }
+}
@@ -0,0 +1,262 @@
+//! Reading qcow2 disk images, as written by QEMU, to import them.
+//!
+//! Only plain images are supported: no compressed clusters, encryption,
+//! backing file, or external data file. `qemu-img convert` can turn other
+//! images into plain ones.
+
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom};
+
+pub const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Where the offset is in L1 and L2 table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+/// The cluster reads as zeros (version 3).
+const ZERO_FLAG: u64 = 1;
+
+/// Incompatible features of version 3 that don't prevent reading: the dirty
+/// bit (only refcounts might be wrong) and the compression type.
+const KNOWN_INCOMPATIBLE_FEATURES: u64 = 1 | 1 << 3;
+
+/// Largest L1 table we accept, 32 MiB (qemu's limit).
+const MAX_L1_SIZE: u64 = 32 << 20;
+
+/// Whether the start of a file is the header of a qcow2 image.
+pub fn is_qcow2(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+fn unsupported(msg: &str) -> IoError {
+    IoError::new(ErrorKind::Unsupported, format!("{}, convert the image with qemu-img first", msg))
+}
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Reads the content of the disk in a qcow2 image, sequentially.
+///
+/// Clusters that are not allocated read as zeros.
+pub struct Qcow2Reader<R> {
+    inner: R,
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_table: Vec<u64>,
+    /// The last L2 table read, with its offset in the file.
+    l2_table: Option<(u64, Vec<u64>)>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Qcow2Reader<R> {
+    pub fn new(mut inner: R) -> Result<Qcow2Reader<R>, IoError> {
+        inner.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a qcow2 image"));
+        }
+        let version = inner.read_u32::<BigEndian>()?;
+        if version != 2 && version != 3 {
+            return Err(invalid(&format!("Unknown qcow2 version {}", version)));
+        }
+        let backing_file_offset = inner.read_u64::<BigEndian>()?;
+        let _backing_file_size = inner.read_u32::<BigEndian>()?;
+        let cluster_bits = inner.read_u32::<BigEndian>()?;
+        let size = inner.read_u64::<BigEndian>()?;
+        let crypt_method = inner.read_u32::<BigEndian>()?;
+        let l1_size = inner.read_u32::<BigEndian>()? as u64;
+        let l1_table_offset = inner.read_u64::<BigEndian>()?;
+        if version >= 3 {
+            // Skip refcount table and snapshots
+            inner.seek(SeekFrom::Start(72))?;
+            let incompatible_features = inner.read_u64::<BigEndian>()?;
+            if incompatible_features & !KNOWN_INCOMPATIBLE_FEATURES != 0 {
+                return Err(unsupported(&format!(
+                    "Unsupported qcow2 features 0x{:x}",
+                    incompatible_features & !KNOWN_INCOMPATIBLE_FEATURES,
+                )));
+            }
+        }
+
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(&format!("Invalid qcow2 cluster size 2^{}", cluster_bits)));
+        }
+        if backing_file_offset != 0 {
+            return Err(unsupported("Images with a backing file are not supported"));
+        }
+        if crypt_method != 0 {
+            return Err(unsupported("Encrypted images are not supported"));
+        }
+        let l2_bits = cluster_bits - 3;
+        let needed_l1_size = size.div_ceil(1 << (cluster_bits + l2_bits));
+        if l1_size < needed_l1_size || l1_size * 8 > MAX_L1_SIZE {
+            return Err(invalid(&format!("Invalid qcow2 L1 table size {}", l1_size)));
+        }
+
+        inner.seek(SeekFrom::Start(l1_table_offset))?;
+        let mut l1_table = vec![0; l1_size as usize];
+        inner.read_u64_into::<BigEndian>(&mut l1_table)?;
+
+        Ok(Qcow2Reader {
+            inner,
+            version,
+            cluster_bits,
+            size,
+            l1_table,
+            l2_table: None,
+            pos: 0,
+        })
+    }
+
+    /// The size of the disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Find where the cluster holding a disk offset is in the file, `None` if
+    /// it reads as zeros.
+    fn cluster_offset(&mut self, offset: u64) -> Result<Option<u64>, IoError> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize;
+
+        let l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+        let table = match &mut self.l2_table {
+            Some((o, table)) if *o == l2_offset => table,
+            l2_table => {
+                let mut table = vec![0; 1 << l2_bits];
+                self.inner.seek(SeekFrom::Start(l2_offset))?;
+                self.inner.read_u64_into::<BigEndian>(&mut table)?;
+                &mut l2_table.insert((l2_offset, table)).1
+            }
+        };
+
+        let entry = table[l2_index];
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(unsupported("Compressed qcow2 clusters are not supported"));
+        }
+        if self.version >= 3 && entry & ZERO_FLAG != 0 {
+            return Ok(None);
+        }
+        match entry & OFFSET_MASK {
+            0 => Ok(None),
+            host_offset => Ok(Some(host_offset)),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for Qcow2Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Read up to the end of the cluster
+        let cluster_size = 1 << self.cluster_bits;
+        let cluster_pos = self.pos & (cluster_size - 1);
+        let len = (buf.len() as u64)
+            .min(cluster_size - cluster_pos)
+            .min(self.size - self.pos) as usize;
+        let buf = &mut buf[..len];
+        match self.cluster_offset(self.pos)? {
+            Some(host_offset) => {
+                self.inner.seek(SeekFrom::Start(host_offset + cluster_pos))?;
+                // The file can end before the last cluster does
+                let mut read = 0;
+                while read < len {
+                    match self.inner.read(&mut buf[read..])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                buf[read..].fill(0);
+            }
+            None => buf.fill(0),
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::io::{Cursor, ErrorKind, Read};
+
+    use super::{MAGIC, Qcow2Reader, is_qcow2};
+
+    /// Build an image with 512-byte clusters, its L1 table in the second
+    /// cluster and its L2 table in the third.
+    fn build_image(version: u32, size: u64, l2_entries: &[u64]) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(MAGIC);
+        image.write_u32::<BigEndian>(version).unwrap();
+        image.write_u64::<BigEndian>(0).unwrap(); // backing file
+        image.write_u32::<BigEndian>(0).unwrap();
+        image.write_u32::<BigEndian>(9).unwrap(); // cluster bits
+        image.write_u64::<BigEndian>(size).unwrap();
+        image.write_u32::<BigEndian>(0).unwrap(); // encryption
+        image.write_u32::<BigEndian>(1).unwrap(); // L1 size
+        image.write_u64::<BigEndian>(512).unwrap(); // L1 offset
+        image.resize(512, 0);
+        image.write_u64::<BigEndian>(1024).unwrap();
+        image.resize(1024, 0);
+        for &entry in l2_entries {
+            image.write_u64::<BigEndian>(entry).unwrap();
+        }
+        image.resize(1536, 0);
+        image
+    }
+
+    fn read_all(image: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut reader = Qcow2Reader::new(Cursor::new(image))?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        assert_eq!(data.len() as u64, reader.size());
+        Ok(data)
+    }
+
+    #[test]
+    fn test_qcow2() {
+        // Clusters 0 and 3 are not allocated, 1 is stored at 2048 and 2 at 1536
+        let mut image = build_image(2, 2000, &[0, 2048, 1536, 0]);
+        assert!(is_qcow2(&image));
+        image.extend_from_slice(&[b'a'; 512]);
+        image.extend_from_slice(&[b'b'; 100]);
+        let data = read_all(image).unwrap();
+        assert_eq!(&data[..512], &[0; 512]);
+        assert_eq!(&data[512..612], &[b'b'; 100]);
+        assert_eq!(&data[612..1024], &[0; 412]);
+        assert_eq!(&data[1024..1536], &[b'a'; 512]);
+        assert_eq!(&data[1536..], &[0; 464]);
+
+        // Zero flag of version 3
+        let mut image = build_image(3, 1024, &[1536 | 1, 1536]);
+        image.extend_from_slice(&[b'a'; 512]);
+        let data = read_all(image).unwrap();
+        assert_eq!(&data[..512], &[0; 512]);
+        assert_eq!(&data[512..], &[b'a'; 512]);
+
+        // Compressed clusters
+        let mut image = build_image(2, 1024, &[0, 1 << 62 | 1536]);
+        image.extend_from_slice(&[b'a'; 512]);
+        assert_eq!(read_all(image).unwrap_err().kind(), ErrorKind::Unsupported);
+
+        // Backing file
+        let mut image = build_image(2, 1024, &[]);
+        image[15] = 200;
+        assert_eq!(read_all(image).unwrap_err().kind(), ErrorKind::Unsupported);
+
+        // L1 table too small
+        let image = build_image(2, 1 << 20, &[]);
+        assert_eq!(read_all(image).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        assert!(!is_qcow2(b"\0\0\0\0\0\0\0\0"));
+        assert!(Qcow2Reader::new(Cursor::new(vec![0; 1024])).is_err());
+    }
+}
@@ -1,32 +1,92 @@
+pub mod admin;
+pub mod archive;
+pub mod bench;
+pub mod buffer_pool;
 pub mod client;
+pub mod config;
+pub mod copy;
 pub mod crypto;
 pub mod daemon;
+#[cfg(feature = "dtls")]
+pub mod dtls;
+pub mod error;
+pub mod gc;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 mod hash;
+pub mod http_gateway;
+pub mod image;
 pub mod master;
 pub mod metrics;
+pub mod mirror;
+pub mod netsim;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod progress;
 pub mod proto;
+pub mod provisioner;
+pub mod redis_gateway;
+pub mod s3_gateway;
 pub mod storage;
 pub mod storage_map;
+pub mod striping;
+pub mod systemd;
+pub mod udp_batch;
+pub mod watch;
 
+pub use error::Error;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, SeqAccess, Visitor};
 use std::fmt::Debug;
 
 /// The ID of a device, which also identifies the storage daemon for it.
+///
+/// Serialized as 32 hexadecimal digits in human-readable formats such as
+/// JSON, and as bytes otherwise.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct DeviceId(pub [u8; 16]);
 
 /// The name of a storage pool.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+///
+/// Pool names are 1 to `MAX_POOL_NAME_LEN` ASCII letters, digits, `-`, `_`
+/// and `.`, not starting with `.`, as storage backends use them as file
+/// names. Use `PoolName::new()` to check them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
 pub struct PoolName(pub String);
 
 /// The name of an object, which can be freely picked by clients.
+///
+/// Object IDs are 1 to `MAX_OBJECT_ID_LEN` bytes. Use `ObjectId::new()` to
+/// check them. Serialized as base64 in human-readable formats, and as bytes
+/// otherwise.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct ObjectId(pub Vec<u8>);
 
+/// Longest pool name, in bytes.
+pub const MAX_POOL_NAME_LEN: usize = 64;
+
+/// Longest object ID, in bytes.
+///
+/// The wire protocol could carry IDs up to 4 GiB, but a request has to fit in
+/// a datagram with a 32 KiB part of the object, the capability and headers.
+pub const MAX_OBJECT_ID_LEN: usize = 4096;
+
+/// Most tags on an object.
+pub const MAX_TAGS: usize = 16;
+
+/// Longest tag, in bytes.
+pub const MAX_TAG_LEN: usize = 64;
+
 /// The ID for a group of objects.
 ///
 /// Objects are assembled into groups using hashes. The procedure depends on
 /// the current number of groups, which changes over time.
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct GroupId(pub u32);
 
 impl Debug for DeviceId {
@@ -39,12 +99,238 @@ impl Debug for DeviceId {
     }
 }
 
+impl std::str::FromStr for DeviceId {
+    type Err = std::io::Error;
+
+    /// Parse 32 hexadecimal digits, optionally separated by colons like the
+    /// `Debug` output.
+    fn from_str(s: &str) -> Result<DeviceId, std::io::Error> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid device ID");
+        let digits: Vec<u8> = s.bytes().filter(|&b| b != b':').collect();
+        if digits.len() != 32 {
+            return Err(invalid());
+        }
+        let mut id = [0; 16];
+        for (byte, pair) in id.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(DeviceId(id))
+    }
+}
+
+impl PoolName {
+    /// Make a pool name, checking that it is valid.
+    pub fn new(name: impl Into<String>) -> Result<PoolName, std::io::Error> {
+        let name = PoolName(name.into());
+        name.check()?;
+        Ok(name)
+    }
+
+    /// Check that the name is valid, for names not made with `new()`.
+    pub fn check(&self) -> Result<(), std::io::Error> {
+        let invalid = |reason: &str| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid pool name {:?}: {}", self.0, reason),
+        );
+        if self.0.is_empty() {
+            return Err(invalid("empty"));
+        }
+        if self.0.len() > MAX_POOL_NAME_LEN {
+            return Err(invalid(&format!("longer than {} bytes", MAX_POOL_NAME_LEN)));
+        }
+        if !self.0.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.') {
+            return Err(invalid("only letters, digits, '-', '_' and '.' are allowed"));
+        }
+        if self.0.starts_with('.') {
+            return Err(invalid("can't start with '.'"));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for PoolName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::str::FromStr for PoolName {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<PoolName, std::io::Error> {
+        PoolName::new(s)
+    }
+}
+
+impl ObjectId {
+    /// Make an object ID, checking that it is valid.
+    pub fn new(id: impl Into<Vec<u8>>) -> Result<ObjectId, std::io::Error> {
+        let id = ObjectId(id.into());
+        id.check()?;
+        Ok(id)
+    }
+
+    /// Check that the ID is valid, for IDs not made with `new()`.
+    pub fn check(&self) -> Result<(), std::io::Error> {
+        if self.0.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty object ID"));
+        }
+        if self.0.len() > MAX_OBJECT_ID_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Object ID is {} bytes, longer than {}", self.0.len(), MAX_OBJECT_ID_LEN),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check the tags of an object against the limits.
+///
+/// Tags are 1 to `MAX_TAG_LEN` bytes without whitespace or control
+/// characters, at most `MAX_TAGS` per object, and can't repeat.
+pub fn check_tags(tags: &[String]) -> Result<(), std::io::Error> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    if tags.len() > MAX_TAGS {
+        return Err(invalid(format!("Objects have at most {} tags", MAX_TAGS)));
+    }
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(invalid(format!("Tags are 1 to {} bytes", MAX_TAG_LEN)));
+        }
+        if tag.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(invalid(format!("Invalid tag {:?}", tag)));
+        }
+        if tags[..i].contains(tag) {
+            return Err(invalid(format!("Repeated tag {:?}", tag)));
+        }
+    }
+    Ok(())
+}
+
+/// Bytes as lowercase hexadecimal digits.
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse hexadecimal digits, `None` if there is anything else or an odd
+/// number of them.
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => std::str::from_utf8(pair).ok().and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Debug for ObjectId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "ObjectId({})", String::from_utf8_lossy(&self.0))
     }
 }
 
+impl Serialize for DeviceId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&encode_hex(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+struct DeviceIdVisitor;
+
+impl<'de> Visitor<'de> for DeviceIdVisitor {
+    type Value = DeviceId;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a device ID")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<DeviceId, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<DeviceId, E> {
+        let id = value.try_into().map_err(|_| E::invalid_length(value.len(), &self))?;
+        Ok(DeviceId(id))
+    }
+
+    /// An array of 16 numbers, which is how IDs used to be serialized.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DeviceId, A::Error> {
+        let mut id = [0; 16];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(17, &self));
+        }
+        Ok(DeviceId(id))
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceId, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(DeviceIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(DeviceIdVisitor)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PoolName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PoolName, D::Error> {
+        PoolName::new(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ObjectId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64.encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+struct ObjectIdVisitor;
+
+impl<'de> Visitor<'de> for ObjectIdVisitor {
+    type Value = ObjectId;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "an object ID")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<ObjectId, E> {
+        let id = BASE64.decode(value).map_err(E::custom)?;
+        ObjectId::new(id).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<ObjectId, E> {
+        ObjectId::new(value).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ObjectId, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(ObjectIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(ObjectIdVisitor)
+        }
+    }
+}
+
 impl Debug for GroupId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "GroupId(0x{:04x})", self.0)
@@ -54,7 +340,7 @@ impl Debug for GroupId {
 #[cfg(test)]
 mod tests {
     use std::fmt::Write;
-    use super::DeviceId;
+    use super::{DeviceId, GroupId, MAX_OBJECT_ID_LEN, MAX_POOL_NAME_LEN, MAX_TAG_LEN, MAX_TAGS, ObjectId, PoolName, check_tags, decode_hex, encode_hex};
 
     #[test]
     fn test_deviceid_debug() {
@@ -66,4 +352,87 @@ mod tests {
             "DeviceId(01:02:03:04:05:06:07:08:09:0a:0b:0c:0d:0e:0f:10)"
         );
     }
+
+    #[test]
+    fn test_deviceid_parse() {
+        let id = DeviceId([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0xff]);
+        assert_eq!("01:02:03:04:05:06:07:08:09:0a:0b:0c:0d:0e:0f:ff".parse::<DeviceId>().unwrap(), id);
+        assert_eq!("0102030405060708090a0b0c0d0e0fFF".parse::<DeviceId>().unwrap(), id);
+        assert!("0102".parse::<DeviceId>().is_err());
+        assert!("0102030405060708090a0b0c0d0e0fzz".parse::<DeviceId>().is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let mut after = b"dir/".to_vec();
+        after.push(0xff);
+        assert_eq!(encode_hex(&after), "6469722fff");
+        assert_eq!(decode_hex("6469722fff"), Some(after));
+        assert_eq!(decode_hex("0AFF"), Some(vec![10, 255]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        assert_eq!(decode_hex("0aff.expires"), None);
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(PoolName::new("default").is_ok());
+        assert!(PoolName::new("my-pool_2.old").is_ok());
+        assert!("x".repeat(MAX_POOL_NAME_LEN).parse::<PoolName>().is_ok());
+        assert!("x".repeat(MAX_POOL_NAME_LEN + 1).parse::<PoolName>().is_err());
+        for name in ["", ".hidden", "..", "a/b", "pool name", "pööl"] {
+            assert!(PoolName::new(name).is_err(), "{:?}", name);
+        }
+
+        assert!(ObjectId::new("a").is_ok());
+        assert!(ObjectId::new(vec![0xff; MAX_OBJECT_ID_LEN]).is_ok());
+        assert!(ObjectId::new(vec![b'a'; MAX_OBJECT_ID_LEN + 1]).is_err());
+        assert!(ObjectId::new("").is_err());
+        assert_eq!(
+            ObjectId::new(vec![]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput,
+        );
+
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert!(check_tags(&[]).is_ok());
+        assert!(check_tags(&tags(&["tmp", "ünïcode", &"x".repeat(MAX_TAG_LEN)])).is_ok());
+        for bad in [&[""][..], &["a b"], &["a\n"], &["tmp", "tmp"], &[&"x".repeat(MAX_TAG_LEN + 1)]] {
+            assert!(check_tags(&tags(bad)).is_err(), "{:?}", bad);
+        }
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| i.to_string()).collect();
+        assert!(check_tags(&many[..MAX_TAGS]).is_ok());
+        assert!(check_tags(&many).is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let device_id = DeviceId([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0xff]);
+        let json = serde_json::to_string(&device_id).unwrap();
+        assert_eq!(json, "\"0102030405060708090a0b0c0d0e0fff\"");
+        assert_eq!(serde_json::from_str::<DeviceId>(&json).unwrap(), device_id);
+        // Maps exported before IDs were hexadecimal
+        assert_eq!(
+            serde_json::from_str::<DeviceId>("[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,255]").unwrap(),
+            device_id,
+        );
+        assert!(serde_json::from_str::<DeviceId>("[1,2,3]").is_err());
+        assert!(serde_json::from_str::<DeviceId>("\"0102\"").is_err());
+
+        let pool = PoolName::new("default").unwrap();
+        assert_eq!(serde_json::to_string(&pool).unwrap(), "\"default\"");
+        assert_eq!(serde_json::from_str::<PoolName>("\"default\"").unwrap(), pool);
+        assert!(serde_json::from_str::<PoolName>("\"../etc\"").is_err());
+
+        let object_id = ObjectId::new(b"dir/obj\xff".to_vec()).unwrap();
+        let json = serde_json::to_string(&object_id).unwrap();
+        assert_eq!(json, "\"ZGlyL29iav8=\"");
+        assert_eq!(serde_json::from_str::<ObjectId>(&json).unwrap(), object_id);
+        assert!(serde_json::from_str::<ObjectId>("\"\"").is_err());
+        assert!(serde_json::from_str::<ObjectId>("\"not base64!\"").is_err());
+
+        assert_eq!(serde_json::to_string(&GroupId(18)).unwrap(), "18");
+        assert!(serde_json::from_str::<GroupId>("18").unwrap() == GroupId(18));
+    }
 }
@@ -1,17 +1,27 @@
-use log::info;
+use log::{info, warn};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Error as IoError, ErrorKind};
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::{self, Certificate, PrivateKey};
 
-use crate::DeviceId;
-use crate::storage_map;
+use crate::{DeviceId, PoolName};
+use crate::admin::{
+    AdminRequest, AdminResponse, ClientRequest, ClientResponse, ClusterStatus, DeviceInfo, PoolInfo,
+    read_message, write_message,
+};
+use crate::crypto::keyring::Keyring;
+use crate::crypto::session::{Ticket, issue_ticket};
+use crate::storage_map::StorageMap;
+use crate::storage_map::builder::{DeviceSpec, Topology};
+use crate::storage_map::overlay::{DeviceStatus, MapOverlay};
+use crate::systemd;
 
 pub struct Master {
     /// Address we listen on for storage daemons (TCP, mTLS).
@@ -24,20 +34,130 @@ pub struct Master {
     storage_daemons: HashMap<DeviceId, StorageDaemon>,
 
     /// The pools, with their storage maps.
-    pool_storage_maps: HashMap<String, storage_map::Node>,
+    pool_storage_maps: HashMap<String, StorageMap>,
+
+    /// The status of the devices, applied on top of all the maps.
+    overlay: MapOverlay,
+
+    /// The keys shared with the storage daemons, used to seal the tickets
+    /// issued to clients. Without them, no tickets are issued.
+    client_keys: Option<Keyring>,
 }
 
 struct StorageDaemon {
     address: SocketAddr,
+    /// Where the device is in the topology, and its weight.
+    spec: DeviceSpec,
 }
 
-fn load_certs(path: &Path) -> Result<Vec<Certificate>, IoError> {
+/// How long the tickets issued to clients are valid.
+const TICKET_LIFETIME: Duration = Duration::from_secs(3600);
+
+impl Master {
+    /// Issue a ticket allowing a client to open sessions with the storage
+    /// daemons.
+    pub fn issue_ticket(&self) -> Option<Ticket> {
+        self.client_keys.as_ref().map(|keyring| issue_ticket(keyring, TICKET_LIFETIME))
+    }
+
+    /// Answer a request from a client.
+    fn handle_client(&self, request: ClientRequest) -> Result<ClientResponse, String> {
+        match request {
+            ClientRequest::GetPool { pool } => {
+                let map = self.pool_storage_maps.get(&pool.0).ok_or_else(|| format!("No pool {}", pool.0))?;
+                let devices = self.device_list();
+                Ok(ClientResponse::Pool { map: map.clone(), devices })
+            }
+            ClientRequest::GetTicket => match self.issue_ticket() {
+                Some(ticket) => Ok(ClientResponse::Ticket(ticket.to_bytes().to_vec())),
+                None => Err("Master has no keyring to issue tickets".to_owned()),
+            },
+        }
+    }
+
+    fn device_list(&self) -> Vec<DeviceInfo> {
+        let mut devices: Vec<DeviceInfo> = self.storage_daemons.iter().map(|(id, daemon)| DeviceInfo {
+            spec: daemon.spec.clone(),
+            address: daemon.address,
+            status: self.overlay.status(id),
+        }).collect();
+        devices.sort_by_key(|d| d.spec.id.0);
+        devices
+    }
+
+    /// Carry out a request from an admin.
+    fn handle_admin(&mut self, request: AdminRequest) -> Result<AdminResponse, String> {
+        match request {
+            AdminRequest::Status => {
+                let count = |status| {
+                    self.storage_daemons.keys().filter(|id| self.overlay.status(id) == status).count()
+                };
+                let mut pools: Vec<PoolInfo> = self.pool_storage_maps.iter().map(|(name, map)| PoolInfo {
+                    name: name.clone(),
+                    generation: map.generation,
+                    groups: map.groups,
+                    replicas: map.replicas,
+                }).collect();
+                pools.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(AdminResponse::Status(ClusterStatus {
+                    devices_up: count(DeviceStatus::Up),
+                    devices_down: count(DeviceStatus::Down),
+                    devices_out: count(DeviceStatus::Out),
+                    pools,
+                }))
+            }
+            AdminRequest::ListDevices => Ok(AdminResponse::Devices(self.device_list())),
+            AdminRequest::AddDevice { spec, address } => {
+                info!("Admin set device {:?} at {}", spec.id, address);
+                self.storage_daemons.insert(spec.id.clone(), StorageDaemon { address, spec });
+                Ok(AdminResponse::Done)
+            }
+            AdminRequest::SetDeviceStatus { device_id, status } => {
+                if !self.storage_daemons.contains_key(&device_id) {
+                    return Err(format!("No device {:?}", device_id));
+                }
+                info!("Admin set device {:?} {:?}", device_id, status);
+                self.overlay.set_status(device_id, status);
+                Ok(AdminResponse::Done)
+            }
+            AdminRequest::CreatePool { name, groups, replicas, failure_domain } => {
+                PoolName::new(name.as_str()).map_err(|e| e.to_string())?;
+                if self.pool_storage_maps.contains_key(&name) {
+                    return Err(format!("Pool {} already exists", name));
+                }
+                let mut topology = Topology::new(groups, replicas, failure_domain);
+                let mut devices: Vec<&StorageDaemon> = self.storage_daemons.values()
+                    .filter(|d| self.overlay.status(&d.spec.id) != DeviceStatus::Out)
+                    .collect();
+                devices.sort_by_key(|d| d.spec.id.0);
+                topology.devices = devices.into_iter().map(|d| d.spec.clone()).collect();
+                let map = topology.build(1).map_err(|e| e.to_string())?;
+                info!("Admin created pool {}", name);
+                self.pool_storage_maps.insert(name, map);
+                Ok(AdminResponse::Done)
+            }
+            AdminRequest::RemovePool { name } => {
+                if self.pool_storage_maps.remove(&name).is_none() {
+                    return Err(format!("No pool {}", name));
+                }
+                info!("Admin removed pool {}", name);
+                Ok(AdminResponse::Done)
+            }
+            AdminRequest::GetMap { pool } => match self.pool_storage_maps.get(&pool) {
+                Some(map) => Ok(AdminResponse::Map(map.clone())),
+                None => Err(format!("No pool {}", pool)),
+            },
+        }
+    }
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>, IoError> {
     rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
         .map_err(|_| IoError::new(ErrorKind::InvalidInput, "Invalid certificate file"))
         .map(|mut certs| certs.drain(..).map(Certificate).collect())
 }
 
-fn load_key(path: &Path) -> Result<PrivateKey, IoError> {
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKey, IoError> {
     let mut keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
         .map_err(|_| IoError::new(ErrorKind::InvalidInput, "Invalid key file"))?;
     let mut keys = keys.drain(..).map(PrivateKey);
@@ -51,6 +171,7 @@ fn load_key(path: &Path) -> Result<PrivateKey, IoError> {
     Ok(key)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_master(
     peer_address: SocketAddr,
     peer_cert: &Path,
@@ -59,12 +180,33 @@ pub async fn run_master(
     listen_address: SocketAddr,
     listen_cert: &Path,
     listen_key: &Path,
+    keyring: Option<&Path>,
+    admin_address: Option<SocketAddr>,
+    admin_ca_cert: Option<&Path>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let client_keys = match keyring {
+        Some(path) => {
+            let keyring = Keyring::load(path)?;
+            info!(
+                "Using key {} from keyring ({} previous keys)",
+                keyring.current().fingerprint(),
+                keyring.keys().len() - 1,
+            );
+            Some(keyring)
+        }
+        None => {
+            warn!("No keyring, clients won't get tickets to open sessions with the daemons");
+            None
+        }
+    };
+
     let master = Master {
         peer_address: peer_address.clone(),
         listen_address: listen_address.clone(),
         storage_daemons: Default::default(),
         pool_storage_maps: Default::default(),
+        overlay: MapOverlay::new(),
+        client_keys,
     };
     let master = Arc::new(Mutex::new(master));
 
@@ -99,9 +241,41 @@ pub async fn run_master(
         serve_peers(listener, acceptor, master.clone())
     };
 
+    let admin_fut = async {
+        let (admin_address, admin_ca_cert) = match (admin_address, admin_ca_cert) {
+            (Some(address), Some(ca_cert)) => (address, ca_cert),
+            (None, None) => return std::future::pending().await,
+            _ => return Err(IoError::new(ErrorKind::InvalidInput, "The admin address needs an admin CA certificate")),
+        };
+        info!("Listening for admin connections on {}", admin_address);
+        let listener: TcpListener = TcpListener::bind(&admin_address).await?;
+        let mut ca = rustls::RootCertStore::empty();
+        ca.add(&load_certs(admin_ca_cert)?.remove(0))
+            .map_err(|err| IoError::new(ErrorKind::InvalidInput, err))?;
+        let client_verifier = rustls::server::AllowAnyAuthenticatedClient::new(ca);
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(load_certs(listen_cert)?, load_key(listen_key)?)
+            .map_err(|err| IoError::new(ErrorKind::InvalidInput, err))?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        serve_admin(listener, acceptor, master.clone()).await
+    };
+
+    systemd::notify("READY=1");
+    {
+        let master = master.clone();
+        systemd::spawn_watchdog(move || drop(master.lock().unwrap()));
+    }
+
     tokio::select! {
         _ = clients_fut => {}
         _ = peers_fut => {}
+        r = admin_fut => r?,
+        signal = systemd::termination_signal() => {
+            info!("Got signal {}, stopping", signal?);
+            systemd::notify("STOPPING=1");
+        }
     };
 
     Ok(())
@@ -112,10 +286,14 @@ async fn serve_clients(listener: TcpListener, acceptor: TlsAcceptor, master: Arc
         let (stream, peer_addr) = listener.accept().await?;
         info!("Client connected from {}", peer_addr);
         let acceptor = acceptor.clone();
+        let master = master.clone();
         tokio::spawn(async move {
             let mut stream = acceptor.accept(stream).await?;
-            stream.write_all(b"Hello").await?;
-            stream.shutdown().await?;
+            while let Some(request) = read_message(&mut stream).await? {
+                let response = master.lock().unwrap().handle_client(request)
+                    .unwrap_or_else(ClientResponse::Error);
+                write_message(&mut stream, &response).await?;
+            }
             Ok(()) as Result<(), IoError>
         });
     }
@@ -134,3 +312,120 @@ async fn serve_peers(listener: TcpListener, acceptor: TlsAcceptor, master: Arc<M
         });
     }
 }
+
+async fn serve_admin(listener: TcpListener, acceptor: TlsAcceptor, master: Arc<Mutex<Master>>) -> Result<(), IoError> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        info!("Admin connected from {}", peer_addr);
+        let acceptor = acceptor.clone();
+        let master = master.clone();
+        tokio::spawn(async move {
+            let mut stream = acceptor.accept(stream).await?;
+            while let Some(request) = read_message(&mut stream).await? {
+                let response = master.lock().unwrap().handle_admin(request)
+                    .unwrap_or_else(AdminResponse::Error);
+                write_message(&mut stream, &response).await?;
+            }
+            Ok(()) as Result<(), IoError>
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{DeviceId, PoolName};
+    use crate::admin::{AdminRequest, AdminResponse, ClientRequest, ClientResponse};
+    use crate::crypto::KeyPair;
+    use crate::crypto::keyring::Keyring;
+    use crate::crypto::session::{Ticket, accept_session};
+    use crate::storage_map::builder::{DeviceSpec, FailureDomain};
+    use crate::storage_map::overlay::{DeviceStatus, MapOverlay};
+    use super::Master;
+
+    #[test]
+    fn test_handle_admin() {
+        let mut master = Master {
+            peer_address: "127.0.0.1:4000".parse().unwrap(),
+            listen_address: "127.0.0.1:4001".parse().unwrap(),
+            storage_daemons: HashMap::new(),
+            pool_storage_maps: HashMap::new(),
+            overlay: MapOverlay::new(),
+            client_keys: Some(Keyring::new(KeyPair::generate())),
+        };
+        for i in 0..4 {
+            let request = AdminRequest::AddDevice {
+                spec: DeviceSpec {
+                    id: DeviceId([i; 16]),
+                    host: format!("host{}", i),
+                    rack: None,
+                    weight: 100,
+                },
+                address: format!("127.0.0.1:{}", 5000 + i as u16).parse().unwrap(),
+            };
+            assert_eq!(master.handle_admin(request), Ok(AdminResponse::Done));
+        }
+        master.handle_admin(AdminRequest::SetDeviceStatus {
+            device_id: DeviceId([3; 16]),
+            status: DeviceStatus::Out,
+        }).unwrap();
+        assert!(master.handle_admin(AdminRequest::SetDeviceStatus {
+            device_id: DeviceId([9; 16]),
+            status: DeviceStatus::Out,
+        }).is_err());
+
+        let create = AdminRequest::CreatePool {
+            name: "default".to_owned(),
+            groups: 16,
+            replicas: 3,
+            failure_domain: FailureDomain::Host,
+        };
+        master.handle_admin(create.clone()).unwrap();
+        assert!(master.handle_admin(create).is_err());
+        assert!(master.handle_admin(AdminRequest::CreatePool {
+            name: "../default".to_owned(),
+            groups: 16,
+            replicas: 3,
+            failure_domain: FailureDomain::Host,
+        }).is_err());
+
+        let status = match master.handle_admin(AdminRequest::Status) {
+            Ok(AdminResponse::Status(status)) => status,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!((status.devices_up, status.devices_down, status.devices_out), (3, 0, 1));
+        assert_eq!(status.pools.len(), 1);
+        assert_eq!((status.pools[0].generation, status.pools[0].groups), (1, 16));
+
+        // The device that is out is not in the map
+        let map = match master.handle_admin(AdminRequest::GetMap { pool: "default".to_owned() }) {
+            Ok(AdminResponse::Map(map)) => map,
+            r => panic!("{:?}", r),
+        };
+        let map = format!("{:?}", map);
+        assert!(map.contains(&format!("{:?}", DeviceId([0; 16]))));
+        assert!(!map.contains(&format!("{:?}", DeviceId([3; 16]))));
+
+        // Clients get the map with the devices' addresses and statuses
+        let devices = match master.handle_client(ClientRequest::GetPool { pool: PoolName("default".to_owned()) }) {
+            Ok(ClientResponse::Pool { devices, .. }) => devices,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(devices.len(), 4);
+        assert_eq!(devices[3].address, "127.0.0.1:5003".parse().unwrap());
+        assert_eq!(devices[3].status, DeviceStatus::Out);
+        assert!(master.handle_client(ClientRequest::GetPool { pool: PoolName("other".to_owned()) }).is_err());
+
+        // Tickets are sealed with the keyring shared with the daemons
+        let ticket = match master.handle_client(ClientRequest::GetTicket) {
+            Ok(ClientResponse::Ticket(ticket)) => Ticket::from_bytes(&ticket).unwrap(),
+            r => panic!("{:?}", r),
+        };
+        let (_, hello) = ticket.start_session();
+        assert!(accept_session(master.client_keys.as_ref().unwrap(), &hello).is_ok());
+
+        master.handle_admin(AdminRequest::RemovePool { name: "default".to_owned() }).unwrap();
+        assert!(master.handle_admin(AdminRequest::GetMap { pool: "default".to_owned() }).is_err());
+    }
+}
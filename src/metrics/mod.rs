@@ -0,0 +1,246 @@
+#[cfg(feature = "profiling")]
+mod profile;
+pub mod reporter;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use log::error;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder, exponential_buckets,
+};
+use std::io::Error as IoError;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::OnceLock;
+use tokio::sync::oneshot;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Set the registry the metrics of clients and daemons are registered in.
+///
+/// This is for programs embedding them that have their own registry. It has
+/// to be called before creating any client or daemon, and fails if a registry
+/// is already in use. By default, Prometheus' default registry is used.
+pub fn set_registry(registry: Registry) -> Result<(), Registry> {
+    REGISTRY.set(registry)
+}
+
+/// The registry metrics are registered in, and served from.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| prometheus::default_registry().clone())
+}
+
+/// Create a counter and register it, with the component's name as prefix.
+///
+/// The prefix avoids collisions between components used in the same process,
+/// such as a client embedded in a daemon.
+pub fn register_counter(component: &str, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::with_opts(Opts::new(name, help).namespace(component)).unwrap();
+    registry().register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+/// Create a counter with labels and register it, with the component's name as
+/// prefix.
+pub fn register_counter_vec(component: &str, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help).namespace(component), labels).unwrap();
+    registry().register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+/// Create a gauge and register it, with the component's name as prefix.
+pub fn register_gauge(component: &str, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::with_opts(Opts::new(name, help).namespace(component)).unwrap();
+    registry().register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+/// Create a floating-point gauge and register it, with the component's name
+/// as prefix.
+pub fn register_float_gauge(component: &str, name: &str, help: &str) -> Gauge {
+    let gauge = Gauge::with_opts(Opts::new(name, help).namespace(component)).unwrap();
+    registry().register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+/// Create a gauge with labels and register it, with the component's name as
+/// prefix.
+pub fn register_gauge_vec(component: &str, name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    let gauge = IntGaugeVec::new(Opts::new(name, help).namespace(component), labels).unwrap();
+    registry().register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+/// Create a latency histogram and register it, with the component's name as
+/// prefix.
+///
+/// Buckets go from 100us to about 3s, as requests usually take well under a
+/// millisecond.
+pub fn register_latency(component: &str, name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help)
+        .namespace(component)
+        .buckets(exponential_buckets(0.0001, 2.0, 16).unwrap());
+    let histogram = HistogramVec::new(opts, labels).unwrap();
+    registry().register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+#[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+async fn serve_req(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    #[cfg(feature = "profiling")]
+    if req.uri().path() == "/debug/pprof/profile" {
+        return Ok(profile::serve_profile(&req).await);
+    }
+
+    let encoder = TextEncoder::new();
+
+    let metric_families = registry().gather();
+    let mut buffer = vec![];
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    let response = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap();
+
+    Ok(response)
+}
+
+/// A metrics server running on its own thread.
+///
+/// Dropping this leaves the server running, use `shutdown()` to stop it.
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    done: oneshot::Receiver<Result<(), hyper::Error>>,
+}
+
+impl MetricsServer {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the server, and wait for it to be done.
+    pub async fn shutdown(self) -> Result<(), IoError> {
+        // If the server is gone already, the error is in `done`
+        self.shutdown.send(()).ok();
+        match self.done.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(IoError::other(e)),
+            Err(_) => Err(IoError::other("Metrics server thread panicked")),
+        }
+    }
+}
+
+/// Serve the metrics over HTTP, on a thread of its own.
+///
+/// Errors binding the address are returned, instead of taking down the
+/// process.
+///
+/// With the `profiling` feature, a CPU profile can also be taken from
+/// `/debug/pprof/profile`, see `profile::serve_profile()`.
+pub fn start_http_server(addr: SocketAddr) -> Result<MetricsServer, IoError> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let local_addr = listener.local_addr()?;
+    let mut runtime = tokio::runtime::Builder::new_current_thread();
+    runtime.enable_all();
+    let runtime = runtime.build()?;
+
+    let (shutdown_send, shutdown_recv) = oneshot::channel();
+    let (done_send, done_recv) = oneshot::channel();
+    std::thread::spawn(move || {
+        let result = runtime.block_on(async move {
+            Server::from_tcp(listener)?
+                .serve(make_service_fn(|_| async {
+                    Ok::<_, hyper::Error>(service_fn(serve_req))
+                }))
+                .with_graceful_shutdown(async {
+                    // Keep going if the handle is dropped without calling
+                    // shutdown()
+                    if shutdown_recv.await.is_err() {
+                        std::future::pending::<()>().await;
+                    }
+                })
+                .await
+        });
+        if let Err(ref e) = result {
+            error!("Metrics server failed: {}", e);
+        }
+        done_send.send(result).ok();
+    });
+
+    Ok(MetricsServer {
+        local_addr,
+        shutdown: shutdown_send,
+        done: done_recv,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{register_counter, register_gauge, register_latency, registry, start_http_server};
+
+    #[test]
+    fn test_components() {
+        // The same name can be used by different components
+        let first = register_counter("test_first", "reads", "Total reads");
+        let second = register_counter("test_second", "reads", "Total reads");
+        let gauge = register_gauge("test_first", "in_flight", "Requests in flight");
+        first.inc();
+        second.inc_by(2);
+        gauge.set(3);
+
+        let families = registry().gather();
+        let value = |name: &str| {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            if metric.has_counter() {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        };
+        assert_eq!(value("test_first_reads"), 1.0);
+        assert_eq!(value("test_second_reads"), 2.0);
+        assert_eq!(value("test_first_in_flight"), 3.0);
+
+        let latency = register_latency("test_first", "duration_seconds", "Latency", &["op"]);
+        latency.with_label_values(&["read"]).observe(0.0005);
+        latency.with_label_values(&["read"]).observe(0.5);
+        let read = latency.with_label_values(&["read"]);
+        assert_eq!(read.get_sample_count(), 2);
+        assert!((read.get_sample_sum() - 0.5005).abs() < 1e-9);
+        assert_eq!(latency.with_label_values(&["write"]).get_sample_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let counter = register_counter("test_server", "hits", "Test counter");
+        counter.inc();
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = start_http_server(addr).unwrap();
+        let addr = server.local_addr();
+
+        // The port is in use
+        assert!(start_http_server(addr).is_err());
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\ntest_server_hits 1\n"));
+
+        server.shutdown().await.unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! On-demand CPU profiling, served by the metrics server.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const DEFAULT_SECONDS: u64 = 10;
+const MAX_SECONDS: u64 = 60;
+
+/// Samples per second.
+const DEFAULT_FREQUENCY: i32 = 99;
+const MAX_FREQUENCY: i32 = 1000;
+
+/// Set while a profile is being taken, there can only be one at a time.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+fn error(status: StatusCode, msg: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+/// Profile the process and return a flame graph, in SVG.
+///
+/// The query can set `seconds` (default 10, at most 60) and `frequency`, the
+/// samples per second (default 99).
+pub(super) async fn serve_profile(req: &Request<Body>) -> Response<Body> {
+    let mut seconds = DEFAULT_SECONDS;
+    let mut frequency = DEFAULT_FREQUENCY;
+    for pair in req.uri().query().unwrap_or("").split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "" => {}
+            "seconds" => match value.parse() {
+                Ok(v) if (1..=MAX_SECONDS).contains(&v) => seconds = v,
+                _ => return error(StatusCode::BAD_REQUEST, "Invalid seconds"),
+            },
+            "frequency" => match value.parse() {
+                Ok(v) if (1..=MAX_FREQUENCY).contains(&v) => frequency = v,
+                _ => return error(StatusCode::BAD_REQUEST, "Invalid frequency"),
+            },
+            _ => return error(StatusCode::BAD_REQUEST, "Unknown parameter"),
+        }
+    }
+
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        return error(StatusCode::CONFLICT, "A profile is already being taken");
+    }
+    info!("Profiling for {}s at {}Hz", seconds, frequency);
+    let result = tokio::task::spawn_blocking(move || profile(seconds, frequency)).await;
+    PROFILING.store(false, Ordering::SeqCst);
+
+    match result {
+        // No stacks were sampled, so there is no graph
+        Ok(Ok(svg)) if svg.is_empty() => Response::builder()
+            .status(200)
+            .body(Body::from("No samples, the process was idle"))
+            .unwrap(),
+        Ok(Ok(svg)) => Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "image/svg+xml")
+            .body(Body::from(svg))
+            .unwrap(),
+        Ok(Err(e)) => {
+            warn!("Error profiling: {}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Error profiling")
+        }
+        Err(e) => {
+            warn!("Error profiling: {}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Error profiling")
+        }
+    }
+}
+
+fn profile(seconds: u64, frequency: i32) -> Result<Vec<u8>, pprof::Error> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(Duration::from_secs(seconds));
+    let report = guard.report().build()?;
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg)?;
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::CONTENT_TYPE;
+    use hyper::{Body, Request, StatusCode};
+    use std::hint::black_box;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::serve_profile;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_profile() {
+        for uri in [
+            "/debug/pprof/profile?seconds=0",
+            "/debug/pprof/profile?seconds=61",
+            "/debug/pprof/profile?frequency=x",
+            "/debug/pprof/profile?other=1",
+        ] {
+            assert_eq!(serve_profile(&request(uri)).await.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Keep busy for the whole profile, so there is something to sample
+        let stop = Arc::new(AtomicBool::new(false));
+        let busy = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut x = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    x = black_box(x.wrapping_mul(31).wrapping_add(7));
+                }
+            }
+        });
+
+        // Only one profile at a time
+        let first = request("/debug/pprof/profile?seconds=1&frequency=500");
+        let second = request("/debug/pprof/profile?seconds=1");
+        let (first, second) = tokio::join!(serve_profile(&first), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            serve_profile(&second).await
+        });
+        stop.store(true, Ordering::Relaxed);
+        busy.join().unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::CONFLICT);
+
+        // The samples can still all miss, when other tests keep the machine
+        // busy
+        let svg = first.headers().get(CONTENT_TYPE).is_some();
+        let body = hyper::body::to_bytes(first.into_body()).await.unwrap();
+        if svg {
+            assert!(body.windows(4).any(|w| w == b"<svg"));
+        } else {
+            assert_eq!(&body[..], b"No samples, the process was idle");
+        }
+    }
+}
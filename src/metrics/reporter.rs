@@ -0,0 +1,184 @@
+//! Periodically log how much some counters went up.
+//!
+//! Components create a `Reporter` with the values they want reported, which
+//! logs their changes every interval, and also exports them as rates. The
+//! interval and log level are set for all reporters with `configure()`.
+
+use lazy_static::lazy_static;
+use log::{Level, log};
+use prometheus::Gauge;
+use std::sync::{Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::register_float_gauge;
+
+/// How often and how loudly reporters log.
+#[derive(Clone, Debug)]
+pub struct ReporterConfig {
+    pub interval: Duration,
+    /// The level to log at, `None` to only update the rate metrics.
+    pub level: Option<Level>,
+}
+
+impl Default for ReporterConfig {
+    fn default() -> ReporterConfig {
+        ReporterConfig {
+            interval: Duration::from_secs(10),
+            level: Some(Level::Info),
+        }
+    }
+}
+
+struct State {
+    config: ReporterConfig,
+    stopped: bool,
+    threads: Vec<JoinHandle<()>>,
+}
+
+lazy_static! {
+    static ref STATE: (Mutex<State>, Condvar) = (
+        Mutex::new(State {
+            config: ReporterConfig::default(),
+            stopped: false,
+            threads: Vec::new(),
+        }),
+        Condvar::new(),
+    );
+}
+
+/// Change the interval and level of all reporters.
+///
+/// This takes effect after their current interval.
+pub fn configure(config: ReporterConfig) {
+    STATE.0.lock().unwrap().config = config;
+}
+
+/// Stop all the reporters, waiting for their threads to exit.
+///
+/// Reporters started after this don't run.
+pub fn shutdown() {
+    let threads = {
+        let mut state = STATE.0.lock().unwrap();
+        state.stopped = true;
+        std::mem::take(&mut state.threads)
+    };
+    STATE.1.notify_all();
+    for thread in threads {
+        thread.join().ok();
+    }
+}
+
+struct Value {
+    description: &'static str,
+    get: Box<dyn Fn() -> u64 + Send>,
+    rate: Gauge,
+}
+
+/// Reports the changes of values of a component.
+pub struct Reporter {
+    component: &'static str,
+    values: Vec<Value>,
+}
+
+impl Reporter {
+    pub fn new(component: &'static str) -> Reporter {
+        Reporter { component, values: Vec::new() }
+    }
+
+    /// Add a value to report, which should only go up.
+    ///
+    /// The rate is exported as `<component>_<name>_rate`, the description is
+    /// used in the log messages.
+    pub fn value<F: Fn() -> u64 + Send + 'static>(mut self, name: &str, description: &'static str, get: F) -> Reporter {
+        let rate = register_float_gauge(
+            self.component,
+            &format!("{}_rate", name),
+            &format!("Rate of {} per second", description),
+        );
+        self.values.push(Value { description, get: Box::new(get), rate });
+        self
+    }
+
+    /// Start reporting, on a thread of its own.
+    pub fn start(self) {
+        let mut state = STATE.0.lock().unwrap();
+        if !state.stopped {
+            state.threads.push(std::thread::spawn(move || self.run()));
+        }
+    }
+
+    fn run(self) {
+        let mut last_values = vec![0; self.values.len()];
+        let mut last_time = Instant::now();
+        loop {
+            // Wait for the interval, or for shutdown
+            let config = {
+                let (lock, condvar) = &*STATE;
+                let state = lock.lock().unwrap();
+                let interval = state.config.interval;
+                let (state, _) = condvar.wait_timeout_while(state, interval, |s| !s.stopped).unwrap();
+                if state.stopped {
+                    return;
+                }
+                state.config.clone()
+            };
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            last_time = now;
+            let mut changed = false;
+            let mut parts = Vec::with_capacity(self.values.len());
+            for (value, last) in self.values.iter().zip(last_values.iter_mut()) {
+                let current = (value.get)();
+                let delta = current.saturating_sub(*last);
+                *last = current;
+                changed |= delta != 0;
+                value.rate.set(delta as f64 / elapsed);
+                parts.push(format!("{} {}", delta, value.description));
+            }
+            if let Some(level) = config.level {
+                if changed {
+                    log!(level, "{}: last {}s: {}", self.component, elapsed.round(), parts.join(", "));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+
+    use super::{Reporter, ReporterConfig, configure, shutdown};
+    use crate::metrics::registry;
+
+    #[test]
+    fn test_reporter() {
+        configure(ReporterConfig { interval: Duration::from_millis(10), level: None });
+        let counter = Arc::new(AtomicU64::new(0));
+        let c = counter.clone();
+        Reporter::new("test_reporter")
+            .value("hits", "hits", move || c.load(Ordering::Relaxed))
+            .start();
+        counter.store(100, Ordering::Relaxed);
+
+        let rate = || {
+            let families = registry().gather();
+            let family = families.iter().find(|f| f.get_name() == "test_reporter_hits_rate").unwrap();
+            family.get_metric()[0].get_gauge().get_value()
+        };
+        let start = Instant::now();
+        while rate() == 0.0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        // Shutdown is immediate, and stops the threads
+        let start = Instant::now();
+        shutdown();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
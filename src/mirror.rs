@@ -0,0 +1,388 @@
+//! Asynchronous replication of a pool to another cluster, for disaster
+//! recovery.
+//!
+//! Daemons don't report changes, so the source pool is scanned in passes:
+//! every object is read and its digest compared to the one recorded when it
+//! was last copied. Changed objects are written to the destination, and
+//! objects that disappeared from the source are deleted from it. Objects of
+//! the destination that were never mirrored are left alone.
+//!
+//! The digests and the position in the current pass are saved to a
+//! checkpoint file, so an interrupted mirror resumes where it stopped.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::ObjectId;
+use crate::client::{Client, ObjectStat, PART_SIZE};
+use crate::metrics::{register_counter, register_gauge};
+
+/// How many objects to get per request when listing the source.
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// How often the checkpoint is saved during a pass.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+const CHECKPOINT_VERSION: u8 = 1;
+
+struct Metrics {
+    passes: prometheus::IntCounter,
+    copied: prometheus::IntCounter,
+    deleted: prometheus::IntCounter,
+    bytes: prometheus::IntCounter,
+    errors: prometheus::IntCounter,
+    last_pass: prometheus::IntGauge,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        passes: register_counter("mirror", "passes", "Total passes over the source pool completed"),
+        copied: register_counter("mirror", "copied", "Total objects copied to the destination"),
+        deleted: register_counter("mirror", "deleted", "Total objects deleted from the destination"),
+        bytes: register_counter("mirror", "bytes", "Total bytes copied to the destination"),
+        errors: register_counter("mirror", "errors", "Total passes that failed"),
+        last_pass: register_gauge("mirror", "last_pass_timestamp", "When the last pass completed, as a Unix timestamp"),
+    };
+}
+
+/// A digest of the content of an object, to notice changes.
+pub type ObjectDigest = [u8; 16];
+
+fn digest(data: &[u8]) -> ObjectDigest {
+    Sha256::digest(data)[..16].try_into().unwrap()
+}
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// What has been mirrored so far.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Number of passes completed.
+    pub passes: u64,
+    /// The last object handled in the current pass, `None` at the start of
+    /// a pass.
+    pub position: Option<ObjectId>,
+    /// The digest of each object in the destination, as copied.
+    pub digests: BTreeMap<Vec<u8>, ObjectDigest>,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint file, or start from scratch if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Checkpoint, IoError> {
+        let mut data = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Checkpoint::default()),
+            Err(e) => return Err(e),
+        };
+        Checkpoint::decode(&data)
+    }
+
+    /// Write the checkpoint file, replacing it.
+    ///
+    /// The new file is written next to it then renamed, so it's never left
+    /// partially written.
+    pub fn save(&self, path: &Path) -> Result<(), IoError> {
+        let mut temp_name = path.file_name()
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Invalid checkpoint path"))?
+            .to_owned();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let result = (|| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+            file.write_all(&self.encode())?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, path)
+        })();
+        if result.is_err() {
+            std::fs::remove_file(&temp_path).ok();
+        }
+        result
+    }
+
+    /// The version byte, the number of passes, the position (a flag then
+    /// the length-prefixed name), then the number of digests followed by
+    /// each length-prefixed name and its digest.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.write_u8(CHECKPOINT_VERSION).unwrap();
+        result.write_u64::<BigEndian>(self.passes).unwrap();
+        match &self.position {
+            None => result.write_u8(0).unwrap(),
+            Some(position) => {
+                result.write_u8(1).unwrap();
+                result.write_u32::<BigEndian>(position.0.len() as u32).unwrap();
+                result.extend_from_slice(&position.0);
+            }
+        }
+        result.write_u64::<BigEndian>(self.digests.len() as u64).unwrap();
+        for (name, digest) in &self.digests {
+            result.write_u32::<BigEndian>(name.len() as u32).unwrap();
+            result.extend_from_slice(name);
+            result.extend_from_slice(digest);
+        }
+        result
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Checkpoint, IoError> {
+        fn read_name(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, IoError> {
+            let len = reader.read_u32::<BigEndian>()? as usize;
+            if len > reader.get_ref().len() - reader.position() as usize {
+                return Err(invalid("Truncated checkpoint"));
+            }
+            let mut name = vec![0; len];
+            reader.read_exact(&mut name)?;
+            Ok(name)
+        }
+
+        let mut reader = Cursor::new(data);
+        if reader.read_u8()? != CHECKPOINT_VERSION {
+            return Err(invalid("Unknown checkpoint version"));
+        }
+        let passes = reader.read_u64::<BigEndian>()?;
+        let position = match reader.read_u8()? {
+            0 => None,
+            1 => Some(ObjectId(read_name(&mut reader)?)),
+            _ => return Err(invalid("Invalid checkpoint position")),
+        };
+        let count = reader.read_u64::<BigEndian>()?;
+        let mut digests = BTreeMap::new();
+        for _ in 0..count {
+            let name = read_name(&mut reader)?;
+            let mut digest = [0; 16];
+            reader.read_exact(&mut digest)?;
+            digests.insert(name, digest);
+        }
+        if reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after checkpoint"));
+        }
+        Ok(Checkpoint { passes, position, digests })
+    }
+}
+
+/// The objects that were mirrored in a range of names but are no longer in
+/// the source.
+///
+/// The range starts after `after` and goes up to and including `up_to`, to
+/// the end if `None`.
+fn vanished(digests: &BTreeMap<Vec<u8>, ObjectDigest>, after: Option<&[u8]>, up_to: Option<&[u8]>, seen: &HashSet<Vec<u8>>) -> Vec<Vec<u8>> {
+    let start = match after {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    let end = match up_to {
+        Some(up_to) => Bound::Included(up_to),
+        None => Bound::Unbounded,
+    };
+    digests.range::<[u8], _>((start, end))
+        .map(|(name, _)| name)
+        .filter(|name| !seen.contains(*name))
+        .cloned()
+        .collect()
+}
+
+/// Read an object in parts, `None` if it doesn't exist.
+pub(crate) async fn read_whole(client: &Client, object_id: &ObjectId) -> Result<Option<(ObjectStat, Vec<u8>)>, IoError> {
+    let stat = match client.stat_object(object_id).await? {
+        Some(stat) => stat,
+        None => return Ok(None),
+    };
+    let size = u32::try_from(stat.size).map_err(|_| invalid("Object too large"))?;
+    let mut data = Vec::with_capacity(size as usize);
+    while (data.len() as u32) < size {
+        let offset = data.len() as u32;
+        let len = (size - offset).min(PART_SIZE as u32);
+        match client.read_part(object_id, offset, len).await? {
+            Some(part) if part.len() == len as usize => data.extend_from_slice(&part),
+            // Changed while we read it, get it again next pass
+            Some(_) => return Err(IoError::new(ErrorKind::Interrupted, "Object changed while reading")),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((stat, data)))
+}
+
+/// Write an object, in parts.
+pub(crate) async fn write_whole(client: &Client, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError> {
+    let mut parts = data.chunks(PART_SIZE);
+    client.write_object(object_id, parts.next().unwrap_or(&[])).await?;
+    let mut offset = PART_SIZE as u32;
+    for part in parts {
+        client.write_part(object_id, offset, part).await?;
+        offset += part.len() as u32;
+    }
+    Ok(())
+}
+
+/// What a pass did.
+#[derive(Debug, Default)]
+pub struct PassStats {
+    pub scanned: u64,
+    pub copied: u64,
+    pub deleted: u64,
+}
+
+/// Copy the changes from the source pool to the destination, from the
+/// position of the checkpoint to the end of a pass.
+pub async fn mirror_pass(source: &Client, destination: &Client, checkpoint: &mut Checkpoint, checkpoint_path: &Path) -> Result<PassStats, IoError> {
+    let mut stats = PassStats::default();
+    let mut last_save = Instant::now();
+    loop {
+        let page = source.list_objects(b"", checkpoint.position.as_ref(), LIST_PAGE_SIZE).await?;
+        let seen: HashSet<Vec<u8>> = page.iter().map(|o| o.0.clone()).collect();
+
+        // Delete the objects that are gone, up to the end of this page (or
+        // to the end if the pass is over)
+        let gone = vanished(
+            &checkpoint.digests,
+            checkpoint.position.as_ref().map(|o| &o.0[..]),
+            page.last().map(|o| &o.0[..]),
+            &seen,
+        );
+        for name in gone {
+            destination.delete_object(&ObjectId(name.clone())).await?;
+            checkpoint.digests.remove(&name);
+            stats.deleted += 1;
+            METRICS.deleted.inc();
+        }
+
+        let last = match page.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        for object_id in page {
+            stats.scanned += 1;
+            let data = match read_whole(source, &object_id).await {
+                Ok(Some((_, data))) => data,
+                // Deleted since listed, next pass will notice
+                Ok(None) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    warn!("Skipping {:?}: {}", object_id, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let digest = digest(&data);
+            if checkpoint.digests.get(&object_id.0) == Some(&digest) {
+                continue;
+            }
+            write_whole(destination, &object_id, &data).await?;
+            checkpoint.digests.insert(object_id.0, digest);
+            stats.copied += 1;
+            METRICS.copied.inc();
+            METRICS.bytes.inc_by(data.len() as u64);
+        }
+        checkpoint.position = Some(last);
+
+        if last_save.elapsed() >= CHECKPOINT_INTERVAL {
+            destination.flush().await?;
+            checkpoint.save(checkpoint_path)?;
+            last_save = Instant::now();
+        }
+    }
+
+    // Make the copies durable before recording the pass
+    destination.flush().await?;
+    checkpoint.position = None;
+    checkpoint.passes += 1;
+    checkpoint.save(checkpoint_path)?;
+    Ok(stats)
+}
+
+/// Mirror the source pool to the destination, one pass every `interval`.
+///
+/// If `once` is set, stop after completing a pass.
+pub async fn run_mirror(source: Client, destination: Client, checkpoint_path: &Path, interval: Duration, once: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut checkpoint = Checkpoint::load(checkpoint_path)?;
+    match &checkpoint.position {
+        Some(position) => info!(
+            "Resuming pass {} after {:?}, {} objects mirrored",
+            checkpoint.passes + 1, position, checkpoint.digests.len(),
+        ),
+        None => info!("Starting pass {}, {} objects mirrored", checkpoint.passes + 1, checkpoint.digests.len()),
+    }
+    loop {
+        let start = Instant::now();
+        match mirror_pass(&source, &destination, &mut checkpoint, checkpoint_path).await {
+            Ok(stats) => {
+                info!(
+                    "Pass {} done in {:.1}s: {} objects scanned, {} copied, {} deleted",
+                    checkpoint.passes, start.elapsed().as_secs_f64(), stats.scanned, stats.copied, stats.deleted,
+                );
+                METRICS.passes.inc();
+                METRICS.last_pass.set(
+                    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64,
+                );
+                if once {
+                    return Ok(());
+                }
+            }
+            Err(e) if !once => {
+                // Save what was done, and try again later
+                warn!("Pass failed: {}", e);
+                METRICS.errors.inc();
+                checkpoint.save(checkpoint_path)?;
+            }
+            Err(e) => {
+                checkpoint.save(checkpoint_path)?;
+                return Err(e.into());
+            }
+        }
+        tokio::time::sleep(interval.saturating_sub(start.elapsed())).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+
+    use crate::ObjectId;
+    use super::{Checkpoint, vanished};
+
+    #[test]
+    fn test_checkpoint() {
+        let checkpoint = Checkpoint::default();
+        assert_eq!(Checkpoint::decode(&checkpoint.encode()).unwrap(), checkpoint);
+
+        let mut digests = BTreeMap::new();
+        digests.insert(b"a".to_vec(), [1; 16]);
+        digests.insert(b"b\0c".to_vec(), [2; 16]);
+        let checkpoint = Checkpoint {
+            passes: 3,
+            position: Some(ObjectId(b"a".to_vec())),
+            digests,
+        };
+        let encoded = checkpoint.encode();
+        assert_eq!(Checkpoint::decode(&encoded).unwrap(), checkpoint);
+
+        assert!(Checkpoint::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut extra = encoded.clone();
+        extra.push(0);
+        assert!(Checkpoint::decode(&extra).is_err());
+        let mut version = encoded;
+        version[0] = 2;
+        assert!(Checkpoint::decode(&version).is_err());
+    }
+
+    #[test]
+    fn test_vanished() {
+        let digests = [b"a", b"b", b"c", b"d", b"e"].iter()
+            .map(|n| (n.to_vec(), [0; 16]))
+            .collect();
+        let seen: HashSet<Vec<u8>> = [b"b".to_vec(), b"d".to_vec()].into_iter().collect();
+        assert_eq!(vanished(&digests, None, Some(b"d"), &seen), vec![b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(vanished(&digests, Some(b"a"), Some(b"d"), &seen), vec![b"c".to_vec()]);
+        assert_eq!(vanished(&digests, Some(b"d"), None, &HashSet::new()), vec![b"e".to_vec()]);
+        assert_eq!(vanished(&digests, Some(b"e"), None, &HashSet::new()), Vec::<Vec<u8>>::new());
+    }
+}
@@ -0,0 +1,272 @@
+//! A simulated network, to test the client and daemons reproducibly.
+//!
+//! Sockets bound on a `SimNetwork` exchange datagrams in memory, which the
+//! network loses, duplicates, delays and reorders at random. The choices
+//! come from a seeded generator, so a test that fails can be run again with
+//! the same ones. Delays use tokio's clock, which tests can pause so they
+//! don't slow down.
+//!
+//! The client and daemons send and receive on a `Socket`, which is either a
+//! real UDP socket or a simulated one.
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::udp_batch::{self, recv_batch, send_batch};
+
+/// How the network mistreats datagrams.
+#[derive(Clone, Debug, Default)]
+pub struct SimConfig {
+    /// Probability that a datagram is lost, from 0 to 1.
+    pub loss: f64,
+    /// Probability that a datagram arrives twice, from 0 to 1.
+    pub duplication: f64,
+    /// Time every datagram takes to arrive.
+    pub latency: Duration,
+    /// Most extra time a datagram takes at random, which reorders them.
+    pub jitter: Duration,
+}
+
+/// What happened to the datagrams sent so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    /// Sent to an address where no socket is bound.
+    pub undeliverable: u64,
+}
+
+/// Largest datagram, the most UDP can carry over IPv4.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+struct SimNetworkInner {
+    config: SimConfig,
+    rng: StdRng,
+    sockets: HashMap<SocketAddr, UnboundedSender<Datagram>>,
+    stats: SimStats,
+}
+
+#[derive(Clone)]
+pub struct SimNetwork(Arc<Mutex<SimNetworkInner>>);
+
+impl SimNetwork {
+    pub fn new(seed: u64, config: SimConfig) -> SimNetwork {
+        SimNetwork(Arc::new(Mutex::new(SimNetworkInner {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            sockets: HashMap::new(),
+            stats: SimStats::default(),
+        })))
+    }
+
+    /// Change how datagrams sent from now on are treated.
+    pub fn set_config(&self, config: SimConfig) {
+        self.0.lock().unwrap().config = config;
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.0.lock().unwrap().stats.clone()
+    }
+
+    pub fn bind(&self, address: SocketAddr) -> Result<SimSocket, IoError> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.sockets.contains_key(&address) {
+            return Err(IoError::new(ErrorKind::AddrInUse, format!("Address {} is already bound", address)));
+        }
+        let (sender, receiver) = unbounded_channel();
+        inner.sockets.insert(address, sender);
+        Ok(SimSocket {
+            network: self.clone(),
+            address,
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+
+    fn send(&self, data: &[u8], from: SocketAddr, to: SocketAddr) {
+        let mut inner = self.0.lock().unwrap();
+        let inner = &mut *inner;
+        inner.stats.sent += 1;
+        if inner.rng.gen_bool(inner.config.loss) {
+            inner.stats.lost += 1;
+            return;
+        }
+        let copies = if inner.rng.gen_bool(inner.config.duplication) {
+            inner.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let sender = match inner.sockets.get(&to) {
+            Some(s) => s.clone(),
+            None => {
+                inner.stats.undeliverable += 1;
+                return;
+            }
+        };
+        for _ in 0..copies {
+            let delay = inner.config.latency + inner.config.jitter.mul_f64(inner.rng.gen());
+            let sender = sender.clone();
+            let datagram = (data.to_owned(), from);
+            if delay.is_zero() {
+                sender.send(datagram).ok();
+            } else {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    sender.send(datagram).ok();
+                });
+            }
+        }
+    }
+}
+
+/// A socket on a simulated network, unbound when dropped.
+pub struct SimSocket {
+    network: SimNetwork,
+    address: SocketAddr,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<Datagram>>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, IoError> {
+        if buf.len() > MAX_DATAGRAM_SIZE {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Message too long"));
+        }
+        self.network.send(buf, self.address, target);
+        Ok(buf.len())
+    }
+
+    /// Receive a datagram, truncated to the size of `buf` like UDP does.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), IoError> {
+        let (data, from) = match self.receiver.lock().await.recv().await {
+            Some(d) => d,
+            None => return Err(IoError::new(ErrorKind::NotConnected, "Socket is not bound")),
+        };
+        let len = data.len().min(buf.len());
+        buf[0..len].copy_from_slice(&data[0..len]);
+        Ok((len, from))
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.0.lock().unwrap().sockets.remove(&self.address);
+    }
+}
+
+/// A socket on the real network, or on a simulated one.
+pub enum Socket {
+    Udp(UdpSocket),
+    Sim(SimSocket),
+}
+
+impl Socket {
+    pub fn local_addr(&self) -> Result<SocketAddr, IoError> {
+        match self {
+            Socket::Udp(socket) => socket.local_addr(),
+            Socket::Sim(socket) => Ok(socket.local_addr()),
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, IoError> {
+        match self {
+            Socket::Udp(socket) => socket.send_to(buf, target).await,
+            Socket::Sim(socket) => socket.send_to(buf, target),
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), IoError> {
+        match self {
+            Socket::Udp(socket) => udp_batch::recv_from(socket, buf).await,
+            Socket::Sim(socket) => socket.recv_from(buf).await,
+        }
+    }
+
+    /// Send all the datagrams in `frames` to `addr`, see `udp_batch`.
+    pub async fn send_batch<B: AsRef<[u8]>>(&self, frames: &[B], addr: SocketAddr) -> Result<(), IoError> {
+        match self {
+            Socket::Udp(socket) => send_batch(socket, frames, addr).await,
+            Socket::Sim(socket) => {
+                for frame in frames {
+                    socket.send_to(frame.as_ref(), addr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Receive datagrams into `bufs`, waiting for at least one, see
+    /// `udp_batch`.
+    pub async fn recv_batch<B: AsMut<[u8]>>(&self, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> Result<usize, IoError> {
+        match self {
+            Socket::Udp(socket) => recv_batch(socket, bufs, received).await,
+            Socket::Sim(socket) => {
+                received.clear();
+                received.push(socket.recv_from(bufs[0].as_mut()).await?);
+                Ok(1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{SimConfig, SimNetwork, SimStats};
+
+    async fn exchange(seed: u64) -> (Vec<u8>, SimStats) {
+        let network = SimNetwork::new(seed, SimConfig {
+            loss: 0.2,
+            duplication: 0.2,
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(50),
+        });
+        let a = network.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+        assert!(network.bind(b.local_addr()).is_err());
+
+        for i in 0..100u8 {
+            a.send_to(&[i], b.local_addr()).unwrap();
+        }
+        // Nobody there
+        a.send_to(b"lost", "10.0.0.3:1000".parse().unwrap()).unwrap();
+        // Too big
+        assert!(a.send_to(&[0; 70000], b.local_addr()).is_err());
+
+        let mut received = Vec::new();
+        let mut buf = [0; 16];
+        while let Ok(Ok((len, from))) = tokio::time::timeout(Duration::from_secs(1), b.recv_from(&mut buf)).await {
+            assert_eq!((len, from), (1, a.local_addr()));
+            received.push(buf[0]);
+        }
+        (received, network.stats())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_network() {
+        let (received, stats) = exchange(1).await;
+        assert_eq!(stats.sent, 101);
+        assert_eq!(stats.undeliverable, 1);
+        assert!(stats.lost > 0 && stats.duplicated > 0);
+        assert_eq!(received.len() as u64, 100 - stats.lost + stats.duplicated);
+        // Reordered
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+
+        // The same seed gives the same results
+        assert_eq!(exchange(1).await, (received, stats));
+    }
+}
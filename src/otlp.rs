@@ -0,0 +1,272 @@
+//! Push metrics to an OpenTelemetry collector.
+//!
+//! This sends the metrics of the registry (see `metrics::registry()`) using
+//! OTLP over HTTP, with the JSON encoding, to `<endpoint>/v1/metrics`. It is
+//! for setups where Prometheus doesn't scrape the processes.
+//!
+//! Only plain HTTP is supported, use a local collector to forward over TLS.
+
+use hyper::{Body, Client, Method, Request, Uri};
+use log::{debug, error, warn};
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{Value, json};
+use std::io::Error as IoError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+use crate::metrics::registry;
+
+/// OTLP aggregation temporality: values are totals since the start time.
+const CUMULATIVE: u32 = 2;
+
+/// An exporter running on its own thread.
+///
+/// Dropping this leaves the exporter running, use `shutdown()` to stop it.
+pub struct OtlpExporter {
+    shutdown: oneshot::Sender<()>,
+    done: oneshot::Receiver<()>,
+}
+
+impl OtlpExporter {
+    /// Send the metrics one last time, and stop.
+    pub async fn shutdown(self) {
+        self.shutdown.send(()).ok();
+        self.done.await.ok();
+    }
+}
+
+/// Start sending the metrics to a collector every `interval`.
+///
+/// `endpoint` is the base URL of the collector, for example
+/// `http://localhost:4318`.
+pub fn start_otlp_exporter(endpoint: &str, service_name: &str, interval: Duration) -> Result<OtlpExporter, IoError> {
+    let url: Uri = format!("{}/v1/metrics", endpoint.trim_end_matches('/'))
+        .parse()
+        .map_err(IoError::other)?;
+    if url.scheme_str() != Some("http") {
+        return Err(IoError::other("Only http:// endpoints are supported"));
+    }
+    let service_name = service_name.to_owned();
+    let mut runtime = tokio::runtime::Builder::new_current_thread();
+    runtime.enable_all();
+    let runtime = runtime.build()?;
+
+    let (shutdown_send, shutdown_recv) = oneshot::channel();
+    let (done_send, done_recv) = oneshot::channel();
+    std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let client = Client::new();
+            let start_time = SystemTime::now();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            let mut shutdown_recv = Some(shutdown_recv);
+            let mut stopping = false;
+            while !stopping {
+                match shutdown_recv {
+                    Some(ref mut recv) => tokio::select! {
+                        _ = ticker.tick() => {}
+                        r = recv => {
+                            // Keep going if the handle is dropped without
+                            // calling shutdown()
+                            if r.is_ok() {
+                                stopping = true;
+                            } else {
+                                shutdown_recv = None;
+                                continue;
+                            }
+                        }
+                    },
+                    None => {
+                        ticker.tick().await;
+                    }
+                }
+
+                let body = encode_metrics(&registry().gather(), &service_name, start_time, SystemTime::now());
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url.clone())
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                match tokio::time::timeout(interval, client.request(request)).await {
+                    Ok(Ok(response)) if response.status().is_success() => {
+                        debug!("Sent metrics to {}", url);
+                    }
+                    Ok(Ok(response)) => warn!("Collector returned {} for metrics", response.status()),
+                    Ok(Err(e)) => error!("Error sending metrics to {}: {}", url, e),
+                    Err(_) => error!("Timeout sending metrics to {}", url),
+                }
+            }
+        });
+        done_send.send(()).ok();
+    });
+
+    Ok(OtlpExporter {
+        shutdown: shutdown_send,
+        done: done_recv,
+    })
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+/// Convert the metrics to an OTLP `ExportMetricsServiceRequest`, in JSON.
+fn encode_metrics(families: &[MetricFamily], service_name: &str, start_time: SystemTime, now: SystemTime) -> Value {
+    let start_time = nanos(start_time);
+    let now = nanos(now);
+    let mut metrics = Vec::new();
+    for family in families {
+        let mut points = Vec::new();
+        for metric in family.get_metric() {
+            let attributes: Vec<Value> = metric.get_label().iter().map(|l| json!({
+                "key": l.get_name(),
+                "value": {"stringValue": l.get_value()},
+            })).collect();
+            let mut point = json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start_time,
+                "timeUnixNano": now,
+            });
+            match family.get_field_type() {
+                MetricType::COUNTER => point["asDouble"] = json!(metric.get_counter().get_value()),
+                MetricType::GAUGE => point["asDouble"] = json!(metric.get_gauge().get_value()),
+                MetricType::HISTOGRAM => {
+                    // Prometheus buckets are cumulative, OTLP ones are not and
+                    // have an extra one for values above the last bound
+                    let histogram = metric.get_histogram();
+                    let mut bounds = Vec::new();
+                    let mut counts = Vec::new();
+                    let mut previous = 0;
+                    for bucket in histogram.get_bucket() {
+                        bounds.push(bucket.get_upper_bound());
+                        counts.push((bucket.get_cumulative_count() - previous).to_string());
+                        previous = bucket.get_cumulative_count();
+                    }
+                    counts.push((histogram.get_sample_count() - previous).to_string());
+                    point["count"] = json!(histogram.get_sample_count().to_string());
+                    point["sum"] = json!(histogram.get_sample_sum());
+                    point["bucketCounts"] = json!(counts);
+                    point["explicitBounds"] = json!(bounds);
+                }
+                _ => continue,
+            }
+            points.push(point);
+        }
+        if points.is_empty() {
+            continue;
+        }
+        let data = match family.get_field_type() {
+            MetricType::COUNTER => ("sum", json!({
+                "dataPoints": points,
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+            })),
+            MetricType::GAUGE => ("gauge", json!({"dataPoints": points})),
+            _ => ("histogram", json!({
+                "dataPoints": points,
+                "aggregationTemporality": CUMULATIVE,
+            })),
+        };
+        let mut metric = json!({
+            "name": family.get_name(),
+            "description": family.get_help(),
+        });
+        metric[data.0] = data.1;
+        metrics.push(metric);
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}],
+            },
+            "scopeMetrics": [{
+                "scope": {"name": "store", "version": env!("CARGO_PKG_VERSION")},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{encode_metrics, start_otlp_exporter};
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "Requests"), &["op"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let gauge = IntGauge::new("in_flight", "In flight").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let opts = HistogramOpts::new("latency", "Latency").buckets(vec![0.1, 1.0]);
+        let histogram = Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.with_label_values(&["read"]).inc_by(3);
+        gauge.set(2);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(0.7);
+        histogram.observe(5.0);
+
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let now = UNIX_EPOCH + Duration::from_secs(2);
+        let value = encode_metrics(&registry.gather(), "test", start, now);
+        let resource = &value["resourceMetrics"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "test");
+        let metrics = resource["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        assert_eq!(metrics.len(), 3);
+
+        // Families are sorted by name
+        assert_eq!(metrics[0]["name"], "in_flight");
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asDouble"], json!(2.0));
+
+        assert_eq!(metrics[1]["name"], "latency");
+        let point = &metrics[1]["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "4");
+        assert_eq!(point["bucketCounts"], json!(["1", "2", "1"]));
+        assert_eq!(point["explicitBounds"], json!([0.1, 1.0]));
+
+        assert_eq!(metrics[2]["name"], "requests");
+        assert_eq!(metrics[2]["sum"]["isMonotonic"], true);
+        let point = &metrics[2]["sum"]["dataPoints"][0];
+        assert_eq!(point["asDouble"], json!(3.0));
+        assert_eq!(point["attributes"][0], json!({"key": "op", "value": {"stringValue": "read"}}));
+        assert_eq!(point["startTimeUnixNano"], "1000000000");
+        assert_eq!(point["timeUnixNano"], "2000000000");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sends() {
+        assert!(start_otlp_exporter("https://localhost:4318", "test", Duration::from_secs(1)).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let exporter = start_otlp_exporter(&endpoint, "test", Duration::from_secs(3600)).unwrap();
+
+        // Nothing is sent until the interval, or the shutdown
+        let collector = async {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(16).any(|w| w == b"resourceMetrics\"") {
+                let len = conn.read(&mut buf).await.unwrap();
+                assert!(len > 0);
+                request.extend_from_slice(&buf[0..len]);
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        };
+        let (request, ()) = tokio::join!(collector, exporter.shutdown());
+        assert!(request.starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+        assert!(request.contains("application/json"));
+    }
+}
@@ -0,0 +1,198 @@
+//! A progress line for long transfers on the command line.
+//!
+//! It is shown on stderr only if it is a terminal, redrawn at most a few
+//! times per second, with a bar and the remaining time if the size of the
+//! transfer is known.
+
+use std::io::{IsTerminal, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How often to redraw the line.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Width of the bar, in characters.
+const BAR_WIDTH: usize = 20;
+
+pub struct Progress {
+    enabled: bool,
+    /// Bytes expected, if known.
+    total: Option<u64>,
+    bytes: u64,
+    /// Objects done, for transfers of many objects.
+    objects: Option<u64>,
+    start: Instant,
+    /// When the line was last drawn, or when the transfer started.
+    last_shown: Instant,
+    shown: bool,
+}
+
+impl Progress {
+    /// Create a progress line, shown if stderr is a terminal.
+    pub fn new(total: Option<u64>) -> Progress {
+        Progress {
+            enabled: std::io::stderr().is_terminal(),
+            total,
+            bytes: 0,
+            objects: None,
+            start: Instant::now(),
+            last_shown: Instant::now(),
+            shown: false,
+        }
+    }
+
+    /// Whether the line is shown, so callers can skip finding the total.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.total = total;
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.show();
+    }
+
+    pub fn set(&mut self, bytes: u64, objects: Option<u64>) {
+        self.bytes = bytes;
+        self.objects = objects;
+        self.show();
+    }
+
+    fn show(&mut self) {
+        // Wait a bit before the first time too, so the rate makes sense
+        if !self.enabled || self.last_shown.elapsed() < REDRAW_INTERVAL {
+            return;
+        }
+        self.last_shown = Instant::now();
+        self.shown = true;
+        let line = format_line(self.bytes, self.total, self.objects, self.start.elapsed());
+        eprint!("\r{}\x1b[K", line);
+    }
+
+    /// Erase the line.
+    pub fn finish(&mut self) {
+        if self.shown {
+            eprint!("\r\x1b[K");
+        }
+        self.shown = false;
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Counts the bytes going through a reader or writer.
+pub struct ProgressIo<T> {
+    inner: T,
+    pub progress: Progress,
+}
+
+impl<T> ProgressIo<T> {
+    pub fn new(inner: T, progress: Progress) -> ProgressIo<T> {
+        ProgressIo { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressIo<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.progress.add(len as u64);
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for ProgressIo<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.progress.add(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Format a size with a binary unit, such as `1.50 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+fn format_line(bytes: u64, total: Option<u64>, objects: Option<u64>, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut line = String::new();
+    if let Some(total) = total.filter(|&t| t > 0) {
+        let done = (bytes as f64 / total as f64).min(1.0);
+        let filled = (done * BAR_WIDTH as f64) as usize;
+        line.push('[');
+        line.extend(std::iter::repeat_n('#', filled));
+        line.extend(std::iter::repeat_n('.', BAR_WIDTH - filled));
+        line.push_str("] ");
+    }
+    if let Some(objects) = objects {
+        line.push_str(&format!("{} objects, ", objects));
+    }
+    line.push_str(&format_bytes(bytes));
+    if let Some(total) = total {
+        line.push_str(&format!(" / {}", format_bytes(total)));
+    }
+    line.push_str(&format!(", {}/s", format_bytes(rate as u64)));
+    if let Some(total) = total {
+        if rate > 0.0 && bytes < total {
+            let left = Duration::from_secs_f64((total - bytes) as f64 / rate);
+            line.push_str(&format!(", ETA {}", format_duration(left)));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{format_bytes, format_line};
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(3 << 30), "3.00 GiB");
+
+        assert_eq!(
+            format_line(1 << 30, Some(4 << 30), None, Duration::from_secs(8)),
+            "[#####...............] 1.00 GiB / 4.00 GiB, 128.00 MiB/s, ETA 0:24",
+        );
+        assert_eq!(
+            format_line(5 << 20, None, Some(12), Duration::from_secs(2)),
+            "12 objects, 5.00 MiB, 2.50 MiB/s",
+        );
+        assert_eq!(
+            format_line(1 << 20, Some(7200 << 20), None, Duration::from_secs(1)),
+            "[....................] 1.00 MiB / 7.03 GiB, 1.00 MiB/s, ETA 1:59:59",
+        );
+    }
+}
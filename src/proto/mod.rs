@@ -0,0 +1,488 @@
+//! A simple ASCII protocol.
+//!
+//! Each message is a line of arguments separated by spaces. Arguments can be
+//! any bytes:
+//!
+//! * A backslash escapes the next character: `\n` is a line feed, `\xHH` is
+//!   the byte with that hex value, and any other character stands for itself
+//!   (`\\`, `\ `, `\"`).
+//! * An argument in double quotes can contain spaces, `""` is the empty
+//!   argument.
+//!
+//! `MessageBuilder` writes messages and `Parser` reads them. Blank lines are
+//! ignored.
+//!
+//! The binary protocol between clients and storage daemons is in `wire`.
+
+pub mod wire;
+
+use std::borrow::Cow;
+use std::fmt::{Debug, Display};
+use std::io::{Error as IoError, ErrorKind};
+
+use crate::ObjectId;
+
+/// Default for the longest line accepted by `Parser`.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 << 10;
+
+/// Default for the most data `Parser` keeps before it is read.
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 1 << 20;
+
+pub struct Parser {
+    buffer: Vec<u8>,
+    pos: usize,
+    /// Start of the incomplete line at the end of the buffer.
+    line_start: usize,
+    /// Whether we are dropping the rest of a line that was too long.
+    discarding: bool,
+    max_line_length: usize,
+    max_buffer_size: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::with_limits(DEFAULT_MAX_LINE_LENGTH, DEFAULT_MAX_BUFFER_SIZE)
+    }
+}
+
+impl Parser {
+    /// Create a parser with a maximum line length, and a maximum for the
+    /// data buffered but not read yet.
+    pub fn with_limits(max_line_length: usize, max_buffer_size: usize) -> Parser {
+        Parser {
+            buffer: Vec::new(),
+            pos: 0,
+            line_start: 0,
+            discarding: false,
+            max_line_length,
+            max_buffer_size,
+        }
+    }
+
+    /// Add received data.
+    ///
+    /// Lines longer than the limit are dropped, and an error is returned;
+    /// the other lines are kept and parsing can continue. If too much data is
+    /// buffered, none of the new data is kept.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), IoError> {
+        self.buffer.drain(0..self.pos);
+        self.line_start -= self.pos;
+        self.pos = 0;
+
+        if self.buffer.len() + data.len() > self.max_buffer_size {
+            return Err(IoError::new(ErrorKind::InvalidData, "Too much data buffered"));
+        }
+
+        let mut result = Ok(());
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (part, complete) = match rest.iter().position(|&c| c == b'\n') {
+                Some(nl) => (&rest[..nl + 1], true),
+                None => (rest, false),
+            };
+            rest = &rest[part.len()..];
+
+            if self.discarding {
+                self.discarding = !complete;
+                continue;
+            }
+
+            let line_length = self.buffer.len() - self.line_start + part.len() - complete as usize;
+            if line_length > self.max_line_length {
+                self.buffer.truncate(self.line_start);
+                self.discarding = !complete;
+                result = Err(IoError::new(ErrorKind::InvalidData, "Line too long"));
+                continue;
+            }
+
+            self.buffer.extend_from_slice(part);
+            if complete {
+                self.line_start = self.buffer.len();
+            }
+        }
+        result
+    }
+
+    pub fn next<'a>(&'a mut self) -> Option<Message<'a>> {
+        loop {
+            // Find next line feed
+            let nl = self.buffer[self.pos..].iter().position(|&c| c == b'\n');
+            let nl = match nl {
+                None => return None,
+                Some(p) => p + self.pos,
+            };
+            let start = self.pos;
+
+            // Update position
+            self.pos = nl + 1;
+
+            // Build a Message, skipping blank lines
+            let line = &self.buffer[start..nl];
+            if line.iter().any(|&c| c != b' ') {
+                return Some(Message::new(line));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer[self.pos..].is_empty()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Message<'a>(Vec<Cow<'a, [u8]>>);
+
+impl<'a> Debug for Message<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Message(")?;
+        for (i, arg) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:?}", arg)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl<'a> Message<'a> {
+    fn new(line: &'a [u8]) -> Message<'a> {
+        let mut args = Vec::new();
+        let mut pos = 0;
+        loop {
+            if pos == line.len() {
+                return Message(args);
+            }
+            while line[pos] == b' ' {
+                pos += 1;
+                if pos == line.len() {
+                    return Message(args);
+                }
+            }
+            let quoted = line[pos] == b'"';
+            let delimiter = if quoted { b'"' } else { b' ' };
+            let start = if quoted { pos + 1 } else { pos };
+            let mut end = start;
+            let mut escaped = false;
+            while end < line.len() && line[end] != delimiter {
+                if line[end] == b'\\' && end + 1 < line.len() {
+                    escaped = true;
+                    end += 1;
+                }
+                end += 1;
+            }
+            if escaped {
+                args.push(Cow::Owned(unescape(&line[start..end])));
+            } else {
+                args.push(Cow::Borrowed(&line[start..end]));
+            }
+            pos = end + 1;
+            if pos >= line.len() {
+                return Message(args);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn get_bytes(&self, idx: usize) -> &[u8] {
+        &self.0[idx]
+    }
+
+    pub fn get_str(&self, idx: usize) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.0[idx])
+    }
+
+    pub fn get_object_id(&self, idx: usize) -> ObjectId {
+        ObjectId(self.0[idx].to_vec())
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn unescape(arg: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(arg.len());
+    let mut iter = arg.iter();
+    while let Some(&c) = iter.next() {
+        if c != b'\\' {
+            result.push(c);
+            continue;
+        }
+        match iter.next() {
+            Some(b'n') => result.push(b'\n'),
+            Some(b'x') => {
+                let rest = iter.as_slice();
+                match (rest.first().and_then(|&c| hex_value(c)), rest.get(1).and_then(|&c| hex_value(c))) {
+                    (Some(high), Some(low)) => {
+                        result.push(high << 4 | low);
+                        iter.nth(1);
+                    }
+                    // Not a valid escape, keep it as it is
+                    _ => result.extend_from_slice(b"\\x"),
+                }
+            }
+            Some(&c) => result.push(c),
+            None => result.push(b'\\'),
+        }
+    }
+    result
+}
+
+/// Writes a message in the line format, escaping arguments as needed.
+pub struct MessageBuilder {
+    line: Vec<u8>,
+}
+
+impl MessageBuilder {
+    /// Start a message with the command, the first argument.
+    pub fn new(command: &str) -> MessageBuilder {
+        let mut builder = MessageBuilder { line: Vec::new() };
+        builder.push(command.as_bytes());
+        builder
+    }
+
+    fn push(&mut self, arg: &[u8]) {
+        if !self.line.is_empty() {
+            self.line.push(b' ');
+        }
+
+        // Quote arguments with spaces, and the empty argument
+        let quoted = arg.is_empty() || arg.contains(&b' ');
+        if quoted {
+            self.line.push(b'"');
+        }
+        for &c in arg {
+            match c {
+                b' ' => self.line.push(c),
+                b'\n' => self.line.extend_from_slice(b"\\n"),
+                b'\\' | b'"' => {
+                    self.line.push(b'\\');
+                    self.line.push(c);
+                }
+                0x21..=0x7e => self.line.push(c),
+                c => self.line.extend_from_slice(format!("\\x{:02x}", c).as_bytes()),
+            }
+        }
+        if quoted {
+            self.line.push(b'"');
+        }
+    }
+
+    pub fn arg(mut self, arg: &[u8]) -> MessageBuilder {
+        self.push(arg);
+        self
+    }
+
+    pub fn arg_str(self, arg: &str) -> MessageBuilder {
+        self.arg(arg.as_bytes())
+    }
+
+    pub fn arg_object_id(self, object_id: &ObjectId) -> MessageBuilder {
+        self.arg(&object_id.0)
+    }
+
+    /// Add an argument formatted with `Display`, like a number.
+    pub fn arg_display<T: Display>(self, arg: T) -> MessageBuilder {
+        self.arg(arg.to_string().as_bytes())
+    }
+
+    /// Get the line, ending with a line feed.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.line.push(b'\n');
+        self.line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use crate::ObjectId;
+    use super::{MessageBuilder, Parser};
+
+    #[test]
+    fn test_parser() {
+        let mut parser = Parser::default();
+
+        parser.feed(b"FOO a").unwrap();
+        assert_eq!(parser.next(), None);
+        assert!(!parser.is_empty());
+
+        parser.feed(b"b 42\nBAR c\nEXI").unwrap();
+        let message = if let Some(m) = parser.next() { m } else { panic!() };
+        assert_eq!(message.len(), 3);
+        assert_eq!(message.get_bytes(0), b"FOO");
+        assert_eq!(message.get_str(1), Ok("ab"));
+        assert_eq!(message.get_str(2).unwrap().parse::<i32>().unwrap(), 42);
+        let message = if let Some(m) = parser.next() { m } else { panic!() };
+        assert_eq!(message.len(), 2);
+        assert_eq!(message.get_str(0), Ok("BAR"));
+        assert_eq!(message.get_bytes(1), b"c");
+        assert_eq!(parser.next(), None);
+        assert!(!parser.is_empty());
+
+        parser.feed(b"T\n").unwrap();
+        assert!(!parser.is_empty());
+        let message = if let Some(m) = parser.next() { m } else { panic!() };
+        assert_eq!(message.len(), 1);
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn test_builder() {
+        let line = MessageBuilder::new("PUT")
+            .arg_str("pool")
+            .arg(b"name with spaces\nand\\slash")
+            .arg_display(42)
+            .finish();
+        assert_eq!(&line, b"PUT pool \"name with spaces\\nand\\\\slash\" 42\n");
+
+        let mut parser = Parser::default();
+        parser.feed(&line).unwrap();
+        let message = parser.next().unwrap();
+        assert_eq!(message.len(), 4);
+        assert_eq!(message.get_str(0), Ok("PUT"));
+        assert_eq!(message.get_str(1), Ok("pool"));
+        assert_eq!(message.get_bytes(2), b"name with spaces\nand\\slash");
+        assert_eq!(message.get_str(3), Ok("42"));
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn test_binary_args() {
+        let object_id = ObjectId(vec![0, b'"', 0xff, b'\r', b'x', b' ']);
+        let line = MessageBuilder::new("GET")
+            .arg_object_id(&object_id)
+            .arg(b"")
+            .arg(b"\"quoted\"")
+            .finish();
+        assert_eq!(&line, b"GET \"\\x00\\\"\\xff\\x0dx \" \"\" \\\"quoted\\\"\n");
+
+        let mut parser = Parser::default();
+        parser.feed(&line).unwrap();
+        let message = parser.next().unwrap();
+        assert_eq!(message.len(), 4);
+        assert_eq!(message.get_object_id(1), object_id);
+        assert_eq!(message.get_bytes(2), b"");
+        assert_eq!(message.get_bytes(3), b"\"quoted\"");
+
+        // Escapes written by hand
+        parser.feed(b"A \\ b\\x41\\x4 \"c d\"e \\q\n").unwrap();
+        let message = parser.next().unwrap();
+        assert_eq!(message.len(), 5);
+        assert_eq!(message.get_bytes(1), b" bA\\x4");
+        assert_eq!(message.get_bytes(2), b"c d");
+        assert_eq!(message.get_bytes(3), b"e");
+        assert_eq!(message.get_bytes(4), b"q");
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut parser = Parser::with_limits(8, 32);
+
+        // Blank lines are skipped
+        parser.feed(b"\n  \nA\n\n").unwrap();
+        assert_eq!(parser.next().unwrap().get_bytes(0), b"A");
+        assert_eq!(parser.next(), None);
+
+        // A long line is dropped, even when it arrives in parts
+        parser.feed(b"B\n0123").unwrap();
+        assert!(parser.feed(b"45678").is_err());
+        parser.feed(b"9abcdef").unwrap();
+        parser.feed(b"\nC 1234\n").unwrap();
+        assert_eq!(parser.next().unwrap().get_bytes(0), b"B");
+        let message = parser.next().unwrap();
+        assert_eq!(message.get_bytes(0), b"C");
+        assert_eq!(message.get_bytes(1), b"1234");
+        assert_eq!(parser.next(), None);
+        assert!(parser.is_empty());
+
+        // Complete lines that are too long are dropped too
+        assert!(parser.feed(b"D\n0123456789\nE\n").is_err());
+        assert_eq!(parser.next().unwrap().get_bytes(0), b"D");
+        assert_eq!(parser.next().unwrap().get_bytes(0), b"E");
+
+        // Data is refused when it is not read
+        parser.feed(b"0123456\n0123456\n0123456\n").unwrap();
+        assert!(parser.feed(b"0123456\n0123456\n").is_err());
+        assert_eq!(parser.next().unwrap().get_bytes(0), b"0123456");
+        parser.feed(b"F\n").unwrap();
+    }
+
+    /// Split data at random places.
+    fn random_parts<'a>(rng: &mut StdRng, data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut parts = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = rng.gen_range(1..=(data.len() - pos).min(20));
+            parts.push(&data[pos..pos + len]);
+            pos += len;
+        }
+        parts
+    }
+
+    #[test]
+    fn fuzz_parser() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let alphabet = b" \n\\\"x0aA";
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..200);
+            let data: Vec<u8> = (0..len).map(|_| {
+                if rng.gen_bool(0.8) {
+                    alphabet[rng.gen_range(0..alphabet.len())]
+                } else {
+                    rng.gen()
+                }
+            }).collect();
+
+            let mut parser = Parser::with_limits(30, 1000);
+            for part in random_parts(&mut rng, &data) {
+                parser.feed(part).ok();
+                while let Some(message) = parser.next() {
+                    assert!(message.len() > 0);
+                    for i in 0..message.len() {
+                        assert!(message.get_bytes(i).len() <= 30);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..1000 {
+            let count = rng.gen_range(0..5);
+            let args: Vec<Vec<u8>> = (0..count).map(|_| {
+                let len = rng.gen_range(0..10);
+                (0..len).map(|_| rng.gen()).collect()
+            }).collect();
+            let mut builder = MessageBuilder::new("CMD");
+            for arg in &args {
+                builder = builder.arg(arg);
+            }
+            let line = builder.finish();
+
+            let mut parser = Parser::default();
+            for part in random_parts(&mut rng, &line) {
+                parser.feed(part).unwrap();
+            }
+            let message = parser.next().unwrap();
+            assert_eq!(message.len(), args.len() + 1);
+            for (i, arg) in args.iter().enumerate() {
+                assert_eq!(message.get_bytes(i + 1), &arg[..]);
+            }
+            assert_eq!(parser.next(), None);
+        }
+    }
+}
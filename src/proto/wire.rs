@@ -0,0 +1,1442 @@
+//! The binary protocol between clients and storage daemons.
+//!
+//! Requests are: protocol version, counter (u32), epoch (u32), trace ID
+//! (u64), capability length (u16), capability, pool name length (u32), pool
+//! name, opcode, then the arguments of the command.
+//!
+//! Responses are: protocol version, counter (u32) of the request, trace ID
+//! (u64) of the request, status byte, then the data if any, or the error
+//! code.
+//!
+//! Every write gives the object a new version (u64), which is higher than the
+//! previous one. Daemons answer writes with it, and reads can be made
+//! conditional on it.
+//!
+//! Objects can also have tags, short strings that are returned with their
+//! stat and that listing can filter on. Writing a whole object clears them.
+//!
+//! A trace ID of 0 means there is none. It is kept when requests are
+//! forwarded, so a request can be followed in the logs of every daemon.
+//!
+//! Clients holding a ticket from the master first open a session with each
+//! daemon with a handshake request, see `crate::crypto::session`. It needs no
+//! capability, the ticket is what the daemon checks. Their other requests are
+//! then sealed with the session keys behind a cleartext header, and so are
+//! the responses, see `crate::crypto::envelope`.
+//!
+//! Responses too big for one datagram are split into chunks: protocol
+//! version, counter (u32), trace ID (u64), `STATUS_CHUNK`, sequence number
+//! (u16), a flag byte set if more chunks follow, then part of the response
+//! from its status byte on. The receiver puts them back together with
+//! `ChunkAssembler`.
+//!
+//! Frames sent as plain datagrams are followed by their CRC32C (u32), which
+//! the receiver checks before parsing them, so corrupted datagrams are dropped
+//! early. Sealed and DTLS datagrams are authenticated already and carry none.
+//!
+//! All integers are big-endian.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::{Rng, thread_rng};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Cursor, Error as IoError, ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{DeviceId, MAX_OBJECT_ID_LEN, ObjectId, PoolName, check_tags};
+use crate::error::Error;
+
+/// Version byte at the start of every message.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+pub const OPCODE_READ_OBJECT: u8 = 0x01;
+pub const OPCODE_READ_PART: u8 = 0x02;
+pub const OPCODE_WRITE_OBJECT: u8 = 0x03;
+pub const OPCODE_WRITE_PART: u8 = 0x04;
+pub const OPCODE_DELETE_OBJECT: u8 = 0x05;
+pub const OPCODE_STAT_OBJECT: u8 = 0x06;
+pub const OPCODE_LIST_OBJECTS: u8 = 0x07;
+pub const OPCODE_APPEND_OBJECT: u8 = 0x08;
+pub const OPCODE_TRUNCATE_OBJECT: u8 = 0x09;
+pub const OPCODE_COMPARE_AND_SWAP: u8 = 0x0a;
+pub const OPCODE_FLUSH: u8 = 0x0b;
+pub const OPCODE_STATS: u8 = 0x0c;
+pub const OPCODE_WATCH: u8 = 0x0d;
+pub const OPCODE_WRITE_IF_VERSION: u8 = 0x0e;
+pub const OPCODE_SET_TAGS: u8 = 0x0f;
+pub const OPCODE_HANDSHAKE: u8 = 0x10;
+
+const STATUS_DONE: u8 = 0x00;
+const STATUS_DATA: u8 = 0x01;
+const STATUS_ERROR: u8 = 0x02;
+const STATUS_STAT: u8 = 0x03;
+const STATUS_LIST: u8 = 0x04;
+const STATUS_CHUNK: u8 = 0x05;
+const STATUS_STATS: u8 = 0x06;
+const STATUS_EVENTS: u8 = 0x07;
+const STATUS_WRITTEN: u8 = 0x08;
+const STATUS_OBJECT: u8 = 0x09;
+const STATUS_UNCHANGED: u8 = 0x0a;
+const STATUS_SESSION: u8 = 0x0b;
+
+/// Size of the version and counter of a response, and of the header of a
+/// chunk.
+const RESPONSE_HEADER_SIZE: usize = 13;
+const CHUNK_HEADER_SIZE: usize = 17;
+
+/// Largest frame to send as a datagram.
+pub const MAX_FRAME_SIZE: usize = 60000;
+
+/// Size of the checksum following frames sent as plain datagrams.
+pub const CHECKSUM_SIZE: usize = 4;
+
+fn invalid(msg: &'static str) -> IoError {
+    Error::Protocol(msg.to_owned()).into()
+}
+
+/// An operation requested by a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Read an object, unless its version is `if_changed`.
+    ReadObject { object_id: ObjectId, if_changed: Option<u64> },
+    ReadPart { object_id: ObjectId, offset: u32, len: u32 },
+    WriteObject { object_id: ObjectId, data: Vec<u8> },
+    WritePart { object_id: ObjectId, offset: u32, data: Vec<u8> },
+    DeleteObject { object_id: ObjectId },
+    StatObject { object_id: ObjectId },
+    /// List the objects a daemon holds whose name starts with `prefix`, in
+    /// order, starting after `start_after` if set, only those with `tag` if
+    /// set.
+    ListObjects { prefix: Vec<u8>, start_after: Option<ObjectId>, limit: u32, tag: Option<String> },
+    AppendObject { object_id: ObjectId, data: Vec<u8> },
+    TruncateObject { object_id: ObjectId, len: u32 },
+    /// Replace the object with `data` if its content is `expected`, `None`
+    /// meaning it must not exist.
+    CompareAndSwap { object_id: ObjectId, expected: Option<Vec<u8>>, data: Vec<u8> },
+    /// Make the writes the daemon acknowledged so far durable.
+    Flush,
+    /// Get the space used by each pool on the daemon.
+    Stats,
+    /// Get the changes to the objects a daemon holds whose name starts with
+    /// `prefix`, from the position `after` returned by the previous request,
+    /// or from now if `None`.
+    Watch { prefix: Vec<u8>, after: Option<u64> },
+    /// Replace the object with `data` if its version is `version`, `None`
+    /// meaning it must not exist.
+    WriteIfVersion { object_id: ObjectId, version: Option<u64>, data: Vec<u8> },
+    /// Replace the tags of an object.
+    SetTags { object_id: ObjectId, tags: Vec<String> },
+    /// Open a session with a ticket, `hello` is from
+    /// `Ticket::start_session()`.
+    Handshake { hello: Vec<u8> },
+}
+
+impl Request {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Request::ReadObject { .. } => OPCODE_READ_OBJECT,
+            Request::ReadPart { .. } => OPCODE_READ_PART,
+            Request::WriteObject { .. } => OPCODE_WRITE_OBJECT,
+            Request::WritePart { .. } => OPCODE_WRITE_PART,
+            Request::DeleteObject { .. } => OPCODE_DELETE_OBJECT,
+            Request::StatObject { .. } => OPCODE_STAT_OBJECT,
+            Request::ListObjects { .. } => OPCODE_LIST_OBJECTS,
+            Request::AppendObject { .. } => OPCODE_APPEND_OBJECT,
+            Request::TruncateObject { .. } => OPCODE_TRUNCATE_OBJECT,
+            Request::CompareAndSwap { .. } => OPCODE_COMPARE_AND_SWAP,
+            Request::Flush => OPCODE_FLUSH,
+            Request::Stats => OPCODE_STATS,
+            Request::Watch { .. } => OPCODE_WATCH,
+            Request::WriteIfVersion { .. } => OPCODE_WRITE_IF_VERSION,
+            Request::SetTags { .. } => OPCODE_SET_TAGS,
+            Request::Handshake { .. } => OPCODE_HANDSHAKE,
+        }
+    }
+
+    /// Name of the operation, for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::ReadObject { .. } => "read_object",
+            Request::ReadPart { .. } => "read_part",
+            Request::WriteObject { .. } => "write_object",
+            Request::WritePart { .. } => "write_part",
+            Request::DeleteObject { .. } => "delete_object",
+            Request::StatObject { .. } => "stat_object",
+            Request::ListObjects { .. } => "list_objects",
+            Request::AppendObject { .. } => "append_object",
+            Request::TruncateObject { .. } => "truncate_object",
+            Request::CompareAndSwap { .. } => "compare_and_swap",
+            Request::Flush => "flush",
+            Request::Stats => "stats",
+            Request::Watch { .. } => "watch",
+            Request::WriteIfVersion { .. } => "write_if_version",
+            Request::SetTags { .. } => "set_tags",
+            Request::Handshake { .. } => "handshake",
+        }
+    }
+
+    /// The object the request is about, `None` for requests about the
+    /// whole daemon, such as listing and flushing.
+    pub fn object_id(&self) -> Option<&ObjectId> {
+        match self {
+            Request::ReadObject { object_id, .. }
+            | Request::ReadPart { object_id, .. }
+            | Request::WriteObject { object_id, .. }
+            | Request::WritePart { object_id, .. }
+            | Request::DeleteObject { object_id }
+            | Request::StatObject { object_id }
+            | Request::AppendObject { object_id, .. }
+            | Request::TruncateObject { object_id, .. }
+            | Request::CompareAndSwap { object_id, .. }
+            | Request::WriteIfVersion { object_id, .. }
+            | Request::SetTags { object_id, .. } => Some(object_id),
+            Request::ListObjects { .. }
+            | Request::Flush
+            | Request::Stats
+            | Request::Watch { .. }
+            | Request::Handshake { .. } => None,
+        }
+    }
+
+    /// Check the object IDs, prefixes and tags against the limits, see
+    /// `ObjectId` and `check_tags()`.
+    pub fn check(&self) -> Result<(), IoError> {
+        if let Some(object_id) = self.object_id() {
+            object_id.check()?;
+        }
+        match self {
+            Request::ListObjects { prefix, .. } | Request::Watch { prefix, .. } if prefix.len() > MAX_OBJECT_ID_LEN => {
+                Err(IoError::new(ErrorKind::InvalidInput, "Prefix is longer than the longest object ID"))
+            }
+            Request::ListObjects { start_after, tag, .. } => {
+                if let Some(start_after) = start_after {
+                    start_after.check()?;
+                }
+                match tag {
+                    Some(tag) => check_tags(std::slice::from_ref(tag)),
+                    None => Ok(()),
+                }
+            }
+            Request::SetTags { tags, .. } => check_tags(tags),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a request failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The object doesn't exist.
+    NotFound,
+    /// This daemon is not responsible for the object.
+    WrongDaemon,
+    /// The pool or the device is full.
+    QuotaExceeded,
+    /// The capability is missing or doesn't allow the request.
+    Unauthorized,
+    /// The stored data is damaged.
+    Corrupt,
+    /// The request doesn't make sense, for example an unknown pool.
+    InvalidRequest,
+    /// Any other failure on the daemon.
+    Internal,
+    /// The daemon doesn't know or doesn't support this operation.
+    Unsupported,
+    /// The object didn't have the expected content or version.
+    Conflict,
+    /// The session the request was sent in is unknown to the daemon, it
+    /// expired or the daemon restarted.
+    NoSession,
+}
+
+impl ErrorCode {
+    /// Short name of the error, for metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::WrongDaemon => "wrong_daemon",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Corrupt => "corrupt",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::NoSession => "no_session",
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            ErrorCode::NotFound => 0x01,
+            ErrorCode::WrongDaemon => 0x02,
+            ErrorCode::QuotaExceeded => 0x03,
+            ErrorCode::Unauthorized => 0x04,
+            ErrorCode::Corrupt => 0x05,
+            ErrorCode::InvalidRequest => 0x06,
+            ErrorCode::Internal => 0x07,
+            ErrorCode::Unsupported => 0x08,
+            ErrorCode::Conflict => 0x09,
+            ErrorCode::NoSession => 0x0a,
+        }
+    }
+
+    /// Decode an error code, unknown codes from newer daemons being
+    /// treated as `Internal`.
+    fn from_u8(code: u8) -> ErrorCode {
+        match code {
+            0x01 => ErrorCode::NotFound,
+            0x02 => ErrorCode::WrongDaemon,
+            0x03 => ErrorCode::QuotaExceeded,
+            0x04 => ErrorCode::Unauthorized,
+            0x05 => ErrorCode::Corrupt,
+            0x06 => ErrorCode::InvalidRequest,
+            0x08 => ErrorCode::Unsupported,
+            0x09 => ErrorCode::Conflict,
+            0x0a => ErrorCode::NoSession,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The closest kind of I/O error.
+    pub fn kind(self) -> ErrorKind {
+        match self {
+            ErrorCode::NotFound => ErrorKind::NotFound,
+            ErrorCode::WrongDaemon => ErrorKind::AddrNotAvailable,
+            ErrorCode::NoSession => ErrorKind::NotConnected,
+            ErrorCode::Unauthorized => ErrorKind::PermissionDenied,
+            ErrorCode::Corrupt => ErrorKind::InvalidData,
+            ErrorCode::InvalidRequest | ErrorCode::Unsupported => ErrorKind::InvalidInput,
+            ErrorCode::QuotaExceeded | ErrorCode::Internal | ErrorCode::Conflict => ErrorKind::Other,
+        }
+    }
+
+    /// Get the code an I/O error was created from, if any.
+    pub fn get(err: &IoError) -> Option<ErrorCode> {
+        err.get_ref()?.downcast_ref::<ErrorCode>().copied()
+    }
+
+    /// The code to send back for an error.
+    pub fn for_error(err: &IoError) -> ErrorCode {
+        if let Some(code) = ErrorCode::get(err) {
+            return code;
+        }
+        match err.kind() {
+            ErrorKind::PermissionDenied => ErrorCode::Unauthorized,
+            ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            ErrorCode::NotFound => "No such object",
+            ErrorCode::WrongDaemon => "Request was sent to wrong daemon",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::Unauthorized => "Request not allowed",
+            ErrorCode::Corrupt => "Stored data is corrupt",
+            ErrorCode::InvalidRequest => "Invalid request",
+            ErrorCode::Internal => "Internal error on storage daemon",
+            ErrorCode::Unsupported => "Operation not supported by storage daemon",
+            ErrorCode::Conflict => "Object doesn't have the expected content or version",
+            ErrorCode::NoSession => "Session is unknown to the storage daemon",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for ErrorCode {}
+
+impl From<ErrorCode> for IoError {
+    fn from(code: ErrorCode) -> IoError {
+        IoError::new(code.kind(), code)
+    }
+}
+
+/// The result of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The delete or flush was done.
+    Done,
+    /// The write was done, giving the object a new version.
+    Written { version: u64 },
+    /// The data that was read.
+    Data(Vec<u8>),
+    /// A whole object that was read, with its version.
+    Object { version: u64, data: Vec<u8> },
+    /// The object still has the version the read was conditional on.
+    Unchanged,
+    /// The request failed.
+    Error(ErrorCode),
+    /// Information about an object.
+    Stat { size: u64, expires: Option<SystemTime>, version: u64, tags: Vec<String> },
+    /// A page of object names.
+    List(Vec<ObjectId>),
+    /// The space used on a daemon, and left if known.
+    Stats { device_id: DeviceId, free_space: Option<u64>, pools: Vec<PoolUsage> },
+    /// Changes to objects, and the position to watch from next. `lost` is set
+    /// if some changes since the requested position were forgotten.
+    Events { next: u64, lost: bool, events: Vec<Event> },
+    /// The answer to a handshake, for `ClientHandshake::finish()`.
+    Session(Vec<u8>),
+}
+
+/// The data a daemon stores for a pool, as of its last count.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub pool: PoolName,
+    /// The number of replicas in the pool's map.
+    pub replicas: u32,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl EventKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            EventKind::Created => 1,
+            EventKind::Updated => 2,
+            EventKind::Deleted => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<EventKind, IoError> {
+        match value {
+            1 => Ok(EventKind::Created),
+            2 => Ok(EventKind::Updated),
+            3 => Ok(EventKind::Deleted),
+            _ => Err(invalid("Invalid event kind")),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A change to an object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub object_id: ObjectId,
+}
+
+/// Identifies a request across the client and the daemons it goes through.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u64);
+
+impl TraceId {
+    pub fn generate() -> TraceId {
+        TraceId(thread_rng().gen_range(1..=u64::MAX))
+    }
+}
+
+impl fmt::Debug for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TraceId({:016x})", self.0)
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Displays an optional trace ID for logging, "-" if there is none.
+pub struct TraceLabel(pub Option<TraceId>);
+
+impl fmt::Display for TraceLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(trace_id) => write!(f, "{}", trace_id),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+fn write_trace_id(result: &mut Vec<u8>, trace_id: Option<TraceId>) {
+    result.write_u64::<BigEndian>(trace_id.map(|t| t.0).unwrap_or(0)).unwrap();
+}
+
+fn read_trace_id(reader: &mut Cursor<&[u8]>) -> Result<Option<TraceId>, IoError> {
+    match reader.read_u64::<BigEndian>()? {
+        0 => Ok(None),
+        id => Ok(Some(TraceId(id))),
+    }
+}
+
+/// A request with the fields common to all operations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestMessage {
+    pub counter: u32,
+    pub epoch: u32,
+    pub trace_id: Option<TraceId>,
+    /// Sealed capability, empty if the client has none.
+    pub capability: Vec<u8>,
+    pub pool: PoolName,
+    pub request: Request,
+}
+
+/// A response, with the counter of the request it answers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseMessage {
+    pub counter: u32,
+    /// The trace ID of the request.
+    pub trace_id: Option<TraceId>,
+    pub response: Response,
+}
+
+fn write_object_id(result: &mut Vec<u8>, object_id: &ObjectId) {
+    result.write_u32::<BigEndian>(object_id.0.len() as u32).unwrap();
+    result.extend_from_slice(&object_id.0);
+}
+
+/// Read a length-prefixed field, checking the length against what's left.
+fn read_bytes(reader: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>, IoError> {
+    if len > reader.get_ref().len() - reader.position() as usize {
+        return Err(invalid("Truncated message"));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_object_id(reader: &mut Cursor<&[u8]>) -> Result<ObjectId, IoError> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    Ok(ObjectId(read_bytes(reader, len)?))
+}
+
+fn read_data(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, IoError> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    read_bytes(reader, len)
+}
+
+fn write_option_u64(result: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            result.write_u8(1).unwrap();
+            result.write_u64::<BigEndian>(value).unwrap();
+        }
+        None => result.write_u8(0).unwrap(),
+    }
+}
+
+fn read_option_u64(reader: &mut Cursor<&[u8]>, msg: &'static str) -> Result<Option<u64>, IoError> {
+    match reader.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(reader.read_u64::<BigEndian>()?)),
+        _ => Err(invalid(msg)),
+    }
+}
+
+fn write_tag(result: &mut Vec<u8>, tag: &str) {
+    result.write_u8(tag.len() as u8).unwrap();
+    result.extend_from_slice(tag.as_bytes());
+}
+
+fn read_tag(reader: &mut Cursor<&[u8]>) -> Result<String, IoError> {
+    let len = reader.read_u8()? as usize;
+    String::from_utf8(read_bytes(reader, len)?).map_err(|_| invalid("Invalid tag"))
+}
+
+/// Write a list of tags, which must be checked with `check_tags()` first.
+fn write_tags(result: &mut Vec<u8>, tags: &[String]) {
+    result.write_u8(tags.len() as u8).unwrap();
+    for tag in tags {
+        write_tag(result, tag);
+    }
+}
+
+fn read_tags(reader: &mut Cursor<&[u8]>) -> Result<Vec<String>, IoError> {
+    let count = reader.read_u8()?;
+    (0..count).map(|_| read_tag(reader)).collect()
+}
+
+fn read_rest(reader: &mut Cursor<&[u8]>) -> Vec<u8> {
+    let data = *reader.get_ref();
+    data[reader.position() as usize..].to_owned()
+}
+
+fn check_version(reader: &mut Cursor<&[u8]>) -> Result<(), IoError> {
+    let version = reader.read_u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!("Unsupported protocol version {}", version)).into());
+    }
+    Ok(())
+}
+
+impl RequestMessage {
+    /// Check the pool name and the request against the limits.
+    pub fn check(&self) -> Result<(), IoError> {
+        self.pool.check()?;
+        self.request.check()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.write_u8(PROTOCOL_VERSION).unwrap();
+        result.write_u32::<BigEndian>(self.counter).unwrap();
+        result.write_u32::<BigEndian>(self.epoch).unwrap();
+        write_trace_id(&mut result, self.trace_id);
+        result.write_u16::<BigEndian>(self.capability.len() as u16).unwrap();
+        result.extend_from_slice(&self.capability);
+        result.write_u32::<BigEndian>(self.pool.0.len() as u32).unwrap();
+        result.extend_from_slice(self.pool.0.as_bytes());
+        result.write_u8(self.request.opcode()).unwrap();
+        match self.request {
+            Request::ReadObject { ref object_id, if_changed } => {
+                write_object_id(&mut result, object_id);
+                write_option_u64(&mut result, if_changed);
+            }
+            Request::DeleteObject { ref object_id } => {
+                write_object_id(&mut result, object_id);
+            }
+            Request::ReadPart { ref object_id, offset, len } => {
+                write_object_id(&mut result, object_id);
+                result.write_u32::<BigEndian>(offset).unwrap();
+                result.write_u32::<BigEndian>(len).unwrap();
+            }
+            Request::WriteObject { ref object_id, ref data } => {
+                write_object_id(&mut result, object_id);
+                result.extend_from_slice(data);
+            }
+            Request::WritePart { ref object_id, offset, ref data } => {
+                write_object_id(&mut result, object_id);
+                result.write_u32::<BigEndian>(offset).unwrap();
+                result.extend_from_slice(data);
+            }
+            Request::StatObject { ref object_id } => {
+                write_object_id(&mut result, object_id);
+            }
+            Request::ListObjects { ref prefix, ref start_after, limit, ref tag } => {
+                result.write_u32::<BigEndian>(prefix.len() as u32).unwrap();
+                result.extend_from_slice(prefix);
+                match start_after {
+                    Some(object_id) => {
+                        result.write_u8(1).unwrap();
+                        write_object_id(&mut result, object_id);
+                    }
+                    None => result.write_u8(0).unwrap(),
+                }
+                result.write_u32::<BigEndian>(limit).unwrap();
+                match tag {
+                    Some(tag) => {
+                        result.write_u8(1).unwrap();
+                        write_tag(&mut result, tag);
+                    }
+                    None => result.write_u8(0).unwrap(),
+                }
+            }
+            Request::AppendObject { ref object_id, ref data } => {
+                write_object_id(&mut result, object_id);
+                result.extend_from_slice(data);
+            }
+            Request::TruncateObject { ref object_id, len } => {
+                write_object_id(&mut result, object_id);
+                result.write_u32::<BigEndian>(len).unwrap();
+            }
+            Request::CompareAndSwap { ref object_id, ref expected, ref data } => {
+                write_object_id(&mut result, object_id);
+                match expected {
+                    Some(expected) => {
+                        result.write_u8(1).unwrap();
+                        result.write_u32::<BigEndian>(expected.len() as u32).unwrap();
+                        result.extend_from_slice(expected);
+                    }
+                    None => result.write_u8(0).unwrap(),
+                }
+                result.extend_from_slice(data);
+            }
+            Request::Watch { ref prefix, after } => {
+                result.write_u32::<BigEndian>(prefix.len() as u32).unwrap();
+                result.extend_from_slice(prefix);
+                write_option_u64(&mut result, after);
+            }
+            Request::WriteIfVersion { ref object_id, version, ref data } => {
+                write_object_id(&mut result, object_id);
+                write_option_u64(&mut result, version);
+                result.extend_from_slice(data);
+            }
+            Request::SetTags { ref object_id, ref tags } => {
+                write_object_id(&mut result, object_id);
+                write_tags(&mut result, tags);
+            }
+            Request::Handshake { ref hello } => result.extend_from_slice(hello),
+            Request::Flush | Request::Stats => {}
+        }
+        result
+    }
+
+    pub fn decode(data: &[u8]) -> Result<RequestMessage, IoError> {
+        let mut reader = Cursor::new(data);
+        check_version(&mut reader)?;
+        let counter = reader.read_u32::<BigEndian>()?;
+        let epoch = reader.read_u32::<BigEndian>()?;
+        let trace_id = read_trace_id(&mut reader)?;
+        let capability_len = reader.read_u16::<BigEndian>()? as usize;
+        let capability = read_bytes(&mut reader, capability_len)?;
+        let pool_len = reader.read_u32::<BigEndian>()? as usize;
+        let pool = String::from_utf8(read_bytes(&mut reader, pool_len)?)
+            .map_err(|_| invalid("Invalid pool name"))?;
+        let opcode = reader.read_u8()?;
+        let request = match opcode {
+            OPCODE_READ_OBJECT => Request::ReadObject {
+                object_id: read_object_id(&mut reader)?,
+                if_changed: read_option_u64(&mut reader, "Invalid read request")?,
+            },
+            OPCODE_READ_PART => Request::ReadPart {
+                object_id: read_object_id(&mut reader)?,
+                offset: reader.read_u32::<BigEndian>()?,
+                len: reader.read_u32::<BigEndian>()?,
+            },
+            OPCODE_WRITE_OBJECT => Request::WriteObject {
+                object_id: read_object_id(&mut reader)?,
+                data: read_rest(&mut reader),
+            },
+            OPCODE_WRITE_PART => Request::WritePart {
+                object_id: read_object_id(&mut reader)?,
+                offset: reader.read_u32::<BigEndian>()?,
+                data: read_rest(&mut reader),
+            },
+            OPCODE_DELETE_OBJECT => Request::DeleteObject {
+                object_id: read_object_id(&mut reader)?,
+            },
+            OPCODE_STAT_OBJECT => Request::StatObject {
+                object_id: read_object_id(&mut reader)?,
+            },
+            OPCODE_LIST_OBJECTS => Request::ListObjects {
+                prefix: read_data(&mut reader)?,
+                start_after: match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(read_object_id(&mut reader)?),
+                    _ => return Err(invalid("Invalid list request")),
+                },
+                limit: reader.read_u32::<BigEndian>()?,
+                tag: match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(read_tag(&mut reader)?),
+                    _ => return Err(invalid("Invalid list request")),
+                },
+            },
+            OPCODE_APPEND_OBJECT => Request::AppendObject {
+                object_id: read_object_id(&mut reader)?,
+                data: read_rest(&mut reader),
+            },
+            OPCODE_TRUNCATE_OBJECT => Request::TruncateObject {
+                object_id: read_object_id(&mut reader)?,
+                len: reader.read_u32::<BigEndian>()?,
+            },
+            OPCODE_COMPARE_AND_SWAP => Request::CompareAndSwap {
+                object_id: read_object_id(&mut reader)?,
+                expected: match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(read_data(&mut reader)?),
+                    _ => return Err(invalid("Invalid compare-and-swap request")),
+                },
+                data: read_rest(&mut reader),
+            },
+            OPCODE_FLUSH => Request::Flush,
+            OPCODE_STATS => Request::Stats,
+            OPCODE_WATCH => Request::Watch {
+                prefix: read_data(&mut reader)?,
+                after: read_option_u64(&mut reader, "Invalid watch request")?,
+            },
+            OPCODE_WRITE_IF_VERSION => Request::WriteIfVersion {
+                object_id: read_object_id(&mut reader)?,
+                version: read_option_u64(&mut reader, "Invalid conditional write request")?,
+                data: read_rest(&mut reader),
+            },
+            OPCODE_SET_TAGS => Request::SetTags {
+                object_id: read_object_id(&mut reader)?,
+                tags: read_tags(&mut reader)?,
+            },
+            OPCODE_HANDSHAKE => Request::Handshake {
+                hello: read_rest(&mut reader),
+            },
+            // Recognizable, so the daemon can tell the client
+            _ => return Err(IoError::new(ErrorKind::InvalidInput, ErrorCode::Unsupported)),
+        };
+        let reads_to_end = matches!(
+            request,
+            Request::WriteObject { .. }
+                | Request::WritePart { .. }
+                | Request::AppendObject { .. }
+                | Request::CompareAndSwap { .. }
+                | Request::WriteIfVersion { .. }
+                | Request::Handshake { .. },
+        );
+        if !reads_to_end && reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after request"));
+        }
+        Ok(RequestMessage { counter, epoch, trace_id, capability, pool: PoolName(pool), request })
+    }
+}
+
+impl ResponseMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.encode_into(&mut result);
+        result
+    }
+
+    /// Encode the response at the end of `result`.
+    pub fn encode_into(&self, result: &mut Vec<u8>) {
+        result.write_u8(PROTOCOL_VERSION).unwrap();
+        result.write_u32::<BigEndian>(self.counter).unwrap();
+        write_trace_id(result, self.trace_id);
+        match self.response {
+            Response::Done => result.write_u8(STATUS_DONE).unwrap(),
+            Response::Written { version } => {
+                result.write_u8(STATUS_WRITTEN).unwrap();
+                result.write_u64::<BigEndian>(version).unwrap();
+            }
+            Response::Data(ref data) => {
+                result.write_u8(STATUS_DATA).unwrap();
+                result.extend_from_slice(data);
+            }
+            Response::Object { version, ref data } => {
+                result.write_u8(STATUS_OBJECT).unwrap();
+                result.write_u64::<BigEndian>(version).unwrap();
+                result.extend_from_slice(data);
+            }
+            Response::Unchanged => result.write_u8(STATUS_UNCHANGED).unwrap(),
+            Response::Error(code) => {
+                result.write_u8(STATUS_ERROR).unwrap();
+                result.write_u8(code.to_u8()).unwrap();
+            }
+            Response::Stat { size, expires, version, ref tags } => {
+                result.write_u8(STATUS_STAT).unwrap();
+                result.write_u64::<BigEndian>(size).unwrap();
+                let expires = expires
+                    .map(|e| e.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).max(1))
+                    .unwrap_or(0);
+                result.write_u64::<BigEndian>(expires).unwrap();
+                result.write_u64::<BigEndian>(version).unwrap();
+                write_tags(result, tags);
+            }
+            Response::List(ref object_ids) => {
+                result.write_u8(STATUS_LIST).unwrap();
+                result.write_u32::<BigEndian>(object_ids.len() as u32).unwrap();
+                for object_id in object_ids {
+                    write_object_id(result, object_id);
+                }
+            }
+            Response::Stats { ref device_id, free_space, ref pools } => {
+                result.write_u8(STATUS_STATS).unwrap();
+                result.extend_from_slice(&device_id.0);
+                match free_space {
+                    Some(free_space) => {
+                        result.write_u8(1).unwrap();
+                        result.write_u64::<BigEndian>(free_space).unwrap();
+                    }
+                    None => result.write_u8(0).unwrap(),
+                }
+                result.write_u32::<BigEndian>(pools.len() as u32).unwrap();
+                for usage in pools {
+                    result.write_u32::<BigEndian>(usage.pool.0.len() as u32).unwrap();
+                    result.extend_from_slice(usage.pool.0.as_bytes());
+                    result.write_u32::<BigEndian>(usage.replicas).unwrap();
+                    result.write_u64::<BigEndian>(usage.objects).unwrap();
+                    result.write_u64::<BigEndian>(usage.bytes).unwrap();
+                }
+            }
+            Response::Events { next, lost, ref events } => {
+                result.write_u8(STATUS_EVENTS).unwrap();
+                result.write_u64::<BigEndian>(next).unwrap();
+                result.write_u8(lost as u8).unwrap();
+                result.write_u32::<BigEndian>(events.len() as u32).unwrap();
+                for event in events {
+                    result.write_u8(event.kind.to_u8()).unwrap();
+                    write_object_id(result, &event.object_id);
+                }
+            }
+            Response::Session(ref answer) => {
+                result.write_u8(STATUS_SESSION).unwrap();
+                result.extend_from_slice(answer);
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ResponseMessage, IoError> {
+        let mut reader = Cursor::new(data);
+        check_version(&mut reader)?;
+        let counter = reader.read_u32::<BigEndian>()?;
+        let trace_id = read_trace_id(&mut reader)?;
+        let status = reader.read_u8()?;
+        let response = match status {
+            STATUS_DONE => Response::Done,
+            STATUS_WRITTEN => Response::Written { version: reader.read_u64::<BigEndian>()? },
+            STATUS_DATA => Response::Data(read_rest(&mut reader)),
+            STATUS_OBJECT => Response::Object {
+                version: reader.read_u64::<BigEndian>()?,
+                data: read_rest(&mut reader),
+            },
+            STATUS_UNCHANGED => Response::Unchanged,
+            STATUS_ERROR => Response::Error(ErrorCode::from_u8(reader.read_u8()?)),
+            STATUS_STAT => Response::Stat {
+                size: reader.read_u64::<BigEndian>()?,
+                expires: match reader.read_u64::<BigEndian>()? {
+                    0 => None,
+                    secs => Some(
+                        UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+                            .ok_or_else(|| invalid("Invalid expiry time"))?,
+                    ),
+                },
+                version: reader.read_u64::<BigEndian>()?,
+                tags: read_tags(&mut reader)?,
+            },
+            STATUS_LIST => {
+                let count = reader.read_u32::<BigEndian>()?;
+                let mut object_ids = Vec::new();
+                for _ in 0..count {
+                    object_ids.push(read_object_id(&mut reader)?);
+                }
+                Response::List(object_ids)
+            }
+            STATUS_STATS => {
+                let mut device_id = [0; 16];
+                reader.read_exact(&mut device_id)?;
+                let free_space = match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(reader.read_u64::<BigEndian>()?),
+                    _ => return Err(invalid("Invalid stats response")),
+                };
+                let count = reader.read_u32::<BigEndian>()?;
+                let mut pools = Vec::new();
+                for _ in 0..count {
+                    let pool = String::from_utf8(read_data(&mut reader)?)
+                        .map_err(|_| invalid("Invalid pool name"))?;
+                    pools.push(PoolUsage {
+                        pool: PoolName(pool),
+                        replicas: reader.read_u32::<BigEndian>()?,
+                        objects: reader.read_u64::<BigEndian>()?,
+                        bytes: reader.read_u64::<BigEndian>()?,
+                    });
+                }
+                Response::Stats { device_id: DeviceId(device_id), free_space, pools }
+            }
+            STATUS_EVENTS => {
+                let next = reader.read_u64::<BigEndian>()?;
+                let lost = match reader.read_u8()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(invalid("Invalid events response")),
+                };
+                let count = reader.read_u32::<BigEndian>()?;
+                let mut events = Vec::new();
+                for _ in 0..count {
+                    events.push(Event {
+                        kind: EventKind::from_u8(reader.read_u8()?)?,
+                        object_id: read_object_id(&mut reader)?,
+                    });
+                }
+                Response::Events { next, lost, events }
+            }
+            STATUS_SESSION => Response::Session(read_rest(&mut reader)),
+            _ => return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("Unknown response status 0x{:02x}", status),
+            )),
+        };
+        let reads_to_end = matches!(status, STATUS_DATA | STATUS_OBJECT | STATUS_SESSION);
+        if !reads_to_end && reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after response"));
+        }
+        Ok(ResponseMessage { counter, trace_id, response })
+    }
+}
+
+/// A part of a response that was too big for one frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseChunk {
+    pub counter: u32,
+    pub trace_id: Option<TraceId>,
+    pub seq: u16,
+    pub more: bool,
+    pub data: Vec<u8>,
+}
+
+/// What a single datagram holds: a whole response or a chunk of one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResponseFrame {
+    Whole(ResponseMessage),
+    Chunk(ResponseChunk),
+}
+
+impl ResponseMessage {
+    /// Encode the response, split into frames of at most `max_size` bytes.
+    pub fn encode_frames(&self, max_size: usize) -> Vec<Vec<u8>> {
+        let encoded = self.encode();
+        if encoded.len() <= max_size {
+            return vec![encoded];
+        }
+        (0..frame_count(&encoded, max_size)).map(|seq| {
+            let mut frame = Vec::new();
+            write_frame(&encoded, max_size, seq, &mut frame);
+            frame
+        }).collect()
+    }
+}
+
+/// Number of frames of at most `max_size` bytes an encoded response is
+/// split into.
+pub fn frame_count(encoded: &[u8], max_size: usize) -> usize {
+    if encoded.len() <= max_size {
+        1
+    } else {
+        (encoded.len() - RESPONSE_HEADER_SIZE).div_ceil(max_size - CHUNK_HEADER_SIZE)
+    }
+}
+
+/// Write frame `seq` of an encoded response at the end of `frame`, for
+/// sending it without `encode_frames()` allocating.
+pub fn write_frame(encoded: &[u8], max_size: usize, seq: usize, frame: &mut Vec<u8>) {
+    if encoded.len() <= max_size {
+        frame.extend_from_slice(encoded);
+        return;
+    }
+    let count = frame_count(encoded, max_size);
+    let chunk_size = max_size - CHUNK_HEADER_SIZE;
+    let body = &encoded[RESPONSE_HEADER_SIZE..];
+    let data = &body[seq * chunk_size..body.len().min((seq + 1) * chunk_size)];
+    frame.extend_from_slice(&encoded[0..RESPONSE_HEADER_SIZE]);
+    frame.write_u8(STATUS_CHUNK).unwrap();
+    frame.write_u16::<BigEndian>(seq as u16).unwrap();
+    frame.write_u8(if seq + 1 < count { 1 } else { 0 }).unwrap();
+    frame.extend_from_slice(data);
+}
+
+impl ResponseFrame {
+    pub fn decode(data: &[u8]) -> Result<ResponseFrame, IoError> {
+        if data.len() <= RESPONSE_HEADER_SIZE || data[RESPONSE_HEADER_SIZE] != STATUS_CHUNK {
+            return Ok(ResponseFrame::Whole(ResponseMessage::decode(data)?));
+        }
+        let mut reader = Cursor::new(data);
+        check_version(&mut reader)?;
+        let counter = reader.read_u32::<BigEndian>()?;
+        let trace_id = read_trace_id(&mut reader)?;
+        reader.read_u8()?;
+        let seq = reader.read_u16::<BigEndian>()?;
+        let more = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid("Invalid chunk flags")),
+        };
+        Ok(ResponseFrame::Chunk(ResponseChunk { counter, trace_id, seq, more, data: read_rest(&mut reader) }))
+    }
+}
+
+/// Puts a response sent in chunks back together.
+///
+/// Chunks can arrive in any order. Duplicates are ignored.
+pub struct ChunkAssembler {
+    chunks: BTreeMap<u16, Vec<u8>>,
+    /// Sequence number of the last chunk, once it arrived.
+    last: Option<u16>,
+    size: usize,
+    max_size: usize,
+}
+
+impl ChunkAssembler {
+    /// Create an assembler, refusing responses bigger than `max_size`.
+    pub fn new(max_size: usize) -> ChunkAssembler {
+        ChunkAssembler { chunks: BTreeMap::new(), last: None, size: 0, max_size }
+    }
+
+    /// Add a chunk, returning the response once all chunks have arrived.
+    pub fn add(&mut self, chunk: ResponseChunk) -> Result<Option<ResponseMessage>, IoError> {
+        if !chunk.more {
+            if self.last.is_some_and(|l| l != chunk.seq) {
+                return Err(invalid("Chunks end twice"));
+            }
+            if self.chunks.keys().next_back().is_some_and(|&s| s > chunk.seq) {
+                return Err(invalid("Chunk after the end"));
+            }
+            self.last = Some(chunk.seq);
+        }
+        if self.last.is_some_and(|l| chunk.seq > l || (chunk.seq == l && chunk.more)) {
+            return Err(invalid("Chunk after the end"));
+        }
+        if self.chunks.contains_key(&chunk.seq) {
+            return Ok(None);
+        }
+        self.size += chunk.data.len();
+        if self.size > self.max_size {
+            return Err(invalid("Chunked response is too big"));
+        }
+        self.chunks.insert(chunk.seq, chunk.data);
+
+        match self.last {
+            Some(last) if self.chunks.len() == last as usize + 1 => {
+                let mut encoded = Vec::with_capacity(RESPONSE_HEADER_SIZE + self.size);
+                encoded.write_u8(PROTOCOL_VERSION).unwrap();
+                encoded.write_u32::<BigEndian>(chunk.counter).unwrap();
+                write_trace_id(&mut encoded, chunk.trace_id);
+                for data in std::mem::take(&mut self.chunks).into_values() {
+                    encoded.extend_from_slice(&data);
+                }
+                Ok(Some(ResponseMessage::decode(&encoded)?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Append the CRC32C of a frame to it, before sending it as a datagram.
+pub fn add_checksum(frame: &mut Vec<u8>) {
+    let checksum = crc32c::crc32c(frame);
+    frame.write_u32::<BigEndian>(checksum).unwrap();
+}
+
+/// Check and remove the CRC32C at the end of a datagram.
+///
+/// Returns `None` if the datagram is corrupted.
+pub fn check_checksum(datagram: &[u8]) -> Option<&[u8]> {
+    if datagram.len() < CHECKSUM_SIZE {
+        return None;
+    }
+    let (frame, checksum) = datagram.split_at(datagram.len() - CHECKSUM_SIZE);
+    let checksum = Cursor::new(checksum).read_u32::<BigEndian>().unwrap();
+    if crc32c::crc32c(frame) == checksum {
+        Some(frame)
+    } else {
+        None
+    }
+}
+
+/// Read the counter of a response without decoding it.
+pub fn response_counter(data: &[u8]) -> Option<u32> {
+    if data.len() < 5 || data[0] != PROTOCOL_VERSION {
+        return None;
+    }
+    Some(Cursor::new(&data[1..5]).read_u32::<BigEndian>().unwrap())
+}
+
+/// Read the counter of a request without decoding it.
+///
+/// Requests start the same way as responses.
+pub fn request_counter(data: &[u8]) -> Option<u32> {
+    response_counter(data)
+}
+
+/// Read the trace ID of a request without decoding it.
+pub fn request_trace_id(data: &[u8]) -> Option<TraceId> {
+    if data.len() < 17 || data[0] != PROTOCOL_VERSION {
+        return None;
+    }
+    read_trace_id(&mut Cursor::new(&data[9..17])).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeviceId, MAX_OBJECT_ID_LEN, ObjectId, PoolName};
+    use std::io::{Error as IoError, ErrorKind};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        ChunkAssembler, ErrorCode, Event, EventKind, MAX_FRAME_SIZE, PoolUsage, Request, RequestMessage, Response,
+        ResponseFrame, ResponseMessage, TraceId, add_checksum, check_checksum, frame_count, request_counter,
+        request_trace_id, response_counter, write_frame,
+    };
+
+    fn all_requests() -> Vec<Request> {
+        let object_id = ObjectId(b"obj".to_vec());
+        vec![
+            Request::ReadObject { object_id: object_id.clone(), if_changed: None },
+            Request::ReadObject { object_id: object_id.clone(), if_changed: Some(1 << 50) },
+            Request::ReadPart { object_id: object_id.clone(), offset: 10, len: 20 },
+            Request::WriteObject { object_id: object_id.clone(), data: b"data".to_vec() },
+            Request::WriteObject { object_id: object_id.clone(), data: vec![] },
+            Request::WritePart { object_id: object_id.clone(), offset: 5, data: b"part".to_vec() },
+            Request::DeleteObject { object_id: ObjectId(vec![]) },
+            Request::StatObject { object_id: object_id.clone() },
+            Request::ListObjects { prefix: b"ob".to_vec(), start_after: None, limit: 100, tag: None },
+            Request::ListObjects { prefix: vec![], start_after: Some(object_id.clone()), limit: 1, tag: Some("tmp".to_owned()) },
+            Request::AppendObject { object_id: object_id.clone(), data: b"more".to_vec() },
+            Request::TruncateObject { object_id: object_id.clone(), len: 3 },
+            Request::CompareAndSwap { object_id: object_id.clone(), expected: None, data: b"new".to_vec() },
+            Request::CompareAndSwap { object_id: object_id.clone(), expected: Some(b"old".to_vec()), data: b"new".to_vec() },
+            Request::WriteIfVersion { object_id: object_id.clone(), version: None, data: b"new".to_vec() },
+            Request::WriteIfVersion { object_id: object_id.clone(), version: Some(12), data: vec![] },
+            Request::SetTags { object_id: object_id.clone(), tags: vec![] },
+            Request::SetTags { object_id, tags: vec!["tmp".to_owned(), "ünïcode".to_owned()] },
+            Request::Flush,
+            Request::Stats,
+            Request::Watch { prefix: b"ob".to_vec(), after: None },
+            Request::Watch { prefix: vec![], after: Some(1 << 40) },
+            Request::Handshake { hello: b"hello".to_vec() },
+        ]
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        for (i, request) in all_requests().into_iter().enumerate() {
+            let message = RequestMessage {
+                counter: 42 + i as u32,
+                epoch: 1000,
+                trace_id: if i % 3 == 0 { None } else { Some(TraceId(0x1234 + i as u64)) },
+                capability: if i % 2 == 0 { vec![] } else { b"cap".to_vec() },
+                pool: PoolName("pool".to_owned()),
+                request,
+            };
+            let encoded = message.encode();
+            assert_eq!(RequestMessage::decode(&encoded).unwrap(), message);
+
+            // Any truncation is detected, except for the trailing data
+            for len in 0..encoded.len() {
+                let truncated = RequestMessage::decode(&encoded[0..len]);
+                match message.request {
+                    Request::WriteObject { ref data, .. }
+                    | Request::WritePart { ref data, .. }
+                    | Request::AppendObject { ref data, .. }
+                    | Request::CompareAndSwap { ref data, .. }
+                    | Request::WriteIfVersion { ref data, .. }
+                    | Request::Handshake { hello: ref data }
+                        if len >= encoded.len() - data.len() => {}
+                    _ => assert!(truncated.is_err(), "{:?} {}", message.request, len),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_layout() {
+        let message = RequestMessage {
+            counter: 1,
+            epoch: 2,
+            trace_id: Some(TraceId(5)),
+            capability: b"c".to_vec(),
+            pool: PoolName("p".to_owned()),
+            request: Request::ReadPart { object_id: ObjectId(b"o".to_vec()), offset: 3, len: 4 },
+        };
+        assert_eq!(
+            message.encode(),
+            b"\x04\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x05\
+              \x00\x01c\x00\x00\x00\x01p\x02\x00\x00\x00\x01o\x00\x00\x00\x03\x00\x00\x00\x04",
+        );
+
+        // Wrong version, unknown opcode, extra data
+        let mut encoded = message.encode();
+        encoded[0] = 1;
+        assert!(RequestMessage::decode(&encoded).is_err());
+        let mut encoded = message.encode();
+        encoded[25] = 0x7f;
+        let err = RequestMessage::decode(&encoded).unwrap_err();
+        assert_eq!(ErrorCode::get(&err), Some(ErrorCode::Unsupported));
+        assert_eq!(request_counter(&encoded), Some(1));
+        assert_eq!(request_trace_id(&encoded), Some(TraceId(5)));
+        let mut encoded = message.encode();
+        encoded.push(0);
+        assert!(RequestMessage::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_request_check() {
+        let long = vec![b'a'; MAX_OBJECT_ID_LEN + 1];
+        let message = |pool: &str, request| RequestMessage {
+            counter: 1,
+            epoch: 1,
+            trace_id: None,
+            capability: vec![],
+            pool: PoolName(pool.to_owned()),
+            request,
+        };
+        assert!(message("pool", Request::ReadObject { object_id: ObjectId(b"obj".to_vec()), if_changed: None }).check().is_ok());
+        assert!(message("pool", Request::ReadObject { object_id: ObjectId(vec![]), if_changed: None }).check().is_err());
+        assert!(message("a/b", Request::ReadObject { object_id: ObjectId(b"obj".to_vec()), if_changed: None }).check().is_err());
+        assert!(message("pool", Request::WriteObject { object_id: ObjectId(long.clone()), data: vec![] }).check().is_err());
+        assert!(message("pool", Request::ListObjects { prefix: vec![], start_after: None, limit: 1, tag: None }).check().is_ok());
+        assert!(message("pool", Request::ListObjects { prefix: long.clone(), start_after: None, limit: 1, tag: None }).check().is_err());
+        assert!(message("pool", Request::ListObjects { prefix: vec![], start_after: Some(ObjectId(long.clone())), limit: 1, tag: None }).check().is_err());
+        assert!(message("pool", Request::ListObjects { prefix: vec![], start_after: None, limit: 1, tag: Some("a b".to_owned()) }).check().is_err());
+        let tags = vec!["tmp".to_owned(); 2];
+        assert!(message("pool", Request::SetTags { object_id: ObjectId(b"obj".to_vec()), tags }).check().is_err());
+        assert!(message("pool", Request::Watch { prefix: long, after: None }).check().is_err());
+        assert!(message("pool", Request::Stats).check().is_ok());
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let responses = [
+            Response::Done,
+            Response::Written { version: 1 << 60 },
+            Response::Data(b"data".to_vec()),
+            Response::Data(vec![]),
+            Response::Object { version: 3, data: b"data".to_vec() },
+            Response::Object { version: 0, data: vec![] },
+            Response::Unchanged,
+            Response::Error(ErrorCode::NotFound),
+            Response::Error(ErrorCode::WrongDaemon),
+            Response::Error(ErrorCode::QuotaExceeded),
+            Response::Error(ErrorCode::Unauthorized),
+            Response::Error(ErrorCode::Corrupt),
+            Response::Error(ErrorCode::InvalidRequest),
+            Response::Error(ErrorCode::Internal),
+            Response::Error(ErrorCode::Unsupported),
+            Response::Error(ErrorCode::Conflict),
+            Response::Error(ErrorCode::NoSession),
+            Response::Stat { size: 12, expires: None, version: 5, tags: vec![] },
+            Response::Stat {
+                size: 0,
+                expires: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                version: 0,
+                tags: vec!["tmp".to_owned(), "x".repeat(64)],
+            },
+            Response::List(vec![]),
+            Response::List(vec![ObjectId(b"a".to_vec()), ObjectId(vec![])]),
+            Response::Stats { device_id: DeviceId([3; 16]), free_space: None, pools: vec![] },
+            Response::Stats {
+                device_id: DeviceId([4; 16]),
+                free_space: Some(1 << 40),
+                pools: vec![
+                    PoolUsage { pool: PoolName("default".to_owned()), replicas: 3, objects: 12, bytes: 4096 },
+                    PoolUsage { pool: PoolName(String::new()), replicas: 1, objects: 0, bytes: 0 },
+                ],
+            },
+            Response::Events { next: 1 << 32, lost: true, events: vec![] },
+            Response::Session(b"answer".to_vec()),
+            Response::Events {
+                next: 12,
+                lost: false,
+                events: vec![
+                    Event { kind: EventKind::Created, object_id: ObjectId(b"a".to_vec()) },
+                    Event { kind: EventKind::Deleted, object_id: ObjectId(vec![]) },
+                ],
+            },
+        ];
+        for response in responses {
+            let message = ResponseMessage { counter: 7, trace_id: Some(TraceId(9)), response };
+            let encoded = message.encode();
+            assert_eq!(ResponseMessage::decode(&encoded).unwrap(), message);
+            assert_eq!(response_counter(&encoded), Some(7));
+            assert!(ResponseMessage::decode(&encoded[0..5]).is_err());
+            if let Response::Written { .. } | Response::Stat { .. } | Response::List(_) | Response::Stats { .. } | Response::Events { .. } = message.response {
+                assert!(ResponseMessage::decode(&encoded[0..encoded.len() - 1]).is_err());
+            }
+        }
+        assert_eq!(
+            ResponseMessage { counter: 1, trace_id: None, response: Response::Data(b"x".to_vec()) }.encode(),
+            b"\x04\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x01x",
+        );
+        assert!(ResponseMessage::decode(b"\x04\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x07").is_err());
+        assert!(ResponseMessage::decode(b"\x04\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00extra").is_err());
+        assert_eq!(
+            ResponseMessage::decode(b"\x04\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x02\x05").unwrap().response,
+            Response::Error(ErrorCode::Corrupt),
+        );
+        assert_eq!(
+            ResponseMessage::decode(b"\x04\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x02\xf0").unwrap().response,
+            Response::Error(ErrorCode::Internal),
+        );
+        assert_eq!(response_counter(b"\x02\x00\x00\x00\x01\x00"), None);
+
+        // An expiry time too far to represent
+        let response = Response::Stat { size: 0, expires: None, version: 1, tags: vec![] };
+        let mut encoded = ResponseMessage { counter: 1, trace_id: None, response }.encode();
+        let len = encoded.len();
+        encoded[len - 17..len - 9].copy_from_slice(&[0xff; 8]);
+        assert!(ResponseMessage::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_error_code() {
+        let err: IoError = ErrorCode::QuotaExceeded.into();
+        assert_eq!(ErrorCode::get(&err), Some(ErrorCode::QuotaExceeded));
+        assert_eq!(ErrorCode::for_error(&err), ErrorCode::QuotaExceeded);
+        assert_eq!(err.to_string(), "Quota exceeded");
+
+        let err = IoError::new(ErrorKind::PermissionDenied, "Missing capability");
+        assert_eq!(ErrorCode::get(&err), None);
+        assert_eq!(ErrorCode::for_error(&err), ErrorCode::Unauthorized);
+        let err = IoError::new(ErrorKind::BrokenPipe, "disk went away");
+        assert_eq!(ErrorCode::for_error(&err), ErrorCode::Internal);
+    }
+
+    #[test]
+    fn test_chunks() {
+        // Small responses are sent whole
+        let small = ResponseMessage { counter: 3, trace_id: None, response: Response::Data(vec![1; 100]) };
+        let frames = small.encode_frames(MAX_FRAME_SIZE);
+        assert_eq!(frames, vec![small.encode()]);
+        assert_eq!(ResponseFrame::decode(&frames[0]).unwrap(), ResponseFrame::Whole(small));
+
+        let data: Vec<u8> = (0..150000u32).map(|i| i as u8).collect();
+        let large = ResponseMessage { counter: 4, trace_id: Some(TraceId::generate()), response: Response::Data(data) };
+        let frames = large.encode_frames(MAX_FRAME_SIZE);
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.len() <= MAX_FRAME_SIZE));
+
+        // The same frames can be written to a reused buffer
+        let encoded = large.encode();
+        assert_eq!(frame_count(&encoded, MAX_FRAME_SIZE), 3);
+        let mut frame = Vec::new();
+        for (seq, expected) in frames.iter().enumerate() {
+            frame.clear();
+            write_frame(&encoded, MAX_FRAME_SIZE, seq, &mut frame);
+            assert_eq!(&frame, expected);
+        }
+        let chunks: Vec<_> = frames.iter().map(|f| match ResponseFrame::decode(f).unwrap() {
+            ResponseFrame::Chunk(c) => c,
+            ResponseFrame::Whole(_) => panic!("Not a chunk"),
+        }).collect();
+        assert_eq!(chunks.iter().map(|c| (c.counter, c.seq, c.more)).collect::<Vec<_>>(), [(4, 0, true), (4, 1, true), (4, 2, false)]);
+
+        // Reassembled out of order, with duplicates
+        let mut assembler = ChunkAssembler::new(1 << 20);
+        assert_eq!(assembler.add(chunks[2].clone()).unwrap(), None);
+        assert_eq!(assembler.add(chunks[0].clone()).unwrap(), None);
+        assert_eq!(assembler.add(chunks[0].clone()).unwrap(), None);
+        assert_eq!(assembler.add(chunks[1].clone()).unwrap(), Some(large.clone()));
+
+        // Too big
+        let mut assembler = ChunkAssembler::new(100000);
+        assert_eq!(assembler.add(chunks[0].clone()).unwrap(), None);
+        assert!(assembler.add(chunks[1].clone()).is_err());
+
+        // Chunks past the end
+        let mut assembler = ChunkAssembler::new(1 << 20);
+        assert_eq!(assembler.add(chunks[1].clone()).unwrap(), None);
+        let mut end = chunks[0].clone();
+        end.more = false;
+        assert!(assembler.add(end).is_err());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut datagram = b"some frame".to_vec();
+        add_checksum(&mut datagram);
+        assert_eq!(datagram.len(), 14);
+        assert_eq!(check_checksum(&datagram), Some(&b"some frame"[..]));
+
+        // Flipping any bit is detected
+        for i in 0..datagram.len() * 8 {
+            let mut corrupted = datagram.clone();
+            corrupted[i / 8] ^= 1 << (i % 8);
+            assert_eq!(check_checksum(&corrupted), None);
+        }
+        assert_eq!(check_checksum(&datagram[1..]), None);
+        assert_eq!(check_checksum(b"abc"), None);
+
+        // Known value of CRC32C
+        let mut empty = vec![];
+        add_checksum(&mut empty);
+        assert_eq!(empty, b"\x00\x00\x00\x00");
+        let mut digits = b"123456789".to_vec();
+        add_checksum(&mut digits);
+        assert_eq!(&digits[9..], b"\xe3\x06\x92\x83");
+    }
+}
@@ -0,0 +1,363 @@
+//! An HTTP API to provision block images as volumes, for a Kubernetes CSI
+//! driver (or other orchestrators).
+//!
+//! Volumes are images of a single pool, named after the volume. The calls
+//! are idempotent as CSI requires: creating a volume that exists with enough
+//! capacity returns it, deleting a missing volume succeeds, expanding to a
+//! smaller size does nothing. Volumes that are attached (their image is
+//! locked by an NBD gateway) can't be expanded or deleted.
+//!
+//! * `PUT /volumes/<name>` with `{"capacity_bytes": N}` creates a volume
+//! * `GET /volumes/<name>` describes it
+//! * `POST /volumes/<name>/expand` with `{"capacity_bytes": N}` grows it
+//! * `DELETE /volumes/<name>` deletes it
+//!
+//! The description includes the `nbdkit` arguments the node should run the
+//! NBD gateway with to attach the volume.
+//!
+//! Clients authenticate with a bearer token, like for the HTTP gateway.
+
+use hyper::body::HttpBody;
+use hyper::header::{ALLOW, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::client::{Client, REQUEST_TIMEOUT};
+use crate::http_gateway::Tokens;
+use crate::image::{Geometry, create_image, delete_image, image_lock_holder, read_image, resize_image};
+use crate::metrics::{register_counter_vec, register_latency};
+
+/// Longest volume name.
+const MAX_NAME_LENGTH: usize = 128;
+
+/// Largest request body.
+const MAX_BODY_SIZE: usize = 4096;
+
+struct Metrics {
+    requests: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+    latency: prometheus::HistogramVec,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        requests: register_counter_vec("provisioner", "requests", "Total requests", &["op"]),
+        errors: register_counter_vec("provisioner", "errors", "Total requests that failed", &["op"]),
+        latency: register_latency("provisioner", "duration_seconds", "Time to handle requests", &["op"]),
+    };
+}
+
+/// An error to send back, with its status.
+#[derive(Debug)]
+struct HttpError {
+    status: StatusCode,
+    message: String,
+}
+
+impl HttpError {
+    fn new<S: Into<String>>(status: StatusCode, message: S) -> HttpError {
+        HttpError { status, message: message.into() }
+    }
+}
+
+impl From<IoError> for HttpError {
+    fn from(err: IoError) -> HttpError {
+        let status = match err.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            // Locked images
+            ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+            ErrorKind::TimedOut => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        HttpError::new(status, err.to_string())
+    }
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> HttpError {
+        HttpError::new(StatusCode::BAD_REQUEST, err.to_string())
+    }
+}
+
+/// How the provisioner creates volumes, and how nodes reach them.
+#[derive(Clone, Debug)]
+pub struct ProvisionerConfig {
+    /// Pool holding the images.
+    pub pool: String,
+    /// Storage daemon the NBD gateways on the nodes connect to.
+    pub storage_daemon: String,
+    /// Block size and stripe unit of new images.
+    pub block_size: usize,
+    pub stripe_unit: usize,
+}
+
+#[derive(Deserialize)]
+struct CapacityRequest {
+    capacity_bytes: u64,
+}
+
+/// What the node needs to attach a volume.
+#[derive(Debug, Serialize)]
+struct Attachment {
+    storage_daemon: String,
+    pool: String,
+    image: String,
+    /// Plugin arguments for `nbdkit libstore_nbd_gateway.so`.
+    nbdkit_args: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Volume {
+    volume_id: String,
+    capacity_bytes: u64,
+    block_size: usize,
+    /// Whether an NBD gateway holds the lock on the image.
+    in_use: bool,
+    attachment: Attachment,
+}
+
+struct Provisioner {
+    client: Client,
+    config: ProvisionerConfig,
+    tokens: Tokens,
+}
+
+/// Serve the provisioning API for images of `client`'s pool on
+/// `listen_address`.
+pub async fn run_provisioner(listen_address: SocketAddr, client: Client, config: ProvisionerConfig, tokens: Tokens) -> Result<(), Box<dyn std::error::Error>> {
+    Geometry { size: 0, block_size: config.block_size, stripe_unit: config.stripe_unit, flags: 0 }.check()?;
+    let client = client.with_timeout(REQUEST_TIMEOUT);
+    let provisioner = Arc::new(Provisioner { client, config, tokens });
+    let server = Server::try_bind(&listen_address)?
+        .serve(make_service_fn(move |_| {
+            let provisioner = provisioner.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(provisioner.clone(), req)))
+            }
+        }));
+    info!("Provisioner listening on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+async fn handle(provisioner: Arc<Provisioner>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let op = match (req.method(), req.uri().path().ends_with("/expand")) {
+        (&Method::PUT, _) => "create",
+        (&Method::GET, _) => "get",
+        (&Method::POST, true) => "expand",
+        (&Method::DELETE, _) => "delete",
+        _ => "unknown",
+    };
+    let path = req.uri().path().to_owned();
+    let response = match route(&provisioner, req).await {
+        Ok(response) => response,
+        Err(err) => {
+            METRICS.errors.with_label_values(&[op]).inc();
+            if err.status.is_server_error() {
+                warn!("Error handling {} on {}: {}", op, path, err.message);
+            }
+            let mut response = Response::builder()
+                .status(err.status)
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+            match err.status {
+                StatusCode::UNAUTHORIZED => response = response.header(WWW_AUTHENTICATE, "Bearer"),
+                StatusCode::METHOD_NOT_ALLOWED => response = response.header(ALLOW, "GET, PUT, POST, DELETE"),
+                _ => {}
+            }
+            response.body(format!("{}\n", err.message).into()).unwrap()
+        }
+    };
+    METRICS.requests.with_label_values(&[op]).inc();
+    METRICS.latency.with_label_values(&[op]).observe(start.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+async fn route(provisioner: &Provisioner, req: Request<Body>) -> Result<Response<Body>, HttpError> {
+    let authorization = req.headers().get(AUTHORIZATION).and_then(|h| h.to_str().ok());
+    if !provisioner.tokens.check(authorization) {
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, "Missing or invalid token"));
+    }
+
+    let path = req.uri().path().to_owned();
+    let (name, expand) = match path.strip_prefix("/volumes/") {
+        Some(rest) => match rest.strip_suffix("/expand") {
+            Some(name) => (name, true),
+            None => (rest, false),
+        },
+        None => return Err(HttpError::new(StatusCode::NOT_FOUND, "Requests are for /volumes/<name>")),
+    };
+    check_name(name)?;
+
+    match (req.method(), expand) {
+        (&Method::PUT, false) => {
+            let capacity = read_capacity(req).await?;
+            create_volume(provisioner, name, capacity).await
+        }
+        (&Method::GET, false) => {
+            let volume = get_volume(provisioner, name).await?
+                .ok_or_else(|| HttpError::new(StatusCode::NOT_FOUND, "No such volume"))?;
+            Ok(json_response(StatusCode::OK, &volume))
+        }
+        (&Method::POST, true) => {
+            let capacity = read_capacity(req).await?;
+            expand_volume(provisioner, name, capacity).await
+        }
+        (&Method::DELETE, false) => {
+            match delete_image(&provisioner.client, name.as_bytes()).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+        }
+        _ => Err(HttpError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+    }
+}
+
+/// Check a volume name, which is used as the image name.
+///
+/// Block objects are named `<image>_<n>`, the lock `<image>.lock`, and
+/// snapshots `<image>@<snapshot>`, so those characters are refused to keep
+/// volumes from overlapping.
+fn check_name(name: &str) -> Result<(), HttpError> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(HttpError::new(StatusCode::BAD_REQUEST, "Invalid volume name length"));
+    }
+    if !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        return Err(HttpError::new(StatusCode::BAD_REQUEST, "Volume names can only have letters, digits, and '-'"));
+    }
+    Ok(())
+}
+
+/// Round a requested capacity up to whole blocks.
+fn round_capacity(capacity: u64, block_size: usize) -> u64 {
+    let block_size = block_size as u64;
+    capacity.div_ceil(block_size).max(1) * block_size
+}
+
+async fn read_capacity(req: Request<Body>) -> Result<u64, HttpError> {
+    let mut body = req.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() > MAX_BODY_SIZE {
+            return Err(HttpError::new(StatusCode::PAYLOAD_TOO_LARGE, "Request too large"));
+        }
+    }
+    let request: CapacityRequest = serde_json::from_slice(&data)
+        .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)))?;
+    Ok(request.capacity_bytes)
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value).unwrap().into())
+        .unwrap()
+}
+
+fn describe(config: &ProvisionerConfig, name: &str, geometry: &Geometry, in_use: bool) -> Volume {
+    Volume {
+        volume_id: name.to_owned(),
+        capacity_bytes: geometry.size,
+        block_size: geometry.block_size,
+        in_use,
+        attachment: Attachment {
+            storage_daemon: config.storage_daemon.clone(),
+            pool: config.pool.clone(),
+            image: name.to_owned(),
+            nbdkit_args: vec![
+                format!("storage_daemon_address={}", config.storage_daemon),
+                format!("pool={}", config.pool),
+                format!("image={}", name),
+            ],
+        },
+    }
+}
+
+async fn get_volume(provisioner: &Provisioner, name: &str) -> Result<Option<Volume>, HttpError> {
+    let geometry = match read_image(&provisioner.client, name.as_bytes()).await? {
+        Some(geometry) => geometry,
+        None => return Ok(None),
+    };
+    let in_use = image_lock_holder(&provisioner.client, name.as_bytes()).await?.is_some();
+    Ok(Some(describe(&provisioner.config, name, &geometry, in_use)))
+}
+
+async fn create_volume(provisioner: &Provisioner, name: &str, capacity: u64) -> Result<Response<Body>, HttpError> {
+    let config = &provisioner.config;
+    let geometry = Geometry {
+        size: round_capacity(capacity, config.block_size),
+        block_size: config.block_size,
+        stripe_unit: config.stripe_unit,
+        flags: 0,
+    };
+    match create_image(&provisioner.client, name.as_bytes(), &geometry, false).await {
+        Ok(()) => {
+            info!("Created volume {} of {} bytes", name, geometry.size);
+            Ok(json_response(StatusCode::CREATED, &describe(config, name, &geometry, false)))
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            // Repeated request, fine if the volume is large enough
+            let volume = get_volume(provisioner, name).await?
+                .ok_or_else(|| HttpError::new(StatusCode::CONFLICT, "Volume is being deleted"))?;
+            if volume.capacity_bytes < capacity {
+                return Err(HttpError::new(
+                    StatusCode::CONFLICT,
+                    format!("Volume exists with a capacity of {} bytes", volume.capacity_bytes),
+                ));
+            }
+            Ok(json_response(StatusCode::OK, &volume))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn expand_volume(provisioner: &Provisioner, name: &str, capacity: u64) -> Result<Response<Body>, HttpError> {
+    let volume = get_volume(provisioner, name).await?
+        .ok_or_else(|| HttpError::new(StatusCode::NOT_FOUND, "No such volume"))?;
+    if volume.capacity_bytes >= capacity {
+        return Ok(json_response(StatusCode::OK, &volume));
+    }
+    let size = round_capacity(capacity, volume.block_size);
+    let geometry = resize_image(&provisioner.client, name.as_bytes(), size).await?;
+    info!("Expanded volume {} to {} bytes", name, geometry.size);
+    Ok(json_response(StatusCode::OK, &describe(&provisioner.config, name, &geometry, false)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_name, round_capacity};
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("pvc-0f2a6c41-1b7e-4d2c-9a55-3e1f0b8c7d21").is_ok());
+        assert!(check_name("data1").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name(&"a".repeat(129)).is_err());
+        for name in ["a_1", "a.lock", "a@snap", "a/b", "a b"] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_round_capacity() {
+        assert_eq!(round_capacity(0, 4096), 4096);
+        assert_eq!(round_capacity(1, 4096), 4096);
+        assert_eq!(round_capacity(4096, 4096), 4096);
+        assert_eq!(round_capacity(4097, 65536), 65536);
+        assert_eq!(round_capacity(1 << 30, 65536), 1 << 30);
+    }
+}
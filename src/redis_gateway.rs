@@ -0,0 +1,706 @@
+//! A server speaking a subset of the Redis protocol (RESP), so existing
+//! Redis clients can use a pool as a durable key-value store.
+//!
+//! Keys are the names of objects and values their content. Supported are
+//! GET, SET (with NX or XX), DEL, EXISTS, and SCAN (with MATCH and COUNT),
+//! plus what clients send when connecting (PING, AUTH, SELECT 0, ...).
+//! Expiry is not supported.
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{MAX_OBJECT_ID_LEN, ObjectId};
+use crate::client::{Client, REQUEST_TIMEOUT};
+use crate::http_gateway::Tokens;
+use crate::metrics::{register_counter_vec, register_gauge};
+
+/// Largest value that can be set, so it is written in one request.
+pub const MAX_VALUE_SIZE: usize = 32 << 10;
+
+/// Largest total size of the arguments of a command, larger ones close the
+/// connection. This is a SET of the largest value, with room for the key and
+/// options.
+const MAX_COMMAND_SIZE: usize = MAX_VALUE_SIZE + MAX_OBJECT_ID_LEN + 1024;
+
+/// Largest number of arguments of a command.
+const MAX_ARGS: usize = 1024;
+
+/// Largest total size of the arguments of a command before AUTH.
+const MAX_COMMAND_SIZE_UNAUTHENTICATED: usize = 1024;
+
+/// Largest number of arguments of a command before AUTH.
+const MAX_ARGS_UNAUTHENTICATED: usize = 8;
+
+/// Largest line (inline command or header) read from clients.
+const MAX_LINE_SIZE: usize = 64 << 10;
+
+/// How many SCAN cursors are remembered, the oldest are forgotten.
+const MAX_CURSORS: usize = 10000;
+
+/// Largest COUNT of SCAN.
+const MAX_SCAN_COUNT: u32 = 1000;
+
+struct Metrics {
+    commands: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+    connections: prometheus::IntGauge,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        commands: register_counter_vec("redis_gateway", "commands", "Total commands", &["command"]),
+        errors: register_counter_vec("redis_gateway", "errors", "Total commands that failed", &["command"]),
+        connections: register_gauge("redis_gateway", "connections", "Connected clients"),
+    };
+}
+
+/// A reply to send to a client.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Error(e) => {
+                out.push(b'-');
+                // Replies are lines, errors can't span several
+                out.extend(e.bytes().map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }));
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn error<S: Into<String>>(msg: S) -> Reply {
+    Reply::Error(format!("ERR {}", msg.into()))
+}
+
+fn syntax_error() -> Reply {
+    error("syntax error")
+}
+
+fn wrong_args(command: &str) -> Reply {
+    error(format!("wrong number of arguments for '{}' command", command))
+}
+
+fn storage_error(err: IoError) -> Reply {
+    warn!("Storage error: {}", err);
+    error(err.to_string())
+}
+
+fn protocol_error(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, format!("Protocol error: {}", msg))
+}
+
+/// Read a line ending in CRLF (or LF), without it.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, IoError> {
+    let mut line = Vec::new();
+    let len = (&mut *reader).take(MAX_LINE_SIZE as u64 + 2).read_until(b'\n', &mut line).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error(if line.len() > MAX_LINE_SIZE { "line too long" } else { "unexpected end" }));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(line: &[u8], max: usize) -> Result<usize, IoError> {
+    std::str::from_utf8(line).ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+/// Read a command, as an array of bulk strings or an inline command.
+///
+/// Clients that are not `authenticated` can only send a few small arguments,
+/// so they can't make us buffer large commands.
+///
+/// Returns `None` when the client closes the connection.
+async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R, authenticated: bool) -> Result<Option<Vec<Vec<u8>>>, IoError> {
+    let (max_args, max_size) = if authenticated {
+        (MAX_ARGS, MAX_COMMAND_SIZE)
+    } else {
+        (MAX_ARGS_UNAUTHENTICATED, MAX_COMMAND_SIZE_UNAUTHENTICATED)
+    };
+    loop {
+        let line = match read_line(reader).await? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let count = match line.strip_prefix(b"*") {
+            Some(count) => parse_length(count, max_args)?,
+            None => {
+                // Inline command, as typed in telnet
+                if line.len() > max_size {
+                    return Err(protocol_error("command too large"));
+                }
+                let args: Vec<Vec<u8>> = line.split(|b| b.is_ascii_whitespace())
+                    .filter(|a| !a.is_empty())
+                    .map(|a| a.to_owned())
+                    .collect();
+                if args.len() > max_args {
+                    return Err(protocol_error("too many arguments"));
+                }
+                if args.is_empty() {
+                    continue;
+                }
+                return Ok(Some(args));
+            }
+        };
+        let mut args = Vec::with_capacity(count);
+        let mut size = 0;
+        for _ in 0..count {
+            let header = read_line(reader).await?.ok_or_else(|| protocol_error("unexpected end"))?;
+            let len = match header.strip_prefix(b"$") {
+                Some(len) => parse_length(len, max_size - size)
+                    .map_err(|_| protocol_error("command too large"))?,
+                None => return Err(protocol_error("expected '$'")),
+            };
+            size += len;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string not followed by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// Whether a key matches a glob-style pattern, as used by SCAN.
+///
+/// Supports `*`, `?`, `[abc]`, `[^a-z]`, and `\` to escape.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // After a mismatch, let the last star match one more character
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        match match_char(pattern, p, key[k]) {
+            Some((true, next)) => {
+                p = next;
+                k += 1;
+            }
+            _ => match star {
+                Some((star_p, star_k)) => {
+                    p = star_p;
+                    k = star_k + 1;
+                    star = Some((star_p, k));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match a character against the element of a pattern at `p` (not a star).
+///
+/// Returns whether it matches and where the next element starts, `None` at
+/// the end of the pattern.
+fn match_char(pattern: &[u8], p: usize, c: u8) -> Option<(bool, usize)> {
+    match *pattern.get(p)? {
+        b'?' => Some((true, p + 1)),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    i += 1;
+                    matched |= pattern[i] == c;
+                } else if pattern.get(i + 1) == Some(&b'-') && i + 2 < pattern.len() && pattern[i + 2] != b']' {
+                    let (lo, hi) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    i += 2;
+                } else {
+                    matched |= pattern[i] == c;
+                }
+                i += 1;
+            }
+            // An unclosed bracket extends to the end of the pattern
+            Some((matched != negate, (i + 1).min(pattern.len())))
+        }
+        b'\\' if p + 1 < pattern.len() => Some((pattern[p + 1] == c, p + 2)),
+        x => Some((x == c, p + 1)),
+    }
+}
+
+/// The part of a pattern before any special character, to list objects
+/// with that prefix.
+fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut iter = pattern.iter();
+    while let Some(&c) = iter.next() {
+        match c {
+            b'*' | b'?' | b'[' => break,
+            b'\\' => match iter.next() {
+                Some(&c) => prefix.push(c),
+                None => break,
+            },
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
+
+/// The SCAN cursors in use, mapping the number given to clients to the last
+/// key they got.
+///
+/// Clients parse cursors as 64-bit integers, so they can't hold the key.
+/// Cursors are shared by connections, since clients with connection pools
+/// can continue a scan on another one.
+#[derive(Default)]
+struct Cursors {
+    keys: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl Cursors {
+    fn insert(&mut self, key: Vec<u8>) -> u64 {
+        let cursor = loop {
+            let cursor = rand::random::<u64>() >> 1;
+            if cursor != 0 && !self.keys.contains_key(&cursor) {
+                break cursor;
+            }
+        };
+        if self.order.len() >= MAX_CURSORS {
+            if let Some(old) = self.order.pop_front() {
+                self.keys.remove(&old);
+            }
+        }
+        self.keys.insert(cursor, key);
+        self.order.push_back(cursor);
+        cursor
+    }
+
+    fn get(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.keys.get(&cursor).cloned()
+    }
+}
+
+struct RedisGateway {
+    client: Client,
+    tokens: Option<Tokens>,
+    cursors: Mutex<Cursors>,
+}
+
+/// Serve the Redis protocol on `listen_address`, storing keys in a pool.
+///
+/// If `tokens` is set, clients must send one of them with AUTH.
+pub async fn run_redis_gateway(listen_address: SocketAddr, client: Client, tokens: Option<Tokens>) -> Result<(), Box<dyn std::error::Error>> {
+    let gateway = Arc::new(RedisGateway {
+        client: client.with_timeout(REQUEST_TIMEOUT),
+        tokens,
+        cursors: Default::default(),
+    });
+    let listener = TcpListener::bind(listen_address).await?;
+    info!("Redis gateway listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            METRICS.connections.inc();
+            if let Err(e) = serve_connection(&gateway, stream).await {
+                info!("Connection from {} closed: {}", address, e);
+            }
+            METRICS.connections.dec();
+        });
+    }
+}
+
+async fn serve_connection(gateway: &RedisGateway, stream: TcpStream) -> Result<(), IoError> {
+    stream.set_nodelay(true)?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let mut authenticated = gateway.tokens.is_none();
+    let mut out = Vec::new();
+    loop {
+        let args = match read_command(&mut reader, authenticated).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if e.kind() == ErrorKind::InvalidData {
+                    error(e.to_string()).encode(&mut out);
+                    write.write_all(&out).await?;
+                }
+                return Err(e);
+            }
+        };
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let (reply, quit) = if name == "quit" {
+            (Reply::Status("OK"), true)
+        } else if name == "auth" {
+            let reply = auth(gateway, &args);
+            authenticated |= reply == Reply::Status("OK");
+            (reply, false)
+        } else if !authenticated && name != "ping" && name != "hello" {
+            (Reply::Error("NOAUTH Authentication required.".to_owned()), false)
+        } else {
+            (run_command(gateway, &name, &args).await, false)
+        };
+
+        let command = if KNOWN_COMMANDS.contains(&name.as_str()) { name.as_str() } else { "unknown" };
+        METRICS.commands.with_label_values(&[command]).inc();
+        if let Reply::Error(_) = reply {
+            METRICS.errors.with_label_values(&[command]).inc();
+        }
+
+        // Send the replies of pipelined commands together
+        reply.encode(&mut out);
+        if quit || reader.buffer().is_empty() {
+            write.write_all(&out).await?;
+            out.clear();
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Commands counted by name in the metrics.
+const KNOWN_COMMANDS: &[&str] = &[
+    "get", "set", "del", "exists", "scan", "ping", "echo", "auth", "select", "quit", "client",
+    "hello", "command",
+];
+
+fn auth(gateway: &RedisGateway, args: &[Vec<u8>]) -> Reply {
+    // AUTH password, or AUTH username password (the username is ignored)
+    let password = match args.len() {
+        2 => &args[1],
+        3 => &args[2],
+        _ => return wrong_args("auth"),
+    };
+    match &gateway.tokens {
+        None => error("AUTH called without any password configured"),
+        Some(tokens) => {
+            let header = format!("Bearer {}", String::from_utf8_lossy(password));
+            if tokens.check(Some(&header)) {
+                Reply::Status("OK")
+            } else {
+                Reply::Error("WRONGPASS invalid username-password pair".to_owned())
+            }
+        }
+    }
+}
+
+async fn run_command(gateway: &RedisGateway, name: &str, args: &[Vec<u8>]) -> Reply {
+    let client = &gateway.client;
+    match name {
+        "ping" => match args.len() {
+            1 => Reply::Status("PONG"),
+            2 => Reply::Bulk(Some(args[1].clone())),
+            _ => wrong_args(name),
+        },
+        "echo" if args.len() == 2 => Reply::Bulk(Some(args[1].clone())),
+        "select" if args.len() == 2 => match &args[1][..] {
+            b"0" => Reply::Status("OK"),
+            _ => error("only database 0 is available"),
+        },
+        // Sent by clients when connecting, setting their name and version
+        "client" if args.len() >= 2 => Reply::Status("OK"),
+        "hello" => Reply::Error("NOPROTO only RESP2 is supported".to_owned()),
+        "get" if args.len() == 2 => match client.read_object(&ObjectId(args[1].clone())).await {
+            Ok(data) => Reply::Bulk(data),
+            Err(e) => storage_error(e),
+        },
+        "set" if args.len() >= 3 => set(client, args).await,
+        "del" if args.len() >= 2 => {
+            let mut count = 0;
+            for key in &args[1..] {
+                let object_id = ObjectId(key.clone());
+                match client.stat_object(&object_id).await {
+                    Ok(Some(_)) => {}
+                    Ok(None) => continue,
+                    Err(e) => return storage_error(e),
+                }
+                match client.delete_object(&object_id).await {
+                    Ok(()) => count += 1,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return storage_error(e),
+                }
+            }
+            Reply::Integer(count)
+        }
+        "exists" if args.len() >= 2 => {
+            let mut count = 0;
+            for key in &args[1..] {
+                match client.stat_object(&ObjectId(key.clone())).await {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => {}
+                    Err(e) => return storage_error(e),
+                }
+            }
+            Reply::Integer(count)
+        }
+        "scan" if args.len() >= 2 => scan(gateway, args).await,
+        "get" | "set" | "del" | "exists" | "scan" | "echo" | "select" | "client" => wrong_args(name),
+        _ => error(format!("unknown command '{}'", name)),
+    }
+}
+
+/// SET key value [NX|XX]
+async fn set(client: &Client, args: &[Vec<u8>]) -> Reply {
+    let object_id = ObjectId(args[1].clone());
+    let value = &args[2];
+    let mut nx = false;
+    let mut xx = false;
+    for option in &args[3..] {
+        match &option.to_ascii_uppercase()[..] {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" | b"KEEPTTL" => return error("expiry is not supported"),
+            b"GET" => return error("SET with GET is not supported"),
+            _ => return syntax_error(),
+        }
+    }
+    if nx && xx {
+        return syntax_error();
+    }
+    if value.len() > MAX_VALUE_SIZE {
+        return error(format!("value is larger than {} bytes", MAX_VALUE_SIZE));
+    }
+
+    if nx {
+        return match client.compare_and_swap(&object_id, None, value).await {
+            Ok(Some(_)) => Reply::Status("OK"),
+            Ok(None) => Reply::Bulk(None),
+            Err(e) => storage_error(e),
+        };
+    }
+    if xx {
+        // Replace the current value, unless it is deleted meanwhile
+        loop {
+            let current = match client.read_object(&object_id).await {
+                Ok(Some(current)) => current,
+                Ok(None) => return Reply::Bulk(None),
+                Err(e) => return storage_error(e),
+            };
+            match client.compare_and_swap(&object_id, Some(&current), value).await {
+                Ok(Some(_)) => return Reply::Status("OK"),
+                Ok(None) => {}
+                Err(e) => return storage_error(e),
+            }
+        }
+    }
+    match client.write_object(&object_id, value).await {
+        Ok(_) => Reply::Status("OK"),
+        Err(e) => storage_error(e),
+    }
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+async fn scan(gateway: &RedisGateway, args: &[Vec<u8>]) -> Reply {
+    let cursor: u64 = match std::str::from_utf8(&args[1]).ok().and_then(|c| c.parse().ok()) {
+        Some(cursor) => cursor,
+        None => return error("invalid cursor"),
+    };
+    let mut pattern = None;
+    let mut count = 10;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return syntax_error(),
+        };
+        match &option.to_ascii_uppercase()[..] {
+            b"MATCH" => pattern = Some(value.clone()),
+            b"COUNT" => match std::str::from_utf8(value).ok().and_then(|c| c.parse::<u32>().ok()) {
+                Some(c) if c > 0 => count = c.min(MAX_SCAN_COUNT),
+                _ => return error("value is not an integer or out of range"),
+            },
+            // Every key holds a string
+            b"TYPE" if value.eq_ignore_ascii_case(b"string") => {}
+            b"TYPE" => return Reply::Array(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(vec![])]),
+            _ => return syntax_error(),
+        }
+    }
+
+    let start_after = match cursor {
+        0 => None,
+        cursor => match gateway.cursors.lock().unwrap().get(cursor) {
+            Some(key) => Some(ObjectId(key)),
+            None => return error("invalid cursor"),
+        },
+    };
+    let prefix = pattern.as_deref().map(literal_prefix).unwrap_or_default();
+    let page = match gateway.client.list_objects(&prefix, start_after.as_ref(), count).await {
+        Ok(page) => page,
+        Err(e) => return storage_error(e),
+    };
+
+    // An empty page is the end, anything else might be followed by more
+    let next = match page.last() {
+        Some(last) => gateway.cursors.lock().unwrap().insert(last.0.clone()),
+        None => 0,
+    };
+    let keys = page.into_iter()
+        .filter(|o| pattern.as_deref().is_none_or(|p| glob_match(p, &o.0)))
+        .map(|o| Reply::Bulk(Some(o.0)))
+        .collect();
+    Reply::Array(vec![Reply::Bulk(Some(next.to_string().into_bytes())), Reply::Array(keys)])
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
+
+    use crate::MAX_OBJECT_ID_LEN;
+    use super::{MAX_ARGS, MAX_VALUE_SIZE, Reply, glob_match, literal_prefix, read_command};
+
+    fn parse(data: &[u8]) -> Vec<Result<Option<Vec<Vec<u8>>>, std::io::ErrorKind>> {
+        parse_as(data, true)
+    }
+
+    fn parse_as(data: &[u8], authenticated: bool) -> Vec<Result<Option<Vec<Vec<u8>>>, std::io::ErrorKind>> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut reader = BufReader::new(data);
+            let mut commands = Vec::new();
+            loop {
+                let command = read_command(&mut reader, authenticated).await.map_err(|e| e.kind());
+                let done = !matches!(command, Ok(Some(_)));
+                commands.push(command);
+                if done {
+                    return commands;
+                }
+            }
+        })
+    }
+
+    fn args(list: &[&[u8]]) -> Vec<Vec<u8>> {
+        list.iter().map(|a| a.to_vec()).collect()
+    }
+
+    #[test]
+    fn test_read_command() {
+        assert_eq!(
+            parse(b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n\r\nPING  x\r\nPING\n*0\r\n"),
+            vec![
+                Ok(Some(args(&[b"GET", b"a\r\nb"]))),
+                Ok(Some(args(&[b"PING", b"x"]))),
+                Ok(Some(args(&[b"PING"]))),
+                Ok(None),
+            ],
+        );
+        assert_eq!(parse(b"*1\r\n$3\r\nGETX\r\n")[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse(b"*1\r\n:3\r\n")[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse(b"*1\r\n$9999999999\r\n")[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse(b"*2\r\n$3\r\nGET\r\n")[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse(b"PING")[0], Err(std::io::ErrorKind::InvalidData));
+    }
+
+    /// Build a command with arguments of the given sizes.
+    fn command(sizes: &[usize]) -> Vec<u8> {
+        let mut data = format!("*{}\r\n", sizes.len()).into_bytes();
+        for &size in sizes {
+            data.extend_from_slice(format!("${}\r\n", size).as_bytes());
+            data.resize(data.len() + size, b'a');
+            data.extend_from_slice(b"\r\n");
+        }
+        data
+    }
+
+    #[test]
+    fn test_command_size() {
+        // A SET of the largest value is fine
+        let set = command(&[3, MAX_OBJECT_ID_LEN, MAX_VALUE_SIZE, 2]);
+        assert!(matches!(parse(&set)[0], Ok(Some(_))));
+
+        // Many arguments can't add up to more than one value
+        assert_eq!(parse(&command(&[MAX_VALUE_SIZE; 3]))[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse(&command(&[1000; MAX_ARGS]))[0], Err(std::io::ErrorKind::InvalidData));
+
+        // Before AUTH, only small commands are read
+        assert_eq!(
+            parse_as(b"*3\r\n$4\r\nAUTH\r\n$4\r\nuser\r\n$6\r\nsecret\r\n", false),
+            vec![Ok(Some(args(&[b"AUTH", b"user", b"secret"]))), Ok(None)],
+        );
+        assert_eq!(parse_as(&set, false)[0], Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(parse_as(&command(&[1; 20]), false)[0], Err(std::io::ErrorKind::InvalidData));
+        let inline = [&b"SET a "[..], &[b'a'; 2000], b"\r\n"].concat();
+        assert_eq!(parse_as(&inline, false)[0], Err(std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut out = Vec::new();
+        Reply::Array(vec![
+            Reply::Status("OK"),
+            Reply::Error("ERR bad\r\nthing".to_owned()),
+            Reply::Integer(-3),
+            Reply::Bulk(None),
+            Reply::Bulk(Some(b"a\r\n".to_vec())),
+        ]).encode(&mut out);
+        assert_eq!(out, b"*5\r\n+OK\r\n-ERR bad  thing\r\n:-3\r\n$-1\r\n$3\r\na\r\n\r\n");
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match(b"user:*", b"user:12"));
+        assert!(glob_match(b"user:*", b"user:"));
+        assert!(!glob_match(b"user:*", b"users"));
+        assert!(glob_match(b"*:1?", b"user:12"));
+        assert!(!glob_match(b"*:1?", b"user:1"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"*a*b*", b"xxaxxbxx"));
+        assert!(glob_match(b"a**", b"a"));
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*b", &[b'a'; 100]));
+
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"a\\*b?c"), b"a*b");
+        assert_eq!(literal_prefix(b"[ab]"), b"");
+    }
+}
@@ -0,0 +1,347 @@
+//! Parsing and formatting of the HTTP parts of S3 requests.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+
+use super::auth::ChunkSignatures;
+
+fn bad_request(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidInput, msg.to_owned())
+}
+
+/// Decode `%XX` escapes, in paths and query strings.
+pub fn percent_decode(s: &str) -> Result<String, IoError> {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or_else(|| bad_request("Invalid escape"))?;
+            let byte = u8::from_str_radix(hex, 16).map_err(|_| bad_request("Invalid escape"))?;
+            result.push(byte);
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(result).map_err(|_| bad_request("Invalid UTF-8"))
+}
+
+/// Escape all but unreserved characters and `/`, for keys in listings with
+/// `encoding-type=url`.
+pub fn percent_encode(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+/// Parse a query string, parameters without a value map to "".
+pub fn parse_query(query: Option<&str>) -> Result<HashMap<String, String>, IoError> {
+    let mut params = HashMap::new();
+    for param in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        params.insert(percent_decode(name)?, percent_decode(value)?);
+    }
+    Ok(params)
+}
+
+/// Split a Unix timestamp into year, month, day, hours, minutes, seconds,
+/// and day of the week (0 is Sunday), in UTC.
+fn civil_time(timestamp: u64) -> (u64, u64, u64, u64, u64, u64, u64) {
+    let days = timestamp / 86400;
+    let secs = timestamp % 86400;
+
+    // From Howard Hinnant's civil_from_days()
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    // 1970-01-01 was a Thursday
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60, (days + 4) % 7)
+}
+
+/// Format a timestamp like `2009-10-12T17:50:30.000Z`, for XML documents.
+pub fn iso8601(timestamp: u64) -> String {
+    let (year, month, day, hours, minutes, seconds, _) = civil_time(timestamp);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z", year, month, day, hours, minutes, seconds)
+}
+
+/// Format a timestamp like `Mon, 12 Oct 2009 17:50:30 GMT`, for headers.
+pub fn http_date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hours, minutes, seconds, weekday) = civil_time(timestamp);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[weekday as usize], day, MONTHS[month as usize - 1], year, hours, minutes, seconds,
+    )
+}
+
+/// Parse a `Range` header, returning the requested bytes as `start..end`.
+///
+/// Only a single range is supported, `None` is returned for several (the
+/// whole object is then sent). An error means the range can't be satisfied.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, IoError> {
+    let unsatisfiable = || bad_request("The requested range is not satisfiable");
+    let range = match header.trim().strip_prefix("bytes=") {
+        Some(r) if !r.contains(',') => r.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = range.split_once('-').ok_or_else(unsatisfiable)?;
+    if start.is_empty() {
+        // The last bytes
+        let len: u64 = end.parse().map_err(|_| unsatisfiable())?;
+        if len == 0 || size == 0 {
+            return Err(unsatisfiable());
+        }
+        return Ok(Some((size.saturating_sub(len), size)));
+    }
+    let start: u64 = start.parse().map_err(|_| unsatisfiable())?;
+    let end = match end {
+        "" => size,
+        e => e.parse::<u64>().map_err(|_| unsatisfiable())?.saturating_add(1).min(size),
+    };
+    if start >= size || start >= end {
+        return Err(unsatisfiable());
+    }
+    Ok(Some((start, end)))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ChunkedState {
+    /// Reading the line with the size of the next chunk.
+    Header,
+    /// Reading this many bytes of data.
+    Data(usize),
+    /// Reading the end of line after the data.
+    DataEnd,
+    /// Reading trailers, after the last chunk.
+    Trailers,
+    Done,
+}
+
+/// Largest line we accept in an `aws-chunked` body.
+const MAX_LINE: usize = 4096;
+
+/// Decodes `aws-chunked` bodies, which AWS SDKs send for streaming uploads.
+///
+/// Each chunk is its size in hexadecimal with optional extensions (the
+/// signature), a line break, the data, and another line break. An empty
+/// chunk ends the data, followed by trailers (for example, a checksum).
+/// Checksums are not checked. Signatures are if `signatures` is set, the
+/// data of a chunk is output before its signature is checked though.
+pub struct AwsChunkedDecoder {
+    state: ChunkedState,
+    line: Vec<u8>,
+    signatures: Option<ChunkSignatures>,
+    /// The signature of the chunk being read, and the hash of its data.
+    signature: Vec<u8>,
+    hasher: Sha256,
+}
+
+impl Default for AwsChunkedDecoder {
+    fn default() -> AwsChunkedDecoder {
+        AwsChunkedDecoder::new(None)
+    }
+}
+
+impl AwsChunkedDecoder {
+    pub fn new(signatures: Option<ChunkSignatures>) -> AwsChunkedDecoder {
+        AwsChunkedDecoder {
+            state: ChunkedState::Header,
+            line: Vec::new(),
+            signatures,
+            signature: Vec::new(),
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Decode part of the body, adding the data to `output`.
+    pub fn decode(&mut self, mut input: &[u8], output: &mut Vec<u8>) -> Result<(), IoError> {
+        while !input.is_empty() {
+            match self.state {
+                ChunkedState::Data(remaining) => {
+                    let len = remaining.min(input.len());
+                    if self.signatures.is_some() {
+                        self.hasher.update(&input[..len]);
+                    }
+                    output.extend_from_slice(&input[..len]);
+                    input = &input[len..];
+                    self.state = match remaining - len {
+                        0 => ChunkedState::DataEnd,
+                        r => ChunkedState::Data(r),
+                    };
+                }
+                ChunkedState::Done => return Err(bad_request("Data after the end of the aws-chunked body")),
+                _ => {
+                    let end = input.iter().position(|&b| b == b'\n');
+                    self.line.extend_from_slice(&input[..end.unwrap_or(input.len())]);
+                    if self.line.len() > MAX_LINE {
+                        return Err(bad_request("Line too long in aws-chunked body"));
+                    }
+                    match end {
+                        Some(pos) => {
+                            input = &input[pos + 1..];
+                            self.end_line()?;
+                        }
+                        None => input = &[],
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a complete line.
+    fn end_line(&mut self) -> Result<(), IoError> {
+        let line = std::mem::take(&mut self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        self.state = match self.state {
+            ChunkedState::Header => {
+                let mut fields = line.split(|&b| b == b';');
+                let size = std::str::from_utf8(fields.next().unwrap()).ok()
+                    .and_then(|s| usize::from_str_radix(s.trim(), 16).ok())
+                    .ok_or_else(|| bad_request("Invalid chunk size in aws-chunked body"))?;
+                if self.signatures.is_some() {
+                    self.signature = fields.find_map(|f| f.strip_prefix(b"chunk-signature="))
+                        .ok_or_else(|| bad_request("Missing chunk signature in aws-chunked body"))?
+                        .to_owned();
+                }
+                match size {
+                    0 => {
+                        // The last chunk is signed too, so the body can't be
+                        // truncated
+                        self.check_signature()?;
+                        ChunkedState::Trailers
+                    }
+                    s => ChunkedState::Data(s),
+                }
+            }
+            ChunkedState::DataEnd if line.is_empty() => {
+                self.check_signature()?;
+                ChunkedState::Header
+            }
+            ChunkedState::DataEnd => return Err(bad_request("Invalid chunk in aws-chunked body")),
+            ChunkedState::Trailers if line.is_empty() => ChunkedState::Done,
+            ChunkedState::Trailers => ChunkedState::Trailers,
+            ChunkedState::Data(_) | ChunkedState::Done => unreachable!(),
+        };
+        Ok(())
+    }
+
+    /// Check the signature of the chunk that was just read.
+    fn check_signature(&mut self) -> Result<(), IoError> {
+        match &mut self.signatures {
+            Some(signatures) => signatures.check(&self.signature, &std::mem::take(&mut self.hasher).finalize()),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the whole body was decoded.
+    ///
+    /// Some clients don't send the empty line after the trailers.
+    pub fn finish(&self) -> Result<(), IoError> {
+        match self.state {
+            ChunkedState::Trailers | ChunkedState::Done => Ok(()),
+            _ => Err(bad_request("Truncated aws-chunked body")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AwsChunkedDecoder, http_date, iso8601, parse_query, parse_range, percent_decode, percent_encode};
+
+    #[test]
+    fn test_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc%c3%a9").unwrap(), "a b/cé");
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("a%zz").is_err());
+        assert!(percent_decode("%ff").is_err());
+        assert_eq!(percent_encode("dir/a b+c%é"), "dir/a%20b%2Bc%25%C3%A9");
+        assert_eq!(percent_decode(&percent_encode("dir/a b+c%é")).unwrap(), "dir/a b+c%é");
+
+        let query = parse_query(Some("list-type=2&prefix=a%2Fb&uploads")).unwrap();
+        assert_eq!(query.len(), 3);
+        assert_eq!(query["list-type"], "2");
+        assert_eq!(query["prefix"], "a/b");
+        assert_eq!(query["uploads"], "");
+        assert!(parse_query(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(iso8601(1255369830), "2009-10-12T17:50:30.000Z");
+        assert_eq!(http_date(1255369830), "Mon, 12 Oct 2009 17:50:30 GMT");
+        assert_eq!(http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(iso8601(4107542399), "2100-02-28T23:59:59.000Z");
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000).unwrap(), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000).unwrap(), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=900-2000", 1000).unwrap(), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-100", 1000).unwrap(), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-2000", 1000).unwrap(), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000).unwrap(), None);
+        assert_eq!(parse_range("lines=1-2", 1000).unwrap(), None);
+        assert!(parse_range("bytes=1000-", 1000).is_err());
+        assert!(parse_range("bytes=5-4", 1000).is_err());
+        assert!(parse_range("bytes=-0", 1000).is_err());
+        assert!(parse_range("bytes=a-b", 1000).is_err());
+        assert!(parse_range("bytes=0-", 0).is_err());
+        assert!(parse_range("bytes=-5", 0).is_err());
+    }
+
+    #[test]
+    fn test_aws_chunked() {
+        let body = b"5;chunk-signature=abc\r\nhello\r\n6;chunk-signature=def\r\n world\r\n0;chunk-signature=ghi\r\nx-amz-checksum-crc32:AAAAAA==\r\n\r\n";
+
+        // In one go, and one byte at a time
+        let mut decoder = AwsChunkedDecoder::default();
+        let mut output = Vec::new();
+        decoder.decode(body, &mut output).unwrap();
+        decoder.finish().unwrap();
+        assert_eq!(output, b"hello world");
+
+        let mut decoder = AwsChunkedDecoder::default();
+        let mut output = Vec::new();
+        for byte in body.chunks(1) {
+            decoder.finish().ok();
+            decoder.decode(byte, &mut output).unwrap();
+        }
+        decoder.finish().unwrap();
+        assert_eq!(output, b"hello world");
+
+        // Without trailers
+        let mut decoder = AwsChunkedDecoder::default();
+        let mut output = Vec::new();
+        decoder.decode(b"3\r\nabc\r\n0\r\n", &mut output).unwrap();
+        decoder.finish().unwrap();
+        assert_eq!(output, b"abc");
+
+        // Truncated or invalid
+        let mut decoder = AwsChunkedDecoder::default();
+        decoder.decode(b"5\r\nhel", &mut Vec::new()).unwrap();
+        assert!(decoder.finish().is_err());
+        assert!(AwsChunkedDecoder::default().decode(b"zz\r\n", &mut Vec::new()).is_err());
+        assert!(AwsChunkedDecoder::default().decode(b"3\r\nabcdef\r\n", &mut Vec::new()).is_err());
+    }
+}
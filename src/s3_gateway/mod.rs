@@ -0,0 +1,533 @@
+//! A gateway translating a subset of the S3 API onto pools.
+//!
+//! Buckets are pools, picked when starting the gateway, and are named in the
+//! path of requests (`http://gateway/bucket/key`). Supported are listing
+//! buckets and objects, getting (with ranges), putting, and deleting
+//! objects (also several at once), and multipart uploads.
+//!
+//! Requests are signed by clients with AWS Signature Version 4, using the
+//! credentials the gateway is given (see `auth`). The gateway then reaches the
+//! pools with its own capabilities.
+
+pub mod auth;
+pub mod http;
+pub mod objects;
+pub mod xml;
+
+use hyper::body::HttpBody;
+use hyper::header::{
+    ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    LAST_MODIFIED, RANGE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::{decode_hex, encode_hex};
+use crate::client::{Client, REQUEST_TIMEOUT};
+use crate::metrics::register_counter_vec;
+use auth::{ChunkSignatures, Credentials, PayloadHash};
+use http::{AwsChunkedDecoder, http_date, iso8601, parse_query, parse_range, percent_decode, percent_encode};
+use objects::{ObjectMeta, SegmentWriter, chunk_reads, delete_segment, head_object, new_id, now, read_chunk};
+
+/// Largest number of keys in a page of listing.
+const MAX_KEYS: usize = 1000;
+
+/// Largest number of parts in a multipart upload.
+const MAX_PARTS: u32 = 10000;
+
+/// Largest XML document we read from clients.
+const MAX_XML_SIZE: usize = 1 << 20;
+
+struct Metrics {
+    requests: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        requests: register_counter_vec("s3_gateway", "requests", "Total requests", &["op"]),
+        errors: register_counter_vec("s3_gateway", "errors", "Total requests that failed", &["op"]),
+    };
+}
+
+/// An error to send back, as an S3 error code.
+#[derive(Debug)]
+struct S3Error {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl S3Error {
+    fn new<S: Into<String>>(status: StatusCode, code: &'static str, message: S) -> S3Error {
+        S3Error { status, code, message: message.into() }
+    }
+
+    fn not_implemented() -> S3Error {
+        S3Error::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "This operation is not supported by the gateway")
+    }
+
+    /// Report a missing object as a missing upload instead.
+    fn for_upload(self) -> S3Error {
+        match self.code {
+            "NoSuchKey" => S3Error { code: "NoSuchUpload", ..self },
+            _ => self,
+        }
+    }
+}
+
+impl From<IoError> for S3Error {
+    fn from(err: IoError) -> S3Error {
+        let (status, code) = match err.kind() {
+            ErrorKind::NotFound => (StatusCode::NOT_FOUND, "NoSuchKey"),
+            ErrorKind::PermissionDenied => (StatusCode::FORBIDDEN, "AccessDenied"),
+            ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, "InvalidRequest"),
+            ErrorKind::TimedOut => (StatusCode::SERVICE_UNAVAILABLE, "ServiceUnavailable"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "InternalError"),
+        };
+        S3Error::new(status, code, err.to_string())
+    }
+}
+
+impl From<hyper::Error> for S3Error {
+    fn from(err: hyper::Error) -> S3Error {
+        S3Error::new(StatusCode::BAD_REQUEST, "IncompleteBody", err.to_string())
+    }
+}
+
+struct S3Gateway {
+    /// The clients for each bucket, which are pools.
+    buckets: HashMap<String, Client>,
+    /// The credentials requests are signed with, `None` to accept any.
+    credentials: Option<Credentials>,
+}
+
+/// Serve the S3 API on `listen_address`, exposing pools as buckets.
+///
+/// Without `credentials`, requests are not authenticated at all.
+pub async fn run_s3_gateway(listen_address: SocketAddr, buckets: HashMap<String, Client>, credentials: Option<Credentials>) -> Result<(), Box<dyn std::error::Error>> {
+    let buckets = buckets.into_iter()
+        .map(|(name, client)| (name, client.with_timeout(REQUEST_TIMEOUT)))
+        .collect();
+    let gateway = Arc::new(S3Gateway { buckets, credentials });
+    let server = Server::try_bind(&listen_address)?
+        .serve(make_service_fn(move |_| {
+            let gateway = gateway.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(gateway.clone(), req)))
+            }
+        }));
+    info!("S3 gateway listening on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+async fn handle(gateway: Arc<S3Gateway>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let resource = req.uri().path().to_owned();
+    let mut op = "unknown";
+    let response = match route(&gateway, req, &mut op).await {
+        Ok(response) => response,
+        Err(err) => {
+            METRICS.errors.with_label_values(&[op]).inc();
+            if err.status.is_server_error() {
+                warn!("Error handling {} on {}: {}", op, resource, err.message);
+            }
+            Response::builder()
+                .status(err.status)
+                .header(CONTENT_TYPE, "application/xml")
+                .body(xml::error(err.code, &err.message, &resource).into())
+                .unwrap()
+        }
+    };
+    METRICS.requests.with_label_values(&[op]).inc();
+    Ok(response)
+}
+
+/// Handle a request, setting `op` to its name for metrics.
+async fn route(gateway: &S3Gateway, mut req: Request<Body>, op: &mut &'static str) -> Result<Response<Body>, S3Error> {
+    if let Some(credentials) = &gateway.credentials {
+        let (_, chunks) = credentials.check(req.method(), req.uri(), req.headers(), now())?;
+        // For write_body()
+        if let Some(chunks) = chunks {
+            req.extensions_mut().insert(chunks);
+        }
+    }
+
+    let path = percent_decode(req.uri().path())?;
+    let query = parse_query(req.uri().query())?;
+    let path = path.strip_prefix('/').unwrap_or(&path);
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    let method = req.method().clone();
+
+    if bucket.is_empty() {
+        return match method {
+            Method::GET => {
+                *op = "list_buckets";
+                Ok(list_buckets(gateway))
+            }
+            _ => Err(S3Error::not_implemented()),
+        };
+    }
+    let client = gateway.buckets.get(bucket).ok_or_else(|| {
+        S3Error::new(StatusCode::NOT_FOUND, "NoSuchBucket", format!("No bucket {:?}", bucket))
+    })?;
+
+    if key.is_empty() {
+        return match method {
+            Method::GET if query.contains_key("location") => {
+                *op = "get_location";
+                Ok(xml_response(StatusCode::OK, format!(
+                    "{}<LocationConstraint xmlns=\"{}\"/>", xml::HEADER, xml::NAMESPACE,
+                )))
+            }
+            Method::GET if query.contains_key("uploads") => Err(S3Error::not_implemented()),
+            Method::POST if query.contains_key("delete") => {
+                *op = "delete_objects";
+                delete_objects(client, req).await
+            }
+            Method::GET => {
+                *op = "list";
+                list_objects(client, bucket, &query).await
+            }
+            // Buckets are pools, which exist already
+            Method::HEAD | Method::PUT => {
+                *op = "head_bucket";
+                Ok(Response::new(Body::empty()))
+            }
+            _ => Err(S3Error::not_implemented()),
+        };
+    }
+
+    match method {
+        Method::PUT if query.contains_key("uploadId") => {
+            *op = "upload_part";
+            upload_part(client, key, &query, req).await.map_err(S3Error::for_upload)
+        }
+        Method::PUT if req.headers().contains_key("x-amz-copy-source") => Err(S3Error::not_implemented()),
+        Method::PUT => {
+            *op = "put";
+            put_object(client, key, req).await
+        }
+        Method::GET | Method::HEAD => {
+            *op = "get";
+            get_object(client, key, &req, method == Method::HEAD).await
+        }
+        Method::DELETE if query.contains_key("uploadId") => {
+            *op = "abort_upload";
+            objects::abort_upload(client, key.as_bytes(), &query["uploadId"]).await
+                .map_err(|e| S3Error::from(e).for_upload())?;
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        Method::DELETE => {
+            *op = "delete";
+            objects::delete_object(client, key.as_bytes()).await?;
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        Method::POST if query.contains_key("uploads") => {
+            *op = "create_upload";
+            let upload_id = objects::create_upload(client, key.as_bytes()).await?;
+            Ok(xml_response(StatusCode::OK, format!(
+                "{}<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                xml::HEADER, xml::NAMESPACE, xml::escape(bucket), xml::escape(key), upload_id,
+            )))
+        }
+        Method::POST if query.contains_key("uploadId") => {
+            *op = "complete_upload";
+            complete_upload(client, bucket, key, &query["uploadId"], req).await.map_err(S3Error::for_upload)
+        }
+        _ => Err(S3Error::not_implemented()),
+    }
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+fn xml_response(status: StatusCode, xml: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/xml")
+        .body(xml.into())
+        .unwrap()
+}
+
+fn quoted(etag: &str) -> String {
+    format!("\"{}\"", etag)
+}
+
+fn list_buckets(gateway: &S3Gateway) -> Response<Body> {
+    let mut names: Vec<&String> = gateway.buckets.keys().collect();
+    names.sort();
+    let mut xml = format!(
+        "{}<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>store</ID><DisplayName>store</DisplayName></Owner><Buckets>",
+        xml::HEADER, xml::NAMESPACE,
+    );
+    for name in names {
+        write!(xml, "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>", xml::escape(name), iso8601(0)).unwrap();
+    }
+    xml.push_str("</Buckets></ListAllMyBucketsResult>");
+    xml_response(StatusCode::OK, xml)
+}
+
+fn invalid_argument(message: &str) -> S3Error {
+    S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+}
+
+/// List objects, with either version of the API.
+///
+/// The continuation tokens of the second version are the key to start after
+/// in hexadecimal.
+async fn list_objects(client: &Client, bucket: &str, query: &HashMap<String, String>) -> Result<Response<Body>, S3Error> {
+    let param = |name: &str| query.get(name).map(String::as_str);
+    let v2 = param("list-type") == Some("2");
+    let prefix = param("prefix").unwrap_or("");
+    let delimiter = param("delimiter").filter(|d| !d.is_empty());
+    let max_keys = match param("max-keys") {
+        Some(m) => m.parse::<usize>().map_err(|_| invalid_argument("Invalid max-keys"))?.min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
+    let url_encoding = match param("encoding-type") {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Err(invalid_argument("Invalid encoding-type")),
+    };
+    let encode = |s: &str| match url_encoding {
+        true => percent_encode(s),
+        false => xml::escape(s),
+    };
+
+    let start_after = if v2 {
+        match param("continuation-token") {
+            Some(token) => Some(decode_hex(token).ok_or_else(|| invalid_argument("Invalid continuation-token"))?),
+            None => param("start-after").map(|s| s.as_bytes().to_owned()),
+        }
+    } else {
+        param("marker").filter(|m| !m.is_empty()).map(|marker| {
+            let mut after = marker.as_bytes().to_owned();
+            // The next marker can be a common prefix, skip the objects in it
+            if delimiter.is_some_and(|d| marker.ends_with(d)) {
+                after.push(0xff);
+            }
+            after
+        })
+    };
+    let listing = objects::list_objects(client, prefix, delimiter, start_after.as_deref(), max_keys).await?;
+
+    let mut xml = format!("{}<ListBucketResult xmlns=\"{}\">", xml::HEADER, xml::NAMESPACE);
+    write!(xml, "<Name>{}</Name><Prefix>{}</Prefix>", xml::escape(bucket), encode(prefix)).unwrap();
+    if let Some(delimiter) = delimiter {
+        write!(xml, "<Delimiter>{}</Delimiter>", encode(delimiter)).unwrap();
+    }
+    if url_encoding {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    write!(xml, "<MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>", max_keys, listing.next.is_some()).unwrap();
+    if v2 {
+        write!(xml, "<KeyCount>{}</KeyCount>", listing.objects.len() + listing.common_prefixes.len()).unwrap();
+        if let Some(token) = param("continuation-token") {
+            write!(xml, "<ContinuationToken>{}</ContinuationToken>", xml::escape(token)).unwrap();
+        }
+        if let Some(start_after) = param("start-after") {
+            write!(xml, "<StartAfter>{}</StartAfter>", encode(start_after)).unwrap();
+        }
+        if let Some(next) = &listing.next {
+            write!(xml, "<NextContinuationToken>{}</NextContinuationToken>", encode_hex(next)).unwrap();
+        }
+    } else {
+        write!(xml, "<Marker>{}</Marker>", encode(param("marker").unwrap_or(""))).unwrap();
+        if let Some(next) = &listing.next {
+            let next = next.strip_suffix(&[0xff]).unwrap_or(next);
+            write!(xml, "<NextMarker>{}</NextMarker>", encode(&String::from_utf8_lossy(next))).unwrap();
+        }
+    }
+    for (key, meta) in &listing.objects {
+        write!(
+            xml,
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            encode(key), iso8601(meta.modified), xml::escape(&quoted(&meta.etag)), meta.size,
+        ).unwrap();
+    }
+    for common_prefix in &listing.common_prefixes {
+        write!(xml, "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", encode(common_prefix)).unwrap();
+    }
+    xml.push_str("</ListBucketResult>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// Delete several objects, reporting errors for each.
+async fn delete_objects(client: &Client, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+    let body = read_small_body(req).await?;
+    let (keys, quiet) = xml::parse_delete_objects(&body)
+        .map_err(|e| S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", e.to_string()))?;
+    if keys.len() > MAX_KEYS {
+        return Err(S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", "Too many keys"));
+    }
+    let mut xml = format!("{}<DeleteResult xmlns=\"{}\">", xml::HEADER, xml::NAMESPACE);
+    for key in keys {
+        match objects::delete_object(client, key.as_bytes()).await {
+            Ok(()) if quiet => {}
+            Ok(()) => write!(xml, "<Deleted><Key>{}</Key></Deleted>", xml::escape(&key)).unwrap(),
+            Err(e) => {
+                let err = S3Error::from(e);
+                write!(
+                    xml,
+                    "<Error><Key>{}</Key><Code>{}</Code><Message>{}</Message></Error>",
+                    xml::escape(&key), err.code, xml::escape(&err.message),
+                ).unwrap();
+            }
+        }
+    }
+    xml.push_str("</DeleteResult>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+async fn get_object(client: &Client, key: &str, req: &Request<Body>, head: bool) -> Result<Response<Body>, S3Error> {
+    let meta = head_object(client, key.as_bytes()).await?.ok_or_else(|| {
+        S3Error::new(StatusCode::NOT_FOUND, "NoSuchKey", format!("No object {:?}", key))
+    })?;
+    let range = match req.headers().get(RANGE).and_then(|r| r.to_str().ok()) {
+        Some(range) => parse_range(range, meta.size).map_err(|e| {
+            S3Error::new(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", e.to_string())
+        })?,
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, meta.size));
+
+    let mut response = Response::builder()
+        .header(CONTENT_LENGTH, end - start)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(ETAG, quoted(&meta.etag))
+        .header(LAST_MODIFIED, http_date(meta.modified))
+        .header(ACCEPT_RANGES, "bytes");
+    if range.is_some() {
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, meta.size));
+    }
+    if head {
+        return Ok(response.body(Body::empty()).unwrap());
+    }
+
+    // Send the chunks as they are read, aborting the response on errors
+    let reads = chunk_reads(&meta.segments, start, end);
+    let (mut sender, body) = Body::channel();
+    let client = client.clone();
+    tokio::spawn(async move {
+        for (object_id, offset, len) in reads {
+            match read_chunk(&client, &object_id, offset, len).await {
+                Ok(data) => {
+                    if sender.send_data(data.into()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("Error reading {:?}: {}", object_id, e);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    Ok(response.body(body).unwrap())
+}
+
+/// Write the body of a request to a segment, decoding `aws-chunked`.
+///
+/// On errors, the segment is deleted.
+async fn write_body(client: &Client, mut req: Request<Body>, writer: &mut SegmentWriter) -> Result<(), S3Error> {
+    let signatures = req.extensions_mut().remove::<ChunkSignatures>();
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let chunked = header("x-amz-content-sha256").is_some_and(|v| v.starts_with("STREAMING-"))
+        || header(CONTENT_ENCODING.as_str()).is_some_and(|v| v.contains("aws-chunked"));
+    let mut decoder = chunked.then(|| AwsChunkedDecoder::new(signatures));
+    let mut hash = PayloadHash::new(req.headers())?;
+
+    let mut body = req.into_body();
+    let mut decoded = Vec::new();
+    let result: Result<(), S3Error> = async {
+        while let Some(data) = body.data().await {
+            let data = data?;
+            hash.update(&data);
+            match &mut decoder {
+                Some(decoder) => {
+                    decoded.clear();
+                    decoder.decode(&data, &mut decoded)?;
+                    writer.write(&decoded).await?;
+                }
+                None => writer.write(&data).await?,
+            }
+        }
+        if let Some(decoder) = &decoder {
+            decoder.finish()?;
+        }
+        hash.finish()
+    }.await;
+    if result.is_err() {
+        delete_segment(client, &writer.segment()).await.ok();
+    }
+    result
+}
+
+/// Read a small body, such as an XML document.
+async fn read_small_body(req: Request<Body>) -> Result<String, S3Error> {
+    // Its chunks would not be checked
+    if req.extensions().get::<ChunkSignatures>().is_some() {
+        return Err(S3Error::not_implemented());
+    }
+    let mut hash = PayloadHash::new(req.headers())?;
+    let mut body = req.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() > MAX_XML_SIZE {
+            return Err(S3Error::new(StatusCode::BAD_REQUEST, "MaxMessageLengthExceeded", "Request is too large"));
+        }
+    }
+    hash.update(&data);
+    hash.finish()?;
+    String::from_utf8(data).map_err(|_| S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", "Invalid UTF-8"))
+}
+
+async fn put_object(client: &Client, key: &str, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+    let mut writer = SegmentWriter::new(client, new_id().as_bytes());
+    write_body(client, req, &mut writer).await?;
+    let meta = objects::put_object(client, key.as_bytes(), writer).await?;
+    Ok(Response::builder().header(ETAG, quoted(&meta.etag)).body(Body::empty()).unwrap())
+}
+
+async fn upload_part(client: &Client, key: &str, query: &HashMap<String, String>, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+    let upload_id = &query["uploadId"];
+    let part_num: u32 = query.get("partNumber")
+        .and_then(|n| n.parse().ok())
+        .filter(|n| (1..=MAX_PARTS).contains(n))
+        .ok_or_else(|| invalid_argument("Invalid partNumber"))?;
+    let mut writer = objects::start_part(client, key.as_bytes(), upload_id, part_num).await?;
+    write_body(client, req, &mut writer).await?;
+    let etag = objects::finish_part(client, upload_id, part_num, writer).await?;
+    Ok(Response::builder().header(ETAG, quoted(&etag)).body(Body::empty()).unwrap())
+}
+
+async fn complete_upload(client: &Client, bucket: &str, key: &str, upload_id: &str, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+    let body = read_small_body(req).await?;
+    let parts = xml::parse_complete_upload(&body)
+        .map_err(|e| S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", e.to_string()))?;
+    let meta: ObjectMeta = objects::complete_upload(client, key.as_bytes(), upload_id, &parts).await
+        .map_err(|e| match e.kind() {
+            ErrorKind::InvalidInput => S3Error::new(StatusCode::BAD_REQUEST, "InvalidPart", e.to_string()),
+            _ => e.into(),
+        })?;
+    Ok(xml_response(StatusCode::OK, format!(
+        "{}<CompleteMultipartUploadResult xmlns=\"{}\"><Location>/{}/{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+        xml::HEADER, xml::NAMESPACE,
+        xml::escape(bucket), xml::escape(key), xml::escape(bucket), xml::escape(key),
+        xml::escape(&quoted(&meta.etag)),
+    )))
+}
@@ -0,0 +1,617 @@
+//! How S3 objects are stored in a pool.
+//!
+//! An S3 object is described by a metadata object `m:<key>`, listing the
+//! segments holding its data: one for a simple upload, one per part for a
+//! multipart upload. Segment `<id>` is stored in chunk objects `d:<id>:<n>`
+//! of `CHUNK_SIZE` bytes.
+//!
+//! Data always goes to new segments, and the metadata is replaced once they
+//! are complete, so readers see either the old or the new object. The old
+//! segments are deleted after that.
+//!
+//! A multipart upload is an object `u:<upload id>` holding the key, and
+//! objects `u:<upload id>:<part number>` describing the parts uploaded so
+//! far, in the same format as the metadata of an object.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{ObjectId, encode_hex};
+use crate::client::Client;
+
+/// Size of the objects holding the data.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Largest part of a chunk written in one request, so that it fits in a
+/// datagram.
+const WRITE_SIZE: usize = 32 << 10;
+
+const METADATA_VERSION: u8 = 1;
+
+/// How many objects to get per request when listing.
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// Part of the data of an object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub id: Vec<u8>,
+    pub size: u64,
+}
+
+/// The metadata object of an S3 object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub size: u64,
+    /// Unix timestamp of the upload.
+    pub modified: u64,
+    /// Hash of the content, without the quotes.
+    pub etag: String,
+    pub segments: Vec<Segment>,
+}
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+impl ObjectMeta {
+    /// Read the metadata object: the version byte 1, then the size and the
+    /// modification time as big-endian u64, the ETag, and the segments, each
+    /// an ID and a big-endian u64 size. The ETag and IDs are prefixed by
+    /// their length as a byte, and the segments by their number as a
+    /// big-endian u32.
+    pub fn decode(data: &[u8]) -> Result<ObjectMeta, IoError> {
+        fn read_bytes(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, IoError> {
+            let len = reader.read_u8()? as usize;
+            let mut bytes = vec![0; len];
+            reader.read_exact(&mut bytes)?;
+            Ok(bytes)
+        }
+
+        let mut reader = Cursor::new(data);
+        let version = reader.read_u8()?;
+        if version != METADATA_VERSION {
+            return Err(invalid(&format!("Unknown S3 object metadata version {}", version)));
+        }
+        let size = reader.read_u64::<BigEndian>()?;
+        let modified = reader.read_u64::<BigEndian>()?;
+        let etag = String::from_utf8(read_bytes(&mut reader)?)
+            .map_err(|_| invalid("Invalid ETag"))?;
+        let num_segments = reader.read_u32::<BigEndian>()?;
+        let mut segments = Vec::new();
+        for _ in 0..num_segments {
+            let id = read_bytes(&mut reader)?;
+            let size = reader.read_u64::<BigEndian>()?;
+            segments.push(Segment { id, size });
+        }
+        if reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after S3 object metadata"));
+        }
+        if segments.iter().map(|s| s.size).sum::<u64>() != size {
+            return Err(invalid("S3 object segments don't add up to its size"));
+        }
+        Ok(ObjectMeta { size, modified, etag, segments })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.write_u8(METADATA_VERSION).unwrap();
+        result.write_u64::<BigEndian>(self.size).unwrap();
+        result.write_u64::<BigEndian>(self.modified).unwrap();
+        result.write_u8(self.etag.len() as u8).unwrap();
+        result.write_all(self.etag.as_bytes()).unwrap();
+        result.write_u32::<BigEndian>(self.segments.len() as u32).unwrap();
+        for segment in &self.segments {
+            result.write_u8(segment.id.len() as u8).unwrap();
+            result.write_all(&segment.id).unwrap();
+            result.write_u64::<BigEndian>(segment.size).unwrap();
+        }
+        result
+    }
+}
+
+fn prefixed(prefix: &[u8], name: &[u8]) -> ObjectId {
+    let mut object_id = prefix.to_owned();
+    object_id.extend_from_slice(name);
+    ObjectId(object_id)
+}
+
+/// The metadata object of an S3 object.
+fn meta_object_id(key: &[u8]) -> ObjectId {
+    prefixed(b"m:", key)
+}
+
+/// An object holding data.
+fn chunk_object_id(segment_id: &[u8], chunk_num: u64) -> ObjectId {
+    let mut object_id = prefixed(b"d:", segment_id);
+    write!(object_id.0, ":{}", chunk_num).unwrap();
+    object_id
+}
+
+fn upload_object_id(upload_id: &str) -> ObjectId {
+    prefixed(b"u:", upload_id.as_bytes())
+}
+
+fn part_object_id(upload_id: &str, part_num: u32) -> ObjectId {
+    let mut object_id = upload_object_id(upload_id);
+    write!(object_id.0, ":{}", part_num).unwrap();
+    object_id
+}
+
+/// The segment a chunk object holds data of, `None` for other objects.
+pub fn chunk_segment(object_id: &ObjectId) -> Option<&[u8]> {
+    let rest = object_id.0.strip_prefix(b"d:")?;
+    let pos = rest.iter().rposition(|&b| b == b':')?;
+    Some(&rest[..pos])
+}
+
+/// The IDs of the segments that objects and uploaded parts refer to.
+pub async fn referenced_segments(client: &Client) -> Result<HashSet<Vec<u8>>, IoError> {
+    let mut segments = HashSet::new();
+    let objects = client.list_all_objects(b"m:").await?;
+    // Uploaded parts, not the uploads holding the key
+    let parts = client.list_all_objects(b"u:").await?.into_iter()
+        .filter(|o| o.0[2..].contains(&b':'));
+    for object_id in objects.into_iter().chain(parts) {
+        // The object might have been deleted since it was listed
+        if let Some(data) = client.read_object(&object_id).await? {
+            segments.extend(ObjectMeta::decode(&data)?.segments.into_iter().map(|s| s.id));
+        }
+    }
+    Ok(segments)
+}
+
+/// A random ID, for segments and uploads.
+pub fn new_id() -> String {
+    encode_hex(&rand::random::<[u8; 16]>())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Ignore errors from deleting objects that are gone already.
+fn ignore_not_found(result: Result<(), IoError>) -> Result<(), IoError> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// Writes data to a new segment, computing its hash.
+///
+/// If this fails, the chunks written so far are left behind, use
+/// `delete_segment()`.
+pub struct SegmentWriter {
+    client: Client,
+    id: Vec<u8>,
+    /// Data of the current chunk.
+    buffer: Vec<u8>,
+    chunk_num: u64,
+    size: u64,
+    hasher: Sha256,
+}
+
+impl SegmentWriter {
+    pub fn new(client: &Client, id: &[u8]) -> SegmentWriter {
+        SegmentWriter {
+            client: client.clone(),
+            id: id.to_owned(),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            chunk_num: 0,
+            size: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The segment, as far as it's been written.
+    pub fn segment(&self) -> Segment {
+        Segment { id: self.id.clone(), size: self.size }
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), IoError> {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        while !data.is_empty() {
+            let len = data.len().min(CHUNK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.buffer.len() == CHUNK_SIZE {
+                self.write_chunk().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last chunk, and return the segment and its hash.
+    pub async fn finish(mut self) -> Result<(Segment, [u8; 32]), IoError> {
+        if !self.buffer.is_empty() {
+            self.write_chunk().await?;
+        }
+        let hash = self.hasher.finalize_reset().into();
+        Ok((self.segment(), hash))
+    }
+
+    /// Write the buffer to the next chunk, sending its parts in parallel.
+    async fn write_chunk(&mut self) -> Result<(), IoError> {
+        let object_id = chunk_object_id(&self.id, self.chunk_num);
+        let handles: Vec<_> = self.buffer.chunks(WRITE_SIZE).enumerate().map(|(i, part)| {
+            let (client, object_id, part) = (self.client.clone(), object_id.clone(), part.to_owned());
+            tokio::spawn(async move {
+                client.write_part(&object_id, (i * WRITE_SIZE) as u32, &part).await
+            })
+        }).collect();
+        for handle in handles {
+            handle.await.map_err(IoError::other)??;
+        }
+        self.buffer.clear();
+        self.chunk_num += 1;
+        Ok(())
+    }
+}
+
+/// The ETag of data, from its hash, like the MD5 sum S3 uses but from
+/// SHA-256.
+pub fn etag(hash: &[u8; 32]) -> String {
+    encode_hex(&hash[..16])
+}
+
+/// Delete the chunks of a segment.
+pub async fn delete_segment(client: &Client, segment: &Segment) -> Result<(), IoError> {
+    for chunk_num in 0..segment.size.div_ceil(CHUNK_SIZE as u64) {
+        ignore_not_found(client.delete_object(&chunk_object_id(&segment.id, chunk_num)).await)?;
+    }
+    Ok(())
+}
+
+/// The parts of chunks to read to get the bytes `start..end` of an object,
+/// as object, offset, and length.
+pub fn chunk_reads(segments: &[Segment], start: u64, end: u64) -> Vec<(ObjectId, u32, u32)> {
+    let mut reads = Vec::new();
+    let mut segment_start = 0;
+    for segment in segments {
+        let segment_end = segment_start + segment.size;
+        let mut pos = start.max(segment_start);
+        while pos < end.min(segment_end) {
+            let offset = pos - segment_start;
+            let chunk_offset = offset % CHUNK_SIZE as u64;
+            let len = (CHUNK_SIZE as u64 - chunk_offset).min(end.min(segment_end) - pos);
+            reads.push((
+                chunk_object_id(&segment.id, offset / CHUNK_SIZE as u64),
+                chunk_offset as u32,
+                len as u32,
+            ));
+            pos += len;
+        }
+        segment_start = segment_end;
+    }
+    reads
+}
+
+/// Read part of a chunk, which must be all there.
+pub async fn read_chunk(client: &Client, object_id: &ObjectId, offset: u32, len: u32) -> Result<Vec<u8>, IoError> {
+    match client.read_part(object_id, offset, len).await? {
+        Some(data) if data.len() == len as usize => Ok(data),
+        _ => Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("Object data is missing from {:?}", object_id),
+        )),
+    }
+}
+
+/// Get the metadata of an object, if it exists.
+pub async fn head_object(client: &Client, key: &[u8]) -> Result<Option<ObjectMeta>, IoError> {
+    match client.read_object(&meta_object_id(key)).await? {
+        Some(data) => Ok(Some(ObjectMeta::decode(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Make written segments an object, replacing the current one.
+pub async fn commit_object(client: &Client, key: &[u8], meta: &ObjectMeta) -> Result<(), IoError> {
+    let object_id = meta_object_id(key);
+    let encoded = meta.encode();
+    loop {
+        let old = client.read_object(&object_id).await?;
+        if client.compare_and_swap(&object_id, old.as_deref(), &encoded).await?.is_some() {
+            if let Some(old) = old.and_then(|o| ObjectMeta::decode(&o).ok()) {
+                for segment in &old.segments {
+                    delete_segment(client, segment).await?;
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Store an object from a single segment.
+pub async fn put_object(client: &Client, key: &[u8], writer: SegmentWriter) -> Result<ObjectMeta, IoError> {
+    let segment = writer.segment();
+    let result = async {
+        let (segment, hash) = writer.finish().await?;
+        let meta = ObjectMeta {
+            size: segment.size,
+            modified: now(),
+            etag: etag(&hash),
+            segments: vec![segment],
+        };
+        commit_object(client, key, &meta).await?;
+        Ok(meta)
+    }.await;
+    if result.is_err() {
+        delete_segment(client, &segment).await.ok();
+    }
+    result
+}
+
+/// Delete an object, if it exists.
+pub async fn delete_object(client: &Client, key: &[u8]) -> Result<(), IoError> {
+    if let Some(meta) = head_object(client, key).await? {
+        ignore_not_found(client.delete_object(&meta_object_id(key)).await)?;
+        for segment in &meta.segments {
+            delete_segment(client, segment).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A page of objects, from `list_objects()`.
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub objects: Vec<(String, ObjectMeta)>,
+    /// Prefixes up to the delimiter, standing for all the objects that
+    /// start with them.
+    pub common_prefixes: Vec<String>,
+    /// Where to start the next page, if there are more objects.
+    pub next: Option<Vec<u8>>,
+}
+
+/// List the objects whose key starts with `prefix`, after `start_after`.
+///
+/// With a delimiter, the objects whose key contains it after the prefix are
+/// grouped into a common prefix. Listing after a common prefix followed by
+/// the byte 0xff skips the objects in it.
+pub async fn list_objects(client: &Client, prefix: &str, delimiter: Option<&str>, start_after: Option<&[u8]>, max_keys: usize) -> Result<Listing, IoError> {
+    let meta_prefix = meta_object_id(prefix.as_bytes());
+    let mut start = start_after.map(meta_object_id);
+    let mut listing = Listing::default();
+    'pages: loop {
+        if listing.objects.len() + listing.common_prefixes.len() >= max_keys {
+            // See if there is more
+            let more = client.list_objects(&meta_prefix.0, start.as_ref(), 1).await?;
+            if !more.is_empty() {
+                listing.next = start.map(|s| s.0[2..].to_owned());
+            }
+            return Ok(listing);
+        }
+
+        let page = client.list_objects(&meta_prefix.0, start.as_ref(), LIST_PAGE_SIZE).await?;
+        if page.is_empty() {
+            return Ok(listing);
+        }
+        for object_id in page {
+            if listing.objects.len() + listing.common_prefixes.len() >= max_keys {
+                continue 'pages;
+            }
+            let key = String::from_utf8_lossy(&object_id.0[2..]).into_owned();
+            if let Some(delimiter) = delimiter.filter(|d| !d.is_empty()) {
+                if let Some(pos) = key[prefix.len()..].find(delimiter) {
+                    let common_prefix = key[..prefix.len() + pos + delimiter.len()].to_owned();
+                    let mut after = common_prefix.as_bytes().to_owned();
+                    after.push(0xff);
+                    start = Some(meta_object_id(&after));
+                    listing.common_prefixes.push(common_prefix);
+                    continue 'pages;
+                }
+            }
+            // The object might have been deleted since it was listed
+            if let Some(data) = client.read_object(&object_id).await? {
+                listing.objects.push((key, ObjectMeta::decode(&data)?));
+            }
+            start = Some(object_id);
+        }
+    }
+}
+
+/// Start a multipart upload, returning its ID.
+pub async fn create_upload(client: &Client, key: &[u8]) -> Result<String, IoError> {
+    let upload_id = new_id();
+    client.write_object(&upload_object_id(&upload_id), key).await?;
+    Ok(upload_id)
+}
+
+fn no_upload(upload_id: &str) -> IoError {
+    IoError::new(ErrorKind::NotFound, format!("No upload {:?}", upload_id))
+}
+
+/// Check that an upload exists, for that key.
+async fn check_upload(client: &Client, key: &[u8], upload_id: &str) -> Result<(), IoError> {
+    match client.read_object(&upload_object_id(upload_id)).await? {
+        Some(upload_key) if upload_key == key => Ok(()),
+        _ => Err(no_upload(upload_id)),
+    }
+}
+
+/// The segment to write a part of an upload to.
+///
+/// Uploading the same part again replaces it, so its previous data is
+/// deleted first.
+pub async fn start_part(client: &Client, key: &[u8], upload_id: &str, part_num: u32) -> Result<SegmentWriter, IoError> {
+    check_upload(client, key, upload_id).await?;
+    if let Some(data) = client.read_object(&part_object_id(upload_id, part_num)).await? {
+        for segment in &ObjectMeta::decode(&data)?.segments {
+            delete_segment(client, segment).await?;
+        }
+    }
+    let segment_id = format!("{}.{}", upload_id, part_num);
+    Ok(SegmentWriter::new(client, segment_id.as_bytes()))
+}
+
+/// Record an uploaded part, returning its ETag.
+pub async fn finish_part(client: &Client, upload_id: &str, part_num: u32, writer: SegmentWriter) -> Result<String, IoError> {
+    let (segment, hash) = writer.finish().await?;
+    let meta = ObjectMeta {
+        size: segment.size,
+        modified: now(),
+        etag: etag(&hash),
+        segments: vec![segment],
+    };
+    client.write_object(&part_object_id(upload_id, part_num), &meta.encode()).await?;
+    Ok(meta.etag)
+}
+
+/// The parts uploaded so far, by number.
+async fn list_parts(client: &Client, upload_id: &str) -> Result<Vec<(u32, ObjectMeta)>, IoError> {
+    let mut prefix = upload_object_id(upload_id).0;
+    prefix.push(b':');
+    let mut parts = Vec::new();
+    let mut start_after = None;
+    loop {
+        let page = client.list_objects(&prefix, start_after.as_ref(), LIST_PAGE_SIZE).await?;
+        let last = match page.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        for object_id in page {
+            let part_num = std::str::from_utf8(&object_id.0[prefix.len()..]).ok()
+                .and_then(|n| n.parse().ok());
+            if let (Some(part_num), Some(data)) = (part_num, client.read_object(&object_id).await?) {
+                parts.push((part_num, ObjectMeta::decode(&data)?));
+            }
+        }
+        start_after = Some(last);
+    }
+    parts.sort_by_key(|&(n, _)| n);
+    Ok(parts)
+}
+
+/// Complete an upload, making the parts an object.
+///
+/// `parts` are the part numbers in order, with their ETags. Parts that were
+/// uploaded but are not used are deleted.
+pub async fn complete_upload(client: &Client, key: &[u8], upload_id: &str, parts: &[(u32, String)]) -> Result<ObjectMeta, IoError> {
+    check_upload(client, key, upload_id).await?;
+    let uploaded = list_parts(client, upload_id).await?;
+
+    let mut meta = ObjectMeta { size: 0, modified: now(), etag: String::new(), segments: Vec::new() };
+    let mut hasher = Sha256::new();
+    for (i, (part_num, etag)) in parts.iter().enumerate() {
+        if i > 0 && *part_num <= parts[i - 1].0 {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Parts are not in order"));
+        }
+        let part = uploaded.iter().find(|(n, _)| n == part_num)
+            .map(|(_, p)| p)
+            .filter(|p| p.etag == *etag)
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, format!("Invalid part {}", part_num)))?;
+        meta.size += part.size;
+        meta.segments.extend(part.segments.iter().cloned());
+        hasher.update(part.etag.as_bytes());
+    }
+    meta.etag = format!("{}-{}", etag(&hasher.finalize().into()), parts.len());
+    commit_object(client, key, &meta).await?;
+
+    for (part_num, part) in &uploaded {
+        if !parts.iter().any(|(n, _)| n == part_num) {
+            for segment in &part.segments {
+                delete_segment(client, segment).await?;
+            }
+        }
+        ignore_not_found(client.delete_object(&part_object_id(upload_id, *part_num)).await)?;
+    }
+    ignore_not_found(client.delete_object(&upload_object_id(upload_id)).await)?;
+    Ok(meta)
+}
+
+/// Abort an upload, deleting its parts.
+pub async fn abort_upload(client: &Client, key: &[u8], upload_id: &str) -> Result<(), IoError> {
+    check_upload(client, key, upload_id).await?;
+    for (part_num, part) in list_parts(client, upload_id).await? {
+        for segment in &part.segments {
+            delete_segment(client, segment).await?;
+        }
+        ignore_not_found(client.delete_object(&part_object_id(upload_id, part_num)).await)?;
+    }
+    ignore_not_found(client.delete_object(&upload_object_id(upload_id)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ObjectId;
+    use super::{CHUNK_SIZE, ObjectMeta, Segment, chunk_object_id, chunk_reads, chunk_segment, meta_object_id};
+
+    #[test]
+    fn test_metadata() {
+        let meta = ObjectMeta {
+            size: 3 << 20,
+            modified: 1_600_000_000,
+            etag: "0123456789abcdef0123456789abcdef-2".to_owned(),
+            segments: vec![
+                Segment { id: b"upload.1".to_vec(), size: 2 << 20 },
+                Segment { id: b"upload.2".to_vec(), size: 1 << 20 },
+            ],
+        };
+        let encoded = meta.encode();
+        assert_eq!(ObjectMeta::decode(&encoded).unwrap(), meta);
+
+        let empty = ObjectMeta { size: 0, modified: 0, etag: String::new(), segments: vec![] };
+        assert_eq!(ObjectMeta::decode(&empty.encode()).unwrap(), empty);
+
+        // Truncated, too long, bad version, wrong size
+        assert!(ObjectMeta::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut long = encoded.clone();
+        long.push(0);
+        assert!(ObjectMeta::decode(&long).is_err());
+        let mut bad = encoded.clone();
+        bad[0] = 2;
+        assert!(ObjectMeta::decode(&bad).is_err());
+        let wrong = ObjectMeta { size: 1, ..meta };
+        assert!(ObjectMeta::decode(&wrong.encode()).is_err());
+    }
+
+    #[test]
+    fn test_chunk_reads() {
+        let segments = vec![
+            Segment { id: b"a".to_vec(), size: CHUNK_SIZE as u64 + 100 },
+            Segment { id: b"b".to_vec(), size: 50 },
+            Segment { id: b"c".to_vec(), size: 0 },
+            Segment { id: b"d".to_vec(), size: 10 },
+        ];
+        let reads = |start, end| {
+            chunk_reads(&segments, start, end).into_iter()
+                .map(|(o, offset, len)| (String::from_utf8(o.0).unwrap(), offset, len))
+                .collect::<Vec<_>>()
+        };
+        let chunk = CHUNK_SIZE as u32;
+
+        assert_eq!(
+            reads(0, CHUNK_SIZE as u64 + 160),
+            vec![
+                ("d:a:0".to_owned(), 0, chunk),
+                ("d:a:1".to_owned(), 0, 100),
+                ("d:b:0".to_owned(), 0, 50),
+                ("d:d:0".to_owned(), 0, 10),
+            ],
+        );
+        assert_eq!(
+            reads(CHUNK_SIZE as u64 - 10, CHUNK_SIZE as u64 + 120),
+            vec![
+                ("d:a:0".to_owned(), chunk - 10, 10),
+                ("d:a:1".to_owned(), 0, 100),
+                ("d:b:0".to_owned(), 0, 20),
+            ],
+        );
+        assert_eq!(reads(5, 6), vec![("d:a:0".to_owned(), 5, 1)]);
+        assert_eq!(reads(5, 5), vec![]);
+        assert_eq!(chunk_reads(&[], 0, 0), vec![] as Vec<(ObjectId, u32, u32)>);
+    }
+
+    #[test]
+    fn test_chunk_segment() {
+        assert_eq!(chunk_segment(&chunk_object_id(b"upload.3", 12)), Some(&b"upload.3"[..]));
+        assert_eq!(chunk_segment(&ObjectId(b"d:a:b:0".to_vec())), Some(&b"a:b"[..]));
+        assert_eq!(chunk_segment(&ObjectId(b"d:nochunk".to_vec())), None);
+        assert_eq!(chunk_segment(&meta_object_id(b"d:a:0")), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! The little XML the S3 API needs.
+
+use std::io::{Error as IoError, ErrorKind};
+
+pub const HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+pub const NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Escape text for XML content and attributes.
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Undo `escape()`, for the content of elements sent by clients.
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// The error document.
+pub fn error(code: &str, message: &str, resource: &str) -> String {
+    format!(
+        "{}<Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource></Error>",
+        HEADER, code, escape(message), escape(resource),
+    )
+}
+
+/// Find the content of the next element `tag`, and what follows it.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<(&'a str, &'a str)> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some((&xml[start..end], &xml[end + close.len()..]))
+}
+
+/// Read the parts listed in a `CompleteMultipartUpload` request, as part
+/// number and ETag (without the quotes).
+pub fn parse_complete_upload(body: &str) -> Result<Vec<(u32, String)>, IoError> {
+    let malformed = || IoError::new(ErrorKind::InvalidInput, "Malformed CompleteMultipartUpload");
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while let Some((part, next)) = element(rest, "Part") {
+        let (number, _) = element(part, "PartNumber").ok_or_else(malformed)?;
+        let number = number.trim().parse().map_err(|_| malformed())?;
+        let (etag, _) = element(part, "ETag").ok_or_else(malformed)?;
+        let etag = unescape(etag.trim());
+        parts.push((number, etag.trim_matches('"').to_owned()));
+        rest = next;
+    }
+    if parts.is_empty() {
+        return Err(malformed());
+    }
+    Ok(parts)
+}
+
+/// Read the keys listed in a `Delete` request (for several objects), and
+/// whether it asks for quiet mode.
+pub fn parse_delete_objects(body: &str) -> Result<(Vec<String>, bool), IoError> {
+    let malformed = || IoError::new(ErrorKind::InvalidInput, "Malformed Delete");
+    let mut keys = Vec::new();
+    let mut rest = body;
+    while let Some((object, next)) = element(rest, "Object") {
+        let (key, _) = element(object, "Key").ok_or_else(malformed)?;
+        keys.push(unescape(key));
+        rest = next;
+    }
+    if keys.is_empty() {
+        return Err(malformed());
+    }
+    let quiet = element(body, "Quiet").is_some_and(|(q, _)| q.trim() == "true");
+    Ok((keys, quiet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, parse_complete_upload, parse_delete_objects};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a<b>&\"c'"), "a&lt;b&gt;&amp;&quot;c&apos;");
+        assert_eq!(escape("dir/é"), "dir/é");
+    }
+
+    #[test]
+    fn test_complete_upload() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+              <Part><ETag>"abc"</ETag><PartNumber>1</PartNumber></Part>
+              <Part>
+                <PartNumber>3</PartNumber>
+                <ETag>&quot;def&quot;</ETag>
+              </Part>
+            </CompleteMultipartUpload>"#;
+        assert_eq!(
+            parse_complete_upload(body).unwrap(),
+            vec![(1, "abc".to_owned()), (3, "def".to_owned())],
+        );
+
+        assert!(parse_complete_upload("<CompleteMultipartUpload/>").is_err());
+        assert!(parse_complete_upload("<Part><PartNumber>x</PartNumber><ETag>a</ETag></Part>").is_err());
+        assert!(parse_complete_upload("<Part><PartNumber>1</PartNumber></Part>").is_err());
+    }
+
+    #[test]
+    fn test_delete_objects() {
+        let body = r#"<Delete xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+            <Object><Key>a&amp;b</Key></Object><Object><Key>dir/c</Key><VersionId>1</VersionId></Object>
+            <Quiet>true</Quiet></Delete>"#;
+        assert_eq!(
+            parse_delete_objects(body).unwrap(),
+            (vec!["a&b".to_owned(), "dir/c".to_owned()], true),
+        );
+        assert_eq!(
+            parse_delete_objects("<Delete><Object><Key>a</Key></Object></Delete>").unwrap(),
+            (vec!["a".to_owned()], false),
+        );
+        assert!(parse_delete_objects("<Delete></Delete>").is_err());
+    }
+}
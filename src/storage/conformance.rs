@@ -0,0 +1,239 @@
+//! Properties every storage backend must have, checked on random operations.
+//!
+//! `check_backend()` runs sequences of operations generated by proptest on a
+//! backend and on a simple model, comparing them after each step, then has
+//! threads use the backend at the same time. Backends, and wrappers around
+//! them, call it from their tests next to `test_backend()`. Backends outside
+//! this crate can do the same with the `conformance` feature.
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::Error as IoError;
+
+use crate::{MAX_OBJECT_ID_LEN, ObjectId, PoolName};
+use super::StorageBackend;
+
+/// Objects the operations pick from, few so they often hit the same one.
+const OBJECTS: usize = 3;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Write(usize, Vec<u8>),
+    WritePart(usize, usize, Vec<u8>),
+    Append(usize, Vec<u8>),
+    Truncate(usize, usize),
+    Delete(usize),
+    /// Compare-and-swap, expecting the current content or something else.
+    CompareAndSwap(usize, bool, Vec<u8>),
+    /// Conditional write, expecting the current version or another one.
+    WriteIfVersion(usize, bool, Vec<u8>),
+}
+
+fn data() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..32)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (0..OBJECTS, data()).prop_map(|(o, d)| Op::Write(o, d)),
+        (0..OBJECTS, 0..48usize, data()).prop_map(|(o, off, d)| Op::WritePart(o, off, d)),
+        (0..OBJECTS, data()).prop_map(|(o, d)| Op::Append(o, d)),
+        (0..OBJECTS, 0..48usize).prop_map(|(o, len)| Op::Truncate(o, len)),
+        (0..OBJECTS).prop_map(Op::Delete),
+        (0..OBJECTS, any::<bool>(), data()).prop_map(|(o, m, d)| Op::CompareAndSwap(o, m, d)),
+        (0..OBJECTS, any::<bool>(), data()).prop_map(|(o, m, d)| Op::WriteIfVersion(o, m, d)),
+    ]
+}
+
+fn object_id(object: usize) -> ObjectId {
+    let mut id = format!("object{}", object).into_bytes();
+    // The last one is as long as IDs get, which backends might store
+    // differently
+    if object == OBJECTS - 1 {
+        id.resize(MAX_OBJECT_ID_LEN, b'.');
+    }
+    ObjectId(id)
+}
+
+fn io<T>(result: Result<T, IoError>) -> Result<T, TestCaseError> {
+    result.map_err(|e| TestCaseError::fail(format!("Backend error: {}", e)))
+}
+
+/// What the backend should hold: the content and version of objects.
+type Model = BTreeMap<usize, (Vec<u8>, u64)>;
+
+/// Check the version returned by a write, and record it.
+fn written(model: &mut Model, object: usize, version: u64) -> Result<&mut Vec<u8>, TestCaseError> {
+    let entry = model.entry(object).or_default();
+    prop_assert!(version > entry.1, "version {} after {}", version, entry.1);
+    entry.1 = version;
+    Ok(&mut entry.0)
+}
+
+/// Apply an operation to the backend and the model.
+fn apply<S: StorageBackend>(storage: &S, pool: &PoolName, model: &mut Model, op: &Op) -> Result<(), TestCaseError> {
+    match *op {
+        Op::Write(object, ref data) => {
+            let version = io(storage.write_object(pool, &object_id(object), data))?;
+            *written(model, object, version)? = data.clone();
+        }
+        Op::WritePart(object, offset, ref data) => {
+            let version = io(storage.write_part(pool, &object_id(object), offset, data))?;
+            let value = written(model, object, version)?;
+            value.resize(value.len().max(offset + data.len()), 0);
+            value[offset..offset + data.len()].copy_from_slice(data);
+        }
+        Op::Append(object, ref data) => {
+            let version = io(storage.append_object(pool, &object_id(object), data))?;
+            written(model, object, version)?.extend_from_slice(data);
+        }
+        Op::Truncate(object, len) => {
+            let version = io(storage.truncate_object(pool, &object_id(object), len))?;
+            prop_assert_eq!(version.is_some(), model.contains_key(&object));
+            if let Some(version) = version {
+                written(model, object, version)?.resize(len, 0);
+            }
+        }
+        Op::Delete(object) => {
+            // Deleting again, or something that doesn't exist, is fine
+            io(storage.delete_object(pool, &object_id(object)))?;
+            io(storage.delete_object(pool, &object_id(object)))?;
+            model.remove(&object);
+        }
+        Op::CompareAndSwap(object, matching, ref data) => {
+            let current = model.get(&object).map(|(value, _)| value.clone());
+            let expected = match (matching, current) {
+                (true, current) => current,
+                (false, Some(mut current)) => {
+                    current.push(0);
+                    Some(current)
+                }
+                (false, None) => Some(Vec::new()),
+            };
+            let version = io(storage.compare_and_swap(pool, &object_id(object), expected.as_deref(), data))?;
+            prop_assert_eq!(version.is_some(), matching);
+            if let Some(version) = version {
+                *written(model, object, version)? = data.clone();
+            }
+        }
+        Op::WriteIfVersion(object, matching, ref data) => {
+            let current = model.get(&object).map(|&(_, version)| version);
+            let expected = match (matching, current) {
+                (true, current) => current,
+                (false, Some(current)) => Some(current + 1),
+                (false, None) => Some(0),
+            };
+            let version = io(storage.write_if_version(pool, &object_id(object), expected, data))?;
+            prop_assert_eq!(version.is_some(), matching);
+            if let Some(version) = version {
+                *written(model, object, version)? = data.clone();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check an object reads back the same as the model, whole and in parts.
+fn check_object<S: StorageBackend>(storage: &S, pool: &PoolName, object: usize, model: &Model) -> Result<(), TestCaseError> {
+    let id = object_id(object);
+    prop_assert_eq!(io(storage.get_version(pool, &id))?, model.get(&object).map(|&(_, version)| version));
+    let expected = model.get(&object).map(|(value, _)| &value[..]);
+    let value = io(storage.read_object(pool, &id))?;
+    prop_assert_eq!(value.as_deref(), expected);
+    prop_assert_eq!(io(storage.object_size(pool, &id))?, expected.map(|v| v.len() as u64));
+    let len = expected.map(|v| v.len()).unwrap_or(0);
+    for offset in [0, len / 2, len, len + 5] {
+        for part_len in [0, 1, len / 2 + 1, len + 1] {
+            let part = io(storage.read_part(pool, &id, offset, part_len))?;
+            let expected = expected.map(|v| &v[offset.min(len)..(offset + part_len).min(len)]);
+            prop_assert_eq!(part.as_deref(), expected, "part at {} of length {}", offset, part_len);
+        }
+    }
+    Ok(())
+}
+
+/// Check that a backend behaves like a map of byte strings.
+///
+/// Reads, whole or in parts, see every change made before, versions increase
+/// with every write, deleting is idempotent, and concurrent changes don't get
+/// lost or torn. Each case uses a new pool, so the backend can be shared.
+pub fn check_backend<S: StorageBackend>(storage: &S) {
+    let case = Cell::new(0);
+    let mut runner = TestRunner::new(Config {
+        cases: 64,
+        failure_persistence: None,
+        ..Config::default()
+    });
+    let result = runner.run(&prop::collection::vec(op(), 1..40), |ops| {
+        case.set(case.get() + 1);
+        let pool = PoolName(format!("conformance{}", case.get()));
+        let mut model = Model::new();
+        for op in &ops {
+            apply(storage, &pool, &mut model, op)?;
+            for object in 0..OBJECTS {
+                check_object(storage, &pool, object, &model)?;
+            }
+        }
+
+        // Listing shows exactly the objects that exist
+        let list = io(storage.list_objects(&pool, b"", None, 10))?;
+        let expected: Vec<ObjectId> = model.keys().map(|&o| object_id(o)).collect();
+        prop_assert_eq!(list, expected);
+        Ok(())
+    });
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+
+    check_concurrent(storage);
+}
+
+/// Have threads write, append and read the same objects at the same time.
+fn check_concurrent<S: StorageBackend>(storage: &S) {
+    const THREADS: u8 = 4;
+    const ROUNDS: usize = 25;
+    const BLOCK: usize = 16;
+
+    let pool = PoolName("concurrent".to_owned());
+    let shared = ObjectId(b"shared".to_vec());
+    let log = ObjectId(b"log".to_vec());
+    let parts = ObjectId(b"parts".to_vec());
+
+    std::thread::scope(|scope| {
+        for thread in 1..=THREADS {
+            let (pool, shared, log, parts) = (&pool, &shared, &log, &parts);
+            scope.spawn(move || {
+                let value = vec![thread; 1000 + thread as usize * 100];
+                for _ in 0..ROUNDS {
+                    // Whole writes replace each other, reads never see a mix
+                    storage.write_object(pool, shared, &value).unwrap();
+                    let read = storage.read_object(pool, shared).unwrap().unwrap();
+                    let byte = read[0];
+                    assert!((1..=THREADS).contains(&byte));
+                    assert_eq!(read, vec![byte; 1000 + byte as usize * 100]);
+
+                    storage.append_object(pool, log, &[thread; BLOCK]).unwrap();
+                    let offset = (thread - 1) as usize * BLOCK;
+                    storage.write_part(pool, parts, offset, &[thread; BLOCK]).unwrap();
+                }
+            });
+        }
+    });
+
+    // No append got lost or mixed with another
+    let log = storage.read_object(&pool, &log).unwrap().unwrap();
+    assert_eq!(log.len(), THREADS as usize * ROUNDS * BLOCK);
+    let mut counts = [0; THREADS as usize];
+    for block in log.chunks(BLOCK) {
+        assert!(block.iter().all(|&b| b == block[0]));
+        counts[block[0] as usize - 1] += 1;
+    }
+    assert_eq!(counts, [ROUNDS; THREADS as usize]);
+
+    // Writes to different parts don't overwrite each other
+    let parts = storage.read_object(&pool, &parts).unwrap().unwrap();
+    let expected: Vec<u8> = (1..=THREADS).flat_map(|t| [t; BLOCK]).collect();
+    assert_eq!(parts, expected);
+}
@@ -0,0 +1,883 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+
+use crate::{DeviceId, ObjectId, PoolName, decode_hex, encode_hex};
+use super::{
+    BackendStats, Problem, ProblemKind, StorageBackend, VerifyReport, decode_tags, encode_tags, free_space,
+    next_version, open_store_dir, read_device_id,
+};
+
+/// Longest object name stored as its file name, so the file name
+/// (hexadecimal plus suffix) stays under the usual limit of 255 bytes.
+///
+/// Longer names are stored under the hash of the name, see `file_name()`.
+const MAX_NAME_LEN: usize = 120;
+
+/// How much of a long name is kept at the start of its file name, so files
+/// are still easy to find.
+const HASHED_NAME_PREFIX: usize = 56;
+
+/// Suffix of the file holding the full name of an object stored under a
+/// hash.
+const NAME_SUFFIX: &str = ".name";
+
+/// Suffix of the file holding the expiry time of an object that has one.
+const EXPIRES_SUFFIX: &str = ".expires";
+
+/// Suffix of the file holding the tags of an object that has some.
+const TAGS_SUFFIX: &str = ".tags";
+
+/// Suffix of the file holding the version of an object.
+const VERSION_SUFFIX: &str = ".version";
+
+/// Suffix of the file a whole object is written to before replacing it.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// The name of the file holding an object.
+///
+/// This is the object name in hexadecimal, or for a name longer than
+/// `MAX_NAME_LEN`, its start in hexadecimal and its SHA-256 hash after a dash.
+/// The full name is then kept in a file next to the object.
+fn file_name(object_id: &[u8]) -> String {
+    if object_id.len() <= MAX_NAME_LEN {
+        encode_hex(object_id)
+    } else {
+        format!("{}-{}", encode_hex(&object_id[..HASHED_NAME_PREFIX]), encode_hex(&Sha256::digest(object_id)))
+    }
+}
+
+/// Whether this is the file name of an object stored under a hash.
+fn is_hashed_name(name: &str) -> bool {
+    match name.split_once('-') {
+        Some((start, hash)) => {
+            start.len() == HASHED_NAME_PREFIX * 2 && decode_hex(start).is_some() && hash.len() == 64 && decode_hex(hash).is_some()
+        }
+        None => false,
+    }
+}
+
+/// Whether this is the file name of an object, rather than one of the files
+/// next to it.
+fn is_object_name(name: &str) -> bool {
+    decode_hex(name).is_some() || is_hashed_name(name)
+}
+
+fn not_found_as_none<T>(result: Result<T, IoError>) -> Result<Option<T>, IoError> {
+    match result {
+        Ok(r) => Ok(Some(r)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), IoError> {
+    not_found_as_none(std::fs::remove_file(path)).map(|_| ())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Move a file over another.
+///
+/// The standard library opens files with `FILE_SHARE_DELETE`, so on Windows
+/// too an object can be replaced while it is being read. Other programs, such
+/// as virus scanners, can hold it open without that flag for a moment, so the
+/// rename is retried for a bit.
+fn replace_file(from: &Path, to: &Path) -> Result<(), IoError> {
+    #[cfg(windows)]
+    for _ in 0..10 {
+        match std::fs::rename(from, to) {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => std::thread::sleep(Duration::from_millis(10)),
+            result => return result,
+        }
+    }
+    std::fs::rename(from, to)
+}
+
+/// How to fix a problem found by `FileStore::check_entry()`.
+enum Fix {
+    /// Delete the object, with its expiry, tags and version.
+    RemoveObject,
+    RemoveFile,
+}
+
+/// A storage backend keeping each object in a file.
+///
+/// Objects are in `pools/<pool>/<name>`, with the names of pools and objects
+/// in hexadecimal, so any name is valid. Names too long for that are stored
+/// under their hash, with the full name in a file next to the object. An
+/// object's expiry time, if set, is in a file next to it, in seconds since the
+/// Unix epoch, and so are its tags, one per line, and its version.
+pub struct FileStore {
+    path: PathBuf,
+    read_only: bool,
+    /// Held while changing objects, so read-modify-write operations are
+    /// atomic.
+    write_lock: Mutex<()>,
+    /// Files and directories changed since the last flush.
+    dirty: Mutex<HashSet<PathBuf>>,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<FileStore, IoError> {
+        Self::open_with(path, false)
+    }
+
+    /// Open the store read-only.
+    ///
+    /// Writes are refused, and expired objects are hidden but not deleted, so
+    /// this doesn't change anything on disk. It can be used while a daemon is
+    /// running on the same store.
+    pub fn open_read_only(path: &Path) -> Result<FileStore, IoError> {
+        Self::open_with(path, true)
+    }
+
+    fn open_with(path: &Path, read_only: bool) -> Result<FileStore, IoError> {
+        if !path.join("pools").is_dir() {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Not a file store"));
+        }
+        // Windows limits paths to 260 characters, unless they are verbatim
+        // ("\\?\C:\..."), which is what canonicalize() returns there
+        #[cfg(windows)]
+        let path = &path.canonicalize()?;
+        Ok(FileStore {
+            path: path.to_owned(),
+            read_only,
+            write_lock: Mutex::new(()),
+            dirty: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), IoError> {
+        if self.read_only {
+            Err(IoError::new(ErrorKind::PermissionDenied, "Store is opened read-only"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pool_dir(&self, pool: &PoolName) -> PathBuf {
+        self.path.join("pools").join(encode_hex(pool.0.as_bytes()))
+    }
+
+    fn object_path(&self, pool: &PoolName, object_id: &ObjectId) -> PathBuf {
+        self.pool_dir(pool).join(file_name(&object_id.0))
+    }
+
+    /// Write the full name of an object stored under a hash, before writing
+    /// the object, with the write lock held.
+    fn write_name(&self, path: &Path, object_id: &ObjectId) -> Result<(), IoError> {
+        if object_id.0.len() <= MAX_NAME_LEN {
+            return Ok(());
+        }
+        let name_path = with_suffix(path, NAME_SUFFIX);
+        if !name_path.is_file() {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&name_path, &object_id.0)?;
+            self.mark_dirty(&name_path);
+        }
+        Ok(())
+    }
+
+    /// Read the full name of an object stored under a hash, `None` if it is
+    /// missing or doesn't match the file name.
+    fn read_name(path: &Path) -> Result<Option<Vec<u8>>, IoError> {
+        let name = not_found_as_none(std::fs::read(with_suffix(path, NAME_SUFFIX)))?;
+        let stored_as = path.file_name().and_then(|n| n.to_str());
+        Ok(name.filter(|name| Some(&file_name(name)[..]) == stored_as))
+    }
+
+    fn mark_dirty(&self, path: &Path) {
+        let mut dirty = self.dirty.lock().unwrap();
+        dirty.insert(path.to_owned());
+        if let Some(dir) = path.parent() {
+            dirty.insert(dir.to_owned());
+        }
+    }
+
+    /// Check an entry of a pool directory, counting the objects.
+    ///
+    /// Returns the problem, and what to remove to fix it if anything.
+    fn check_entry(entry: &std::fs::DirEntry, report: &mut VerifyReport) -> Result<Option<(ProblemKind, String, Option<Fix>)>, IoError> {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let name = file_name.to_str().unwrap_or("");
+        if !entry.file_type()?.is_file() {
+            return Ok(Some((ProblemKind::Orphaned, format!("Unknown entry {}", path.display()), None)));
+        }
+
+        if is_object_name(name) {
+            report.objects += 1;
+            if is_hashed_name(name) && Self::read_name(&path)?.is_none() {
+                return Ok(Some((ProblemKind::Corrupt, format!("Missing or invalid name of {}", path.display()), Some(Fix::RemoveObject))));
+            }
+            return Ok(match File::open(&path).and_then(|mut file| std::io::copy(&mut file, &mut std::io::sink())) {
+                Ok(_) => None,
+                Err(e) => Some((ProblemKind::Corrupt, format!("Can't read {}: {}", path.display(), e), Some(Fix::RemoveObject))),
+            });
+        }
+        if let Some(object) = name.strip_suffix(EXPIRES_SUFFIX).filter(|o| is_object_name(o)) {
+            let object_path = path.with_file_name(object);
+            return Ok(if !object_path.is_file() {
+                Some((ProblemKind::Orphaned, format!("Expiry of missing object {}", path.display()), Some(Fix::RemoveFile)))
+            } else if Self::read_expiry(&object_path).is_err() {
+                Some((ProblemKind::Corrupt, format!("Invalid expiry {}", path.display()), Some(Fix::RemoveFile)))
+            } else {
+                None
+            });
+        }
+        if let Some(object) = name.strip_suffix(TAGS_SUFFIX).filter(|o| is_object_name(o)) {
+            let object_path = path.with_file_name(object);
+            return Ok(if !object_path.is_file() {
+                Some((ProblemKind::Orphaned, format!("Tags of missing object {}", path.display()), Some(Fix::RemoveFile)))
+            } else if Self::read_tags(&object_path).is_err() {
+                Some((ProblemKind::Corrupt, format!("Invalid tags {}", path.display()), Some(Fix::RemoveFile)))
+            } else {
+                None
+            });
+        }
+        if let Some(object) = name.strip_suffix(VERSION_SUFFIX).filter(|o| is_object_name(o)) {
+            let object_path = path.with_file_name(object);
+            return Ok(if !object_path.is_file() {
+                Some((ProblemKind::Orphaned, format!("Version of missing object {}", path.display()), Some(Fix::RemoveFile)))
+            } else if Self::read_version(&object_path).is_err() {
+                Some((ProblemKind::Corrupt, format!("Invalid version {}", path.display()), Some(Fix::RemoveFile)))
+            } else {
+                None
+            });
+        }
+        if let Some(object) = name.strip_suffix(NAME_SUFFIX).filter(|o| is_hashed_name(o)) {
+            return Ok(if !path.with_file_name(object).is_file() {
+                Some((ProblemKind::Orphaned, format!("Name of missing object {}", path.display()), Some(Fix::RemoveFile)))
+            } else {
+                None
+            });
+        }
+        if name.strip_suffix(TMP_SUFFIX).is_some_and(is_object_name) {
+            return Ok(Some((ProblemKind::Orphaned, format!("Interrupted write {}", path.display()), Some(Fix::RemoveFile))));
+        }
+        Ok(Some((ProblemKind::Orphaned, format!("Unknown entry {}", path.display()), None)))
+    }
+
+    fn read_expiry(path: &Path) -> Result<Option<SystemTime>, IoError> {
+        let text = match not_found_as_none(std::fs::read_to_string(with_suffix(path, EXPIRES_SUFFIX)))? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        let secs: u64 = text.trim().parse()
+            .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid expiry file"))?;
+        Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
+    fn read_tags(path: &Path) -> Result<Vec<String>, IoError> {
+        match not_found_as_none(std::fs::read(with_suffix(path, TAGS_SUFFIX)))? {
+            Some(data) => decode_tags(&data),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Read the version of an object, `None` if it has no version file.
+    fn read_version(path: &Path) -> Result<Option<u64>, IoError> {
+        let text = match not_found_as_none(std::fs::read_to_string(with_suffix(path, VERSION_SUFFIX)))? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        let version = text.trim().parse()
+            .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid version file"))?;
+        Ok(Some(version))
+    }
+
+    /// Give a new version to an object that was just written, with the write
+    /// lock held.
+    fn bump_version(&self, path: &Path) -> Result<u64, IoError> {
+        let version = next_version(Self::read_version(path)?);
+        let version_path = with_suffix(path, VERSION_SUFFIX);
+        std::fs::write(&version_path, version.to_string())?;
+        self.mark_dirty(&version_path);
+        Ok(version)
+    }
+
+    /// Get the version of an object, with the write lock held.
+    fn current_version(path: &Path) -> Result<Option<u64>, IoError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(Self::read_version(path)?.unwrap_or(0)))
+    }
+
+    /// Delete the object if it has expired, with the write lock held.
+    ///
+    /// Returns whether it had. In read-only mode, nothing gets deleted.
+    fn check_expired(&self, path: &Path) -> Result<bool, IoError> {
+        match Self::read_expiry(path)? {
+            Some(expires) if expires <= SystemTime::now() => {
+                if !self.read_only {
+                    self.remove(path)?;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Delete the object if it has expired, taking the write lock if needed.
+    fn check_expired_locked(&self, path: &Path) -> Result<bool, IoError> {
+        match Self::read_expiry(path)? {
+            Some(expires) if expires <= SystemTime::now() => {
+                let _lock = self.write_lock.lock().unwrap();
+                self.check_expired(path)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), IoError> {
+        remove_if_exists(path)?;
+        remove_if_exists(&with_suffix(path, EXPIRES_SUFFIX))?;
+        remove_if_exists(&with_suffix(path, TAGS_SUFFIX))?;
+        remove_if_exists(&with_suffix(path, VERSION_SUFFIX))?;
+        remove_if_exists(&with_suffix(path, NAME_SUFFIX))?;
+        self.mark_dirty(path);
+        Ok(())
+    }
+
+    /// Replace the content of an object, clearing its expiry and tags.
+    ///
+    /// Returns the new version.
+    fn replace(&self, path: &Path, data: &[u8]) -> Result<u64, IoError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = with_suffix(path, TMP_SUFFIX);
+        std::fs::write(&tmp, data)?;
+        replace_file(&tmp, path)?;
+        remove_if_exists(&with_suffix(path, EXPIRES_SUFFIX))?;
+        remove_if_exists(&with_suffix(path, TAGS_SUFFIX))?;
+        self.mark_dirty(path);
+        self.bump_version(path)
+    }
+
+    /// Open an object for writing, creating it and its pool if needed.
+    fn open_for_write(&self, path: &Path, options: &OpenOptions) -> Result<File, IoError> {
+        match options.open(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                options.open(path)
+            }
+            r => r,
+        }
+    }
+
+    /// The objects in a pool directory, as `(name, path)`.
+    fn pool_objects(dir: &Path) -> Result<Vec<(Vec<u8>, PathBuf)>, IoError> {
+        let entries = match not_found_as_none(std::fs::read_dir(dir))? {
+            Some(entries) => entries,
+            None => return Ok(Vec::new()),
+        };
+        let mut objects = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            // Skips expiry, tags, version, name and temporary files, which
+            // have a suffix
+            let entry_name = entry.file_name();
+            let entry_name = entry_name.to_str().unwrap_or("");
+            if let Some(name) = decode_hex(entry_name) {
+                objects.push((name, entry.path()));
+            } else if is_hashed_name(entry_name) {
+                // Being written or deleted if the name is missing
+                if let Some(name) = Self::read_name(&entry.path())? {
+                    objects.push((name, entry.path()));
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
+
+impl StorageBackend for FileStore {
+    fn read_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<Vec<u8>>, IoError> {
+        let path = self.object_path(pool, object_id);
+        if self.check_expired_locked(&path)? {
+            return Ok(None);
+        }
+        not_found_as_none(std::fs::read(&path))
+    }
+
+    fn read_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, len: usize) -> Result<Option<Vec<u8>>, IoError> {
+        let path = self.object_path(pool, object_id);
+        if self.check_expired_locked(&path)? {
+            return Ok(None);
+        }
+        let mut file = match not_found_as_none(File::open(&path))? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut data = Vec::new();
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.write_name(&path, object_id)?;
+        self.replace(&path, data)
+    }
+
+    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<u64, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        self.write_name(&path, object_id)?;
+        let mut file = self.open_for_write(&path, OpenOptions::new().write(true).create(true).truncate(false))?;
+        if data.is_empty() {
+            // Writing nothing past the end still extends the object
+            if file.metadata()?.len() < offset as u64 {
+                file.set_len(offset as u64)?;
+            }
+        } else {
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(data)?;
+        }
+        self.mark_dirty(&path);
+        self.bump_version(&path)
+    }
+
+    fn delete_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<(), IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.remove(&path)
+    }
+
+    fn set_expiry(&self, pool: &PoolName, object_id: &ObjectId, expires: Option<SystemTime>) -> Result<(), IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let expiry_path = with_suffix(&path, EXPIRES_SUFFIX);
+        match expires {
+            Some(expires) if path.is_file() => {
+                let secs = expires.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                std::fs::write(&expiry_path, secs.to_string())?;
+            }
+            _ => remove_if_exists(&expiry_path)?,
+        }
+        self.mark_dirty(&expiry_path);
+        Ok(())
+    }
+
+    fn get_expiry(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<SystemTime>, IoError> {
+        Self::read_expiry(&self.object_path(pool, object_id))
+    }
+
+    fn set_tags(&self, pool: &PoolName, object_id: &ObjectId, tags: &[String]) -> Result<bool, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        if !path.is_file() {
+            return Ok(false);
+        }
+        let tags_path = with_suffix(&path, TAGS_SUFFIX);
+        if tags.is_empty() {
+            remove_if_exists(&tags_path)?;
+        } else {
+            std::fs::write(&tags_path, encode_tags(tags))?;
+        }
+        self.mark_dirty(&tags_path);
+        Ok(true)
+    }
+
+    fn get_tags(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Vec<String>, IoError> {
+        Self::read_tags(&self.object_path(pool, object_id))
+    }
+
+    fn sweep_expired(&self, now: SystemTime) -> Result<usize, IoError> {
+        if self.read_only {
+            return Ok(0);
+        }
+        let _lock = self.write_lock.lock().unwrap();
+        let mut count = 0;
+        for pool in std::fs::read_dir(self.path.join("pools"))? {
+            for (_, path) in Self::pool_objects(&pool?.path())? {
+                match Self::read_expiry(&path)? {
+                    Some(expires) if expires <= now => {
+                        self.remove(&path)?;
+                        count += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn object_size(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let path = self.object_path(pool, object_id);
+        if self.check_expired_locked(&path)? {
+            return Ok(None);
+        }
+        Ok(not_found_as_none(std::fs::metadata(&path))?.map(|m| m.len()))
+    }
+
+    fn get_version(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let path = self.object_path(pool, object_id);
+        let _lock = self.write_lock.lock().unwrap();
+        if self.check_expired(&path)? {
+            return Ok(None);
+        }
+        Self::current_version(&path)
+    }
+
+    fn list_objects(&self, pool: &PoolName, prefix: &[u8], start_after: Option<&ObjectId>, limit: usize) -> Result<Vec<ObjectId>, IoError> {
+        let now = SystemTime::now();
+        let mut objects: Vec<(Vec<u8>, PathBuf)> = Self::pool_objects(&self.pool_dir(pool))?
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .filter(|(name, _)| start_after.is_none_or(|s| *name > s.0))
+            .collect();
+        objects.sort();
+        let mut list = Vec::new();
+        for (name, path) in objects {
+            if list.len() >= limit {
+                break;
+            }
+            match Self::read_expiry(&path)? {
+                Some(expires) if expires <= now => {}
+                _ => list.push(ObjectId(name)),
+            }
+        }
+        Ok(list)
+    }
+
+    fn append_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        self.write_name(&path, object_id)?;
+        let mut file = self.open_for_write(&path, OpenOptions::new().append(true).create(true))?;
+        file.write_all(data)?;
+        self.mark_dirty(&path);
+        self.bump_version(&path)
+    }
+
+    fn truncate_object(&self, pool: &PoolName, object_id: &ObjectId, len: usize) -> Result<Option<u64>, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        match not_found_as_none(OpenOptions::new().write(true).open(&path))? {
+            Some(file) => {
+                file.set_len(len as u64)?;
+                self.mark_dirty(&path);
+                Ok(Some(self.bump_version(&path)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_swap(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<&[u8]>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        let current = not_found_as_none(std::fs::read(&path))?;
+        if current.as_deref() != expected {
+            return Ok(None);
+        }
+        self.write_name(&path, object_id)?;
+        Ok(Some(self.replace(&path, data)?))
+    }
+
+    fn write_if_version(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<u64>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        let path = self.object_path(pool, object_id);
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.check_expired(&path)?;
+        if Self::current_version(&path)? != expected {
+            return Ok(None);
+        }
+        self.write_name(&path, object_id)?;
+        Ok(Some(self.replace(&path, data)?))
+    }
+
+    fn stats(&self) -> Result<BackendStats, IoError> {
+        let now = SystemTime::now();
+        let mut stats = BackendStats::default();
+        for pool in std::fs::read_dir(self.path.join("pools"))? {
+            let pool = pool?;
+            let name = match pool.file_name().to_str().and_then(decode_hex).and_then(|n| String::from_utf8(n).ok()) {
+                Some(name) => PoolName(name),
+                None => continue,
+            };
+            for (_, path) in Self::pool_objects(&pool.path())? {
+                if let Some(expires) = Self::read_expiry(&path)? {
+                    if expires <= now {
+                        continue;
+                    }
+                }
+                // Deleted since listed
+                let size = match not_found_as_none(std::fs::metadata(&path))? {
+                    Some(metadata) => metadata.len(),
+                    None => continue,
+                };
+                let pool_stats = stats.pools.entry(name.clone()).or_default();
+                pool_stats.objects += 1;
+                pool_stats.bytes += size;
+            }
+        }
+        stats.free_space = free_space(&self.path);
+        Ok(stats)
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        for path in dirty {
+            // Directories can only be synced this way on Unix
+            if cfg!(not(unix)) && path.is_dir() {
+                continue;
+            }
+            // Windows only flushes files opened for writing
+            let mut options = OpenOptions::new();
+            options.read(true).write(cfg!(windows));
+            if let Some(file) = not_found_as_none(options.open(&path))? {
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&self, repair: bool) -> Result<VerifyReport, IoError> {
+        if repair {
+            self.check_writable()?;
+        }
+        let _lock = self.write_lock.lock().unwrap();
+        let mut report = VerifyReport::default();
+        for pool in std::fs::read_dir(self.path.join("pools"))? {
+            let pool = pool?;
+            let is_pool = pool.file_type()?.is_dir()
+                && pool.file_name().to_str().and_then(decode_hex).is_some_and(|n| String::from_utf8(n).is_ok());
+            if !is_pool {
+                report.problems.push(Problem {
+                    kind: ProblemKind::Orphaned,
+                    description: format!("Unknown entry {}", pool.path().display()),
+                    repaired: false,
+                });
+                continue;
+            }
+
+            for entry in std::fs::read_dir(pool.path())? {
+                let entry = entry?;
+                let (kind, description, fix) = match Self::check_entry(&entry, &mut report)? {
+                    Some(problem) => problem,
+                    None => continue,
+                };
+                // Files we don't know about are left to the user
+                let repaired = repair && fix.is_some();
+                match fix {
+                    Some(Fix::RemoveObject) if repair => self.remove(&entry.path())?,
+                    Some(Fix::RemoveFile) if repair => {
+                        remove_if_exists(&entry.path())?;
+                        self.mark_dirty(&entry.path());
+                    }
+                    _ => {}
+                }
+                report.problems.push(Problem { kind, description, repaired });
+            }
+        }
+        Ok(report)
+    }
+}
+
+pub fn create_file_store(storage_dir: &Path) -> Result<(FileStore, DeviceId), IoError> {
+    let (device_id, created) = open_store_dir(storage_dir)?;
+    if created {
+        std::fs::create_dir(storage_dir.join("pools"))?;
+    }
+    Ok((FileStore::open(storage_dir)?, device_id))
+}
+
+/// Open an existing store.
+///
+/// Unlike `create_file_store()`, this never creates anything.
+pub fn open_file_store(storage_dir: &Path) -> Result<(FileStore, DeviceId), IoError> {
+    let store = FileStore::open(storage_dir)?;
+    Ok((store, read_device_id(storage_dir)?))
+}
+
+/// Open an existing store read-only.
+pub fn open_file_store_read_only(storage_dir: &Path) -> Result<(FileStore, DeviceId), IoError> {
+    let store = FileStore::open_read_only(storage_dir)?;
+    Ok((store, read_device_id(storage_dir)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::{MAX_OBJECT_ID_LEN, ObjectId, PoolName, encode_hex};
+    use crate::storage::{ProblemKind, StorageBackend};
+    use super::{FileStore, create_file_store, file_name, is_hashed_name};
+
+    #[test]
+    fn test_filestore_common() {
+        let path = TempDir::new("store_file_test").unwrap();
+        let path: &Path = path.as_ref();
+        std::fs::create_dir(path.join("pools")).unwrap();
+        let storage = FileStore::open(path).unwrap();
+        assert!(storage.stats().unwrap().free_space.is_some());
+        super::super::test_backend(storage);
+    }
+
+    #[test]
+    fn test_filestore_conformance() {
+        let path = TempDir::new("store_file_test").unwrap();
+        let path: &Path = path.as_ref();
+        std::fs::create_dir(path.join("pools")).unwrap();
+        let storage = FileStore::open(path).unwrap();
+        super::super::conformance::check_backend(&storage);
+    }
+
+    #[test]
+    fn test_filestore_reopen() {
+        let dir = TempDir::new("store_file_test").unwrap();
+        let path = dir.path().join("store");
+        let pool = PoolName("mapoule".to_owned());
+        let obj = ObjectId((b"greeting" as &[u8]).to_owned());
+
+        let (storage, device_id) = create_file_store(&path).unwrap();
+        storage.write_object(&pool, &obj, b"hello").unwrap();
+        let long = ObjectId(vec![b'a'; MAX_OBJECT_ID_LEN]);
+        storage.write_object(&pool, &long, b"long").unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let (storage, device_id2) = create_file_store(&path).unwrap();
+        assert_eq!(device_id, device_id2);
+        assert_eq!(
+            storage.read_object(&pool, &obj).unwrap().as_deref(),
+            Some(b"hello" as &[u8]),
+        );
+        assert_eq!(
+            storage.read_object(&pool, &long).unwrap().as_deref(),
+            Some(b"long" as &[u8]),
+        );
+        assert_eq!(storage.list_objects(&pool, b"a", None, 10).unwrap(), vec![long.clone()]);
+
+        // Long names are stored under their hash, and deleted with the object
+        let pool_dir = path.join("pools").join(encode_hex(b"mapoule"));
+        assert_eq!(std::fs::read_dir(&pool_dir).unwrap().count(), 5);
+        storage.delete_object(&pool, &long).unwrap();
+        assert_eq!(std::fs::read_dir(&pool_dir).unwrap().count(), 2);
+
+        // Not a file store
+        std::fs::write(dir.path().join("other"), b"").unwrap();
+        assert!(create_file_store(dir.path()).is_err());
+        assert!(is_hashed_name(&file_name(&[b'a'; 121])));
+        assert!(!is_hashed_name("61-62"));
+    }
+
+    #[test]
+    fn test_filestore_read_only() {
+        let path = TempDir::new("store_file_test").unwrap();
+        let path: &Path = path.as_ref();
+        std::fs::create_dir(path.join("pools")).unwrap();
+        let pool = PoolName("mapoule".to_owned());
+        let obj = ObjectId((b"greeting" as &[u8]).to_owned());
+        let old = ObjectId((b"old" as &[u8]).to_owned());
+
+        let storage = FileStore::open(path).unwrap();
+        storage.write_object(&pool, &obj, b"hello").unwrap();
+        storage.write_object(&pool, &old, b"bye").unwrap();
+        storage.set_expiry(&pool, &old, Some(UNIX_EPOCH + Duration::from_secs(1))).unwrap();
+
+        // Open read-only while the store is open
+        let read_only = FileStore::open_read_only(path).unwrap();
+        assert!(read_only.is_read_only());
+        assert_eq!(
+            read_only.read_object(&pool, &obj).unwrap().as_deref(),
+            Some(b"hello" as &[u8]),
+        );
+        assert!(read_only.write_object(&pool, &obj, b"bye").is_err());
+        assert!(read_only.delete_object(&pool, &obj).is_err());
+        assert!(read_only.verify(true).is_err());
+
+        // Expired objects are hidden, but left in place
+        assert_eq!(read_only.read_object(&pool, &old).unwrap(), None);
+        assert_eq!(read_only.get_version(&pool, &old).unwrap(), None);
+        assert_eq!(read_only.sweep_expired(SystemTime::now()).unwrap(), 0);
+        assert!(storage.object_path(&pool, &old).is_file());
+    }
+
+    #[test]
+    fn test_filestore_verify() {
+        let dir = TempDir::new("store_file_test").unwrap();
+        let pool = PoolName("mapoule".to_owned());
+        let (storage, _) = create_file_store(dir.path()).unwrap();
+        storage.write_object(&pool, &ObjectId(b"a".to_vec()), b"hello").unwrap();
+        storage.write_object(&pool, &ObjectId(b"b".to_vec()), b"world").unwrap();
+        assert_eq!(storage.verify(false).unwrap().problems, vec![]);
+
+        // Interrupted write, expiry and tags without object, invalid expiry,
+        // unknown file
+        let pool_dir = dir.path().join("pools").join(encode_hex(b"mapoule"));
+        std::fs::write(pool_dir.join("63.tmp"), b"partial").unwrap();
+        std::fs::write(pool_dir.join("64.expires"), b"1700000000").unwrap();
+        std::fs::write(pool_dir.join("64.tags"), b"tmp").unwrap();
+        std::fs::write(pool_dir.join("62.expires"), b"soon").unwrap();
+        std::fs::write(pool_dir.join("notes.txt"), b"").unwrap();
+
+        let report = storage.verify(false).unwrap();
+        assert_eq!(report.objects, 2);
+        let mut kinds: Vec<_> = report.problems.iter().map(|p| (p.kind == ProblemKind::Corrupt, p.repaired)).collect();
+        kinds.sort();
+        assert_eq!(kinds, vec![(false, false), (false, false), (false, false), (false, false), (true, false)]);
+        assert!(pool_dir.join("63.tmp").exists());
+
+        let report = storage.verify(true).unwrap();
+        assert_eq!(report.problems.iter().filter(|p| p.repaired).count(), 4);
+        assert!(!pool_dir.join("63.tmp").exists());
+        assert!(!pool_dir.join("64.expires").exists());
+        assert!(!pool_dir.join("64.tags").exists());
+        assert_eq!(storage.get_expiry(&pool, &ObjectId(b"b".to_vec())).unwrap(), None);
+
+        // Only the file we don't know about is left
+        let report = storage.verify(true).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].description.contains("notes.txt"));
+        assert_eq!(report.objects, 2);
+
+        // Object stored under a hash that lost its name
+        let long = ObjectId(vec![b'c'; 200]);
+        storage.write_object(&pool, &long, b"long").unwrap();
+        assert_eq!(storage.verify(false).unwrap().problems.len(), 1);
+        std::fs::remove_file(pool_dir.join(format!("{}.name", file_name(&long.0)))).unwrap();
+        assert_eq!(storage.list_objects(&pool, b"", None, 10).unwrap().len(), 2);
+        let report = storage.verify(true).unwrap();
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems.iter().any(|p| p.kind == ProblemKind::Corrupt && p.repaired));
+        assert_eq!(storage.read_object(&pool, &long).unwrap(), None);
+    }
+}
@@ -4,12 +4,59 @@ use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::io::Error as IoError;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use crate::{DeviceId, ObjectId, PoolName};
-use super::StorageBackend;
+use super::{BackendStats, Problem, ProblemKind, StorageBackend, VerifyReport, next_version};
 
 #[derive(Default)]
-struct InnerStore(HashMap<PoolName, HashMap<ObjectId, Vec<u8>>>);
+struct InnerStore {
+    objects: HashMap<PoolName, HashMap<ObjectId, Vec<u8>>>,
+    expiry: HashMap<(PoolName, ObjectId), SystemTime>,
+    tags: HashMap<(PoolName, ObjectId), Vec<String>>,
+    versions: HashMap<(PoolName, ObjectId), u64>,
+}
+
+impl InnerStore {
+    /// Delete the object if it has expired.
+    fn check_expired(&mut self, pool: &PoolName, object_id: &ObjectId) {
+        let key = (pool.clone(), object_id.clone());
+        if let Some(&expires) = self.expiry.get(&key) {
+            if expires <= SystemTime::now() {
+                self.remove(pool, object_id);
+            }
+        }
+    }
+
+    fn remove(&mut self, pool: &PoolName, object_id: &ObjectId) {
+        let key = (pool.clone(), object_id.clone());
+        self.expiry.remove(&key);
+        self.tags.remove(&key);
+        self.versions.remove(&key);
+        self.objects.get_mut(pool).map(|p| p.remove(object_id));
+    }
+
+    fn get_version(&self, pool: &PoolName, object_id: &ObjectId) -> Option<u64> {
+        self.objects.get(pool).and_then(|p| p.get(object_id))?;
+        Some(self.versions.get(&(pool.clone(), object_id.clone())).copied().unwrap_or(0))
+    }
+
+    /// Give a new version to an object that was just written.
+    fn bump_version(&mut self, pool: &PoolName, object_id: &ObjectId) -> u64 {
+        let version = self.versions.entry((pool.clone(), object_id.clone())).or_default();
+        *version = next_version(Some(*version));
+        *version
+    }
+
+    fn replace(&mut self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> u64 {
+        let key = (pool.clone(), object_id.clone());
+        self.expiry.remove(&key);
+        self.tags.remove(&key);
+        let objects = self.objects.entry(pool.to_owned()).or_default();
+        objects.insert(object_id.clone(), data.to_owned());
+        self.bump_version(pool, object_id)
+    }
+}
 
 /// A storage backend keeping all data in memory, in a HashMap.
 ///
@@ -20,29 +67,30 @@ pub struct MemStore(Arc<Mutex<InnerStore>>);
 
 impl StorageBackend for MemStore {
     fn read_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<Vec<u8>>, IoError> {
-        let store = self.0.lock().unwrap();
-        let object = store.0.get(pool).and_then(|p| p.get(&object_id));
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        let object = store.objects.get(pool).and_then(|p| p.get(&object_id));
         Ok(object.cloned())
     }
 
     fn read_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, len: usize) -> Result<Option<Vec<u8>>, IoError> {
-        let store = self.0.lock().unwrap();
-        let object = store.0.get(pool).and_then(|p| p.get(&object_id));
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        let object = store.objects.get(pool).and_then(|p| p.get(&object_id));
         let part = object.map(|o| o[o.len().min(offset)..o.len().min(offset + len)].to_owned());
         Ok(part)
     }
 
-    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError> {
+    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
         let mut store = self.0.lock().unwrap();
-        let pool = store.0.entry(pool.to_owned()).or_default();
-        pool.insert(object_id.clone(), data.to_owned());
-        Ok(())
+        Ok(store.replace(pool, object_id, data))
     }
 
-    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<(), IoError> {
+    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<u64, IoError> {
         let mut store = self.0.lock().unwrap();
-        let pool = store.0.entry(pool.to_owned()).or_default();
-        match pool.entry(object_id.to_owned()) {
+        store.check_expired(pool, object_id);
+        let objects = store.objects.entry(pool.to_owned()).or_default();
+        match objects.entry(object_id.to_owned()) {
             Entry::Occupied(mut e) => {
                 let value = e.get_mut();
                 value.resize(value.len().max(offset + data.len()), 0);
@@ -55,14 +103,196 @@ impl StorageBackend for MemStore {
                 e.insert(value);
             }
         }
-        Ok(())
+        Ok(store.bump_version(pool, object_id))
     }
 
     fn delete_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<(), IoError> {
         let mut store = self.0.lock().unwrap();
-        store.0.get_mut(pool).map(|p| p.remove(&object_id));
+        store.remove(pool, object_id);
         Ok(())
     }
+
+    fn set_expiry(&self, pool: &PoolName, object_id: &ObjectId, expires: Option<SystemTime>) -> Result<(), IoError> {
+        let mut store = self.0.lock().unwrap();
+        let exists = store.objects.get(pool).map(|p| p.contains_key(object_id)).unwrap_or(false);
+        let key = (pool.clone(), object_id.clone());
+        match expires {
+            Some(expires) if exists => {
+                store.expiry.insert(key, expires);
+            }
+            _ => {
+                store.expiry.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_expiry(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<SystemTime>, IoError> {
+        let store = self.0.lock().unwrap();
+        Ok(store.expiry.get(&(pool.clone(), object_id.clone())).cloned())
+    }
+
+    fn set_tags(&self, pool: &PoolName, object_id: &ObjectId, tags: &[String]) -> Result<bool, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        if !store.objects.get(pool).is_some_and(|p| p.contains_key(object_id)) {
+            return Ok(false);
+        }
+        let key = (pool.clone(), object_id.clone());
+        if tags.is_empty() {
+            store.tags.remove(&key);
+        } else {
+            store.tags.insert(key, tags.to_owned());
+        }
+        Ok(true)
+    }
+
+    fn get_tags(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Vec<String>, IoError> {
+        let store = self.0.lock().unwrap();
+        Ok(store.tags.get(&(pool.clone(), object_id.clone())).cloned().unwrap_or_default())
+    }
+
+    fn sweep_expired(&self, now: SystemTime) -> Result<usize, IoError> {
+        let mut store = self.0.lock().unwrap();
+        let expired: Vec<(PoolName, ObjectId)> = store.expiry
+            .iter()
+            .filter(|(_, &expires)| expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (pool, object_id) in &expired {
+            store.remove(pool, object_id);
+        }
+        Ok(expired.len())
+    }
+
+    fn object_size(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        let object = store.objects.get(pool).and_then(|p| p.get(object_id));
+        Ok(object.map(|o| o.len() as u64))
+    }
+
+    fn get_version(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        Ok(store.get_version(pool, object_id))
+    }
+
+    fn list_objects(&self, pool: &PoolName, prefix: &[u8], start_after: Option<&ObjectId>, limit: usize) -> Result<Vec<ObjectId>, IoError> {
+        let store = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        let mut list: Vec<ObjectId> = match store.objects.get(pool) {
+            Some(p) => p.keys()
+                .filter(|o| o.0.starts_with(prefix))
+                .filter(|o| start_after.is_none_or(|s| o.0 > s.0))
+                .filter(|o| match store.expiry.get(&(pool.clone(), (*o).clone())) {
+                    Some(&expires) => expires > now,
+                    None => true,
+                })
+                .cloned()
+                .collect(),
+            None => return Ok(Vec::new()),
+        };
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list.truncate(limit);
+        Ok(list)
+    }
+
+    fn append_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        let objects = store.objects.entry(pool.to_owned()).or_default();
+        let value = objects.entry(object_id.to_owned()).or_default();
+        value.extend_from_slice(data);
+        Ok(store.bump_version(pool, object_id))
+    }
+
+    fn truncate_object(&self, pool: &PoolName, object_id: &ObjectId, len: usize) -> Result<Option<u64>, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        match store.objects.get_mut(pool).and_then(|p| p.get_mut(object_id)) {
+            Some(value) => {
+                value.resize(len, 0);
+                Ok(Some(store.bump_version(pool, object_id)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_swap(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<&[u8]>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        let current = store.objects.get(pool).and_then(|p| p.get(object_id));
+        if current.map(|v| &v[..]) != expected {
+            return Ok(None);
+        }
+        Ok(Some(store.replace(pool, object_id, data)))
+    }
+
+    fn write_if_version(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<u64>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        let mut store = self.0.lock().unwrap();
+        store.check_expired(pool, object_id);
+        if store.get_version(pool, object_id) != expected {
+            return Ok(None);
+        }
+        Ok(Some(store.replace(pool, object_id, data)))
+    }
+
+    fn stats(&self) -> Result<BackendStats, IoError> {
+        let store = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        let mut stats = BackendStats::default();
+        for (pool, objects) in &store.objects {
+            for (object_id, data) in objects {
+                if let Some(&expires) = store.expiry.get(&(pool.clone(), object_id.clone())) {
+                    if expires <= now {
+                        continue;
+                    }
+                }
+                let pool_stats = stats.pools.entry(pool.clone()).or_default();
+                pool_stats.objects += 1;
+                pool_stats.bytes += data.len() as u64;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        // Nothing is ever stable
+        Ok(())
+    }
+
+    fn verify(&self, repair: bool) -> Result<VerifyReport, IoError> {
+        let mut store = self.0.lock().unwrap();
+        let mut report = VerifyReport {
+            objects: store.objects.values().map(|p| p.len() as u64).sum(),
+            problems: Vec::new(),
+        };
+        let exists = |(pool, object_id): &(PoolName, ObjectId)| store.objects.get(pool).is_some_and(|p| p.contains_key(object_id));
+        let orphaned_expiry: Vec<(PoolName, ObjectId)> = store.expiry.keys().filter(|k| !exists(k)).cloned().collect();
+        let orphaned_tags: Vec<(PoolName, ObjectId)> = store.tags.keys().filter(|k| !exists(k)).cloned().collect();
+        for key in orphaned_expiry {
+            report.problems.push(Problem {
+                kind: ProblemKind::Orphaned,
+                description: format!("Expiry of missing object {:?} in pool {}", key.1, key.0.0),
+                repaired: repair,
+            });
+            if repair {
+                store.expiry.remove(&key);
+            }
+        }
+        for key in orphaned_tags {
+            report.problems.push(Problem {
+                kind: ProblemKind::Orphaned,
+                description: format!("Tags of missing object {:?} in pool {}", key.1, key.0.0),
+                repaired: repair,
+            });
+            if repair {
+                store.tags.remove(&key);
+            }
+        }
+        Ok(report)
+    }
 }
 
 pub fn create_mem_store() -> (MemStore, DeviceId) {
@@ -85,4 +315,9 @@ mod tests {
         let storage = MemStore::default();
         super::super::test_backend(storage);
     }
+
+    #[test]
+    fn test_memstore_conformance() {
+        super::super::conformance::check_backend(&MemStore::default());
+    }
 }
@@ -1,10 +1,177 @@
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod file_store;
 pub mod mem_store;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb_store;
 
-use std::io::Error as IoError;
+use log::{info, warn};
+use rand::{Rng, thread_rng};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::{DeviceId, ObjectId, PoolName};
+
+/// Usage of a storage backend, see `StorageBackend::stats()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendStats {
+    /// Objects stored in each pool, not counting expired ones.
+    pub pools: HashMap<PoolName, PoolStats>,
+    /// Space left for data, if known.
+    pub free_space: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// What `StorageBackend::verify()` found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Objects that were read back.
+    pub objects: u64,
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// What is wrong and where.
+    pub description: String,
+    /// Whether it was fixed, which can only happen when asked to repair.
+    pub repaired: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// Data that isn't part of any object, such as what an interrupted
+    /// write left behind.
+    Orphaned,
+    /// An object or its metadata that can't be read back.
+    Corrupt,
+}
+
+/// Get the space available to unprivileged users on the filesystem of a path.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    if unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+        return None;
+    }
+    Some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Get the device ID of a store directory, setting it up with a new ID if it
+/// is empty or doesn't exist.
+///
+/// Returns the ID and whether the store was created.
+pub(crate) fn open_store_dir(storage_dir: &Path) -> Result<(DeviceId, bool), IoError> {
+    if storage_dir.exists() {
+        if !storage_dir.is_dir() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                "Storage path exists and is not a directory",
+            ));
+        }
+
+        // Check layout
+        if storage_dir.join("store.id").is_file() {
+            // It's ready to go
+            info!("Using existing store");
+            return Ok((read_device_id(storage_dir)?, false));
+        } else if std::fs::read_dir(storage_dir)?.next().is_some() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                "Storage path exists and is not an empty directory",
+            ));
+        }
+    } else {
+        // It doesn't exist, make an empty directory
+        std::fs::create_dir(storage_dir)?;
+    }
 
-use crate::{ObjectId, PoolName};
+    warn!("Creating new store");
+
+    // Generate a random device ID
+    let mut rng = thread_rng();
+    let mut bytes = [0; 16];
+    rng.fill(&mut bytes);
+    let device_id = DeviceId(bytes);
+    info!("Generated ID: {:?}", device_id);
+
+    // Write it to "store.id"
+    let mut id = File::create(storage_dir.join("store.id"))?;
+    id.write_all(&device_id.0)?;
+    Ok((device_id, true))
+}
+
+/// Get the version for a write to an object, given its current version.
+///
+/// Versions are the time of the write in microseconds, bumped past the
+/// previous version if the clock is behind, so they increase with every
+/// write to an object, and a newer write usually has a higher version even
+/// on another daemon.
+pub fn next_version(previous: Option<u64>) -> u64 {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+    match previous {
+        Some(previous) => now.max(previous.saturating_add(1)),
+        None => now,
+    }
+}
+
+/// Encode tags for storage, one per line.
+pub(crate) fn encode_tags(tags: &[String]) -> Vec<u8> {
+    tags.join("\n").into_bytes()
+}
+
+pub(crate) fn decode_tags(data: &[u8]) -> Result<Vec<String>, IoError> {
+    let text = std::str::from_utf8(data).map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid tags"))?;
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(text.split('\n').map(str::to_owned).collect())
+}
+
+/// Read the device ID of an existing store directory.
+pub(crate) fn read_device_id(storage_dir: &Path) -> Result<DeviceId, IoError> {
+    // Read device ID from "store.id"
+    let mut bytes = [0; 16];
+    let mut id = File::open(storage_dir.join("store.id"))?;
+    id.read_exact(&mut bytes)?;
+    let device_id = DeviceId(bytes);
+    info!("Read device ID {:?}", device_id);
+    Ok(device_id)
+}
 
 pub trait StorageBackend: Send + Sync {
     /// Reads a whole object.
@@ -14,13 +181,92 @@ pub trait StorageBackend: Send + Sync {
     fn read_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, len: usize) -> Result<Option<Vec<u8>>, IoError>;
 
     /// Write a whole object.
-    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError>;
+    ///
+    /// Returns the new version of the object. Every write changes it, see
+    /// `next_version()`.
+    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError>;
 
     /// Overwrite part of an object.
-    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<(), IoError>;
+    ///
+    /// Returns the new version of the object.
+    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<u64, IoError>;
 
     /// Delete an object.
     fn delete_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<(), IoError>;
+
+    /// Set or clear the time at which an object expires.
+    ///
+    /// Expired objects are deleted lazily when read, or by `sweep_expired()`.
+    /// Writing a whole object clears its expiry, writing part of it doesn't.
+    fn set_expiry(&self, pool: &PoolName, object_id: &ObjectId, expires: Option<SystemTime>) -> Result<(), IoError>;
+
+    /// Get the time at which an object expires, if set.
+    fn get_expiry(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<SystemTime>, IoError>;
+
+    /// Replace the tags of an object, which must be checked with
+    /// `check_tags()`.
+    ///
+    /// Returns whether the object exists, nothing is stored if it doesn't.
+    /// Like its expiry, writing a whole object clears its tags.
+    fn set_tags(&self, pool: &PoolName, object_id: &ObjectId, tags: &[String]) -> Result<bool, IoError>;
+
+    /// Get the tags of an object, in the order they were set.
+    fn get_tags(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Vec<String>, IoError>;
+
+    /// Delete all the objects that expired before `now`.
+    ///
+    /// Returns the number of objects that were deleted.
+    fn sweep_expired(&self, now: SystemTime) -> Result<usize, IoError>;
+
+    /// Get the size of an object.
+    fn object_size(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError>;
+
+    /// Get the version of an object.
+    ///
+    /// Objects written before versions existed have version 0.
+    fn get_version(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError>;
+
+    /// List objects whose name starts with `prefix`, in order.
+    ///
+    /// Listing starts after `start_after` if set, and returns at most `limit`
+    /// objects.
+    fn list_objects(&self, pool: &PoolName, prefix: &[u8], start_after: Option<&ObjectId>, limit: usize) -> Result<Vec<ObjectId>, IoError>;
+
+    /// Add data at the end of an object, creating it if needed.
+    ///
+    /// Returns the new version of the object.
+    fn append_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError>;
+
+    /// Shorten or zero-extend an object.
+    ///
+    /// Returns the new version, `None` if the object doesn't exist.
+    fn truncate_object(&self, pool: &PoolName, object_id: &ObjectId, len: usize) -> Result<Option<u64>, IoError>;
+
+    /// Write a whole object if its content is `expected`.
+    ///
+    /// `None` means the object must not exist. Returns the new version, `None`
+    /// if it wasn't written.
+    fn compare_and_swap(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<&[u8]>, data: &[u8]) -> Result<Option<u64>, IoError>;
+
+    /// Write a whole object if its version is `expected`.
+    ///
+    /// `None` means the object must not exist. Returns the new version, `None`
+    /// if it wasn't written.
+    fn write_if_version(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<u64>, data: &[u8]) -> Result<Option<u64>, IoError>;
+
+    /// Count the objects and bytes stored in each pool, and the free space.
+    ///
+    /// This can go over all the objects, so it shouldn't be called often.
+    fn stats(&self) -> Result<BackendStats, IoError>;
+
+    /// Make sure all the changes made so far are on stable storage.
+    fn flush(&self) -> Result<(), IoError>;
+
+    /// Read back every object and check the store is consistent.
+    ///
+    /// With `repair`, problems are fixed where possible, which means
+    /// deleting what can't be read. Otherwise nothing is changed.
+    fn verify(&self, repair: bool) -> Result<VerifyReport, IoError>;
 }
 
 #[cfg(test)]
@@ -100,4 +346,143 @@ fn test_backend<S: StorageBackend>(storage: S) {
     // Read non-existent object
     assert_eq!(storage.read_object(&pool1, &obj3).unwrap(), None);
     assert_eq!(storage.read_part(&pool1, &obj3, 3, 2).unwrap(), None);
+
+    // Expiry in the future
+    // (backends may only store whole seconds)
+    let now = SystemTime::now();
+    let later = UNIX_EPOCH + Duration::from_secs(
+        now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 3600,
+    );
+    storage.set_expiry(&pool1, &obj1, Some(later)).unwrap();
+    assert_eq!(storage.get_expiry(&pool1, &obj1).unwrap(), Some(later));
+    assert!(storage.read_object(&pool1, &obj1).unwrap().is_some());
+
+    // Writing part of the object keeps expiry, writing whole object clears it
+    storage.write_part(&pool1, &obj1, 0, b"H").unwrap();
+    assert_eq!(storage.get_expiry(&pool1, &obj1).unwrap(), Some(later));
+    storage.write_object(&pool1, &obj1, b"hello").unwrap();
+    assert_eq!(storage.get_expiry(&pool1, &obj1).unwrap(), None);
+
+    // Expired object is deleted on read
+    storage.set_expiry(&pool1, &obj1, Some(now - Duration::from_secs(10))).unwrap();
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap(), None);
+    assert_eq!(storage.read_part(&pool1, &obj1, 0, 2).unwrap(), None);
+    assert_eq!(storage.get_expiry(&pool1, &obj1).unwrap(), None);
+
+    // Sweep deletes expired objects only
+    storage.write_object(&pool1, &obj1, b"hello").unwrap();
+    storage.set_expiry(&pool1, &obj1, Some(now - Duration::from_secs(10))).unwrap();
+    storage.set_expiry(&pool1, &obj2, Some(later)).unwrap();
+    assert_eq!(storage.sweep_expired(now).unwrap(), 1);
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap(), None);
+    assert!(storage.read_object(&pool1, &obj2).unwrap().is_some());
+    assert_eq!(storage.sweep_expired(now).unwrap(), 0);
+
+    // Size
+    assert_eq!(storage.object_size(&pool1, &obj2).unwrap(), Some(7));
+    assert_eq!(storage.object_size(&pool1, &obj1).unwrap(), None);
+
+    // Append, to existing and new object
+    storage.append_object(&pool1, &obj2, b"!").unwrap();
+    assert_eq!(storage.object_size(&pool1, &obj2).unwrap(), Some(8));
+    assert_eq!(
+        storage.read_object(&pool1, &obj2).unwrap().as_deref(),
+        Some(b"\x00\x00\x00\x00\x00hi!" as &[u8]),
+    );
+    assert_eq!(storage.get_expiry(&pool1, &obj2).unwrap(), Some(later));
+    storage.append_object(&pool1, &obj3, b"abc").unwrap();
+    assert_eq!(storage.object_size(&pool1, &obj3).unwrap(), Some(3));
+
+    // Truncate, shortening and extending
+    assert!(storage.truncate_object(&pool1, &obj3, 1).unwrap().is_some());
+    assert_eq!(storage.read_object(&pool1, &obj3).unwrap().as_deref(), Some(b"a" as &[u8]));
+    assert!(storage.truncate_object(&pool1, &obj3, 3).unwrap().is_some());
+    assert_eq!(storage.read_object(&pool1, &obj3).unwrap().as_deref(), Some(b"a\x00\x00" as &[u8]));
+    assert!(storage.truncate_object(&pool1, &obj1, 3).unwrap().is_none());
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap(), None);
+
+    // Compare-and-swap
+    assert!(storage.compare_and_swap(&pool1, &obj1, Some(b"x"), b"one").unwrap().is_none());
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap(), None);
+    assert!(storage.compare_and_swap(&pool1, &obj1, None, b"one").unwrap().is_some());
+    assert!(storage.compare_and_swap(&pool1, &obj1, None, b"two").unwrap().is_none());
+    assert!(storage.compare_and_swap(&pool1, &obj1, Some(b"two"), b"three").unwrap().is_none());
+    assert!(storage.compare_and_swap(&pool1, &obj1, Some(b"one"), b"two").unwrap().is_some());
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap().as_deref(), Some(b"two" as &[u8]));
+
+    // Versions, increasing with every write, and conditional writes
+    let version = storage.get_version(&pool1, &obj1).unwrap().unwrap();
+    assert!(storage.write_if_version(&pool1, &obj1, Some(version + 1), b"three").unwrap().is_none());
+    assert!(storage.write_if_version(&pool1, &obj1, None, b"three").unwrap().is_none());
+    let written = storage.write_if_version(&pool1, &obj1, Some(version), b"three").unwrap().unwrap();
+    assert!(written > version);
+    let appended = storage.append_object(&pool1, &obj1, b"!").unwrap();
+    assert!(appended > written);
+    assert_eq!(storage.get_version(&pool1, &obj1).unwrap(), Some(appended));
+    assert_eq!(storage.read_object(&pool1, &obj1).unwrap().as_deref(), Some(b"three!" as &[u8]));
+    let obj4 = ObjectId((b"new" as &[u8]).to_owned());
+    assert_eq!(storage.get_version(&pool1, &obj4).unwrap(), None);
+    assert!(storage.write_if_version(&pool1, &obj4, None, b"new").unwrap().is_some());
+    storage.delete_object(&pool1, &obj4).unwrap();
+    assert_eq!(storage.get_version(&pool1, &obj4).unwrap(), None);
+
+    // Tags, kept by partial writes, cleared by whole writes and deletion
+    let tags = vec!["tmp".to_owned(), "b".to_owned()];
+    assert!(storage.set_tags(&pool1, &obj2, &tags).unwrap());
+    assert_eq!(storage.get_tags(&pool1, &obj2).unwrap(), tags);
+    storage.write_part(&pool1, &obj2, 0, b"\x00").unwrap();
+    assert_eq!(storage.get_tags(&pool1, &obj2).unwrap(), tags);
+    assert!(storage.set_tags(&pool1, &obj1, &tags[..1]).unwrap());
+    storage.write_object(&pool1, &obj1, b"three!").unwrap();
+    assert!(storage.get_tags(&pool1, &obj1).unwrap().is_empty());
+    assert!(!storage.set_tags(&pool1, &obj4, &tags).unwrap());
+    assert!(storage.get_tags(&pool1, &obj4).unwrap().is_empty());
+    storage.write_object(&pool1, &obj4, b"new").unwrap();
+    assert!(storage.set_tags(&pool1, &obj4, &tags).unwrap());
+    storage.delete_object(&pool1, &obj4).unwrap();
+    storage.write_object(&pool1, &obj4, b"new").unwrap();
+    assert!(storage.get_tags(&pool1, &obj4).unwrap().is_empty());
+    storage.delete_object(&pool1, &obj4).unwrap();
+    assert!(storage.set_tags(&pool1, &obj3, &tags[1..]).unwrap());
+    assert!(storage.set_tags(&pool1, &obj3, &[]).unwrap());
+    assert!(storage.get_tags(&pool1, &obj3).unwrap().is_empty());
+
+    // Listing, in order and by pages, skipping other pools and expired objects
+    let pool2 = PoolName("mapoule2".to_owned());
+    storage.write_object(&pool2, &obj1, b"other pool").unwrap();
+    let list = storage.list_objects(&pool1, b"", None, 10).unwrap();
+    assert_eq!(list, vec![obj1.clone(), obj3.clone(), obj2.clone()]);
+    let list = storage.list_objects(&pool1, b"", None, 2).unwrap();
+    assert_eq!(list, vec![obj1.clone(), obj3.clone()]);
+    let list = storage.list_objects(&pool1, b"", Some(&obj3), 2).unwrap();
+    assert_eq!(list, vec![obj2.clone()]);
+    let list = storage.list_objects(&pool1, b"m", None, 10).unwrap();
+    assert_eq!(list, vec![obj3.clone()]);
+    storage.set_expiry(&pool1, &obj3, Some(now - Duration::from_secs(10))).unwrap();
+    let list = storage.list_objects(&pool1, b"", None, 10).unwrap();
+    assert_eq!(list, vec![obj1.clone(), obj2.clone()]);
+
+    // Stats, not counting expired objects
+    let stats = storage.stats().unwrap();
+    assert_eq!(stats.pools.len(), 2);
+    let size1 = storage.object_size(&pool1, &obj1).unwrap().unwrap();
+    let size2 = storage.object_size(&pool1, &obj2).unwrap().unwrap();
+    assert_eq!(stats.pools[&pool1], PoolStats { objects: 2, bytes: size1 + size2 });
+    assert_eq!(stats.pools[&pool2], PoolStats { objects: 1, bytes: 10 });
+
+    // Flushing doesn't change anything
+    storage.flush().unwrap();
+    assert_eq!(storage.object_size(&pool1, &obj1).unwrap(), Some(size1));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn test_free_space() {
+        assert!(super::free_space(&std::env::temp_dir()).is_some());
+        assert_eq!(super::free_space(Path::new("/nonexistent/path")), None);
+    }
 }
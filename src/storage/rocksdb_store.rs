@@ -1,15 +1,36 @@
-use log::{error, info, warn};
-use rand::{Rng, thread_rng};
-use rocksdb::{DBWithThreadMode, Error as RdbError, MultiThreaded, Options};
-use std::io::{Error as IoError, ErrorKind, Read, Write};
-use std::fs::File;
-use std::path::Path;
+use log::warn;
+use rocksdb::{BoundColumnFamily, DBWithThreadMode, Direction, Error as RdbError, IteratorMode, MultiThreaded, Options, WriteBatch};
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::{DeviceId, ObjectId, PoolName};
-use super::StorageBackend;
+use crate::error::Error;
+use super::{
+    BackendStats, Problem, ProblemKind, StorageBackend, VerifyReport, decode_tags, encode_tags, free_space,
+    next_version, open_store_dir, read_device_id,
+};
+
+/// Column family holding the expiry time of objects that have one.
+const EXPIRY_CF: &str = "expiry";
+
+/// Column family holding the version of objects.
+const VERSION_CF: &str = "version";
+
+/// Column family holding the tags of objects that have some.
+const TAGS_CF: &str = "tags";
 
 /// A storage backend using RocksDB.
-pub struct RocksdbStore(DBWithThreadMode<MultiThreaded>);
+pub struct RocksdbStore {
+    db: DBWithThreadMode<MultiThreaded>,
+    /// Where the database is, to get the free space.
+    path: PathBuf,
+    read_only: bool,
+    /// Held while changing objects, so read-modify-write operations are
+    /// atomic.
+    write_lock: Mutex<()>,
+}
 
 /// Extension trait adding conversion of RdbError to IoError.
 trait RdbToIoResultExt<T> {
@@ -18,19 +39,180 @@ trait RdbToIoResultExt<T> {
 
 impl<T> RdbToIoResultExt<T> for Result<T, RdbError> {
     fn to_io_err(self) -> Result<T, IoError> {
-        self.map_err(|e| IoError::new(ErrorKind::Other, e.into_string()))
+        self.map_err(|e| Error::Backend(e.into_string()).into())
     }
 }
 
+fn encode_expiry(expires: SystemTime) -> u64 {
+    expires.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn decode_expiry(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
 impl RocksdbStore {
     pub fn open(path: &Path) -> Result<RocksdbStore, IoError> {
         let mut options = Options::default();
         options.create_if_missing(true);
-        let db = DBWithThreadMode::<MultiThreaded>::open(
+        options.create_missing_column_families(true);
+        let db = DBWithThreadMode::<MultiThreaded>::open_cf(
             &options,
             path,
+            [EXPIRY_CF, VERSION_CF, TAGS_CF],
         ).to_io_err()?;
-        Ok(RocksdbStore(db))
+        Ok(RocksdbStore { db, path: path.to_owned(), read_only: false, write_lock: Mutex::new(()) })
+    }
+
+    /// Open the store read-only.
+    ///
+    /// This doesn't take the RocksDB lock, so it can be used while a daemon
+    /// is running on the same store, though changes made by that daemon after
+    /// opening won't be visible.
+    pub fn open_read_only(path: &Path) -> Result<RocksdbStore, IoError> {
+        let options = Options::default();
+        // Stores from before versions or tags don't have their column family
+        // until they are opened for writing
+        let existing = DBWithThreadMode::<MultiThreaded>::list_cf(&options, path).to_io_err()?;
+        let column_families = [EXPIRY_CF, VERSION_CF, TAGS_CF].into_iter()
+            .filter(|cf| *cf == EXPIRY_CF || existing.iter().any(|e| e == cf));
+        let db = DBWithThreadMode::<MultiThreaded>::open_cf_for_read_only(
+            &options,
+            path,
+            column_families,
+            false,
+        ).to_io_err()?;
+        Ok(RocksdbStore { db, path: path.to_owned(), read_only: true, write_lock: Mutex::new(()) })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), IoError> {
+        if self.read_only {
+            Err(IoError::new(ErrorKind::PermissionDenied, "Store is opened read-only"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn expiry_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(EXPIRY_CF).unwrap()
+    }
+
+    fn read_expiry(&self, key: &[u8]) -> Result<Option<SystemTime>, IoError> {
+        match self.db.get_cf(&self.expiry_cf(), key).to_io_err()? {
+            None => Ok(None),
+            Some(value) => {
+                let secs: [u8; 8] = value[..].try_into()
+                    .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid expiry record"))?;
+                Ok(Some(decode_expiry(u64::from_be_bytes(secs))))
+            }
+        }
+    }
+
+    /// Delete the object if it has expired, with the write lock held.
+    ///
+    /// Returns whether it had. In read-only mode, nothing gets deleted.
+    fn check_expired(&self, key: &[u8]) -> Result<bool, IoError> {
+        if let Some(expires) = self.read_expiry(key)? {
+            if expires <= SystemTime::now() {
+                if !self.read_only {
+                    self.delete(key)?;
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Delete the object if it has expired, taking the write lock if needed.
+    fn check_expired_locked(&self, key: &[u8]) -> Result<bool, IoError> {
+        match self.read_expiry(key)? {
+            Some(expires) if expires <= SystemTime::now() => {
+                if self.read_only {
+                    return Ok(true);
+                }
+                // It might have been written again since, check again
+                let _lock = self.write_lock.lock().unwrap();
+                self.check_expired(key)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Delete an object, with its expiry, tags and version, with the write
+    /// lock held.
+    fn delete(&self, key: &[u8]) -> Result<(), IoError> {
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(&self.expiry_cf(), key);
+        for cf in [self.tags_cf(), self.version_cf()].into_iter().flatten() {
+            batch.delete_cf(&cf, key);
+        }
+        batch.delete(key);
+        self.db.write(batch).to_io_err()
+    }
+
+    /// The column family of versions, missing if opened read-only from
+    /// before versions.
+    fn version_cf(&self) -> Option<Arc<BoundColumnFamily>> {
+        self.db.cf_handle(VERSION_CF)
+    }
+
+    /// The column family of tags, missing if opened read-only from before
+    /// tags.
+    fn tags_cf(&self) -> Option<Arc<BoundColumnFamily>> {
+        self.db.cf_handle(TAGS_CF)
+    }
+
+    /// Read the version of an object, `None` if it has no version record.
+    fn read_version(&self, key: &[u8]) -> Result<Option<u64>, IoError> {
+        let cf = match self.version_cf() {
+            Some(cf) => cf,
+            None => return Ok(None),
+        };
+        match self.db.get_cf(&cf, key).to_io_err()? {
+            None => Ok(None),
+            Some(value) => {
+                let version: [u8; 8] = value[..].try_into()
+                    .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid version record"))?;
+                Ok(Some(u64::from_be_bytes(version)))
+            }
+        }
+    }
+
+    /// Write the new content of an object, giving it a new version, with the
+    /// write lock held.
+    ///
+    /// The content and version are written together, along with whatever
+    /// else is in `batch`. Returns the new version.
+    fn put_with_version(&self, mut batch: WriteBatch, key: &[u8], value: &[u8]) -> Result<u64, IoError> {
+        let version = next_version(self.read_version(key)?);
+        batch.put(key, value);
+        // Opened for writing, so the column family exists
+        batch.put_cf(&self.version_cf().unwrap(), key, version.to_be_bytes());
+        self.db.write(batch).to_io_err()?;
+        Ok(version)
+    }
+
+    fn current_version(&self, key: &[u8]) -> Result<Option<u64>, IoError> {
+        if self.db.get_pinned(key).to_io_err()?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.read_version(key)?.unwrap_or(0)))
+    }
+
+    /// Replace the content of an object, clearing its expiry and tags, with
+    /// the write lock held.
+    ///
+    /// Returns the new version.
+    fn replace(&self, key: &[u8], data: &[u8]) -> Result<u64, IoError> {
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(&self.expiry_cf(), key);
+        // Opened for writing, so the column family exists
+        batch.delete_cf(&self.tags_cf().unwrap(), key);
+        self.put_with_version(batch, key, data)
     }
 }
 
@@ -43,7 +225,11 @@ fn key(pool: &PoolName, object_id: &ObjectId) -> Vec<u8> {
 
 impl StorageBackend for RocksdbStore {
     fn read_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<Vec<u8>>, IoError> {
-        self.0.get(&key(pool, object_id)).to_io_err()
+        let key = key(pool, object_id);
+        if self.check_expired_locked(&key)? {
+            return Ok(None);
+        }
+        self.db.get(&key).to_io_err()
     }
 
     fn read_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, len: usize) -> Result<Option<Vec<u8>>, IoError> {
@@ -54,93 +240,311 @@ impl StorageBackend for RocksdbStore {
         )
     }
 
-    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError> {
-        self.0.put(
-            &key(pool, object_id),
-            data,
-        ).to_io_err()
+    fn write_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.replace(&key(pool, object_id), data)
     }
 
-    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<(), IoError> {
+    fn write_part(&self, pool: &PoolName, object_id: &ObjectId, offset: usize, data: &[u8]) -> Result<u64, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
         let key = key(pool, object_id);
-        match self.0.get(&key).to_io_err()? {
+        self.check_expired(&key)?;
+        let value = match self.db.get(&key).to_io_err()? {
             Some(mut value) => {
                 value.resize(value.len().max(offset + data.len()), 0);
                 value[offset..offset + data.len()].clone_from_slice(data);
-                self.0.put(&key, value).to_io_err()
+                value
             }
             None => {
                 let mut value = Vec::with_capacity(offset + data.len());
                 value.resize(offset, 0);
                 value.extend_from_slice(data);
-                self.0.put(&key, value).to_io_err()
+                value
             }
-        }
+        };
+        self.put_with_version(WriteBatch::default(), &key, &value)
     }
 
     fn delete_object(&self, pool: &PoolName, object_id: &ObjectId) -> Result<(), IoError> {
-        self.0.delete(&key(pool, object_id)).to_io_err()
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        self.delete(&key(pool, object_id))
     }
-}
 
-pub fn create_rocksdb_store(storage_dir: &Path) -> Result<(RocksdbStore, DeviceId), IoError> {
-    let create = if storage_dir.exists() {
-        if !storage_dir.is_dir() {
-            error!("Storage path exists and is not a directory");
-            return Err(IoError::new(
-                ErrorKind::AlreadyExists,
-                "Storage path exists and is not a directory",
-            ));
-        }
-
-        // Check layout
-        if storage_dir.join("store.id").is_file() {
-            // It's ready to go
-            info!("Using existing store");
-            false
+    fn set_expiry(&self, pool: &PoolName, object_id: &ObjectId, expires: Option<SystemTime>) -> Result<(), IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        match expires {
+            Some(expires) if self.db.get_pinned(&key).to_io_err()?.is_some() => {
+                self.db.put_cf(
+                    &self.expiry_cf(),
+                    &key,
+                    encode_expiry(expires).to_be_bytes(),
+                ).to_io_err()
+            }
+            _ => self.db.delete_cf(&self.expiry_cf(), &key).to_io_err(),
+        }
+    }
+
+    fn get_expiry(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<SystemTime>, IoError> {
+        self.read_expiry(&key(pool, object_id))
+    }
+
+    fn set_tags(&self, pool: &PoolName, object_id: &ObjectId, tags: &[String]) -> Result<bool, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        if self.check_expired(&key)? || self.db.get_pinned(&key).to_io_err()?.is_none() {
+            return Ok(false);
+        }
+        let cf = self.tags_cf().unwrap();
+        if tags.is_empty() {
+            self.db.delete_cf(&cf, &key).to_io_err()?;
         } else {
-            for _ in std::fs::read_dir(storage_dir)? {
-                return Err(IoError::new(
-                    ErrorKind::AlreadyExists,
-                    "Storage path exists and is not an empty directory",
-                ));
-            }
-            // It's empty
-            true
-        }
-    } else {
-        // It doesn't exist, make an empty directory
-        std::fs::create_dir(storage_dir)?;
-        true
-    };
-
-    if create {
-        warn!("Creating new file store");
-
-        // Generate a random device ID
-        let mut rng = thread_rng();
-        let mut bytes = [0; 16];
-        rng.fill(&mut bytes);
-        let device_id = DeviceId(bytes);
-        info!("Generated ID: {:?}", device_id);
-
-        // Write it to "store.id"
-        let mut id = File::create(storage_dir.join("store.id"))?;
-        id.write_all(&device_id.0)?;
-
-        // Open the store
-        Ok((RocksdbStore::open(&storage_dir.to_owned())?, device_id))
-    } else {
-        // Read device ID from "store.id"
-        let mut bytes = [0; 16];
-        let mut id = File::open(storage_dir.join("store.id"))?;
-        id.read_exact(&mut bytes)?;
-        let device_id = DeviceId(bytes);
-        info!("Read device ID {:?}", device_id);
-
-        // Open the store
-        Ok((RocksdbStore::open(&storage_dir.to_owned())?, device_id))
+            self.db.put_cf(&cf, &key, encode_tags(tags)).to_io_err()?;
+        }
+        Ok(true)
+    }
+
+    fn get_tags(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Vec<String>, IoError> {
+        let cf = match self.tags_cf() {
+            Some(cf) => cf,
+            None => return Ok(Vec::new()),
+        };
+        match self.db.get_pinned_cf(&cf, key(pool, object_id)).to_io_err()? {
+            Some(value) => decode_tags(&value),
+            None => Ok(Vec::new()),
+        }
     }
+
+    fn sweep_expired(&self, now: SystemTime) -> Result<usize, IoError> {
+        if self.read_only {
+            return Ok(0);
+        }
+        let _lock = self.write_lock.lock().unwrap();
+        let cf = self.expiry_cf();
+        let mut expired = Vec::new();
+        for (key, value) in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            let secs: [u8; 8] = value[..].try_into()
+                .map_err(|_| IoError::new(ErrorKind::InvalidData, "Invalid expiry record"))?;
+            if decode_expiry(u64::from_be_bytes(secs)) <= now {
+                expired.push(key);
+            }
+        }
+        for key in &expired {
+            self.delete(key)?;
+        }
+        Ok(expired.len())
+    }
+
+    fn object_size(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let key = key(pool, object_id);
+        if self.check_expired_locked(&key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get_pinned(&key).to_io_err()?.map(|v| v.len() as u64))
+    }
+
+    fn get_version(&self, pool: &PoolName, object_id: &ObjectId) -> Result<Option<u64>, IoError> {
+        let key = key(pool, object_id);
+        if self.check_expired_locked(&key)? {
+            return Ok(None);
+        }
+        self.current_version(&key)
+    }
+
+    fn list_objects(&self, pool: &PoolName, prefix: &[u8], start_after: Option<&ObjectId>, limit: usize) -> Result<Vec<ObjectId>, IoError> {
+        let prefix_key = key(pool, &ObjectId(prefix.to_owned()));
+        let start = match start_after {
+            Some(object_id) if object_id.0[..] > prefix[..] => key(pool, object_id),
+            _ => prefix_key.clone(),
+        };
+        let pool_len = pool.0.len() + 1;
+        let now = SystemTime::now();
+        let mut list = Vec::new();
+        for (key, _) in self.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            if list.len() >= limit || !key.starts_with(&prefix_key) {
+                break;
+            }
+            if start_after.is_some() && key[..] == start[..] {
+                continue;
+            }
+            if let Some(expires) = self.read_expiry(&key)? {
+                if expires <= now {
+                    continue;
+                }
+            }
+            list.push(ObjectId(key[pool_len..].to_owned()));
+        }
+        Ok(list)
+    }
+
+    fn append_object(&self, pool: &PoolName, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        self.check_expired(&key)?;
+        let mut value = self.db.get(&key).to_io_err()?.unwrap_or_default();
+        value.extend_from_slice(data);
+        self.put_with_version(WriteBatch::default(), &key, &value)
+    }
+
+    fn truncate_object(&self, pool: &PoolName, object_id: &ObjectId, len: usize) -> Result<Option<u64>, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        if self.check_expired(&key)? {
+            return Ok(None);
+        }
+        match self.db.get(&key).to_io_err()? {
+            Some(mut value) => {
+                value.resize(len, 0);
+                Ok(Some(self.put_with_version(WriteBatch::default(), &key, &value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_swap(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<&[u8]>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        self.check_expired(&key)?;
+        let current = self.db.get_pinned(&key).to_io_err()?;
+        if current.as_deref() != expected {
+            return Ok(None);
+        }
+        drop(current);
+        Ok(Some(self.replace(&key, data)?))
+    }
+
+    fn write_if_version(&self, pool: &PoolName, object_id: &ObjectId, expected: Option<u64>, data: &[u8]) -> Result<Option<u64>, IoError> {
+        self.check_writable()?;
+        let _lock = self.write_lock.lock().unwrap();
+        let key = key(pool, object_id);
+        self.check_expired(&key)?;
+        if self.current_version(&key)? != expected {
+            return Ok(None);
+        }
+        Ok(Some(self.replace(&key, data)?))
+    }
+
+    fn stats(&self) -> Result<BackendStats, IoError> {
+        let now = SystemTime::now();
+        let mut stats = BackendStats::default();
+        for (key, value) in self.db.iterator(IteratorMode::Start) {
+            let pool_len = match key.iter().position(|&b| b == b'/') {
+                Some(i) => i,
+                None => {
+                    warn!("Invalid key in store: {:?}", String::from_utf8_lossy(&key));
+                    continue;
+                }
+            };
+            if let Some(expires) = self.read_expiry(&key)? {
+                if expires <= now {
+                    continue;
+                }
+            }
+            let pool = PoolName(String::from_utf8_lossy(&key[..pool_len]).into_owned());
+            let pool_stats = stats.pools.entry(pool).or_default();
+            pool_stats.objects += 1;
+            pool_stats.bytes += value.len() as u64;
+        }
+        stats.free_space = free_space(&self.path);
+        Ok(stats)
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        if self.read_only {
+            return Ok(());
+        }
+        // Writes go to the log without syncing it, sync it now
+        self.db.flush_wal(true).to_io_err()
+    }
+
+    fn verify(&self, repair: bool) -> Result<VerifyReport, IoError> {
+        if repair {
+            self.check_writable()?;
+        }
+        let _lock = self.write_lock.lock().unwrap();
+        let mut report = VerifyReport::default();
+
+        // Reading goes through every block, which checks their checksums
+        let mut invalid_keys = Vec::new();
+        let mut iter = self.db.iterator(IteratorMode::Start);
+        for (key, _) in &mut iter {
+            match key.iter().position(|&b| b == b'/') {
+                Some(i) if std::str::from_utf8(&key[..i]).is_ok() => report.objects += 1,
+                _ => invalid_keys.push(key),
+            }
+        }
+        iter.status().to_io_err()?;
+        drop(iter);
+        for key in invalid_keys {
+            if repair {
+                self.db.delete(&key).to_io_err()?;
+            }
+            report.problems.push(Problem {
+                kind: ProblemKind::Corrupt,
+                description: format!("Invalid key {:?}", String::from_utf8_lossy(&key)),
+                repaired: repair,
+            });
+        }
+
+        // Expiry and version records are 8 bytes, tags are text, for an
+        // existing object
+        let eight_bytes: fn(&[u8]) -> bool = |value| value.len() == 8;
+        let tags: fn(&[u8]) -> bool = |value| decode_tags(value).is_ok();
+        let records = [
+            (Some(self.expiry_cf()), eight_bytes, "Invalid expiry record for", "Expiry of missing object"),
+            (self.version_cf(), eight_bytes, "Invalid version record for", "Version of missing object"),
+            (self.tags_cf(), tags, "Invalid tags record for", "Tags of missing object"),
+        ];
+        for (cf, valid, invalid, orphaned) in records {
+            let cf = match cf {
+                Some(cf) => cf,
+                None => continue,
+            };
+            let mut problems = Vec::new();
+            let mut iter = self.db.iterator_cf(&cf, IteratorMode::Start);
+            for (key, value) in &mut iter {
+                if !valid(&value) {
+                    problems.push((key, ProblemKind::Corrupt, invalid));
+                } else if self.db.get_pinned(&key).to_io_err()?.is_none() {
+                    problems.push((key, ProblemKind::Orphaned, orphaned));
+                }
+            }
+            iter.status().to_io_err()?;
+            drop(iter);
+            for (key, kind, what) in problems {
+                if repair {
+                    self.db.delete_cf(&cf, &key).to_io_err()?;
+                }
+                report.problems.push(Problem {
+                    kind,
+                    description: format!("{} {:?}", what, String::from_utf8_lossy(&key)),
+                    repaired: repair,
+                });
+            }
+        }
+        Ok(report)
+    }
+}
+
+pub fn create_rocksdb_store(storage_dir: &Path) -> Result<(RocksdbStore, DeviceId), IoError> {
+    let (device_id, _) = open_store_dir(storage_dir)?;
+    Ok((RocksdbStore::open(storage_dir)?, device_id))
+}
+
+/// Open an existing store read-only.
+///
+/// Unlike `create_rocksdb_store()`, this never creates anything.
+pub fn open_rocksdb_store_read_only(storage_dir: &Path) -> Result<(RocksdbStore, DeviceId), IoError> {
+    let device_id = read_device_id(storage_dir)?;
+    Ok((RocksdbStore::open_read_only(storage_dir)?, device_id))
 }
 
 #[cfg(test)]
@@ -148,6 +552,8 @@ mod tests {
     use tempdir::TempDir;
     use std::path::Path;
 
+    use crate::{ObjectId, PoolName};
+    use crate::storage::StorageBackend;
     use super::RocksdbStore;
 
     #[test]
@@ -155,6 +561,36 @@ mod tests {
         let path = TempDir::new("store_rocksdb_test").unwrap();
         let path: &Path = path.as_ref();
         let storage = RocksdbStore::open(path).unwrap();
+        assert!(storage.stats().unwrap().free_space.is_some());
         super::super::test_backend(storage);
     }
+
+    #[test]
+    fn test_rdbstore_conformance() {
+        let path = TempDir::new("store_rocksdb_test").unwrap();
+        let path: &Path = path.as_ref();
+        let storage = RocksdbStore::open(path).unwrap();
+        super::super::conformance::check_backend(&storage);
+    }
+
+    #[test]
+    fn test_rdbstore_read_only() {
+        let path = TempDir::new("store_rocksdb_test").unwrap();
+        let path: &Path = path.as_ref();
+        let pool = PoolName("mapoule".to_owned());
+        let obj = ObjectId((b"greeting" as &[u8]).to_owned());
+
+        let storage = RocksdbStore::open(path).unwrap();
+        storage.write_object(&pool, &obj, b"hello").unwrap();
+
+        // Open read-only while the store is open
+        let read_only = RocksdbStore::open_read_only(path).unwrap();
+        assert!(read_only.is_read_only());
+        assert_eq!(
+            read_only.read_object(&pool, &obj).unwrap().as_deref(),
+            Some(b"hello" as &[u8]),
+        );
+        assert!(read_only.write_object(&pool, &obj, b"bye").is_err());
+        assert!(read_only.delete_object(&pool, &obj).is_err());
+    }
 }
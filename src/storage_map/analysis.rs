@@ -0,0 +1,200 @@
+//! Simulating placement to check how evenly a map spreads data.
+
+use std::collections::HashMap;
+
+use crate::{DeviceId, GroupId, ObjectId};
+use super::{Algorithm, Node, StorageMap};
+
+/// Load of a single device, from the simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceLoad {
+    pub device_id: DeviceId,
+    /// Number of replicas the device should get, according to the weights.
+    pub expected: f64,
+    /// Number of replicas the device actually got.
+    pub actual: usize,
+}
+
+impl DeviceLoad {
+    /// Ratio of actual over expected load, 1.0 meaning perfect balance.
+    pub fn ratio(&self) -> f64 {
+        self.actual as f64 / self.expected
+    }
+}
+
+/// Result of simulating placement for a number of groups or objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Distribution {
+    /// Number of groups, or objects, that were placed.
+    pub placed: u32,
+    /// Number of replicas that couldn't be placed.
+    pub missing_replicas: usize,
+    /// The devices, in the order they appear in the map.
+    pub devices: Vec<DeviceLoad>,
+    /// Standard deviation of the ratio of actual over expected load.
+    pub std_dev: f64,
+    /// How much the most loaded device goes over its expected load, as a
+    /// fraction (0.1 means 10% more than expected).
+    pub max_imbalance: f64,
+}
+
+/// Compute the share of the data each device should get from the weights.
+fn expected_shares(node: &Node, share: f64, result: &mut Vec<(DeviceId, f64)>) {
+    match node {
+        Node::Device(device_id) => result.push((device_id.clone(), share)),
+        Node::Bucket(bucket) => {
+            let total: u32 = bucket.children.iter().map(|c| c.weight).sum();
+            for (i, child) in bucket.children.iter().enumerate() {
+                let child_share = match bucket.algorithm {
+                    Algorithm::Uniform => share / bucket.children.len() as f64,
+                    // Only the first child is used unless it fails
+                    Algorithm::Fallback => if i == 0 { share } else { 0.0 },
+                    _ if total == 0 => 0.0,
+                    _ => share * child.weight as f64 / total as f64,
+                };
+                expected_shares(&child.node, child_share, result);
+            }
+        }
+    }
+}
+
+impl StorageMap {
+    /// Place the given number of groups and compare each device's load with
+    /// what its weight entitles it to.
+    ///
+    /// The groups don't have to exist in the map, a larger number gives more
+    /// accurate statistics.
+    pub fn analyze(&self, groups: u32) -> Distribution {
+        self.distribution(groups, (0..groups).map(|group| (GroupId(group), 1)))
+    }
+
+    /// Place the given number of objects in the map's groups and compare
+    /// each device's load with what its weight entitles it to.
+    ///
+    /// Unlike `analyze()`, this shows the imbalance due to the number of
+    /// groups being too low.
+    pub fn analyze_objects(&self, objects: u32) -> Distribution {
+        let counts = self.objects_per_group(objects);
+        self.distribution(
+            objects,
+            counts.into_iter().enumerate().map(|(group, count)| (GroupId(group as u32), count as usize)),
+        )
+    }
+
+    /// Count how many of the given number of simulated objects land in each
+    /// group.
+    pub fn objects_per_group(&self, objects: u32) -> Vec<u64> {
+        let mut counts = vec![0; self.groups];
+        for i in 0..objects {
+            let object_id = ObjectId(format!("object{}", i).into_bytes());
+            counts[self.object_to_group(&object_id).0 as usize] += 1;
+        }
+        counts
+    }
+
+    /// Place groups, each standing for a number of items.
+    fn distribution<I: Iterator<Item=(GroupId, usize)>>(&self, placed: u32, groups: I) -> Distribution {
+        let mut shares = Vec::new();
+        expected_shares(&self.map_root, 1.0, &mut shares);
+
+        // Simulate placement
+        let mut counts: HashMap<DeviceId, usize> = HashMap::new();
+        let mut missing_replicas = 0;
+        for (group_id, items) in groups {
+            if items == 0 {
+                continue;
+            }
+            let devices = self.group_to_replicas(&group_id);
+            missing_replicas += (self.replicas as usize - devices.len()) * items;
+            for device_id in devices {
+                *counts.entry(device_id).or_insert(0) += items;
+            }
+        }
+
+        let total_replicas = placed as f64 * self.replicas as f64;
+        let devices: Vec<DeviceLoad> = shares
+            .into_iter()
+            .map(|(device_id, share)| DeviceLoad {
+                actual: counts.get(&device_id).copied().unwrap_or(0),
+                expected: share * total_replicas,
+                device_id,
+            })
+            .collect();
+
+        // Statistics over devices that should get data
+        let ratios: Vec<f64> = devices
+            .iter()
+            .filter(|d| d.expected > 0.0)
+            .map(|d| d.ratio())
+            .collect();
+        let (std_dev, max_imbalance) = if ratios.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let variance = ratios.iter().map(|r| (r - 1.0) * (r - 1.0)).sum::<f64>() / ratios.len() as f64;
+            let max_ratio = ratios.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            (variance.sqrt(), max_ratio - 1.0)
+        };
+
+        Distribution {
+            placed,
+            missing_replicas,
+            devices,
+            std_dev,
+            max_imbalance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceId;
+    use super::super::{Algorithm, Bucket, GroupMapping, HashVersion, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+
+    #[test]
+    fn test_analyze() {
+        let map = StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas: 1,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Bucket(Bucket {
+                id: 0,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::PseudoRandom,
+                children: vec![
+                    NodeEntry { weight: 1, node: Node::Device(DeviceId([1; 16])) },
+                    NodeEntry { weight: 3, node: Node::Device(DeviceId([2; 16])) },
+                    NodeEntry { weight: 0, node: Node::Device(DeviceId([3; 16])) },
+                ],
+            }),
+        };
+        let distribution = map.analyze(100000);
+        assert_eq!(distribution.placed, 100000);
+        assert_eq!(distribution.missing_replicas, 0);
+        assert_eq!(distribution.devices.len(), 3);
+        assert_eq!(distribution.devices[0].expected, 25000.0);
+        assert_eq!(distribution.devices[1].expected, 75000.0);
+        assert_eq!(distribution.devices[2].expected, 0.0);
+        assert_eq!(distribution.devices[2].actual, 0);
+        assert_eq!(
+            distribution.devices.iter().map(|d| d.actual).sum::<usize>(),
+            100000,
+        );
+        assert!(distribution.std_dev < 0.02, "{}", distribution.std_dev);
+        assert!(distribution.max_imbalance < 0.02, "{}", distribution.max_imbalance);
+
+        // With few groups, objects are spread unevenly
+        let distribution = map.analyze_objects(100000);
+        assert_eq!(distribution.placed, 100000);
+        assert_eq!(map.objects_per_group(100000).iter().sum::<u64>(), 100000);
+        assert_eq!(
+            distribution.devices.iter().map(|d| d.actual).sum::<usize>(),
+            100000,
+        );
+        assert_eq!(distribution.devices[2].actual, 0);
+        assert!(distribution.max_imbalance < 0.5, "{}", distribution.max_imbalance);
+    }
+}
@@ -0,0 +1,336 @@
+//! Building storage maps from a description of the cluster's topology.
+//!
+//! Rather than assembling buckets by hand, operators describe where each
+//! device is (host and optionally rack) and where replicas should be spread,
+//! and this module produces the bucket tree.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Error as IoError, ErrorKind};
+
+use crate::DeviceId;
+use super::{Algorithm, Bucket, GroupMapping, HashVersion, MAX_GROUPS, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+
+/// The level of the topology across which replicas are spread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureDomain {
+    /// Replicas go to distinct devices, possibly on the same host.
+    Device,
+    /// Replicas go to distinct hosts.
+    Host,
+    /// Replicas go to distinct racks.
+    Rack,
+}
+
+/// A device and its location in the topology.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSpec {
+    pub id: DeviceId,
+    pub host: String,
+    #[serde(default)]
+    pub rack: Option<String>,
+    pub weight: u32,
+}
+
+/// Description of the cluster, from which a storage map can be built.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topology {
+    pub groups: usize,
+    pub replicas: u32,
+    pub failure_domain: FailureDomain,
+    pub devices: Vec<DeviceSpec>,
+}
+
+/// Hosts or racks, in the order they were first seen.
+struct Grouping<T> {
+    names: Vec<String>,
+    members: Vec<Vec<T>>,
+}
+
+impl<T> Grouping<T> {
+    fn new() -> Grouping<T> {
+        Grouping {
+            names: Vec::new(),
+            members: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, member: T) {
+        match self.names.iter().position(|n| n == name) {
+            Some(i) => self.members[i].push(member),
+            None => {
+                self.names.push(name.to_owned());
+                self.members.push(vec![member]);
+            }
+        }
+    }
+}
+
+impl Topology {
+    pub fn new(groups: usize, replicas: u32, failure_domain: FailureDomain) -> Topology {
+        Topology {
+            groups,
+            replicas,
+            failure_domain,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Add a device on a host, optionally in a rack.
+    pub fn add_device(&mut self, id: DeviceId, host: &str, rack: Option<&str>, weight: u32) -> &mut Topology {
+        self.devices.push(DeviceSpec {
+            id,
+            host: host.to_owned(),
+            rack: rack.map(|r| r.to_owned()),
+            weight,
+        });
+        self
+    }
+
+    /// Build the storage map.
+    ///
+    /// The tree has a bucket for the root, then one per rack (if racks are
+    /// used), then one per host. Bucket IDs are assigned in that order,
+    /// starting from 0 for the root. The buckets whose children are in the
+    /// failure domain never repeat a pick, and the placement rule requires
+    /// replicas to be in distinct failure domains.
+    pub fn build(&self, generation: u32) -> Result<StorageMap, IoError> {
+        if self.groups == 0 {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Number of groups can't be 0"));
+        } else if self.groups > MAX_GROUPS {
+            return Err(IoError::new(ErrorKind::InvalidInput, format!("Number of groups can't be more than {}", MAX_GROUPS)));
+        }
+        if self.devices.is_empty() {
+            return Err(IoError::new(ErrorKind::InvalidInput, "No devices in topology"));
+        }
+        let mut seen = HashSet::new();
+        for device in &self.devices {
+            if !seen.insert(&device.id) {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Device {:?} appears multiple times", device.id),
+                ));
+            }
+        }
+
+        // Check whether racks are used, then it has to be for all devices
+        let use_racks = self.devices.iter().any(|d| d.rack.is_some());
+        if use_racks && self.devices.iter().any(|d| d.rack.is_none()) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "Some devices have a rack and some don't",
+            ));
+        }
+        if self.failure_domain == FailureDomain::Rack && !use_racks {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "Failure domain is rack but no racks are set",
+            ));
+        }
+
+        // Group devices by rack, then by host
+        let mut racks = Grouping::new();
+        for device in &self.devices {
+            racks.add(device.rack.as_deref().unwrap_or(""), device);
+        }
+        let racks: Vec<Grouping<&DeviceSpec>> = racks.members
+            .into_iter()
+            .map(|devices| {
+                let mut hosts = Grouping::new();
+                for device in devices {
+                    hosts.add(&device.host, device);
+                }
+                hosts
+            })
+            .collect();
+
+        // Check that there are enough failure domains for the replicas
+        let domains = match self.failure_domain {
+            FailureDomain::Device => self.devices.len(),
+            FailureDomain::Host => racks.iter().map(|r| r.names.len()).sum(),
+            FailureDomain::Rack => racks.len(),
+        };
+        if (self.replicas as usize) > domains {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Can't place {} replicas in {} distinct {:?}s",
+                    self.replicas, domains, self.failure_domain,
+                ),
+            ));
+        }
+
+        // Assign IDs: root, then racks, then hosts
+        let mut next_id = if use_racks { 1 + racks.len() as u32 } else { 1 };
+        let pick_mode = |level: FailureDomain| if level == self.failure_domain {
+            PickMode::NeverRepeat
+        } else {
+            PickMode::PseudoRandom
+        };
+
+        let mut rack_entries = Vec::with_capacity(racks.len());
+        for (rack_idx, hosts) in racks.iter().enumerate() {
+            let mut host_entries = Vec::with_capacity(hosts.members.len());
+            for devices in &hosts.members {
+                let children: Vec<NodeEntry> = devices
+                    .iter()
+                    .map(|d| NodeEntry {
+                        weight: d.weight,
+                        node: Node::Device(d.id.clone()),
+                    })
+                    .collect();
+                host_entries.push(make_entry(children, next_id, pick_mode(FailureDomain::Device)));
+                next_id += 1;
+            }
+            rack_entries.push(make_entry(host_entries, 1 + rack_idx as u32, pick_mode(FailureDomain::Host)));
+        }
+
+        let map_root = if use_racks {
+            make_entry(rack_entries, 0, pick_mode(FailureDomain::Rack)).node
+        } else {
+            // Without racks, the single rack bucket becomes the root
+            let mut root = rack_entries.remove(0).node;
+            if let Node::Bucket(ref mut bucket) = root {
+                bucket.id = 0;
+            }
+            root
+        };
+
+        // Depth of the failure domain in the tree, for the placement rule
+        let depth = match self.failure_domain {
+            FailureDomain::Rack => 0,
+            FailureDomain::Host => 1,
+            FailureDomain::Device => 2,
+        } + if use_racks { 1 } else { 0 };
+
+        Ok(StorageMap {
+            generation,
+            groups: self.groups,
+            replicas: self.replicas,
+            group_mapping: GroupMapping::Split,
+            placement_groups: None,
+            hash: HashVersion::Murmur3,
+            rule: PlacementRule::DistinctAtDepth(depth),
+            map_root,
+        })
+    }
+}
+
+/// Make a straw2 bucket, weighted by the total of its children.
+fn make_entry(children: Vec<NodeEntry>, id: u32, pick_mode: PickMode) -> NodeEntry {
+    let weight = children.iter().map(|c| c.weight).sum();
+    NodeEntry {
+        weight,
+        node: Node::Bucket(Bucket {
+            id,
+            algorithm: Algorithm::Straw2,
+            pick_mode,
+            children,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{DeviceId, GroupId};
+    use super::super::{Node, PickMode, PlacementRule};
+    use super::{FailureDomain, Topology};
+
+    #[test]
+    fn test_build_hosts() {
+        let mut topology = Topology::new(128, 2, FailureDomain::Host);
+        topology
+            .add_device(DeviceId([1; 16]), "host1", None, 10)
+            .add_device(DeviceId([2; 16]), "host2", None, 10)
+            .add_device(DeviceId([3; 16]), "host1", None, 5);
+        let map = topology.build(3).unwrap();
+        assert_eq!(map.generation, 3);
+        assert_eq!(map.replicas, 2);
+        assert_eq!(map.rule, PlacementRule::DistinctAtDepth(1));
+
+        // Replicas are on distinct hosts
+        for i in 0..1000 {
+            let devices = map.group_to_devices(&GroupId(i), 2);
+            assert_eq!(devices.len(), 2);
+            let hosts: HashSet<&str> = devices
+                .iter()
+                .map(|d| &topology.devices.iter().find(|s| &s.id == d).unwrap().host as &str)
+                .collect();
+            assert_eq!(hosts.len(), 2);
+        }
+
+        let root = match map.map_root {
+            Node::Bucket(b) => b,
+            _ => panic!("Root should be a bucket"),
+        };
+        assert_eq!(root.id, 0);
+        assert_eq!(root.pick_mode, PickMode::NeverRepeat);
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(root.children[0].weight, 15);
+        assert_eq!(root.children[1].weight, 10);
+        let host1 = match root.children[0].node {
+            Node::Bucket(ref b) => b,
+            _ => panic!("Expected a host bucket"),
+        };
+        assert_eq!(host1.id, 1);
+        assert_eq!(host1.pick_mode, PickMode::PseudoRandom);
+        assert_eq!(host1.children.len(), 2);
+    }
+
+    #[test]
+    fn test_build_racks() {
+        let mut topology = Topology::new(128, 2, FailureDomain::Rack);
+        topology
+            .add_device(DeviceId([1; 16]), "host1", Some("rack1"), 1)
+            .add_device(DeviceId([2; 16]), "host2", Some("rack2"), 1)
+            .add_device(DeviceId([3; 16]), "host3", Some("rack2"), 1);
+        let map = topology.build(1).unwrap();
+        assert_eq!(map.rule, PlacementRule::DistinctAtDepth(1));
+        for i in 0..1000 {
+            let devices = map.group_to_devices(&GroupId(i), 2);
+            assert_eq!(devices.len(), 2);
+            assert!(devices.contains(&DeviceId([1; 16])));
+        }
+        let root = match map.map_root {
+            Node::Bucket(b) => b,
+            _ => panic!("Root should be a bucket"),
+        };
+        assert_eq!(root.pick_mode, PickMode::NeverRepeat);
+        let ids: Vec<u32> = root.children
+            .iter()
+            .map(|c| match c.node {
+                Node::Bucket(ref b) => b.id,
+                _ => panic!("Expected a rack bucket"),
+            })
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(root.children[1].weight, 2);
+    }
+
+    #[test]
+    fn test_build_errors() {
+        // Not enough hosts
+        let mut topology = Topology::new(128, 3, FailureDomain::Host);
+        topology
+            .add_device(DeviceId([1; 16]), "host1", None, 1)
+            .add_device(DeviceId([2; 16]), "host2", None, 1)
+            .add_device(DeviceId([3; 16]), "host2", None, 1);
+        assert!(topology.build(1).is_err());
+
+        // Duplicate device
+        let mut topology = Topology::new(128, 1, FailureDomain::Device);
+        topology
+            .add_device(DeviceId([1; 16]), "host1", None, 1)
+            .add_device(DeviceId([1; 16]), "host2", None, 1);
+        assert!(topology.build(1).is_err());
+
+        // Mixed racks
+        let mut topology = Topology::new(128, 1, FailureDomain::Device);
+        topology
+            .add_device(DeviceId([1; 16]), "host1", Some("rack1"), 1)
+            .add_device(DeviceId([2; 16]), "host2", None, 1);
+        assert!(topology.build(1).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Comparing storage maps, to preview the data movement of a new map.
+
+use crate::{DeviceId, GroupId};
+use super::{StorageMap, parent_group};
+
+/// A group whose devices are different in the new map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupChange {
+    pub group_id: GroupId,
+    pub old_devices: Vec<DeviceId>,
+    pub new_devices: Vec<DeviceId>,
+}
+
+impl GroupChange {
+    /// Whether the first device, that serves reads and writes, changed.
+    pub fn primary_changed(&self) -> bool {
+        self.old_devices.first() != self.new_devices.first()
+    }
+
+    /// The devices that will need to receive a copy of the group.
+    pub fn added_devices(&self) -> impl Iterator<Item=&DeviceId> {
+        self.new_devices.iter().filter(move |d| !self.old_devices.contains(d))
+    }
+
+    /// The devices that will no longer hold the group.
+    pub fn removed_devices(&self) -> impl Iterator<Item=&DeviceId> {
+        self.old_devices.iter().filter(move |d| !self.new_devices.contains(d))
+    }
+}
+
+/// The differences in placement between two maps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapDiff {
+    /// Number of groups in the new map.
+    pub groups: usize,
+    /// The groups that changed, in order.
+    pub changes: Vec<GroupChange>,
+}
+
+impl MapDiff {
+    /// Number of groups whose primary device changed.
+    pub fn primary_changes(&self) -> usize {
+        self.changes.iter().filter(|c| c.primary_changed()).count()
+    }
+
+    /// Estimate the number of bytes to copy, given the size of each group.
+    ///
+    /// Each device newly holding a group needs a full copy of it.
+    pub fn bytes_to_move<F: Fn(&GroupId) -> u64>(&self, group_size: F) -> u64 {
+        self.changes
+            .iter()
+            .map(|c| group_size(&c.group_id) * c.added_devices().count() as u64)
+            .sum()
+    }
+}
+
+impl StorageMap {
+    /// Compare the placement of groups between this map and a new one.
+    ///
+    /// If groups were split, each new group is compared with the old group its
+    /// objects come from.
+    pub fn diff(&self, new: &StorageMap) -> MapDiff {
+        let mut changes = Vec::new();
+        for group in 0..new.groups as u32 {
+            let old_devices = self.group_to_devices(
+                &parent_group(&GroupId(group), self.groups),
+                self.replicas as usize,
+            );
+            let new_devices = new.group_to_devices(&GroupId(group), new.replicas as usize);
+            if old_devices != new_devices {
+                changes.push(GroupChange {
+                    group_id: GroupId(group),
+                    old_devices,
+                    new_devices,
+                });
+            }
+        }
+        MapDiff {
+            groups: new.groups,
+            changes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceId;
+    use super::super::{Algorithm, Bucket, GroupMapping, HashVersion, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+
+    fn map(devices: &[u8], groups: usize) -> StorageMap {
+        StorageMap {
+            generation: 1,
+            groups,
+            replicas: 2,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Bucket(Bucket {
+                id: 0,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::NeverRepeat,
+                children: devices
+                    .iter()
+                    .map(|&d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                    .collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_diff_same() {
+        let old = map(&[1, 2, 3], 128);
+        let diff = old.diff(&old.clone());
+        assert_eq!(diff.groups, 128);
+        assert!(diff.changes.is_empty());
+        assert_eq!(diff.bytes_to_move(|_| 1000), 0);
+    }
+
+    #[test]
+    fn test_diff_add_device() {
+        let old = map(&[1, 2, 3], 128);
+        let new = map(&[1, 2, 3, 4], 128);
+        let diff = old.diff(&new);
+        assert!(!diff.changes.is_empty());
+        let mut added = 0;
+        for change in &diff.changes {
+            // Every change is caused by the new device
+            assert!(change.added_devices().any(|d| d == &DeviceId([4; 16])));
+            assert_eq!(change.added_devices().count(), change.removed_devices().count());
+            added += change.added_devices().count();
+        }
+        assert!(diff.primary_changes() <= diff.changes.len());
+        assert_eq!(diff.bytes_to_move(|_| 10), 10 * added as u64);
+
+        // With 2 replicas on 4 devices, about half the groups get a copy on the
+        // new device
+        let ratio = diff.changes.len() as f32 / 128.0;
+        assert!(ratio > 0.3 && ratio < 0.7, "{}", ratio);
+    }
+
+    #[test]
+    fn test_diff_split_groups() {
+        let old = map(&[1, 2, 3], 128);
+        let new = map(&[1, 2, 3], 256);
+        let diff = old.diff(&new);
+        assert_eq!(diff.groups, 256);
+        // The first half of the groups are the same
+        assert!(diff.changes.iter().all(|c| c.group_id.0 >= 128));
+        assert!(!diff.changes.is_empty());
+
+        // Nothing moves if the placement of the new groups is kept
+        let mut new = new;
+        new.placement_groups = Some(128);
+        assert!(old.diff(&new).changes.is_empty());
+    }
+}
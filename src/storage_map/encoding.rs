@@ -0,0 +1,471 @@
+//! Compact binary encoding of storage maps.
+//!
+//! This is the format used to send maps from the master to the storage
+//! daemons and clients. It starts with a version byte, so that the format can
+//! evolve while old maps can still be read.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Error as IoError, ErrorKind, Read};
+
+use crate::DeviceId;
+use super::{Algorithm, Bucket, GroupMapping, HashVersion, MAX_GROUPS, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+
+/// Current version of the encoding.
+///
+/// Version 1 didn't have a placement rule, version 2 didn't have the number
+/// of placement groups, version 3 didn't have the hash function, version 4
+/// didn't have the group mapping.
+const VERSION: u8 = 5;
+
+/// Maximum depth of the tree, to protect against malicious input.
+const MAX_DEPTH: u32 = 32;
+
+/// Maximum number of children per bucket, to protect against malicious input.
+const MAX_CHILDREN: usize = 65536;
+
+/// Maximum number of replicas, to protect against malicious input.
+const MAX_REPLICAS: u32 = 16;
+
+const NODE_DEVICE: u8 = 0;
+const NODE_BUCKET: u8 = 1;
+
+const ALGORITHM_UNIFORM: u8 = 0;
+const ALGORITHM_STRAW: u8 = 1;
+const ALGORITHM_LIST: u8 = 2;
+const ALGORITHM_FALLBACK: u8 = 3;
+const ALGORITHM_STRAW2: u8 = 4;
+const ALGORITHM_TREE: u8 = 5;
+const ALGORITHM_ALIAS: u8 = 6;
+
+const PICK_PSEUDO_RANDOM: u8 = 0;
+const PICK_NEVER_REPEAT: u8 = 1;
+
+const HASH_FX: u8 = 0;
+const HASH_MURMUR3: u8 = 1;
+
+const GROUPS_MODULO: u8 = 0;
+const GROUPS_SPLIT: u8 = 1;
+
+const RULE_ANY: u8 = 0;
+const RULE_DISTINCT_AT_DEPTH: u8 = 1;
+
+fn invalid(msg: &'static str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+impl StorageMap {
+    /// Encode the map in the binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.write_u8(VERSION).unwrap();
+        result.write_u32::<BigEndian>(self.generation).unwrap();
+        result.write_u32::<BigEndian>(self.groups as u32).unwrap();
+        result.write_u32::<BigEndian>(self.replicas).unwrap();
+        // 0 means the same as the number of groups
+        result.write_u32::<BigEndian>(self.placement_groups.unwrap_or(0) as u32).unwrap();
+        match self.group_mapping {
+            GroupMapping::Modulo => result.write_u8(GROUPS_MODULO).unwrap(),
+            GroupMapping::Split => result.write_u8(GROUPS_SPLIT).unwrap(),
+        }
+        match self.hash {
+            HashVersion::Fx => result.write_u8(HASH_FX).unwrap(),
+            HashVersion::Murmur3 => result.write_u8(HASH_MURMUR3).unwrap(),
+        }
+        match self.rule {
+            PlacementRule::Any => result.write_u8(RULE_ANY).unwrap(),
+            PlacementRule::DistinctAtDepth(depth) => {
+                result.write_u8(RULE_DISTINCT_AT_DEPTH).unwrap();
+                result.write_u32::<BigEndian>(depth).unwrap();
+            }
+        }
+        encode_node(&self.map_root, &mut result);
+        result
+    }
+
+    /// Decode a map from the binary format.
+    pub fn decode(data: &[u8]) -> Result<StorageMap, IoError> {
+        let mut reader = Cursor::new(data);
+        let version = reader.read_u8()?;
+        if version == 0 || version > VERSION {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("Unknown storage map version {}", version),
+            ));
+        }
+        let generation = reader.read_u32::<BigEndian>()?;
+        let groups = reader.read_u32::<BigEndian>()? as usize;
+        if groups == 0 {
+            return Err(invalid("Storage map has no groups"));
+        } else if groups > MAX_GROUPS {
+            return Err(invalid("Storage map has too many groups"));
+        }
+        let replicas = reader.read_u32::<BigEndian>()?;
+        if replicas == 0 {
+            return Err(invalid("Storage map has no replicas"));
+        } else if replicas > MAX_REPLICAS {
+            return Err(invalid("Storage map has too many replicas"));
+        }
+        let placement_groups = if version >= 3 {
+            match reader.read_u32::<BigEndian>()? as usize {
+                0 => None,
+                n if n > groups => return Err(invalid("More placement groups than groups")),
+                n => Some(n),
+            }
+        } else {
+            None
+        };
+        let group_mapping = if version >= 5 {
+            match reader.read_u8()? {
+                GROUPS_MODULO => GroupMapping::Modulo,
+                GROUPS_SPLIT => GroupMapping::Split,
+                _ => return Err(invalid("Unknown group mapping")),
+            }
+        } else if placement_groups.is_some() {
+            // Placement groups were only ever used with splitting
+            GroupMapping::Split
+        } else {
+            GroupMapping::Modulo
+        };
+        let hash = if version >= 4 {
+            match reader.read_u8()? {
+                HASH_FX => HashVersion::Fx,
+                HASH_MURMUR3 => HashVersion::Murmur3,
+                _ => return Err(invalid("Unknown hash function")),
+            }
+        } else {
+            HashVersion::Fx
+        };
+        let rule = if version >= 2 {
+            match reader.read_u8()? {
+                RULE_ANY => PlacementRule::Any,
+                RULE_DISTINCT_AT_DEPTH => PlacementRule::DistinctAtDepth(reader.read_u32::<BigEndian>()?),
+                _ => return Err(invalid("Unknown placement rule")),
+            }
+        } else {
+            PlacementRule::Any
+        };
+        let map_root = decode_node(&mut reader, 0)?;
+        if reader.position() as usize != data.len() {
+            return Err(invalid("Extra data after storage map"));
+        }
+        Ok(StorageMap {
+            generation,
+            groups,
+            replicas,
+            group_mapping,
+            placement_groups,
+            hash,
+            rule,
+            map_root,
+        })
+    }
+}
+
+fn encode_node(node: &Node, result: &mut Vec<u8>) {
+    match node {
+        Node::Device(id) => {
+            result.write_u8(NODE_DEVICE).unwrap();
+            result.extend_from_slice(&id.0);
+        }
+        Node::Bucket(bucket) => {
+            result.write_u8(NODE_BUCKET).unwrap();
+            result.write_u32::<BigEndian>(bucket.id).unwrap();
+            match bucket.pick_mode {
+                PickMode::PseudoRandom => result.write_u8(PICK_PSEUDO_RANDOM).unwrap(),
+                PickMode::NeverRepeat => result.write_u8(PICK_NEVER_REPEAT).unwrap(),
+            }
+            result.write_u32::<BigEndian>(bucket.children.len() as u32).unwrap();
+
+            // Algorithm, followed by its parameters
+            match bucket.algorithm {
+                Algorithm::Uniform => result.write_u8(ALGORITHM_UNIFORM).unwrap(),
+                Algorithm::Straw(ref factors) => {
+                    result.write_u8(ALGORITHM_STRAW).unwrap();
+                    for &factor in factors {
+                        result.write_u32::<BigEndian>(factor).unwrap();
+                    }
+                }
+                Algorithm::Straw2 => result.write_u8(ALGORITHM_STRAW2).unwrap(),
+                Algorithm::Tree(ref node_weights) => {
+                    result.write_u8(ALGORITHM_TREE).unwrap();
+                    for &weight in node_weights {
+                        result.write_u32::<BigEndian>(weight).unwrap();
+                    }
+                }
+                Algorithm::Alias(ref table) => {
+                    result.write_u8(ALGORITHM_ALIAS).unwrap();
+                    for &(threshold, alias) in table {
+                        result.write_u32::<BigEndian>(threshold).unwrap();
+                        result.write_u32::<BigEndian>(alias).unwrap();
+                    }
+                }
+                Algorithm::List => result.write_u8(ALGORITHM_LIST).unwrap(),
+                Algorithm::Fallback => result.write_u8(ALGORITHM_FALLBACK).unwrap(),
+            }
+
+            // Children
+            for child in &bucket.children {
+                result.write_u32::<BigEndian>(child.weight).unwrap();
+                encode_node(&child.node, result);
+            }
+        }
+    }
+}
+
+fn decode_node(reader: &mut Cursor<&[u8]>, depth: u32) -> Result<Node, IoError> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("Storage map is too deep"));
+    }
+    match reader.read_u8()? {
+        NODE_DEVICE => {
+            let mut id = [0; 16];
+            reader.read_exact(&mut id)?;
+            Ok(Node::Device(DeviceId(id)))
+        }
+        NODE_BUCKET => {
+            let id = reader.read_u32::<BigEndian>()?;
+            let pick_mode = match reader.read_u8()? {
+                PICK_PSEUDO_RANDOM => PickMode::PseudoRandom,
+                PICK_NEVER_REPEAT => PickMode::NeverRepeat,
+                _ => return Err(invalid("Unknown pick mode")),
+            };
+            let num_children = reader.read_u32::<BigEndian>()? as usize;
+            if num_children == 0 {
+                return Err(invalid("Empty bucket in storage map"));
+            }
+            if num_children > MAX_CHILDREN {
+                return Err(invalid("Too many children in bucket"));
+            }
+
+            // Algorithm, followed by its parameters
+            let algorithm = match reader.read_u8()? {
+                ALGORITHM_UNIFORM => Algorithm::Uniform,
+                ALGORITHM_STRAW => {
+                    let mut factors = Vec::with_capacity(num_children);
+                    for _ in 0..num_children {
+                        let factor = reader.read_u32::<BigEndian>()?;
+                        if factor == 0 {
+                            return Err(invalid("Invalid straw factor in bucket"));
+                        }
+                        factors.push(factor);
+                    }
+                    Algorithm::Straw(factors)
+                }
+                ALGORITHM_STRAW2 => Algorithm::Straw2,
+                ALGORITHM_TREE => {
+                    let len = 2 * num_children.next_power_of_two();
+                    let mut node_weights = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        node_weights.push(reader.read_u32::<BigEndian>()?);
+                    }
+                    Algorithm::Tree(node_weights)
+                }
+                ALGORITHM_ALIAS => {
+                    let mut table = Vec::with_capacity(num_children);
+                    for _ in 0..num_children {
+                        let threshold = reader.read_u32::<BigEndian>()?;
+                        let alias = reader.read_u32::<BigEndian>()?;
+                        if alias as usize >= num_children {
+                            return Err(invalid("Invalid alias in bucket"));
+                        }
+                        table.push((threshold, alias));
+                    }
+                    Algorithm::Alias(table)
+                }
+                ALGORITHM_LIST => Algorithm::List,
+                ALGORITHM_FALLBACK => Algorithm::Fallback,
+                _ => return Err(invalid("Unknown bucket algorithm")),
+            };
+
+            // Children
+            let mut children = Vec::with_capacity(num_children);
+            for _ in 0..num_children {
+                let weight = reader.read_u32::<BigEndian>()?;
+                let node = decode_node(reader, depth + 1)?;
+                children.push(NodeEntry { weight, node });
+            }
+            if let Algorithm::List = algorithm {
+                // Picking draws a number below the total weight
+                let total: u64 = children.iter().map(|c| c.weight as u64).sum();
+                if total == 0 || total > u32::MAX as u64 {
+                    return Err(invalid("Invalid weights in list bucket"));
+                }
+            }
+
+            Ok(Node::Bucket(Bucket {
+                id,
+                algorithm,
+                pick_mode,
+                children,
+            }))
+        }
+        _ => Err(invalid("Unknown node type")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceId;
+    use super::super::{Algorithm, Bucket, GroupMapping, HashVersion, MAX_GROUPS, Node, NodeEntry, PickMode, PlacementRule, StorageMap, build_alias_bucket, build_straw_bucket, build_tree_bucket};
+
+    fn example_map() -> StorageMap {
+        let hosts = vec![
+            NodeEntry {
+                weight: 2,
+                node: Node::Bucket(Bucket {
+                    id: 1,
+                    algorithm: Algorithm::List,
+                    pick_mode: PickMode::PseudoRandom,
+                    children: vec![
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([1; 16])) },
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([2; 16])) },
+                    ],
+                }),
+            },
+            NodeEntry {
+                weight: 1,
+                node: Node::Bucket(build_tree_bucket(
+                    vec![
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([4; 16])) },
+                        NodeEntry { weight: 2, node: Node::Device(DeviceId([5; 16])) },
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([6; 16])) },
+                    ],
+                    3,
+                    PickMode::PseudoRandom,
+                )),
+            },
+            NodeEntry {
+                weight: 2,
+                node: Node::Bucket(build_alias_bucket(
+                    vec![
+                        NodeEntry { weight: 3, node: Node::Device(DeviceId([7; 16])) },
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([8; 16])) },
+                    ],
+                    4,
+                    PickMode::PseudoRandom,
+                )),
+            },
+            NodeEntry {
+                weight: 1,
+                node: Node::Bucket(Bucket {
+                    id: 2,
+                    algorithm: Algorithm::Straw2,
+                    pick_mode: PickMode::PseudoRandom,
+                    children: vec![
+                        NodeEntry { weight: 1, node: Node::Device(DeviceId([3; 16])) },
+                    ],
+                }),
+            },
+        ];
+        StorageMap {
+            generation: 12,
+            groups: 128,
+            replicas: 2,
+            group_mapping: GroupMapping::Split,
+            placement_groups: Some(100),
+            hash: HashVersion::Murmur3,
+            rule: PlacementRule::DistinctAtDepth(1),
+            map_root: Node::Bucket(build_straw_bucket(hosts, 0, PickMode::NeverRepeat)),
+        }
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let map = example_map();
+        let encoded = map.encode();
+        assert_eq!(encoded[0], 5);
+        assert_eq!(StorageMap::decode(&encoded).unwrap(), map);
+
+        // Truncated or extended data is rejected
+        assert!(StorageMap::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut extended = encoded.clone();
+        extended.push(0);
+        assert!(StorageMap::decode(&extended).is_err());
+
+        // Version 4 had no group mapping, groups split only with placement
+        // groups
+        let mut version4 = vec![4];
+        version4.extend_from_slice(&encoded[1..17]);
+        version4.extend_from_slice(&encoded[18..]);
+        let decoded = StorageMap::decode(&version4).unwrap();
+        assert_eq!(decoded, map);
+        version4[13..17].copy_from_slice(&[0; 4]);
+        let decoded = StorageMap::decode(&version4).unwrap();
+        assert_eq!(decoded.group_mapping, GroupMapping::Modulo);
+        assert_eq!(decoded.placement_groups, None);
+
+        // Version 3 had no hash function
+        let mut version3 = vec![3];
+        version3.extend_from_slice(&encoded[1..17]);
+        version3.extend_from_slice(&encoded[19..]);
+        let decoded = StorageMap::decode(&version3).unwrap();
+        assert_eq!(decoded.hash, HashVersion::Fx);
+        assert_eq!(decoded.placement_groups, map.placement_groups);
+        assert_eq!(decoded.map_root, map.map_root);
+
+        // Version 2 had no placement groups
+        let mut version2 = vec![2];
+        version2.extend_from_slice(&encoded[1..13]);
+        version2.extend_from_slice(&encoded[19..]);
+        let decoded = StorageMap::decode(&version2).unwrap();
+        assert_eq!(decoded.group_mapping, GroupMapping::Modulo);
+        assert_eq!(decoded.placement_groups, None);
+        assert_eq!(decoded.rule, map.rule);
+        assert_eq!(decoded.map_root, map.map_root);
+
+        // Version 1 had no placement rule either
+        let mut version1 = vec![1];
+        version1.extend_from_slice(&encoded[1..13]);
+        version1.extend_from_slice(&encoded[24..]);
+        let decoded = StorageMap::decode(&version1).unwrap();
+        assert_eq!(decoded.rule, PlacementRule::Any);
+        assert_eq!(decoded.map_root, map.map_root);
+
+        // Too many groups is rejected
+        let mut too_many = encoded.clone();
+        too_many[5..9].copy_from_slice(&(MAX_GROUPS as u32 + 1).to_be_bytes());
+        assert!(StorageMap::decode(&too_many).is_err());
+
+        // No replicas, or too many, is rejected
+        let mut no_replicas = encoded.clone();
+        no_replicas[9..13].copy_from_slice(&0u32.to_be_bytes());
+        assert!(StorageMap::decode(&no_replicas).is_err());
+        let mut too_many = encoded.clone();
+        too_many[9..13].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(StorageMap::decode(&too_many).is_err());
+
+        // Unknown version is rejected
+        let mut wrong_version = encoded;
+        wrong_version[0] = 42;
+        assert!(StorageMap::decode(&wrong_version).is_err());
+    }
+
+    #[test]
+    fn test_decode_zero_weights() {
+        // Those would divide by zero when computing locations
+        let with_root = |algorithm| {
+            let mut map = example_map();
+            map.map_root = Node::Bucket(Bucket {
+                id: 0,
+                algorithm,
+                pick_mode: PickMode::PseudoRandom,
+                children: vec![
+                    NodeEntry { weight: 0, node: Node::Device(DeviceId([1; 16])) },
+                    NodeEntry { weight: 0, node: Node::Device(DeviceId([2; 16])) },
+                ],
+            });
+            map.encode()
+        };
+        assert!(StorageMap::decode(&with_root(Algorithm::List)).is_err());
+        assert!(StorageMap::decode(&with_root(Algorithm::Straw(vec![1, 0]))).is_err());
+        assert!(StorageMap::decode(&with_root(Algorithm::Straw(vec![1, 1]))).is_ok());
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let map = example_map();
+        let json = serde_json::to_string(&map).unwrap();
+        let decoded: StorageMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, map);
+    }
+}
@@ -0,0 +1,1170 @@
+mod analysis;
+pub mod builder;
+mod diff;
+mod encoding;
+pub mod overlay;
+mod validate;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::{DeviceId, GroupId, ObjectId};
+use crate::hash::{compute_hash, compute_object_hash, mix32};
+pub use crate::hash::HashVersion;
+pub use analysis::{DeviceLoad, Distribution};
+pub use diff::{GroupChange, MapDiff};
+pub use validate::MapProblem;
+use overlay::MapOverlay;
+
+/// How many times to try placing a replica before giving up.
+const MAX_REPLICA_TRIES: usize = 50;
+
+/// How many times to pick in a bucket before giving up.
+const MAX_BUCKET_ATTEMPTS: u32 = 50;
+
+/// The configuration for a storage pool.
+///
+/// This contains the tree used to map a group to a device, as well as the
+/// current number of groups.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageMap {
+    pub generation: u32,
+    pub groups: usize,
+    pub replicas: u32,
+    /// How objects are assigned to groups.
+    #[serde(default)]
+    pub group_mapping: GroupMapping,
+    /// Number of groups used for placement, if lower than `groups`.
+    ///
+    /// When `groups` is increased, groups split and the new groups get placed
+    /// on the same devices as the group they came from, until this is raised
+    /// as well. This allows splitting without moving data, and requires
+    /// `GroupMapping::Split`.
+    #[serde(default)]
+    pub placement_groups: Option<usize>,
+    /// The hash function used for placement.
+    #[serde(default)]
+    pub hash: HashVersion,
+    #[serde(default)]
+    pub rule: PlacementRule,
+    pub map_root: Node,
+}
+
+/// How objects are assigned to groups.
+///
+/// Changing it moves most of the data, so it is recorded in the map, and
+/// existing maps keep the one they were created with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupMapping {
+    /// The hash modulo the number of groups, used by the first maps.
+    ///
+    /// Changing the number of groups moves about half of the objects.
+    #[default]
+    Modulo,
+    /// Groups split in two as more are added (see `stable_mod()`), so the
+    /// number of groups can grow without moving data.
+    Split,
+}
+
+/// Constraint on where the replicas of a group can be placed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlacementRule {
+    /// Replicas go to distinct devices, only `NeverRepeat` buckets constrain
+    /// them further.
+    #[default]
+    Any,
+    /// Replicas have to be under distinct nodes at this depth of the tree.
+    ///
+    /// The root is at depth 0. For example in a tree root > rack > host >
+    /// device, `DistinctAtDepth(2)` puts replicas on different hosts.
+    DistinctAtDepth(u32),
+}
+
+/// Most groups a map can have, so group IDs and their rounding up to a power
+/// of two fit in a u32.
+pub const MAX_GROUPS: usize = 1 << 31;
+
+/// Modulo that keeps values stable when the divisor grows.
+///
+/// Increasing `n` by one splits a single group in two: values either keep
+/// their result or move to the new group `n`. For powers of two, this is the
+/// usual modulo.
+fn stable_mod(x: u32, n: usize) -> u32 {
+    let mask = (n as u32).next_power_of_two() - 1;
+    if (x & mask) < n as u32 {
+        x & mask
+    } else {
+        x & (mask >> 1)
+    }
+}
+
+/// Gets the group that a group was split from, when there were fewer groups.
+pub fn parent_group(group_id: &GroupId, groups: usize) -> GroupId {
+    GroupId(stable_mod(group_id.0, groups))
+}
+
+impl StorageMap {
+    /// A map sending every group to a single device.
+    ///
+    /// This is used by clients and daemons until the master sends them the
+    /// actual map.
+    pub fn single_device(device_id: DeviceId) -> StorageMap {
+        StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas: 1,
+            group_mapping: GroupMapping::Split,
+            placement_groups: None,
+            hash: HashVersion::Murmur3,
+            rule: PlacementRule::Any,
+            map_root: Node::Device(device_id),
+        }
+    }
+
+    pub fn object_to_group(&self, object_id: &ObjectId) -> GroupId {
+        let h = compute_object_hash(self.hash, object_id);
+        match self.group_mapping {
+            GroupMapping::Modulo => GroupId(h % self.groups as u32),
+            GroupMapping::Split => GroupId(stable_mod(h, self.groups)),
+        }
+    }
+
+    /// Gets the group whose placement is used for this group.
+    ///
+    /// This is the group itself, unless it was split from a group that hasn't
+    /// been moved yet (see `placement_groups`).
+    pub fn placement_group(&self, group_id: &GroupId) -> GroupId {
+        match self.placement_groups {
+            Some(placement_groups) if placement_groups < self.groups => {
+                parent_group(group_id, placement_groups)
+            }
+            _ => group_id.clone(),
+        }
+    }
+
+    /// Gets the devices handling the given object group, in order.
+    ///
+    /// The devices are distinct and follow the map's placement rule. If not
+    /// enough devices can be found, fewer are returned.
+    pub fn group_to_devices(&self, group_id: &GroupId, replicas: usize) -> Vec<DeviceId> {
+        self.group_to_devices_with_overlay(group_id, replicas, &MapOverlay::new())
+    }
+
+    /// Gets the devices handling the given object group, skipping the devices
+    /// that the overlay marks as unavailable.
+    pub fn group_to_devices_with_overlay(&self, group_id: &GroupId, replicas: usize, overlay: &MapOverlay) -> Vec<DeviceId> {
+        let group_id = &self.placement_group(group_id);
+        let mut devices = Vec::with_capacity(replicas);
+        let mut domains = HashSet::new();
+        let mut already_picked = HashSet::new();
+        for i in 0..replicas {
+            let mut found = false;
+            for retry in 0..MAX_REPLICA_TRIES {
+                // Use a different input for each retry
+                let replica_num = (i + retry * replicas) as u32;
+                let mut path = Vec::new();
+                let device = match compute_location_with_path(
+                    &self.map_root,
+                    group_id,
+                    replica_num,
+                    0,
+                    &mut already_picked,
+                    overlay,
+                    self.hash,
+                    &mut path,
+                ) {
+                    Some(device) => device,
+                    None => break,
+                };
+
+                // Check constraints
+                if devices.contains(&device) {
+                    continue;
+                }
+                if let PlacementRule::DistinctAtDepth(depth) = self.rule {
+                    // If the depth is below the buckets, it's the device,
+                    // which we already know to be distinct
+                    if let Some(&bucket_id) = path.get(depth as usize) {
+                        if !domains.insert(bucket_id) {
+                            continue;
+                        }
+                    }
+                }
+
+                devices.push(device);
+                found = true;
+                break;
+            }
+            if !found {
+                break;
+            }
+        }
+        devices
+    }
+
+    /// Gets all the replicas of the given object group, per the map.
+    ///
+    /// Shortcut for `group_to_devices(group_id, self.replicas)`
+    pub fn group_to_replicas(&self, group_id: &GroupId) -> Vec<DeviceId> {
+        self.group_to_devices(group_id, self.replicas as usize)
+    }
+
+    /// Gets the first device handling the given object.
+    pub fn object_to_first_device(&self, object_id: &ObjectId) -> Option<DeviceId> {
+        self.group_to_first_device(&self.object_to_group(object_id))
+    }
+
+    /// Gets the first device handling the given object group.
+    ///
+    /// Shortcut for `group_to_devices.get(0)`
+    pub fn group_to_first_device(&self, group_id: &GroupId) -> Option<DeviceId> {
+        compute_location(&self.map_root, &self.placement_group(group_id), 0, 0, &mut HashSet::new(), self.hash)
+    }
+
+    /// Gets the first device handling the given object group, skipping the
+    /// devices that the overlay marks as unavailable.
+    pub fn group_to_first_device_with_overlay(&self, group_id: &GroupId, overlay: &MapOverlay) -> Option<DeviceId> {
+        let group_id = &self.placement_group(group_id);
+        compute_location_with_path(&self.map_root, group_id, 0, 0, &mut HashSet::new(), overlay, self.hash, &mut Vec::new())
+    }
+}
+
+/// A node in the storage map.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Node {
+    Device(DeviceId),
+    Bucket(Bucket),
+}
+
+/// Internal node in the storage map, allows picking one of multiple children.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub id: u32,
+    pub algorithm: Algorithm,
+    pub pick_mode: PickMode,
+    pub children: Vec<NodeEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PickMode {
+    /// Pseudo-random mode, pick whatever the hash function gives us.
+    PseudoRandom,
+    /// Don't pick the same child twice, fail instead.
+    NeverRepeat,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub weight: u32,
+    pub node: Node,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    Uniform,
+    Straw(Vec<u32>),
+    /// Straw drawing using the logarithm of the hash, scaled by the weights.
+    ///
+    /// This gives exact weight proportionality, and changing the weight of one
+    /// child only moves data from or to that child.
+    Straw2,
+    /// Binary tree over the children, for O(log n) selection in big buckets.
+    ///
+    /// This contains the total weight of each node of the tree, stored like a
+    /// binary heap: node `i` has children `2i` and `2i+1`, the root is at 1,
+    /// and the leaves are at the end. Use `build_tree_bucket()` to compute it.
+    Tree(Vec<u32>),
+    /// Alias table over the children, for O(1) weighted selection.
+    ///
+    /// A slot is drawn uniformly, then it is kept or replaced by its alias
+    /// depending on a second draw. This contains `(threshold, alias)` for each
+    /// slot, where the slot is kept if the draw out of `ALIAS_SCALE` is below
+    /// the threshold. Use `build_alias_bucket()` to compute it; it has to be
+    /// recomputed when weights change.
+    Alias(Vec<(u32, u32)>),
+    List,
+    Fallback,
+}
+
+/// Denominator of the thresholds in alias tables.
+pub const ALIAS_SCALE: u32 = 0x10000;
+
+fn draw_straw(group_id: &GroupId, replica_num: u32, level: u32, attempt: u32, idx: usize, weight: u32, hash: HashVersion) -> u32 {
+    let hash = compute_hash(hash, level, group_id, replica_num, attempt, idx);
+    hash % weight
+}
+
+fn draw_straw2(group_id: &GroupId, replica_num: u32, level: u32, attempt: u32, idx: usize, weight: u32, hash: HashVersion) -> f64 {
+    if weight == 0 {
+        return f64::NEG_INFINITY;
+    }
+    // Draws for different children need to be independent, so mix the bits
+    let hash = mix32(compute_hash(hash, level, group_id, replica_num, attempt, idx));
+    // Uniform number in (0, 1]
+    let u = ((hash >> 16) + 1) as f64 / 65536.0;
+    // ln(u) is negative, dividing by the weight brings it closer to zero
+    u.ln() / weight as f64
+}
+
+fn compute_location(node: &Node, group_id: &GroupId, replica_num: u32, level: u32, already_picked: &mut HashSet<(u32, u32)>, hash: HashVersion) -> Option<DeviceId> {
+    compute_location_with_path(node, group_id, replica_num, level, already_picked, &MapOverlay::new(), hash, &mut Vec::new())
+}
+
+/// Compute the location, recording the IDs of the buckets we go through.
+///
+/// Devices rejected by the overlay return `None`, so that the bucket above
+/// picks again. A bucket gives up and returns `None` once all its children
+/// have been picked (`NeverRepeat`) or tried (`Fallback`), or after
+/// `MAX_BUCKET_ATTEMPTS` picks that didn't lead to a device.
+#[allow(clippy::too_many_arguments)]
+fn compute_location_with_path(node: &Node, group_id: &GroupId, replica_num: u32, level: u32, already_picked: &mut HashSet<(u32, u32)>, overlay: &MapOverlay, hash: HashVersion, path: &mut Vec<u32>) -> Option<DeviceId> {
+    match node {
+        &Node::Device(ref id) => {
+            if overlay.is_rejected(id, group_id, hash) {
+                None
+            } else {
+                Some(id.clone())
+            }
+        }
+        &Node::Bucket(ref bucket) => {
+            path.push(bucket.id);
+            let max_attempts = match bucket.algorithm {
+                Algorithm::Fallback => bucket.children.len() as u32,
+                _ => MAX_BUCKET_ATTEMPTS,
+            };
+            let mut attempt = 0;
+            loop {
+                if attempt >= max_attempts || bucket.children.is_empty() {
+                    path.pop();
+                    return None;
+                }
+
+                // Check that there are still children to be picked
+                if let PickMode::NeverRepeat = bucket.pick_mode {
+                    let all_picked = (0..bucket.children.len())
+                        .all(|i| already_picked.contains(&(bucket.id, i as u32)));
+                    if all_picked {
+                        path.pop();
+                        return None;
+                    }
+                }
+
+                // Compute location based on bucket's algorithm
+                let index = compute_location_in_bucket(
+                    bucket,
+                    group_id,
+                    replica_num,
+                    level,
+                    attempt,
+                    hash,
+                );
+
+                // Avoid repeats by looping if child has already been picked
+                if let PickMode::NeverRepeat = bucket.pick_mode {
+                    // Mark it
+                    let was_already_marked = !already_picked.insert((bucket.id, index as u32));
+
+                    // Skip if already marked
+                    if was_already_marked {
+                        attempt += 1;
+                        continue;
+                    }
+                }
+
+                // Recursively process that child
+                if let Some(device) = compute_location_with_path(
+                    &bucket.children[index].node,
+                    group_id,
+                    replica_num,
+                    level + 1,
+                    already_picked,
+                    overlay,
+                    hash,
+                    path,
+                ) {
+                    return Some(device);
+                }
+
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn compute_location_in_bucket(bucket: &Bucket, group_id: &GroupId, replica_num: u32, level: u32, attempt: u32, hash: HashVersion) -> usize {
+    match bucket.algorithm {
+        Algorithm::Uniform => {
+            // Hash the input
+            let hash = compute_hash(hash, level, group_id, replica_num, attempt, 0);
+
+            // Pick the entry
+            hash as usize % bucket.children.len()
+        }
+        Algorithm::List => {
+            // Compute total weight
+            let total_weight: u32 = bucket.children.iter().map(|e| e.weight).sum();
+
+            // Draw
+            let mut hash = compute_hash(hash, level, group_id, replica_num, attempt, 0) % total_weight;
+            for (i, child) in bucket.children[0..bucket.children.len() - 1].iter().enumerate() {
+                if hash < child.weight {
+                    return i;
+                }
+                hash -= child.weight;
+            }
+            bucket.children.len() - 1
+        }
+        Algorithm::Straw(ref factors) => {
+            // Draw straws for every entry, scaled by the factors
+            let mut best = 0;
+            let mut best_straw = draw_straw(group_id, replica_num, level, attempt, 0, factors[0], hash);
+            for i in 1..bucket.children.len() {
+                let straw = draw_straw(group_id, replica_num, level, attempt, i, factors[i], hash);
+                if straw > best_straw {
+                    best = i;
+                    best_straw = straw;
+                }
+            }
+
+            best
+        }
+        Algorithm::Straw2 => {
+            let mut best = 0;
+            let mut best_straw = f64::NEG_INFINITY;
+            for (i, child) in bucket.children.iter().enumerate() {
+                let straw = draw_straw2(group_id, replica_num, level, attempt, i, child.weight, hash);
+                if straw > best_straw {
+                    best = i;
+                    best_straw = straw;
+                }
+            }
+
+            best
+        }
+        Algorithm::Tree(ref node_weights) => {
+            // Walk down from the root, picking a side based on their weight
+            let leaves = node_weights.len() / 2;
+            let mut node = 1;
+            while node < leaves {
+                let total = node_weights[node];
+                let left = node_weights[2 * node];
+                let h = mix32(compute_hash(hash, level, group_id, replica_num, attempt, node));
+                node = if total > 0 && h % total < left {
+                    2 * node
+                } else {
+                    2 * node + 1
+                };
+            }
+
+            // Padding leaves have no weight so are never reached
+            (node - leaves).min(bucket.children.len() - 1)
+        }
+        Algorithm::Alias(ref table) => {
+            // Pick a slot, then pick between it and its alias
+            let h = mix32(compute_hash(hash, level, group_id, replica_num, attempt, 0));
+            let slot = ((h as u64 * table.len() as u64) >> 32) as usize;
+            let coin = mix32(compute_hash(hash, level, group_id, replica_num, attempt, 1)) % ALIAS_SCALE;
+            let (threshold, alias) = table[slot];
+            if coin < threshold {
+                slot
+            } else {
+                alias as usize
+            }
+        }
+        Algorithm::Fallback => {
+            attempt as usize
+        }
+    }
+}
+
+pub fn build_tree_bucket(children: Vec<NodeEntry>, id: u32, pick_mode: PickMode) -> Bucket {
+    // Leaves are at the end, padded to a power of two
+    let leaves = children.len().next_power_of_two();
+    let mut node_weights = vec![0; 2 * leaves];
+    for (i, child) in children.iter().enumerate() {
+        node_weights[leaves + i] = child.weight;
+    }
+
+    // Sum weights up the tree
+    for node in (1..leaves).rev() {
+        node_weights[node] = node_weights[2 * node] + node_weights[2 * node + 1];
+    }
+
+    Bucket {
+        id,
+        algorithm: Algorithm::Tree(node_weights),
+        pick_mode,
+        children,
+    }
+}
+
+pub fn build_alias_bucket(children: Vec<NodeEntry>, id: u32, pick_mode: PickMode) -> Bucket {
+    // Scale weights so that the average is ALIAS_SCALE
+    let n = children.len() as u64;
+    let total: u64 = children.iter().map(|c| c.weight as u64).sum();
+    let mut scaled: Vec<u64> = children
+        .iter()
+        .map(|c| {
+            (c.weight as u64 * n * ALIAS_SCALE as u64)
+                .checked_div(total)
+                .unwrap_or(ALIAS_SCALE as u64)
+        })
+        .collect();
+
+    // Vose's method: fill the slots of light children with heavy ones
+    let mut table: Vec<(u32, u32)> = (0..children.len() as u32).map(|i| (ALIAS_SCALE, i)).collect();
+    let mut small: Vec<usize> = (0..children.len()).filter(|&i| scaled[i] < ALIAS_SCALE as u64).collect();
+    let mut large: Vec<usize> = (0..children.len()).filter(|&i| scaled[i] >= ALIAS_SCALE as u64).collect();
+    while let (Some(&s), Some(&l)) = (small.last(), large.last()) {
+        small.pop();
+        table[s] = (scaled[s] as u32, l as u32);
+        scaled[l] -= ALIAS_SCALE as u64 - scaled[s];
+        if scaled[l] < ALIAS_SCALE as u64 {
+            large.pop();
+            small.push(l);
+        }
+    }
+    // Whatever remains is full, up to rounding errors
+
+    Bucket {
+        id,
+        algorithm: Algorithm::Alias(table),
+        pick_mode,
+        children,
+    }
+}
+
+pub fn build_straw_bucket(children: Vec<NodeEntry>, id: u32, pick_mode: PickMode) -> Bucket {
+    // Sort weights from highest to lowest
+    let mut order: Vec<usize> = (0..children.len()).collect();
+    order.sort_by_key(|&i| -(children[i].weight as i32));
+
+    // Turn given weights into probabilities
+    let total: u32 = children.iter().map(|i| i.weight).sum();
+    let probs: Vec<f64> = (0..children.len())
+        .map(|i| children[order[i]].weight as f64 / total as f64)
+        .collect();
+
+    // Compute factors for desired probabilities
+    let mut factors: Vec<u32> = vec![0; children.len()];
+    factors[order[0]] = 0x100000;
+    let mut mult = 1.0;
+    for i in 1..children.len() {
+        factors[order[i]] = (factors[order[i - 1]] as f32 * (1.0 - i as f32 * mult * (probs[i - 1] - probs[i]) as f32).powf(1.0 / i as f32)) as u32;
+        mult *= (factors[order[i - 1]] as f32 / factors[order[i]] as f32).powf(i as f32);
+    }
+
+    Bucket {
+        id,
+        algorithm: Algorithm::Straw(factors),
+        pick_mode,
+        children: children,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::overlay::DeviceStatus;
+    use super::{Algorithm, Bucket, DeviceId, GroupId, GroupMapping, HashVersion, MapOverlay, Node, NodeEntry, ObjectId, PickMode, PlacementRule, StorageMap, build_alias_bucket, build_straw_bucket, build_tree_bucket, compute_location, parent_group, stable_mod};
+
+    fn object_id(num: usize) -> ObjectId {
+        ObjectId(vec![
+            num as u8,
+            (num >> 8) as u8,
+            (num >> 16) as u8,
+            (num >> 24) as u8,
+        ])
+    }
+
+    fn assert_frequencies(counts: &[usize], target: &[f32]) {
+        assert_eq!(counts.len(), target.len());
+        let total: usize = counts.iter().sum();
+        let frequencies: Vec<f32> = counts.iter().map(|&c| c as f32 / total as f32).collect();
+        assert!(
+            frequencies
+                .iter()
+                .enumerate()
+                .all(|(i, f)| f - 0.01 <= target[i] && f + 0.01 >= target[i]),
+            "{:.2?} != {:.2?}",
+            frequencies,
+            target,
+        );
+    }
+
+    #[test]
+    fn test_groups() {
+        fn equal_1percent(a: usize, b: usize) -> bool {
+            a * 100 >= b * 99 && a * 100 <= b * 101
+        }
+
+        const OBJECTS: usize = 100000;
+        let objects: Vec<ObjectId> = (0..OBJECTS).into_iter().map(object_id).collect();
+
+        // Map objects to groups
+        const GROUPS1: usize = 128;
+        let map1 = StorageMap {
+            generation: 1,
+            groups: GROUPS1,
+            replicas: 1,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Device(DeviceId([1; 16])),
+        };
+        let mut group_counts1 = [0; GROUPS1];
+        for obj in &objects {
+            let group = map1.object_to_group(obj);
+            group_counts1[group.0 as usize] += 1;
+        }
+
+        // Assert it is about even (2%)
+        for &count in &group_counts1 {
+            assert!(equal_1percent(count * GROUPS1, OBJECTS));
+        }
+
+        // Map objects to groups using a different number of groups
+        const GROUPS2: usize = 256;
+        let map2 = StorageMap {
+            generation: 1,
+            groups: GROUPS2,
+            replicas: 1,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Device(DeviceId([1; 16])),
+        };
+        let mut group_counts2 = [0; GROUPS2];
+        for obj in &objects {
+            let group = map2.object_to_group(obj);
+            group_counts2[group.0 as usize] += 1;
+        }
+
+        // Assert it is even also
+        for &count in &group_counts2 {
+            assert!(equal_1percent(count * GROUPS2, OBJECTS));
+        }
+
+        // Assert the number of differences
+        let mut move_to_new = 0;
+        let mut move_inner = 0;
+        for obj in &objects {
+            let group1 = map1.object_to_group(obj);
+            let group2 = map2.object_to_group(obj);
+            if group1 == group2 {
+                // No movement
+            } else if group2.0 as usize >= GROUPS1 {
+                move_to_new += 1;
+            } else {
+                move_inner += 1;
+            }
+        }
+        eprintln!("moved to new groups:{}", move_to_new);
+        eprintln!("moved in old groups:{}", move_inner);
+        assert_eq!(move_inner, 0);
+        // About half the objects are moved
+        assert!(equal_1percent(move_to_new * 2, OBJECTS));
+    }
+
+    #[test]
+    fn test_split_groups() {
+        // Power of two is the usual modulo
+        for i in 0..1000 {
+            assert_eq!(stable_mod(i, 64), i % 64);
+        }
+
+        // Adding one group only splits one group
+        const OBJECTS: usize = 10000;
+        for groups in 100..130 {
+            let mut split_from = HashSet::new();
+            for i in 0..OBJECTS as u32 {
+                let before = stable_mod(i, groups);
+                let after = stable_mod(i, groups + 1);
+                assert!(after < groups as u32 + 1);
+                if before != after {
+                    assert_eq!(after, groups as u32);
+                    split_from.insert(before);
+                }
+                assert_eq!(parent_group(&GroupId(after), groups), GroupId(before));
+            }
+            assert_eq!(split_from.len(), 1);
+        }
+
+        // Objects don't change devices until placement groups are raised
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Straw2,
+            pick_mode: PickMode::NeverRepeat,
+            children: (1..5)
+                .map(|d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                .collect(),
+        });
+        let map1 = StorageMap {
+            generation: 1,
+            groups: 100,
+            replicas: 2,
+            group_mapping: GroupMapping::Split,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: root,
+        };
+        let mut map2 = map1.clone();
+        map2.groups = 150;
+        map2.placement_groups = Some(100);
+        for i in 0..OBJECTS {
+            let obj = object_id(i);
+            assert_eq!(
+                map1.group_to_devices(&map1.object_to_group(&obj), 2),
+                map2.group_to_devices(&map2.object_to_group(&obj), 2),
+            );
+        }
+    }
+
+    #[test]
+    fn test_modulo_groups() {
+        // Existing maps keep their placement, whatever their number of groups
+        let map = StorageMap {
+            generation: 1,
+            groups: 100,
+            replicas: 1,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Device(DeviceId([1; 16])),
+        };
+        let groups: Vec<u32> = (0..8).map(|i| map.object_to_group(&object_id(i)).0).collect();
+        assert_eq!(groups, [0, 57, 14, 71, 28, 85, 42, 3]);
+
+        // Splitting groups would have moved some of them
+        let split = StorageMap { group_mapping: GroupMapping::Split, ..map };
+        let split_groups: Vec<u32> = (0..8).map(|i| split.object_to_group(&object_id(i)).0).collect();
+        assert!(split_groups != groups);
+    }
+
+    #[test]
+    fn test_uniform() {
+        let root = Node::Bucket(
+            Bucket {
+                id: 0,
+                algorithm: Algorithm::Uniform,
+                pick_mode: PickMode::PseudoRandom,
+                children: vec![
+                    // Note that the weights do nothing
+                    NodeEntry {
+                        weight: 1,
+                        node: Node::Device(DeviceId([1; 16])),
+                    },
+                    NodeEntry {
+                        weight: 2,
+                        node: Node::Device(DeviceId([2; 16])),
+                    },
+                    NodeEntry {
+                        weight: 3,
+                        node: Node::Device(DeviceId([3; 16])),
+                    },
+                ],
+            }
+        );
+        let target = [0.333, 0.333, 0.333];
+
+        let mut counts = [0; 3];
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+    }
+
+    #[test]
+    fn test_list() {
+        let root = Node::Bucket(
+            Bucket {
+                id: 0,
+                algorithm: Algorithm::List,
+                pick_mode: PickMode::PseudoRandom,
+                children: vec![
+                    NodeEntry {
+                        weight: 4,
+                        node: Node::Device(DeviceId([1; 16])),
+                    },
+                    NodeEntry {
+                        weight: 3,
+                        node: Node::Device(DeviceId([2; 16])),
+                    },
+                    NodeEntry {
+                        weight: 1,
+                        node: Node::Device(DeviceId([3; 16])),
+                    },
+                    NodeEntry {
+                        weight: 2,
+                        node: Node::Device(DeviceId([4; 16])),
+                    },
+                ],
+            }
+        );
+        let target = [0.4, 0.3, 0.1, 0.2];
+
+        let mut counts = [0; 4];
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+    }
+
+    #[test]
+    fn test_straw() {
+        let root = build_straw_bucket(
+            vec![
+                NodeEntry { weight: 1, node: Node::Device(DeviceId([1; 16])) },
+                NodeEntry { weight: 3, node: Node::Device(DeviceId([2; 16])) },
+                NodeEntry { weight: 4, node: Node::Device(DeviceId([3; 16])) },
+                NodeEntry { weight: 2, node: Node::Device(DeviceId([4; 16])) },
+            ],
+            0,
+            PickMode::PseudoRandom,
+        );
+        let factors = match root.algorithm {
+            Algorithm::Straw(ref factors) => factors,
+            _ => panic!("Invalid algorithm"),
+        };
+        assert_eq!(factors[0], 690648);
+        assert_eq!(factors[1], 943718);
+        assert_eq!(factors[2], 1048576);
+        assert_eq!(factors[3], 832281);
+
+        let root = Node::Bucket(root);
+        let target = [0.1, 0.3, 0.4, 0.2];
+
+        let mut counts = [0; 4];
+        const NUM: usize = 1000000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+    }
+
+    #[test]
+    fn test_straw2() {
+        fn make_root(weights: &[u32]) -> Node {
+            Node::Bucket(Bucket {
+                id: 0,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::PseudoRandom,
+                children: weights
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &weight)| NodeEntry {
+                        weight,
+                        node: Node::Device(DeviceId([i as u8 + 1; 16])),
+                    })
+                    .collect(),
+            })
+        }
+
+        let root = make_root(&[1, 3, 4, 2]);
+        let target = [0.1, 0.3, 0.4, 0.2];
+
+        let mut counts = [0; 4];
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+
+        // Change the weight of one child, only moves to that child happen
+        let new_root = make_root(&[1, 3, 6, 2]);
+        let mut moved = 0;
+        for i in 0..NUM {
+            let before = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            let after = compute_location(&new_root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            if before != after {
+                assert_eq!(after, DeviceId([3; 16]));
+                moved += 1;
+            }
+        }
+        // 40% -> 50%, about 10% of groups should move
+        assert!(moved > NUM * 8 / 100 && moved < NUM * 12 / 100, "moved {}", moved);
+
+        // Zero weight is never picked
+        let root = make_root(&[1, 0, 1]);
+        for i in 0..1000 {
+            let device = compute_location(&root, &GroupId(i), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            assert_ne!(device, DeviceId([2; 16]));
+        }
+    }
+
+    #[test]
+    fn test_alias() {
+        let root = build_alias_bucket(
+            vec![
+                NodeEntry { weight: 1, node: Node::Device(DeviceId([1; 16])) },
+                NodeEntry { weight: 3, node: Node::Device(DeviceId([2; 16])) },
+                NodeEntry { weight: 4, node: Node::Device(DeviceId([3; 16])) },
+                NodeEntry { weight: 0, node: Node::Device(DeviceId([4; 16])) },
+                NodeEntry { weight: 2, node: Node::Device(DeviceId([5; 16])) },
+            ],
+            0,
+            PickMode::PseudoRandom,
+        );
+        match root.algorithm {
+            Algorithm::Alias(ref table) => {
+                assert_eq!(table.len(), 5);
+                // Zero weight is never kept
+                assert_eq!(table[3].0, 0);
+            }
+            _ => panic!("Expected an alias bucket"),
+        }
+
+        let root = Node::Bucket(root);
+        let target = [0.1, 0.3, 0.4, 0.0, 0.2];
+
+        let mut counts = [0; 5];
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+    }
+
+    #[test]
+    fn test_tree() {
+        let root = build_tree_bucket(
+            vec![
+                NodeEntry { weight: 1, node: Node::Device(DeviceId([1; 16])) },
+                NodeEntry { weight: 3, node: Node::Device(DeviceId([2; 16])) },
+                NodeEntry { weight: 4, node: Node::Device(DeviceId([3; 16])) },
+                NodeEntry { weight: 0, node: Node::Device(DeviceId([4; 16])) },
+                NodeEntry { weight: 2, node: Node::Device(DeviceId([5; 16])) },
+            ],
+            0,
+            PickMode::PseudoRandom,
+        );
+        let node_weights = match root.algorithm {
+            Algorithm::Tree(ref w) => w,
+            _ => panic!("Invalid algorithm"),
+        };
+        assert_eq!(
+            node_weights,
+            &vec![0, 10, 8, 2, 4, 4, 2, 0, 1, 3, 4, 0, 2, 0, 0, 0],
+        );
+
+        let root = Node::Bucket(root);
+        let target = [0.1, 0.3, 0.4, 0.0, 0.2];
+
+        let mut counts = [0; 5];
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &target);
+
+        // Big bucket
+        let children = (0..1000)
+            .map(|i| NodeEntry {
+                weight: 1,
+                node: Node::Device(DeviceId([(i % 4) as u8 + 1, (i / 4) as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])),
+            })
+            .collect();
+        let root = Node::Bucket(build_tree_bucket(children, 0, PickMode::PseudoRandom));
+        let mut counts = [0; 4];
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        assert_frequencies(&counts, &[0.25, 0.25, 0.25, 0.25]);
+    }
+
+    #[test]
+    fn test_hash_versions() {
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Straw2,
+            pick_mode: PickMode::PseudoRandom,
+            children: (1..5)
+                .map(|d| NodeEntry { weight: d as u32, node: Node::Device(DeviceId([d; 16])) })
+                .collect(),
+        });
+        let mut counts = [0; 4];
+        let mut different = 0;
+        const NUM: usize = 100000;
+        for i in 0..NUM {
+            let device = compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Murmur3).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+            if Some(device) != compute_location(&root, &GroupId(i as u32), 0, 0, &mut HashSet::new(), HashVersion::Fx) {
+                different += 1;
+            }
+        }
+
+        assert_frequencies(&counts, &[0.1, 0.2, 0.3, 0.4]);
+        // The hash function changes placement
+        assert!(different > NUM / 2);
+    }
+
+    #[test]
+    fn test_distinct_at_depth() {
+        // Three hosts, with pseudo-random buckets only
+        let host = |id: u32, devices: &[u8]| NodeEntry {
+            weight: devices.len() as u32,
+            node: Node::Bucket(Bucket {
+                id,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::PseudoRandom,
+                children: devices
+                    .iter()
+                    .map(|&d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                    .collect(),
+            }),
+        };
+        let mut map = StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas: 3,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Bucket(Bucket {
+                id: 0,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::PseudoRandom,
+                children: vec![host(1, &[1, 2]), host(2, &[3, 4]), host(3, &[5, 6])],
+            }),
+        };
+        let host_of = |d: &DeviceId| (d.0[0] - 1) / 2;
+
+        // Without the rule, devices are distinct but hosts can be repeated
+        let mut repeated = 0;
+        for i in 0..1000 {
+            let devices = map.group_to_devices(&GroupId(i), 3);
+            assert_eq!(devices.len(), 3);
+            let hosts: HashSet<u8> = devices.iter().map(host_of).collect();
+            if hosts.len() < 3 {
+                repeated += 1;
+            }
+        }
+        assert!(repeated > 0);
+
+        // With the rule, hosts are distinct
+        map.rule = PlacementRule::DistinctAtDepth(1);
+        for i in 0..1000 {
+            let devices = map.group_to_devices(&GroupId(i), 3);
+            assert_eq!(devices.len(), 3);
+            let hosts: HashSet<u8> = devices.iter().map(host_of).collect();
+            assert_eq!(hosts.len(), 3);
+            // First device doesn't change
+            assert_eq!(Some(devices[0].clone()), map.group_to_first_device(&GroupId(i)));
+        }
+
+        // Can't place 4 replicas on 3 hosts
+        assert_eq!(map.group_to_devices(&GroupId(0), 4).len(), 3);
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let host = |id: u32, devices: &[u8]| NodeEntry {
+            weight: devices.len() as u32,
+            node: Node::Bucket(Bucket {
+                id,
+                algorithm: Algorithm::Uniform,
+                pick_mode: PickMode::NeverRepeat,
+                children: devices
+                    .iter()
+                    .map(|&d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                    .collect(),
+            }),
+        };
+
+        // Pseudo-random root over never-repeat hosts, which run out
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Straw2,
+            pick_mode: PickMode::PseudoRandom,
+            children: vec![host(1, &[1]), host(2, &[2])],
+        });
+        for i in 0..100 {
+            let mut already_picked = HashSet::new();
+            assert!(compute_location(&root, &GroupId(i), 0, 0, &mut already_picked, HashVersion::Fx).is_some());
+            assert!(compute_location(&root, &GroupId(i), 1, 0, &mut already_picked, HashVersion::Fx).is_some());
+            assert_eq!(compute_location(&root, &GroupId(i), 2, 0, &mut already_picked, HashVersion::Fx), None);
+        }
+
+        // All devices are down
+        let map = StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas: 2,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: root,
+        };
+        let mut overlay = MapOverlay::new();
+        overlay.set_status(DeviceId([1; 16]), DeviceStatus::Down);
+        overlay.set_status(DeviceId([2; 16]), DeviceStatus::Out);
+        for i in 0..100 {
+            assert_eq!(map.group_to_devices_with_overlay(&GroupId(i), 2, &overlay), vec![]);
+            assert_eq!(map.group_to_first_device_with_overlay(&GroupId(i), &overlay), None);
+        }
+
+        // Fallback tries each child once
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Fallback,
+            pick_mode: PickMode::PseudoRandom,
+            children: vec![host(1, &[1]), host(2, &[2])],
+        });
+        let mut already_picked = HashSet::new();
+        assert!(compute_location(&root, &GroupId(0), 0, 0, &mut already_picked, HashVersion::Fx).is_some());
+        assert!(compute_location(&root, &GroupId(0), 1, 0, &mut already_picked, HashVersion::Fx).is_some());
+        assert_eq!(compute_location(&root, &GroupId(0), 2, 0, &mut already_picked, HashVersion::Fx), None);
+
+        // Empty bucket
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Uniform,
+            pick_mode: PickMode::PseudoRandom,
+            children: vec![],
+        });
+        assert_eq!(compute_location(&root, &GroupId(0), 0, 0, &mut HashSet::new(), HashVersion::Fx), None);
+    }
+
+    #[test]
+    fn test_never_repeat() {
+        let root = Node::Bucket(Bucket {
+            id: 0,
+            algorithm: Algorithm::Uniform,
+            pick_mode: PickMode::NeverRepeat,
+            children: (1..4)
+                .map(|d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                .collect(),
+        });
+        for i in 0..100 {
+            let mut already_picked = HashSet::new();
+            let mut devices = HashSet::new();
+            for r in 0..3 {
+                let device = compute_location(&root, &GroupId(i), r, 0, &mut already_picked, HashVersion::Fx).unwrap();
+                assert!(devices.insert(device));
+            }
+            assert_eq!(compute_location(&root, &GroupId(i), 3, 0, &mut already_picked, HashVersion::Fx), None);
+        }
+    }
+}
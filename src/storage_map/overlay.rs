@@ -0,0 +1,181 @@
+//! Device status overlaid on top of a storage map.
+//!
+//! Publishing a new map moves data around, which is not what we want when a
+//! device is only temporarily unreachable. Instead, the master keeps an
+//! overlay of device statuses and reweight factors, and placement skips the
+//! devices it marks as unavailable.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{DeviceId, GroupId};
+use crate::hash::{HashVersion, compute_device_hash};
+
+/// Reweight factor for a device that gets its full share of data.
+pub const FULL_WEIGHT: u32 = 0x10000;
+
+/// The status of a device, as seen by the master.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceStatus {
+    /// The device is working normally.
+    Up,
+    /// The device is temporarily unreachable, its groups are served by other
+    /// devices until it comes back.
+    Down,
+    /// The device was removed, its data should be moved to other devices.
+    Out,
+}
+
+/// Status and reweight factors for devices, applied on top of a map.
+///
+/// Devices not in the overlay are up with their full weight.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MapOverlay {
+    statuses: HashMap<DeviceId, DeviceStatus>,
+    reweights: HashMap<DeviceId, u32>,
+}
+
+impl MapOverlay {
+    pub fn new() -> MapOverlay {
+        Default::default()
+    }
+
+    pub fn status(&self, device_id: &DeviceId) -> DeviceStatus {
+        self.statuses.get(device_id).copied().unwrap_or(DeviceStatus::Up)
+    }
+
+    pub fn set_status(&mut self, device_id: DeviceId, status: DeviceStatus) {
+        if status == DeviceStatus::Up {
+            self.statuses.remove(&device_id);
+        } else {
+            self.statuses.insert(device_id, status);
+        }
+    }
+
+    /// Get the reweight factor of a device, between 0 and `FULL_WEIGHT`.
+    pub fn reweight(&self, device_id: &DeviceId) -> u32 {
+        self.reweights.get(device_id).copied().unwrap_or(FULL_WEIGHT)
+    }
+
+    /// Set the reweight factor of a device.
+    ///
+    /// A device with factor `FULL_WEIGHT / 2` rejects about half the groups
+    /// that the map would place on it. Values above `FULL_WEIGHT` are clamped.
+    pub fn set_reweight(&mut self, device_id: DeviceId, factor: u32) {
+        if factor >= FULL_WEIGHT {
+            self.reweights.remove(&device_id);
+        } else {
+            self.reweights.insert(device_id, factor);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statuses.is_empty() && self.reweights.is_empty()
+    }
+
+    /// Whether placement should skip this device for this group.
+    pub(crate) fn is_rejected(&self, device_id: &DeviceId, group_id: &GroupId, hash: HashVersion) -> bool {
+        if self.status(device_id) != DeviceStatus::Up {
+            return true;
+        }
+        let factor = self.reweight(device_id);
+        if factor >= FULL_WEIGHT {
+            false
+        } else if factor == 0 {
+            true
+        } else {
+            // Reject deterministically, so the group always goes to the same
+            // place for a given overlay
+            compute_device_hash(hash, group_id, device_id) & 0xFFFF >= factor
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeviceId, GroupId};
+    use super::super::{Algorithm, Bucket, GroupMapping, HashVersion, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+    use super::{DeviceStatus, FULL_WEIGHT, MapOverlay};
+
+    fn example_map() -> StorageMap {
+        StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas: 2,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule: PlacementRule::Any,
+            map_root: Node::Bucket(Bucket {
+                id: 0,
+                algorithm: Algorithm::Straw2,
+                pick_mode: PickMode::NeverRepeat,
+                children: (1..5)
+                    .map(|d| NodeEntry { weight: 1, node: Node::Device(DeviceId([d; 16])) })
+                    .collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_down() {
+        let map = example_map();
+        let empty = MapOverlay::new();
+        let mut overlay = MapOverlay::new();
+        overlay.set_status(DeviceId([2; 16]), DeviceStatus::Down);
+        overlay.set_status(DeviceId([3; 16]), DeviceStatus::Out);
+        assert_eq!(overlay.status(&DeviceId([1; 16])), DeviceStatus::Up);
+        assert_eq!(overlay.status(&DeviceId([2; 16])), DeviceStatus::Down);
+
+        let mut moved = 0;
+        for i in 0..1000 {
+            let before = map.group_to_devices_with_overlay(&GroupId(i), 2, &empty);
+            assert_eq!(before, map.group_to_devices(&GroupId(i), 2));
+            let after = map.group_to_devices_with_overlay(&GroupId(i), 2, &overlay);
+            assert_eq!(after.len(), 2);
+            assert!(after.contains(&DeviceId([1; 16])));
+            assert!(after.contains(&DeviceId([4; 16])));
+            assert_eq!(
+                after.first(),
+                map.group_to_first_device_with_overlay(&GroupId(i), &overlay).as_ref(),
+            );
+
+            // Groups that were not on the failed devices don't move
+            if !before.contains(&DeviceId([2; 16])) && !before.contains(&DeviceId([3; 16])) {
+                assert_eq!(before, after);
+            } else {
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+
+        // Bringing the devices back up restores the placement
+        overlay.set_status(DeviceId([2; 16]), DeviceStatus::Up);
+        overlay.set_status(DeviceId([3; 16]), DeviceStatus::Up);
+        assert!(overlay.is_empty());
+    }
+
+    #[test]
+    fn test_reweight() {
+        let map = example_map();
+        let mut overlay = MapOverlay::new();
+        overlay.set_reweight(DeviceId([1; 16]), FULL_WEIGHT / 2);
+        assert_eq!(overlay.reweight(&DeviceId([1; 16])), FULL_WEIGHT / 2);
+        assert_eq!(overlay.reweight(&DeviceId([2; 16])), FULL_WEIGHT);
+
+        let mut counts = [0; 4];
+        const NUM: u32 = 10000;
+        for i in 0..NUM {
+            let device = map.group_to_first_device_with_overlay(&GroupId(i), &overlay).unwrap();
+            counts[device.0[0] as usize - 1] += 1;
+        }
+
+        // Device 1 gets half its share, which is spread over the others
+        let expected = NUM as f32 / 8.0;
+        assert!((counts[0] as f32 - expected).abs() < expected * 0.1, "{:?}", counts);
+
+        // Factors above the full weight are ignored
+        overlay.set_reweight(DeviceId([1; 16]), FULL_WEIGHT + 1);
+        assert!(overlay.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+//! Checking a storage map for problems before it is applied.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::DeviceId;
+use super::{Algorithm, Bucket, GroupMapping, MAX_GROUPS, Node, PickMode, PlacementRule, StorageMap};
+
+/// A problem found in a storage map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MapProblem {
+    /// The map has no groups.
+    NoGroups,
+    /// The map has more than `MAX_GROUPS` groups.
+    TooManyGroups { groups: usize },
+    /// There are more placement groups than groups.
+    TooManyPlacementGroups { placement_groups: usize, groups: usize },
+    /// Placement groups are set but groups don't split (see `GroupMapping`).
+    PlacementGroupsWithoutSplit,
+    /// A bucket has no children.
+    EmptyBucket { bucket_id: u32 },
+    /// A bucket's children all have a weight of 0, so nothing can be picked.
+    ZeroWeight { bucket_id: u32 },
+    /// Multiple buckets have the same ID.
+    DuplicateBucketId { bucket_id: u32 },
+    /// The parameters of the algorithm don't match the children.
+    InvalidParameters { bucket_id: u32 },
+    /// A device appears multiple times in the tree.
+    DuplicateDevice { device_id: DeviceId },
+    /// A device can never be picked, because of a zero weight on its path.
+    UnreachableDevice { device_id: DeviceId },
+    /// The map can't provide as many distinct replicas as it requires.
+    NotEnoughReplicas { replicas: u32, available: usize },
+}
+
+impl fmt::Display for MapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapProblem::NoGroups => write!(f, "Map has no groups"),
+            MapProblem::TooManyGroups { groups } => write!(f, "Map has {} groups, more than {}", groups, MAX_GROUPS),
+            MapProblem::TooManyPlacementGroups { placement_groups, groups } => write!(
+                f,
+                "Map has {} placement groups but only {} groups",
+                placement_groups, groups,
+            ),
+            MapProblem::PlacementGroupsWithoutSplit => write!(f, "Map has placement groups but its groups don't split"),
+            MapProblem::EmptyBucket { bucket_id } => write!(f, "Bucket {} is empty", bucket_id),
+            MapProblem::ZeroWeight { bucket_id } => write!(f, "Bucket {} has a total weight of 0", bucket_id),
+            MapProblem::DuplicateBucketId { bucket_id } => write!(f, "Bucket ID {} is used multiple times", bucket_id),
+            MapProblem::InvalidParameters { bucket_id } => write!(f, "Bucket {} has invalid algorithm parameters", bucket_id),
+            MapProblem::DuplicateDevice { device_id } => write!(f, "Device {:?} appears multiple times", device_id),
+            MapProblem::UnreachableDevice { device_id } => write!(f, "Device {:?} can never be picked", device_id),
+            MapProblem::NotEnoughReplicas { replicas, available } => write!(
+                f,
+                "Map requires {} replicas but can only place {}",
+                replicas, available,
+            ),
+        }
+    }
+}
+
+/// State accumulated while walking the tree.
+struct Validation {
+    problems: Vec<MapProblem>,
+    bucket_ids: HashSet<u32>,
+    devices: HashSet<DeviceId>,
+    /// Number of reachable nodes at each depth.
+    reachable_at_depth: Vec<usize>,
+}
+
+/// Whether the algorithm takes the weights of the children into account.
+fn uses_weights(algorithm: &Algorithm) -> bool {
+    !matches!(algorithm, Algorithm::Uniform | Algorithm::Fallback)
+}
+
+impl Validation {
+    /// Check a node, returning how many distinct devices can be picked from
+    /// it for a single group.
+    fn check_node(&mut self, node: &Node, reachable: bool, depth: usize) -> usize {
+        if reachable {
+            if self.reachable_at_depth.len() <= depth {
+                self.reachable_at_depth.resize(depth + 1, 0);
+            }
+            self.reachable_at_depth[depth] += 1;
+        }
+        match node {
+            Node::Device(device_id) => {
+                if !self.devices.insert(device_id.clone()) {
+                    self.problems.push(MapProblem::DuplicateDevice { device_id: device_id.clone() });
+                }
+                if reachable {
+                    1
+                } else {
+                    self.problems.push(MapProblem::UnreachableDevice { device_id: device_id.clone() });
+                    0
+                }
+            }
+            Node::Bucket(bucket) => self.check_bucket(bucket, reachable, depth),
+        }
+    }
+
+    fn check_bucket(&mut self, bucket: &Bucket, reachable: bool, depth: usize) -> usize {
+        if !self.bucket_ids.insert(bucket.id) {
+            self.problems.push(MapProblem::DuplicateBucketId { bucket_id: bucket.id });
+        }
+        if bucket.children.is_empty() {
+            self.problems.push(MapProblem::EmptyBucket { bucket_id: bucket.id });
+            return 0;
+        }
+        let weighted = uses_weights(&bucket.algorithm);
+        if weighted && bucket.children.iter().all(|c| c.weight == 0) {
+            self.problems.push(MapProblem::ZeroWeight { bucket_id: bucket.id });
+        }
+        let valid_parameters = match bucket.algorithm {
+            Algorithm::Straw(ref factors) => factors.len() == bucket.children.len(),
+            Algorithm::Tree(ref node_weights) => {
+                node_weights.len() == 2 * bucket.children.len().next_power_of_two()
+            }
+            Algorithm::Alias(ref table) => {
+                table.len() == bucket.children.len()
+                    && table.iter().all(|&(_, alias)| (alias as usize) < bucket.children.len())
+            }
+            _ => true,
+        };
+        if !valid_parameters {
+            self.problems.push(MapProblem::InvalidParameters { bucket_id: bucket.id });
+        }
+
+        let mut available = 0;
+        for child in &bucket.children {
+            let child_reachable = reachable && (!weighted || child.weight > 0);
+            let child_available = self.check_node(&child.node, child_reachable, depth + 1);
+            available += match bucket.pick_mode {
+                // Each child is only entered once per group
+                PickMode::NeverRepeat => child_available.min(1),
+                PickMode::PseudoRandom => child_available,
+            };
+        }
+        available
+    }
+}
+
+impl StorageMap {
+    /// Check the map for problems that would prevent placing data correctly.
+    ///
+    /// Returns all the problems found, an empty list meaning the map is fine.
+    pub fn validate(&self) -> Vec<MapProblem> {
+        let mut validation = Validation {
+            problems: Vec::new(),
+            bucket_ids: HashSet::new(),
+            devices: HashSet::new(),
+            reachable_at_depth: Vec::new(),
+        };
+
+        if self.groups == 0 {
+            validation.problems.push(MapProblem::NoGroups);
+        } else if self.groups > MAX_GROUPS {
+            validation.problems.push(MapProblem::TooManyGroups { groups: self.groups });
+        }
+        if let Some(placement_groups) = self.placement_groups {
+            if self.group_mapping != GroupMapping::Split {
+                validation.problems.push(MapProblem::PlacementGroupsWithoutSplit);
+            }
+            if placement_groups > self.groups {
+                validation.problems.push(MapProblem::TooManyPlacementGroups {
+                    placement_groups,
+                    groups: self.groups,
+                });
+            }
+        }
+
+        let mut available = validation.check_node(&self.map_root, true, 0);
+        if let PlacementRule::DistinctAtDepth(depth) = self.rule {
+            // Replicas need distinct nodes at that depth
+            if let Some(&nodes) = validation.reachable_at_depth.get(depth as usize) {
+                available = available.min(nodes);
+            }
+        }
+        if available < self.replicas as usize {
+            validation.problems.push(MapProblem::NotEnoughReplicas {
+                replicas: self.replicas,
+                available,
+            });
+        }
+
+        validation.problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceId;
+    use super::super::{Algorithm, Bucket, GroupMapping, HashVersion, Node, NodeEntry, PickMode, PlacementRule, StorageMap};
+    use super::MapProblem;
+
+    fn device(id: u8, weight: u32) -> NodeEntry {
+        NodeEntry { weight, node: Node::Device(DeviceId([id; 16])) }
+    }
+
+    fn bucket(id: u32, pick_mode: PickMode, children: Vec<NodeEntry>) -> NodeEntry {
+        NodeEntry {
+            weight: children.iter().map(|c| c.weight).sum(),
+            node: Node::Bucket(Bucket {
+                id,
+                algorithm: Algorithm::Straw2,
+                pick_mode,
+                children,
+            }),
+        }
+    }
+
+    fn make_map(replicas: u32, rule: PlacementRule, root: NodeEntry) -> StorageMap {
+        StorageMap {
+            generation: 1,
+            groups: 128,
+            replicas,
+            group_mapping: GroupMapping::Modulo,
+            placement_groups: None,
+            hash: HashVersion::Fx,
+            rule,
+            map_root: root.node,
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        let root = bucket(0, PickMode::NeverRepeat, vec![
+            bucket(1, PickMode::PseudoRandom, vec![device(1, 1), device(2, 1)]),
+            bucket(2, PickMode::PseudoRandom, vec![device(3, 1), device(4, 1)]),
+        ]);
+        let map = make_map(2, PlacementRule::DistinctAtDepth(1), root);
+        assert_eq!(map.validate(), vec![]);
+    }
+
+    #[test]
+    fn test_problems() {
+        let root = bucket(0, PickMode::NeverRepeat, vec![
+            bucket(1, PickMode::PseudoRandom, vec![device(1, 1), device(2, 0)]),
+            bucket(1, PickMode::PseudoRandom, vec![device(1, 1)]),
+            bucket(3, PickMode::PseudoRandom, vec![]),
+        ]);
+        let mut map = make_map(4, PlacementRule::Any, root);
+        map.placement_groups = Some(256);
+        assert_eq!(
+            map.validate(),
+            vec![
+                MapProblem::PlacementGroupsWithoutSplit,
+                MapProblem::TooManyPlacementGroups { placement_groups: 256, groups: 128 },
+                MapProblem::UnreachableDevice { device_id: DeviceId([2; 16]) },
+                MapProblem::DuplicateBucketId { bucket_id: 1 },
+                MapProblem::DuplicateDevice { device_id: DeviceId([1; 16]) },
+                MapProblem::EmptyBucket { bucket_id: 3 },
+                MapProblem::NotEnoughReplicas { replicas: 4, available: 2 },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_replicas() {
+        // Pseudo-random buckets can pick all the devices
+        let root = bucket(0, PickMode::PseudoRandom, vec![
+            bucket(1, PickMode::PseudoRandom, vec![device(1, 1), device(2, 1)]),
+            bucket(2, PickMode::PseudoRandom, vec![device(3, 1)]),
+        ]);
+        let mut map = make_map(3, PlacementRule::Any, root);
+        assert_eq!(map.validate(), vec![]);
+
+        // But not with distinct hosts
+        map.rule = PlacementRule::DistinctAtDepth(1);
+        assert_eq!(
+            map.validate(),
+            vec![MapProblem::NotEnoughReplicas { replicas: 3, available: 2 }],
+        );
+
+        // Or with a root that never repeats
+        map.rule = PlacementRule::Any;
+        if let Node::Bucket(ref mut root) = map.map_root {
+            root.pick_mode = PickMode::NeverRepeat;
+        }
+        assert_eq!(
+            map.validate(),
+            vec![MapProblem::NotEnoughReplicas { replicas: 3, available: 2 }],
+        );
+
+        // Zero weight
+        let root = bucket(0, PickMode::PseudoRandom, vec![device(1, 0), device(2, 0)]);
+        let map = make_map(1, PlacementRule::Any, root);
+        let problems = map.validate();
+        assert_eq!(problems[0], MapProblem::ZeroWeight { bucket_id: 0 });
+        assert_eq!(
+            problems[3].to_string(),
+            "Map requires 1 replicas but can only place 0",
+        );
+    }
+}
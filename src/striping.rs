@@ -0,0 +1,344 @@
+//! Objects larger than a request can carry, split over many objects.
+//!
+//! `StripedClient` stores objects up to a threshold as they are, and splits
+//! larger ones into stripe objects `<id>.0`, `<id>.1`, ... which hash to
+//! different groups, so they are spread over the daemons. The object `<id>`
+//! then holds a manifest listing the stripes, which starts with `MAGIC`.
+//! Small objects starting with `MAGIC` are also striped, so that they can't
+//! be mistaken for a manifest.
+//!
+//! Stripes are written before the manifest and deleted after it, so the
+//! manifest only refers to stripes that exist. The manifest records the
+//! version of each stripe, so a read that overlaps a write fails with
+//! `ErrorKind::Interrupted` instead of mixing old and new data.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Error as IoError, ErrorKind};
+
+use crate::ObjectId;
+use crate::client::{Client, VersionedRead};
+
+/// Start of the manifest objects.
+pub const MAGIC: &[u8] = b"\xffSTRIPED";
+
+const MANIFEST_VERSION: u8 = 1;
+
+/// Length of the manifest before the versions of the stripes.
+const MANIFEST_HEADER_LEN: usize = 21;
+
+pub const DEFAULT_STRIPE_SIZE: usize = 1 << 20;
+
+/// Largest part of a stripe written in one request, so that it fits in a
+/// datagram.
+const WRITE_SIZE: usize = 32 << 10;
+
+/// The manifest of a striped object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub size: u64,
+    pub stripe_size: u32,
+    /// Version of each stripe after it was written.
+    pub versions: Vec<u64>,
+}
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn stripe_count(size: u64, stripe_size: u32) -> u64 {
+    size.div_ceil(stripe_size as u64)
+}
+
+impl Manifest {
+    /// Read the manifest object: `MAGIC`, the version byte 1, the size as a
+    /// big-endian u64, the stripe size as a big-endian u32, then the version
+    /// of each stripe as a big-endian u64.
+    pub fn decode(data: &[u8]) -> Result<Manifest, IoError> {
+        let (size, stripe_size) = Manifest::decode_header(data)?;
+        let count = stripe_count(size, stripe_size);
+        if count.checked_mul(8) != Some((data.len() - MANIFEST_HEADER_LEN) as u64) {
+            return Err(invalid("Striped object manifest doesn't match its size"));
+        }
+        let mut reader = Cursor::new(&data[MANIFEST_HEADER_LEN..]);
+        let versions = (0..count).map(|_| reader.read_u64::<BigEndian>()).collect::<Result<_, _>>()?;
+        Ok(Manifest { size, stripe_size, versions })
+    }
+
+    /// Read the size and stripe size, from the start of a manifest.
+    fn decode_header(data: &[u8]) -> Result<(u64, u32), IoError> {
+        if !data.starts_with(MAGIC) {
+            return Err(invalid("Not a striped object manifest"));
+        }
+        let mut reader = Cursor::new(&data[MAGIC.len()..]);
+        let version = reader.read_u8()?;
+        if version != MANIFEST_VERSION {
+            return Err(invalid(&format!("Unknown striped object manifest version {}", version)));
+        }
+        let size = reader.read_u64::<BigEndian>()?;
+        let stripe_size = reader.read_u32::<BigEndian>()?;
+        if stripe_size == 0 {
+            return Err(invalid("Invalid stripe size 0"));
+        }
+        Ok((size, stripe_size))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = MAGIC.to_owned();
+        result.write_u8(MANIFEST_VERSION).unwrap();
+        result.write_u64::<BigEndian>(self.size).unwrap();
+        result.write_u32::<BigEndian>(self.stripe_size).unwrap();
+        for &version in &self.versions {
+            result.write_u64::<BigEndian>(version).unwrap();
+        }
+        result
+    }
+}
+
+/// The object holding stripe `num` of an object.
+pub fn stripe_object_id(object_id: &ObjectId, num: u64) -> Result<ObjectId, IoError> {
+    let mut id = object_id.0.clone();
+    id.extend_from_slice(format!(".{}", num).as_bytes());
+    ObjectId::new(id)
+}
+
+/// The stripe number and the object it is part of, if the ID is one of a
+/// stripe.
+pub fn parse_stripe_object_id(object_id: &ObjectId) -> Option<(ObjectId, u64)> {
+    let pos = object_id.0.iter().rposition(|&b| b == b'.')?;
+    let num = std::str::from_utf8(&object_id.0[pos + 1..]).ok()?;
+    // Only the names stripe_object_id() makes
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) || (num.len() > 1 && num.starts_with('0')) {
+        return None;
+    }
+    Some((ObjectId::new(&object_id.0[..pos]).ok()?, num.parse().ok()?))
+}
+
+/// Number of stripes of the object currently stored, 0 if it is not striped.
+pub async fn current_stripes(client: &Client, object_id: &ObjectId) -> Result<u64, IoError> {
+    let header = client.read_part(object_id, 0, MANIFEST_HEADER_LEN as u32).await?;
+    match header {
+        Some(header) if header.starts_with(MAGIC) => {
+            let (size, stripe_size) = Manifest::decode_header(&header)?;
+            Ok(stripe_count(size, stripe_size))
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Write an object in parts, returning its version after the last one.
+async fn write_in_parts(client: &Client, object_id: &ObjectId, data: &[u8]) -> Result<u64, IoError> {
+    let mut parts = data.chunks(WRITE_SIZE);
+    let mut version = client.write_object(object_id, parts.next().unwrap_or(&[])).await?;
+    let mut offset = WRITE_SIZE;
+    for part in parts {
+        version = client.write_part(object_id, offset as u32, part).await?;
+        offset += part.len();
+    }
+    Ok(version)
+}
+
+fn changed() -> IoError {
+    IoError::new(ErrorKind::Interrupted, "Object changed while reading")
+}
+
+/// A client that splits large objects into stripes.
+#[derive(Clone)]
+pub struct StripedClient {
+    client: Client,
+    /// Largest object stored as is.
+    threshold: usize,
+    stripe_size: usize,
+}
+
+impl StripedClient {
+    pub fn new(client: Client) -> StripedClient {
+        StripedClient {
+            client,
+            threshold: DEFAULT_STRIPE_SIZE,
+            stripe_size: DEFAULT_STRIPE_SIZE,
+        }
+    }
+
+    /// Get a client that stripes objects larger than `threshold` bytes.
+    pub fn with_threshold(&self, threshold: usize) -> StripedClient {
+        StripedClient {
+            threshold,
+            ..self.clone()
+        }
+    }
+
+    /// Get a client that writes stripes of `stripe_size` bytes.
+    ///
+    /// Objects that were written with another stripe size can still be read.
+    pub fn with_stripe_size(&self, stripe_size: usize) -> Result<StripedClient, IoError> {
+        if stripe_size == 0 || stripe_size > u32::MAX as usize {
+            return Err(IoError::new(ErrorKind::InvalidInput, format!("Invalid stripe size {}", stripe_size)));
+        }
+        Ok(StripedClient {
+            stripe_size,
+            ..self.clone()
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    async fn delete_stripes(&self, object_id: &ObjectId, stripes: std::ops::Range<u64>) -> Result<(), IoError> {
+        for num in stripes {
+            self.client.delete_object(&stripe_object_id(object_id, num)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace an object, striping it if it is large.
+    pub async fn write_object(&self, object_id: &ObjectId, data: &[u8]) -> Result<(), IoError> {
+        let old_stripes = current_stripes(&self.client, object_id).await?;
+        if data.len() <= self.threshold && !data.starts_with(MAGIC) {
+            write_in_parts(&self.client, object_id, data).await?;
+            return self.delete_stripes(object_id, 0..old_stripes).await;
+        }
+
+        // Write the stripes in parallel
+        let stripe_ids = (0..stripe_count(data.len() as u64, self.stripe_size as u32))
+            .map(|num| stripe_object_id(object_id, num))
+            .collect::<Result<Vec<_>, _>>()?;
+        let handles: Vec<_> = data.chunks(self.stripe_size).zip(stripe_ids).map(|(stripe, stripe_id)| {
+            let (client, stripe) = (self.client.clone(), stripe.to_owned());
+            tokio::spawn(async move {
+                write_in_parts(&client, &stripe_id, &stripe).await
+            })
+        }).collect();
+        let mut versions = Vec::with_capacity(handles.len());
+        for handle in handles {
+            versions.push(handle.await.map_err(IoError::other)??);
+        }
+
+        let manifest = Manifest {
+            size: data.len() as u64,
+            stripe_size: self.stripe_size as u32,
+            versions,
+        };
+        write_in_parts(&self.client, object_id, &manifest.encode()).await?;
+        self.delete_stripes(object_id, manifest.versions.len() as u64..old_stripes).await
+    }
+
+    /// Read an object, putting its stripes back together.
+    pub async fn read_object(&self, object_id: &ObjectId) -> Result<Option<Vec<u8>>, IoError> {
+        let data = match self.client.read_object(object_id).await? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        if !data.starts_with(MAGIC) {
+            return Ok(Some(data));
+        }
+
+        let manifest = Manifest::decode(&data)?;
+        let handles: Vec<_> = manifest.versions.iter().enumerate().map(|(num, &version)| {
+            let client = self.client.clone();
+            let stripe_id = stripe_object_id(object_id, num as u64);
+            tokio::spawn(async move {
+                match client.read_versioned(&stripe_id?, None).await? {
+                    VersionedRead::Object { version: v, data } if v == version => Ok(data),
+                    _ => Err(changed()),
+                }
+            })
+        }).collect();
+        let mut result = Vec::with_capacity(manifest.size as usize);
+        for (num, handle) in handles.into_iter().enumerate() {
+            let stripe = handle.await.map_err(IoError::other)??;
+            let expected = (manifest.size - result.len() as u64).min(manifest.stripe_size as u64);
+            if stripe.len() as u64 != expected {
+                return Err(invalid(&format!("Stripe {} has the wrong size", num)));
+            }
+            result.extend_from_slice(&stripe);
+        }
+        Ok(Some(result))
+    }
+
+    /// Delete an object and its stripes.
+    pub async fn delete_object(&self, object_id: &ObjectId) -> Result<(), IoError> {
+        let stripes = current_stripes(&self.client, object_id).await?;
+        self.client.delete_object(object_id).await?;
+        self.delete_stripes(object_id, 0..stripes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::{ObjectId, PoolName};
+    use crate::client::create_client_with_socket;
+    use crate::daemon::spawn_test_daemon;
+    use crate::netsim::{SimConfig, SimNetwork, Socket};
+    use super::{MAGIC, Manifest, StripedClient, parse_stripe_object_id, stripe_object_id};
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest { size: 2500, stripe_size: 1000, versions: vec![1, 2, 1 << 60] };
+        let encoded = manifest.encode();
+        assert!(encoded.starts_with(MAGIC));
+        assert_eq!(Manifest::decode(&encoded).unwrap(), manifest);
+        // Wrong number of stripes for the size
+        assert!(Manifest::decode(&encoded[..encoded.len() - 8]).is_err());
+        let empty = Manifest { size: 0, stripe_size: 1000, versions: vec![] };
+        assert_eq!(Manifest::decode(&empty.encode()).unwrap(), empty);
+        assert!(Manifest::decode(b"\xffSTRIPE").is_err());
+        assert!(Manifest::decode(&Manifest { stripe_size: 0, ..empty }.encode()).is_err());
+        // More stripes than can be counted
+        assert!(Manifest::decode(&Manifest { size: u64::MAX, stripe_size: 1, versions: vec![] }.encode()).is_err());
+    }
+
+    #[test]
+    fn test_stripe_object_id() {
+        let object_id = ObjectId(b"dir/obj.tar".to_vec());
+        let stripe = stripe_object_id(&object_id, 12).unwrap();
+        assert_eq!(stripe.0, b"dir/obj.tar.12");
+        assert_eq!(parse_stripe_object_id(&stripe), Some((object_id.clone(), 12)));
+        for id in [&b"obj"[..], b"obj.tar", b"obj.", b"obj.012", b".3", b"obj.-1"] {
+            assert_eq!(parse_stripe_object_id(&ObjectId(id.to_vec())), None, "{:?}", id);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_striping() {
+        let network = SimNetwork::new(1, SimConfig::default());
+        let daemon_address = "10.0.0.1:4000".parse().unwrap();
+        let daemon = spawn_test_daemon(network.bind(daemon_address).unwrap());
+        let socket = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let client = create_client_with_socket(Socket::Sim(socket), daemon_address, PoolName("default".to_owned())).await.unwrap();
+        let striped = StripedClient::new(client.clone()).with_threshold(50000).with_stripe_size(40000).unwrap();
+        let object_id = ObjectId(b"obj".to_vec());
+        let stripe = |num| stripe_object_id(&object_id, num).unwrap();
+
+        // Large objects are striped
+        let big: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        striped.write_object(&object_id, &big).await.unwrap();
+        assert_eq!(striped.read_object(&object_id).await.unwrap(), Some(big.clone()));
+        assert_eq!(client.read_object(&stripe(2)).await.unwrap().as_deref(), Some(&big[80000..]));
+
+        // A read overlapping a write fails instead of mixing them
+        client.write_object(&stripe(1), &big[40000..80000]).await.unwrap();
+        assert_eq!(striped.read_object(&object_id).await.unwrap_err().kind(), ErrorKind::Interrupted);
+
+        // Fewer stripes, the extra one is deleted
+        striped.write_object(&object_id, &big[..70000]).await.unwrap();
+        assert_eq!(striped.read_object(&object_id).await.unwrap().as_deref(), Some(&big[..70000]));
+        assert_eq!(client.read_object(&stripe(2)).await.unwrap(), None);
+
+        // Small objects are stored as is, unless they look like a manifest
+        striped.write_object(&object_id, b"small").await.unwrap();
+        assert_eq!(client.read_object(&object_id).await.unwrap().as_deref(), Some(&b"small"[..]));
+        assert_eq!(client.read_object(&stripe(0)).await.unwrap(), None);
+        striped.write_object(&object_id, MAGIC).await.unwrap();
+        assert_eq!(striped.read_object(&object_id).await.unwrap().as_deref(), Some(MAGIC));
+        assert_eq!(client.read_object(&stripe(0)).await.unwrap().as_deref(), Some(MAGIC));
+
+        striped.delete_object(&object_id).await.unwrap();
+        assert_eq!(striped.read_object(&object_id).await.unwrap(), None);
+        assert_eq!(client.read_object(&stripe(0)).await.unwrap(), None);
+
+        daemon.abort();
+    }
+}
@@ -0,0 +1,256 @@
+//! Running as a systemd service: readiness and watchdog notifications,
+//! sockets passed by socket activation, stopping cleanly on SIGTERM, and
+//! reloading on SIGHUP.
+//!
+//! The protocols are simple enough to speak without libsystemd.
+//! Notifications are datagrams sent to the Unix socket named by
+//! `$NOTIFY_SOCKET`. Activated sockets are `$LISTEN_FDS` file descriptors
+//! starting at 3, meant for us if `$LISTEN_PID` is our PID. Without these
+//! variables, as when not started by systemd, nothing is sent or taken.
+//!
+//! Changing the environment isn't safe once other threads run, so
+//! `init_listen_fds()` has to be called at the start of `main()`.
+
+use log::{debug, info, warn};
+use std::io::Error as IoError;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Send a notification to systemd, such as `READY=1`, if started by it.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(p) => p,
+            None => return,
+        };
+        if let Err(e) = unix::notify(&path, state) {
+            warn!("Can't notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Tell systemd we are reloading our configuration, if started by it.
+///
+/// Send `READY=1` once done.
+pub fn notify_reloading() {
+    // Type=notify-reload services have to give the time as well
+    #[cfg(unix)]
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", unix::monotonic_usec()));
+    #[cfg(not(unix))]
+    notify("RELOADING=1");
+}
+
+/// How often to ping the watchdog, half its timeout, if systemd enabled it.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_str() != Some(&std::process::id().to_string()) {
+            return None;
+        }
+    }
+    let timeout: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if timeout == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(timeout / 2))
+}
+
+/// Ping the watchdog, if systemd enabled it, after calling `check`.
+///
+/// The pings come from a task on the daemon's runtime, so they stop if it
+/// gets stuck, or if `check` blocks, for example on a lock that is never
+/// released.
+pub fn spawn_watchdog<F: Fn() + Send + 'static>(check: F) {
+    if let Some(interval) = watchdog_interval() {
+        debug!("Pinging the systemd watchdog every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                check();
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
+
+/// Look up the sockets passed by systemd, clearing the variables so child
+/// processes don't use them too.
+///
+/// This changes the environment, so it has to be called before any other
+/// thread is started, including the runtime's. Without it,
+/// `take_udp_socket()` finds nothing.
+pub fn init_listen_fds() {
+    #[cfg(unix)]
+    unix::init_listen_fds();
+}
+
+/// Take the UDP socket bound to `address` that systemd passed, if any.
+///
+/// A socket that is taken is not returned again.
+pub fn take_udp_socket(address: SocketAddr) -> Result<Option<UdpSocket>, IoError> {
+    #[cfg(unix)]
+    {
+        let socket = unix::take_udp_socket(address)?;
+        if socket.is_some() {
+            info!("Using socket {} passed by systemd", address);
+        }
+        Ok(socket)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = address;
+        Ok(None)
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, returning its number.
+///
+/// On Windows, Ctrl-C is reported as SIGINT. Once this is called, these
+/// signals no longer kill the process.
+pub async fn termination_signal() -> Result<i32, IoError> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(libc::SIGTERM),
+            _ = interrupt.recv() => Ok(libc::SIGINT),
+        }
+    }
+    #[cfg(windows)]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok(2)
+    }
+    #[cfg(not(any(unix, windows)))]
+    std::future::pending().await
+}
+
+/// The SIGHUP signals received, asking to reload the configuration.
+pub struct Hangups {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangups {
+    /// Start catching SIGHUP, which then no longer kills the process.
+    pub fn new() -> Result<Hangups, IoError> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            Ok(Hangups { signal: signal(SignalKind::hangup())? })
+        }
+        #[cfg(not(unix))]
+        Ok(Hangups {})
+    }
+
+    /// Wait for the next SIGHUP. There are none on other platforms.
+    pub async fn recv(&mut self) -> Result<(), IoError> {
+        #[cfg(unix)]
+        {
+            self.signal.recv().await;
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        std::future::pending().await
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::OsStr;
+    use std::io::Error as IoError;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::Mutex;
+
+    /// First file descriptor passed by socket activation.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// The activated sockets not taken yet.
+    static LISTEN_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+    pub fn monotonic_usec() -> u64 {
+        let mut time: libc::timespec = unsafe { std::mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+        time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000
+    }
+
+    pub fn notify(path: &OsStr, state: &str) -> Result<(), IoError> {
+        let socket = UnixDatagram::unbound()?;
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(IoError::new(std::io::ErrorKind::Unsupported, "Abstract sockets are only supported on Linux")),
+            None => {
+                socket.send_to(state.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn init_listen_fds() {
+        *LISTEN_FDS.lock().unwrap() = listen_fds();
+    }
+
+    /// Get the file descriptors passed by socket activation, clearing the
+    /// variables.
+    fn listen_fds() -> Vec<RawFd> {
+        let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|c| c.parse::<RawFd>().ok());
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        match (pid, count) {
+            (Some(pid), Some(count)) if pid == std::process::id() => {
+                let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+                for &fd in &fds {
+                    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                }
+                fds
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn is_datagram_socket(fd: RawFd) -> bool {
+        let mut kind: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut kind as *mut _ as *mut libc::c_void, &mut len)
+        };
+        result == 0 && kind == libc::SOCK_DGRAM
+    }
+
+    pub fn take_udp_socket(address: SocketAddr) -> Result<Option<UdpSocket>, IoError> {
+        let mut fds = LISTEN_FDS.lock().unwrap();
+        for (i, &fd) in fds.iter().enumerate() {
+            if !is_datagram_socket(fd) {
+                continue;
+            }
+            let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+            if socket.local_addr().ok() == Some(address) {
+                fds.remove(i);
+                socket.set_nonblocking(true)?;
+                return Ok(Some(socket));
+            }
+            // Not this one, leave it open
+            let _ = socket.into_raw_fd();
+        }
+        Ok(None)
+    }
+}
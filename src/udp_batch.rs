@@ -0,0 +1,294 @@
+//! Receiving and sending many datagrams per system call.
+//!
+//! On Linux this uses `recvmmsg()` and `sendmmsg()`, so that workloads of
+//! small objects aren't limited by a system call per datagram. Elsewhere,
+//! datagrams are received and sent one at a time.
+//!
+//! Also on Linux, several sockets can be bound to the same address with
+//! `SO_REUSEPORT`, the kernel spreading the datagrams between them by
+//! sender, so they can be read by different threads.
+
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Most datagrams received or sent by one system call.
+pub const MAX_BATCH: usize = 32;
+
+/// Receive datagrams into `bufs`, waiting for at least one.
+///
+/// The length and sender of each datagram are put in `received`, which is
+/// cleared first; the number of datagrams is returned.
+pub async fn recv_batch<B: AsMut<[u8]>>(socket: &UdpSocket, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> Result<usize, IoError> {
+    received.clear();
+    #[cfg(target_os = "linux")]
+    loop {
+        socket.readable().await?;
+        match socket.try_io(tokio::io::Interest::READABLE, || linux::recvmmsg(socket, bufs, received)) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = recv_from(socket, bufs[0].as_mut()).await?;
+        received.push((len, addr));
+        Ok(1)
+    }
+}
+
+/// Receive one datagram.
+///
+/// On Windows, an ICMP "port unreachable" answering a datagram we sent makes
+/// the next receive fail with `ConnectionReset`. It is about that earlier
+/// datagram, not a problem with the socket, so it is skipped.
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> Result<(usize, SocketAddr), IoError> {
+    loop {
+        match socket.recv_from(buf).await {
+            Err(e) if cfg!(windows) && e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Send all the datagrams in `frames` to `addr`.
+pub async fn send_batch<B: AsRef<[u8]>>(socket: &UdpSocket, frames: &[B], addr: SocketAddr) -> Result<(), IoError> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut sent = 0;
+        while sent < frames.len() {
+            socket.writable().await?;
+            match socket.try_io(tokio::io::Interest::WRITABLE, || linux::sendmmsg(socket, &frames[sent..], addr)) {
+                Ok(count) => sent += count,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    for frame in frames {
+        socket.send_to(frame.as_ref(), addr).await?;
+    }
+    Ok(())
+}
+
+/// Bind a UDP socket to an address that other sockets can be bound to as
+/// well, sharing the datagrams sent to it.
+///
+/// Only the sockets bound this way can share the address.
+pub fn bind_reuse_port(address: SocketAddr) -> Result<std::net::UdpSocket, IoError> {
+    #[cfg(target_os = "linux")]
+    return linux::bind_reuse_port(address);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = address;
+        Err(IoError::new(std::io::ErrorKind::Unsupported, "Sharing a UDP address between sockets is only supported on Linux"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io::{Error as IoError, ErrorKind};
+    use std::mem::size_of;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::net::UdpSocket as UdpSocketStd;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use tokio::net::UdpSocket;
+
+    use super::MAX_BATCH;
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Result<SocketAddr, IoError> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => Err(IoError::new(ErrorKind::InvalidData, "Unsupported address family")),
+        }
+    }
+
+    fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                (storage, size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            }
+            SocketAddr::V6(addr) => {
+                let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_flowinfo = addr.flowinfo();
+                sockaddr.sin6_scope_id = addr.scope_id();
+                (storage, size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+            }
+        }
+    }
+
+    pub fn bind_reuse_port(address: SocketAddr) -> Result<UdpSocketStd, IoError> {
+        let family = match address {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+        // Closes the descriptor on errors
+        let socket = unsafe { UdpSocketStd::from_raw_fd(fd) };
+        let one: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEPORT,
+                &one as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(IoError::last_os_error());
+        }
+        let (sockaddr, sockaddr_len) = to_sockaddr(address);
+        if unsafe { libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, sockaddr_len) } != 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    pub fn recvmmsg<B: AsMut<[u8]>>(socket: &UdpSocket, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> Result<usize, IoError> {
+        let count = bufs.len().min(MAX_BATCH);
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        for (i, buf) in bufs[0..count].iter_mut().enumerate() {
+            let buf = buf.as_mut();
+            iovecs[i].iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = buf.len();
+            headers[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            headers[i].msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+            headers[i].msg_hdr.msg_iovlen = 1;
+        }
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(IoError::last_os_error());
+        }
+        for i in 0..result as usize {
+            received.push((headers[i].msg_len as usize, from_sockaddr(&addrs[i])?));
+        }
+        Ok(result as usize)
+    }
+
+    pub fn sendmmsg<B: AsRef<[u8]>>(socket: &UdpSocket, frames: &[B], addr: SocketAddr) -> Result<usize, IoError> {
+        let count = frames.len().min(MAX_BATCH);
+        let (mut sockaddr, sockaddr_len) = to_sockaddr(addr);
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        for (i, frame) in frames[0..count].iter().enumerate() {
+            let frame = frame.as_ref();
+            // Not written to, the type is shared with receiving
+            iovecs[i].iov_base = frame.as_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = frame.len();
+            headers[i].msg_hdr.msg_name = &mut sockaddr as *mut _ as *mut libc::c_void;
+            headers[i].msg_hdr.msg_namelen = sockaddr_len;
+            headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+            headers[i].msg_hdr.msg_iovlen = 1;
+        }
+        let result = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT as _)
+        };
+        if result < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(result as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::{recv_batch, send_batch};
+
+    #[tokio::test]
+    async fn test_batch() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let sender = match UdpSocket::bind(address).await {
+                Ok(s) => s,
+                // No IPv6 on this machine
+                Err(_) => continue,
+            };
+            let receiver = UdpSocket::bind(address).await.unwrap();
+            let receiver_address = receiver.local_addr().unwrap();
+
+            let frames: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 10 + i as usize]).collect();
+            send_batch(&sender, &frames, receiver_address).await.unwrap();
+
+            let mut bufs = vec![vec![0; 100]; 8];
+            let mut received = Vec::new();
+            let mut got: Vec<Vec<u8>> = Vec::new();
+            while got.len() < frames.len() {
+                let count = recv_batch(&receiver, &mut bufs, &mut received).await.unwrap();
+                assert!((1..=8).contains(&count));
+                assert_eq!(received.len(), count);
+                for (buf, &(len, addr)) in bufs.iter().zip(&received) {
+                    assert_eq!(addr, sender.local_addr().unwrap());
+                    got.push(buf[0..len].to_owned());
+                }
+            }
+            assert_eq!(got, frames);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        use super::bind_reuse_port;
+
+        let first = UdpSocket::from_std(bind_reuse_port("127.0.0.1:0".parse().unwrap()).unwrap()).unwrap();
+        let address = first.local_addr().unwrap();
+        let second = UdpSocket::from_std(bind_reuse_port(address).unwrap()).unwrap();
+        assert!(UdpSocket::bind(address).await.is_err());
+
+        // Each sender's datagrams go to one of the sockets
+        for i in 0..20u8 {
+            let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            sender.send_to(&[i], address).await.unwrap();
+        }
+        let mut got = Vec::new();
+        let (mut buf1, mut buf2) = ([0; 10], [0; 10]);
+        while got.len() < 20 {
+            tokio::select! {
+                r = first.recv_from(&mut buf1) => got.extend_from_slice(&buf1[0..r.unwrap().0]),
+                r = second.recv_from(&mut buf2) => got.extend_from_slice(&buf2[0..r.unwrap().0]),
+            }
+        }
+        got.sort();
+        assert_eq!(got, (0..20).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,151 @@
+//! The recent changes to objects on a storage daemon, for watchers.
+//!
+//! Changes are numbered in the order they happen, and watchers poll with the
+//! position they got from their previous request. The log only records while
+//! somebody polls it, and only keeps the last `MAX_EVENTS` changes; watchers
+//! asking for changes that were not kept are told some were lost.
+//!
+//! Positions start from the daemon's epoch shifted by 32 bits, so positions
+//! from before a restart are always older than what the log holds.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::{ObjectId, PoolName};
+use crate::proto::wire::{Event, EventKind};
+
+/// Most changes kept.
+const MAX_EVENTS: usize = 10000;
+
+/// Stop recording once nobody polled for this long.
+const WATCH_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Most changes returned by one request.
+const MAX_WATCH_EVENTS: usize = 1000;
+
+/// Most bytes of object names returned by one request.
+const MAX_WATCH_SIZE: usize = 32768;
+
+pub struct EventLog {
+    /// Position of the first entry.
+    first: u64,
+    entries: VecDeque<(PoolName, Event)>,
+    /// When somebody last polled, if recording.
+    last_watch: Option<Instant>,
+}
+
+impl EventLog {
+    pub fn new(epoch: u32) -> EventLog {
+        EventLog {
+            first: (epoch as u64) << 32,
+            entries: VecDeque::new(),
+            last_watch: None,
+        }
+    }
+
+    /// Position of the next change.
+    fn next(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+
+    /// Whether changes are being recorded.
+    pub fn is_watched(&self) -> bool {
+        self.last_watch.is_some_and(|t| t.elapsed() < WATCH_EXPIRY)
+    }
+
+    /// Record a change, if somebody is watching.
+    pub fn record(&mut self, pool: &PoolName, kind: EventKind, object_id: &ObjectId) {
+        if !self.is_watched() {
+            return;
+        }
+        if self.entries.len() >= MAX_EVENTS {
+            self.entries.pop_front();
+            self.first += 1;
+        }
+        self.entries.push_back((pool.clone(), Event { kind, object_id: object_id.clone() }));
+    }
+
+    /// Get the changes to a pool's objects whose name starts with `prefix`,
+    /// from position `after` or from now.
+    ///
+    /// Returns the position to poll from next, whether some changes were
+    /// lost, and the changes.
+    pub fn watch(&mut self, pool: &PoolName, prefix: &[u8], after: Option<u64>) -> (u64, bool, Vec<Event>) {
+        if !self.is_watched() {
+            // Changes were not recorded since the last watcher went away,
+            // skip a position so its requests see changes were lost
+            self.first = self.next() + 1;
+            self.entries.clear();
+        }
+        self.last_watch = Some(Instant::now());
+
+        let next = self.next();
+        let (start, lost) = match after {
+            None => return (next, false, Vec::new()),
+            // Changes before `first` were dropped or never recorded
+            Some(after) if after < self.first || after > next => (self.first, true),
+            Some(after) => (after, false),
+        };
+
+        let mut events = Vec::new();
+        let mut size = 0;
+        let mut position = start;
+        for (entry_pool, event) in self.entries.iter().skip((start - self.first) as usize) {
+            if entry_pool == pool && event.object_id.0.starts_with(prefix) {
+                size += 5 + event.object_id.0.len();
+                if events.len() >= MAX_WATCH_EVENTS || size > MAX_WATCH_SIZE {
+                    break;
+                }
+                events.push(event.clone());
+            }
+            position += 1;
+        }
+        (position, lost, events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ObjectId, PoolName};
+    use crate::proto::wire::{Event, EventKind};
+    use super::{EventLog, MAX_EVENTS};
+
+    #[test]
+    fn test_event_log() {
+        let pool = PoolName("pool".to_owned());
+        let other = PoolName("other".to_owned());
+        let object = |name: &str| ObjectId(name.as_bytes().to_owned());
+        let mut log = EventLog::new(5);
+
+        // Nothing is recorded until somebody watches
+        log.record(&pool, EventKind::Created, &object("a/1"));
+        let (start, lost, events) = log.watch(&pool, b"a/", None);
+        assert_eq!((lost, events), (false, vec![]));
+        assert!(start > 5 << 32);
+
+        log.record(&pool, EventKind::Created, &object("a/1"));
+        log.record(&other, EventKind::Created, &object("a/2"));
+        log.record(&pool, EventKind::Created, &object("b/1"));
+        log.record(&pool, EventKind::Deleted, &object("a/1"));
+        let (next, lost, events) = log.watch(&pool, b"a/", Some(start));
+        assert!(!lost);
+        assert_eq!(next, start + 4);
+        assert_eq!(
+            events,
+            vec![
+                Event { kind: EventKind::Created, object_id: object("a/1") },
+                Event { kind: EventKind::Deleted, object_id: object("a/1") },
+            ],
+        );
+        assert_eq!(log.watch(&pool, b"a/", Some(next)), (next, false, vec![]));
+
+        // Positions from before a restart, or dropped changes, are lost
+        assert!(log.watch(&pool, b"a/", Some(3 << 32)).1);
+        for _ in 0..=MAX_EVENTS {
+            log.record(&pool, EventKind::Updated, &object("b/1"));
+        }
+        let (_, lost, events) = log.watch(&pool, b"a/", Some(next));
+        assert!(lost);
+        assert!(events.is_empty());
+    }
+}